@@ -0,0 +1,363 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use clap::Clap;
+
+use bindle::{
+    events::{Event, KeyRotated},
+    provider::{
+        file::FileProvider,
+        hooks::{EventHook, HttpHook},
+        Provider,
+    },
+    search::NoopEngine,
+    signature::{SecretKeyEntry, SecretKeyFile, Signature, SignatureRole},
+    Id, Invoice,
+};
+
+const DESCRIPTION: &str = r#"
+The Bindle Admin Tool
+
+This program performs maintenance tasks directly on the directory a Bindle
+server stores its bindles in, such as re-signing invoices after rotating keys.
+"#;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clap)]
+#[clap(name = "bindle-admin", version = clap::crate_version!(), author = "DeisLabs at Microsoft Azure", about = DESCRIPTION)]
+struct Opts {
+    #[clap(
+        name = "bindle_directory",
+        short = 'd',
+        long = "directory",
+        env = "BINDLE_DIRECTORY",
+        default_value = "/tmp",
+        about = "the path to the directory in which the server stores bindles"
+    )]
+    bindle_directory: PathBuf,
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+
+#[derive(Clap)]
+enum SubCommand {
+    #[clap(
+        name = "resign",
+        about = "adds signatures made with a new key to stored invoices, such as after rotating the host key. Invoices that already have a current signature from the key are left alone. A running server only returns the new signatures from queries once it is restarted"
+    )]
+    Resign(Resign),
+}
+
+#[derive(Clap)]
+struct Resign {
+    #[clap(
+        name = "role",
+        long = "role",
+        default_value = "host",
+        about = "the role to sign in"
+    )]
+    role: SignatureRole,
+    #[clap(
+        name = "key",
+        long = "key",
+        about = "the path to a secret key file (as created by `bindle keys create-key`) containing the key to sign with. The passphrase is read from BINDLE_SIGNING_KEY_PASSPHRASE, or asked for if that is not set"
+    )]
+    key: PathBuf,
+    #[clap(
+        name = "key_label",
+        long = "key-label",
+        about = "the label or base64 encoded public key of the key to sign with. Defaults to the first key with the role"
+    )]
+    key_label: Option<String>,
+    #[clap(
+        name = "filter",
+        long = "filter",
+        number_of_values = 1,
+        about = "only re-sign invoices matching the filter, either `name:PATTERN`, where a trailing `*` matches any name starting with the rest of the pattern, or `version:REQUIREMENT` with a SemVer requirement like `>=1.2`. Can be given multiple times, in which case all filters must match"
+    )]
+    filters: Vec<Filter>,
+    #[clap(
+        name = "max_age",
+        long = "max-age",
+        about = "treat signatures in the role that are older than this many days as expired and replace them, including ones made with the new key"
+    )]
+    max_age: Option<u64>,
+    #[clap(
+        name = "replace",
+        long = "replace",
+        about = "remove all other signatures in the role, such as the ones made with a key that is being rotated out"
+    )]
+    replace: bool,
+    #[clap(
+        name = "dry_run",
+        long = "dry-run",
+        about = "only report which invoices would be re-signed, without changing anything"
+    )]
+    dry_run: bool,
+    #[clap(
+        name = "event_hook",
+        long = "event-hook",
+        env = "BINDLE_EVENT_HOOKS",
+        number_of_values = 1,
+        use_delimiter = true,
+        about = "a URL that is sent a key_rotated JSON event for every re-signed invoice, the same way the server sends its events. Can be given multiple times"
+    )]
+    event_hooks: Vec<String>,
+    #[clap(
+        name = "event_hook_secret",
+        long = "event-hook-secret",
+        env = "BINDLE_EVENT_HOOK_SECRET",
+        requires = "event_hook",
+        about = "a secret shared with the receivers of event hooks. If set, every event is signed with an HMAC-SHA256 of its body, which is sent in the X-Bindle-Signature header as `sha256=<hex>`"
+    )]
+    event_hook_secret: Option<String>,
+}
+
+/// A condition an invoice has to meet to be re-signed
+enum Filter {
+    Name(String),
+    Version(semver::VersionReq),
+}
+
+impl Filter {
+    fn matches(&self, id: &Id) -> bool {
+        match self {
+            Filter::Name(pattern) => match pattern.strip_suffix('*') {
+                Some(prefix) => id.name().starts_with(prefix),
+                None => id.name() == pattern,
+            },
+            Filter::Version(req) => req.matches(id.version()),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("name"), Some(pattern)) if !pattern.is_empty() => {
+                Ok(Filter::Name(pattern.to_owned()))
+            }
+            (Some("version"), Some(req)) => semver::VersionReq::parse(req)
+                .map(Filter::Version)
+                .map_err(|e| format!("Invalid version requirement {}: {}", req, e)),
+            _ => Err(format!(
+                "Invalid filter {}, expected name:PATTERN or version:REQUIREMENT",
+                s
+            )),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+    env_logger::init();
+
+    match opts.subcmd {
+        SubCommand::Resign(resign_opts) => resign(&opts.bindle_directory, resign_opts).await,
+    }
+}
+
+async fn resign(dir: &Path, opts: Resign) -> anyhow::Result<()> {
+    let key = load_key(&opts.key, opts.key_label.as_deref(), opts.role).await?;
+    let store = FileProvider::new(dir, NoopEngine::default()).await;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs();
+    let rules = Rules {
+        role: opts.role,
+        key: key.key_entry().key,
+        expired_before: opts
+            .max_age
+            .map(|days| now.saturating_sub(days * SECONDS_PER_DAY)),
+        replace: opts.replace,
+    };
+
+    let mut hooks = Vec::new();
+    for url in &opts.event_hooks {
+        let mut hook = HttpHook::new(url)
+            .map_err(|e| anyhow::anyhow!("Invalid event hook URL {}: {}", url, e))?;
+        if let Some(secret) = &opts.event_hook_secret {
+            hook = hook.secret(secret);
+        }
+        hooks.push(hook);
+    }
+
+    let ids: Vec<Id> = store
+        .invoice_ids()
+        .await?
+        .into_iter()
+        .filter(|id| opts.filters.iter().all(|f| f.matches(id)))
+        .collect();
+    let total = ids.len();
+    let (mut resigned, mut failed) = (0, 0);
+    for (i, id) in ids.iter().enumerate() {
+        let progress = format!("[{}/{}]", i + 1, total);
+        let inv = match store.get_yanked_invoice(id).await {
+            Ok(inv) => inv,
+            Err(e) => {
+                eprintln!("{} Unable to load {}: {}", progress, id, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let plan = match rules.apply(&inv) {
+            Some(plan) => plan,
+            None => {
+                println!("{} {} is up to date", progress, id);
+                continue;
+            }
+        };
+        let removed =
+            inv.signature.as_ref().map(Vec::len).unwrap_or_default() - plan.signatures.len();
+        if opts.dry_run {
+            println!(
+                "{} Would re-sign {} (removing {} signatures)",
+                progress, id, removed
+            );
+            resigned += 1;
+            continue;
+        }
+
+        match store_plan(&store, inv, plan, opts.role, &key).await {
+            Ok(()) => {
+                println!(
+                    "{} Re-signed {} (removed {} signatures)",
+                    progress, id, removed
+                );
+                resigned += 1;
+                let event = Event::from(KeyRotated {
+                    bindle_id: id.clone(),
+                    role: opts.role,
+                    key: rules.key.clone(),
+                });
+                for hook in &hooks {
+                    hook.on_event(&event).await;
+                }
+            }
+            Err(e) => {
+                eprintln!("{} Unable to re-sign {}: {}", progress, id, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} {} of {} matching invoices{}",
+        if opts.dry_run {
+            "Would re-sign"
+        } else {
+            "Re-signed"
+        },
+        resigned,
+        total,
+        if failed > 0 {
+            format!(", {} failed", failed)
+        } else {
+            String::new()
+        }
+    );
+    if failed > 0 {
+        anyhow::bail!("Unable to re-sign {} invoices", failed);
+    }
+    Ok(())
+}
+
+/// Decides which signatures of an invoice are kept when re-signing it
+struct Rules {
+    role: SignatureRole,
+    /// The base64 encoded public key of the new key
+    key: String,
+    /// Signatures in the role made before this UNIX timestamp are expired
+    expired_before: Option<u64>,
+    replace: bool,
+}
+
+/// The changes to the signatures of an invoice
+struct Plan {
+    /// The signatures to keep
+    signatures: Vec<Signature>,
+    /// Whether a signature with the new key needs to be added
+    sign: bool,
+}
+
+impl Rules {
+    /// Returns how the signatures of the invoice have to change, or `None` if the invoice already
+    /// has a current signature from the new key and nothing needs to be removed
+    fn apply(&self, inv: &Invoice) -> Option<Plan> {
+        let signatures = inv.signature.as_deref().unwrap_or_default();
+        let kept: Vec<Signature> = signatures
+            .iter()
+            .filter(|sig| self.keep(sig))
+            .cloned()
+            .collect();
+        let current = kept
+            .iter()
+            .any(|sig| sig.role == self.role && sig.key == self.key);
+        if current && kept.len() == signatures.len() {
+            return None;
+        }
+        Some(Plan {
+            signatures: kept,
+            sign: !current,
+        })
+    }
+
+    fn keep(&self, sig: &Signature) -> bool {
+        if sig.role != self.role {
+            return true;
+        }
+        let expired = matches!(self.expired_before, Some(before) if sig.at < before);
+        !expired && (!self.replace || sig.key == self.key)
+    }
+}
+
+/// Signs the invoice with the key if needed and stores its new signatures
+async fn store_plan(
+    store: &FileProvider<NoopEngine>,
+    mut inv: Invoice,
+    plan: Plan,
+    role: SignatureRole,
+    key: &SecretKeyEntry,
+) -> anyhow::Result<()> {
+    inv.signature = Some(plan.signatures);
+    if plan.sign {
+        inv.sign_with_key(role, key)?;
+    }
+    store
+        .replace_signatures(&inv.bindle.id, inv.signature.unwrap_or_default())
+        .await?;
+    Ok(())
+}
+
+/// Loads and decrypts the key with the given label or public key from the secret key file, or the
+/// first key with the role if no key is given
+async fn load_key(
+    path: &Path,
+    label_or_key: Option<&str>,
+    role: SignatureRole,
+) -> anyhow::Result<SecretKeyEntry> {
+    let keys = SecretKeyFile::load(path).await?;
+    let encrypted = match label_or_key {
+        Some(label_or_key) => keys.get(label_or_key),
+        None => keys.get_first_matching(role),
+    }
+    .ok_or_else(|| anyhow::anyhow!("No signing key found in {}", path.display()))?;
+    if !encrypted.roles.contains(&role) {
+        anyhow::bail!(
+            "Signing key {} does not have the {} role",
+            encrypted.label,
+            role
+        );
+    }
+    let passphrase = match std::env::var("BINDLE_SIGNING_KEY_PASSPHRASE") {
+        Ok(passphrase) => passphrase,
+        Err(_) => rpassword::read_password_from_tty(Some("Passphrase: "))?,
+    };
+    Ok(encrypted.decrypt(&passphrase)?)
+}
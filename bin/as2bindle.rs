@@ -99,7 +99,9 @@ async fn main() {
             conditions: None,
         }]),
         annotations: None,
+        requires: None,
         group: None,
+        signature: None,
     };
 
     if let Some(auth) = package.author {
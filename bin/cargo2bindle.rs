@@ -127,7 +127,9 @@ async fn main() {
         },
         parcel: None,
         annotations: None,
+        requires: None,
         group: None,
+        signature: None,
     };
 
     if !parcels.is_empty() {
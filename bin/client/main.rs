@@ -1,9 +1,20 @@
-use std::path::Path;
+#![recursion_limit = "256"]
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use bindle::client::{Client, ClientError, Result};
+use bindle::client::config::ClientConfig;
+use bindle::client::downloader::{DownloadOptions, Downloader};
+use bindle::client::progress::{ParcelStatus, Progress, ProgressCallback};
+use bindle::client::{tokens::DeviceFlow, Client, ClientError, Result, Timeouts, TokenCache};
 use bindle::provider::ProviderError;
-use bindle::standalone::{StandaloneRead, StandaloneWrite};
+use bindle::signature::{
+    KeyEntry, KeyRing, RevocationList, SecretKeyEntry, SecretKeyFile, VerificationStrategy,
+};
+use bindle::standalone::{ParcelPushStatus, PushOptions, StandaloneRead, StandaloneWrite};
+use bindle::trace::TraceParent;
+use bindle::Id;
 use bindle::{
     cache::{Cache, DumbCache},
     provider::Provider,
@@ -13,19 +24,134 @@ use clap::Clap;
 use log::{info, warn};
 use sha2::Digest;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
 
 mod opts;
 
 use opts::*;
 
 #[tokio::main]
-async fn main() -> std::result::Result<(), ClientError> {
+async fn main() {
     let opts = opts::Opts::parse();
     // TODO: Allow log level setting
     env_logger::init();
 
-    let bindle_client = Client::new(&opts.server_url)?;
+    let command = opts.subcmd.name();
+    let error_format = opts.error_format;
+    let telemetry_file = opts
+        .telemetry_file
+        .clone()
+        .unwrap_or_else(default_telemetry_file);
+
+    // All requests made for a command share one trace, so they can be found in the server's logs
+    let trace = TraceParent::new_root();
+    info!("Running command with trace ID {}", trace.trace_id());
+    let res = bindle::trace::scope(trace, run(opts)).await;
+    // Enabling or disabling telemetry shouldn't show up in the usage data
+    if command != "telemetry" {
+        record_usage(&telemetry_file, command, &res).await;
+    }
+    if let Err(e) = res {
+        let kind = e.kind();
+        match error_format {
+            ErrorFormat::Text => eprintln!("Error: {}", e),
+            ErrorFormat::Json => eprintln!(
+                "{}",
+                serde_json::json!({
+                    "error": e.to_string(),
+                    "kind": kind,
+                    "exitCode": kind.exit_code(),
+                })
+            ),
+        }
+        std::process::exit(kind.exit_code());
+    }
+}
+
+fn default_telemetry_file() -> PathBuf {
+    dirs::home_dir().unwrap().join(".bindle/telemetry.toml")
+}
+
+async fn run(opts: opts::Opts) -> Result<()> {
+    let config_file = opts
+        .config
+        .clone()
+        .or_else(ClientConfig::default_path)
+        .unwrap();
+    let profile = ClientConfig::load(&config_file)
+        .await?
+        .profile(opts.profile.as_deref())?;
+    let keyring_file = opts
+        .keyring
+        .clone()
+        .or_else(|| profile.keyring.clone())
+        .unwrap_or_else(|| dirs::home_dir().unwrap().join(".bindle/keyring.toml"));
+    // Managing local keys doesn't involve a server, so it is handled before setting up the client
+    match &opts.subcmd {
+        SubCommand::Keys(keys_opts) if !keys_opts.subcmd.needs_server() => {
+            let secret_keys_file = opts
+                .secret_keys
+                .clone()
+                .unwrap_or_else(|| dirs::home_dir().unwrap().join(".bindle/secret_keys.toml"));
+            return keys(&keyring_file, &secret_keys_file, keys_opts).await;
+        }
+        SubCommand::Telemetry(telemetry_opts) => {
+            let telemetry_file = opts
+                .telemetry_file
+                .clone()
+                .unwrap_or_else(default_telemetry_file);
+            return manage_telemetry(&telemetry_file, telemetry_opts).await;
+        }
+        SubCommand::Pack(pack_opts) => return pack(pack_opts).await,
+        _ => (),
+    }
+    let server_url = opts
+        .server_url
+        .or_else(|| profile.server_url.clone())
+        .ok_or_else(|| {
+            ClientError::InvalidConfig(
+                "A server URL must be given with --server, BINDLE_SERVER_URL or a profile"
+                    .to_string(),
+            )
+        })?;
+
+    // The profile's proxy and token apply first, so the flags can override the token
+    let mut builder = profile.apply(Client::builder()).await?;
+    let tokens = match (opts.token_file, profile.token_cache().await?) {
+        (Some(path), _) => TokenCache::load(&path).await?,
+        (None, Some(tokens)) => tokens,
+        (None, None) => {
+            TokenCache::load(dirs::home_dir().unwrap().join(".bindle/token.toml")).await?
+        }
+    };
+    builder = builder.token_cache(tokens.clone());
+    if opts.verification_strategy != VerificationStrategy::None {
+        let keyring = KeyRing::load(&keyring_file).await?;
+        builder = builder.verification(opts.verification_strategy, keyring);
+    }
+    if let Some(path) = &opts.revocation_list {
+        builder = builder.revocations(RevocationList::load(path).await?);
+    }
+    if let Some(key) = opts.transparency_log_key {
+        let key = base64::decode(&key)
+            .ok()
+            .and_then(|k| ed25519_dalek::PublicKey::from_bytes(&k).ok())
+            .ok_or_else(|| {
+                ClientError::InvalidConfig(format!("Invalid transparency log key {}", key))
+            })?;
+        builder = builder.transparency_log(key);
+    }
+    if let Some(path) = opts.ca_cert {
+        builder = builder.ca_certificates_file(path).await?;
+    }
+    if let (Some(cert), Some(key)) = (opts.client_cert, opts.client_key) {
+        builder = builder.identity_files(cert, key).await?;
+    }
+    builder = builder.timeouts(Timeouts {
+        invoice: opts.invoice_timeout.map(Duration::from_secs),
+        upload: opts.upload_timeout.map(Duration::from_secs),
+        download: opts.download_timeout.map(Duration::from_secs),
+    });
+    let bindle_client = builder.build(&server_url)?;
     let bindle_dir = opts
         .bindle_dir
         .unwrap_or_else(|| dirs::home_dir().unwrap().join(".bindle/bindles"));
@@ -39,14 +165,24 @@ async fn main() -> std::result::Result<(), ClientError> {
     let cache = DumbCache::new(proxy, local);
 
     match opts.subcmd {
+        SubCommand::Info(info_opts) if info_opts.summary => {
+            let id = resolve_bindle(&bindle_client, &info_opts.bindle_id).await?;
+            let summary = bindle_client.get_invoice_summary(id).await?;
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(&toml::to_vec(&summary)?).await?;
+            stdout.flush().await?;
+        }
         SubCommand::Info(info_opts) => {
+            let id = resolve_bindle(&bindle_client, &info_opts.bindle_id).await?;
             let inv = match info_opts.yanked {
-                true => cache.get_invoice(info_opts.bindle_id),
-                false => cache.get_yanked_invoice(info_opts.bindle_id),
+                true => cache.get_invoice(id),
+                false => cache.get_yanked_invoice(id),
             }
             .await
             .map_err(map_storage_error)?;
-            tokio::io::stdout().write_all(&toml::to_vec(&inv)?).await?;
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(&toml::to_vec(&inv)?).await?;
+            stdout.flush().await?;
         }
         SubCommand::GetInvoice(gi_opts) => {
             let inv = match gi_opts.yanked {
@@ -69,10 +205,27 @@ async fn main() -> std::result::Result<(), ClientError> {
             );
         }
         SubCommand::GetParcel(gp_opts) => get_parcel(cache, gp_opts).await?,
+        SubCommand::Compose(compose_opts) => compose(cache, compose_opts).await?,
         SubCommand::Yank(yank_opts) => {
             bindle_client.yank_invoice(&yank_opts.bindle_id).await?;
             println!("Bindle {} yanked", yank_opts.bindle_id);
         }
+        SubCommand::Delete(delete_opts) => {
+            let resp = bindle_client.delete_invoice(&delete_opts.bindle_id).await?;
+            // The deleted bindle shouldn't be served from the local cache either
+            match cache.delete_invoice(&delete_opts.bindle_id).await {
+                Ok(_) | Err(ProviderError::NotFound) => (),
+                Err(e) => warn!("Unable to remove deleted bindle from the cache: {}", e),
+            }
+            println!(
+                "Bindle {} deleted, along with {} parcels no other bindle uses",
+                delete_opts.bindle_id,
+                resp.removed_parcels.len()
+            );
+        }
+        SubCommand::Search(search_opts) if search_opts.watch => {
+            watch_search(bindle_client, search_opts).await?
+        }
         SubCommand::Search(search_opts) => {
             // TODO: Do we want to use the cache for searching?
             let matches = bindle_client.query_invoices(search_opts.into()).await?;
@@ -80,13 +233,24 @@ async fn main() -> std::result::Result<(), ClientError> {
                 .write_all(&toml::to_vec(&matches)?)
                 .await?;
         }
-        SubCommand::Get(get_opts) => get_all(cache, get_opts).await?,
+        SubCommand::Get(get_opts) => {
+            let id = resolve_bindle(&bindle_client, &get_opts.bindle_id).await?;
+            get_all(cache, id, get_opts).await?
+        }
         SubCommand::Push(push_opts) => push_all(bindle_client, push_opts).await?,
         SubCommand::PushInvoice(push_opts) => {
             let resp = bindle_client
                 .create_invoice_from_file(push_opts.path)
                 .await?;
             println!("Invoice {} created", resp.invoice.bindle.id);
+            if let Some(policy) = resp.signing_policy.filter(|p| !p.satisfied) {
+                let missing: Vec<String> = policy.missing.iter().map(|r| r.to_string()).collect();
+                println!(
+                    "The signing policy for {} still requires trusted signatures in the roles: {}",
+                    policy.bindles,
+                    missing.join(", ")
+                );
+            }
         }
         SubCommand::PushFile(push_opts) => {
             let label =
@@ -97,6 +261,7 @@ async fn main() -> std::result::Result<(), ClientError> {
                 .await?;
             println!("File successfully uploaded");
         }
+        SubCommand::Login(login_opts) => login(tokens, login_opts).await?,
         SubCommand::GenerateLabel(generate_opts) => {
             let label = generate_label(
                 generate_opts.path,
@@ -106,11 +271,175 @@ async fn main() -> std::result::Result<(), ClientError> {
             .await?;
             println!("{}", toml::to_string_pretty(&label)?);
         }
+        SubCommand::Keys(keys_opts) => sync_keys(&bindle_client, &keyring_file, &keys_opts).await?,
+        SubCommand::Signatures(sig_opts) => {
+            let id = resolve_bindle(&bindle_client, &sig_opts.bindle_id).await?;
+            // The signatures are checked below, so invoices failing the client's verification
+            // strategy are shown as well
+            let inv = bindle_client
+                .with_verification(VerificationStrategy::None, KeyRing::default())
+                .get_yanked_invoice(&id)
+                .await?;
+            let keyring = KeyRing::load(&keyring_file).await?;
+            print_signatures(&inv, &keyring, &keyring_file);
+        }
+        SubCommand::Ping => {
+            let capabilities = bindle_client.ping().await?;
+            println!("Server at {} is compatible", server_url);
+            println!("{}", toml::to_string_pretty(&capabilities)?);
+        }
+        // Handled before connecting to the server
+        SubCommand::Telemetry(_) | SubCommand::Pack(_) => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Counts the command and the category of the error it failed with, if the user has enabled
+/// telemetry. Telemetry must never make a command fail, so errors are only logged
+#[cfg(feature = "telemetry")]
+async fn record_usage(path: &Path, command: &str, res: &Result<()>) {
+    let mut telemetry = match bindle::telemetry::Telemetry::load(path).await {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Unable to load telemetry file {}: {}", path.display(), e);
+            return;
+        }
+    };
+    if !telemetry.is_enabled() {
+        return;
+    }
+    telemetry.record_command(command);
+    if let Err(e) = res {
+        telemetry.record_error(bindle::telemetry::error_category(e));
     }
+    if let Err(e) = telemetry.save().await {
+        warn!("Unable to save telemetry file {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(not(feature = "telemetry"))]
+async fn record_usage(_: &Path, _: &str, _: &Result<()>) {}
 
+#[cfg(feature = "telemetry")]
+async fn manage_telemetry(path: &Path, opts: &Telemetry) -> Result<()> {
+    use bindle::telemetry::disabled_by_env;
+
+    let mut telemetry = bindle::telemetry::Telemetry::load(path).await?;
+    match &opts.subcmd {
+        TelemetryCommand::Enable => {
+            telemetry.enable();
+            telemetry.save().await?;
+            println!(
+                "Telemetry enabled. Usage data is stored in {} and is never sent anywhere",
+                path.display()
+            );
+            if disabled_by_env() {
+                println!("Telemetry is currently disabled by the DO_NOT_TRACK or BINDLE_TELEMETRY_DISABLED environment variable");
+            }
+        }
+        TelemetryCommand::Disable => {
+            telemetry.disable();
+            telemetry.save().await?;
+            println!("Telemetry disabled and the collected data discarded");
+        }
+        TelemetryCommand::Status => {
+            let data = telemetry.data();
+            let status = match (data.enabled, disabled_by_env()) {
+                (false, _) => "disabled",
+                (true, true) => "disabled by the environment",
+                (true, false) => "enabled",
+            };
+            println!("Telemetry is {}", status);
+            if data.enabled {
+                println!(
+                    "{} commands counted in {}",
+                    data.commands.values().sum::<u64>(),
+                    path.display()
+                );
+            }
+        }
+        TelemetryCommand::Report(report_opts) => {
+            let report = toml::to_vec(&telemetry.report())?;
+            match &report_opts.output {
+                Some(output) => {
+                    tokio::fs::write(output, report).await?;
+                    println!("Wrote usage report to {}", output.display());
+                }
+                None => {
+                    let mut stdout = tokio::io::stdout();
+                    stdout.write_all(&report).await?;
+                    stdout.flush().await?;
+                }
+            }
+        }
+        TelemetryCommand::Clear => {
+            telemetry.clear();
+            telemetry.save().await?;
+            println!("Collected usage data discarded");
+        }
+    }
     Ok(())
 }
 
+#[cfg(not(feature = "telemetry"))]
+async fn manage_telemetry(_: &Path, _: &Telemetry) -> Result<()> {
+    Err(ClientError::InvalidConfig(
+        "This build of bindle doesn't include telemetry".to_owned(),
+    ))
+}
+
+/// Runs the search every `interval` seconds and prints the IDs of the matching bindles that weren't
+/// there before, until the process is stopped
+async fn watch_search(client: Client, opts: Search) -> Result<()> {
+    let interval = Duration::from_secs(opts.interval.max(1));
+    let options: bindle::QueryOptions = opts.into();
+    let mut seen = HashSet::new();
+    let mut first = true;
+    loop {
+        match matching_ids(&client, &options).await {
+            Ok(ids) => {
+                let mut stdout = tokio::io::stdout();
+                for id in ids {
+                    // Everything matching when the watch starts is only recorded
+                    if seen.insert(id.clone()) && !first {
+                        stdout.write_all(format!("{}\n", id).as_bytes()).await?;
+                    }
+                }
+                stdout.flush().await?;
+                if first {
+                    info!("Watching for new bindles, {} already match", seen.len());
+                    first = false;
+                }
+            }
+            // An invalid query won't get any better, but a server that is briefly unavailable
+            // shouldn't end the watch
+            Err(e) if first => return Err(e),
+            Err(e) => warn!("Unable to run query, retrying in {:?}: {}", interval, e),
+        }
+        tokio::time::delay_for(interval).await;
+    }
+}
+
+/// Returns the IDs of all bindles matching the query, going through every page of results
+async fn matching_ids(client: &Client, options: &bindle::QueryOptions) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    let mut offset = options.offset.unwrap_or(0);
+    loop {
+        let matches = client
+            .query_invoices(bindle::QueryOptions {
+                offset: Some(offset),
+                ..options.clone()
+            })
+            .await?;
+        offset += matches.invoices.len() as u64;
+        ids.extend(matches.invoices.iter().map(|i| i.bindle.id.to_string()));
+        if !matches.more || matches.invoices.is_empty() {
+            return Ok(ids);
+        }
+    }
+}
+
 async fn generate_label(
     file_path: impl AsRef<Path>,
     name: Option<String>,
@@ -143,113 +472,431 @@ async fn generate_label(
     })
 }
 
+async fn pack(opts: &Pack) -> Result<()> {
+    let manifest_file = opts
+        .manifest
+        .clone()
+        .unwrap_or_else(|| opts.dir.join(bindle::standalone::pack::MANIFEST_FILE));
+    let manifest = bindle::standalone::pack::PackManifest::load(&manifest_file).await?;
+    let path =
+        bindle::standalone::pack::pack_with_manifest(&opts.dir, &manifest, &opts.output).await?;
+    println!(
+        "Packed {} into standalone bindle {}",
+        opts.dir.display(),
+        path.display()
+    );
+    Ok(())
+}
+
 async fn get_parcel<C: Cache + Send + Sync + Clone>(cache: C, opts: GetParcel) -> Result<()> {
     let parcel = cache
         .get_parcel(opts.bindle_id, &opts.sha)
         .await
-        .map_err(map_storage_error)?;
+        .map_err(|e| match e {
+            ProviderError::NotFound => ClientError::ParcelNotFound,
+            e => map_storage_error(e),
+        })?;
     let mut file = tokio::fs::OpenOptions::new()
         .write(true)
         .create_new(true) // Make sure we aren't overwriting
         .open(&opts.output)
         .await?;
-    tokio::io::copy(&mut bindle::async_util::BodyReadBuffer(parcel), &mut file).await?;
+    tokio::io::copy(
+        &mut bindle::async_util::BodyReadBuffer::new(parcel),
+        &mut file,
+    )
+    .await?;
     println!("Wrote parcel {} to {}", opts.sha, opts.output.display());
     Ok(())
 }
 
+async fn compose<C: Cache + Send + Sync + Clone>(cache: C, opts: Compose) -> Result<()> {
+    let mut inputs = Vec::with_capacity(opts.from.len());
+    for id in opts.from {
+        inputs.push(cache.get_invoice(id).await.map_err(map_storage_error)?);
+    }
+    let inv = bindle::compose::compose(opts.bindle_id, &inputs)
+        .map_err(|e| ClientError::Other(e.to_string()))?;
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true) // Make sure we aren't overwriting
+        .open(&opts.output)
+        .await?
+        .write_all(&toml::to_vec(&inv)?)
+        .await?;
+    println!(
+        "Wrote invoice for composed bindle {} to {}",
+        inv.bindle.id,
+        opts.output.display()
+    );
+    Ok(())
+}
+
+async fn login(tokens: TokenCache, opts: Login) -> Result<()> {
+    let flow = DeviceFlow::discover(&opts.issuer_url, &opts.client_id, &opts.scopes).await?;
+    let code = flow.start().await?;
+    match &code.verification_uri_complete {
+        Some(uri) => println!("To log in, open {} in your browser", uri),
+        None => println!(
+            "To log in, open {} in your browser and enter the code {}",
+            code.verification_uri, code.user_code
+        ),
+    }
+    let token = flow.wait_for_token(&code).await?;
+    tokens.store(token).await?;
+    println!("Login successful");
+    Ok(())
+}
+
+async fn keys(keyring_file: &Path, secret_keys_file: &Path, opts: &Keys) -> Result<()> {
+    let mut keyring = KeyRing::load(keyring_file).await?;
+    match &opts.subcmd {
+        KeysCommand::Add(add_opts) => {
+            keyring.add_key(KeyEntry {
+                label: add_opts.label.clone(),
+                roles: add_opts.roles.clone(),
+                key: add_opts.key.clone(),
+                algorithm: add_opts.algorithm,
+                expires: add_opts.expires,
+            })?;
+            keyring.save(keyring_file).await?;
+            println!("Added key {} to {}", add_opts.label, keyring_file.display());
+        }
+        KeysCommand::List(list_opts) => {
+            let entries = match list_opts.role {
+                Some(role) => keyring
+                    .trusted_keys_for_role(role)
+                    .into_iter()
+                    .cloned()
+                    .collect(),
+                None => keyring.key,
+            };
+            let mut stdout = tokio::io::stdout();
+            stdout
+                .write_all(&toml::to_vec(&KeyRing::new(entries))?)
+                .await?;
+            stdout.flush().await?;
+        }
+        KeysCommand::Remove(remove_opts) => {
+            let removed = keyring.remove_key(&remove_opts.label_or_key);
+            if removed.is_empty() {
+                return Err(ClientError::Other(format!(
+                    "No key with the label or key {} was found in {}",
+                    remove_opts.label_or_key,
+                    keyring_file.display()
+                )));
+            }
+            keyring.save(keyring_file).await?;
+            for entry in removed {
+                println!(
+                    "Removed key {} from {}",
+                    entry.label,
+                    keyring_file.display()
+                );
+            }
+        }
+        KeysCommand::CreateKey(create_opts) => {
+            let passphrase = read_passphrase("BINDLE_KEY_PASSPHRASE", true)?;
+            let entry = SecretKeyEntry::generate(&create_opts.label, create_opts.roles.clone());
+            let mut secret_keys = SecretKeyFile::load(secret_keys_file).await?;
+            secret_keys.add_key(&entry, &passphrase)?;
+            secret_keys.save(secret_keys_file).await?;
+            println!(
+                "Created key {} in {}",
+                create_opts.label,
+                secret_keys_file.display()
+            );
+            if !create_opts.no_keyring {
+                keyring.add_key(entry.key_entry())?;
+                keyring.save(keyring_file).await?;
+                println!(
+                    "Added key {} to {}",
+                    create_opts.label,
+                    keyring_file.display()
+                );
+            }
+            println!(
+                "Public key: {}",
+                base64::encode(entry.public_key().as_bytes())
+            );
+        }
+        KeysCommand::Push(_) | KeysCommand::Pull(_) => {
+            unreachable!("Keyring sync is handled after setting up the client")
+        }
+    }
+    Ok(())
+}
+
+/// Pushes the keyring to or pulls it from the server
+async fn sync_keys(client: &Client, keyring_file: &Path, opts: &Keys) -> Result<()> {
+    match &opts.subcmd {
+        KeysCommand::Push(_) => {
+            let keyring = KeyRing::load(keyring_file).await?;
+            let passphrase = read_passphrase("BINDLE_KEYRING_PASSPHRASE", true)?;
+            client.push_keyring(&keyring, &passphrase).await?;
+            println!(
+                "Pushed keyring with {} keys to the server",
+                keyring.key.len()
+            );
+        }
+        KeysCommand::Pull(pull_opts) => {
+            let passphrase = read_passphrase("BINDLE_KEYRING_PASSPHRASE", false)?;
+            let remote = client.pull_keyring(&passphrase).await?.ok_or_else(|| {
+                ClientError::Other("No keyring is stored on the server".to_string())
+            })?;
+            let keyring = if pull_opts.merge {
+                let mut keyring = KeyRing::load(keyring_file).await?;
+                keyring.merge(remote)?;
+                keyring
+            } else {
+                remote
+            };
+            keyring.save(keyring_file).await?;
+            println!(
+                "Saved keyring with {} keys to {}",
+                keyring.key.len(),
+                keyring_file.display()
+            );
+        }
+        _ => unreachable!("Local key commands are handled before setting up the client"),
+    }
+    Ok(())
+}
+
+/// Reads a passphrase from the given environment variable, or prompts for it if the variable isn't
+/// set. A new passphrase is prompted for twice to catch typos
+fn read_passphrase(env_var: &str, new: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(env_var) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::read_password_from_tty(Some("Passphrase: "))?;
+    if new {
+        let confirmation = rpassword::read_password_from_tty(Some("Confirm passphrase: "))?;
+        if passphrase != confirmation {
+            return Err(ClientError::Other("Passphrases do not match".to_string()));
+        }
+    }
+    Ok(passphrase)
+}
+
 async fn push_all(client: Client, opts: Push) -> Result<()> {
     let standalone = StandaloneRead::new(opts.path, &opts.bindle_id).await?;
-    standalone.push(&client).await?;
-    println!("Pushed bindle {}", opts.bindle_id);
+    let report = standalone
+        .push_with_options(
+            &client,
+            PushOptions {
+                concurrency: opts.concurrency,
+                retries: opts.retries,
+                progress: Some(print_progress("Uploaded")),
+                ..Default::default()
+            },
+        )
+        .await?;
+    for parcel in report.parcels.iter() {
+        if let ParcelPushStatus::Failed(e) = &parcel.status {
+            println!(
+                "Failed to upload parcel {} after {} attempts: {}",
+                parcel.sha, parcel.attempts, e
+            )
+        }
+    }
+    if !report.is_complete() {
+        return Err(ClientError::Other(format!(
+            "Bindle {} is still missing parcels on the server: {}",
+            report.bindle_id,
+            report.missing.join(", ")
+        )));
+    }
+    println!(
+        "Pushed bindle {} ({} bytes uploaded) in {:?}",
+        opts.bindle_id,
+        report.bytes_uploaded(),
+        report.elapsed
+    );
     Ok(())
 }
 
-async fn get_all<C: Cache + Send + Sync + Clone>(cache: C, opts: Get) -> Result<()> {
+async fn get_all<C: Cache + Send + Sync + Clone>(cache: C, id: Id, opts: Get) -> Result<()> {
     let inv = match opts.yanked {
-        true => cache.get_invoice(opts.bindle_id),
-        false => cache.get_yanked_invoice(opts.bindle_id),
+        true => cache.get_invoice(id),
+        false => cache.get_yanked_invoice(id),
     }
     .await
     .map_err(map_storage_error)?;
 
     println!("Fetched invoice. Starting fetch of parcels");
 
-    let parcels = Arc::new(Mutex::new(std::collections::HashMap::new()));
-    let zero_vec = Vec::with_capacity(0);
-    let is_export = opts.export.is_some();
-    let parcel_fetch = inv
-        .parcel
-        .as_ref()
-        .unwrap_or(&zero_vec)
-        .iter()
-        .map(|p| {
-            (
-                p.label.sha256.clone(),
-                inv.bindle.id.clone(),
-                cache.clone(),
-                parcels.clone(),
-            )
-        })
-        .map(|(sha, bindle_id, c, parcels)| async move {
-            match c.get_parcel(bindle_id, &sha).await {
-                Ok(p) => {
-                    println!("Fetched parcel {}", sha);
-                    if is_export {
-                        parcels
-                            .lock()
-                            .await
-                            .insert(sha, bindle::async_util::BodyReadBuffer(p));
-                    }
-                }
-                Err(e) => {
-                    match e {
-                        ProviderError::NotFound => warn!("Parcel {} does not exist", sha),
-                        ProviderError::ProxyError(err)
-                            if matches!(err, ClientError::ParcelNotFound) =>
-                        {
-                            warn!("Parcel {} does not exist", sha)
-                        }
-                        // Only return an error if it isn't a not found error. By design, an invoice
-                        // can contain parcels that don't yet exist
-                        ProviderError::ProxyError(inner) => return Err(inner),
-                        _ => {
-                            return Err(ClientError::Other(format!(
-                                "Unable to get parcel {}: {:?}",
-                                sha, e
-                            )))
-                        }
+    let downloader = Downloader::new(
+        cache,
+        DownloadOptions {
+            concurrency: opts.concurrency,
+            retries: opts.retries,
+            progress: Some(print_progress("Fetched")),
+            ..Default::default()
+        },
+    );
+    let report = match opts.export {
+        None => downloader.fetch(&inv).await?,
+        Some(p) => {
+            let standalone = StandaloneWrite::new(p, &inv.bindle.id)?;
+            match opts.export_format {
+                ExportFormat::Dir => downloader.download(&inv, standalone.path()).await?,
+                ExportFormat::Tar => {
+                    // The parcels are downloaded next to the archive first, so an interrupted
+                    // export picks up where it stopped
+                    let mut staging = standalone.path().to_owned().into_os_string();
+                    staging.push(".partial");
+                    let staging = std::path::PathBuf::from(staging);
+                    let report = downloader.download(&inv, &staging).await?;
+                    let mut parcels = std::collections::HashMap::new();
+                    for label in report.downloaded.iter().chain(report.reused.iter()) {
+                        let path = staging
+                            .join(bindle::standalone::PARCEL_DIR)
+                            .join(format!("{}.dat", label.sha256));
+                        parcels.insert(label.sha256.clone(), tokio::fs::File::open(path).await?);
                     }
+                    let path = standalone.write_archive(inv, parcels).await?;
+                    tokio::fs::remove_dir_all(&staging).await?;
+                    println!("Wrote standalone bindle to {}", path.display());
+                    report
                 }
             }
-            Ok(())
-        });
-    futures::future::join_all(parcel_fetch)
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>>>()?;
-    if let Some(p) = opts.export {
-        let standalone = StandaloneWrite::new(p, &inv.bindle.id)?;
-        standalone
-            .write(
-                inv,
-                // All locks should be done at this point (as all futures exited), so panicing feels
-                // right here as it is an unrecoverable condition
-                Arc::try_unwrap(parcels)
-                    .map_err(|_| ClientError::Other("Unexpected lock error".to_string()))
-                    .unwrap()
-                    .into_inner(),
-            )
-            .await?;
-    }
+        }
+    };
+    println!(
+        "Fetched {} parcels ({} bytes), {} were already present and {} do not exist",
+        report.downloaded.len(),
+        report.bytes_downloaded(),
+        report.reused.len(),
+        report.missing.len()
+    );
 
     Ok(())
 }
 
+/// Returns a progress reporter that prints a line for each parcel of a transfer once it is done,
+/// using the given verb for the parcels that were transferred
+fn print_progress(verb: &'static str) -> ProgressCallback {
+    Arc::new(move |p: &Progress| {
+        let count = format!("[{}/{}]", p.done, p.total);
+        match &p.status {
+            ParcelStatus::Transferred => {
+                println!("{} {} parcel {} ({} bytes)", count, verb, p.sha, p.bytes)
+            }
+            ParcelStatus::Skipped => println!("{} Parcel {} is already present", count, p.sha),
+            ParcelStatus::Missing => println!("{} Parcel {} does not exist", count, p.sha),
+            ParcelStatus::Failed(e) => println!("{} Parcel {} failed: {}", count, p.sha, e),
+        }
+    })
+}
+
+/// Prints every signature of the invoice along with whether it is valid and trusted by the keyring
+fn print_signatures(inv: &bindle::Invoice, keyring: &KeyRing, keyring_file: &Path) {
+    let checks = inv.check_signatures(keyring);
+    if checks.is_empty() {
+        println!("{} is not signed", inv.bindle.id);
+        return;
+    }
+    println!(
+        "{} has {} signatures, {} of them trusted by the keyring at {}",
+        inv.bindle.id,
+        checks.len(),
+        checks.iter().filter(|c| c.trusted).count(),
+        keyring_file.display()
+    );
+    for check in checks {
+        let sig = &check.signature;
+        println!("\n{}: {}", sig.role, sig.by);
+        println!("  signed at: {}", format_timestamp(sig.at));
+        match &check.error {
+            None => println!("  signature: valid"),
+            Some(e) => println!("  signature: INVALID ({})", e),
+        }
+        match &check.key_entry {
+            Some(entry) if check.trusted => {
+                println!("  keyring:   trusted, key \"{}\"", entry.label)
+            }
+            Some(entry) if entry.is_expired_at(sig.at) => println!(
+                "  keyring:   key \"{}\", but it expired at {}",
+                entry.label,
+                format_timestamp(entry.expires.unwrap_or_default())
+            ),
+            Some(entry) if entry.has_role(sig.role) => {
+                println!("  keyring:   key \"{}\"", entry.label)
+            }
+            Some(entry) => println!(
+                "  keyring:   key \"{}\", but not trusted for the {} role",
+                entry.label, sig.role
+            ),
+            None => println!("  keyring:   not in the keyring"),
+        }
+        println!("  key:       {}", sig.key);
+    }
+}
+
+/// Formats a UNIX timestamp as a UTC date and time, like `2021-03-04 12:30:00 UTC`
+fn format_timestamp(secs: u64) -> String {
+    // Converts the days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let (days, time) = (secs / 86400, secs % 86400);
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Resolves a bindle given on the command line to an exact ID. Besides a full ID, this accepts a
+/// name, which resolves to its latest version, or `NAME@REQUIREMENT` (like `my/app@^1`), which
+/// resolves to the newest version matching the SemVer requirement
+async fn resolve_bindle(client: &Client, bindle: &str) -> Result<Id> {
+    let (name, requirement) = match bindle.split_once('@') {
+        Some(parts) => parts,
+        None => match bindle.parse() {
+            Ok(id) => return Ok(id),
+            Err(_) => (bindle, ""),
+        },
+    };
+    let id = client
+        .resolve_version(name, requirement)
+        .await
+        .inspect_err(|e| {
+            // The error stays a not found error, so the exit code tells scripts what happened
+            match e {
+                ClientError::InvoiceNotFound if requirement.is_empty() => {
+                    eprintln!("No unyanked version of {} was found", name)
+                }
+                ClientError::InvoiceNotFound => {
+                    eprintln!("No unyanked version of {} matches {}", name, requirement)
+                }
+                _ => (),
+            }
+        })?;
+    // Print to stderr, so it doesn't end up in the output of commands like info
+    eprintln!("Resolved {} to {}", bindle, id);
+    Ok(id)
+}
+
 fn map_storage_error(e: ProviderError) -> ClientError {
     match e {
         ProviderError::Io(e) => ClientError::Io(e),
         ProviderError::ProxyError(inner) => inner,
+        ProviderError::NotFound | ProviderError::Yanked => ClientError::InvoiceNotFound,
+        ProviderError::Exists => ClientError::InvoiceAlreadyExists,
+        ProviderError::InvalidSignature(e) => ClientError::SignatureError(e),
         _ => ClientError::Other(format!("{:?}", e)),
     }
 }
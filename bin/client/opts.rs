@@ -15,9 +15,21 @@ pub struct Opts {
         short = 's',
         long = "server",
         env = "BINDLE_SERVER_URL",
-        about = "The address of the bindle server"
+        about = "The address of the bindle server. Required by all commands except `keys`, unless set by the profile"
     )]
-    pub server_url: String,
+    pub server_url: Option<String>,
+    #[clap(
+        long = "config",
+        env = "BINDLE_CONFIG",
+        about = "The configuration file containing named profiles, defaults to $HOME/.bindle/config.toml"
+    )]
+    pub config: Option<PathBuf>,
+    #[clap(
+        long = "profile",
+        env = "BINDLE_PROFILE",
+        about = "The profile from the configuration file to use. Defaults to the file's defaultProfile, or the profile named `default`. Flags take precedence over the profile"
+    )]
+    pub profile: Option<String>,
     #[clap(
         short = 'd',
         long = "bindle-dir",
@@ -25,10 +37,118 @@ pub struct Opts {
         about = "The directory where bindles are stored/cached, defaults to $HOME/.bindle/bindles"
     )]
     pub bindle_dir: Option<PathBuf>,
+    #[clap(
+        long = "token-file",
+        env = "BINDLE_TOKEN_FILE",
+        about = "The file where access tokens obtained with `bindle login` are stored, defaults to $HOME/.bindle/token.toml"
+    )]
+    pub token_file: Option<PathBuf>,
+    #[clap(
+        long = "ca-cert",
+        env = "BINDLE_CA_CERT",
+        about = "A PEM file containing additional CA certificates to trust when connecting to the server"
+    )]
+    pub ca_cert: Option<PathBuf>,
+    #[clap(
+        long = "client-cert",
+        env = "BINDLE_CLIENT_CERT",
+        requires = "client-key",
+        about = "A PEM file containing the client certificate to present to servers that require mutual TLS. Requires --client-key"
+    )]
+    pub client_cert: Option<PathBuf>,
+    #[clap(
+        long = "client-key",
+        env = "BINDLE_CLIENT_KEY",
+        requires = "client-cert",
+        about = "A PEM file containing the private key for the client certificate. Requires --client-cert"
+    )]
+    pub client_key: Option<PathBuf>,
+    #[clap(
+        long = "keyring",
+        env = "BINDLE_KEYRING",
+        about = "The keyring file of trusted public keys, defaults to $HOME/.bindle/keyring.toml"
+    )]
+    pub keyring: Option<PathBuf>,
+    #[clap(
+        long = "secret-keys",
+        env = "BINDLE_SECRET_KEYS",
+        about = "The file of encrypted secret keys used for signing, defaults to $HOME/.bindle/secret_keys.toml"
+    )]
+    pub secret_keys: Option<PathBuf>,
+    #[clap(
+        long = "telemetry-file",
+        env = "BINDLE_TELEMETRY_FILE",
+        about = "The file where usage telemetry is stored if it was enabled with `bindle telemetry enable`, defaults to $HOME/.bindle/telemetry.toml"
+    )]
+    pub telemetry_file: Option<PathBuf>,
+    #[clap(
+        long = "verification-strategy",
+        env = "BINDLE_VERIFICATION_STRATEGY",
+        default_value = "None",
+        about = "How fetched invoices are verified against the keyring: None, CreativeIntegrity, AuthoritativeIntegrity or GreedyVerification. Invoices that fail verification are rejected"
+    )]
+    pub verification_strategy: bindle::signature::VerificationStrategy,
+    #[clap(
+        long = "revocation-list",
+        env = "BINDLE_REVOCATION_LIST",
+        about = "The path to a TOML list of revoked keys. If set, fetched invoices with signatures made by a revoked key are rejected when verifying them"
+    )]
+    pub revocation_list: Option<PathBuf>,
+    #[clap(
+        long = "transparency-log-key",
+        env = "BINDLE_TRANSPARENCY_LOG_KEY",
+        about = "The base64 encoded public key of a trusted transparency log. If set, fetched invoices are rejected unless their signatures were recorded in that log"
+    )]
+    pub transparency_log_key: Option<String>,
+    #[clap(
+        long = "invoice-timeout",
+        env = "BINDLE_INVOICE_TIMEOUT",
+        about = "The number of seconds after which requests that don't transfer parcel data (like fetching or querying invoices) are aborted. Defaults to no timeout"
+    )]
+    pub invoice_timeout: Option<u64>,
+    #[clap(
+        long = "upload-timeout",
+        env = "BINDLE_UPLOAD_TIMEOUT",
+        about = "The number of seconds after which a parcel upload is aborted. Defaults to no timeout"
+    )]
+    pub upload_timeout: Option<u64>,
+    #[clap(
+        long = "download-timeout",
+        env = "BINDLE_DOWNLOAD_TIMEOUT",
+        about = "The number of seconds after which a parcel download is aborted. Defaults to no timeout"
+    )]
+    pub download_timeout: Option<u64>,
+    #[clap(
+        long = "error-format",
+        env = "BINDLE_ERROR_FORMAT",
+        default_value = "text",
+        possible_values = &["text", "json"],
+        about = "how errors are printed to stderr: text, or a JSON object with the message, kind and exit code. The exit code is 3 for not found, 4 for verification failures, 5 for authentication failures, 6 for network errors, 7 for conflicts, 2 for invalid usage and 1 for anything else"
+    )]
+    pub error_format: ErrorFormat,
     #[clap(subcommand)]
     pub subcmd: SubCommand,
 }
 
+/// The formats errors can be printed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format {}", s)),
+        }
+    }
+}
+
 #[derive(Clap)]
 pub enum SubCommand {
     #[clap(name = "info", about = "get the bindle invoice and display it")]
@@ -49,6 +169,11 @@ pub enum SubCommand {
     Get(Get),
     #[clap(name = "yank", about = "yank an existing bindle")]
     Yank(Yank),
+    #[clap(
+        name = "delete",
+        about = "permanently delete a bindle and any of its parcels no other bindle uses. Unlike yanking, this cannot be undone"
+    )]
+    Delete(Delete),
     #[clap(name = "search", about = "search for bindles")]
     Search(Search),
     #[clap(
@@ -66,11 +191,73 @@ pub enum SubCommand {
         about = "generates a label for the given file and prints it to stdout. This can be used to generate the label and add it to an invoice"
     )]
     GenerateLabel(GenerateLabel),
+    #[clap(
+        name = "login",
+        about = "log in to an OpenID Connect identity provider and store the access token for future requests"
+    )]
+    Login(Login),
+    #[clap(
+        name = "compose",
+        about = "compose a new bindle that references all of the parcels of the given bindles and write its invoice to a file"
+    )]
+    Compose(Compose),
+    #[clap(
+        name = "pack",
+        about = "pack a directory of files into a standalone bindle, described by the bindle-pack.toml manifest in the directory. Does not need a server"
+    )]
+    Pack(Pack),
+    #[clap(name = "keys", about = "manage the keyring of trusted public keys")]
+    Keys(Keys),
+    #[clap(
+        name = "signatures",
+        about = "show who signed a bindle, and whether each signature is valid and made by a key in the keyring"
+    )]
+    Signatures(Signatures),
+    #[clap(
+        name = "ping",
+        about = "check that the server is reachable and compatible with this client, and print its capabilities"
+    )]
+    Ping,
+    #[clap(
+        name = "telemetry",
+        about = "manage the opt-in usage telemetry, which only counts the commands run and the kinds of errors they failed with and is never sent anywhere"
+    )]
+    Telemetry(Telemetry),
+}
+
+impl SubCommand {
+    /// Returns the name of the command, as recorded by telemetry
+    pub fn name(&self) -> &'static str {
+        match self {
+            SubCommand::Info(_) => "info",
+            SubCommand::Push(_) => "push",
+            SubCommand::PushInvoice(_) => "push-invoice",
+            SubCommand::PushFile(_) => "push-file",
+            SubCommand::Get(_) => "get",
+            SubCommand::Yank(_) => "yank",
+            SubCommand::Delete(_) => "delete",
+            SubCommand::Search(_) => "search",
+            SubCommand::GetParcel(_) => "get-parcel",
+            SubCommand::GetInvoice(_) => "get-invoice",
+            SubCommand::GenerateLabel(_) => "generate-label",
+            SubCommand::Login(_) => "login",
+            SubCommand::Compose(_) => "compose",
+            SubCommand::Pack(_) => "pack",
+            SubCommand::Keys(_) => "keys",
+            SubCommand::Signatures(_) => "signatures",
+            SubCommand::Ping => "ping",
+            SubCommand::Telemetry(_) => "telemetry",
+        }
+    }
 }
 
 #[derive(Clap)]
 pub struct Info {
-    #[clap(index = 1, value_name = "BINDLE")]
+    #[clap(
+        index = 1,
+        value_name = "BINDLE",
+        about = "the bindle: either a full ID, a name to use its latest version, or NAME@REQUIREMENT (such as my/app@^1) to use the newest version matching a SemVer requirement. Yanked versions are never picked"
+    )]
     pub bindle_id: String,
     #[clap(
         short = 'y',
@@ -78,6 +265,21 @@ pub struct Info {
         about = "whether or not to fetch a yanked bindle. If you attempt to fetch a yanked bindle without this set, it will error"
     )]
     pub yanked: bool,
+    #[clap(
+        long = "summary",
+        about = "only display a summary of the bindle (such as its parcel count and total size) fetched from the server, without downloading the whole invoice"
+    )]
+    pub summary: bool,
+}
+
+#[derive(Clap)]
+pub struct Signatures {
+    #[clap(
+        index = 1,
+        value_name = "BINDLE",
+        about = "the bindle: either a full ID, a name to use its latest version, or NAME@REQUIREMENT (such as my/app@^1) to use the newest version matching a SemVer requirement. Yanked bindles can be given by their full ID"
+    )]
+    pub bindle_id: String,
 }
 
 #[derive(Clap)]
@@ -91,11 +293,28 @@ pub struct Push {
         about = "a path where the standalone bindle directory is located"
     )]
     pub path: PathBuf,
+    #[clap(
+        short = 'c',
+        long = "concurrency",
+        default_value = "4",
+        about = "the maximum number of parcels to upload at the same time"
+    )]
+    pub concurrency: usize,
+    #[clap(
+        long = "retries",
+        default_value = "3",
+        about = "the number of times to retry a parcel upload that failed due to a network or server error"
+    )]
+    pub retries: u32,
 }
 
 #[derive(Clap)]
 pub struct Get {
-    #[clap(index = 1, value_name = "BINDLE")]
+    #[clap(
+        index = 1,
+        value_name = "BINDLE",
+        about = "the bindle: either a full ID, a name to use its latest version, or NAME@REQUIREMENT (such as my/app@^1) to use the newest version matching a SemVer requirement. Yanked versions are never picked"
+    )]
     pub bindle_id: String,
     #[clap(
         short = 'y',
@@ -109,6 +328,45 @@ pub struct Get {
         about = "if specified, export the bindle as a standlone bindle in the given directory"
     )]
     pub export: Option<PathBuf>,
+    #[clap(
+        long = "export-format",
+        default_value = "dir",
+        possible_values = &["dir", "tar"],
+        about = "the format to export the bindle in: a directory, or a single gzipped tarball (`<bindle sha>.bindle.tar.gz`) in the export directory"
+    )]
+    pub export_format: ExportFormat,
+    #[clap(
+        short = 'c',
+        long = "concurrency",
+        default_value = "4",
+        about = "the maximum number of parcels to fetch at the same time"
+    )]
+    pub concurrency: usize,
+    #[clap(
+        long = "retries",
+        default_value = "3",
+        about = "the number of times to retry a parcel fetch that failed due to a network or server error"
+    )]
+    pub retries: u32,
+}
+
+/// The formats a standalone bindle can be exported in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Dir,
+    Tar,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dir" => Ok(ExportFormat::Dir),
+            "tar" => Ok(ExportFormat::Tar),
+            _ => Err(format!("unknown export format {}", s)),
+        }
+    }
 }
 
 #[derive(Clap)]
@@ -117,6 +375,12 @@ pub struct Yank {
     pub bindle_id: String,
 }
 
+#[derive(Clap)]
+pub struct Delete {
+    #[clap(index = 1, value_name = "BINDLE")]
+    pub bindle_id: String,
+}
+
 const VERSION_QUERY: &str = r#"version constraint of the bindle to search for. This is a semver range modifier that can either denote an exact version, or a range of versions.
 
 For example, the range modifier `v=1.0.0-beta.1` indicates that a version MUST match version `1.0.0-beta.1`. Version `1.0.0-beta.12` does NOT match this modifier. 
@@ -158,6 +422,41 @@ pub struct Search {
         about = "whether or not to include yanked bindles in the search result"
     )]
     pub yanked: Option<bool>,
+    #[clap(
+        long = "federated",
+        about = "whether or not to include results from the peer registries of a federated server"
+    )]
+    pub federated: Option<bool>,
+    #[clap(
+        long = "distinct",
+        about = "whether or not to only return the latest version of each bindle"
+    )]
+    pub distinct: Option<bool>,
+    #[clap(
+        long = "annotation",
+        number_of_values = 1,
+        parse(try_from_str = parse_annotation),
+        about = "only match bindles with the given invoice annotation, as KEY=VALUE. Can be given multiple times"
+    )]
+    pub annotations: Vec<(String, String)>,
+    #[clap(
+        long = "media-type",
+        number_of_values = 1,
+        about = "only match bindles with a parcel of the given media type. Can be given multiple times to match any of them"
+    )]
+    pub media_types: Vec<String>,
+    #[clap(
+        long = "watch",
+        about = "keep running the query and print the IDs of matching bindles as they appear",
+        long_about = "keep running the query and print the ID of each matching bindle that appears after the watch was started, one per line. All pages of results are checked every time, so --limit only sets the page size. Runs until interrupted"
+    )]
+    pub watch: bool,
+    #[clap(
+        long = "interval",
+        default_value = "30",
+        about = "the number of seconds to wait between queries in watch mode"
+    )]
+    pub interval: u64,
 }
 
 impl From<Search> for bindle::QueryOptions {
@@ -169,10 +468,21 @@ impl From<Search> for bindle::QueryOptions {
             limit: s.limit,
             strict: s.strict,
             yanked: s.yanked,
+            federated: s.federated,
+            distinct: s.distinct,
+            annotations: s.annotations.into_iter().collect(),
+            media_type: Some(s.media_types.join(",")).filter(|m| !m.is_empty()),
         }
     }
 }
 
+fn parse_annotation(s: &str) -> Result<(String, String), String> {
+    match s.find('=') {
+        Some(i) if i > 0 => Ok((s[..i].to_owned(), s[i + 1..].to_owned())),
+        _ => Err(format!("Invalid annotation {}, expected KEY=VALUE", s)),
+    }
+}
+
 #[derive(Clap)]
 pub struct GetParcel {
     #[clap(index = 1, value_name = "BINDLE_ID")]
@@ -250,3 +560,241 @@ pub struct PushFile {
     )]
     pub media_type: Option<String>,
 }
+
+#[derive(Clap)]
+pub struct Login {
+    #[clap(
+        long = "issuer-url",
+        env = "BINDLE_OIDC_ISSUER_URL",
+        about = "the URL of the OpenID Connect identity provider"
+    )]
+    pub issuer_url: String,
+    #[clap(
+        long = "client-id",
+        env = "BINDLE_OIDC_CLIENT_ID",
+        about = "the client ID of the bindle client registered with the identity provider"
+    )]
+    pub client_id: String,
+    #[clap(
+        long = "scope",
+        multiple_occurrences = true,
+        about = "additional scopes to request. The openid and offline_access scopes are always requested"
+    )]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct Compose {
+    #[clap(index = 1, value_name = "OUTPUT")]
+    pub output: PathBuf,
+    #[clap(
+        short = 'i',
+        long = "bindle-id",
+        about = "the ID of the new bindle (e.g. example.com/meta/1.0.0)"
+    )]
+    pub bindle_id: bindle::Id,
+    #[clap(
+        short = 'f',
+        long = "from",
+        required = true,
+        number_of_values = 1,
+        about = "a bindle to include in the new bindle. Can be given multiple times"
+    )]
+    pub from: Vec<bindle::Id>,
+}
+
+#[derive(Clap)]
+pub struct Pack {
+    #[clap(index = 1, value_name = "DIR")]
+    pub dir: PathBuf,
+    #[clap(
+        short = 'm',
+        long = "manifest",
+        about = "the manifest describing the bindle, defaults to bindle-pack.toml in the directory"
+    )]
+    pub manifest: Option<PathBuf>,
+    #[clap(
+        short = 'o',
+        long = "output",
+        default_value = ".",
+        about = "the directory to write the standalone bindle to. It is written to a subdirectory named after the SHA of the bindle ID"
+    )]
+    pub output: PathBuf,
+}
+
+#[derive(Clap)]
+pub struct Keys {
+    #[clap(subcommand)]
+    pub subcmd: KeysCommand,
+}
+
+#[derive(Clap)]
+pub enum KeysCommand {
+    #[clap(
+        name = "add",
+        about = "add a public key to the keyring, replacing any existing entry for the same key"
+    )]
+    Add(AddKey),
+    #[clap(name = "list", about = "list the keys in the keyring")]
+    List(ListKeys),
+    #[clap(
+        name = "remove",
+        about = "remove all keys with the given label or base64 encoded key from the keyring"
+    )]
+    Remove(RemoveKey),
+    #[clap(
+        name = "create-key",
+        about = "generate a new signing key, storing the secret key encrypted with a passphrase and adding the public key to the keyring. The passphrase is read from BINDLE_KEY_PASSPHRASE or prompted for"
+    )]
+    CreateKey(CreateKey),
+    #[clap(
+        name = "push",
+        about = "encrypt the keyring and store it on the server for the current user, replacing any keyring stored before. The passphrase is read from BINDLE_KEYRING_PASSPHRASE or prompted for"
+    )]
+    Push(PushKeys),
+    #[clap(
+        name = "pull",
+        about = "fetch the keyring stored on the server for the current user and replace the local keyring with it. The passphrase is read from BINDLE_KEYRING_PASSPHRASE or prompted for"
+    )]
+    Pull(PullKeys),
+}
+
+impl KeysCommand {
+    /// Returns whether the command needs to talk to a server
+    pub fn needs_server(&self) -> bool {
+        matches!(self, KeysCommand::Push(_) | KeysCommand::Pull(_))
+    }
+}
+
+#[derive(Clap)]
+pub struct AddKey {
+    #[clap(
+        index = 1,
+        value_name = "LABEL",
+        about = "a label for the key, generally in the form `Name <email>`"
+    )]
+    pub label: String,
+    #[clap(index = 2, value_name = "KEY", about = "the base64 encoded public key")]
+    pub key: String,
+    #[clap(
+        short = 'r',
+        long = "role",
+        required = true,
+        number_of_values = 1,
+        about = "a role the key is trusted for (creator, proxy, host or approver). Can be given multiple times"
+    )]
+    pub roles: Vec<bindle::signature::SignatureRole>,
+    #[clap(
+        short = 'a',
+        long = "algorithm",
+        default_value = "ed25519",
+        about = "the algorithm of the key (ed25519 or ecdsa-p256)"
+    )]
+    pub algorithm: bindle::signature::SignatureAlgorithm,
+    #[clap(
+        long = "expires",
+        value_name = "TIMESTAMP",
+        about = "the UNIX timestamp (in seconds) from which signatures made with the key are no longer trusted"
+    )]
+    pub expires: Option<u64>,
+}
+
+#[derive(Clap)]
+pub struct ListKeys {
+    #[clap(
+        short = 'r',
+        long = "role",
+        about = "only list the keys trusted for the given role"
+    )]
+    pub role: Option<bindle::signature::SignatureRole>,
+}
+
+#[derive(Clap)]
+pub struct RemoveKey {
+    #[clap(index = 1, value_name = "LABEL_OR_KEY")]
+    pub label_or_key: String,
+}
+
+#[derive(Clap)]
+pub struct CreateKey {
+    #[clap(
+        index = 1,
+        value_name = "LABEL",
+        about = "a label for the key, generally in the form `Name <email>`. It is used as the name of the signer"
+    )]
+    pub label: String,
+    #[clap(
+        short = 'r',
+        long = "role",
+        required = true,
+        number_of_values = 1,
+        about = "a role the key signs in (creator, proxy, host or approver). Can be given multiple times"
+    )]
+    pub roles: Vec<bindle::signature::SignatureRole>,
+    #[clap(
+        long = "no-keyring",
+        about = "don't add the public key to the keyring of trusted keys"
+    )]
+    pub no_keyring: bool,
+}
+
+#[derive(Clap)]
+pub struct PushKeys {}
+
+#[derive(Clap)]
+pub struct PullKeys {
+    #[clap(
+        long = "merge",
+        about = "add the keys from the server to the local keyring instead of replacing it"
+    )]
+    pub merge: bool,
+}
+
+// The telemetry commands only report an error when telemetry isn't compiled in
+#[derive(Clap)]
+#[cfg_attr(not(feature = "telemetry"), allow(dead_code))]
+pub struct Telemetry {
+    #[clap(subcommand)]
+    pub subcmd: TelemetryCommand,
+}
+
+#[derive(Clap)]
+#[cfg_attr(not(feature = "telemetry"), allow(dead_code))]
+pub enum TelemetryCommand {
+    #[clap(
+        name = "enable",
+        about = "start counting the commands run and the kinds of errors they fail with"
+    )]
+    Enable,
+    #[clap(
+        name = "disable",
+        about = "stop collecting usage data and discard the data collected so far"
+    )]
+    Disable,
+    #[clap(
+        name = "status",
+        about = "show whether telemetry is enabled and how many commands were counted"
+    )]
+    Status,
+    #[clap(
+        name = "report",
+        about = "print a report of the collected usage data, which can be shared with the maintainers"
+    )]
+    Report(TelemetryReport),
+    #[clap(
+        name = "clear",
+        about = "discard the data collected so far, without disabling telemetry"
+    )]
+    Clear,
+}
+
+#[derive(Clap)]
+#[cfg_attr(not(feature = "telemetry"), allow(dead_code))]
+pub struct TelemetryReport {
+    #[clap(
+        short = 'o',
+        long = "output",
+        about = "a file to write the report to instead of stdout"
+    )]
+    pub output: Option<PathBuf>,
+}
@@ -1,11 +1,42 @@
+#![recursion_limit = "256"]
+
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Clap;
+use ed25519_dalek::PublicKey;
 
 use bindle::{
-    provider, search,
-    server::{server, TlsConfig},
+    client::Client,
+    provider::{
+        self,
+        expiry::ExpiryAction,
+        file::layout::{PathTemplate, StorageLayout},
+        hooks::{HookedProvider, HttpHook},
+        mirror::MirrorProvider,
+        worm::WormProvider,
+        Provider,
+    },
+    replication::{ReplicationHandle, Replicator},
+    search,
+    server::{
+        auth::{Authenticator, BasicAuthenticator, BearerAuthenticator, NoopAuthenticator},
+        authz::{AllowAll, Authorizer, RolePolicy},
+        processing::{ParcelDigestCheck, Pipeline, SignatureCheck},
+        server,
+        tenancy::{self, Tenant},
+        ApiOptions, CrawlerPolicy, DispositionPolicy, InProcessOptions, Metrics, QuotaPolicy,
+        Quotas, RequestMonitor, RequestThresholds, ServerConfig, SigningPolicy, TlsConfig,
+        DEFAULT_DRAIN_TIMEOUT,
+    },
+    signature::{
+        KeyRing, RevocationList, SecretKeyEntry, SecretKeyFile, SignatureRole, VerificationStrategy,
+    },
+    tasks::{RestartPolicy, TaskRegistry},
+    transparency::{HttpLog, TransparencyLog},
+    QueryOptions,
 };
 
 const DESCRIPTION: &str = r#"
@@ -15,26 +46,35 @@ Bindle is a technology for storing and retrieving aggregate applications.
 This program runs an HTTP frontend for a Bindle repository.
 "#;
 
+/// How long background jobs, such as processing bindles or delivering events to hooks, are given
+/// to finish once the server stopped serving requests
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
 #[derive(Clap)]
 #[clap(name = "bindle-server", version = clap::crate_version!(), author = "DeisLabs at Microsoft Azure", about = DESCRIPTION)]
 struct Opts {
+    #[clap(
+        name = "config",
+        long = "config",
+        env = "BINDLE_CONFIG",
+        about = "the path to a TOML or YAML (with a .yaml or .yml extension) configuration file. Flags and their environment variables take precedence over the settings in it"
+    )]
+    config: Option<PathBuf>,
     #[clap(
         short = 'i',
         long = "address",
         env = "BINDLE_IP_ADDRESS_PORT",
-        default_value = "127.0.0.1:8080",
-        about = "the IP address and port to listen on"
+        about = "the IP address and port to listen on. Defaults to 127.0.0.1:8080"
     )]
-    address: String,
+    address: Option<SocketAddr>,
     #[clap(
         name = "bindle_directory",
         short = 'd',
         long = "directory",
         env = "BINDLE_DIRECTORY",
-        default_value = "/tmp",
-        about = "the path to the directory in which bindles will be stored"
+        about = "the path to the directory in which bindles will be stored. Defaults to /tmp"
     )]
-    bindle_directory: PathBuf,
+    bindle_directory: Option<PathBuf>,
     #[clap(
         name = "cert_path",
         short = 'c',
@@ -53,21 +93,412 @@ struct Opts {
         about = "the path to the TLS certificate key to use. If set, --cert-path must be set as well. If not set, the server will use HTTP"
     )]
     key_path: Option<PathBuf>,
+    #[clap(
+        name = "client_ca_path",
+        long = "client-ca-path",
+        env = "BINDLE_CLIENT_CA_PATH",
+        requires = "cert_path",
+        about = "the path to the CA certificate(s) used to verify client certificates. If set, clients must present a certificate signed by one of these CAs (mutual TLS)"
+    )]
+    client_ca_path: Option<PathBuf>,
+    #[cfg(feature = "postgres")]
+    #[clap(
+        name = "postgres_url",
+        long = "postgres-url",
+        env = "BINDLE_POSTGRES_URL",
+        about = "the connection string of a Postgres database to persist the search index in. If not set, an in memory index will be used"
+    )]
+    postgres_url: Option<String>,
+    #[clap(
+        name = "htpasswd_file",
+        long = "htpasswd-file",
+        env = "BINDLE_HTPASSWD_FILE",
+        conflicts_with = "token_file",
+        about = "the path to an htpasswd file (with bcrypt hashed passwords) used for HTTP Basic authentication. If neither this nor --token-file is set, authentication is disabled"
+    )]
+    htpasswd_file: Option<PathBuf>,
+    #[clap(
+        name = "token_file",
+        long = "token-file",
+        env = "BINDLE_TOKEN_FILE",
+        about = "the path to a file containing `NAME:SHA256_OF_TOKEN` lines used for bearer token authentication"
+    )]
+    token_file: Option<PathBuf>,
+    #[clap(
+        name = "protect_reads",
+        long = "protect-reads",
+        env = "BINDLE_PROTECT_READS",
+        about = "require credentials for reading bindles as well. By default, only creating and yanking bindles requires credentials"
+    )]
+    protect_reads: bool,
+    #[clap(
+        name = "policy_file",
+        long = "policy-file",
+        env = "BINDLE_POLICY_FILE",
+        about = "the path to a TOML file granting roles to identities for bindles. If not set, any authenticated identity can create and yank any bindle"
+    )]
+    policy_file: Option<PathBuf>,
+    #[clap(
+        name = "peer",
+        long = "peer",
+        env = "BINDLE_PEERS",
+        number_of_values = 1,
+        use_delimiter = true,
+        about = "a peer registry to include in federated queries, given as NAME=URL (e.g. team-a=https://bindle.team-a.example.com/v1/). Can be given multiple times"
+    )]
+    peers: Vec<String>,
+    #[clap(
+        name = "slow_request_threshold",
+        long = "slow-request-threshold",
+        env = "BINDLE_SLOW_REQUEST_THRESHOLD",
+        about = "log a warning for requests that take longer than this many seconds. 0 disables the warning. Defaults to 5"
+    )]
+    slow_request_threshold: Option<u64>,
+    #[clap(
+        name = "large_invoice_threshold",
+        long = "large-invoice-threshold",
+        env = "BINDLE_LARGE_INVOICE_THRESHOLD",
+        about = "log a warning for invoices larger than this many bytes. 0 disables the warning. Defaults to 1048576"
+    )]
+    large_invoice_threshold: Option<u64>,
+    #[clap(
+        name = "large_parcel_threshold",
+        long = "large-parcel-threshold",
+        env = "BINDLE_LARGE_PARCEL_THRESHOLD",
+        about = "log a warning for parcel uploads larger than this many bytes. If not set, parcel sizes are not checked"
+    )]
+    large_parcel_threshold: Option<u64>,
+    #[clap(
+        name = "otlp_endpoint",
+        long = "otlp-endpoint",
+        env = "BINDLE_OTLP_ENDPOINT",
+        about = "the OTLP endpoint of an OpenTelemetry collector to export request traces to (e.g. http://localhost:4317). Logs are written with the tracing subscriber instead, still filtered by RUST_LOG. Requires the otlp feature"
+    )]
+    otlp_endpoint: Option<String>,
+    #[clap(
+        name = "metrics",
+        long = "metrics",
+        env = "BINDLE_METRICS",
+        about = "serve Prometheus metrics about requests, transferred parcel data, storage size and the search index at /metrics. Scraping them requires the admin role"
+    )]
+    metrics: bool,
+    #[clap(
+        name = "signing_keys",
+        long = "signing-keys",
+        env = "BINDLE_SIGNING_KEYS",
+        about = "the path to a secret key file (as created by `bindle keys create-key`) containing the key used to sign every new invoice in the host role. The passphrase is read from BINDLE_SIGNING_KEY_PASSPHRASE. If not set, invoices are not signed"
+    )]
+    signing_keys: Option<PathBuf>,
+    #[clap(
+        name = "signing_key",
+        long = "signing-key",
+        env = "BINDLE_SIGNING_KEY",
+        requires = "signing_keys",
+        about = "the label or base64 encoded public key of the key to sign with. Defaults to the first key with the host role"
+    )]
+    signing_key: Option<String>,
+    #[clap(
+        name = "transparency_log",
+        long = "transparency-log",
+        env = "BINDLE_TRANSPARENCY_LOG",
+        requires = "transparency_log_key",
+        about = "the base URL of a transparency log in which the signatures of every new invoice are recorded before it is stored. Invoices are rejected if the log can't be reached. If not set, signatures are not recorded"
+    )]
+    transparency_log: Option<String>,
+    #[clap(
+        name = "transparency_log_key",
+        long = "transparency-log-key",
+        env = "BINDLE_TRANSPARENCY_LOG_KEY",
+        requires = "transparency_log",
+        about = "the base64 encoded Ed25519 public key the transparency log signs its entries with. Entries that aren't signed with it are rejected"
+    )]
+    transparency_log_key: Option<String>,
+    #[clap(
+        name = "keyring_dir",
+        long = "keyring-dir",
+        env = "BINDLE_KEYRING_DIR",
+        about = "the path to a directory in which authenticated users can store their (encrypted) personal keyrings with `bindle keys push`. If not set, keyring storage is disabled"
+    )]
+    keyring_dir: Option<PathBuf>,
+    #[clap(
+        name = "verification_strategy",
+        long = "verification-strategy",
+        env = "BINDLE_VERIFICATION_STRATEGY",
+        default_value = "None",
+        about = "how the signatures of new invoices are checked against the keyring: None, CreativeIntegrity (a trusted creator signature is required), AuthoritativeIntegrity (a trusted creator or approver signature is required) or GreedyVerification (every signature must be trusted, including a creator signature). Invoices that fail are rejected"
+    )]
+    verification_strategy: VerificationStrategy,
+    #[clap(
+        name = "keyring",
+        long = "keyring",
+        env = "BINDLE_KEYRING",
+        about = "the path to the keyring of trusted public keys used to verify signatures. Required if a verification strategy is set"
+    )]
+    keyring: Option<PathBuf>,
+    #[clap(
+        name = "revocation_list",
+        long = "revocation-list",
+        env = "BINDLE_REVOCATION_LIST",
+        about = "the path to a TOML list of revoked keys. Signatures made with a revoked key are rejected by the verification strategy and the `signatures` processor"
+    )]
+    revocation_list: Option<PathBuf>,
+    #[clap(
+        name = "signing_policy",
+        long = "signing-policy",
+        env = "BINDLE_SIGNING_POLICY",
+        requires = "keyring",
+        about = "the path to a TOML file listing the signature roles required for new invoices in each namespace. Signatures are checked against the keyring, which is required"
+    )]
+    signing_policy: Option<PathBuf>,
+    #[clap(
+        name = "disposition_policy",
+        long = "disposition-policy",
+        env = "BINDLE_DISPOSITION_POLICY",
+        about = "the path to a TOML file listing which parcel media types browsers should display inline and which they should download as attachments. If not set, no Content-Disposition header is sent"
+    )]
+    disposition_policy: Option<PathBuf>,
+    #[clap(
+        name = "crawler_policy",
+        long = "crawler-policy",
+        env = "BINDLE_CRAWLER_POLICY",
+        about = "the path to a TOML file with the robots.txt to serve and the minimum delay between reads for crawler user agents. If not set, no robots.txt is served and clients are not throttled"
+    )]
+    crawler_policy: Option<PathBuf>,
+    #[clap(
+        name = "quota_policy",
+        long = "quota-policy",
+        env = "BINDLE_QUOTA_POLICY",
+        about = "the path to a TOML file with the maximum number of bytes and bindles the whole server and each user may store. The usage is kept in quota-usage.toml in the storage root, and tenants each have their own. If not set, storage is not limited"
+    )]
+    quota_policy: Option<PathBuf>,
+    #[clap(
+        name = "process",
+        long = "process",
+        env = "BINDLE_PROCESS",
+        number_of_values = 1,
+        use_delimiter = true,
+        possible_values = &["signatures", "parcel-digests"],
+        about = "a check to run in the background on every new bindle once all of its parcels are uploaded: `signatures` checks the signatures against the keyring, `parcel-digests` reads every parcel and checks its SHA-256. Can be given multiple times. The results are served at /v1/_i/{id}/_status"
+    )]
+    processors: Vec<String>,
+    #[clap(
+        name = "event_hook",
+        long = "event-hook",
+        env = "BINDLE_EVENT_HOOKS",
+        number_of_values = 1,
+        use_delimiter = true,
+        about = "a URL that is sent a JSON event whenever an invoice or parcel is created or an invoice is yanked or deleted, such as for purging or pre-warming a CDN. Failed deliveries are retried. Can be given multiple times"
+    )]
+    event_hooks: Vec<String>,
+    #[clap(
+        name = "event_hook_secret",
+        long = "event-hook-secret",
+        env = "BINDLE_EVENT_HOOK_SECRET",
+        requires = "event_hook",
+        about = "a secret shared with the receivers of event hooks. If set, every event is signed with an HMAC-SHA256 of its body, which is sent in the X-Bindle-Signature header as `sha256=<hex>`"
+    )]
+    event_hook_secret: Option<String>,
+    #[clap(
+        name = "gc_interval",
+        long = "gc-interval",
+        env = "BINDLE_GC_INTERVAL",
+        about = "remove parcels that are no longer referenced by any invoice every this many seconds. If not set, garbage is only collected when an admin asks for it"
+    )]
+    gc_interval: Option<u64>,
+    #[clap(
+        name = "expiry_interval",
+        long = "expiry-interval",
+        env = "BINDLE_EXPIRY_INTERVAL",
+        conflicts_with = "write_once",
+        about = "yank or delete invoices whose bindle.expiresAt annotation has passed every this many seconds, such as preview builds. If not set, invoices never expire"
+    )]
+    expiry_interval: Option<u64>,
+    #[clap(
+        name = "expiry_action",
+        long = "expiry-action",
+        env = "BINDLE_EXPIRY_ACTION",
+        default_value = "yank",
+        possible_values = &["yank", "delete"],
+        about = "what happens to expired invoices: `yank` keeps them around for anything that already uses them, `delete` removes them along with the parcels no other invoice references"
+    )]
+    expiry_action: ExpiryAction,
+    #[clap(
+        name = "upstream",
+        long = "upstream",
+        env = "BINDLE_UPSTREAM_URL",
+        about = "the base URL of a Bindle server to mirror. Bindles that aren't stored yet are fetched from it and stored when they are requested. Creating bindles is not possible on a mirror"
+    )]
+    upstream: Option<String>,
+    #[clap(
+        name = "write_once",
+        long = "write-once",
+        env = "BINDLE_WRITE_ONCE",
+        conflicts_with = "gc_interval",
+        about = "never change or remove anything once it is stored, including yanking and deleting invoices and collecting garbage. Refused attempts are logged"
+    )]
+    write_once: bool,
+    #[clap(
+        name = "verify_reads",
+        long = "verify-reads",
+        env = "BINDLE_VERIFY_READS",
+        about = "check the SHA-256 of every parcel again as it is served, so data corrupted on disk is never sent in full. Responses with corrupted data are aborted before their last chunk and the corruption is logged. This costs CPU on every download, and range requests are not checked"
+    )]
+    verify_reads: bool,
+    #[clap(
+        name = "snapshot_index",
+        long = "snapshot-index",
+        env = "BINDLE_SNAPSHOT_INDEX",
+        about = "save the in-memory search index to index-snapshot.json in the bindle directory when the server shuts down, and restore it from there on the next start instead of reading every invoice. The snapshot is ignored if any invoice changed in the meantime"
+    )]
+    snapshot_index: bool,
+    #[clap(
+        name = "invoice_path_template",
+        long = "invoice-path-template",
+        env = "BINDLE_INVOICE_PATH_TEMPLATE",
+        about = "where invoices are stored, relative to the bindle directory unless absolute. The last directory must be {name}, and shard directories like {name[0..2]} can come before it. Defaults to invoices/{name}. Can't be changed once the store is created"
+    )]
+    invoice_path_template: Option<PathTemplate>,
+    #[clap(
+        name = "parcel_path_template",
+        long = "parcel-path-template",
+        env = "BINDLE_PARCEL_PATH_TEMPLATE",
+        about = "where parcels are stored, like --invoice-path-template. Defaults to parcels/{name}. Absolute templates can be used to keep parcels on another volume"
+    )]
+    parcel_path_template: Option<PathTemplate>,
+    #[clap(
+        name = "replicate_from",
+        long = "replicate-from",
+        env = "BINDLE_REPLICATE_FROM",
+        conflicts_with = "upstream",
+        about = "the base URL of a primary Bindle server to replicate. New bindles, yanks and deletions are copied from it in the background. The primary can send its events here by adding /v1/_replication/events of this server as an event hook"
+    )]
+    replicate_from: Option<String>,
+    #[clap(
+        name = "replication_secret",
+        long = "replication-secret",
+        env = "BINDLE_REPLICATION_SECRET",
+        requires = "replicate_from",
+        about = "the secret the primary signs the events it sends to /v1/_replication/events with (its --event-hook-secret). Events without a valid signature are rejected. If not set, events are not accepted and only polling finds new bindles"
+    )]
+    replication_secret: Option<String>,
+    #[clap(
+        name = "replication_query",
+        long = "replication-query",
+        env = "BINDLE_REPLICATION_QUERY",
+        number_of_values = 1,
+        use_delimiter = true,
+        requires = "replicate_from",
+        about = "a query the primary is polled with for bindles to replicate. On primaries using strict search, this has to be the exact name of a bindle. Can be given multiple times. If not set, only the bindles the primary sends events for are replicated"
+    )]
+    replication_queries: Vec<String>,
+    #[clap(
+        name = "replication_interval",
+        long = "replication-interval",
+        env = "BINDLE_REPLICATION_INTERVAL",
+        default_value = "300",
+        about = "the number of seconds to wait between polls of the primary"
+    )]
+    replication_interval: u64,
+    #[clap(
+        name = "tenant",
+        long = "tenant",
+        env = "BINDLE_TENANTS",
+        number_of_values = 1,
+        use_delimiter = true,
+        conflicts_with_all = &["upstream", "replicate_from", "peer", "write_once", "process"],
+        about = "a tenant to serve at /{NAME}/v1/ instead of serving a single store at /v1/. Every tenant has its own storage root at tenants/{NAME} in the bindle directory and its own search index. Can be given multiple times"
+    )]
+    tenants: Vec<String>,
+    #[clap(
+        name = "tenant_policy_dir",
+        long = "tenant-policy-dir",
+        env = "BINDLE_TENANT_POLICY_DIR",
+        requires = "tenant",
+        about = "the path to a directory of authorization policies for tenants, each named {NAME}.toml like the --policy-file. Tenants without a policy in it use the --policy-file"
+    )]
+    tenant_policy_dir: Option<PathBuf>,
+}
+
+const DEFAULT_ADDRESS: ([u8; 4], u16) = ([127, 0, 0, 1], 8080);
+const DEFAULT_DIRECTORY: &str = "/tmp";
+const DEFAULT_SLOW_REQUEST_THRESHOLD: u64 = 5;
+const DEFAULT_LARGE_INVOICE_THRESHOLD: u64 = 1024 * 1024;
+
+/// The file in the storage root that the search index is saved to on shutdown, if enabled
+const INDEX_SNAPSHOT: &str = "index-snapshot.json";
+/// The file in the storage root that the usage of the quotas is kept in, if enabled
+const QUOTA_USAGE: &str = "quota-usage.toml";
+
+/// Everything needed to serve a store, apart from the store and its search index
+struct Frontend {
+    authenticator: Arc<dyn Authenticator + Send + Sync>,
+    authorizer: Arc<dyn Authorizer + Send + Sync>,
+    addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    monitor: RequestMonitor,
+    options: ApiOptions,
+    hooks: Vec<HttpHook>,
+    gc_interval: Option<u64>,
+    expiry: Option<(u64, ExpiryAction)>,
+    write_once: bool,
+    verify_reads: bool,
+    snapshot_index: bool,
+    quota_policy: Option<Arc<QuotaPolicy>>,
+    layout: Option<StorageLayout>,
+    replicator: Option<ReplicatorOptions>,
+    tasks: TaskRegistry,
+}
+
+/// The primary to replicate from and how to find its bindles
+struct ReplicatorOptions {
+    primary: Client,
+    queries: Vec<String>,
+    interval: Duration,
+    event_secret: Option<String>,
 }
 
 #[tokio::main(threaded_scheduler)]
 async fn main() -> anyhow::Result<()> {
-    let opts = Opts::parse();
-    env_logger::init();
+    let mut opts = Opts::parse();
+    if let Some(path) = &opts.config {
+        let config = ServerConfig::from_file(path).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Unable to load configuration from {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        apply_config(&mut opts, config)?;
+    }
+    // The guard flushes the remaining spans when the server stops
+    #[cfg(feature = "otlp")]
+    type Guard = bindle::trace::otlp::Uninstall;
+    #[cfg(not(feature = "otlp"))]
+    type Guard = ();
+    let _otlp: Option<Guard> = match &opts.otlp_endpoint {
+        None => {
+            env_logger::init();
+            None
+        }
+        #[cfg(feature = "otlp")]
+        Some(endpoint) => Some(bindle::trace::otlp::install(endpoint, "bindle-server")?),
+        #[cfg(not(feature = "otlp"))]
+        Some(_) => anyhow::bail!("Exporting traces requires the otlp feature"),
+    };
 
-    let addr: SocketAddr = opts.address.parse()?;
-    let index = search::StrictEngine::default();
-    let store = provider::file::FileProvider::new(&opts.bindle_directory, index.clone()).await;
+    if let Some(path) = &opts.config {
+        log::info!("Using configuration from {}", path.display());
+    }
+    let addr = opts.address.unwrap_or_else(|| DEFAULT_ADDRESS.into());
+    let bindle_directory = opts
+        .bindle_directory
+        .take()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DIRECTORY));
 
     log::info!(
         "Starting server at {}, and serving bindles from {}",
         addr.to_string(),
-        opts.bindle_directory.display()
+        bindle_directory.display()
     );
 
     // Map doesn't work here because we've already moved data out of opts
@@ -78,7 +509,635 @@ async fn main() -> anyhow::Result<()> {
             key_path: opts
                 .key_path
                 .expect("--key-path should be set if --cert-path was set"),
+            client_ca_path: opts.client_ca_path,
         }),
     };
-    server(store, index, addr, tls).await
+
+    let authenticator: Arc<dyn Authenticator + Send + Sync> =
+        match (opts.htpasswd_file, opts.token_file) {
+            (Some(path), _) => {
+                log::info!(
+                    "Using HTTP Basic authentication with users from {}",
+                    path.display()
+                );
+                let auth = BasicAuthenticator::from_htpasswd(&path).await?;
+                Arc::new(if opts.protect_reads {
+                    auth.protect_reads()
+                } else {
+                    auth
+                })
+            }
+            (None, Some(path)) => {
+                log::info!(
+                    "Using bearer token authentication with tokens from {}",
+                    path.display()
+                );
+                let auth = BearerAuthenticator::from_file(&path).await?;
+                Arc::new(if opts.protect_reads {
+                    auth.protect_reads()
+                } else {
+                    auth
+                })
+            }
+            (None, None) => {
+                log::warn!("No authentication configured, anyone can create or yank bindles");
+                Arc::new(NoopAuthenticator)
+            }
+        };
+
+    let authorizer: Arc<dyn Authorizer + Send + Sync> = match opts.policy_file {
+        Some(path) => {
+            log::info!("Using authorization policy from {}", path.display());
+            Arc::new(RolePolicy::from_file(&path).await?)
+        }
+        None => Arc::new(AllowAll),
+    };
+
+    // A threshold of 0 would warn about everything, so it is used to turn the warning off
+    let monitor = RequestMonitor::new(RequestThresholds {
+        slow_request: Some(
+            opts.slow_request_threshold
+                .unwrap_or(DEFAULT_SLOW_REQUEST_THRESHOLD),
+        )
+        .filter(|t| *t > 0)
+        .map(std::time::Duration::from_secs),
+        large_invoice: Some(
+            opts.large_invoice_threshold
+                .unwrap_or(DEFAULT_LARGE_INVOICE_THRESHOLD),
+        )
+        .filter(|t| *t > 0),
+        large_parcel: opts.large_parcel_threshold,
+    });
+
+    let signing_key = match opts.signing_keys {
+        Some(path) => {
+            let key = load_signing_key(&path, opts.signing_key.as_deref()).await?;
+            log::info!("Signing new invoices as host with key {}", key.label);
+            Some(Arc::new(key))
+        }
+        None => None,
+    };
+    let transparency_log = match (opts.transparency_log, opts.transparency_log_key) {
+        (Some(url), Some(key)) => {
+            let key = base64::decode(&key)
+                .ok()
+                .and_then(|k| PublicKey::from_bytes(&k).ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid transparency log key {}", key))?;
+            let log = HttpLog::new(&url, key)
+                .map_err(|e| anyhow::anyhow!("Invalid transparency log URL {}: {}", url, e))?;
+            log::info!("Recording signatures of new invoices in {:?}", log);
+            Some(Arc::new(log) as Arc<dyn TransparencyLog + Send + Sync>)
+        }
+        _ => None,
+    };
+    let has_keyring = opts.keyring.is_some();
+    let keyring = match (opts.keyring, opts.verification_strategy) {
+        (Some(path), strategy) => {
+            let keyring = KeyRing::load(&path).await?;
+            log::info!(
+                "Verifying invoices with strategy {} against {} keys from {}",
+                strategy,
+                keyring.key.len(),
+                path.display()
+            );
+            keyring
+        }
+        (None, VerificationStrategy::None) => KeyRing::default(),
+        (None, strategy) => anyhow::bail!(
+            "A keyring must be given with --keyring to use the {} verification strategy",
+            strategy
+        ),
+    };
+    let revocations = match opts.revocation_list {
+        Some(path) => {
+            let revocations = RevocationList::load(&path).await?;
+            log::info!(
+                "Rejecting signatures of {} revoked keys from {}",
+                revocations.revoked.len(),
+                path.display()
+            );
+            revocations
+        }
+        None => RevocationList::default(),
+    };
+    let signing_policy = match opts.signing_policy {
+        Some(path) => {
+            log::info!("Using signing policy from {}", path.display());
+            SigningPolicy::from_file(&path).await?
+        }
+        None => SigningPolicy::default(),
+    };
+    let disposition_policy = match opts.disposition_policy {
+        Some(path) => {
+            log::info!("Using disposition policy from {}", path.display());
+            DispositionPolicy::from_file(&path).await?
+        }
+        None => DispositionPolicy::default(),
+    };
+    let crawler_policy = match opts.crawler_policy {
+        Some(path) => {
+            log::info!("Using crawler policy from {}", path.display());
+            CrawlerPolicy::from_file(&path).await?
+        }
+        None => CrawlerPolicy::default(),
+    };
+    let quota_policy = match opts.quota_policy {
+        Some(path) => {
+            log::info!("Using quota policy from {}", path.display());
+            Some(Arc::new(QuotaPolicy::from_file(&path).await?))
+        }
+        None => None,
+    };
+    let keyring = Arc::new(keyring);
+    let tasks = TaskRegistry::default();
+    let processing = if opts.processors.is_empty() {
+        None
+    } else {
+        let mut pipeline = Pipeline::default().with_tasks(tasks.clone());
+        for name in &opts.processors {
+            pipeline = match name.as_str() {
+                "signatures" if !has_keyring => {
+                    anyhow::bail!("A keyring must be given with --keyring to check signatures")
+                }
+                "signatures" => pipeline.with_processor(
+                    SignatureCheck::new(keyring.clone()).with_revocations(revocations.clone()),
+                ),
+                "parcel-digests" => pipeline.with_processor(ParcelDigestCheck),
+                _ => anyhow::bail!("Unknown processor {}", name),
+            };
+        }
+        log::info!("Processing new bindles with {:?}", pipeline);
+        Some(pipeline)
+    };
+    let options = ApiOptions {
+        signing_key,
+        keyring_dir: opts.keyring_dir,
+        verification_strategy: opts.verification_strategy,
+        keyring,
+        revocations: Arc::new(revocations),
+        signing_policy: Arc::new(signing_policy),
+        disposition_policy: Arc::new(disposition_policy),
+        crawler_policy: Arc::new(crawler_policy),
+        metrics: if opts.metrics {
+            log::info!("Serving metrics at /metrics");
+            Some(Metrics::default())
+        } else {
+            None
+        },
+        processing,
+        transparency_log,
+        // Every storage root keeps its own usage, so the quotas are set up with the store
+        quotas: None,
+        replication: None,
+    };
+
+    let mut hooks = Vec::new();
+    for url in &opts.event_hooks {
+        let mut hook = HttpHook::new(url)
+            .map_err(|e| anyhow::anyhow!("Invalid event hook URL {}: {}", url, e))?;
+        if let Some(secret) = &opts.event_hook_secret {
+            hook = hook.secret(secret);
+        }
+        hooks.push(hook);
+        log::info!("Sending provider events to {}", url);
+    }
+
+    let peers = opts
+        .peers
+        .iter()
+        .map(|p| parse_peer(p))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if !peers.is_empty() {
+        log::info!(
+            "Federating queries to peers: {}",
+            peers
+                .iter()
+                .map(|p| p.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    let upstream = match opts.upstream {
+        Some(url) => {
+            log::info!("Mirroring bindles from {}", url);
+            Some(
+                bindle::client::Client::new(&url)
+                    .map_err(|e| anyhow::anyhow!("Invalid upstream URL {}: {}", url, e))?,
+            )
+        }
+        None => None,
+    };
+
+    let replicator = match opts.replicate_from {
+        Some(url) => {
+            log::info!("Replicating bindles from {}", url);
+            Some(ReplicatorOptions {
+                primary: Client::new(&url)
+                    .map_err(|e| anyhow::anyhow!("Invalid primary URL {}: {}", url, e))?,
+                queries: opts.replication_queries,
+                interval: Duration::from_secs(opts.replication_interval),
+                event_secret: opts.replication_secret,
+            })
+        }
+        None => None,
+    };
+
+    // Templates that aren't given keep their default, so only one of them has to be set
+    let layout = match (opts.invoice_path_template, opts.parcel_path_template) {
+        (None, None) => None,
+        (invoices, parcels) => {
+            let default = StorageLayout::default();
+            Some(StorageLayout {
+                invoices: invoices.unwrap_or(default.invoices),
+                parcels: parcels.unwrap_or(default.parcels),
+            })
+        }
+    };
+
+    let frontend = Frontend {
+        authenticator,
+        authorizer,
+        addr,
+        tls,
+        monitor,
+        options,
+        hooks,
+        gc_interval: opts.gc_interval,
+        expiry: opts.expiry_interval.zip(Some(opts.expiry_action)),
+        write_once: opts.write_once,
+        verify_reads: opts.verify_reads,
+        snapshot_index: opts.snapshot_index,
+        quota_policy,
+        layout,
+        replicator,
+        tasks,
+    };
+
+    if !opts.tenants.is_empty() {
+        #[cfg(feature = "postgres")]
+        if opts.postgres_url.is_some() {
+            anyhow::bail!("Tenants can't use a Postgres search index");
+        }
+        return serve_tenants(
+            &bindle_directory,
+            &opts.tenants,
+            opts.tenant_policy_dir.as_deref(),
+            frontend,
+        )
+        .await;
+    }
+
+    #[cfg(feature = "postgres")]
+    if let Some(url) = opts.postgres_url {
+        log::info!("Using Postgres search index");
+        let index = search::PostgresEngine::connect(&url).await?;
+        return serve(&bindle_directory, index, peers, upstream, frontend).await;
+    }
+
+    let mut index = search::StrictEngine::default();
+    if frontend.snapshot_index {
+        index = index.with_snapshot(bindle_directory.join(INDEX_SNAPSHOT));
+    }
+    if let Some(metrics) = &frontend.options.metrics {
+        metrics.register(index.clone());
+    }
+    serve(&bindle_directory, index, peers, upstream, frontend).await
+}
+
+/// Fills in the options that weren't given as flags or environment variables from the
+/// configuration file
+fn apply_config(opts: &mut Opts, config: ServerConfig) -> anyhow::Result<()> {
+    opts.address = opts.address.or(config.address);
+    opts.bindle_directory = opts.bindle_directory.take().or(config.storage.directory);
+    #[cfg(feature = "postgres")]
+    {
+        opts.postgres_url = opts.postgres_url.take().or(config.storage.postgres_url);
+    }
+    #[cfg(not(feature = "postgres"))]
+    if config.storage.postgres_url.is_some() {
+        anyhow::bail!("A Postgres search index requires the postgres feature");
+    }
+    opts.snapshot_index |= config.storage.snapshot_index;
+    // TLS from the flags replaces the whole section, so the paths are never mixed up
+    if opts.cert_path.is_none() {
+        if let Some(tls) = config.tls {
+            opts.cert_path = Some(tls.cert_path);
+            opts.key_path = Some(tls.key_path);
+            opts.client_ca_path = opts.client_ca_path.take().or(tls.client_ca_path);
+        }
+    }
+    // Only one kind of authentication can be used, so flags for either replace both
+    if opts.htpasswd_file.is_none() && opts.token_file.is_none() {
+        opts.htpasswd_file = config.auth.htpasswd_file;
+        opts.token_file = config.auth.token_file;
+        if opts.htpasswd_file.is_some() && opts.token_file.is_some() {
+            anyhow::bail!("Only one of htpasswdFile and tokenFile can be configured");
+        }
+    }
+    opts.protect_reads |= config.auth.protect_reads;
+    opts.policy_file = opts.policy_file.take().or(config.auth.policy_file);
+    opts.slow_request_threshold = opts
+        .slow_request_threshold
+        .or(config.limits.slow_request_threshold);
+    opts.large_invoice_threshold = opts
+        .large_invoice_threshold
+        .or(config.limits.large_invoice_threshold);
+    opts.large_parcel_threshold = opts
+        .large_parcel_threshold
+        .or(config.limits.large_parcel_threshold);
+    opts.quota_policy = opts.quota_policy.take().or(config.limits.quota_policy);
+    if opts.signing_keys.is_none() {
+        opts.signing_keys = config.signing.keys;
+        opts.signing_key = opts.signing_key.take().or(config.signing.key);
+    }
+    opts.otlp_endpoint = opts.otlp_endpoint.take().or(config.tracing.otlp_endpoint);
+    Ok(())
+}
+
+/// Serves the bindles stored in the given directory, mirroring the upstream server if one is given
+async fn serve<I>(
+    dir: &Path,
+    index: I,
+    peers: Vec<search::Peer>,
+    upstream: Option<Client>,
+    mut frontend: Frontend,
+) -> anyhow::Result<()>
+where
+    I: search::Search + Clone + Send + Sync + 'static,
+{
+    if frontend.verify_reads {
+        log::info!("Verifying parcels as they are served");
+    }
+    if let Some(policy) = &frontend.quota_policy {
+        frontend.options.quotas = Some(Quotas::load(policy.clone(), dir.join(QUOTA_USAGE)).await?);
+    }
+    let store = match &frontend.layout {
+        Some(layout) => {
+            log::info!("Using storage layout {:?}", layout);
+            provider::file::FileProvider::with_layout(dir, index.clone(), layout.clone()).await?
+        }
+        None => provider::file::FileProvider::new(dir, index.clone()).await,
+    }
+    .with_read_verification(frontend.verify_reads);
+    if let Some(metrics) = &frontend.options.metrics {
+        metrics.register(store.clone());
+    }
+    let index = search::FederatedSearch::new(index, peers);
+    let snapshot_index = frontend.snapshot_index;
+    let res = if frontend.write_once {
+        log::info!("Using write-once storage, stored bindles can never be changed or removed");
+        serve_store(WormProvider::new(store.clone()), index, upstream, frontend).await
+    } else {
+        serve_store(store.clone(), index, upstream, frontend).await
+    };
+    if snapshot_index {
+        save_index_snapshot(&store).await;
+    }
+    res
+}
+
+/// Saves a snapshot of the search index of the store once the server stopped. Failing to save it
+/// only makes the next start slower, so errors are only logged
+async fn save_index_snapshot<I>(store: &provider::file::FileProvider<I>)
+where
+    I: search::Search + Send + Sync,
+{
+    log::info!("Saving a snapshot of the search index");
+    if let Err(e) = store.save_index_snapshot().await {
+        log::error!("Unable to save a snapshot of the search index: {}", e);
+    }
+}
+
+async fn serve_store<P, I>(
+    store: P,
+    index: I,
+    upstream: Option<Client>,
+    frontend: Frontend,
+) -> anyhow::Result<()>
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: search::Search + Clone + Send + Sync + 'static,
+{
+    match upstream {
+        Some(client) => run(MirrorProvider::from_client(client, store), index, frontend).await,
+        None => run(store, index, frontend).await,
+    }
+}
+
+async fn run<P, I>(store: P, index: I, mut frontend: Frontend) -> anyhow::Result<()>
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: search::Search + Clone + Send + Sync + 'static,
+{
+    let tasks = frontend.tasks;
+    let store = with_hooks(store, frontend.hooks).with_tasks(tasks.clone());
+    start_gc(&tasks, &store, frontend.gc_interval);
+    start_expiry(&tasks, &store, frontend.expiry);
+    if let Some(opts) = frontend.replicator {
+        frontend.options.replication = Some(start_replication(&tasks, &store, opts));
+    }
+    let res = server(
+        store,
+        index,
+        InProcessOptions {
+            address: frontend.addr,
+            authenticator: frontend.authenticator,
+            authorizer: frontend.authorizer,
+            tls: frontend.tls,
+            monitor: frontend.monitor,
+            api: frontend.options,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        },
+    )
+    .await;
+    log::info!("Stopping background tasks");
+    tasks.shutdown(SHUTDOWN_GRACE_PERIOD).await;
+    res
+}
+
+/// Serves every tenant from its own storage root in the given directory, with its own search index
+/// and metrics. Tenants with a policy in the policy directory are authorized with it, the others
+/// with the authorizer of the frontend
+async fn serve_tenants(
+    dir: &Path,
+    names: &[String],
+    policy_dir: Option<&Path>,
+    frontend: Frontend,
+) -> anyhow::Result<()> {
+    if frontend.verify_reads {
+        log::info!("Verifying parcels as they are served");
+    }
+    let tasks = frontend.tasks;
+    let mut tenants = Vec::new();
+    let mut stores = Vec::new();
+    for name in names {
+        let root = tenancy::storage_root(dir, name)?;
+        log::info!("Serving tenant {} from {}", name, root.display());
+        let mut index = search::StrictEngine::default();
+        if frontend.snapshot_index {
+            index = index.with_snapshot(root.join(INDEX_SNAPSHOT));
+        }
+        let store = match &frontend.layout {
+            Some(layout) => {
+                provider::file::FileProvider::with_layout(&root, index.clone(), layout.clone())
+                    .await?
+            }
+            None => provider::file::FileProvider::new(&root, index.clone()).await,
+        }
+        .with_read_verification(frontend.verify_reads);
+        stores.push(store.clone());
+        let mut options = frontend.options.clone();
+        if let Some(policy) = &frontend.quota_policy {
+            options.quotas = Some(Quotas::load(policy.clone(), root.join(QUOTA_USAGE)).await?);
+        }
+        if options.metrics.is_some() {
+            let metrics = Metrics::default();
+            metrics.register(store.clone());
+            metrics.register(index.clone());
+            options.metrics = Some(metrics);
+        }
+        let store = with_hooks(store, frontend.hooks.clone()).with_tasks(tasks.clone());
+        start_gc(&tasks, &store, frontend.gc_interval);
+        start_expiry(&tasks, &store, frontend.expiry);
+
+        let policy = policy_dir
+            .map(|dir| dir.join(format!("{}.toml", name)))
+            .filter(|path| path.is_file());
+        let authorizer: Arc<dyn Authorizer + Send + Sync> = match policy {
+            Some(path) => {
+                log::info!(
+                    "Using authorization policy from {} for tenant {}",
+                    path.display(),
+                    name
+                );
+                Arc::new(RolePolicy::from_file(&path).await?)
+            }
+            None => frontend.authorizer.clone(),
+        };
+        tenants.push(Tenant::new(name.as_str(), store, index, authorizer)?.with_options(options));
+    }
+    let res = tenancy::server(
+        tenants,
+        frontend.authenticator,
+        frontend.addr,
+        frontend.tls,
+        frontend.monitor,
+    )
+    .await;
+    log::info!("Stopping background tasks");
+    tasks.shutdown(SHUTDOWN_GRACE_PERIOD).await;
+    if frontend.snapshot_index {
+        for store in &stores {
+            save_index_snapshot(store).await;
+        }
+    }
+    res
+}
+
+/// Wraps the store so that its events are sent to all of the given hooks
+fn with_hooks<P: Provider>(store: P, hooks: Vec<HttpHook>) -> HookedProvider<P> {
+    hooks
+        .into_iter()
+        .fold(HookedProvider::new(store), |store, hook| {
+            store.with_hook(hook)
+        })
+}
+
+/// Collects garbage in the store in the background every `interval` seconds, if set
+fn start_gc<P>(tasks: &TaskRegistry, store: &P, interval: Option<u64>)
+where
+    P: Provider + Clone + Send + Sync + 'static,
+{
+    if let Some(secs) = interval.filter(|s| *s > 0) {
+        log::info!("Collecting garbage every {} seconds", secs);
+        let store = store.clone();
+        tasks.spawn_service("garbage collection", RestartPolicy::default(), move || {
+            provider::gc::collect_periodically(store.clone(), Duration::from_secs(secs))
+        });
+    }
+}
+
+/// Sweeps the store for expired invoices in the background, if an interval is configured
+fn start_expiry<P>(tasks: &TaskRegistry, store: &P, expiry: Option<(u64, ExpiryAction)>)
+where
+    P: Provider + Clone + Send + Sync + 'static,
+{
+    if let Some((secs, action)) = expiry.filter(|(s, _)| *s > 0) {
+        log::info!(
+            "Sweeping expired invoices every {} seconds (action: {})",
+            secs,
+            action
+        );
+        let store = store.clone();
+        tasks.spawn_service("invoice expiry", RestartPolicy::default(), move || {
+            provider::expiry::expire_periodically(store.clone(), Duration::from_secs(secs), action)
+        });
+    }
+}
+
+/// Replicates bindles from the primary into the store in the background, returning the handle for
+/// the replication endpoints of the API
+fn start_replication<P>(
+    tasks: &TaskRegistry,
+    store: &P,
+    opts: ReplicatorOptions,
+) -> ReplicationHandle
+where
+    P: Provider + Clone + Send + Sync + 'static,
+{
+    let mut replicator = opts
+        .queries
+        .into_iter()
+        .fold(
+            Replicator::new(opts.primary, store.clone()),
+            |replicator, query| {
+                replicator.with_query(QueryOptions {
+                    query: Some(query),
+                    ..Default::default()
+                })
+            },
+        )
+        .with_poll_interval(opts.interval);
+    if let Some(secret) = opts.event_secret {
+        replicator = replicator.with_event_secret(secret);
+    }
+    let handle = replicator.handle();
+    tasks.spawn_service("replication", RestartPolicy::default(), move || {
+        replicator.clone().run()
+    });
+    handle
+}
+
+/// Loads and decrypts the host signing key with the given label or public key from the secret key
+/// file, or the first key with the host role if no key is given
+async fn load_signing_key(
+    path: &Path,
+    label_or_key: Option<&str>,
+) -> anyhow::Result<SecretKeyEntry> {
+    let keys = SecretKeyFile::load(path).await?;
+    let encrypted = match label_or_key {
+        Some(label_or_key) => keys.get(label_or_key),
+        None => keys.get_first_matching(SignatureRole::Host),
+    }
+    .ok_or_else(|| anyhow::anyhow!("No signing key found in {}", path.display()))?;
+    if !encrypted.roles.contains(&SignatureRole::Host) {
+        anyhow::bail!(
+            "Signing key {} does not have the host role",
+            encrypted.label
+        );
+    }
+    let passphrase = std::env::var("BINDLE_SIGNING_KEY_PASSPHRASE").unwrap_or_default();
+    Ok(encrypted.decrypt(&passphrase)?)
+}
+
+/// Parses a peer given as `NAME=URL`
+fn parse_peer(raw: &str) -> anyhow::Result<search::Peer> {
+    let mut parts = raw.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(url)) if !name.is_empty() => {
+            Ok(search::Peer::new(name, Client::new(url)?))
+        }
+        _ => anyhow::bail!("Invalid peer {}, expected NAME=URL", raw),
+    }
 }
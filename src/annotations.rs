@@ -0,0 +1,274 @@
+//! Well-known annotation keys for invoices and labels.
+//!
+//! Annotations are free-form, but metadata like where a bindle's source lives or what license it
+//! is under is useful to tools across the ecosystem. Using the keys defined here (instead of each
+//! tool coming up with its own) means that this metadata can be read by any tool and that search
+//! engines can offer facets over it. All of the keys are prefixed with `bindle.` so they can't
+//! clash with custom annotations.
+//!
+//! Besides the constants, [`Invoice`](crate::Invoice) and [`Label`](crate::Label) have typed
+//! accessors for the annotations that apply to them:
+//!
+//! ```
+//! use bindle::annotations::BuildInfo;
+//!
+//! let mut inv: bindle::Invoice = toml::from_str(r#"
+//!     bindleVersion = "1.0.0"
+//!     [bindle]
+//!     name = "app"
+//!     version = "1.0.0"
+//!     [annotations]
+//!     "bindle.license" = "Apache-2.0"
+//! "#).unwrap();
+//!
+//! assert_eq!(Some("Apache-2.0"), inv.license());
+//! inv.set_build_info(&BuildInfo {
+//!     revision: Some("7c1f4a2".to_owned()),
+//!     ..BuildInfo::default()
+//! });
+//! assert_eq!(Some("7c1f4a2"), inv.annotation(bindle::annotations::BUILD_REVISION));
+//! ```
+
+use crate::{AnnotationMap, Id, Invoice, Label};
+
+pub use crate::chunking::MEDIA_TYPE_ANNOTATION as CHUNKED_MEDIA_TYPE;
+pub use crate::compose::ORIGIN_ANNOTATION as ORIGIN;
+
+/// The URL of the source code (such as a git repository) the bindle or parcel was built from
+pub const SOURCE_URL: &str = "bindle.source.url";
+/// The license of the bindle or parcel, as an [SPDX](https://spdx.org/licenses/) expression (e.g.
+/// `MIT OR Apache-2.0`)
+pub const LICENSE: &str = "bindle.license";
+/// The tool, and optionally its version, that built the bindle (e.g. `cargo 1.50.0`)
+pub const BUILD_TOOL: &str = "bindle.build.tool";
+/// The revision of the source the bindle was built from, such as a git commit
+pub const BUILD_REVISION: &str = "bindle.build.revision";
+/// The UNIX timestamp (in seconds) at which the bindle was built
+pub const BUILD_TIMESTAMP: &str = "bindle.build.timestamp";
+/// The UNIX timestamp (in seconds) after which the bindle is no longer needed, such as for preview
+/// builds of pull requests. Servers that sweep expired bindles yank or delete them afterwards (see
+/// the [`expiry`](crate::provider::expiry) module)
+pub const EXPIRES_AT: &str = "bindle.expiresAt";
+
+/// Information about how a bindle was built, stored in the `bindle.build.*` annotations of its
+/// invoice
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// See [`BUILD_TOOL`](BUILD_TOOL)
+    pub tool: Option<String>,
+    /// See [`BUILD_REVISION`](BUILD_REVISION)
+    pub revision: Option<String>,
+    /// See [`BUILD_TIMESTAMP`](BUILD_TIMESTAMP)
+    pub timestamp: Option<u64>,
+}
+
+impl BuildInfo {
+    fn is_empty(&self) -> bool {
+        self.tool.is_none() && self.revision.is_none() && self.timestamp.is_none()
+    }
+}
+
+impl Invoice {
+    /// Returns the value of the given annotation, if it is set
+    pub fn annotation(&self, key: &str) -> Option<&str> {
+        get(&self.annotations, key)
+    }
+
+    /// Sets the given annotation, replacing any previous value
+    pub fn set_annotation(&mut self, key: &str, value: impl Into<String>) {
+        set(&mut self.annotations, key, value.into())
+    }
+
+    /// Returns the [`SOURCE_URL`](SOURCE_URL) annotation
+    pub fn source_url(&self) -> Option<&str> {
+        self.annotation(SOURCE_URL)
+    }
+
+    /// Sets the [`SOURCE_URL`](SOURCE_URL) annotation
+    pub fn set_source_url(&mut self, url: impl Into<String>) {
+        self.set_annotation(SOURCE_URL, url)
+    }
+
+    /// Returns the [`LICENSE`](LICENSE) annotation
+    pub fn license(&self) -> Option<&str> {
+        self.annotation(LICENSE)
+    }
+
+    /// Sets the [`LICENSE`](LICENSE) annotation
+    pub fn set_license(&mut self, license: impl Into<String>) {
+        self.set_annotation(LICENSE, license)
+    }
+
+    /// Returns the [`EXPIRES_AT`](EXPIRES_AT) annotation. A timestamp that isn't a valid number is
+    /// treated as missing
+    pub fn expires_at(&self) -> Option<u64> {
+        self.annotation(EXPIRES_AT).and_then(|t| t.parse().ok())
+    }
+
+    /// Sets the [`EXPIRES_AT`](EXPIRES_AT) annotation
+    pub fn set_expires_at(&mut self, timestamp: u64) {
+        self.set_annotation(EXPIRES_AT, timestamp.to_string())
+    }
+
+    /// Returns whether the bindle expired at the given UNIX timestamp. Bindles without an
+    /// [`EXPIRES_AT`](EXPIRES_AT) annotation never expire
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        self.expires_at().map(|at| at <= now).unwrap_or(false)
+    }
+
+    /// Returns the build information from the `bindle.build.*` annotations, or `None` if none of
+    /// them are set. A timestamp that isn't a valid number is treated as missing
+    pub fn build_info(&self) -> Option<BuildInfo> {
+        let info = BuildInfo {
+            tool: self.annotation(BUILD_TOOL).map(ToOwned::to_owned),
+            revision: self.annotation(BUILD_REVISION).map(ToOwned::to_owned),
+            timestamp: self
+                .annotation(BUILD_TIMESTAMP)
+                .and_then(|t| t.parse().ok()),
+        };
+        if info.is_empty() {
+            return None;
+        }
+        Some(info)
+    }
+
+    /// Sets the `bindle.build.*` annotations to the given build information. Fields that are
+    /// `None` are left untouched
+    pub fn set_build_info(&mut self, info: &BuildInfo) {
+        if let Some(tool) = &info.tool {
+            self.set_annotation(BUILD_TOOL, tool.as_str());
+        }
+        if let Some(revision) = &info.revision {
+            self.set_annotation(BUILD_REVISION, revision.as_str());
+        }
+        if let Some(timestamp) = info.timestamp {
+            self.set_annotation(BUILD_TIMESTAMP, timestamp.to_string());
+        }
+    }
+}
+
+impl Label {
+    /// Returns the value of the given annotation, if it is set
+    pub fn annotation(&self, key: &str) -> Option<&str> {
+        get(&self.annotations, key)
+    }
+
+    /// Sets the given annotation, replacing any previous value
+    pub fn set_annotation(&mut self, key: &str, value: impl Into<String>) {
+        set(&mut self.annotations, key, value.into())
+    }
+
+    /// Returns the [`SOURCE_URL`](SOURCE_URL) annotation. This is only needed for parcels that
+    /// come from a different source than the rest of the bindle, such as vendored files
+    pub fn source_url(&self) -> Option<&str> {
+        self.annotation(SOURCE_URL)
+    }
+
+    /// Sets the [`SOURCE_URL`](SOURCE_URL) annotation
+    pub fn set_source_url(&mut self, url: impl Into<String>) {
+        self.set_annotation(SOURCE_URL, url)
+    }
+
+    /// Returns the [`LICENSE`](LICENSE) annotation. This is only needed for parcels that are
+    /// under a different license than the rest of the bindle
+    pub fn license(&self) -> Option<&str> {
+        self.annotation(LICENSE)
+    }
+
+    /// Sets the [`LICENSE`](LICENSE) annotation
+    pub fn set_license(&mut self, license: impl Into<String>) {
+        self.set_annotation(LICENSE, license)
+    }
+
+    /// Returns the ID of the bindle this parcel came from, if it is part of a composed bindle (see
+    /// the [`compose`](crate::compose) module). An origin that isn't a valid ID is treated as
+    /// missing
+    pub fn origin(&self) -> Option<Id> {
+        self.annotation(ORIGIN).and_then(|o| o.parse().ok())
+    }
+}
+
+fn get<'a>(annotations: &'a Option<AnnotationMap>, key: &str) -> Option<&'a str> {
+    annotations
+        .as_ref()
+        .and_then(|a| a.get(key))
+        .map(String::as_str)
+}
+
+fn set(annotations: &mut Option<AnnotationMap>, key: &str, value: String) {
+    annotations
+        .get_or_insert_with(AnnotationMap::new)
+        .insert(key.to_owned(), value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expires_at() {
+        let mut inv: Invoice = toml::from_str(
+            r#"
+            bindleVersion = "1.0.0"
+            [bindle]
+            name = "app"
+            version = "1.0.0"
+            "#,
+        )
+        .expect("invoice should parse");
+        assert!(!inv.is_expired_at(u64::MAX));
+
+        inv.set_expires_at(100);
+        assert_eq!(Some("100"), inv.annotation(EXPIRES_AT));
+        assert!(!inv.is_expired_at(99));
+        assert!(inv.is_expired_at(100));
+
+        inv.set_annotation(EXPIRES_AT, "tomorrow");
+        assert_eq!(None, inv.expires_at());
+        assert!(!inv.is_expired_at(u64::MAX));
+    }
+
+    #[test]
+    fn test_build_info() {
+        let mut inv: Invoice = toml::from_str(
+            r#"
+            bindleVersion = "1.0.0"
+            [bindle]
+            name = "app"
+            version = "1.0.0"
+            "#,
+        )
+        .expect("invoice should parse");
+        assert!(inv.build_info().is_none());
+
+        let info = BuildInfo {
+            tool: Some("cargo 1.50.0".to_owned()),
+            revision: Some("7c1f4a2".to_owned()),
+            timestamp: Some(1_612_137_600),
+        };
+        inv.set_build_info(&info);
+        assert_eq!(Some("1612137600"), inv.annotation(BUILD_TIMESTAMP));
+        assert_eq!(Some(info), inv.build_info());
+
+        inv.set_annotation(BUILD_TIMESTAMP, "yesterday");
+        let info = inv.build_info().expect("build info should still be set");
+        assert!(info.timestamp.is_none());
+        assert_eq!(Some("7c1f4a2".to_owned()), info.revision);
+    }
+
+    #[test]
+    fn test_label_annotations() {
+        let mut label = Label::new("vendor.js".to_owned(), "abc123".to_owned());
+        assert!(label.license().is_none());
+        assert!(label.origin().is_none());
+
+        label.set_license("MIT");
+        label.set_annotation(ORIGIN, "app/1.0.0");
+        assert_eq!(Some("MIT"), label.license());
+        assert_eq!(
+            "app/1.0.0",
+            label.origin().expect("origin should be set").to_string()
+        );
+        assert_eq!(2, label.annotations.as_ref().unwrap().len());
+    }
+}
@@ -1,12 +1,14 @@
 //! A collection of various utilities for asyncifying things, publicly exposed for convenience of
 //! those consuming Bindle as a Rust SDK
 
-use std::io::{Read, Write};
+use std::io::Write;
 use std::pin::Pin;
+#[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::task::{Context, Poll};
 
-use bytes::buf::{Buf, BufExt};
+use bytes::{Buf, Bytes};
 use sha2::{Digest, Sha256};
 use tokio::io::AsyncRead;
 use tokio::stream::Stream;
@@ -15,11 +17,31 @@ use tokio::stream::Stream;
 ///
 /// This might no longer be necessary once we hit tokio 0.3 and upgrade tokio-util. Tokio util has a
 /// StreamReader wrapper we can use, but there might still be some conversion stuff to deal with
-pub struct BodyReadBuffer<B, T, E>(pub T)
+pub struct BodyReadBuffer<B, T, E>
 where
     B: Buf,
     T: Stream<Item = Result<B, E>> + Unpin,
-    E: std::error::Error;
+    E: std::error::Error,
+{
+    stream: T,
+    // The rest of the last chunk from the stream, if it didn't fit into the read buffer
+    current: Option<Bytes>,
+}
+
+impl<B, T, E> BodyReadBuffer<B, T, E>
+where
+    B: Buf,
+    T: Stream<Item = Result<B, E>> + Unpin,
+    E: std::error::Error,
+{
+    /// Wraps the given stream
+    pub fn new(stream: T) -> Self {
+        BodyReadBuffer {
+            stream,
+            current: None,
+        }
+    }
+}
 
 impl<'a, B, T, E> AsyncRead for BodyReadBuffer<B, T, E>
 where
@@ -32,30 +54,71 @@ where
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<std::io::Result<usize>> {
-        let res = match Pin::new(&mut self.0).poll_next(cx) {
-            Poll::Pending => return Poll::Pending,
-            // End of stream maps to EOF in this situation
-            Poll::Ready(None) => return Poll::Ready(Ok(0)),
-            // If we get here, we can unwrap safely
-            Poll::Ready(Some(res)) => res,
-        };
-
-        let buffer = match res {
-            Ok(b) => b,
-            // There isn't much of a way to introspect a warp error easily so we can't really
-            // provide much context here with the right kind
-            Err(e) => {
-                return Poll::Ready(Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("{:?}", e), // dirty hack to get around lifetimes
-                )));
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                if current.has_remaining() {
+                    let n = std::cmp::min(buf.len(), current.remaining());
+                    current.copy_to_slice(&mut buf[..n]);
+                    return Poll::Ready(Ok(n));
+                }
             }
-        };
 
-        Poll::Ready(buffer.reader().read(buf))
+            let res = match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                // End of stream maps to EOF in this situation
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                // If we get here, we can unwrap safely
+                Poll::Ready(Some(res)) => res,
+            };
+
+            match res {
+                // Empty chunks are skipped by the loop, as returning 0 would signal EOF
+                Ok(mut b) => self.current = Some(b.to_bytes()),
+                // There isn't much of a way to introspect a warp error easily so we can't really
+                // provide much context here with the right kind
+                Err(e) => {
+                    return Poll::Ready(Err(std::io::Error::other(
+                        format!("{:?}", e), // dirty hack to get around lifetimes
+                    )));
+                }
+            }
+        }
     }
 }
 
+/// Returns a stream containing only the given range of the data in the given stream, starting at
+/// `offset` and containing at most `length` bytes (or everything after the offset if `None`).
+/// Chunks outside of the range are read and discarded, so this should only be used when the
+/// underlying data can't be read from an offset directly
+pub fn slice_stream<S, E>(
+    stream: S,
+    offset: u64,
+    length: Option<u64>,
+) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let end = length.map(|l| offset.saturating_add(l));
+    let mut position = 0u64;
+    let in_range = move |res: Result<Bytes, E>| {
+        let chunk = match res {
+            Ok(c) => c,
+            Err(e) => return futures::future::ready(Some(Err(e))),
+        };
+        let start = position;
+        let len = chunk.len() as u64;
+        position += len;
+        let from = std::cmp::min(offset.saturating_sub(start), len);
+        let to = end.map_or(len, |end| std::cmp::min(end.saturating_sub(start), len));
+        futures::future::ready(if from < to {
+            Some(Ok(chunk.slice(from as usize..to as usize)))
+        } else {
+            None
+        })
+    };
+    futures::StreamExt::filter_map(stream, in_range)
+}
+
 /// A wrapper to implement `AsyncWrite` on Sha256
 pub struct AsyncSha256 {
     inner: Mutex<Sha256>,
@@ -119,3 +182,321 @@ impl tokio::io::AsyncWrite for AsyncSha256 {
         self.poll_flush(cx)
     }
 }
+
+/// Why the data passing through a [`VerifyingStream`](VerifyingStream) was rejected
+#[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Mismatch {
+    Digest { expected: String, actual: String },
+    Size { expected: u64, actual: u64 },
+}
+
+#[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mismatch::Digest { expected, actual } => write!(
+                f,
+                "data has a SHA-256 sum of {}, but {} was expected",
+                actual, expected
+            ),
+            Mismatch::Size { expected, actual } => write!(
+                f,
+                "data is {} bytes long, but {} bytes were expected",
+                actual, expected
+            ),
+        }
+    }
+}
+
+/// Wraps a stream of parcel data, computing its SHA-256 sum and length as the data passes
+/// through. If the data doesn't match the expected values, the stream ends with an error instead
+/// of completing, which aborts whatever is consuming it (like an upload or a write to disk). The
+/// reason for the failure can be retrieved from the handle returned by
+/// [`failure`](VerifyingStream::failure)
+#[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+pub(crate) struct VerifyingStream<S> {
+    inner: S,
+    hasher: Sha256,
+    expected_sha: String,
+    expected_length: Option<u64>,
+    length: u64,
+    failure: Arc<Mutex<Option<Mismatch>>>,
+    done: bool,
+}
+
+#[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+impl<S> VerifyingStream<S> {
+    pub(crate) fn new(inner: S, expected_sha: &str, expected_length: Option<u64>) -> Self {
+        VerifyingStream {
+            inner,
+            hasher: Sha256::new(),
+            expected_sha: expected_sha.to_owned(),
+            expected_length,
+            length: 0,
+            failure: Arc::new(Mutex::new(None)),
+            done: false,
+        }
+    }
+
+    /// Returns a handle that contains the reason the verification failed (if it did) once the
+    /// stream has ended
+    pub(crate) fn failure(&self) -> Arc<Mutex<Option<Mismatch>>> {
+        self.failure.clone()
+    }
+
+    fn fail(&mut self, err: Mismatch) -> std::io::Error {
+        self.done = true;
+        let io_err = std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string());
+        *self.failure.lock().unwrap() = Some(err);
+        io_err
+    }
+
+    fn check_length(&mut self, done: bool) -> Result<(), std::io::Error> {
+        match self.expected_length {
+            // Bail out as soon as we know there is too much data rather than waiting for the end
+            Some(expected) if self.length > expected || (done && self.length != expected) => {
+                let actual = self.length;
+                Err(self.fail(Mismatch::Size { expected, actual }))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+impl<S, B> Stream for VerifyingStream<S>
+where
+    S: Stream<Item = std::io::Result<B>> + Unpin,
+    B: Buf,
+{
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        match futures::ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+            Some(Ok(mut buf)) => {
+                let bytes = buf.to_bytes();
+                self.length += bytes.len() as u64;
+                if let Err(e) = self.check_length(false) {
+                    return Poll::Ready(Some(Err(e)));
+                }
+                self.hasher.update(&bytes);
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            Some(Err(e)) => Poll::Ready(Some(Err(e))),
+            None => {
+                if let Err(e) = self.check_length(true) {
+                    return Poll::Ready(Some(Err(e)));
+                }
+                self.done = true;
+                let actual = format!("{:x}", self.hasher.finalize_reset());
+                if actual != self.expected_sha {
+                    let expected = self.expected_sha.clone();
+                    return Poll::Ready(Some(
+                        Err(self.fail(Mismatch::Digest { expected, actual })),
+                    ));
+                }
+                Poll::Ready(None)
+            }
+        }
+    }
+}
+
+/// Wraps a stream of data, holding back each chunk until the next one arrives or the stream ends.
+/// In front of a [`VerifyingStream`](VerifyingStream), this makes sure the consumer never receives
+/// all of the data if it fails verification, as the error takes the place of the last chunk. This
+/// matters for responses with a `Content-Length`, which look complete once all bytes are sent
+#[cfg(feature = "provider-file")]
+pub(crate) struct HoldLast<S> {
+    inner: S,
+    held: Option<Bytes>,
+    done: bool,
+}
+
+#[cfg(feature = "provider-file")]
+impl<S> HoldLast<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        HoldLast {
+            inner,
+            held: None,
+            done: false,
+        }
+    }
+}
+
+#[cfg(feature = "provider-file")]
+impl<S, E> Stream for HoldLast<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.done {
+                return Poll::Ready(self.held.take().map(Ok));
+            }
+            match futures::ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(bytes)) => {
+                    if let Some(previous) = self.held.replace(bytes) {
+                        return Poll::Ready(Some(Ok(previous)));
+                    }
+                }
+                Some(Err(e)) => {
+                    self.held = None;
+                    self.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                None => self.done = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::stream::StreamExt;
+
+    #[tokio::test]
+    async fn test_body_read_buffer_large_chunks() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let chunks = vec![
+            Ok::<_, std::io::Error>(Bytes::from(data[..7000].to_vec())),
+            Ok(Bytes::new()),
+            Ok(Bytes::from(data[7000..].to_vec())),
+        ];
+        let mut reader = BodyReadBuffer::new(tokio::stream::iter(chunks));
+        let mut out = Vec::new();
+        // Read with a buffer smaller than the chunks so they have to be split across reads
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = reader.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert!(out == data, "All of the data should be read");
+    }
+
+    #[tokio::test]
+    async fn test_slice_stream() {
+        use tokio::stream::StreamExt;
+
+        let chunks = || {
+            tokio::stream::iter(vec![
+                Ok::<_, std::io::Error>(Bytes::from_static(b"hello ")),
+                Ok(Bytes::from_static(b"wonderful ")),
+                Ok(Bytes::from_static(b"world")),
+            ])
+        };
+        let collect = |offset, length| async move {
+            slice_stream(chunks(), offset, length)
+                .map(|b| b.unwrap())
+                .collect::<Vec<_>>()
+                .await
+                .concat()
+        };
+        assert_eq!(b"o wonderful w".to_vec(), collect(4, Some(13)).await);
+        assert_eq!(b"world".to_vec(), collect(16, None).await);
+        assert_eq!(b"hello".to_vec(), collect(0, Some(5)).await);
+        assert!(collect(100, None).await.is_empty());
+    }
+
+    #[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+    const DATA: &[&str] = &["hello ", "world"];
+    #[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+    // sha256 of "hello world"
+    const SHA: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+    async fn drain(
+        expected_sha: &str,
+        expected_length: Option<u64>,
+    ) -> (Vec<std::io::Result<Bytes>>, Option<Mismatch>) {
+        let inner = tokio::stream::iter(
+            DATA.iter()
+                .map(|s| Ok::<_, std::io::Error>(Bytes::from_static(s.as_bytes())))
+                .collect::<Vec<_>>(),
+        );
+        let stream = VerifyingStream::new(inner, expected_sha, expected_length);
+        let failure = stream.failure();
+        let items = stream.collect::<Vec<_>>().await;
+        let failure = failure.lock().unwrap().take();
+        (items, failure)
+    }
+
+    #[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+    #[tokio::test]
+    async fn test_verifying_stream() {
+        let (items, failure) = drain(SHA, Some(11)).await;
+        assert!(items.iter().all(|i| i.is_ok()));
+        assert_eq!(2, items.len());
+        assert!(failure.is_none());
+
+        // The length is optional
+        let (items, failure) = drain(SHA, None).await;
+        assert!(items.iter().all(|i| i.is_ok()));
+        assert!(failure.is_none());
+
+        let (items, failure) = drain("abc123", None).await;
+        assert!(items.last().unwrap().is_err());
+        assert!(matches!(
+            failure,
+            Some(Mismatch::Digest { ref actual, .. }) if actual == SHA
+        ));
+
+        // Too much data should fail as soon as it is seen
+        let (items, failure) = drain(SHA, Some(3)).await;
+        assert_eq!(1, items.len());
+        assert!(items[0].is_err());
+        assert!(matches!(
+            failure,
+            Some(Mismatch::Size {
+                expected: 3,
+                actual: 6
+            })
+        ));
+
+        let (items, failure) = drain(SHA, Some(20)).await;
+        assert!(items.last().unwrap().is_err());
+        assert!(matches!(
+            failure,
+            Some(Mismatch::Size {
+                expected: 20,
+                actual: 11
+            })
+        ));
+    }
+
+    #[cfg(feature = "provider-file")]
+    #[tokio::test]
+    async fn test_hold_last() {
+        let chunks = vec![
+            Ok::<_, std::io::Error>(Bytes::from_static(b"hello ")),
+            Ok(Bytes::from_static(b"world")),
+        ];
+        let data: Vec<Bytes> = HoldLast::new(tokio::stream::iter(chunks))
+            .collect::<std::io::Result<_>>()
+            .await
+            .expect("all chunks should be passed through");
+        assert_eq!(vec!["hello ", "world"], data);
+
+        // The error replaces the chunk before it
+        let chunks = vec![
+            Ok(Bytes::from_static(b"hello ")),
+            Ok(Bytes::from_static(b"world")),
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad")),
+        ];
+        let items: Vec<_> = HoldLast::new(tokio::stream::iter(chunks)).collect().await;
+        assert_eq!(2, items.len());
+        assert_eq!(&b"hello "[..], items[0].as_ref().unwrap());
+        assert!(items[1].is_err());
+    }
+}
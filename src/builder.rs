@@ -0,0 +1,462 @@
+//! Builders for constructing invoices in code.
+//!
+//! Filling in an [`Invoice`](crate::Invoice) by hand means setting a lot of optional fields.
+//! [`InvoiceBuilder`](InvoiceBuilder) sets the `bindleVersion` and leaves out anything that wasn't
+//! given, and [`LabelBuilder::from_file`](LabelBuilder::from_file) computes the SHA, size and media
+//! type of a parcel from its file. [`InvoiceBuilder::build`](InvoiceBuilder::build) checks that the
+//! resulting invoice is valid:
+//!
+//! ```
+//! use bindle::builder::{InvoiceBuilder, LabelBuilder, ParcelBuilder};
+//!
+//! let label = LabelBuilder::new(
+//!     "app.wasm",
+//!     "b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c",
+//!     4,
+//! )
+//! .media_type("application/wasm")
+//! .build();
+//! let inv = InvoiceBuilder::new("example.com/app", "1.0.0")
+//!     .description("An example app")
+//!     .parcel(ParcelBuilder::new(label).member_of("server").build())
+//!     .group("server", true)
+//!     .build()
+//!     .expect("invoice should be valid");
+//!
+//! assert_eq!(bindle::BINDLE_VERSION_1, inv.bindle_version);
+//! assert_eq!("example.com/app/1.0.0", inv.bindle.id.to_string());
+//! ```
+
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    AnnotationMap, BindleSpec, Condition, Dependency, FeatureMap, Group, Id, Invoice, Label,
+    Parcel, BINDLE_VERSION_1,
+};
+
+/// The reasons an [`InvoiceBuilder`](InvoiceBuilder) can refuse to build an invoice
+#[derive(Error, Debug)]
+pub enum BuildError {
+    /// The name and version don't form a valid bindle ID
+    #[error("Invalid bindle ID: {0}")]
+    InvalidId(#[from] crate::id::ParseError),
+    /// A dependency has a version range that isn't valid SemVer. Contains the dependency
+    #[error("Dependency {0} has an invalid version range")]
+    InvalidDependency(String),
+    /// A parcel's SHA isn't a hex encoded SHA-256 sum. Contains the name of the parcel
+    #[error("Parcel {0} does not have a valid SHA-256 sum")]
+    InvalidSha(String),
+    /// More than one parcel has the same SHA. Contains the SHA
+    #[error("More than one parcel has the SHA {0}")]
+    DuplicateParcel(String),
+    /// More than one group has the same name. Contains the name
+    #[error("More than one group is named {0}")]
+    DuplicateGroup(String),
+    /// A parcel is a member of or requires a group the invoice doesn't define. Contains the names
+    /// of the parcel and the group
+    #[error("Parcel {parcel} refers to the undefined group {group}")]
+    UndefinedGroup { parcel: String, group: String },
+}
+
+/// Builds an [`Invoice`](crate::Invoice). See the [module documentation](self) for an example
+#[derive(Debug, Clone)]
+pub struct InvoiceBuilder {
+    name: String,
+    version: String,
+    description: Option<String>,
+    authors: Vec<String>,
+    annotations: Option<AnnotationMap>,
+    requires: Vec<Dependency>,
+    parcels: Vec<Parcel>,
+    groups: Vec<Group>,
+}
+
+impl InvoiceBuilder {
+    /// Starts an invoice for the bindle with the given name and SemVer version
+    pub fn new(name: impl Into<String>, version: impl Into<String>) -> Self {
+        InvoiceBuilder {
+            name: name.into(),
+            version: version.into(),
+            description: None,
+            authors: Vec::new(),
+            annotations: None,
+            requires: Vec::new(),
+            parcels: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    /// Sets the description of the bindle
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Adds an author of the bindle
+    pub fn author(mut self, author: impl Into<String>) -> Self {
+        self.authors.push(author.into());
+        self
+    }
+
+    /// Sets an annotation of the invoice, replacing any previous value
+    pub fn annotation(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.annotations
+            .get_or_insert_with(AnnotationMap::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    /// Adds a dependency on the bindle with the given name and SemVer version range. An empty range
+    /// is satisfied by any version
+    pub fn requires(mut self, name: impl Into<String>, version: impl Into<String>) -> Self {
+        self.requires.push(Dependency {
+            name: name.into(),
+            version: version.into(),
+        });
+        self
+    }
+
+    /// Adds a parcel. Use a [`ParcelBuilder`](ParcelBuilder) to build one
+    pub fn parcel(mut self, parcel: Parcel) -> Self {
+        self.parcels.push(parcel);
+        self
+    }
+
+    /// Adds a group with the default `allOf` criterion
+    pub fn group(self, name: impl Into<String>, required: bool) -> Self {
+        self.group_with(Group {
+            name: name.into(),
+            required: Some(required),
+            satisfied_by: None,
+        })
+    }
+
+    /// Adds a fully specified group, such as one with a `satisfiedBy` criterion
+    pub fn group_with(mut self, group: Group) -> Self {
+        self.groups.push(group);
+        self
+    }
+
+    /// Validates and returns the invoice. Fails if the ID or a dependency range is invalid, if a
+    /// parcel has an invalid SHA, if parcels or groups are duplicated, or if a parcel refers to a
+    /// group that isn't defined
+    pub fn build(self) -> Result<Invoice, BuildError> {
+        let id: Id = format!("{}/{}", self.name, self.version).parse()?;
+        for dep in &self.requires {
+            if !dep.version.is_empty() && semver::VersionReq::parse(&dep.version).is_err() {
+                return Err(BuildError::InvalidDependency(dep.to_string()));
+            }
+        }
+
+        let mut group_names = HashSet::new();
+        for group in &self.groups {
+            if !group_names.insert(group.name.as_str()) {
+                return Err(BuildError::DuplicateGroup(group.name.clone()));
+            }
+        }
+        let mut shas = HashSet::new();
+        for parcel in &self.parcels {
+            let label = &parcel.label;
+            if label.sha256.len() != 64 || !label.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(BuildError::InvalidSha(label.name.clone()));
+            }
+            if !shas.insert(label.sha256.as_str()) {
+                return Err(BuildError::DuplicateParcel(label.sha256.clone()));
+            }
+            let referenced = parcel.conditions.iter().flat_map(|c| {
+                c.member_of
+                    .iter()
+                    .chain(c.requires.iter())
+                    .flatten()
+                    .map(String::as_str)
+            });
+            for group in referenced {
+                if !group_names.contains(group) {
+                    return Err(BuildError::UndefinedGroup {
+                        parcel: label.name.clone(),
+                        group: group.to_owned(),
+                    });
+                }
+            }
+        }
+
+        Ok(Invoice {
+            bindle_version: BINDLE_VERSION_1.to_owned(),
+            yanked: None,
+            bindle: BindleSpec {
+                id,
+                description: self.description,
+                authors: non_empty(self.authors),
+            },
+            annotations: self.annotations,
+            requires: non_empty(self.requires),
+            parcel: non_empty(self.parcels),
+            group: non_empty(self.groups),
+            signature: None,
+        })
+    }
+}
+
+/// Builds a [`Parcel`](crate::Parcel) from its label and the groups it belongs to or requires
+#[derive(Debug, Clone)]
+pub struct ParcelBuilder {
+    label: Label,
+    member_of: Vec<String>,
+    requires: Vec<String>,
+}
+
+impl ParcelBuilder {
+    /// Starts a parcel with the given label
+    pub fn new(label: Label) -> Self {
+        ParcelBuilder {
+            label,
+            member_of: Vec::new(),
+            requires: Vec::new(),
+        }
+    }
+
+    /// Starts a parcel with a label for the given file. See
+    /// [`LabelBuilder::from_file`](LabelBuilder::from_file)
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(ParcelBuilder::new(LabelBuilder::from_file(path)?.build()))
+    }
+
+    /// Makes the parcel a member of the given group. Parcels without groups are in the global group
+    pub fn member_of(mut self, group: impl Into<String>) -> Self {
+        self.member_of.push(group.into());
+        self
+    }
+
+    /// Makes the parcel require the given group
+    pub fn requires(mut self, group: impl Into<String>) -> Self {
+        self.requires.push(group.into());
+        self
+    }
+
+    /// Returns the parcel. The conditions are left out if it has no groups
+    pub fn build(self) -> Parcel {
+        let conditions = match (non_empty(self.member_of), non_empty(self.requires)) {
+            (None, None) => None,
+            (member_of, requires) => Some(Condition {
+                member_of,
+                requires,
+            }),
+        };
+        Parcel {
+            label: self.label,
+            conditions,
+        }
+    }
+}
+
+/// Builds a [`Label`](crate::Label)
+#[derive(Debug, Clone)]
+pub struct LabelBuilder {
+    label: Label,
+}
+
+impl LabelBuilder {
+    /// Starts a label for a parcel with the given name, SHA-256 sum and size in bytes. The media
+    /// type defaults to `application/octet-stream`
+    pub fn new(name: impl Into<String>, sha256: impl Into<String>, size: u64) -> Self {
+        LabelBuilder {
+            label: Label {
+                name: name.into(),
+                sha256: sha256.into(),
+                size,
+                ..Label::default()
+            },
+        }
+    }
+
+    /// Starts a label for the given file, named after the file and with its SHA-256 sum and size.
+    /// With the `client` feature, the media type is guessed from the file extension. The file is
+    /// read synchronously, so async code should call this on a blocking thread
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = [0; 8192];
+        let mut size = 0;
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            size += read as u64;
+        }
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(
+            LabelBuilder::new(name, format!("{:x}", hasher.finalize()), size)
+                .media_type(guess_media_type(path)),
+        )
+    }
+
+    /// Overrides the name of the parcel
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.label.name = name.into();
+        self
+    }
+
+    /// Sets the media type of the parcel
+    pub fn media_type(mut self, media_type: impl Into<String>) -> Self {
+        self.label.media_type = media_type.into();
+        self
+    }
+
+    /// Sets an annotation of the label, replacing any previous value
+    pub fn annotation(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.label.set_annotation(key, value);
+        self
+    }
+
+    /// Sets a feature of the label, replacing any previous value
+    pub fn feature(mut self, group: &str, name: &str, value: impl Into<String>) -> Self {
+        self.label.set_feature(group, name, value);
+        self
+    }
+
+    /// Replaces all features of the label, such as with a map from a
+    /// [`FeatureBuilder`](crate::features::FeatureBuilder)
+    pub fn features(mut self, features: FeatureMap) -> Self {
+        self.label.feature = Some(features);
+        self
+    }
+
+    /// Returns the label
+    pub fn build(self) -> Label {
+        self.label
+    }
+}
+
+#[cfg(feature = "client")]
+fn guess_media_type(path: &Path) -> String {
+    mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+#[cfg(not(feature = "client"))]
+fn guess_media_type(_: &Path) -> String {
+    Label::default().media_type
+}
+
+fn non_empty<T>(items: Vec<T>) -> Option<Vec<T>> {
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHA: &str = "b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c";
+
+    #[test]
+    fn test_build_invoice() {
+        let inv = InvoiceBuilder::new("app", "1.0.0")
+            .author("Ferris")
+            .annotation("custom", "value")
+            .requires("lib", "^1.2")
+            .parcel(ParcelBuilder::new(LabelBuilder::new("a", SHA, 4).build()).build())
+            .build()
+            .expect("invoice should be valid");
+        assert_eq!(Some(vec!["Ferris".to_owned()]), inv.bindle.authors);
+        assert_eq!(1, inv.dependencies().len());
+        let parcels = inv.parcel.expect("parcels should be set");
+        assert!(parcels[0].conditions.is_none());
+        assert!(parcels[0].is_global_group());
+        assert!(inv.group.is_none());
+        assert!(inv.signature.is_none());
+
+        // The built invoice round trips through TOML
+        let inv = InvoiceBuilder::new("app", "1.0.0")
+            .build()
+            .expect("empty invoice should be valid");
+        let raw = toml::to_string(&inv).expect("invoice should serialize");
+        let parsed: Invoice = toml::from_str(&raw).expect("invoice should parse");
+        assert_eq!(inv.bindle.id.to_string(), parsed.bindle.id.to_string());
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(matches!(
+            InvoiceBuilder::new("app", "one").build(),
+            Err(BuildError::InvalidId(_))
+        ));
+        assert!(matches!(
+            InvoiceBuilder::new("app", "1.0.0")
+                .requires("lib", "not a range")
+                .build(),
+            Err(BuildError::InvalidDependency(_))
+        ));
+        assert!(matches!(
+            InvoiceBuilder::new("app", "1.0.0")
+                .parcel(ParcelBuilder::new(LabelBuilder::new("a", "abc", 1).build()).build())
+                .build(),
+            Err(BuildError::InvalidSha(_))
+        ));
+        let parcel = ParcelBuilder::new(LabelBuilder::new("a", SHA, 4).build()).build();
+        assert!(matches!(
+            InvoiceBuilder::new("app", "1.0.0")
+                .parcel(parcel.clone())
+                .parcel(parcel.clone())
+                .build(),
+            Err(BuildError::DuplicateParcel(_))
+        ));
+        assert!(matches!(
+            InvoiceBuilder::new("app", "1.0.0")
+                .group("server", true)
+                .group("server", false)
+                .build(),
+            Err(BuildError::DuplicateGroup(_))
+        ));
+        let member = ParcelBuilder::new(parcel.label.clone())
+            .member_of("server")
+            .requires("client")
+            .build();
+        assert!(matches!(
+            InvoiceBuilder::new("app", "1.0.0")
+                .parcel(member.clone())
+                .group("server", true)
+                .build(),
+            Err(BuildError::UndefinedGroup { group, .. }) if group == "client"
+        ));
+        InvoiceBuilder::new("app", "1.0.0")
+            .parcel(member)
+            .group("server", true)
+            .group("client", false)
+            .build()
+            .expect("all groups are defined");
+    }
+
+    #[test]
+    fn test_label_from_file() {
+        let dir = tempfile::tempdir().expect("unable to create tempdir");
+        let path = dir.path().join("foo.txt");
+        std::fs::write(&path, b"foo\n").expect("unable to write file");
+
+        let label = LabelBuilder::from_file(&path)
+            .expect("label should be built")
+            .annotation("custom", "value")
+            .build();
+        assert_eq!("foo.txt", label.name);
+        assert_eq!(SHA, label.sha256);
+        assert_eq!(4, label.size);
+        assert_eq!(Some("value"), label.annotation("custom"));
+        #[cfg(feature = "client")]
+        assert_eq!("text/plain", label.media_type);
+    }
+}
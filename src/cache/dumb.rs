@@ -1,10 +1,12 @@
 //! A cache that doesn't ever expire entries, generally for use by a client storing bindles on disk
 use std::convert::TryInto;
 
-use log::{info, warn};
+use std::time::Duration;
+
+use log::{debug, info, warn};
 use tokio::stream::{Stream, StreamExt};
 
-use super::{into_cache_result, Cache};
+use super::{into_cache_result, invoice_key, parcel_key, Cache, NegativeCache};
 use crate::provider::{Provider, ProviderError, Result};
 use crate::Id;
 
@@ -14,11 +16,23 @@ use crate::Id;
 pub struct DumbCache<Local: Provider + Clone, Remote: Provider + Clone> {
     remote: Remote,
     local: Local,
+    negative: NegativeCache,
 }
 
 impl<Local: Provider + Clone, Remote: Provider + Clone> DumbCache<Local, Remote> {
     pub fn new(remote: Remote, local: Local) -> DumbCache<Local, Remote> {
-        DumbCache { remote, local }
+        DumbCache {
+            remote,
+            local,
+            negative: NegativeCache::default(),
+        }
+    }
+
+    /// Remembers invoices and parcels the remote provider doesn't have for the given time, during
+    /// which looking them up again returns a `NotFound` error without asking the remote provider
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative = NegativeCache::new(ttl);
+        self
     }
 }
 
@@ -52,11 +66,18 @@ where
         match possible_entry {
             Some(inv) => Ok(inv),
             None => {
+                let key = invoice_key(&parsed_id);
+                if self.negative.is_missing(&key) {
+                    debug!("Invoice {} is known to be missing on the server", parsed_id);
+                    return Err(ProviderError::NotFound);
+                }
                 info!(
                     "Cache miss for invoice {}, attempting to fetch from server",
                     parsed_id
                 );
-                let inv = self.remote.get_yanked_invoice(parsed_id).await?;
+                let inv = self
+                    .negative
+                    .check(key, self.remote.get_yanked_invoice(parsed_id).await)?;
                 // Attempt to insert the invoice into the store, if it fails, warn the user and return the invoice anyway
                 if let Err(e) = self.local.create_invoice(&inv).await {
                     warn!("Fetched invoice from server, but encountered error when trying to save to local store: {:?}", e);
@@ -75,6 +96,24 @@ where
         self.local.yank_invoice(id).await
     }
 
+    // Same as yanking, this only removes the invoice from the local cache
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.local.delete_invoice(id).await
+    }
+
+    // History is constantly changing and only authoritative on the server, so it is never cached
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.remote.get_invoice_history(id).await
+    }
+
     async fn create_parcel<I, R, B>(&self, _: I, _: &str, _: R) -> Result<()>
     where
         I: TryInto<Id> + Send,
@@ -101,6 +140,11 @@ where
         match possible_entry {
             Some(parcel) => Ok(parcel),
             None => {
+                let key = parcel_key(&parsed_id, parcel_id);
+                if self.negative.is_missing(&key) {
+                    debug!("Parcel {} is known to be missing on the server", parcel_id);
+                    return Err(ProviderError::NotFound);
+                }
                 info!(
                     "Cache miss for parcel {}, attempting to fetch from server",
                     parcel_id
@@ -111,9 +155,11 @@ where
                     ..crate::Label::default()
                 };
                 let stream = self
-                    .remote
-                    .get_parcel(parsed_id.clone(), parcel_id)
-                    .await?
+                    .negative
+                    .check(
+                        key,
+                        self.remote.get_parcel(parsed_id.clone(), parcel_id).await,
+                    )?
                     // This isn't my favorite. Right now we are mapping to an io error which will be mapped back to a storage error
                     .map(|res| {
                         res.map_err(|e| {
@@ -147,4 +193,18 @@ where
     {
         self.local.parcel_exists(bindle_id, parcel_id).await
     }
+
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        self.local.missing_shas(shas).await
+    }
+
+    /// Collects garbage in the local storage. Parcels are cached separately from their invoices,
+    /// so this also evicts cached parcels whose invoice isn't cached
+    async fn collect_garbage(&self, dry_run: bool) -> Result<crate::provider::gc::GcReport> {
+        self.local.collect_garbage(dry_run).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.local.flush().await
+    }
 }
@@ -1,6 +1,704 @@
-//! A least recently used cache implementation
+//! A least recently used cache implementation, for long running clients that need to bound how much
+//! they store
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-/// A least recently used cache implementation
-// TODO: Actually implement
-#[allow(dead_code)]
-pub struct LruCache {}
+use log::{debug, info, warn};
+use tokio::stream::{Stream, StreamExt};
+
+use super::{into_cache_result, invoice_key, parcel_key, Cache, NegativeCache};
+use crate::provider::{Provider, ProviderError, Result};
+use crate::Id;
+
+/// Metrics about the usage of an [`LruCache`](LruCache)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheMetrics {
+    /// The number of invoices and parcels served from the local storage
+    pub hits: u64,
+    /// The number of invoices and parcels that had to be fetched from the remote provider
+    pub misses: u64,
+    /// The number of lookups answered with `NotFound` because the remote provider recently didn't
+    /// have the invoice or parcel (see [`with_negative_ttl`](LruCache::with_negative_ttl))
+    pub negative_hits: u64,
+    /// The number of invoices evicted from the local storage, along with their parcels
+    pub evictions: u64,
+    /// The number of invoices currently tracked by the cache
+    pub invoices: u64,
+    /// The combined size in bytes of the parcels currently tracked by the cache
+    pub parcel_bytes: u64,
+}
+
+/// A cache that evicts the least recently used bindles once it holds more invoices or more parcel
+/// data than its budget allows. Like the [`DumbCache`](super::DumbCache), it fills the local storage
+/// by requesting bindles from the remote provider.
+///
+/// Bindles are evicted as a whole by deleting their invoice from the local storage, which also
+/// removes the parcels no other cached invoice references, so the local provider must support
+/// [`delete_invoice`](Provider::delete_invoice). Using a parcel counts as using its bindle. Only
+/// bindles used through this cache are tracked, so anything that was in the local storage before
+/// is only evicted once it has been used again.
+///
+/// As invoices can only change by being yanked, a cached invoice that is older than the configured
+/// time to live is revalidated against the remote provider on its next use, which picks up whether
+/// it was yanked (or deleted). Parcels are content addressed, so they never go stale. Invoices and
+/// parcels the remote provider doesn't have can be remembered for a separate, usually much shorter,
+/// time to live, so clients retrying in a tight loop don't hammer the remote provider
+#[derive(Clone)]
+pub struct LruCache<Local: Provider + Clone, Remote: Provider + Clone> {
+    remote: Remote,
+    local: Local,
+    max_invoices: Option<usize>,
+    max_parcel_bytes: Option<u64>,
+    ttl: Option<Duration>,
+    negative: NegativeCache,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Incremented on every use of a bindle, so entries can be ordered by how recently they were used
+    clock: u64,
+    /// The tracked invoices, keyed by their ID
+    invoices: HashMap<String, InvoiceEntry>,
+    /// The sizes of the tracked parcels, keyed by their SHA
+    parcels: HashMap<String, u64>,
+    hits: u64,
+    misses: u64,
+    negative_hits: u64,
+    evictions: u64,
+}
+
+struct InvoiceEntry {
+    id: Id,
+    last_used: u64,
+    /// When the invoice was fetched or last revalidated. This is unknown for invoices that were
+    /// already in the local storage
+    fetched: Option<Instant>,
+}
+
+impl State {
+    fn parcel_bytes(&self) -> u64 {
+        self.parcels.values().sum()
+    }
+
+    /// Marks the bindle as used, starting to track it if needed
+    fn touch(&mut self, id: &Id) -> &mut InvoiceEntry {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self
+            .invoices
+            .entry(id.to_string())
+            .or_insert_with(|| InvoiceEntry {
+                id: id.clone(),
+                last_used: clock,
+                fetched: None,
+            });
+        entry.last_used = clock;
+        entry
+    }
+}
+
+impl<Local: Provider + Clone, Remote: Provider + Clone> LruCache<Local, Remote> {
+    /// Creates a cache without any limits. Use the `with_*` methods to configure them
+    pub fn new(remote: Remote, local: Local) -> LruCache<Local, Remote> {
+        LruCache {
+            remote,
+            local,
+            max_invoices: None,
+            max_parcel_bytes: None,
+            ttl: None,
+            negative: NegativeCache::default(),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Sets the maximum number of invoices to keep
+    pub fn with_max_invoices(mut self, max: usize) -> Self {
+        self.max_invoices = Some(max);
+        self
+    }
+
+    /// Sets the maximum combined size of the cached parcels in bytes
+    pub fn with_max_parcel_bytes(mut self, max: u64) -> Self {
+        self.max_parcel_bytes = Some(max);
+        self
+    }
+
+    /// Sets how long a cached invoice is used before it is revalidated against the remote provider
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets how long invoices and parcels the remote provider doesn't have are remembered, during
+    /// which looking them up again returns a `NotFound` error without asking the remote provider
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative = NegativeCache::new(ttl);
+        self
+    }
+
+    /// Returns the current metrics of the cache
+    pub fn metrics(&self) -> CacheMetrics {
+        let state = self.state.lock().unwrap();
+        CacheMetrics {
+            hits: state.hits,
+            misses: state.misses,
+            negative_hits: state.negative_hits,
+            evictions: state.evictions,
+            invoices: state.invoices.len() as u64,
+            parcel_bytes: state.parcel_bytes(),
+        }
+    }
+
+    fn is_over_budget(&self, state: &State) -> bool {
+        self.max_invoices
+            .map(|max| state.invoices.len() > max)
+            .unwrap_or(false)
+            || self
+                .max_parcel_bytes
+                .map(|max| state.parcel_bytes() > max)
+                .unwrap_or(false)
+    }
+
+    fn untrack(&self, id: &Id, removed_parcels: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        state.invoices.remove(&id.to_string());
+        for sha in removed_parcels {
+            state.parcels.remove(sha);
+        }
+    }
+}
+
+impl<Local, Remote> LruCache<Local, Remote>
+where
+    Local: Provider + Send + Sync + Clone,
+    Remote: Provider + Send + Sync + Clone,
+{
+    /// Evicts the least recently used bindles until the cache is within its budget again. The
+    /// bindle being used is never evicted, even if it doesn't fit in the budget by itself
+    async fn evict(&self, in_use: &Id) {
+        let in_use = in_use.to_string();
+        loop {
+            let victim = {
+                let mut state = self.state.lock().unwrap();
+                if !self.is_over_budget(&state) {
+                    return;
+                }
+                let victim = state
+                    .invoices
+                    .iter()
+                    .filter(|(key, _)| **key != in_use)
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, entry)| (key.clone(), entry.id.clone()));
+                match victim {
+                    // Stop tracking the victim right away, so a failed eviction can't be retried
+                    // forever
+                    Some((key, id)) => {
+                        state.invoices.remove(&key);
+                        id
+                    }
+                    None => return,
+                }
+            };
+            match self.local.delete_invoice(&victim).await {
+                Ok(removed) => {
+                    debug!(
+                        "Evicted bindle {} from the cache, along with {} parcels",
+                        victim,
+                        removed.len()
+                    );
+                    self.untrack(&victim, &removed);
+                    self.state.lock().unwrap().evictions += 1;
+                }
+                Err(e) => warn!("Unable to evict bindle {} from the cache: {}", victim, e),
+            }
+        }
+    }
+
+    /// Checks a cached invoice against the remote provider, yanking it in the local storage if it
+    /// was yanked remotely. If the remote provider can't be reached, the cached invoice is used
+    async fn revalidate(&self, cached: crate::Invoice) -> Result<crate::Invoice> {
+        let id = cached.bindle.id.clone();
+        debug!("Revalidating cached invoice {}", id);
+        let inv = match self.remote.get_yanked_invoice(&id).await {
+            Ok(inv) => inv,
+            Err(ProviderError::NotFound) => {
+                info!(
+                    "Invoice {} no longer exists, evicting it from the cache",
+                    id
+                );
+                match self.local.delete_invoice(&id).await {
+                    Ok(removed) => self.untrack(&id, &removed),
+                    Err(e) => warn!("Unable to evict bindle {} from the cache: {}", id, e),
+                }
+                return Err(ProviderError::NotFound);
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to revalidate invoice {}, using the cached copy: {}",
+                    id, e
+                );
+                return Ok(cached);
+            }
+        };
+        if inv.yanked.unwrap_or(false) && !cached.yanked.unwrap_or(false) {
+            if let Err(e) = self.local.yank_invoice(&id).await {
+                warn!("Unable to yank invoice {} in the cache: {}", id, e);
+            }
+        }
+        self.state.lock().unwrap().touch(&id).fetched = Some(Instant::now());
+        Ok(inv)
+    }
+}
+
+impl<Local, Remote> Cache for LruCache<Local, Remote>
+where
+    Local: Provider + Send + Sync + Clone,
+    Remote: Provider + Send + Sync + Clone,
+{
+}
+
+#[async_trait::async_trait]
+impl<Local, Remote> Provider for LruCache<Local, Remote>
+where
+    Local: Provider + Send + Sync + Clone,
+    Remote: Provider + Send + Sync + Clone,
+{
+    async fn create_invoice(&self, _: &crate::Invoice) -> Result<Vec<crate::Label>> {
+        Err(ProviderError::Other(
+            "This cache implementation does not allow for creation of invoices".to_string(),
+        ))
+    }
+
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let possible_entry = into_cache_result(self.local.get_yanked_invoice(&parsed_id).await)?;
+        match possible_entry {
+            Some(inv) => {
+                let expired = {
+                    let mut state = self.state.lock().unwrap();
+                    state.hits += 1;
+                    let fetched = state.touch(&parsed_id).fetched;
+                    match (self.ttl, fetched) {
+                        (Some(ttl), Some(fetched)) => fetched.elapsed() >= ttl,
+                        (Some(_), None) => true,
+                        (None, _) => false,
+                    }
+                };
+                let inv = if expired {
+                    self.revalidate(inv).await?
+                } else {
+                    inv
+                };
+                // A bindle that was already in the local storage is now tracked as well
+                self.evict(&parsed_id).await;
+                Ok(inv)
+            }
+            None => {
+                let key = invoice_key(&parsed_id);
+                if self.negative.is_missing(&key) {
+                    debug!("Invoice {} is known to be missing on the server", parsed_id);
+                    self.state.lock().unwrap().negative_hits += 1;
+                    return Err(ProviderError::NotFound);
+                }
+                info!(
+                    "Cache miss for invoice {}, attempting to fetch from server",
+                    parsed_id
+                );
+                self.state.lock().unwrap().misses += 1;
+                let inv = self
+                    .negative
+                    .check(key, self.remote.get_yanked_invoice(&parsed_id).await)?;
+                // Attempt to insert the invoice into the store, if it fails, warn the user and return the invoice anyway
+                match self.local.create_invoice(&inv).await {
+                    Ok(_) => {
+                        self.state.lock().unwrap().touch(&parsed_id).fetched = Some(Instant::now());
+                        self.evict(&parsed_id).await;
+                    }
+                    Err(e) => warn!("Fetched invoice from server, but encountered error when trying to save to local store: {:?}", e),
+                }
+                Ok(inv)
+            }
+        }
+    }
+
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // This is just an update of the local cache
+        self.local.yank_invoice(id).await
+    }
+
+    // Same as yanking, this only removes the invoice from the local cache
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let removed = self.local.delete_invoice(&parsed_id).await?;
+        self.untrack(&parsed_id, &removed);
+        Ok(removed)
+    }
+
+    // History is constantly changing and only authoritative on the server, so it is never cached
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.remote.get_invoice_history(id).await
+    }
+
+    async fn create_parcel<I, R, B>(&self, _: I, _: &str, _: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync,
+        B: bytes::Buf,
+    {
+        Err(ProviderError::Other(
+            "This cache implementation does not allow for creation of parcels".to_string(),
+        ))
+    }
+
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        let possible_entry = into_cache_result(self.local.get_parcel(&parsed_id, parcel_id).await)?;
+        if let Some(parcel) = possible_entry {
+            let mut state = self.state.lock().unwrap();
+            state.hits += 1;
+            state.touch(&parsed_id);
+            return Ok(parcel);
+        }
+
+        let key = parcel_key(&parsed_id, parcel_id);
+        if self.negative.is_missing(&key) {
+            debug!("Parcel {} is known to be missing on the server", parcel_id);
+            self.state.lock().unwrap().negative_hits += 1;
+            return Err(ProviderError::NotFound);
+        }
+        info!(
+            "Cache miss for parcel {}, attempting to fetch from server",
+            parcel_id
+        );
+        self.state.lock().unwrap().misses += 1;
+        let size = Arc::new(AtomicU64::new(0));
+        let counter = size.clone();
+        let stream = self
+            .negative
+            .check(
+                key,
+                self.remote.get_parcel(parsed_id.clone(), parcel_id).await,
+            )?
+            // Same as in the dumb cache, errors are mapped to io errors and back to storage errors
+            .map(move |res| match res {
+                Ok(bytes) => {
+                    counter.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                    Ok(bytes)
+                }
+                Err(e) => Err(std::io::Error::other(e.to_string())),
+            });
+        // Attempt to insert the parcel into the store, if it fails, warn the user and return the
+        // parcel anyway. Either way, we need to refetch the stream, since it has been read after we
+        // try to insert
+        match self
+            .local
+            .create_parcel(&parsed_id, parcel_id, stream)
+            .await
+        {
+            Ok(_) => {
+                {
+                    let mut state = self.state.lock().unwrap();
+                    state
+                        .parcels
+                        .insert(parcel_id.to_owned(), size.load(Ordering::Relaxed));
+                    state.touch(&parsed_id);
+                }
+                self.evict(&parsed_id).await;
+                self.local.get_parcel(parsed_id, parcel_id).await
+            }
+            Err(e) => {
+                warn!("Fetched parcel from server, but encountered error when trying to save to local store: {:?}", e);
+                self.remote.get_parcel(parsed_id, parcel_id).await
+            }
+        }
+    }
+
+    // In a cache implementation, this just checks for if the local provider has it
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.local.parcel_exists(bindle_id, parcel_id).await
+    }
+
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        self.local.missing_shas(shas).await
+    }
+
+    /// Collects garbage in the local storage. Parcels are cached separately from their invoices,
+    /// so this also evicts cached parcels whose invoice isn't cached
+    async fn collect_garbage(&self, dry_run: bool) -> Result<crate::provider::gc::GcReport> {
+        let report = self.local.collect_garbage(dry_run).await?;
+        if !dry_run {
+            let mut state = self.state.lock().unwrap();
+            for sha in &report.removed {
+                state.parcels.remove(sha);
+            }
+        }
+        Ok(report)
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.local.flush().await
+    }
+}
+
+#[cfg(all(test, feature = "provider-file"))]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::search::NoopEngine;
+
+    use sha2::{Digest, Sha256};
+    use tempfile::{tempdir, TempDir};
+
+    type TestCache = LruCache<FileProvider<NoopEngine>, FileProvider<NoopEngine>>;
+
+    struct Fixture {
+        remote: FileProvider<NoopEngine>,
+        local: FileProvider<NoopEngine>,
+        // Keeps the directories around until the end of the test
+        _dirs: (TempDir, TempDir),
+    }
+
+    fn sha(content: &str) -> String {
+        format!("{:x}", Sha256::digest(content.as_bytes()))
+    }
+
+    /// Creates a remote provider with a bindle for each of the given names, all at version 1.0.0
+    /// and containing a single parcel that is 10 bytes long
+    async fn setup(names: &[&str]) -> Fixture {
+        let (remote_dir, local_dir) = (tempdir().unwrap(), tempdir().unwrap());
+        let remote = FileProvider::new(remote_dir.path(), NoopEngine::default()).await;
+        let local = FileProvider::new(local_dir.path(), NoopEngine::default()).await;
+        for name in names {
+            let content = format!("{:<10}", name);
+            let mut inv: crate::Invoice = toml::from_str(&format!(
+                "bindleVersion = \"1.0.0\"\n[bindle]\nname = \"{}\"\nversion = \"1.0.0\"",
+                name
+            ))
+            .unwrap();
+            inv.parcel = Some(vec![crate::Parcel {
+                label: crate::Label {
+                    size: content.len() as u64,
+                    ..crate::Label::new(format!("{}.txt", name), sha(&content))
+                },
+                conditions: None,
+            }]);
+            remote
+                .create_invoice(&inv)
+                .await
+                .expect("unable to create invoice");
+            let data =
+                tokio::stream::iter(vec![Ok::<_, std::io::Error>(bytes::Bytes::from(content))]);
+            remote
+                .create_parcel(&inv.bindle.id, &inv.parcel.unwrap()[0].label.sha256, data)
+                .await
+                .expect("unable to create parcel");
+        }
+        Fixture {
+            remote,
+            local,
+            _dirs: (remote_dir, local_dir),
+        }
+    }
+
+    /// Fetches the bindle and its parcel through the cache
+    async fn fetch(cache: &TestCache, name: &str) {
+        let id = format!("{}/1.0.0", name);
+        cache
+            .get_invoice(id.as_str())
+            .await
+            .expect("unable to get invoice");
+        let mut stream = cache
+            .get_parcel(id.as_str(), &sha(&format!("{:<10}", name)))
+            .await
+            .expect("unable to get parcel");
+        while let Some(chunk) = stream.next().await {
+            chunk.expect("unable to read parcel");
+        }
+    }
+
+    async fn is_cached(local: &FileProvider<NoopEngine>, name: &str) -> bool {
+        let id = format!("{}/1.0.0", name);
+        let invoice = local.get_yanked_invoice(id.as_str()).await.is_ok();
+        let parcel = local
+            .parcel_exists(id.as_str(), &sha(&format!("{:<10}", name)))
+            .await
+            .expect("unable to check parcel");
+        assert_eq!(
+            invoice, parcel,
+            "The parcel should be cached with its invoice"
+        );
+        invoice
+    }
+
+    #[tokio::test]
+    async fn test_invoice_budget() {
+        let fixture = setup(&["a", "b", "c"]).await;
+        let cache =
+            LruCache::new(fixture.remote.clone(), fixture.local.clone()).with_max_invoices(2);
+
+        fetch(&cache, "a").await;
+        fetch(&cache, "b").await;
+        // Using a makes b the least recently used bindle
+        fetch(&cache, "a").await;
+        fetch(&cache, "c").await;
+
+        assert!(is_cached(&fixture.local, "a").await);
+        assert!(!is_cached(&fixture.local, "b").await);
+        assert!(is_cached(&fixture.local, "c").await);
+        assert_eq!(
+            CacheMetrics {
+                hits: 2,
+                misses: 6,
+                negative_hits: 0,
+                evictions: 1,
+                invoices: 2,
+                parcel_bytes: 20,
+            },
+            cache.metrics()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parcel_budget() {
+        let fixture = setup(&["a", "b"]).await;
+        let cache =
+            LruCache::new(fixture.remote.clone(), fixture.local.clone()).with_max_parcel_bytes(15);
+
+        fetch(&cache, "a").await;
+        fetch(&cache, "b").await;
+
+        assert!(!is_cached(&fixture.local, "a").await);
+        assert!(is_cached(&fixture.local, "b").await);
+        let metrics = cache.metrics();
+        assert_eq!(1, metrics.evictions);
+        assert_eq!(10, metrics.parcel_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let fixture = setup(&["a", "b"]).await;
+        let cache = LruCache::new(fixture.remote.clone(), fixture.local.clone());
+        let expiring = cache.clone().with_ttl(Duration::from_secs(0));
+        fetch(&cache, "a").await;
+        fetch(&cache, "b").await;
+
+        fixture
+            .remote
+            .yank_invoice("a/1.0.0")
+            .await
+            .expect("unable to yank invoice");
+        fixture
+            .remote
+            .delete_invoice("b/1.0.0")
+            .await
+            .expect("unable to delete invoice");
+
+        // Without a time to live, the cached copies are used as they are
+        cache
+            .get_invoice("a/1.0.0")
+            .await
+            .expect("cached invoice should not be yanked");
+        cache
+            .get_invoice("b/1.0.0")
+            .await
+            .expect("cached invoice should still exist");
+
+        // Expired invoices are revalidated
+        assert!(matches!(
+            expiring.get_invoice("a/1.0.0").await,
+            Err(ProviderError::Yanked)
+        ));
+        assert!(matches!(
+            cache.get_invoice("a/1.0.0").await,
+            Err(ProviderError::Yanked)
+        ));
+        assert!(matches!(
+            expiring.get_invoice("b/1.0.0").await,
+            Err(ProviderError::NotFound)
+        ));
+        assert!(!is_cached(&fixture.local, "b").await);
+    }
+
+    #[tokio::test]
+    async fn test_negative_ttl() {
+        let fixture = setup(&["a"]).await;
+        let cache = LruCache::new(fixture.remote.clone(), fixture.local.clone())
+            .with_negative_ttl(Duration::from_secs(60));
+        let expiring = LruCache::new(fixture.remote.clone(), fixture.local.clone())
+            .with_negative_ttl(Duration::from_secs(0));
+        let missing_parcel = sha("missing");
+
+        for _ in 0..3 {
+            assert!(matches!(
+                cache.get_invoice("b/1.0.0").await,
+                Err(ProviderError::NotFound)
+            ));
+            assert!(matches!(
+                cache.get_parcel("a/1.0.0", &missing_parcel).await,
+                Err(ProviderError::NotFound)
+            ));
+        }
+        let metrics = cache.metrics();
+        assert_eq!(2, metrics.misses);
+        assert_eq!(4, metrics.negative_hits);
+
+        // Once the invoice exists, it is still reported missing until the time to live passed
+        let inv: crate::Invoice = toml::from_str(
+            "bindleVersion = \"1.0.0\"\n[bindle]\nname = \"b\"\nversion = \"1.0.0\"",
+        )
+        .unwrap();
+        fixture
+            .remote
+            .create_invoice(&inv)
+            .await
+            .expect("unable to create invoice");
+        assert!(matches!(
+            cache.get_invoice("b/1.0.0").await,
+            Err(ProviderError::NotFound)
+        ));
+        for _ in 0..2 {
+            assert!(matches!(
+                expiring.get_invoice("c/1.0.0").await,
+                Err(ProviderError::NotFound)
+            ));
+        }
+        assert_eq!(0, expiring.metrics().negative_hits);
+        expiring
+            .get_invoice("b/1.0.0")
+            .await
+            .expect("invoice should be fetched once it exists");
+    }
+}
@@ -1,14 +1,16 @@
 //! Caching implementations for client and server-side usage. This module is under heavy development
 //! and iteration
 
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use crate::provider::{Provider, ProviderError};
 
 pub mod dumb;
+pub mod lru;
 pub use dumb::DumbCache;
-
-// Once implemented, we can export this
-mod lru;
-// pub use lru::LruCache;
+pub use lru::LruCache;
 
 /// A marker trait that indicates this is a caching implementation (as opposed to just a provider)
 pub trait Cache: Provider {}
@@ -27,3 +29,66 @@ pub(crate) fn into_cache_result<T>(res: crate::provider::Result<T>) -> CacheResu
         Err(e) => Err(e),
     }
 }
+
+/// Remembers invoices and parcels the remote provider didn't have for a while, so repeated lookups
+/// of something that doesn't exist (yet), such as a parcel that was declared but not uploaded, are
+/// answered without asking the remote provider again. Nothing is remembered unless a time to live
+/// is set
+#[derive(Clone, Default)]
+pub(crate) struct NegativeCache {
+    ttl: Option<Duration>,
+    /// When each missing key was last found to be missing
+    missing: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl NegativeCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        NegativeCache {
+            ttl: Some(ttl),
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether the key was found to be missing within the time to live
+    pub(crate) fn is_missing(&self, key: &str) -> bool {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return false,
+        };
+        let mut missing = self.missing.lock().unwrap();
+        match missing.get(key) {
+            Some(at) if at.elapsed() < ttl => true,
+            Some(_) => {
+                missing.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers the key as missing if the result of fetching it from the remote provider is a
+    /// `NotFound` error, and passes the result on
+    pub(crate) fn check<T>(
+        &self,
+        key: String,
+        res: crate::provider::Result<T>,
+    ) -> crate::provider::Result<T> {
+        if let (Some(ttl), Err(ProviderError::NotFound)) = (self.ttl, &res) {
+            let mut missing = self.missing.lock().unwrap();
+            // Expired entries are only cleaned up here, which keeps the map from growing forever
+            missing.retain(|_, at| at.elapsed() < ttl);
+            missing.insert(key, Instant::now());
+        }
+        res
+    }
+}
+
+/// The key of an invoice in the [`NegativeCache`](NegativeCache)
+pub(crate) fn invoice_key(id: &crate::Id) -> String {
+    format!("invoice {}", id)
+}
+
+/// The key of a parcel in the [`NegativeCache`](NegativeCache)
+pub(crate) fn parcel_key(id: &crate::Id, parcel_id: &str) -> String {
+    format!("parcel {}@{}", id, parcel_id)
+}
@@ -0,0 +1,364 @@
+//! Tools for splitting very large artifacts into chunks that are stored as individual parcels.
+//!
+//! A chunked artifact is made up of a set of chunk parcels plus a manifest parcel (with a media
+//! type of [`MANIFEST_MEDIA_TYPE`](MANIFEST_MEDIA_TYPE)) that lists the chunks needed to reassemble
+//! it, in order. Because parcels are content addressed, chunks that are shared with other bindles
+//! (such as a previous version of the same artifact) only have to be stored and uploaded once. With
+//! [content defined chunking](Chunker::ContentDefined), the chunk boundaries move along with the
+//! data, so changing part of a large artifact only changes the chunks around the modification.
+//!
+//! The chunk parcels are members of a group that the manifest requires, so they are selected
+//! along with the manifest by the usual [filters](crate::filters). See the
+//! [`Client`](crate::client::Client) for uploading and reassembling chunked parcels.
+//!
+//! ```
+//! use bindle::chunking::{ChunkedParcel, Chunker};
+//!
+//! let data = vec![7u8; 100];
+//! let chunked = ChunkedParcel::new("data.bin", "application/octet-stream", &data, &Chunker::Fixed(40));
+//! assert_eq!(3, chunked.manifest.chunk.len());
+//! // Identical chunks are only stored once
+//! assert_eq!(2, chunked.chunks().count());
+//! ```
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Condition, Group, Invoice, Label, Parcel};
+
+/// The media type of a chunk manifest parcel
+pub const MANIFEST_MEDIA_TYPE: &str = "application/vnd.bindle.chunks+toml";
+/// The label annotation on a manifest parcel containing the media type of the reassembled data
+pub const MEDIA_TYPE_ANNOTATION: &str = "bindle.chunked.mediaType";
+
+const DEFAULT_AVG_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The strategy used to split data into chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunker {
+    /// Splits data into chunks of the given size (the last chunk may be smaller). This is the
+    /// cheapest strategy, but inserting or removing data changes all of the following chunks
+    Fixed(usize),
+    /// Splits data where the rolling hash of the content matches a pattern, so chunk boundaries
+    /// survive insertions and removals elsewhere in the data. Chunks are between `min` and `max`
+    /// bytes, and about `avg` bytes on average
+    ContentDefined { min: usize, avg: usize, max: usize },
+}
+
+impl Chunker {
+    /// Returns a content defined chunker with the given average chunk size, allowing chunks to be
+    /// between a quarter and four times that size
+    pub fn content_defined(avg: usize) -> Self {
+        let avg = avg.max(4);
+        Chunker::ContentDefined {
+            min: avg / 4,
+            avg,
+            max: avg * 4,
+        }
+    }
+
+    /// Splits the given data into chunks. Empty data produces no chunks
+    pub fn split<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        match *self {
+            Chunker::Fixed(size) => data.chunks(size.max(1)).collect(),
+            Chunker::ContentDefined { min, avg, max } => split_content_defined(data, min, avg, max),
+        }
+    }
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Chunker::content_defined(DEFAULT_AVG_CHUNK_SIZE)
+    }
+}
+
+/// The contents of a manifest parcel, describing how to reassemble a chunked artifact
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ChunkManifest {
+    /// The SHA-256 sum of the reassembled data
+    pub sha256: String,
+    /// The size of the reassembled data in bytes
+    pub size: u64,
+    /// The chunks making up the data, in order. A chunk can appear more than once
+    #[serde(default)]
+    pub chunk: Vec<ChunkRef>,
+}
+
+/// A reference to a single chunk parcel in a [`ChunkManifest`](ChunkManifest)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ChunkRef {
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// An artifact that has been split into chunks, ready to be added to an invoice and uploaded
+#[derive(Debug, Clone)]
+pub struct ChunkedParcel<'a> {
+    /// The name of the artifact, used for the label of the manifest parcel
+    pub name: String,
+    /// The media type of the reassembled data
+    pub media_type: String,
+    /// The manifest describing the chunks
+    pub manifest: ChunkManifest,
+    chunks: Vec<&'a [u8]>,
+}
+
+impl<'a> ChunkedParcel<'a> {
+    /// Splits the given data into chunks using the given chunker
+    pub fn new(
+        name: impl Into<String>,
+        media_type: impl Into<String>,
+        data: &'a [u8],
+        chunker: &Chunker,
+    ) -> Self {
+        let chunks = chunker.split(data);
+        let manifest = ChunkManifest {
+            sha256: sha256(data),
+            size: data.len() as u64,
+            chunk: chunks
+                .iter()
+                .map(|c| ChunkRef {
+                    sha256: sha256(c),
+                    size: c.len() as u64,
+                })
+                .collect(),
+        };
+        ChunkedParcel {
+            name: name.into(),
+            media_type: media_type.into(),
+            manifest,
+            chunks,
+        }
+    }
+
+    /// Returns the serialized manifest parcel data
+    pub fn manifest_data(&self) -> Vec<u8> {
+        // A manifest only contains strings and integers, so this can't fail
+        toml::to_vec(&self.manifest).expect("chunk manifest should serialize")
+    }
+
+    /// Returns the label of the manifest parcel
+    pub fn manifest_label(&self) -> Label {
+        let data = self.manifest_data();
+        let mut annotations = crate::AnnotationMap::new();
+        annotations.insert(MEDIA_TYPE_ANNOTATION.to_owned(), self.media_type.clone());
+        Label {
+            sha256: sha256(&data),
+            media_type: MANIFEST_MEDIA_TYPE.to_owned(),
+            name: self.name.clone(),
+            size: data.len() as u64,
+            annotations: Some(annotations),
+            feature: None,
+        }
+    }
+
+    /// Returns the name of the group containing the chunk parcels
+    pub fn group_name(&self) -> String {
+        format!("{}.chunks", self.name)
+    }
+
+    /// Returns the label and data of each unique chunk, in the order they first appear
+    pub fn chunks(&self) -> impl Iterator<Item = (Label, &'a [u8])> + '_ {
+        let mut seen = HashSet::new();
+        self.manifest
+            .chunk
+            .iter()
+            .zip(self.chunks.iter())
+            .filter(move |(c, _)| seen.insert(c.sha256.as_str()))
+            .enumerate()
+            .map(move |(i, (c, data))| {
+                let label = Label {
+                    size: c.size,
+                    ..Label::new(format!("{}.chunk{}", self.name, i), c.sha256.clone())
+                };
+                (label, *data)
+            })
+    }
+
+    /// Adds the manifest and chunk parcels, along with the group of chunks, to the given invoice.
+    /// Chunks that the invoice already contains a parcel for are not added again
+    pub fn add_to(&self, invoice: &mut Invoice) {
+        let group = self.group_name();
+        let parcels = invoice.parcel.get_or_insert_with(Vec::new);
+        let mut existing: HashSet<String> =
+            parcels.iter().map(|p| p.label.sha256.clone()).collect();
+        for (label, _) in self.chunks() {
+            if existing.insert(label.sha256.clone()) {
+                parcels.push(Parcel {
+                    label,
+                    conditions: Some(Condition {
+                        member_of: Some(vec![group.clone()]),
+                        requires: None,
+                    }),
+                });
+            }
+        }
+        parcels.push(Parcel {
+            label: self.manifest_label(),
+            conditions: Some(Condition {
+                member_of: None,
+                requires: Some(vec![group.clone()]),
+            }),
+        });
+        invoice.group.get_or_insert_with(Vec::new).push(Group {
+            name: group,
+            required: Some(false),
+            satisfied_by: None,
+        });
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns a table of pseudorandom values for the gear hash. It is generated with splitmix64 using
+/// a fixed seed, so it is the same everywhere and chunk boundaries are stable
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    for entry in table.iter_mut() {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        *entry = z ^ (z >> 31);
+    }
+    table
+}
+
+fn split_content_defined(data: &[u8], min: usize, avg: usize, max: usize) -> Vec<&[u8]> {
+    let min = min.max(1);
+    let max = max.max(min);
+    let gear = gear_table();
+    // Use the top bits of the hash for the boundary check, as the gear hash mixes in new bytes
+    // from the bottom
+    let bits = (avg.max(2) as f64).log2().round() as u32;
+    let mask = !0u64 << (64 - bits);
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let remaining = data.len() - start;
+        if remaining <= min {
+            chunks.push(&data[start..]);
+            break;
+        }
+        let end = std::cmp::min(remaining, max);
+        let mut hash = 0u64;
+        let mut cut = end;
+        // Bytes before the minimum size still go into the hash, but can't end a chunk
+        for (i, b) in data[start..start + end].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(gear[*b as usize]);
+            if i + 1 >= min && hash & mask == 0 {
+                cut = i + 1;
+                break;
+            }
+        }
+        chunks.push(&data[start..start + cut]);
+        start += cut;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::filters::BindleFilter;
+
+    fn random_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let data = random_data(512 * 1024, 42);
+        for chunker in &[Chunker::Fixed(10_000), Chunker::content_defined(16 * 1024)] {
+            let chunks = chunker.split(&data);
+            assert_eq!(data, chunks.concat(), "Chunks should reassemble the data");
+            if let Chunker::ContentDefined { min, max, .. } = chunker {
+                let (last, rest) = chunks.split_last().unwrap();
+                assert!(rest.iter().all(|c| c.len() >= *min && c.len() <= *max));
+                assert!(last.len() <= *max);
+            }
+        }
+        assert!(Chunker::default().split(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_content_defined_dedup() {
+        let chunker = Chunker::content_defined(8 * 1024);
+        let original = random_data(512 * 1024, 7);
+        // Insert some data near the start, which would shift every fixed size chunk
+        let mut modified = original[..1000].to_vec();
+        modified.extend_from_slice(b"some new data in the middle of it all");
+        modified.extend_from_slice(&original[1000..]);
+
+        let before: HashSet<Vec<u8>> = chunker
+            .split(&original)
+            .into_iter()
+            .map(|c| c.to_vec())
+            .collect();
+        let after = chunker.split(&modified);
+        let shared = after.iter().filter(|c| before.contains(**c)).count();
+        assert!(
+            shared + 3 >= after.len(),
+            "Only the chunks around the change should differ, but {} of {} did",
+            after.len() - shared,
+            after.len()
+        );
+    }
+
+    #[test]
+    fn test_add_to_invoice() {
+        let mut data = random_data(1000, 3);
+        // Repeat the first chunk so there is a duplicate
+        let head = data[..100].to_vec();
+        data.extend(head);
+        let chunked =
+            ChunkedParcel::new("big.bin", "application/wasm", &data, &Chunker::Fixed(100));
+        assert_eq!(11, chunked.manifest.chunk.len());
+        assert_eq!(10, chunked.chunks().count());
+        assert_eq!(data.len() as u64, chunked.manifest.size);
+
+        let manifest: ChunkManifest =
+            toml::from_slice(&chunked.manifest_data()).expect("manifest should round trip");
+        assert_eq!(chunked.manifest, manifest);
+
+        let mut invoice: Invoice = toml::from_str(
+            r#"
+            bindleVersion = "1.0.0"
+            [bindle]
+            name = "chunky"
+            version = "1.0.0"
+            "#,
+        )
+        .unwrap();
+        chunked.add_to(&mut invoice);
+        assert_eq!(11, invoice.parcel.as_ref().unwrap().len());
+        assert_eq!("big.bin.chunks", invoice.group.as_ref().unwrap()[0].name);
+
+        // The manifest is global and pulls in all of its chunks
+        let selected = BindleFilter::new(invoice).filter();
+        assert_eq!(11, selected.len());
+        let manifest_label = selected
+            .iter()
+            .find(|p| p.label.media_type == MANIFEST_MEDIA_TYPE)
+            .expect("manifest should be selected");
+        assert_eq!(chunked.manifest_label(), manifest_label.label);
+    }
+}
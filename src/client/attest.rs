@@ -0,0 +1,195 @@
+//! Verified downloads of a bindle that leave behind a machine-readable record of what was verified
+
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::SystemTime;
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::update::{is_valid, parcel_path};
+use super::{Client, ClientError, Result};
+use crate::signature::{KeyRing, SignatureRole, VerificationStrategy};
+use crate::standalone::{INVOICE_FILE, PARCEL_DIR};
+use crate::Id;
+
+/// The name of the file that [`Client::fetch_and_attest`](Client::fetch_and_attest) writes the
+/// attestation to, next to the invoice
+pub const ATTESTATION_FILE: &str = "attestation.json";
+/// The current version of the attestation format
+pub const ATTESTATION_VERSION: &str = "1.0";
+
+/// A record of the verification of a downloaded bindle, as written by
+/// [`Client::fetch_and_attest`](Client::fetch_and_attest)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Attestation {
+    /// The version of the attestation format
+    pub version: String,
+    /// The version of bindle that verified the bindle
+    pub bindle_version: String,
+    /// The UNIX timestamp (in seconds) at which the bindle was verified
+    pub verified_at: u64,
+    /// The URL of the server the bindle was fetched from
+    pub server: String,
+    /// The ID of the bindle, such as `example.com/foo/1.0.0`
+    pub bindle_id: String,
+    /// The SHA-256 of the invoice file written next to the attestation
+    pub invoice_sha256: String,
+    /// The strategy the signatures were verified with
+    pub verification_strategy: VerificationStrategy,
+    /// Whether the signatures were also checked against a transparency log
+    pub transparency_log_verified: bool,
+    /// Every signature of the invoice
+    pub signatures: Vec<SignatureAttestation>,
+    /// Every parcel of the bindle, all of which were verified
+    pub parcels: Vec<ParcelAttestation>,
+}
+
+/// The verification result of a single signature of an attested invoice
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SignatureAttestation {
+    /// The name of the signer
+    pub by: String,
+    /// The base64 encoded public key of the signer
+    pub key: String,
+    /// The role the signature was made in
+    pub role: SignatureRole,
+    /// The UNIX timestamp (in seconds) at which the signature was made
+    pub at: u64,
+    /// The label of the key in the keyring, if the keyring has it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_label: Option<String>,
+    /// Whether the keyring trusts the key for the role of the signature
+    pub trusted: bool,
+}
+
+/// A parcel of an attested bindle, whose size and SHA-256 were checked against its label
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ParcelAttestation {
+    pub sha256: String,
+    pub name: String,
+    pub size: u64,
+    /// The path of the parcel, relative to the directory of the attestation
+    pub path: String,
+}
+
+impl Client {
+    /// Downloads a bindle to the `dest` directory and writes an [`Attestation`](Attestation) of
+    /// what was verified to `attestation.json` in it, for pipelines that need to prove which
+    /// bindles they consumed. The directory uses the same layout as a
+    /// [standalone bindle](crate::standalone), like [`update`](Client::update).
+    ///
+    /// The signatures of the invoice are verified against the given keyring with the strategy the
+    /// client was configured with, or with
+    /// [`GreedyVerification`](VerificationStrategy::GreedyVerification) if it has none, as an
+    /// attestation that nothing was checked would be worthless. Every parcel is checked against the
+    /// SHA-256 and size in its label; parcels that are already in the directory are only downloaded
+    /// again if they don't match. Nothing is attested unless the
+    /// whole bindle is verified: the attestation is written last, and any stale attestation is
+    /// removed before starting
+    pub async fn fetch_and_attest<I, P>(
+        &self,
+        id: I,
+        keyring: &KeyRing,
+        dest: P,
+    ) -> Result<Attestation>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+        P: AsRef<Path>,
+    {
+        let id: Id = id.try_into().map_err(|e| e.into())?;
+        let dest = dest.as_ref();
+        info!("Fetching and attesting bindle {} in {}", id, dest.display());
+
+        let parcel_dir = dest.join(PARCEL_DIR);
+        tokio::fs::create_dir_all(&parcel_dir).await?;
+        match tokio::fs::remove_file(dest.join(ATTESTATION_FILE)).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let invoice = self.get_invoice(&id).await?;
+        let strategy = match self.verification_strategy {
+            VerificationStrategy::None => VerificationStrategy::GreedyVerification,
+            strategy => strategy,
+        };
+        strategy
+            .verify(&invoice, keyring, &self.revocations)
+            .map_err(|e| {
+                ClientError::VerificationFailed(crate::VerificationFailure {
+                    strategy,
+                    reason: e.to_string(),
+                })
+            })?;
+        let signatures = invoice
+            .check_signatures(keyring)
+            .into_iter()
+            .map(|report| SignatureAttestation {
+                by: report.signature.by,
+                key: report.signature.key,
+                role: report.signature.role,
+                at: report.signature.at,
+                key_label: report.key_entry.map(|e| e.label),
+                trusted: report.trusted,
+            })
+            .collect();
+
+        let mut parcels = Vec::new();
+        for label in invoice.parcel.iter().flatten().map(|p| &p.label) {
+            let path = parcel_path(&parcel_dir, &label.sha256);
+            if is_valid(&path, &label.sha256).await? {
+                debug!("Parcel {} was already downloaded", label.sha256);
+            } else {
+                self.download_parcel(&id, &label.sha256, &path).await?;
+            }
+            let actual = tokio::fs::metadata(&path).await?.len();
+            if actual != label.size {
+                return Err(ClientError::SizeMismatch {
+                    expected: label.size,
+                    actual,
+                });
+            }
+            parcels.push(ParcelAttestation {
+                sha256: label.sha256.clone(),
+                name: label.name.clone(),
+                size: label.size,
+                path: format!("{}/{}.dat", PARCEL_DIR, label.sha256),
+            });
+        }
+
+        let raw_invoice = toml::to_vec(&invoice)?;
+        tokio::fs::write(dest.join(INVOICE_FILE), &raw_invoice).await?;
+        let attestation = Attestation {
+            version: ATTESTATION_VERSION.to_owned(),
+            bindle_version: env!("CARGO_PKG_VERSION").to_owned(),
+            verified_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            server: self.base_url.to_string(),
+            bindle_id: id.to_string(),
+            invoice_sha256: format!("{:x}", Sha256::digest(&raw_invoice)),
+            verification_strategy: strategy,
+            transparency_log_verified: self.transparency_log.is_some(),
+            signatures,
+            parcels,
+        };
+        tokio::fs::write(
+            dest.join(ATTESTATION_FILE),
+            serde_json::to_vec_pretty(&attestation)?,
+        )
+        .await?;
+        info!(
+            "Attested bindle {} with {} parcels",
+            attestation.bindle_id,
+            attestation.parcels.len()
+        );
+        Ok(attestation)
+    }
+}
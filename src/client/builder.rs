@@ -0,0 +1,282 @@
+//! A builder for configuring a [`Client`](super::Client) beyond the defaults used by
+//! [`Client::new`](super::Client::new)
+
+use std::path::Path;
+use std::sync::Arc;
+
+use ed25519_dalek::PublicKey;
+use log::info;
+use reqwest::header;
+use reqwest::Client as HttpClient;
+use url::Url;
+
+use super::progress::ProgressCallback;
+use super::{Client, ClientError, Result, Timeouts, TokenCache, JSON_MIME_TYPE, TOML_MIME_TYPE};
+use crate::signature::{KeyRing, RevocationList, VerificationStrategy};
+
+/// Configures and builds a [`Client`](super::Client). This is needed for talking to servers that
+/// use a private CA or require client certificates (mutual TLS):
+///
+/// ```no_run
+/// # async fn example() -> bindle::client::Result<()> {
+/// let client = bindle::client::Client::builder()
+///     .ca_certificates_file("/etc/bindle/ca.crt")
+///     .await?
+///     .identity_files("/etc/bindle/client.crt", "/etc/bindle/client.key")
+///     .await?
+///     .build("https://bindle.example.com/v1/")?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// When custom CA certificates or a client identity are configured, the client uses rustls for
+/// TLS. Otherwise it uses the platform's native TLS implementation
+#[derive(Clone, Default)]
+pub struct ClientBuilder {
+    ca_certificates: Vec<Vec<u8>>,
+    identity: Option<Vec<u8>>,
+    tokens: Option<TokenCache>,
+    verification_strategy: VerificationStrategy,
+    keyring: Arc<KeyRing>,
+    revocations: Arc<RevocationList>,
+    transparency_log: Option<PublicKey>,
+    json: bool,
+    timeouts: Timeouts,
+    progress: Option<ProgressCallback>,
+    proxy: Option<String>,
+}
+
+impl ClientBuilder {
+    /// Trusts the given PEM encoded CA certificates (which can be a bundle of multiple
+    /// certificates) in addition to the system's trusted certificates
+    pub fn ca_certificates(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.ca_certificates.push(pem.into());
+        self
+    }
+
+    /// Same as [`ca_certificates`](ClientBuilder::ca_certificates), but loads the certificates
+    /// from the given file
+    pub async fn ca_certificates_file(self, path: impl AsRef<Path>) -> Result<Self> {
+        let pem = tokio::fs::read(path).await?;
+        Ok(self.ca_certificates(pem))
+    }
+
+    /// Presents the given PEM encoded certificate (chain) and private key to servers that require
+    /// client certificates. The key can be in either PKCS8 or RSA format
+    pub fn identity(mut self, cert_pem: &[u8], key_pem: &[u8]) -> Self {
+        let mut identity = Vec::with_capacity(cert_pem.len() + key_pem.len() + 1);
+        identity.extend_from_slice(cert_pem);
+        identity.push(b'\n');
+        identity.extend_from_slice(key_pem);
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Same as [`identity`](ClientBuilder::identity), but loads the certificate and key from the
+    /// given files
+    pub async fn identity_files(
+        self,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let cert = tokio::fs::read(cert_path).await?;
+        let key = tokio::fs::read(key_path).await?;
+        Ok(self.identity(&cert, &key))
+    }
+
+    /// Authenticates all requests using tokens from the given cache. See
+    /// [`Client::with_token_cache`](super::Client::with_token_cache) for more details
+    pub fn token_cache(mut self, tokens: TokenCache) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Verifies every invoice fetched from the server against the keyring using the given
+    /// strategy. See [`Client::with_verification`](super::Client::with_verification) for more
+    /// details
+    pub fn verification(
+        mut self,
+        strategy: VerificationStrategy,
+        keyring: impl Into<Arc<KeyRing>>,
+    ) -> Self {
+        self.verification_strategy = strategy;
+        self.keyring = keyring.into();
+        self
+    }
+
+    /// Rejects invoices with signatures made by keys from the given revocation list when verifying
+    /// them. See [`Client::with_revocations`](super::Client::with_revocations) for more details
+    pub fn revocations(mut self, revocations: impl Into<Arc<RevocationList>>) -> Self {
+        self.revocations = revocations.into();
+        self
+    }
+
+    /// Checks that every invoice fetched from the server was recorded in the transparency log with
+    /// the given public key. See
+    /// [`Client::with_transparency_log`](super::Client::with_transparency_log) for more details
+    pub fn transparency_log(mut self, log_key: PublicKey) -> Self {
+        self.transparency_log = Some(log_key);
+        self
+    }
+
+    /// Asks the server to reply with JSON instead of TOML and sends invoices as JSON. Servers that
+    /// don't support JSON keep replying with TOML, which the client still understands
+    pub fn json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    /// Sets the timeouts for the different kinds of requests. See [`Timeouts`](super::Timeouts) for
+    /// more details
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Reports the bytes of every parcel the client uploads or downloads to the given reporter. See
+    /// [`Client::with_progress_reporter`](super::Client::with_progress_reporter) for more details
+    pub fn progress_reporter(mut self, reporter: ProgressCallback) -> Self {
+        self.progress = Some(reporter);
+        self
+    }
+
+    /// Sends all requests through the HTTP(S) proxy at the given URL. The URL is checked when the
+    /// client is built
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Builds a client for the given base URL. This URL should be the FQDN plus any namespacing
+    /// (like `v1`). Will return an error if the URL or any of the TLS configuration is invalid
+    pub fn build(self, base_url: &str) -> Result<Client> {
+        // Note that the trailing slash is important, otherwise the URL parser will treat is as a
+        // "file" component of the URL. So we need to check that it is added before parsing
+        let mut base = base_url.to_owned();
+        if !base.ends_with('/') {
+            info!("Provided base URL missing trailing slash, adding...");
+            base.push('/');
+        }
+        let base_parsed = Url::parse(&base)?;
+        let mut headers = header::HeaderMap::new();
+        let accept = if self.json {
+            format!("{}, {};q=0.9", JSON_MIME_TYPE, TOML_MIME_TYPE)
+        } else {
+            TOML_MIME_TYPE.to_owned()
+        };
+        headers.insert(header::ACCEPT, accept.parse().unwrap());
+        let mut builder = HttpClient::builder()
+            .http2_prior_knowledge()
+            .default_headers(headers);
+
+        // Client certificates from PEM files are only supported by rustls, so switch over
+        // whenever TLS is customized to keep the behavior consistent
+        if !self.ca_certificates.is_empty() || self.identity.is_some() {
+            builder = builder.use_rustls_tls();
+        }
+        for pem in self.ca_certificates {
+            let cert = reqwest::Certificate::from_pem(&pem).map_err(|e| {
+                ClientError::InvalidConfig(format!("Invalid CA certificate: {}", e))
+            })?;
+            builder = builder.add_root_certificate(cert);
+        }
+        if let Some(pem) = self.identity {
+            let identity = reqwest::Identity::from_pem(&pem).map_err(|e| {
+                ClientError::InvalidConfig(format!("Invalid client certificate or key: {}", e))
+            })?;
+            builder = builder.identity(identity);
+        }
+        if let Some(url) = self.proxy {
+            let proxy = reqwest::Proxy::all(&url).map_err(|e| {
+                ClientError::InvalidConfig(format!("Invalid proxy URL {}: {}", url, e))
+            })?;
+            builder = builder.proxy(proxy);
+        }
+
+        let client = builder
+            .build()
+            .map_err(|e| ClientError::Other(e.to_string()))?;
+        Ok(Client {
+            client,
+            base_url: base_parsed,
+            tokens: self.tokens,
+            verification_strategy: self.verification_strategy,
+            keyring: self.keyring,
+            revocations: self.revocations,
+            transparency_log: self.transparency_log,
+            json: self.json,
+            timeouts: self.timeouts,
+            progress: self.progress,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::server::{start_in_process, InProcessOptions, TlsConfig};
+    use crate::testing;
+
+    fn tls_file(name: &str) -> PathBuf {
+        let root = std::env::var("CARGO_MANIFEST_DIR").expect("Unable to get project directory");
+        PathBuf::from(root).join("test/data/tls").join(name)
+    }
+
+    #[tokio::test]
+    async fn test_mtls() {
+        let (store, index) = testing::setup().await;
+        let handle = start_in_process(
+            store,
+            index,
+            InProcessOptions {
+                tls: Some(TlsConfig {
+                    cert_path: tls_file("server.crt"),
+                    key_path: tls_file("server.key"),
+                    client_ca_path: Some(tls_file("ca.crt")),
+                }),
+                ..Default::default()
+            },
+        )
+        .expect("server should start");
+        // The test certificate is only valid for localhost
+        let url = format!("https://localhost:{}/v1/", handle.addr().port());
+
+        let client = Client::builder()
+            .ca_certificates_file(tls_file("ca.crt"))
+            .await
+            .unwrap()
+            .identity_files(tls_file("client.crt"), tls_file("client.key"))
+            .await
+            .unwrap()
+            .build(&url)
+            .expect("client should build");
+        client
+            .query_invoices(Default::default())
+            .await
+            .expect("request with a client certificate should succeed");
+
+        let client = Client::builder()
+            .ca_certificates_file(tls_file("ca.crt"))
+            .await
+            .unwrap()
+            .build(&url)
+            .expect("client should build");
+        assert!(
+            client.query_invoices(Default::default()).await.is_err(),
+            "request without a client certificate should fail"
+        );
+
+        handle.shutdown().await;
+    }
+
+    #[test]
+    fn test_invalid_identity() {
+        let res = Client::builder()
+            .identity(b"not a cert", b"not a key")
+            .build("https://localhost/v1/");
+        assert!(matches!(res, Err(ClientError::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,192 @@
+//! Configuration files with named profiles for clients.
+//!
+//! Instead of passing the server URL, token and keyring to every command, they can be stored as
+//! named profiles in `$HOME/.bindle/config.toml`:
+//!
+//! ```toml
+//! defaultProfile = "staging"
+//!
+//! [profile.staging]
+//! serverUrl = "https://bindle.staging.example.com/v1/"
+//! tokenFile = "/home/me/.bindle/staging-token.toml"
+//!
+//! [profile.production]
+//! serverUrl = "https://bindle.example.com/v1/"
+//! token = "a-static-bearer-token"
+//! keyring = "/etc/bindle/keyring.toml"
+//! proxy = "http://proxy.example.com:3128"
+//! ```
+//!
+//! The `bindle` CLI picks a profile with `--profile` (or `BINDLE_PROFILE`), falling back to the
+//! `defaultProfile` and then to a profile named `default`. Command line flags and their environment
+//! variables take precedence over the profile. Other tools embedding the client can resolve
+//! profiles the same way with [`ClientConfig::profile`](ClientConfig::profile) and
+//! [`Profile::apply`](Profile::apply)
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::tokens::Token;
+use super::{ClientBuilder, ClientError, Result, TokenCache};
+
+/// The name of the profile used when none is given and the file doesn't set a default
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// The contents of a client configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ClientConfig {
+    /// The profile used when none is chosen explicitly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    /// The profiles, by name
+    #[serde(rename = "profile")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// The settings for talking to one server. Every field is optional
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Profile {
+    /// The base URL of the server, including any namespacing (like `v1`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_url: Option<String>,
+    /// A static bearer token to authenticate with. Takes precedence over `token_file`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// The file where access tokens obtained by logging in are cached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+    /// The keyring of trusted public keys used to verify invoices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyring: Option<PathBuf>,
+    /// The URL of an HTTP(S) proxy that all requests are sent through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+}
+
+impl ClientConfig {
+    /// Returns the default location of the configuration file, `$HOME/.bindle/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| home.join(".bindle/config.toml"))
+    }
+
+    /// Loads the configuration file at the given path. If the file does not exist, an empty
+    /// configuration is returned
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(raw) => super::error::from_toml_slice(&raw),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ClientConfig::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the profile with the given name. If no name is given, the default profile is used
+    /// if it exists, otherwise an empty profile is returned. An error is returned if a profile was
+    /// asked for by name (either here or as the default profile) and doesn't exist
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        let name = match name.or(self.default_profile.as_deref()) {
+            Some(name) => name,
+            None => {
+                return Ok(self
+                    .profiles
+                    .get(DEFAULT_PROFILE)
+                    .cloned()
+                    .unwrap_or_default())
+            }
+        };
+        self.profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ClientError::InvalidConfig(format!("Profile {} does not exist", name)))
+    }
+}
+
+impl Profile {
+    /// Returns the token cache for the profile's static token or token file, if it has either
+    pub async fn token_cache(&self) -> Result<Option<TokenCache>> {
+        match (&self.token, &self.token_file) {
+            (Some(token), _) => Ok(Some(TokenCache::in_memory(Token::from_static(
+                token.as_str(),
+            )))),
+            (None, Some(path)) => TokenCache::load(path).await.map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Configures the given builder with the profile's token and proxy. The server URL and keyring
+    /// are left to the caller, which usually combines them with its own flags
+    pub async fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder> {
+        if let Some(tokens) = self.token_cache().await? {
+            builder = builder.token_cache(tokens);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.as_str());
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_profiles() {
+        let dir = tempfile::tempdir().expect("unable to create tempdir");
+        let path = dir.path().join("config.toml");
+
+        // A missing file is the same as an empty one
+        let config = ClientConfig::load(&path).await.expect("should load");
+        assert_eq!(Profile::default(), config.profile(None).unwrap());
+        assert!(config.profile(Some("staging")).is_err());
+
+        tokio::fs::write(
+            &path,
+            r#"
+defaultProfile = "staging"
+
+[profile.staging]
+serverUrl = "https://staging.example.com/v1/"
+token = "abc"
+
+[profile.production]
+serverUrl = "https://example.com/v1/"
+keyring = "/etc/bindle/keyring.toml"
+proxy = "http://proxy.example.com:3128"
+"#,
+        )
+        .await
+        .expect("unable to write config");
+        let config = ClientConfig::load(&path).await.expect("should load");
+
+        let staging = config.profile(None).expect("default profile should exist");
+        assert_eq!(
+            Some("https://staging.example.com/v1/"),
+            staging.server_url.as_deref()
+        );
+        let tokens = staging
+            .token_cache()
+            .await
+            .unwrap()
+            .expect("token should be set");
+        assert_eq!(Some("abc".to_owned()), tokens.access_token().await.unwrap());
+
+        let production = config.profile(Some("production")).unwrap();
+        assert_eq!(
+            Some(Path::new("/etc/bindle/keyring.toml")),
+            production.keyring.as_deref()
+        );
+        assert!(production.token_cache().await.unwrap().is_none());
+        production
+            .apply(ClientBuilder::default())
+            .await
+            .unwrap()
+            .build("https://example.com/v1/")
+            .expect("proxy should be valid");
+
+        assert!(config.profile(Some("nope")).is_err());
+    }
+}
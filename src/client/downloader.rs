@@ -0,0 +1,445 @@
+//! Fetching all parcels of a bindle in parallel, with retries and resumable downloads
+
+use std::path::Path;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use sha2::Digest;
+use tokio::io::AsyncWriteExt;
+use tokio::stream::StreamExt;
+
+use super::progress::{ParcelStatus, ProgressCallback, ProgressTracker, ReportingStream};
+use super::{ClientError, Result};
+use crate::provider::{Provider, ProviderError};
+use crate::standalone::{INVOICE_FILE, PARCEL_DIR};
+use crate::{Invoice, Label};
+
+/// The directory in a standalone bindle that parcels are downloaded into until they are complete
+const PARTIAL_DIR: &str = ".partial";
+
+/// Options for controlling how a [`Downloader`](Downloader) fetches parcels
+#[derive(Clone)]
+pub struct DownloadOptions {
+    /// The maximum number of parcels fetched at the same time. Defaults to 4
+    pub concurrency: usize,
+    /// The number of times a parcel is retried after a transient (network, storage or server)
+    /// error. Defaults to 3
+    pub retries: u32,
+    /// The delay before the first retry, doubling with every following attempt. Defaults to 500ms
+    pub retry_delay: Duration,
+    /// Reports the bytes of each fetch, and each parcel once it is fetched, found to be present
+    /// already, found to be missing, or failed. Defaults to `None`
+    pub progress: Option<ProgressCallback>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            concurrency: 4,
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            progress: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for DownloadOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DownloadOptions")
+            .field("concurrency", &self.concurrency)
+            .field("retries", &self.retries)
+            .field("retry_delay", &self.retry_delay)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+/// The outcome of fetching the parcels of a bindle
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    /// Parcels that were fetched
+    pub downloaded: Vec<Label>,
+    /// Parcels that were already present and valid in the destination directory
+    pub reused: Vec<Label>,
+    /// Parcels that don't exist. By design, an invoice can contain parcels that don't exist yet
+    pub missing: Vec<Label>,
+}
+
+impl DownloadReport {
+    /// Returns true if every parcel of the bindle was fetched or already present
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Returns the total number of parcel bytes that were fetched
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.downloaded.iter().map(|l| l.size).sum()
+    }
+}
+
+enum Outcome {
+    Downloaded,
+    Reused,
+    Missing,
+}
+
+/// Fetches all of the parcels of a bindle from a [`Provider`](crate::provider::Provider), such as
+/// a [`Proxy`](crate::proxy::Proxy) for a server or a cache in front of one. At most
+/// [`concurrency`](DownloadOptions::concurrency) parcels are fetched at the same time, and parcels
+/// failing with a transient error are retried with an exponential backoff.
+///
+/// When downloading into a directory, each parcel is written to a partial file that is only moved
+/// into place once its digest has been verified. Downloading the same bindle into the same
+/// directory again skips the parcels that are already there and continues partial files where
+/// they stopped, so an interrupted download can simply be run again
+pub struct Downloader<P> {
+    provider: P,
+    options: DownloadOptions,
+}
+
+impl<P: Provider + Send + Sync> Downloader<P> {
+    /// Returns a new `Downloader` fetching from the given provider
+    pub fn new(provider: P, options: DownloadOptions) -> Self {
+        Downloader { provider, options }
+    }
+
+    /// Fetches all parcels of the given invoice without keeping their data. This is useful with a
+    /// caching provider, to make sure the whole bindle is available from the cache
+    pub async fn fetch(&self, inv: &Invoice) -> Result<DownloadReport> {
+        self.fetch_all(inv, None).await
+    }
+
+    /// Downloads the given invoice and all of its parcels into the given directory, which is
+    /// created if it doesn't exist. The directory has the layout of a standalone bindle (see the
+    /// [`standalone`](crate::standalone) module). Parcels that don't exist are only recorded in
+    /// the returned report, so check [`is_complete`](DownloadReport::is_complete) if the bindle
+    /// needs all of them
+    pub async fn download<D: AsRef<Path>>(&self, inv: &Invoice, dir: D) -> Result<DownloadReport> {
+        let dir = dir.as_ref();
+        tokio::fs::create_dir_all(dir.join(PARCEL_DIR)).await?;
+        tokio::fs::create_dir_all(dir.join(PARTIAL_DIR)).await?;
+        // The invoice can change when a bindle is re-signed, so it is always rewritten
+        tokio::fs::write(dir.join(INVOICE_FILE), toml::to_vec(inv)?).await?;
+
+        let report = self.fetch_all(inv, Some(dir)).await?;
+        // Only empty once every parcel is done, otherwise this keeps the partial files around
+        if let Err(e) = tokio::fs::remove_dir(dir.join(PARTIAL_DIR)).await {
+            debug!("Not removing partial download directory: {}", e);
+        }
+        Ok(report)
+    }
+
+    async fn fetch_all(&self, inv: &Invoice, dir: Option<&Path>) -> Result<DownloadReport> {
+        let labels: Vec<&Label> = inv.parcel.iter().flatten().map(|p| &p.label).collect();
+        let tracker = ProgressTracker::new(labels.len(), self.options.progress.clone());
+        let semaphore = tokio::sync::Semaphore::new(self.options.concurrency.max(1));
+        let fetches = labels.iter().map(|label| {
+            let (semaphore, tracker) = (&semaphore, &tracker);
+            async move {
+                let _permit = semaphore.acquire().await;
+                let outcome = match self.fetch_parcel(inv, label, dir).await {
+                    Ok(o) => o,
+                    Err(e) => {
+                        tracker.parcel_done(&label.sha256, ParcelStatus::Failed(e.to_string()), 0);
+                        return Err(e);
+                    }
+                };
+                let (status, bytes) = match outcome {
+                    Outcome::Downloaded => (ParcelStatus::Transferred, label.size),
+                    Outcome::Reused => (ParcelStatus::Skipped, 0),
+                    Outcome::Missing => (ParcelStatus::Missing, 0),
+                };
+                tracker.parcel_done(&label.sha256, status, bytes);
+                Ok((*label, outcome))
+            }
+        });
+        let mut report = DownloadReport::default();
+        for res in futures::future::join_all(fetches).await {
+            let (label, outcome) = res?;
+            match outcome {
+                Outcome::Downloaded => report.downloaded.push(label.clone()),
+                Outcome::Reused => report.reused.push(label.clone()),
+                Outcome::Missing => report.missing.push(label.clone()),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Fetches a single parcel, retrying transient errors as configured in the options
+    async fn fetch_parcel(
+        &self,
+        inv: &Invoice,
+        label: &Label,
+        dir: Option<&Path>,
+    ) -> Result<Outcome> {
+        let sha = &label.sha256;
+        let paths = dir.map(|d| {
+            (
+                d.join(PARCEL_DIR).join(format!("{}.dat", sha)),
+                d.join(PARTIAL_DIR).join(format!("{}.dat", sha)),
+            )
+        });
+        if let Some((path, _)) = &paths {
+            if is_valid(path, label).await? {
+                debug!("Parcel {} already exists in {}", sha, path.display());
+                return Ok(Outcome::Reused);
+            }
+        }
+
+        let mut delay = self.options.retry_delay;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let res = match &paths {
+                Some((path, partial)) => self.download_parcel(inv, label, path, partial).await,
+                None => self.drain_parcel(inv, sha).await,
+            };
+            match res {
+                Ok(()) => {
+                    info!("Fetched parcel {}", sha);
+                    return Ok(Outcome::Downloaded);
+                }
+                Err(e) if is_not_found(&e) => {
+                    warn!("Parcel {} does not exist", sha);
+                    return Ok(Outcome::Missing);
+                }
+                Err(e) if is_transient(&e) && attempts <= self.options.retries => {
+                    warn!(
+                        "Error fetching parcel {}, retrying in {:?}: {}",
+                        sha, delay, e
+                    );
+                    tokio::time::delay_for(delay).await;
+                    delay *= 2;
+                }
+                Err(ProviderError::ProxyError(e)) => return Err(e),
+                Err(ProviderError::Io(e)) => return Err(e.into()),
+                Err(e) => {
+                    return Err(ClientError::Other(format!(
+                        "Unable to get parcel {}: {}",
+                        sha, e
+                    )))
+                }
+            }
+        }
+    }
+
+    fn report<S>(&self, sha: &str, stream: S) -> ReportingStream<S> {
+        ReportingStream::new(stream, sha, self.options.progress.clone())
+    }
+
+    /// Reads the whole parcel and throws its data away
+    async fn drain_parcel(
+        &self,
+        inv: &Invoice,
+        sha: &str,
+    ) -> std::result::Result<(), ProviderError> {
+        let mut stream = self.report(sha, self.provider.get_parcel(&inv.bindle.id, sha).await?);
+        while let Some(chunk) = stream.next().await {
+            chunk?;
+        }
+        Ok(())
+    }
+
+    /// Downloads the parcel into the partial file, continuing wherever a previous attempt
+    /// stopped, and moves it to the given path once it is complete and valid
+    async fn download_parcel(
+        &self,
+        inv: &Invoice,
+        label: &Label,
+        path: &Path,
+        partial: &Path,
+    ) -> std::result::Result<(), ProviderError> {
+        let sha = &label.sha256;
+        let offset = match tokio::fs::metadata(partial).await {
+            Ok(m) if m.len() < label.size => m.len(),
+            // Anything as long as the label could only be a complete but invalid parcel
+            Ok(_) => {
+                tokio::fs::remove_file(partial).await?;
+                0
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let stream = if offset > 0 {
+            debug!("Resuming parcel {} at byte {}", sha, offset);
+            self.provider
+                .get_parcel_range(&inv.bindle.id, sha, offset, None)
+                .await?
+        } else {
+            self.provider.get_parcel(&inv.bindle.id, sha).await?
+        };
+        let mut stream = self.report(sha, stream);
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(partial)
+            .await?;
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        drop(file);
+
+        let actual = file_sha256(partial).await?;
+        if &actual != sha {
+            // Start over next time, as there is no telling which part is wrong
+            tokio::fs::remove_file(partial).await?;
+            return Err(ProviderError::DigestMismatch {
+                expected: sha.to_owned(),
+                actual,
+            });
+        }
+        tokio::fs::rename(partial, path).await?;
+        Ok(())
+    }
+}
+
+/// Returns the SHA-256 of the file at the given path
+pub(super) async fn file_sha256(path: &Path) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = crate::async_util::AsyncSha256::new();
+    tokio::io::copy(&mut file, &mut hasher).await?;
+    let hasher = hasher
+        .into_inner()
+        .map_err(|_| std::io::Error::other("Parcel hasher mutex was poisoned"))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns whether the file at the given path exists and matches the given label
+async fn is_valid(path: &Path, label: &Label) -> Result<bool> {
+    match tokio::fs::metadata(path).await {
+        Ok(m) if m.len() == label.size => (),
+        Ok(_) => return Ok(false),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(file_sha256(path).await? == label.sha256)
+}
+
+fn is_not_found(e: &ProviderError) -> bool {
+    matches!(
+        e,
+        ProviderError::NotFound | ProviderError::ProxyError(ClientError::ParcelNotFound)
+    )
+}
+
+/// Returns whether the given error could go away by fetching the parcel again
+fn is_transient(e: &ProviderError) -> bool {
+    match e {
+        ProviderError::ProxyError(e) => crate::standalone::is_transient(e),
+        ProviderError::Io(_) | ProviderError::DigestMismatch { .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_download_resumes() {
+        let (store, _) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        let mut inv = scaffold.invoice.clone();
+        store
+            .create_invoice(&inv)
+            .await
+            .expect("Unable to create invoice");
+        for info in scaffold.parcel_files.values() {
+            let data = std::io::Cursor::new(info.data.clone());
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &info.sha,
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await
+                .expect("Unable to create parcel");
+        }
+        // A parcel that hasn't been uploaded yet
+        let missing = Label {
+            sha256: "abcdef1234567890987654321".to_owned(),
+            size: 10,
+            ..Label::default()
+        };
+        inv.parcel.get_or_insert_with(Vec::new).push(crate::Parcel {
+            label: missing.clone(),
+            conditions: None,
+        });
+
+        let dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let downloader = Downloader::new(
+            store,
+            DownloadOptions {
+                concurrency: 2,
+                retry_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+        );
+        let report = downloader
+            .download(&inv, dir.path())
+            .await
+            .expect("Unable to download bindle");
+        assert_eq!(3, report.downloaded.len());
+        assert!(report.reused.is_empty());
+        assert_eq!(vec![missing.sha256.clone()], label_shas(&report.missing));
+        assert!(dir.path().join(INVOICE_FILE).is_file());
+
+        // Interrupt the download of one parcel halfway and lose another one completely
+        let parcel_path = |sha: &str| dir.path().join(PARCEL_DIR).join(format!("{}.dat", sha));
+        let (lost, partial) = (
+            &scaffold.parcel_files["crate"],
+            &scaffold.parcel_files["barrel"],
+        );
+        tokio::fs::remove_file(parcel_path(&lost.sha))
+            .await
+            .unwrap();
+        tokio::fs::remove_file(parcel_path(&partial.sha))
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(dir.path().join(PARTIAL_DIR))
+            .await
+            .unwrap();
+        tokio::fs::write(
+            dir.path()
+                .join(PARTIAL_DIR)
+                .join(format!("{}.dat", partial.sha)),
+            &partial.data[..partial.data.len() / 2],
+        )
+        .await
+        .unwrap();
+
+        let report = downloader
+            .download(&inv, dir.path())
+            .await
+            .expect("Unable to resume download");
+        let mut downloaded = label_shas(&report.downloaded);
+        downloaded.sort();
+        let mut expected = vec![lost.sha.clone(), partial.sha.clone()];
+        expected.sort();
+        assert_eq!(expected, downloaded);
+        assert_eq!(
+            vec![scaffold.parcel_files["parcel"].sha.clone()],
+            label_shas(&report.reused)
+        );
+        for info in scaffold.parcel_files.values() {
+            assert_eq!(
+                info.data,
+                tokio::fs::read(parcel_path(&info.sha)).await.unwrap(),
+                "Parcel {} should have the right data",
+                info.sha
+            );
+        }
+        assert!(
+            !dir.path().join(PARTIAL_DIR).exists(),
+            "Partial download directory should be removed"
+        );
+    }
+
+    fn label_shas(labels: &[Label]) -> Vec<String> {
+        labels.iter().map(|l| l.sha256.clone()).collect()
+    }
+}
@@ -1,3 +1,6 @@
+use std::fmt;
+
+use serde::de::DeserializeOwned;
 use thiserror::Error;
 
 /// Describes the various errors that can be returned from the client
@@ -12,12 +15,18 @@ pub enum ClientError {
     /// IO errors from interacting with the file system
     #[error("Error while performing IO operation: {0:?}")]
     Io(#[from] std::io::Error),
-    /// Invalid TOML parsing that can occur when loading an invoice or label from disk
-    #[error("Invalid toml: {0:?}")]
-    InvalidToml(#[from] toml::de::Error),
+    /// Invalid TOML parsing that can occur when loading an invoice or label from disk or when the
+    /// server returns data the client doesn't understand. Contains detailed information about
+    /// where the problem is
+    #[error("Invalid toml: {0}")]
+    InvalidToml(#[from] Box<TomlDiagnostics>),
     /// Invalid TOML serialization that can occur when serializing an object to a request
     #[error("Invalid toml: {0:?}")]
     TomlSerializationError(#[from] toml::ser::Error),
+    /// Invalid JSON that can occur when the server returns JSON the client doesn't understand or
+    /// when serializing an object to a request
+    #[error("Invalid json: {0}")]
+    InvalidJson(#[from] serde_json::Error),
     /// There was a problem with the http client. This is likely not a user issue. Contains the
     /// underlying error
     #[error("Error creating request: {0:?}")]
@@ -25,6 +34,12 @@ pub enum ClientError {
     /// An invalid ID was given. Returns the underlying parse error
     #[error("Invalid id: {0:?}")]
     InvalidId(#[from] crate::id::ParseError),
+    /// Signing, verifying or managing keys failed. Contains the underlying error
+    #[error("Signature error: {0}")]
+    SignatureError(#[from] crate::signature::SignatureError),
+    /// The invoice wasn't recorded in the trusted transparency log, or its log entries are invalid
+    #[error("Transparency log verification failed: {0}")]
+    TransparencyError(#[from] crate::transparency::TransparencyError),
 
     // API errors
     /// The invoice was not found. Note that this does not necessarily mean it doesn't exist. It
@@ -40,6 +55,23 @@ pub enum ClientError {
     /// The parcel already exists.
     #[error("Parcel already exists")]
     ParcelAlreadyExists,
+    /// An invoice's signatures don't satisfy a verification strategy. This is returned both when
+    /// the server rejects an invoice and when an invoice fetched from the server fails the
+    /// client's own verification
+    #[error("Invoice failed signature verification with the {} strategy: {}", .0.strategy, .0.reason)]
+    VerificationFailed(crate::VerificationFailure),
+    /// The server rejected an invoice because it is missing signatures required by the signing
+    /// policy for its namespace
+    #[error("Invoice is missing trusted signatures required by the signing policy for {}: {:?}", .0.bindles, .0.missing)]
+    SigningPolicyNotSatisfied(crate::SigningPolicyResult),
+    /// The server rejected a request because it would exceed a storage quota of the user or of the
+    /// whole server
+    #[error("Request would exceed a quota of {} {}: {} of them are used and {} more were requested", .0.limit, .0.resource, .0.used, .0.requested)]
+    QuotaExceeded(crate::QuotaExceeded),
+    /// A conditional request failed because the invoice no longer matches the entity tag it was
+    /// made with, which means it was yanked or replaced after it was read
+    #[error("Invoice has changed since it was read")]
+    PreconditionFailed,
     /// The error returned when the request is invalid. Contains the underlying HTTP status code and
     /// any message returned from the API
     #[error("Invalid request (status code {status_code:?}): {message:?}")]
@@ -47,6 +79,14 @@ pub enum ClientError {
         status_code: reqwest::StatusCode,
         message: Option<String>,
     },
+    /// The data of a parcel did not match its expected SHA-256 sum. When uploading, the upload is
+    /// aborted once this is detected
+    #[error("Parcel data has a SHA-256 sum of {actual}, but {expected} was expected")]
+    DigestMismatch { expected: String, actual: String },
+    /// The data uploaded for a parcel did not match the length it was uploaded with. The upload is
+    /// aborted once this is detected
+    #[error("Parcel data is {actual} bytes long, but {expected} bytes were expected")]
+    SizeMismatch { expected: u64, actual: u64 },
     /// A server error was encountered. Contains an optional message from the server
     #[error("Server has encountered an error: {0:?}")]
     ServerError(Option<String>),
@@ -54,6 +94,15 @@ pub enum ClientError {
     /// is only valid if the server supports authentication and/or permissions
     #[error("User has invalid credentials or is not authorized to access the requested resource")]
     Unauthorized,
+    /// There was a problem obtaining or refreshing an access token from the identity provider.
+    /// Contains a message describing the underlying issue
+    #[error("Unable to get access token: {0}")]
+    TokenError(String),
+
+    /// The server implements a version of the Bindle spec that this client doesn't support.
+    /// Contains the spec version of the server and the one supported by the client
+    #[error("Server implements version {server} of the Bindle spec, but this client only supports version {client}")]
+    IncompatibleServer { server: String, client: String },
 
     /// A catch-all for uncategorized errors. Contains an error message describing the underlying
     /// issue
@@ -61,9 +110,289 @@ pub enum ClientError {
     Other(String),
 }
 
+impl ClientError {
+    /// Returns the broad kind of this error, which command line tools use to pick an exit code
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ClientError::InvalidURL(_)
+            | ClientError::InvalidConfig(_)
+            | ClientError::InvalidId(_) => ErrorKind::Usage,
+            ClientError::InvoiceNotFound | ClientError::ParcelNotFound => ErrorKind::NotFound,
+            ClientError::SignatureError(_)
+            | ClientError::TransparencyError(_)
+            | ClientError::VerificationFailed(_)
+            | ClientError::SigningPolicyNotSatisfied(_)
+            | ClientError::DigestMismatch { .. }
+            | ClientError::SizeMismatch { .. } => ErrorKind::Verification,
+            ClientError::Unauthorized | ClientError::TokenError(_) => ErrorKind::Auth,
+            ClientError::HttpClientError(_) => ErrorKind::Network,
+            ClientError::InvoiceAlreadyExists
+            | ClientError::ParcelAlreadyExists
+            | ClientError::PreconditionFailed => ErrorKind::Conflict,
+            ClientError::Io(_)
+            | ClientError::InvalidToml(_)
+            | ClientError::TomlSerializationError(_)
+            | ClientError::InvalidJson(_)
+            | ClientError::QuotaExceeded(_)
+            | ClientError::InvalidRequest { .. }
+            | ClientError::ServerError(_)
+            | ClientError::IncompatibleServer { .. }
+            | ClientError::Other(_) => ErrorKind::Other,
+        }
+    }
+}
+
+/// The broad kinds of client errors. Each kind has a stable exit code, so scripts calling the
+/// `bindle` CLI can branch on the type of failure without parsing error messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Any error that doesn't fit one of the other kinds
+    Other,
+    /// The command was given invalid configuration, such as a malformed URL or bindle ID
+    Usage,
+    /// The requested invoice or parcel was not found
+    NotFound,
+    /// An invoice failed signature or transparency log verification, or parcel data didn't match
+    /// its label
+    Verification,
+    /// The credentials were invalid, or an access token could not be obtained
+    Auth,
+    /// The server could not be reached or the connection failed
+    Network,
+    /// The invoice or parcel already exists, or changed since it was read
+    Conflict,
+}
+
+impl ErrorKind {
+    /// Returns the exit code the CLI uses for this kind of error. `0` is never returned, and `2`
+    /// is shared with invalid command line arguments
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::NotFound => 3,
+            ErrorKind::Verification => 4,
+            ErrorKind::Auth => 5,
+            ErrorKind::Network => 6,
+            ErrorKind::Conflict => 7,
+        }
+    }
+}
+
+impl From<toml::de::Error> for ClientError {
+    fn from(e: toml::de::Error) -> Self {
+        ClientError::InvalidToml(Box::new(TomlDiagnostics::new(e, None, None)))
+    }
+}
+
 impl From<std::convert::Infallible> for ClientError {
     fn from(_: std::convert::Infallible) -> Self {
         // Doesn't matter what we return as Infallible cannot happen
         ClientError::Other("Shouldn't happen".to_string())
     }
 }
+
+/// Detailed information about a TOML document that could not be deserialized, such as an invoice
+/// returned by a server with a newer (or older) schema than the client expects
+#[derive(Debug)]
+pub struct TomlDiagnostics {
+    /// The path to the field that could not be deserialized (e.g. `parcel[1].label.sha256`), or to
+    /// the table containing an unexpected field. This is `None` for problems at the top level of
+    /// the document
+    pub path: Option<String>,
+    /// The 1-based line and column of the problem, if known
+    pub line_col: Option<(usize, usize)>,
+    /// The name of the field that wasn't expected, if the problem was an unknown field
+    pub unexpected_field: Option<String>,
+    /// The offending line from the document, if known
+    pub snippet: Option<String>,
+    source: toml::de::Error,
+}
+
+impl TomlDiagnostics {
+    fn new(source: toml::de::Error, path: Option<String>, raw: Option<&str>) -> Self {
+        let unexpected_field = source
+            .to_string()
+            .strip_prefix("unknown field `")
+            .and_then(|rest| rest.split('`').next())
+            .map(|field| field.to_owned());
+        // The TOML error points at the start of the table for unknown fields, so look for the
+        // actual key within the table to make the snippet more useful
+        let line_col = match (source.line_col(), &unexpected_field, raw) {
+            (Some((start, _)), Some(field), Some(raw)) => raw
+                .lines()
+                .enumerate()
+                .skip(start)
+                .find_map(|(line, content)| {
+                    let key = content.trim_start();
+                    let is_field = key
+                        .strip_prefix(field.as_str())
+                        .map(|rest| rest.trim_start().starts_with('='))
+                        .unwrap_or(false);
+                    if is_field {
+                        Some((line, content.len() - key.len()))
+                    } else {
+                        None
+                    }
+                })
+                .or_else(|| source.line_col()),
+            _ => source.line_col(),
+        };
+        let snippet = line_col
+            .and_then(|(line, _)| raw.and_then(|raw| raw.lines().nth(line)))
+            .map(|line| line.to_owned());
+        TomlDiagnostics {
+            path,
+            line_col: line_col.map(|(line, col)| (line + 1, col + 1)),
+            unexpected_field,
+            snippet,
+            source,
+        }
+    }
+}
+
+impl std::error::Error for TomlDiagnostics {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl fmt::Display for TomlDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)?;
+        // The TOML error only knows about table keys, so the path is more precise for arrays
+        if let Some(path) = &self.path {
+            write!(f, " (at `{}`)", path)?;
+        }
+        if let (Some(snippet), Some((line, col))) = (&self.snippet, self.line_col) {
+            let gutter = line.to_string();
+            write!(
+                f,
+                "\n{} | {}\n{} | {}^",
+                gutter,
+                snippet,
+                " ".repeat(gutter.len()),
+                " ".repeat(col - 1)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl From<crate::async_util::Mismatch> for ClientError {
+    fn from(m: crate::async_util::Mismatch) -> Self {
+        match m {
+            crate::async_util::Mismatch::Digest { expected, actual } => {
+                ClientError::DigestMismatch { expected, actual }
+            }
+            crate::async_util::Mismatch::Size { expected, actual } => {
+                ClientError::SizeMismatch { expected, actual }
+            }
+        }
+    }
+}
+
+/// Deserializes the given TOML data, returning a [`ClientError::InvalidToml`](ClientError) with
+/// detailed diagnostics on failure
+pub(crate) fn from_toml_slice<T: DeserializeOwned>(raw: &[u8]) -> Result<T, ClientError> {
+    let raw = std::str::from_utf8(raw).map_err(|e| {
+        ClientError::from(<toml::de::Error as serde::de::Error>::custom(format!(
+            "invalid UTF-8: {}",
+            e
+        )))
+    })?;
+    let mut deserializer = toml::Deserializer::new(raw);
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        // An empty path means the error occurred at the top level of the document
+        let path = if path == "." { None } else { Some(path) };
+        ClientError::InvalidToml(Box::new(TomlDiagnostics::new(
+            e.into_inner(),
+            path,
+            Some(raw),
+        )))
+    })?;
+    deserializer.end().map_err(|e| {
+        ClientError::InvalidToml(Box::new(TomlDiagnostics::new(e, None, Some(raw))))
+    })?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_toml_diagnostics() {
+        let raw = r#"bindleVersion = "1.0.0"
+
+[bindle]
+name = "example.com/foo"
+version = "1.0.0"
+colour = "blue"
+"#;
+        let err = from_toml_slice::<crate::Invoice>(raw.as_bytes())
+            .expect_err("unknown field should fail");
+        let diagnostics = match err {
+            ClientError::InvalidToml(d) => d,
+            e => panic!("Expected an InvalidToml error, got {:?}", e),
+        };
+        assert_eq!(Some("colour"), diagnostics.unexpected_field.as_deref());
+        // Unknown fields are reported on the table containing them
+        assert_eq!(Some("bindle"), diagnostics.path.as_deref());
+        let (line, _) = diagnostics.line_col.expect("line should be set");
+        assert_eq!(6, line);
+        assert_eq!(Some("colour = \"blue\""), diagnostics.snippet.as_deref());
+
+        let raw = r#"bindleVersion = "1.0.0"
+
+[bindle]
+name = "example.com/foo"
+version = "1.0.0"
+
+[[parcel]]
+[parcel.label]
+sha256 = "abc"
+mediaType = "text/plain"
+name = "foo.txt"
+size = "big"
+"#;
+        let err = from_toml_slice::<crate::Invoice>(raw.as_bytes())
+            .expect_err("invalid size should fail");
+        let diagnostics = match err {
+            ClientError::InvalidToml(d) => d,
+            e => panic!("Expected an InvalidToml error, got {:?}", e),
+        };
+        assert_eq!(Some("parcel[0].label.size"), diagnostics.path.as_deref());
+        assert!(diagnostics.unexpected_field.is_none());
+        assert!(diagnostics.to_string().contains("size = \"big\""));
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        // Scripts depend on these, so they must never change
+        assert_eq!(3, ClientError::InvoiceNotFound.kind().exit_code());
+        assert_eq!(3, ClientError::ParcelNotFound.kind().exit_code());
+        assert_eq!(
+            4,
+            ClientError::DigestMismatch {
+                expected: "a".into(),
+                actual: "b".into()
+            }
+            .kind()
+            .exit_code()
+        );
+        assert_eq!(5, ClientError::Unauthorized.kind().exit_code());
+        assert_eq!(7, ClientError::PreconditionFailed.kind().exit_code());
+        assert_eq!(
+            2,
+            ClientError::InvalidConfig("bad".into()).kind().exit_code()
+        );
+        assert_eq!(1, ClientError::Other("oops".into()).kind().exit_code());
+        assert_eq!(
+            "\"not_found\"",
+            serde_json::to_string(&ErrorKind::NotFound).unwrap()
+        );
+    }
+}
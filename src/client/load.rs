@@ -25,5 +25,5 @@ where
     T: serde::de::DeserializeOwned,
 {
     let data = tokio::fs::read(file_path).await?;
-    Ok(toml::from_slice::<T>(&data)?)
+    super::error::from_toml_slice(&data)
 }
@@ -1,22 +1,46 @@
 //! Client implementation for consuming a Bindle API. Although written in Rust, it is not specific
 //! to the Rust implementation. It is meant to consume any spec-compliant bindle implementation.
 
+pub mod attest;
+mod builder;
+pub mod config;
+pub mod downloader;
 mod error;
 pub mod load;
+pub mod progress;
+mod timeouts;
+pub mod tokens;
+mod update;
+mod upload;
 
 use std::convert::TryInto;
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
+use std::sync::Arc;
 
-use log::{debug, info};
+use ed25519_dalek::PublicKey;
+use log::{debug, warn};
 use reqwest::header;
 use reqwest::Client as HttpClient;
 use reqwest::{Body, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
 use tokio::stream::{Stream, StreamExt};
+use tracing::Instrument;
 use url::Url;
 
+use crate::filters::resolution::{FeatureSelector, Preference};
+use crate::signature::{EncryptedKeyRing, KeyRing, RevocationList, VerificationStrategy};
 use crate::Id;
+use error::from_toml_slice;
+use progress::{ProgressCallback, ReportingStream};
+use timeouts::Operation;
 
-pub use error::ClientError;
+pub use builder::ClientBuilder;
+pub use error::{ClientError, ErrorKind, TomlDiagnostics};
+pub use timeouts::Timeouts;
+pub use tokens::TokenCache;
+pub use update::UpdateReport;
+pub use upload::DEFAULT_UPLOAD_CHUNK_SIZE;
 
 /// A shorthand `Result` type that always uses `ClientError` as its error variant
 pub type Result<T> = std::result::Result<T, ClientError>;
@@ -24,42 +48,151 @@ pub type Result<T> = std::result::Result<T, ClientError>;
 pub const INVOICE_ENDPOINT: &str = "_i";
 pub const QUERY_ENDPOINT: &str = "_q";
 pub const RELATIONSHIP_ENDPOINT: &str = "_r";
+pub const UPLOAD_ENDPOINT: &str = "_u";
+pub const CAPABILITIES_ENDPOINT: &str = "_capabilities";
+pub const KEYRING_ENDPOINT: &str = "_keyring";
+pub const GC_ENDPOINT: &str = "_gc";
+pub const REPLICATION_ENDPOINT: &str = "_replication";
+pub const QUOTA_ENDPOINT: &str = "_quota";
+pub const BLOB_ENDPOINT: &str = "_b";
+pub const HISTORY_SUBRESOURCE: &str = "_history";
+pub const SUMMARY_SUBRESOURCE: &str = "_summary";
+pub const METADATA_SUBRESOURCE: &str = "_meta";
+pub const SELECTION_SUBRESOURCE: &str = "_selection";
+pub const STATUS_SUBRESOURCE: &str = "_status";
+pub const SIGNATURE_SUBRESOURCE: &str = "_signature";
 const TOML_MIME_TYPE: &str = "application/toml";
+const JSON_MIME_TYPE: &str = "application/json";
+const NDJSON_MIME_TYPE: &str = "application/x-ndjson";
 
 /// A client type for interacting with a Bindle server
 #[derive(Clone)]
 pub struct Client {
     client: HttpClient,
     base_url: Url,
+    tokens: Option<TokenCache>,
+    verification_strategy: VerificationStrategy,
+    keyring: Arc<KeyRing>,
+    revocations: Arc<RevocationList>,
+    transparency_log: Option<PublicKey>,
+    json: bool,
+    timeouts: Timeouts,
+    progress: Option<ProgressCallback>,
 }
 
 impl Client {
     /// Returns a new Client with the given URL. This URL should be the FQDN plus any namespacing
     /// (like `v1`). So if you were running a bindle server mounted at the v1 endpoint, your URL
     /// would look something like `http://my.bindle.com/v1/`. Will return an error if the URL is not
-    /// valid
+    /// valid. Use [`builder`](Client::builder) for more configuration options, such as TLS
+    /// client certificates
     pub fn new(base_url: &str) -> Result<Self> {
-        // Note that the trailing slash is important, otherwise the URL parser will treat is as a
-        // "file" component of the URL. So we need to check that it is added before parsing
-        let mut base = base_url.to_owned();
-        if !base.ends_with('/') {
-            info!("Provided base URL missing trailing slash, adding...");
-            base.push('/');
+        ClientBuilder::default().build(base_url)
+    }
+
+    /// Returns a [`ClientBuilder`](ClientBuilder) for configuring a new client
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// Returns the base URL of the server this client talks to
+    pub fn base_url(&self) -> &str {
+        self.base_url.as_str()
+    }
+
+    /// Configures the client to authenticate all requests with an `Authorization: Bearer` header
+    /// whenever the given cache contains a token. Expired tokens are refreshed automatically
+    pub fn with_token_cache(mut self, tokens: TokenCache) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Configures the client to verify the signatures of every invoice it fetches against the given
+    /// keyring using the given strategy. Invoices that don't satisfy the strategy are never
+    /// returned; a [`ClientError::VerificationFailed`](ClientError::VerificationFailed) error is
+    /// returned instead. The default strategy, [`None`](VerificationStrategy::None), accepts all
+    /// invoices
+    pub fn with_verification(
+        mut self,
+        strategy: VerificationStrategy,
+        keyring: impl Into<Arc<KeyRing>>,
+    ) -> Self {
+        self.verification_strategy = strategy;
+        self.keyring = keyring.into();
+        self
+    }
+
+    /// Configures the client to reject invoices with signatures made by keys from the given
+    /// revocation list when verifying them (see [`with_verification`](Client::with_verification)).
+    /// Has no effect with the [`None`](VerificationStrategy::None) strategy
+    pub fn with_revocations(mut self, revocations: impl Into<Arc<RevocationList>>) -> Self {
+        self.revocations = revocations.into();
+        self
+    }
+
+    /// Configures the client to check that every invoice it fetches was recorded in the
+    /// transparency log with the given public key (see the [`transparency`](crate::transparency)
+    /// module). Invoices without a valid log entry are never returned; a
+    /// [`ClientError::TransparencyError`](ClientError::TransparencyError) error is returned instead
+    pub fn with_transparency_log(mut self, log_key: PublicKey) -> Self {
+        self.transparency_log = Some(log_key);
+        self
+    }
+
+    /// Configures the client to use the given timeouts instead of the ones it was built with. As
+    /// clients are cheap to clone, this can be used to override the timeouts for a single call,
+    /// such as for downloading a parcel that is known to be large
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Configures the client to report the bytes of every parcel it uploads or downloads to the
+    /// given reporter (see the [`progress`](progress) module). Like
+    /// [`with_timeouts`](Client::with_timeouts), this can be used on a clone of a client to only
+    /// report the progress of some calls
+    pub fn with_progress_reporter(mut self, reporter: ProgressCallback) -> Self {
+        self.progress = Some(reporter);
+        self
+    }
+
+    fn report_bytes(&self, sha: &str, bytes: u64) {
+        if let Some(reporter) = &self.progress {
+            reporter.bytes_transferred(sha, bytes);
         }
-        let base_parsed = Url::parse(&base)?;
-        let mut headers = header::HeaderMap::new();
-        headers.insert(header::ACCEPT, "application/toml".parse().unwrap());
-        // TODO: As this evolves, we might want to allow for setting time outs and accepting
-        // self-signed certs
-        let client = HttpClient::builder()
-            .http2_prior_knowledge()
-            .default_headers(headers)
-            .build()
-            .map_err(|e| ClientError::Other(e.to_string()))?;
-        Ok(Client {
-            client,
-            base_url: base_parsed,
-        })
+    }
+
+    /// Sends the given request, adding the bearer token if there is one, the timeout for the kind
+    /// of operation and the trace context (see the [`trace`](crate::trace) module)
+    async fn send(&self, req: RequestBuilder, operation: Operation) -> Result<reqwest::Response> {
+        let span = tracing::debug_span!(
+            "bindle_client_request",
+            ?operation,
+            trace_id = tracing::field::Empty
+        );
+        self.send_traced(req, operation).instrument(span).await
+    }
+
+    async fn send_traced(
+        &self,
+        req: RequestBuilder,
+        operation: Operation,
+    ) -> Result<reqwest::Response> {
+        let trace = crate::trace::outgoing();
+        tracing::Span::current().record("trace_id", &trace.trace_id().as_str());
+        let req = req.header(crate::trace::TRACEPARENT_HEADER, trace.to_string());
+        let req = match self.timeouts.get(operation) {
+            Some(timeout) => req.timeout(timeout),
+            None => req,
+        };
+        let req = match &self.tokens {
+            Some(tokens) => match tokens.access_token().await? {
+                Some(token) => req.bearer_auth(token),
+                None => req,
+            },
+            None => req,
+        };
+        Ok(req.send().await?)
     }
 
     /// Performs a raw request using the underlying HTTP client and returns the raw response. The
@@ -79,7 +212,9 @@ impl Client {
             Some(b) => req.body(b),
             None => req,
         };
-        req.send().await.map_err(|e| e.into())
+        self.send(req, Operation::Invoice)
+            .await
+            .map_err(|e| e.into())
     }
 
     //////////////// Create Invoice ////////////////
@@ -90,7 +225,13 @@ impl Client {
         &self,
         inv: crate::Invoice,
     ) -> Result<crate::InvoiceCreateResponse> {
-        let req = self.create_invoice_builder().body(toml::to_vec(&inv)?);
+        let req = if self.json {
+            self.create_invoice_builder(JSON_MIME_TYPE)
+                .body(serde_json::to_vec(&inv)?)
+        } else {
+            self.create_invoice_builder(TOML_MIME_TYPE)
+                .body(toml::to_vec(&inv)?)
+        };
         self.create_invoice_request(req).await
     }
 
@@ -106,25 +247,25 @@ impl Client {
         let inv_stream = load::raw(path).await?;
         debug!("Successfully loaded invoice stream");
         let req = self
-            .create_invoice_builder()
+            .create_invoice_builder(TOML_MIME_TYPE)
             .body(Body::wrap_stream(inv_stream));
         self.create_invoice_request(req).await
     }
 
-    fn create_invoice_builder(&self) -> RequestBuilder {
+    fn create_invoice_builder(&self, content_type: &str) -> RequestBuilder {
         // We can unwrap here because any URL error would be programmers fault
         self.client
             .post(self.base_url.join(INVOICE_ENDPOINT).unwrap())
-            .header(header::CONTENT_TYPE, TOML_MIME_TYPE)
+            .header(header::CONTENT_TYPE, content_type)
     }
 
     async fn create_invoice_request(
         &self,
         req: RequestBuilder,
     ) -> Result<crate::InvoiceCreateResponse> {
-        let resp = req.send().await?;
+        let resp = self.send(req, Operation::Invoice).await?;
         let resp = unwrap_status(resp, Endpoint::Invoice).await?;
-        Ok(toml::from_slice(&resp.bytes().await?)?)
+        parse_response(resp).await
     }
 
     //////////////// Get Invoice ////////////////
@@ -132,7 +273,9 @@ impl Client {
     /// Returns the requested invoice from the bindle server if it exists. This can take any form
     /// that can convert into the `Id` type, but generally speaking, this is the canonical name of
     /// the bindle (e.g. `example.com/foo/1.0.0`). If you want to fetch a yanked invoice, use the
-    /// [`get_yanked_invoice`](Client::get_yanked_invoice) function
+    /// [`get_yanked_invoice`](Client::get_yanked_invoice) function. If the client is configured
+    /// with a verification strategy (see [`with_verification`](Client::with_verification)), the
+    /// invoice is only returned if its signatures satisfy it
     pub async fn get_invoice<I>(&self, id: I) -> Result<crate::Invoice>
     where
         I: TryInto<Id>,
@@ -160,11 +303,186 @@ impl Client {
         self.get_invoice_request(url).await
     }
 
+    /// Same as [`get_invoice`](Client::get_invoice), but also returns the entity tag of the invoice,
+    /// if the server sent one. The tag can be passed to conditional requests like
+    /// [`create_parcel_if_match`](Client::create_parcel_if_match) so they fail with
+    /// [`PreconditionFailed`](ClientError::PreconditionFailed) if the invoice is yanked or
+    /// replaced in the meantime
+    pub async fn get_invoice_with_etag<I>(&self, id: I) -> Result<(crate::Invoice, Option<String>)>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        self.get_invoice_response(
+            self.base_url
+                .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?,
+        )
+        .await
+    }
+
+    /// Returns the recorded history of the given invoice, such as when it was created and yanked.
+    /// This works for yanked invoices as well
+    pub async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let req = self.client.get(self.base_url.join(&format!(
+            "{}/{}/{}",
+            INVOICE_ENDPOINT, parsed_id, HISTORY_SUBRESOURCE
+        ))?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        parse_response(resp).await
+    }
+
+    /// Returns a summary of the given invoice, which is much smaller than the invoice itself for
+    /// bindles with many parcels. This works for yanked invoices as well. As the summary doesn't
+    /// contain the signatures, it is not checked against the client's verification strategy
+    pub async fn get_invoice_summary<I>(&self, id: I) -> Result<crate::InvoiceSummary>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let req = self.client.get(self.base_url.join(&format!(
+            "{}/{}/{}",
+            INVOICE_ENDPOINT, parsed_id, SUMMARY_SUBRESOURCE
+        ))?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        parse_response(resp).await
+    }
+
+    /// Returns the metadata of the given invoice, such as when it was created and last modified and
+    /// how many parcels and signatures it has. This works for yanked invoices as well
+    pub async fn get_invoice_metadata<I>(&self, id: I) -> Result<crate::InvoiceMetadata>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let req = self.client.get(self.base_url.join(&format!(
+            "{}/{}/{}",
+            INVOICE_ENDPOINT, parsed_id, METADATA_SUBRESOURCE
+        ))?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        parse_response(resp).await
+    }
+
+    /// Returns the status of the background processing of the given bindle, on servers with a
+    /// processing pipeline. Servers without one, and bindles the server has no status for, return
+    /// a [`InvoiceNotFound`](ClientError::InvoiceNotFound) error
+    pub async fn get_processing_status<I>(&self, id: I) -> Result<crate::ProcessingStatus>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let req = self.client.get(self.base_url.join(&format!(
+            "{}/{}/{}",
+            INVOICE_ENDPOINT, parsed_id, STATUS_SUBRESOURCE
+        ))?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        parse_response(resp).await
+    }
+
+    /// Returns the labels of the parcels of the given invoice that are needed for the given groups
+    /// and features, as selected by the server. See the
+    /// [`resolution`](crate::filters::resolution) module for how the parcels are chosen
+    pub async fn get_parcel_selection<I>(
+        &self,
+        id: I,
+        groups: &[&str],
+        features: &[FeatureSelector],
+    ) -> Result<Vec<crate::Label>>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        self.get_parcel_selection_with_preference(id, groups, features, Preference::default())
+            .await
+    }
+
+    /// Like [`get_parcel_selection`](Client::get_parcel_selection), but asks the server to choose
+    /// the parcels of `oneOf` groups according to the given preference. This lets clients that
+    /// can't run the resolver themselves pick, for example, the smallest parcels that work for
+    /// their platform
+    pub async fn get_parcel_selection_with_preference<I>(
+        &self,
+        id: I,
+        groups: &[&str],
+        features: &[FeatureSelector],
+        preference: Preference,
+    ) -> Result<Vec<crate::Label>>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let features: Vec<String> = features.iter().map(|f| f.to_string()).collect();
+        let mut query = vec![
+            ("groups", groups.join(",")),
+            ("features", features.join(",")),
+        ];
+        if preference != Preference::default() {
+            query.push(("prefer", preference.to_string()));
+        }
+        let req = self
+            .client
+            .get(self.base_url.join(&format!(
+                "{}/{}/{}",
+                INVOICE_ENDPOINT, parsed_id, SELECTION_SUBRESOURCE
+            ))?)
+            .query(&query);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        Ok(parse_response::<crate::ParcelSelectionResponse>(resp)
+            .await?
+            .labels)
+    }
+
     async fn get_invoice_request(&self, url: Url) -> Result<crate::Invoice> {
+        Ok(self.get_invoice_response(url).await?.0)
+    }
+
+    async fn get_invoice_response(&self, url: Url) -> Result<(crate::Invoice, Option<String>)> {
         let req = self.client.get(url);
-        let resp = req.send().await?;
+        let resp = self.send(req, Operation::Invoice).await?;
         let resp = unwrap_status(resp, Endpoint::Invoice).await?;
-        Ok(toml::from_slice(&resp.bytes().await?)?)
+        let etag = resp
+            .headers()
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned());
+        let inv: crate::Invoice = parse_response(resp).await?;
+        self.verify_invoice(&inv)?;
+        Ok((inv, etag))
+    }
+
+    /// Checks the invoice against the configured verification strategy, revocation list and
+    /// transparency log
+    fn verify_invoice(&self, inv: &crate::Invoice) -> Result<()> {
+        self.verification_strategy
+            .verify(inv, &self.keyring, &self.revocations)
+            .map_err(|e| {
+                debug!("Invoice {} failed verification: {}", inv.bindle.id, e);
+                ClientError::VerificationFailed(crate::VerificationFailure {
+                    strategy: self.verification_strategy,
+                    reason: e.to_string(),
+                })
+            })?;
+        if let Some(log_key) = &self.transparency_log {
+            inv.verify_log_entries(log_key).map_err(|e| {
+                debug!("Invoice {} failed log verification: {}", inv.bindle.id, e);
+                ClientError::TransparencyError(e)
+            })?;
+        }
+        Ok(())
     }
 
     //////////////// Query Invoice ////////////////
@@ -174,14 +492,72 @@ impl Client {
         &self,
         query_opts: crate::QueryOptions,
     ) -> Result<crate::search::Matches> {
-        let resp = self
+        let req = self
+            .client
+            .get(self.base_url.join(QUERY_ENDPOINT).unwrap())
+            .query(&query_opts);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Query).await?;
+        parse_response(resp).await
+    }
+
+    /// Same as [`query_invoices`](Client::query_invoices), but returns every matching invoice as a
+    /// stream instead of a single page of matches. The server sends the invoices as they are found,
+    /// using the limit as the size of the pages it fetches them in, so large result sets can be
+    /// processed without holding them in memory. Returns an error if the server doesn't support
+    /// streaming queries
+    pub async fn query_stream(
+        &self,
+        query_opts: crate::QueryOptions,
+    ) -> Result<impl Stream<Item = Result<crate::Invoice>> + Unpin> {
+        let req = self
             .client
             .get(self.base_url.join(QUERY_ENDPOINT).unwrap())
             .query(&query_opts)
-            .send()
-            .await?;
+            .header(header::ACCEPT, NDJSON_MIME_TYPE);
+        let resp = self.send(req, Operation::Invoice).await?;
         let resp = unwrap_status(resp, Endpoint::Query).await?;
-        Ok(toml::from_slice(&resp.bytes().await?)?)
+        let is_ndjson = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with(NDJSON_MIME_TYPE))
+            .unwrap_or(false);
+        if !is_ndjson {
+            return Err(ClientError::Other(
+                "Server does not support streaming query results".to_owned(),
+            ));
+        }
+        Ok(ndjson_stream(
+            resp.bytes_stream().map(|r| r.map_err(|e| e.into())),
+        ))
+    }
+
+    /// Returns the ID of the newest version of the named bindle that matches the given SemVer
+    /// requirement (such as `^1.2`) and isn't yanked. An empty requirement matches any version, so
+    /// it resolves the latest version. Returns an
+    /// [`InvoiceNotFound`](ClientError::InvoiceNotFound) error if no version matches
+    pub async fn resolve_version(&self, name: &str, requirement: &str) -> Result<Id> {
+        let matches = self
+            .query_invoices(crate::QueryOptions {
+                query: Some(name.to_owned()),
+                version: Some(requirement.to_owned()),
+                strict: Some(true),
+                yanked: Some(false),
+                distinct: Some(true),
+                ..Default::default()
+            })
+            .await?;
+        // Not every search engine supports strict queries or filters out yanked bindles, so both
+        // are checked again here
+        matches
+            .invoices
+            .into_iter()
+            .filter(|inv| !inv.yanked.unwrap_or_default())
+            .map(|inv| inv.bindle.id)
+            .filter(|id| id.name() == name)
+            .max_by(|a, b| a.version().cmp(b.version()))
+            .ok_or(ClientError::InvoiceNotFound)
     }
 
     //////////////// Yank Invoice ////////////////
@@ -200,11 +576,92 @@ impl Client {
             INVOICE_ENDPOINT,
             parsed_id.to_string()
         ))?);
-        let resp = req.send().await?;
+        let resp = self.send(req, Operation::Invoice).await?;
         unwrap_status(resp, Endpoint::Invoice).await?;
         Ok(())
     }
 
+    //////////////// Sign Invoice ////////////////
+
+    /// Adds a signature to an invoice that is already stored on the server, such as an approver
+    /// countersigning it, without uploading the invoice again. The signature must have been made
+    /// over the stored invoice, so the server rejects it if the parcels differ. Returns the
+    /// invoice with all of its signatures
+    pub async fn add_signature<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let url = self.base_url.join(&format!(
+            "{}/{}/{}",
+            INVOICE_ENDPOINT, parsed_id, SIGNATURE_SUBRESOURCE
+        ))?;
+        let req = if self.json {
+            self.client
+                .post(url)
+                .header(header::CONTENT_TYPE, JSON_MIME_TYPE)
+                .body(serde_json::to_vec(&signature)?)
+        } else {
+            self.client
+                .post(url)
+                .header(header::CONTENT_TYPE, TOML_MIME_TYPE)
+                .body(toml::to_vec(&signature)?)
+        };
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        parse_response(resp).await
+    }
+
+    /// Fetches the invoice, signs it in the given role with the key and adds the signature to the
+    /// invoice on the server with [`add_signature`](Client::add_signature)
+    pub async fn countersign<I>(
+        &self,
+        id: I,
+        role: crate::signature::SignatureRole,
+        key: &crate::signature::SecretKeyEntry,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let mut inv = self.get_invoice(&parsed_id).await?;
+        inv.signature = None;
+        inv.sign_with_key(role, key)?;
+        let signature =
+            inv.signature.into_iter().flatten().next().ok_or_else(|| {
+                ClientError::Other("Signing did not produce a signature".to_owned())
+            })?;
+        self.add_signature(parsed_id, signature).await
+    }
+
+    //////////////// Delete Invoice ////////////////
+
+    /// Permanently deletes the invoice from the bindle server, along with any of its parcels that
+    /// no other invoice uses. Unlike yanking, this cannot be undone and requires admin access.
+    /// Returns the SHAs of the parcels the server removed
+    pub async fn delete_invoice<I>(&self, id: I) -> Result<crate::InvoiceDeleteResponse>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let mut url = self
+            .base_url
+            .join(&format!("{}/{}", INVOICE_ENDPOINT, parsed_id))?;
+        url.set_query(Some("purge=true"));
+        let resp = self
+            .send(self.client.delete(url), Operation::Invoice)
+            .await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        parse_response(resp).await
+    }
+
     //////////////// Create Parcel ////////////////
 
     /// Creates the given parcel using the SHA and the raw parcel data to upload to the server.
@@ -221,16 +678,49 @@ impl Client {
         I::Error: Into<ClientError>,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        let len = data.len() as u64;
         self.create_parcel_request(
             self.create_parcel_builder(&parsed_id, parcel_sha)
                 .body(data),
         )
-        .await
+        .await?;
+        self.report_bytes(parcel_sha, len);
+        Ok(())
+    }
+
+    /// Same as [`create_parcel`](Client::create_parcel), but the parcel is only created if the
+    /// invoice still matches the given entity tag (as returned by
+    /// [`get_invoice_with_etag`](Client::get_invoice_with_etag)). If the invoice was yanked or
+    /// replaced since it was read, a [`PreconditionFailed`](ClientError::PreconditionFailed) error
+    /// is returned instead
+    pub async fn create_parcel_if_match<I>(
+        &self,
+        bindle_id: I,
+        parcel_sha: &str,
+        data: Vec<u8>,
+        etag: &str,
+    ) -> Result<()>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        let len = data.len() as u64;
+        self.create_parcel_request(
+            self.create_parcel_builder(&parsed_id, parcel_sha)
+                .header(header::IF_MATCH, etag)
+                .body(data),
+        )
+        .await?;
+        self.report_bytes(parcel_sha, len);
+        Ok(())
     }
 
     /// Same as [`create_parcel`](Client::create_parcel), but takes a path to the parcel
     /// file. This will be more efficient for large files as it will stream the data into the body
-    /// rather than taking the intermediate step of loading the bytes into a `Vec`.
+    /// rather than taking the intermediate step of loading the bytes into a `Vec`. The data is
+    /// verified against the given SHA as it is uploaded, see
+    /// [`create_parcel_from_stream`](Client::create_parcel_from_stream) for more details
     pub async fn create_parcel_from_file<D, I>(
         &self,
         bindle_id: I,
@@ -244,24 +734,28 @@ impl Client {
     {
         // Copy the path to avoid lifetime issues
         let data = data_path.as_ref().to_owned();
-        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
         debug!("Loading parcel data from {}", data.display());
+        let length = tokio::fs::metadata(&data).await?.len();
         let stream = load::raw(data).await?;
         debug!("Successfully loaded parcel stream");
-        let data_body = Body::wrap_stream(stream);
-
-        self.create_parcel_request(
-            self.create_parcel_builder(&parsed_id, parcel_sha)
-                .body(data_body),
-        )
-        .await
+        self.create_parcel_from_stream(bindle_id, parcel_sha, Some(length), stream)
+            .await
     }
 
-    /// Same as [`create_parcel`](Client::create_parcel), but takes a stream of parcel data as bytes
+    /// Same as [`create_parcel`](Client::create_parcel), but takes a stream of parcel data as
+    /// bytes. The data is streamed directly to the server, so this can be used for parcels that are
+    /// too large to hold in memory.
+    ///
+    /// If the length of the data is known ahead of time, it should be passed as `length` so it can
+    /// be sent to the server as the `Content-Length`. The SHA-256 sum (and length, if given) of the
+    /// data is computed as it is uploaded and the upload is aborted if it doesn't match, returning
+    /// a [`DigestMismatch`](ClientError::DigestMismatch) or
+    /// [`SizeMismatch`](ClientError::SizeMismatch) error
     pub async fn create_parcel_from_stream<I, S, B>(
         &self,
         bindle_id: I,
         parcel_sha: &str,
+        length: Option<u64>,
         stream: S,
     ) -> Result<()>
     where
@@ -271,13 +765,21 @@ impl Client {
         B: bytes::Buf,
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
-        let map = stream.map(|res| res.map(|mut b| b.to_bytes()));
-        let data_body = Body::wrap_stream(map);
-        self.create_parcel_request(
-            self.create_parcel_builder(&parsed_id, parcel_sha)
-                .body(data_body),
-        )
-        .await
+        let stream = crate::async_util::VerifyingStream::new(stream, parcel_sha, length);
+        let failure = stream.failure();
+        let stream = ReportingStream::new(stream, parcel_sha, self.progress.clone());
+        let mut req = self
+            .create_parcel_builder(&parsed_id, parcel_sha)
+            .body(Body::wrap_stream(stream));
+        if let Some(len) = length {
+            req = req.header(header::CONTENT_LENGTH, len);
+        }
+        let res = self.create_parcel_request(req).await;
+        // A failed verification shows up as an opaque body error, so return the real reason
+        if let Some(e) = failure.lock().unwrap().take() {
+            return Err(e.into());
+        }
+        res
     }
 
     fn create_parcel_builder(&self, bindle_id: &Id, parcel_sha: &str) -> RequestBuilder {
@@ -294,7 +796,7 @@ impl Client {
 
     async fn create_parcel_request(&self, req: RequestBuilder) -> Result<()> {
         // We can unwrap here because any URL error would be programmers fault
-        let resp = req.send().await?;
+        let resp = self.send(req, Operation::Upload).await?;
         unwrap_status(resp, Endpoint::Parcel).await?;
         Ok(())
     }
@@ -309,7 +811,9 @@ impl Client {
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
         let resp = self.get_parcel_request(&parsed_id, sha).await?;
-        Ok(resp.bytes().await?.to_vec())
+        let data = resp.bytes().await?.to_vec();
+        self.report_bytes(sha, data.len() as u64);
+        Ok(data)
     }
 
     /// Returns the requested parcel (identified by its Bindle ID and SHA) as a stream of bytes.
@@ -326,21 +830,182 @@ impl Client {
     {
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
         let resp = self.get_parcel_request(&parsed_id, sha).await?;
-        Ok(resp.bytes_stream().map(|r| r.map_err(|e| e.into())))
+        Ok(ReportingStream::new(
+            resp.bytes_stream().map(|r| r.map_err(|e| e.into())),
+            sha,
+            self.progress.clone(),
+        ))
+    }
+
+    /// Returns the given range of bytes of the requested parcel (identified by its Bindle ID and
+    /// SHA). This is useful for resuming interrupted downloads or for reading only part of a large
+    /// parcel. Ranges that go past the end of the parcel are truncated, but a range that starts past
+    /// the end of the parcel will return an error. If the server doesn't support range requests,
+    /// the whole parcel is downloaded and the range is extracted locally
+    pub async fn get_parcel_range<I, R>(&self, bindle_id: I, sha: &str, range: R) -> Result<Vec<u8>>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+        R: RangeBounds<u64>,
+    {
+        let mut stream = self.get_parcel_range_stream(bindle_id, sha, range).await?;
+        let mut data = Vec::new();
+        while let Some(bytes) = stream.next().await {
+            data.extend_from_slice(&bytes?);
+        }
+        Ok(data)
+    }
+
+    /// Same as [`get_parcel_range`](Client::get_parcel_range), but returns the data as a stream of
+    /// bytes
+    pub async fn get_parcel_range_stream<I, R>(
+        &self,
+        bindle_id: I,
+        sha: &str,
+        range: R,
+    ) -> Result<impl Stream<Item = Result<bytes::Bytes>>>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+        R: RangeBounds<u64>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        let start = match range.start_bound() {
+            Bound::Included(s) => *s,
+            Bound::Excluded(s) => s + 1,
+            Bound::Unbounded => 0,
+        };
+        // The end is kept exclusive here, but is inclusive in the header
+        let end = match range.end_bound() {
+            Bound::Included(e) => Some(e + 1),
+            Bound::Excluded(e) => Some(*e),
+            Bound::Unbounded => None,
+        };
+        if matches!(end, Some(e) if e <= start) {
+            return Err(ClientError::Other(format!(
+                "Invalid parcel range {}..{}",
+                start,
+                end.unwrap_or_default()
+            )));
+        }
+
+        let mut req = self
+            .client
+            .get(
+                self.base_url
+                    .join(&format!("{}/{}@{}", INVOICE_ENDPOINT, parsed_id, sha))
+                    .unwrap(),
+            )
+            .header(header::ACCEPT, "*/*");
+        req = match end {
+            Some(e) => req.header(header::RANGE, format!("bytes={}-{}", start, e - 1)),
+            None => req.header(header::RANGE, format!("bytes={}-", start)),
+        };
+        let resp = self.send(req, Operation::Download).await?;
+        let stream: Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync> =
+            if resp.status() == StatusCode::PARTIAL_CONTENT {
+                Box::new(resp.bytes_stream().map(|r| r.map_err(|e| e.into())))
+            } else {
+                // The server ignored the range, so we have to skip to it ourselves
+                let resp = unwrap_status(resp, Endpoint::Parcel).await?;
+                Box::new(crate::async_util::slice_stream(
+                    resp.bytes_stream().map(|r| r.map_err(|e| e.into())),
+                    start,
+                    end.map(|e| e - start),
+                ))
+            };
+        Ok(ReportingStream::new(stream, sha, self.progress.clone()))
+    }
+
+    /// Uploads the manifest and chunks of the given [chunked parcel](crate::chunking::ChunkedParcel)
+    /// to the given bindle. The chunked parcel must have been [added](crate::chunking::ChunkedParcel::add_to)
+    /// to the invoice before it was created. Only the parcels the server reports as missing are
+    /// uploaded, so chunks that are already stored (e.g. as part of a previous version of the
+    /// bindle) are skipped. Returns the number of parcels that were uploaded
+    pub async fn create_chunked_parcel<I>(
+        &self,
+        bindle_id: I,
+        chunked: &crate::chunking::ChunkedParcel<'_>,
+    ) -> Result<usize>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        let missing: std::collections::HashSet<String> = self
+            .get_missing_parcels(&parsed_id)
+            .await?
+            .into_iter()
+            .map(|l| l.sha256)
+            .collect();
+        let mut uploaded = 0;
+        for (label, data) in chunked.chunks() {
+            if missing.contains(&label.sha256) {
+                self.create_parcel(&parsed_id, &label.sha256, data.to_vec())
+                    .await?;
+                uploaded += 1;
+            }
+        }
+        // The manifest goes last, so a manifest is never stored without its chunks
+        let manifest = chunked.manifest_label();
+        if missing.contains(&manifest.sha256) {
+            self.create_parcel(&parsed_id, &manifest.sha256, chunked.manifest_data())
+                .await?;
+            uploaded += 1;
+        }
+        Ok(uploaded)
+    }
+
+    /// Returns the [manifest](crate::chunking::ChunkManifest) stored in the given manifest parcel
+    pub async fn get_chunk_manifest<I>(
+        &self,
+        bindle_id: I,
+        manifest_sha: &str,
+    ) -> Result<crate::chunking::ChunkManifest>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        from_toml_slice(&self.get_parcel(bindle_id, manifest_sha).await?)
+    }
+
+    /// Reassembles the chunked parcel described by the given manifest parcel, returning the data
+    /// as a stream of bytes. Chunks are fetched one at a time as the stream is read, so the data
+    /// is never held in memory all at once. Each chunk is verified by the server when it is
+    /// uploaded, so the reassembled data matches the SHA in the manifest
+    pub async fn get_chunked_parcel_stream<I>(
+        &self,
+        bindle_id: I,
+        manifest_sha: &str,
+    ) -> Result<impl Stream<Item = Result<bytes::Bytes>>>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        let manifest = self.get_chunk_manifest(&parsed_id, manifest_sha).await?;
+        let client = self.clone();
+        let chunks =
+            futures::StreamExt::then(futures::stream::iter(manifest.chunk), move |chunk| {
+                let client = client.clone();
+                let id = parsed_id.clone();
+                async move { client.get_parcel_stream(id, &chunk.sha256).await }
+            });
+        // Pinned so callers can poll it directly, like the other parcel streams
+        Ok(Box::pin(futures::TryStreamExt::try_flatten(chunks)))
     }
 
     async fn get_parcel_request(&self, bindle_id: &Id, sha: &str) -> Result<reqwest::Response> {
         // Override the default accept header
-        let resp = self
+        let req = self
             .client
             .get(
                 self.base_url
                     .join(&format!("{}/{}@{}", INVOICE_ENDPOINT, bindle_id, sha))
                     .unwrap(),
             )
-            .header(header::ACCEPT, "*/*")
-            .send()
-            .await?;
+            .header(header::ACCEPT, "*/*");
+        let resp = self.send(req, Operation::Download).await?;
         unwrap_status(resp, Endpoint::Parcel).await
     }
 
@@ -360,9 +1025,287 @@ impl Client {
             "missing",
             parsed_id.to_string()
         ))?);
-        let resp = req.send().await?;
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        Ok(parse_response::<crate::MissingParcelsResponse>(resp)
+            .await?
+            .missing)
+    }
+
+    /// Returns which of the given parcel SHAs the server doesn't have, no matter which bindle they
+    /// were uploaded for. Parcels the server already has don't need to be uploaded again, even
+    /// for a different bindle
+    pub async fn get_missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        let body = crate::MissingShasRequest {
+            shas: shas.to_vec(),
+        };
+        let url = self
+            .base_url
+            .join(&format!("{}/{}", BLOB_ENDPOINT, "_missing"))?;
+        let req = if self.json {
+            self.client
+                .post(url)
+                .header(header::CONTENT_TYPE, JSON_MIME_TYPE)
+                .body(serde_json::to_vec(&body)?)
+        } else {
+            self.client
+                .post(url)
+                .header(header::CONTENT_TYPE, TOML_MIME_TYPE)
+                .body(toml::to_vec(&body)?)
+        };
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Query).await?;
+        Ok(parse_response::<crate::MissingShasResponse>(resp)
+            .await?
+            .missing)
+    }
+
+    /// Returns the invoices that contain the parcel with the given SHA, including yanked ones, as
+    /// found by the server's search index. A parcel that no invoice contains returns an empty list
+    pub async fn find_invoices_by_parcel(&self, sha: &str) -> Result<Vec<crate::ParcelInvoice>> {
+        let req = self.client.get(
+            self.base_url
+                .join(&format!("{}/{}/{}", BLOB_ENDPOINT, sha, "_invoices"))?,
+        );
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Query).await?;
+        Ok(parse_response::<crate::ParcelInvoicesResponse>(resp)
+            .await?
+            .invoices)
+    }
+
+    /// Returns the IDs of all bindles the specified bindle depends on, directly or indirectly, as
+    /// resolved by the server. If no version of a dependency satisfies all of the bindles requiring
+    /// it, an [`InvalidRequest`](ClientError::InvalidRequest) error describing the problem is
+    /// returned. If the bindle is yanked, this will fail
+    pub async fn resolve_dependencies<I>(&self, id: I) -> Result<Vec<Id>>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let req = self.client.get(self.base_url.join(&format!(
+            "{}/{}/{}",
+            RELATIONSHIP_ENDPOINT, "dependencies", parsed_id
+        ))?);
+        let resp = self.send(req, Operation::Invoice).await?;
         let resp = unwrap_status(resp, Endpoint::Invoice).await?;
-        Ok(toml::from_slice::<crate::MissingParcelsResponse>(&resp.bytes().await?)?.missing)
+        Ok(parse_response::<crate::DependenciesResponse>(resp)
+            .await?
+            .resolved)
+    }
+
+    /// Returns all versions of the bindle with the given name that the server knows about,
+    /// including yanked ones, in ascending version order. An unknown name returns a
+    /// [`InvoiceNotFound`](ClientError::InvoiceNotFound) error
+    pub async fn list_versions(&self, name: &str) -> Result<Vec<crate::VersionInfo>> {
+        let req = self.client.get(self.base_url.join(&format!(
+            "{}/{}/{}",
+            RELATIONSHIP_ENDPOINT, "versions", name
+        ))?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Invoice).await?;
+        Ok(parse_response::<crate::VersionsResponse>(resp)
+            .await?
+            .versions)
+    }
+
+    //////////////// Capabilities ////////////////
+
+    /// Returns the optional features supported by the server. Servers that don't advertise their
+    /// capabilities are assumed to only support the core protocol, which is what the
+    /// [default](crate::Capabilities::default) capabilities describe
+    pub async fn capabilities(&self) -> Result<crate::Capabilities> {
+        let req = self.client.get(self.base_url.join(CAPABILITIES_ENDPOINT)?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(crate::Capabilities::default());
+        }
+        let resp = unwrap_status(resp, Endpoint::Query).await?;
+        parse_response(resp).await
+    }
+
+    /// Checks that the server is reachable and implements a compatible version of the Bindle spec,
+    /// returning its capabilities. This is meant to be called before doing any real work, so that
+    /// an incompatible server is detected up front instead of failing obscurely on the first real
+    /// request.
+    ///
+    /// Servers with a different major spec version are rejected with an
+    /// [`IncompatibleServer`](ClientError::IncompatibleServer) error. A warning is logged for
+    /// servers that implement a newer minor version or don't advertise their spec version at all,
+    /// as they should still understand everything this client sends
+    pub async fn ping(&self) -> Result<crate::Capabilities> {
+        let capabilities = self.capabilities().await?;
+        match capabilities.spec_version.as_deref() {
+            Some(server) => check_spec_version(server)?,
+            None => warn!(
+                "Server at {} does not advertise its spec version, assuming it is compatible",
+                self.base_url
+            ),
+        }
+        Ok(capabilities)
+    }
+
+    //////////////// Standalone Bindles ////////////////
+
+    /// Pushes the standalone bindle in the given directory (the one containing its invoice file) to
+    /// the server, handling invoices and parcels that already exist. See
+    /// [`StandaloneRead::push_with_options`](crate::standalone::StandaloneRead::push_with_options)
+    /// for how the push works and the errors it returns
+    pub async fn push_standalone<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<crate::standalone::PushReport> {
+        self.push_standalone_with_options(path, crate::standalone::PushOptions::default())
+            .await
+    }
+
+    /// Same as [`push_standalone`](Client::push_standalone), but with the given options, such as a
+    /// callback for the progress of the push
+    pub async fn push_standalone_with_options<P: AsRef<Path>>(
+        &self,
+        path: P,
+        options: crate::standalone::PushOptions,
+    ) -> Result<crate::standalone::PushReport> {
+        crate::standalone::StandaloneRead::from_dir(path)
+            .await?
+            .push_with_options(self, options)
+            .await
+    }
+
+    /// Exports the bindle with the given ID and all of its parcels from the server as a standalone
+    /// bindle in the given directory, returning the directory the bindle was written to. That
+    /// directory can be passed to [`push_standalone`](Client::push_standalone) to push the bindle
+    /// to another server
+    pub async fn export_standalone<I, P>(&self, id: I, path: P) -> Result<std::path::PathBuf>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+        P: AsRef<Path>,
+    {
+        self.export_standalone_with_options(id, path, crate::standalone::ExportOptions::default())
+            .await
+    }
+
+    /// Same as [`export_standalone`](Client::export_standalone), but with the given options, such
+    /// as a callback for the progress of the export
+    pub async fn export_standalone_with_options<I, P>(
+        &self,
+        id: I,
+        path: P,
+        options: crate::standalone::ExportOptions,
+    ) -> Result<std::path::PathBuf>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+        P: AsRef<Path>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        crate::standalone::export(self, parsed_id, path.as_ref(), options).await
+    }
+
+    /// Fetches the bindle with the given ID from the server and writes its parcels to files named
+    /// after their labels in the given directory. See the [`unpack`](crate::standalone::unpack)
+    /// module for how names and collisions are handled
+    pub async fn unpack<I, P>(
+        &self,
+        id: I,
+        dest: P,
+    ) -> Result<crate::standalone::unpack::UnpackReport>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+        P: AsRef<Path>,
+    {
+        self.unpack_with_options(id, dest, &Default::default())
+            .await
+    }
+
+    /// Same as [`unpack`](Client::unpack), but with the given options, such as the groups and
+    /// features to select parcels with
+    pub async fn unpack_with_options<I, P>(
+        &self,
+        id: I,
+        dest: P,
+        options: &crate::standalone::unpack::UnpackOptions,
+    ) -> Result<crate::standalone::unpack::UnpackReport>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+        P: AsRef<Path>,
+    {
+        let inv = self.get_invoice(id).await?;
+        let proxy = crate::proxy::Proxy::new(self.clone());
+        crate::standalone::unpack::unpack(&proxy, &inv, dest, options).await
+    }
+
+    //////////////// Garbage Collection ////////////////
+
+    /// Asks the server to remove all parcels that are no longer referenced by any invoice and
+    /// returns what was removed. If `dry_run` is set, nothing is removed, but the report still lists
+    /// the parcels that would have been. This requires the admin role for all bindles
+    pub async fn collect_garbage(&self, dry_run: bool) -> Result<crate::provider::gc::GcReport> {
+        let mut url = self.base_url.join(GC_ENDPOINT)?;
+        if dry_run {
+            url.set_query(Some("dryRun=true"));
+        }
+        let resp = self.send(self.client.post(url), Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Query).await?;
+        parse_response(resp).await
+    }
+
+    //////////////// Replication ////////////////
+
+    /// Returns the status of the replication of the server from its primary server. This requires
+    /// the admin role for all bindles
+    pub async fn get_replication_status(&self) -> Result<crate::replication::ReplicationStatus> {
+        let req = self.client.get(self.base_url.join(REPLICATION_ENDPOINT)?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Query).await?;
+        parse_response(resp).await
+    }
+
+    //////////////// Quotas ////////////////
+
+    /// Returns the storage used on the server by every user and by the server as a whole, along
+    /// with their quotas. This requires the admin role for all bindles
+    pub async fn get_quotas(&self) -> Result<crate::QuotaReport> {
+        let req = self.client.get(self.base_url.join(QUOTA_ENDPOINT)?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Query).await?;
+        parse_response(resp).await
+    }
+
+    //////////////// Keyrings ////////////////
+
+    /// Encrypts the keyring with the passphrase and stores it on the server for the authenticated
+    /// user, replacing any keyring stored before. The server never sees the passphrase or the
+    /// contents of the keyring
+    pub async fn push_keyring(&self, keyring: &KeyRing, passphrase: &str) -> Result<()> {
+        let encrypted = keyring.encrypt(passphrase)?;
+        let req = self
+            .client
+            .put(self.base_url.join(KEYRING_ENDPOINT)?)
+            .header(header::CONTENT_TYPE, "application/toml")
+            .body(toml::to_vec(&encrypted)?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        if resp.status() != StatusCode::NO_CONTENT {
+            unwrap_status(resp, Endpoint::Query).await?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the keyring stored on the server for the authenticated user and decrypts it with the
+    /// passphrase. Returns `None` if no keyring has been stored
+    pub async fn pull_keyring(&self, passphrase: &str) -> Result<Option<KeyRing>> {
+        let req = self.client.get(self.base_url.join(KEYRING_ENDPOINT)?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = unwrap_status(resp, Endpoint::Query).await?;
+        let encrypted: EncryptedKeyRing = parse_response(resp).await?;
+        Ok(Some(encrypted.decrypt(passphrase)?))
     }
 }
 
@@ -372,6 +1315,9 @@ enum Endpoint {
     Invoice,
     Parcel,
     Query,
+    // A not found or conflict for an upload is about the upload itself rather than a parcel, so
+    // those are returned as invalid requests
+    Upload,
 }
 
 async fn unwrap_status(resp: reqwest::Response, endpoint: Endpoint) -> Result<reqwest::Response> {
@@ -386,14 +1332,31 @@ async fn unwrap_status(resp: reqwest::Response, endpoint: Endpoint) -> Result<re
         (StatusCode::CONFLICT, Endpoint::Invoice) => Err(ClientError::InvoiceAlreadyExists),
         (StatusCode::CONFLICT, Endpoint::Parcel) => Err(ClientError::ParcelAlreadyExists),
         (StatusCode::UNAUTHORIZED, _) => Err(ClientError::Unauthorized),
+        (StatusCode::PRECONDITION_FAILED, _) => Err(ClientError::PreconditionFailed),
         // You can't range match on u16 so we use a guard
         (_, _) if resp.status().is_server_error() => {
             Err(ClientError::ServerError(parse_error_from_body(resp).await))
         }
-        (_, _) if resp.status().is_client_error() => Err(ClientError::InvalidRequest {
-            status_code: resp.status(),
-            message: parse_error_from_body(resp).await,
-        }),
+        (_, _) if resp.status().is_client_error() => {
+            let status_code = resp.status();
+            match parse_error_response(resp).await {
+                Some(crate::ErrorResponse {
+                    verification: Some(failure),
+                    ..
+                }) => Err(ClientError::VerificationFailed(failure)),
+                Some(crate::ErrorResponse {
+                    signing_policy: Some(result),
+                    ..
+                }) => Err(ClientError::SigningPolicyNotSatisfied(result)),
+                Some(crate::ErrorResponse {
+                    quota: Some(quota), ..
+                }) => Err(ClientError::QuotaExceeded(quota)),
+                e => Err(ClientError::InvalidRequest {
+                    status_code,
+                    message: e.map(|e| e.error),
+                }),
+            }
+        }
         _ => Err(ClientError::Other(format!(
             "Unknown error: {}",
             parse_error_from_body(resp).await.unwrap_or_default()
@@ -401,14 +1364,146 @@ async fn unwrap_status(resp: reqwest::Response, endpoint: Endpoint) -> Result<re
     }
 }
 
+/// Deserializes the body of a response from JSON or TOML, depending on its `Content-Type`. Servers
+/// that don't speak JSON reply with TOML even if JSON was asked for
+async fn parse_response<T: DeserializeOwned>(resp: reqwest::Response) -> Result<T> {
+    let json = is_json(&resp);
+    let raw = resp.bytes().await?;
+    if json {
+        Ok(serde_json::from_slice(&raw)?)
+    } else {
+        from_toml_slice(&raw)
+    }
+}
+
+fn is_json(resp: &reqwest::Response) -> bool {
+    resp.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start().starts_with(JSON_MIME_TYPE))
+        .unwrap_or(false)
+}
+
 async fn parse_error_from_body(resp: reqwest::Response) -> Option<String> {
-    let bytes = match resp.bytes().await {
-        Ok(b) => b,
-        Err(_) => return None,
+    parse_error_response(resp).await.map(|e| e.error)
+}
+
+async fn parse_error_response(resp: reqwest::Response) -> Option<crate::ErrorResponse> {
+    parse_response(resp).await.ok()
+}
+
+/// Returns an error if the given spec version of a server is incompatible with the one supported
+/// by this client, which is the case if the major versions differ
+fn check_spec_version(server: &str) -> Result<()> {
+    let incompatible = || ClientError::IncompatibleServer {
+        server: server.to_owned(),
+        client: crate::BINDLE_VERSION_1.to_owned(),
     };
+    let server_version = semver::Version::parse(server).map_err(|_| incompatible())?;
+    // This is a constant, so it can't fail to parse
+    let client_version = semver::Version::parse(crate::BINDLE_VERSION_1).unwrap();
+    if server_version.major != client_version.major {
+        return Err(incompatible());
+    }
+    if server_version > client_version {
+        warn!(
+            "Server implements version {} of the Bindle spec, which is newer than version {} supported by this client",
+            server_version, client_version
+        );
+    }
+    Ok(())
+}
+
+/// Parses a stream of newline delimited JSON documents. The stream ends after the first error
+fn ndjson_stream<S, T>(body: S) -> impl Stream<Item = Result<T>> + Unpin
+where
+    S: Stream<Item = Result<bytes::Bytes>> + Unpin,
+    T: DeserializeOwned,
+{
+    Box::pin(futures::stream::unfold(
+        (body, Vec::new(), false),
+        |(mut body, mut buf, mut done)| async move {
+            loop {
+                let line: Vec<u8> = match buf.iter().position(|b| *b == b'\n') {
+                    Some(end) => buf.drain(..=end).collect(),
+                    None if done => std::mem::take(&mut buf),
+                    None => {
+                        match body.next().await {
+                            Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                            Some(Err(e)) => return Some((Err(e), (body, Vec::new(), true))),
+                            None => done = true,
+                        }
+                        continue;
+                    }
+                };
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    if done && buf.is_empty() {
+                        return None;
+                    }
+                    continue;
+                }
+                return match serde_json::from_slice(&line) {
+                    Ok(item) => Some((Ok(item), (body, buf, done))),
+                    Err(e) => Some((Err(e.into()), (body, Vec::new(), true))),
+                };
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_spec_version() {
+        for compatible in &["1.0.0", "1.0.1", "1.3.0"] {
+            assert!(
+                check_spec_version(compatible).is_ok(),
+                "{} should be compatible",
+                compatible
+            );
+        }
+        for incompatible in &["0.9.0", "2.0.0", "not-a-version"] {
+            match check_spec_version(incompatible) {
+                Err(ClientError::IncompatibleServer { server, client }) => {
+                    assert_eq!(*incompatible, server);
+                    assert_eq!(crate::BINDLE_VERSION_1, client);
+                }
+                r => panic!("{} should be incompatible, got {:?}", incompatible, r),
+            }
+        }
+    }
+    #[tokio::test]
+    async fn test_ndjson_stream() {
+        let chunks: Vec<Result<bytes::Bytes>> = vec![
+            Ok(bytes::Bytes::from_static(b"{\"a\": 1}\n{\"a\"")),
+            Ok(bytes::Bytes::from_static(b": 2}\n\n")),
+            Ok(bytes::Bytes::from_static(b"{\"a\": 3}")),
+        ];
+        let items: Vec<serde_json::Value> = ndjson_stream(tokio::stream::iter(chunks))
+            .collect::<Result<_>>()
+            .await
+            .expect("parse stream");
+        assert_eq!(
+            vec![1, 2, 3],
+            items
+                .iter()
+                .map(|v| v["a"].as_i64().unwrap())
+                .collect::<Vec<_>>()
+        );
 
-    match toml::from_slice::<crate::ErrorResponse>(&bytes) {
-        Ok(e) => Some(e.error),
-        Err(_) => None,
+        // Invalid documents and transport errors end the stream
+        let chunks: Vec<Result<bytes::Bytes>> = vec![
+            Ok(bytes::Bytes::from_static(
+                b"{\"a\": 1}\nnot json\n{\"a\": 2}\n",
+            )),
+            Err(ClientError::Other("connection reset".to_owned())),
+        ];
+        let items: Vec<Result<serde_json::Value>> =
+            ndjson_stream(tokio::stream::iter(chunks)).collect().await;
+        assert_eq!(2, items.len());
+        assert!(items[0].is_ok());
+        assert!(matches!(items[1], Err(ClientError::InvalidJson(_))));
     }
 }
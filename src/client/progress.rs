@@ -0,0 +1,197 @@
+//! Reporting the progress of parcel transfers, for rendering progress bars and the like.
+//!
+//! A [`ProgressReporter`](ProgressReporter) can be given to a [`Client`](super::Client) (see
+//! [`Client::with_progress_reporter`](super::Client::with_progress_reporter)), which reports the
+//! bytes of every parcel it uploads or downloads into it. Operations transferring many parcels,
+//! such as pushing a [standalone bindle](crate::standalone) or fetching a bindle with a
+//! [`Downloader`](super::downloader::Downloader), also report when each parcel is done. As a
+//! shortcut, any `Fn(&Progress)` closure is a reporter that only receives the latter
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::stream::Stream;
+
+/// Receives the progress of parcel transfers. All methods do nothing by default, so only the ones
+/// that are needed have to be implemented. They are called from the tasks transferring the
+/// parcels, so they should return quickly
+pub trait ProgressReporter: Send + Sync {
+    /// Called each time data of the parcel with the given SHA is sent or received, with the number
+    /// of bytes in that chunk of data. A transfer that is retried reports its bytes again
+    fn bytes_transferred(&self, _sha: &str, _bytes: u64) {}
+
+    /// Called when a parcel of a transfer of multiple parcels is done, successfully or not
+    fn parcel_done(&self, _progress: &Progress) {}
+}
+
+impl<F> ProgressReporter for F
+where
+    F: Fn(&Progress) + Send + Sync,
+{
+    fn parcel_done(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// A shared [`ProgressReporter`](ProgressReporter), as taken by the options of the operations that
+/// report progress
+pub type ProgressCallback = Arc<dyn ProgressReporter>;
+
+/// The progress of transferring multiple parcels, as passed to
+/// [`ProgressReporter::parcel_done`](ProgressReporter::parcel_done) each time a parcel is done
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// The SHA of the parcel that is done
+    pub sha: String,
+    /// What happened to the parcel
+    pub status: ParcelStatus,
+    /// The number of bytes transferred for the parcel. This is 0 if the parcel didn't need to be
+    /// transferred or failed
+    pub bytes: u64,
+    /// The number of parcels done so far, including this one
+    pub done: usize,
+    /// The number of parcels in the bindle
+    pub total: usize,
+}
+
+/// What happened to a parcel that is done
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParcelStatus {
+    /// The parcel was transferred
+    Transferred,
+    /// The parcel was already present at the destination, so it wasn't transferred
+    Skipped,
+    /// The parcel doesn't exist at the source
+    Missing,
+    /// The parcel could not be transferred. Contains the message of the last error
+    Failed(String),
+}
+
+/// Counts the parcels that are done and reports them to the reporter, if any
+pub(crate) struct ProgressTracker {
+    done: AtomicUsize,
+    total: usize,
+    reporter: Option<ProgressCallback>,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(total: usize, reporter: Option<ProgressCallback>) -> Self {
+        ProgressTracker {
+            done: AtomicUsize::new(0),
+            total,
+            reporter,
+        }
+    }
+
+    pub(crate) fn parcel_done(&self, sha: &str, status: ParcelStatus, bytes: u64) {
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(reporter) = &self.reporter {
+            reporter.parcel_done(&Progress {
+                sha: sha.to_owned(),
+                status,
+                bytes,
+                done,
+                total: self.total,
+            });
+        }
+    }
+}
+
+/// A stream of parcel data that reports the size of every chunk that goes through it
+pub(crate) struct ReportingStream<S> {
+    inner: S,
+    sha: String,
+    reporter: Option<ProgressCallback>,
+}
+
+impl<S> ReportingStream<S> {
+    pub(crate) fn new(inner: S, sha: &str, reporter: Option<ProgressCallback>) -> Self {
+        ReportingStream {
+            inner,
+            sha: sha.to_owned(),
+            reporter,
+        }
+    }
+}
+
+impl<S, B, E> Stream for ReportingStream<S>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: bytes::Buf,
+{
+    type Item = Result<B, E>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let res = std::pin::Pin::new(&mut self.inner).poll_next(cx);
+        if let (Poll::Ready(Some(Ok(chunk))), Some(reporter)) = (&res, &self.reporter) {
+            reporter.bytes_transferred(&self.sha, chunk.remaining() as u64);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use tokio::stream::StreamExt;
+
+    #[derive(Default)]
+    struct Recorder {
+        bytes: Mutex<Vec<(String, u64)>>,
+        done: Mutex<Vec<Progress>>,
+    }
+
+    impl ProgressReporter for Recorder {
+        fn bytes_transferred(&self, sha: &str, bytes: u64) {
+            self.bytes.lock().unwrap().push((sha.to_owned(), bytes));
+        }
+
+        fn parcel_done(&self, progress: &Progress) {
+            self.done.lock().unwrap().push(progress.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_progress_reporting() {
+        let recorder = Arc::new(Recorder::default());
+        let chunks: Vec<Result<bytes::Bytes, std::io::Error>> = vec![
+            Ok(bytes::Bytes::from_static(b"abc")),
+            Ok(bytes::Bytes::from_static(b"de")),
+        ];
+        let stream = ReportingStream::new(
+            tokio::stream::iter(chunks),
+            "abc123",
+            Some(recorder.clone()),
+        );
+        assert_eq!(2, stream.collect::<Vec<_>>().await.len());
+        assert_eq!(
+            vec![("abc123".to_owned(), 3), ("abc123".to_owned(), 2)],
+            *recorder.bytes.lock().unwrap()
+        );
+
+        let tracker = ProgressTracker::new(2, Some(recorder.clone()));
+        tracker.parcel_done("abc123", ParcelStatus::Transferred, 5);
+        tracker.parcel_done("def456", ParcelStatus::Missing, 0);
+        let done = recorder.done.lock().unwrap();
+        assert_eq!(vec![1, 2], done.iter().map(|p| p.done).collect::<Vec<_>>());
+        assert_eq!(ParcelStatus::Missing, done[1].status);
+        assert!(done.iter().all(|p| p.total == 2));
+
+        // Closures only receive the parcels that are done
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let reporter: ProgressCallback = Arc::new(move |_: &Progress| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        reporter.bytes_transferred("abc123", 3);
+        reporter.parcel_done(&done[0]);
+        assert_eq!(1, count.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,121 @@
+//! Timeouts for the different kinds of requests a [`Client`](super::Client) makes
+
+use std::time::Duration;
+
+/// The timeouts for the different kinds of requests a client makes. Transferring the data of a
+/// large parcel can take minutes, while fetching an invoice should only take seconds, so each kind
+/// gets its own timeout. A timeout covers the whole request, from connecting to the server until
+/// the whole response body was read. By default, there are no timeouts.
+///
+/// Timeouts are configured for all requests of a client with
+/// [`ClientBuilder::timeouts`](super::ClientBuilder::timeouts) and can be overridden for single
+/// calls with [`Client::with_timeouts`](super::Client::with_timeouts):
+///
+/// ```no_run
+/// # async fn example(client: bindle::client::Client) -> bindle::client::Result<()> {
+/// use std::time::Duration;
+/// use bindle::client::Timeouts;
+///
+/// let data = client
+///     .clone()
+///     .with_timeouts(Timeouts::default().download(Duration::from_secs(3600)))
+///     .get_parcel("enterprise.com/warpcore/1.0.0", "23f310b54076878fd4c36f0c60ec92011a8b406349b98dd37d08577d17397de5")
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    /// The timeout for requests that don't transfer parcel data, like creating, fetching and
+    /// querying invoices or starting resumable uploads
+    pub invoice: Option<Duration>,
+    /// The timeout for uploading the data of a parcel or one chunk of a resumable upload
+    pub upload: Option<Duration>,
+    /// The timeout for downloading the data of a parcel, including reading the whole stream
+    pub download: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Uses the same timeout for all kinds of requests
+    pub fn all(timeout: Duration) -> Self {
+        Timeouts {
+            invoice: Some(timeout),
+            upload: Some(timeout),
+            download: Some(timeout),
+        }
+    }
+
+    /// Sets the timeout for requests that don't transfer parcel data
+    pub fn invoice(mut self, timeout: Duration) -> Self {
+        self.invoice = Some(timeout);
+        self
+    }
+
+    /// Sets the timeout for parcel uploads
+    pub fn upload(mut self, timeout: Duration) -> Self {
+        self.upload = Some(timeout);
+        self
+    }
+
+    /// Sets the timeout for parcel downloads
+    pub fn download(mut self, timeout: Duration) -> Self {
+        self.download = Some(timeout);
+        self
+    }
+
+    pub(crate) fn get(&self, operation: Operation) -> Option<Duration> {
+        match operation {
+            Operation::Invoice => self.invoice,
+            Operation::Upload => self.upload,
+            Operation::Download => self.download,
+        }
+    }
+}
+
+/// The kind of a request, which decides the timeout it gets
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Operation {
+    Invoice,
+    Upload,
+    Download,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{Client, ClientError};
+
+    fn is_timeout<T>(res: crate::client::Result<T>) -> bool {
+        matches!(res, Err(ClientError::HttpClientError(e)) if e.is_timeout())
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        // A server that accepts connections, but never responds
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+
+        let client = Client::builder()
+            .timeouts(Timeouts::default().download(Duration::from_millis(100)))
+            .build(&url)
+            .unwrap();
+        let id = "enterprise.com/warpcore/1.0.0";
+        assert!(is_timeout(client.get_parcel(id, "abc").await));
+
+        // Overriding the timeouts only applies to the returned client
+        let res = client
+            .clone()
+            .with_timeouts(Timeouts::all(Duration::from_millis(100)))
+            .get_invoice(id)
+            .await;
+        assert!(is_timeout(res));
+        let res = tokio::time::timeout(Duration::from_millis(300), client.get_invoice(id)).await;
+        assert!(res.is_err(), "invoice requests should not have a timeout");
+    }
+}
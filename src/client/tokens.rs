@@ -0,0 +1,540 @@
+//! Types for obtaining, caching and refreshing the access tokens used to authenticate with a
+//! Bindle server.
+//!
+//! Tokens are obtained from an OpenID Connect identity provider using the [OAuth 2.0 Device
+//! Authorization Grant](https://tools.ietf.org/html/rfc8628) (also known as the "device flow"),
+//! which works well for command line tools as the user logs in using a browser on any device.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, trace};
+use reqwest::Client as HttpClient;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use super::{ClientError, Result};
+
+const DISCOVERY_PATH: &str = ".well-known/openid-configuration";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const REFRESH_TOKEN_GRANT: &str = "refresh_token";
+/// Tokens are refreshed this long before they actually expire to account for clock skew and
+/// request latency
+const EXPIRY_LEEWAY_SECS: u64 = 30;
+/// The polling interval to use if the identity provider doesn't specify one, as defined in the RFC
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 5;
+
+/// An access token along with all of the information needed to refresh it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Token {
+    pub access_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// The time the access token expires, in seconds since the UNIX epoch. If not set, the token
+    /// is assumed to never expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// The token endpoint of the identity provider, used for refreshing the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_endpoint: Option<String>,
+    /// The client ID the token was issued to, used for refreshing the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+}
+
+impl Token {
+    /// Returns a token that is never refreshed, for use with static bearer tokens
+    pub fn from_static(access_token: impl Into<String>) -> Self {
+        Token {
+            access_token: access_token.into(),
+            refresh_token: None,
+            expires_at: None,
+            token_endpoint: None,
+            client_id: None,
+        }
+    }
+
+    /// Returns true if the access token is expired or about to expire
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => now() + EXPIRY_LEEWAY_SECS >= expires_at,
+            None => false,
+        }
+    }
+
+    fn from_response(resp: TokenResponse, token_endpoint: &str, client_id: &str) -> Self {
+        Token {
+            access_token: resp.access_token,
+            refresh_token: resp.refresh_token,
+            expires_at: resp.expires_in.map(|secs| now() + secs),
+            token_endpoint: Some(token_endpoint.to_owned()),
+            client_id: Some(client_id.to_owned()),
+        }
+    }
+}
+
+/// A cache containing the current token, optionally persisted to a file. The token is
+/// automatically refreshed (and the file updated) when it expires. Clones of the cache share the
+/// same token
+#[derive(Clone)]
+pub struct TokenCache {
+    path: Option<PathBuf>,
+    token: Arc<Mutex<Option<Token>>>,
+    http: HttpClient,
+}
+
+impl TokenCache {
+    /// Loads the token cache stored at the given path. If the file does not exist, the cache is
+    /// empty until a token is stored
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let token = match tokio::fs::read(&path).await {
+            Ok(raw) => Some(toml::from_slice(&raw)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(TokenCache {
+            path: Some(path),
+            token: Arc::new(Mutex::new(token)),
+            http: HttpClient::new(),
+        })
+    }
+
+    /// Returns a cache that only keeps the given token in memory
+    pub fn in_memory(token: Token) -> Self {
+        TokenCache {
+            path: None,
+            token: Arc::new(Mutex::new(Some(token))),
+            http: HttpClient::new(),
+        }
+    }
+
+    /// Replaces the cached token, persisting it if the cache is backed by a file
+    pub async fn store(&self, token: Token) -> Result<()> {
+        let mut current = self.token.lock().await;
+        self.persist(&token).await?;
+        *current = Some(token);
+        Ok(())
+    }
+
+    /// Returns the current access token, if there is one, refreshing it first if it has expired
+    pub async fn access_token(&self) -> Result<Option<String>> {
+        let mut current = self.token.lock().await;
+        let token = match current.as_ref() {
+            None => return Ok(None),
+            Some(t) if !t.is_expired() => return Ok(Some(t.access_token.clone())),
+            Some(t) => t,
+        };
+        let (refresh_token, token_endpoint, client_id) = match (
+            &token.refresh_token,
+            &token.token_endpoint,
+            &token.client_id,
+        ) {
+            (Some(r), Some(e), Some(c)) => (r, e, c),
+            _ => {
+                return Err(ClientError::TokenError(
+                    "Access token has expired and cannot be refreshed, please log in again"
+                        .to_string(),
+                ))
+            }
+        };
+        debug!("Access token has expired, refreshing");
+        let resp = self
+            .http
+            .post(token_endpoint)
+            .form(&[
+                ("grant_type", REFRESH_TOKEN_GRANT),
+                ("refresh_token", refresh_token),
+                ("client_id", client_id),
+            ])
+            .send()
+            .await?;
+        let mut refreshed = match parse_token_response(resp).await? {
+            Ok(r) => Token::from_response(r, token_endpoint, client_id),
+            Err(e) => {
+                return Err(ClientError::TokenError(format!(
+                    "Unable to refresh access token, please log in again: {}",
+                    e
+                )))
+            }
+        };
+        // Identity providers aren't required to rotate refresh tokens, so keep the old one
+        if refreshed.refresh_token.is_none() {
+            refreshed.refresh_token = token.refresh_token.clone();
+        }
+        self.persist(&refreshed).await?;
+        let access_token = refreshed.access_token.clone();
+        *current = Some(refreshed);
+        Ok(Some(access_token))
+    }
+
+    async fn persist(&self, token: &Token) -> Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        trace!("Writing token cache to {}", path.display());
+        tokio::fs::write(path, toml::to_vec(token)?).await?;
+        // The cache contains credentials, so make sure only the current user can read it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The information the user needs to complete a device flow login
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceCode {
+    pub device_code: String,
+    /// The code the user should enter at the verification URI
+    pub user_code: String,
+    /// The URI the user should visit to log in
+    pub verification_uri: String,
+    /// A URI containing the user code, so the user doesn't have to enter it
+    #[serde(default)]
+    pub verification_uri_complete: Option<String>,
+    /// The number of seconds until the device code expires
+    pub expires_in: u64,
+    #[serde(default)]
+    pub interval: Option<u64>,
+}
+
+/// A client for logging in to an OpenID Connect identity provider using the device flow
+#[derive(Clone)]
+pub struct DeviceFlow {
+    http: HttpClient,
+    client_id: String,
+    scopes: Vec<String>,
+    device_authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+impl DeviceFlow {
+    /// Discovers the endpoints of the identity provider with the given issuer URL and returns a
+    /// new device flow for the given client ID. The `openid` and `offline_access` (for obtaining
+    /// refresh tokens) scopes are requested along with any additional scopes
+    pub async fn discover(issuer_url: &str, client_id: &str, scopes: &[String]) -> Result<Self> {
+        let mut issuer = issuer_url.to_owned();
+        if !issuer.ends_with('/') {
+            issuer.push('/');
+        }
+        let discovery_url = url::Url::parse(&issuer)?.join(DISCOVERY_PATH)?;
+        let http = HttpClient::new();
+        debug!("Fetching OpenID configuration from {}", discovery_url);
+        let resp = http.get(discovery_url).send().await?;
+        if !resp.status().is_success() {
+            return Err(ClientError::TokenError(format!(
+                "Unable to fetch OpenID configuration (status code {})",
+                resp.status()
+            )));
+        }
+        let config: OpenIdConfiguration = serde_json::from_slice(&resp.bytes().await?)
+            .map_err(|e| ClientError::TokenError(format!("Invalid OpenID configuration: {}", e)))?;
+        let device_authorization_endpoint =
+            config.device_authorization_endpoint.ok_or_else(|| {
+                ClientError::TokenError(
+                    "Identity provider does not support the device authorization flow".to_string(),
+                )
+            })?;
+
+        let mut all_scopes = vec!["openid".to_owned(), "offline_access".to_owned()];
+        for scope in scopes {
+            if !all_scopes.contains(scope) {
+                all_scopes.push(scope.clone());
+            }
+        }
+        Ok(DeviceFlow {
+            http,
+            client_id: client_id.to_owned(),
+            scopes: all_scopes,
+            device_authorization_endpoint,
+            token_endpoint: config.token_endpoint,
+        })
+    }
+
+    /// Starts the login by requesting a device code. The returned code should be shown to the user
+    /// before calling [`wait_for_token`](DeviceFlow::wait_for_token)
+    pub async fn start(&self) -> Result<DeviceCode> {
+        let resp = self
+            .http
+            .post(&self.device_authorization_endpoint)
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("scope", self.scopes.join(" ").as_str()),
+            ])
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(ClientError::TokenError(format!(
+                "Unable to start device authorization: {}",
+                parse_oauth_error(&resp.bytes().await?)
+            )));
+        }
+        serde_json::from_slice(&resp.bytes().await?)
+            .map_err(|e| ClientError::TokenError(format!("Invalid device code response: {}", e)))
+    }
+
+    /// Polls the identity provider until the user has completed the login, returning the issued
+    /// token. Returns an error if the user denies access or the device code expires
+    pub async fn wait_for_token(&self, code: &DeviceCode) -> Result<Token> {
+        let mut interval = code.interval.unwrap_or(DEFAULT_POLL_INTERVAL_SECS);
+        let deadline = now() + code.expires_in;
+        loop {
+            tokio::time::delay_for(Duration::from_secs(interval)).await;
+            if now() > deadline {
+                return Err(ClientError::TokenError(
+                    "Device code expired before the login was completed".to_string(),
+                ));
+            }
+            let resp = self
+                .http
+                .post(&self.token_endpoint)
+                .form(&[
+                    ("grant_type", DEVICE_CODE_GRANT),
+                    ("device_code", code.device_code.as_str()),
+                    ("client_id", self.client_id.as_str()),
+                ])
+                .send()
+                .await?;
+            match parse_token_response(resp).await? {
+                Ok(r) => {
+                    info!("Successfully obtained access token");
+                    return Ok(Token::from_response(
+                        r,
+                        &self.token_endpoint,
+                        &self.client_id,
+                    ));
+                }
+                Err(e) if e == "authorization_pending" => {
+                    trace!("Login not completed yet, polling again in {}s", interval);
+                }
+                // As specified in the RFC, the interval must be increased by 5 seconds
+                Err(e) if e == "slow_down" => interval += 5,
+                Err(e) => {
+                    return Err(ClientError::TokenError(format!("Login failed: {}", e)));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenIdConfiguration {
+    token_endpoint: String,
+    #[serde(default)]
+    device_authorization_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OAuthError {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Parses a response from a token endpoint. The outer result contains any request errors, while
+/// the inner result contains either the token or the OAuth error code
+async fn parse_token_response(
+    resp: reqwest::Response,
+) -> Result<std::result::Result<TokenResponse, String>> {
+    let success = resp.status().is_success();
+    let body = resp.bytes().await?;
+    if !success {
+        return Ok(Err(parse_oauth_error(&body)));
+    }
+    serde_json::from_slice(&body)
+        .map(Ok)
+        .map_err(|e| ClientError::TokenError(format!("Invalid token response: {}", e)))
+}
+
+fn parse_oauth_error(body: &[u8]) -> String {
+    match serde_json::from_slice::<OAuthError>(body) {
+        Ok(OAuthError {
+            error,
+            error_description: None,
+        }) => error,
+        // Only the error code is used for matching, so the description is only added to errors
+        // that end the flow
+        Ok(OAuthError {
+            error,
+            error_description: Some(d),
+        }) if error != "authorization_pending" && error != "slow_down" => {
+            format!("{} ({})", error, d)
+        }
+        Ok(OAuthError { error, .. }) => error,
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use warp::Filter;
+
+    /// Starts a fake identity provider that makes the client poll once before issuing a token that
+    /// is already expired
+    fn fake_idp() -> String {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let (addr, server) = warp::serve(
+            warp::path!(".well-known" / "openid-configuration")
+                .and(warp::host::optional())
+                .map(|host: Option<warp::host::Authority>| {
+                    let base = format!("http://{}", host.unwrap());
+                    warp::reply::json(&serde_json::json!({
+                        "token_endpoint": format!("{}/token", base),
+                        "device_authorization_endpoint": format!("{}/device", base),
+                    }))
+                })
+                .or(warp::path("device").and(warp::post()).map(|| {
+                    warp::reply::json(&serde_json::json!({
+                        "device_code": "device-code",
+                        "user_code": "ABCD-EFGH",
+                        "verification_uri": "http://example.com/activate",
+                        "expires_in": 60,
+                        "interval": 0,
+                    }))
+                }))
+                .or(warp::path("token")
+                    .and(warp::post())
+                    .and(warp::body::form())
+                    .map(move |form: std::collections::HashMap<String, String>| {
+                        let body = match form.get("grant_type").map(|s| s.as_str()) {
+                            Some(DEVICE_CODE_GRANT)
+                                if polls.fetch_add(1, Ordering::SeqCst) == 0 =>
+                            {
+                                return warp::reply::with_status(
+                                    warp::reply::json(
+                                        &serde_json::json!({"error": "authorization_pending"}),
+                                    ),
+                                    warp::http::StatusCode::BAD_REQUEST,
+                                )
+                            }
+                            Some(DEVICE_CODE_GRANT) => serde_json::json!({
+                                "access_token": "first",
+                                "refresh_token": "refresh",
+                                "expires_in": 0,
+                            }),
+                            _ => serde_json::json!({
+                                "access_token": "refreshed",
+                                "expires_in": 3600,
+                            }),
+                        };
+                        warp::reply::with_status(
+                            warp::reply::json(&body),
+                            warp::http::StatusCode::OK,
+                        )
+                    })),
+        )
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_device_flow() {
+        let issuer = fake_idp();
+        let flow = DeviceFlow::discover(&issuer, "bindle", &["bindle:write".to_owned()])
+            .await
+            .expect("discovery should succeed");
+        assert_eq!(
+            vec!["openid", "offline_access", "bindle:write"],
+            flow.scopes
+        );
+
+        let code = flow.start().await.expect("should get a device code");
+        assert_eq!("ABCD-EFGH", code.user_code);
+        let token = flow
+            .wait_for_token(&code)
+            .await
+            .expect("should get a token");
+        assert_eq!("first", token.access_token);
+        assert!(token.is_expired());
+
+        // Storing the token should persist it, and fetching it should refresh it since it expired
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("token.toml");
+        let cache = TokenCache::load(&path).await.unwrap();
+        assert!(cache.access_token().await.unwrap().is_none());
+        cache.store(token).await.unwrap();
+        assert_eq!(
+            Some("refreshed".to_owned()),
+            cache.access_token().await.unwrap()
+        );
+
+        let reloaded = TokenCache::load(&path).await.unwrap();
+        let stored = reloaded.token.lock().await.clone().unwrap();
+        assert_eq!("refreshed", stored.access_token);
+        assert_eq!(Some("refresh".to_owned()), stored.refresh_token);
+        assert!(!stored.is_expired());
+    }
+
+    #[tokio::test]
+    async fn test_client_sends_bearer_token() {
+        let (addr, server) = warp::serve(
+            warp::path!("v1" / "whoami")
+                .and(warp::header::optional::<String>("authorization"))
+                .map(|auth: Option<String>| auth.unwrap_or_default()),
+        )
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let base = format!("http://{}/v1/", addr);
+        let client = crate::client::Client::new(&base).unwrap();
+        let resp = client
+            .raw(reqwest::Method::GET, "whoami", None::<String>)
+            .await
+            .unwrap();
+        assert_eq!("", resp.text().await.unwrap());
+
+        let client = client.with_token_cache(TokenCache::in_memory(Token::from_static("foo")));
+        let resp = client
+            .raw(reqwest::Method::GET, "whoami", None::<String>)
+            .await
+            .unwrap();
+        assert_eq!("Bearer foo", resp.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_expired_static_token() {
+        let mut token = Token::from_static("foo");
+        assert_eq!(
+            Some("foo".to_owned()),
+            TokenCache::in_memory(token.clone())
+                .access_token()
+                .await
+                .unwrap()
+        );
+        token.expires_at = Some(now() - 1);
+        assert!(matches!(
+            TokenCache::in_memory(token).access_token().await,
+            Err(ClientError::TokenError(_))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+//! Delta updates of a locally downloaded bindle from one version to another
+
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::stream::StreamExt;
+
+use super::{timeouts::Operation, Client, ClientError, Result, RELATIONSHIP_ENDPOINT};
+use crate::standalone::{INVOICE_FILE, PARCEL_DIR};
+use crate::{Id, Label, ParcelDelta};
+
+/// The outcome of a [`Client::update`](Client::update)
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    /// Parcels that were downloaded, either because they are new or because the local copy was
+    /// missing or corrupted
+    pub downloaded: Vec<Label>,
+    /// Parcels that were already present and valid locally
+    pub reused: Vec<Label>,
+    /// Parcels of the old version that were removed because the new version doesn't contain them
+    pub removed: Vec<Label>,
+}
+
+impl UpdateReport {
+    /// Returns the total number of parcel bytes that were downloaded
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.downloaded.iter().map(|l| l.size).sum()
+    }
+}
+
+impl Client {
+    /// Returns the difference between the parcels of the `from` and `to` bindles, as computed by
+    /// the server. The `from` bindle may be yanked, but the `to` bindle may not
+    pub async fn get_parcel_delta<I1, I2>(&self, from_id: I1, to_id: I2) -> Result<ParcelDelta>
+    where
+        I1: TryInto<Id>,
+        I1::Error: Into<ClientError>,
+        I2: TryInto<Id>,
+        I2::Error: Into<ClientError>,
+    {
+        let from = from_id.try_into().map_err(|e| e.into())?;
+        let to = to_id.try_into().map_err(|e| e.into())?;
+        let mut url = self
+            .base_url
+            .join(&format!("{}/delta/{}", RELATIONSHIP_ENDPOINT, to))?;
+        url.query_pairs_mut().append_pair("from", &from.to_string());
+        let resp = self.send(self.client.get(url), Operation::Invoice).await?;
+        let resp = super::unwrap_status(resp, super::Endpoint::Invoice).await?;
+        super::parse_response(resp).await
+    }
+
+    /// Updates a bindle downloaded to the `dest` directory from the `from` version to the `to`
+    /// version, only fetching the parcels that changed. The directory uses the same layout as a
+    /// [standalone bindle](crate::standalone): the invoice is stored in `invoice.toml` and each
+    /// parcel is stored in the `parcels` directory as `{sha}.dat`.
+    ///
+    /// Parcels that the two versions share are verified against their SHA and downloaded again if
+    /// the local copy is missing or corrupted, so this can also be used to repair a download.
+    /// Parcels that are no longer part of the bindle are removed. The invoice is written last, so it
+    /// always reflects a completely updated directory
+    pub async fn update<I1, I2, P>(&self, from_id: I1, to_id: I2, dest: P) -> Result<UpdateReport>
+    where
+        I1: TryInto<Id>,
+        I1::Error: Into<ClientError>,
+        I2: TryInto<Id>,
+        I2::Error: Into<ClientError>,
+        P: AsRef<Path>,
+    {
+        let from: Id = from_id.try_into().map_err(|e| e.into())?;
+        let to: Id = to_id.try_into().map_err(|e| e.into())?;
+        let dest = dest.as_ref();
+        info!(
+            "Updating bindle in {} from {} to {}",
+            dest.display(),
+            from,
+            to
+        );
+
+        let delta = self.get_parcel_delta(&from, &to).await?;
+        let invoice = self.get_invoice(&to).await?;
+        let parcel_dir = dest.join(PARCEL_DIR);
+        tokio::fs::create_dir_all(&parcel_dir).await?;
+
+        let mut report = UpdateReport::default();
+        let mut to_fetch = Vec::new();
+        for label in delta.unchanged {
+            if is_valid(&parcel_path(&parcel_dir, &label.sha256), &label.sha256).await? {
+                report.reused.push(label);
+            } else {
+                warn!(
+                    "Local copy of parcel {} is missing or invalid, downloading it again",
+                    label.sha256
+                );
+                to_fetch.push(label);
+            }
+        }
+        to_fetch.extend(delta.added);
+
+        for label in to_fetch {
+            debug!("Downloading parcel {}", label.sha256);
+            self.download_parcel(&to, &label.sha256, &parcel_path(&parcel_dir, &label.sha256))
+                .await?;
+            report.downloaded.push(label);
+        }
+
+        for label in delta.removed {
+            match tokio::fs::remove_file(parcel_path(&parcel_dir, &label.sha256)).await {
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+            report.removed.push(label);
+        }
+
+        tokio::fs::write(dest.join(INVOICE_FILE), toml::to_vec(&invoice)?).await?;
+        info!(
+            "Updated bindle to {}, downloaded {} parcels ({} bytes) and reused {}",
+            to,
+            report.downloaded.len(),
+            report.bytes_downloaded(),
+            report.reused.len()
+        );
+        Ok(report)
+    }
+
+    /// Downloads a parcel to the given path, verifying its SHA. The data is written to a temporary
+    /// file first, so an interrupted or invalid download never replaces the file at the path
+    pub(super) async fn download_parcel(
+        &self,
+        bindle_id: &Id,
+        sha: &str,
+        path: &Path,
+    ) -> Result<()> {
+        let mut stream = self.get_parcel_stream(bindle_id, sha).await?;
+        let part = path.with_extension("part");
+        let mut file = tokio::fs::File::create(&part).await?;
+        let mut hasher = Sha256::new();
+        while let Some(bytes) = stream.next().await {
+            let bytes = bytes?;
+            hasher.update(&bytes);
+            file.write_all(&bytes).await?;
+        }
+        file.flush().await?;
+        drop(file);
+
+        let actual = format!("{:x}", hasher.finalize());
+        if actual != sha {
+            tokio::fs::remove_file(&part).await?;
+            return Err(ClientError::DigestMismatch {
+                expected: sha.to_owned(),
+                actual,
+            });
+        }
+        tokio::fs::rename(&part, path).await?;
+        Ok(())
+    }
+}
+
+pub(super) fn parcel_path(parcel_dir: &Path, sha: &str) -> PathBuf {
+    parcel_dir.join(format!("{}.dat", sha))
+}
+
+/// Returns whether the file at the given path exists and has the given SHA
+pub(super) async fn is_valid(path: &Path, sha: &str) -> Result<bool> {
+    match super::downloader::file_sha256(path).await {
+        Ok(actual) => Ok(actual == sha),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
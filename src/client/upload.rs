@@ -0,0 +1,192 @@
+//! Resumable uploads for large parcels
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use log::{debug, warn};
+use reqwest::{header, RequestBuilder};
+use tokio::io::AsyncReadExt;
+
+use super::{timeouts::Operation, unwrap_status, ClientError, Endpoint, Result, UPLOAD_ENDPOINT};
+use crate::{Id, UploadStatus};
+
+const UPLOAD_OFFSET_HEADER: &str = "Upload-Offset";
+/// The default size of the chunks sent by
+/// [`upload_parcel_file`](super::Client::upload_parcel_file)
+pub const DEFAULT_UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+// How many times in a row a chunk can fail before giving up
+const MAX_CHUNK_ATTEMPTS: usize = 3;
+
+impl super::Client {
+    /// Starts a resumable upload of the given parcel, returning the status of the new upload. The
+    /// ID of the upload can be used to send the parcel's data in chunks with
+    /// [`upload_parcel_chunk`](super::Client::upload_parcel_chunk) (or
+    /// [`upload_parcel_file`](super::Client::upload_parcel_file)) and to resume the upload if it is
+    /// interrupted. The parcel is created once all of its data has been sent
+    pub async fn start_parcel_upload<I>(
+        &self,
+        bindle_id: I,
+        parcel_sha: &str,
+    ) -> Result<UploadStatus>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.start_upload_request(self.start_upload_builder(&parsed_id, parcel_sha)?)
+            .await
+    }
+
+    /// Same as [`start_parcel_upload`](super::Client::start_parcel_upload), but the upload only
+    /// starts if the invoice still matches the given entity tag (as returned by
+    /// [`get_invoice_with_etag`](super::Client::get_invoice_with_etag)). The server checks the tag
+    /// again once all of the data has been sent, so a
+    /// [`PreconditionFailed`](ClientError::PreconditionFailed) error can be returned by the last
+    /// chunk if the invoice was yanked or replaced during the upload
+    pub async fn start_parcel_upload_if_match<I>(
+        &self,
+        bindle_id: I,
+        parcel_sha: &str,
+        etag: &str,
+    ) -> Result<UploadStatus>
+    where
+        I: TryInto<Id>,
+        I::Error: Into<ClientError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.start_upload_request(
+            self.start_upload_builder(&parsed_id, parcel_sha)?
+                .header(header::IF_MATCH, etag),
+        )
+        .await
+    }
+
+    fn start_upload_builder(&self, bindle_id: &Id, parcel_sha: &str) -> Result<RequestBuilder> {
+        Ok(self.client.post(
+            self.base_url
+                .join(&format!("{}/{}@{}", UPLOAD_ENDPOINT, bindle_id, parcel_sha))?,
+        ))
+    }
+
+    async fn start_upload_request(&self, req: RequestBuilder) -> Result<UploadStatus> {
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_start_status(resp).await?;
+        super::parse_response(resp).await
+    }
+
+    /// Returns the current status of the given upload. This is used to find out where to resume an
+    /// interrupted upload
+    pub async fn get_upload_status(&self, upload_id: &str) -> Result<UploadStatus> {
+        let req = self.client.get(self.upload_url(upload_id)?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        let resp = unwrap_status(resp, Endpoint::Upload).await?;
+        super::parse_response(resp).await
+    }
+
+    /// Sends a chunk of data for the given upload, which must start at the current offset of the
+    /// upload. Returns the new status of the upload. Once the last chunk is sent, the server
+    /// verifies the data and creates the parcel
+    pub async fn upload_parcel_chunk(
+        &self,
+        upload_id: &str,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<UploadStatus> {
+        let len = data.len() as u64;
+        let req = self
+            .client
+            .patch(self.upload_url(upload_id)?)
+            .header(UPLOAD_OFFSET_HEADER, offset)
+            .header(header::CONTENT_LENGTH, data.len())
+            .body(data);
+        let resp = self.send(req, Operation::Upload).await?;
+        let resp = unwrap_status(resp, Endpoint::Upload).await?;
+        let status: UploadStatus = super::parse_response(resp).await?;
+        self.report_bytes(&status.sha256, len);
+        Ok(status)
+    }
+
+    /// Cancels the given upload, discarding any data sent so far
+    pub async fn cancel_parcel_upload(&self, upload_id: &str) -> Result<()> {
+        let req = self.client.delete(self.upload_url(upload_id)?);
+        let resp = self.send(req, Operation::Invoice).await?;
+        unwrap_status(resp, Endpoint::Upload).await?;
+        Ok(())
+    }
+
+    /// Sends the parcel data in the given file for an upload in chunks of the given size, starting
+    /// wherever the upload currently is. This means it can be called again with the same upload ID
+    /// to resume an upload that was interrupted. Chunks that fail are retried a few times before
+    /// giving up. Returns the final status of the upload
+    pub async fn upload_parcel_file<P: AsRef<Path>>(
+        &self,
+        upload_id: &str,
+        data_path: P,
+        chunk_size: usize,
+    ) -> Result<UploadStatus> {
+        let mut status = self.get_upload_status(upload_id).await?;
+        let mut file = tokio::fs::File::open(data_path.as_ref()).await?;
+        let file_len = file.metadata().await?.len();
+        if file_len != status.size {
+            return Err(ClientError::SizeMismatch {
+                expected: status.size,
+                actual: file_len,
+            });
+        }
+        let mut failures = 0;
+        while !status.is_complete() {
+            file.seek(std::io::SeekFrom::Start(status.offset)).await?;
+            let len = std::cmp::min(chunk_size.max(1) as u64, status.size - status.offset);
+            let mut chunk = vec![0; len as usize];
+            file.read_exact(&mut chunk).await?;
+            debug!(
+                "Sending {} bytes of upload {} at offset {}",
+                len, upload_id, status.offset
+            );
+            match self
+                .upload_parcel_chunk(upload_id, status.offset, chunk)
+                .await
+            {
+                Ok(s) => {
+                    status = s;
+                    failures = 0;
+                }
+                // Rejected data (like a digest mismatch) won't get better by trying again
+                Err(e @ ClientError::InvalidRequest { .. }) if !is_conflict(&e) => return Err(e),
+                // Neither will an invoice that changed, and the server has dropped the upload
+                Err(e @ ClientError::PreconditionFailed) => return Err(e),
+                Err(e) => {
+                    failures += 1;
+                    if failures >= MAX_CHUNK_ATTEMPTS {
+                        return Err(e);
+                    }
+                    // Part of the chunk may have made it, so check where to pick back up
+                    warn!(
+                        "Error sending chunk of upload {}, resuming: {}",
+                        upload_id, e
+                    );
+                    status = self.get_upload_status(upload_id).await?;
+                }
+            }
+        }
+        Ok(status)
+    }
+
+    fn upload_url(&self, upload_id: &str) -> Result<url::Url> {
+        Ok(self
+            .base_url
+            .join(&format!("{}/{}", UPLOAD_ENDPOINT, upload_id))?)
+    }
+}
+
+fn is_conflict(e: &ClientError) -> bool {
+    matches!(e, ClientError::InvalidRequest { status_code, .. } if *status_code == reqwest::StatusCode::CONFLICT)
+}
+
+// Starting an upload can fail for the same reasons as creating a parcel, so it uses the same errors
+async fn unwrap_start_status(resp: reqwest::Response) -> Result<reqwest::Response> {
+    match resp.status() {
+        reqwest::StatusCode::CREATED => Ok(resp),
+        _ => unwrap_status(resp, Endpoint::Parcel).await,
+    }
+}
@@ -0,0 +1,246 @@
+//! Tools for composing new bindles out of existing ones.
+//!
+//! A composed bindle (sometimes called a "meta-bindle") references all of the parcels of its
+//! input bindles. Because parcels are referenced by their SHA, no parcel data has to be copied or
+//! uploaded again. To keep the groups of the inputs from clashing, every group is namespaced with
+//! the ID of the bindle it came from (e.g. the `server` group of `app/1.0.0` becomes
+//! `app/1.0.0:server`). Each parcel also gets an [`ORIGIN_ANNOTATION`](ORIGIN_ANNOTATION) with the
+//! ID of the bindle it came from, so the origin of every parcel can be traced.
+//!
+//! ```
+//! let app: bindle::Invoice = toml::from_str(r#"
+//!     bindleVersion = "1.0.0"
+//!     [bindle]
+//!     name = "app"
+//!     version = "1.0.0"
+//!     [[group]]
+//!     name = "server"
+//!     [[parcel]]
+//!     [parcel.label]
+//!     name = "server.wasm"
+//!     sha256 = "abc123"
+//!     mediaType = "application/wasm"
+//!     size = 123
+//!     [parcel.conditions]
+//!     memberOf = ["server"]
+//! "#).unwrap();
+//!
+//! let composed = bindle::compose::compose("meta/1.0.0".parse().unwrap(), &[app]).unwrap();
+//! let group = &composed.group.unwrap()[0];
+//! assert_eq!("app/1.0.0:server", group.name);
+//! ```
+
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+use crate::{Condition, Group, Id, Invoice, Parcel, BINDLE_VERSION_1};
+
+/// The label annotation containing the ID of the bindle a parcel came from
+pub const ORIGIN_ANNOTATION: &str = "bindle.origin";
+
+/// Describes the errors that can occur when composing bindles
+#[derive(Error, Debug)]
+pub enum ComposeError {
+    /// No input bindles were given
+    #[error("At least one bindle is needed to compose a new bindle")]
+    NoInputs,
+    /// The same bindle was given more than once
+    #[error("Bindle {0} was given more than once")]
+    DuplicateInput(Id),
+}
+
+/// Returns the namespaced name of the given group from the bindle with the given ID
+pub fn namespaced_group(origin: &Id, group: &str) -> String {
+    format!("{}:{}", origin, group)
+}
+
+/// Composes a new bindle with the given ID out of the given input bindles. The returned invoice
+/// contains all parcels and (namespaced) groups of the inputs, but none of their annotations.
+/// Parcels that are members of the global group in an input stay in the global group
+pub fn compose(id: Id, inputs: &[Invoice]) -> Result<Invoice, ComposeError> {
+    if inputs.is_empty() {
+        return Err(ComposeError::NoInputs);
+    }
+    let mut seen = HashSet::new();
+    let mut parcels = Vec::new();
+    let mut groups = Vec::new();
+    for input in inputs {
+        let origin = &input.bindle.id;
+        if !seen.insert(origin.to_string()) {
+            return Err(ComposeError::DuplicateInput(origin.clone()));
+        }
+        let namespace = |names: &Vec<String>| {
+            names
+                .iter()
+                .map(|n| namespaced_group(origin, n))
+                .collect::<Vec<_>>()
+        };
+
+        groups.extend(input.group.iter().flatten().map(|g| Group {
+            name: namespaced_group(origin, &g.name),
+            ..g.clone()
+        }));
+        parcels.extend(input.parcel.iter().flatten().map(|p| {
+            let mut label = p.label.clone();
+            label
+                .annotations
+                .get_or_insert_with(Default::default)
+                .insert(ORIGIN_ANNOTATION.to_owned(), origin.to_string());
+            Parcel {
+                label,
+                conditions: p.conditions.as_ref().map(|c| Condition {
+                    member_of: c.member_of.as_ref().map(namespace),
+                    requires: c.requires.as_ref().map(namespace),
+                }),
+            }
+        }));
+    }
+
+    Ok(Invoice {
+        bindle_version: BINDLE_VERSION_1.to_owned(),
+        yanked: None,
+        bindle: crate::BindleSpec {
+            id,
+            description: None,
+            authors: None,
+        },
+        annotations: None,
+        requires: None,
+        parcel: if parcels.is_empty() {
+            None
+        } else {
+            Some(parcels)
+        },
+        group: if groups.is_empty() {
+            None
+        } else {
+            Some(groups)
+        },
+        signature: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::filters::BindleFilter;
+
+    fn invoice(raw: &str) -> Invoice {
+        toml::from_str(raw).expect("invoice should parse")
+    }
+
+    #[test]
+    fn test_compose() {
+        let app = invoice(
+            r#"
+            bindleVersion = "1.0.0"
+            [bindle]
+            name = "app"
+            version = "1.0.0"
+
+            [[group]]
+            name = "server"
+            required = true
+
+            [[group]]
+            name = "extras"
+
+            [[parcel]]
+            [parcel.label]
+            name = "readme.md"
+            sha256 = "aaa"
+            mediaType = "text/markdown"
+            size = 1
+
+            [[parcel]]
+            [parcel.label]
+            name = "server.wasm"
+            sha256 = "bbb"
+            mediaType = "application/wasm"
+            size = 2
+            [parcel.conditions]
+            memberOf = ["server"]
+            requires = ["extras"]
+
+            [[parcel]]
+            [parcel.label]
+            name = "extra.wasm"
+            sha256 = "ccc"
+            mediaType = "application/wasm"
+            size = 3
+            [parcel.conditions]
+            memberOf = ["extras"]
+            "#,
+        );
+        let libs = invoice(
+            r#"
+            bindleVersion = "1.0.0"
+            [bindle]
+            name = "libs"
+            version = "2.1.0"
+
+            [[group]]
+            name = "server"
+
+            [[parcel]]
+            [parcel.label]
+            name = "lib.wasm"
+            sha256 = "ddd"
+            mediaType = "application/wasm"
+            size = 4
+            [parcel.conditions]
+            memberOf = ["server"]
+            "#,
+        );
+
+        let id: Id = "meta/1.0.0".parse().unwrap();
+        let composed = compose(id.clone(), &[app.clone(), libs]).expect("should compose");
+        assert_eq!("meta/1.0.0", composed.bindle.id.to_string());
+
+        let groups: Vec<String> = composed
+            .group
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|g| g.name.clone())
+            .collect();
+        assert_eq!(
+            vec!["app/1.0.0:server", "app/1.0.0:extras", "libs/2.1.0:server"],
+            groups
+        );
+
+        let parcels = composed.parcel.as_ref().unwrap();
+        assert_eq!(4, parcels.len());
+        assert!(
+            parcels[0].conditions.is_none(),
+            "Global parcels should stay global"
+        );
+        let conditions = parcels[1].conditions.as_ref().unwrap();
+        assert_eq!(
+            Some(vec!["app/1.0.0:server".to_owned()]),
+            conditions.member_of
+        );
+        assert_eq!(
+            Some(vec!["app/1.0.0:extras".to_owned()]),
+            conditions.requires
+        );
+        assert_eq!(
+            "libs/2.1.0",
+            parcels[3].label.annotations.as_ref().unwrap()[ORIGIN_ANNOTATION]
+        );
+
+        // The same parcels should be selected as when filtering the inputs on their own
+        let filtered = BindleFilter::new(composed.clone()).filter();
+        let mut shas: Vec<&str> = filtered.iter().map(|p| p.label.sha256.as_str()).collect();
+        shas.sort_unstable();
+        assert_eq!(vec!["aaa", "bbb", "ccc"], shas);
+
+        assert!(matches!(
+            compose(id.clone(), &[app.clone(), app]),
+            Err(ComposeError::DuplicateInput(_))
+        ));
+        assert!(matches!(compose(id, &[]), Err(ComposeError::NoInputs)));
+    }
+}
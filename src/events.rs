@@ -0,0 +1,220 @@
+//! The events describing changes to the bindles stored by a server.
+//!
+//! Every integration that reacts to changes uses these types: [`HookedProvider`] emits them to its
+//! hooks, the [`HttpHook`] posts them as JSON, a replica reads them from the primary, and the
+//! [`history_action`](Event::history_action) of an event is what is recorded in the audit history
+//! of an invoice. Clients receiving events can deserialize them with the same types. An event is
+//! serialized as a JSON object with the name of the event in the `event` field, followed by the
+//! fields of the event in camelCase:
+//!
+//! ```json
+//! {"event": "parcel_created", "bindleId": "example.com/foo/1.0.0", "parcel": "<sha256>"}
+//! ```
+//!
+//! New fields and events may be added, but existing ones are never renamed or removed
+//!
+//! [`HookedProvider`]: crate::provider::hooks::HookedProvider
+//! [`HttpHook`]: crate::provider::hooks::HttpHook
+
+use serde::{Deserialize, Serialize};
+
+use crate::signature::SignatureRole;
+use crate::{HistoryAction, Id};
+
+/// A change to a stored bindle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A new invoice was created
+    InvoiceCreated(InvoiceCreated),
+    /// A parcel was uploaded. Its name predates the event types, so it is kept for existing
+    /// receivers
+    #[serde(rename = "parcel_created")]
+    ParcelUploaded(ParcelUploaded),
+    /// An invoice was yanked
+    InvoiceYanked(InvoiceYanked),
+    /// An invoice was permanently deleted, along with any of its parcels no other invoice uses
+    InvoiceDeleted(InvoiceDeleted),
+    /// An invoice was signed with a new key
+    KeyRotated(KeyRotated),
+    /// A signature was added to an existing invoice, such as an approver countersigning it
+    InvoiceSigned(InvoiceSigned),
+}
+
+/// The body of an [`Event::InvoiceCreated`](Event::InvoiceCreated)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvoiceCreated {
+    #[serde(with = "id_string")]
+    pub bindle_id: Id,
+}
+
+/// The body of an [`Event::ParcelUploaded`](Event::ParcelUploaded)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParcelUploaded {
+    /// The bindle the parcel was uploaded for
+    #[serde(with = "id_string")]
+    pub bindle_id: Id,
+    /// The SHA-256 of the parcel
+    pub parcel: String,
+}
+
+/// The body of an [`Event::InvoiceYanked`](Event::InvoiceYanked)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvoiceYanked {
+    #[serde(with = "id_string")]
+    pub bindle_id: Id,
+}
+
+/// The body of an [`Event::InvoiceDeleted`](Event::InvoiceDeleted)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvoiceDeleted {
+    #[serde(with = "id_string")]
+    pub bindle_id: Id,
+}
+
+/// The body of an [`Event::KeyRotated`](Event::KeyRotated)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRotated {
+    /// The bindle whose invoice was re-signed
+    #[serde(with = "id_string")]
+    pub bindle_id: Id,
+    /// The role the invoice was signed in
+    pub role: SignatureRole,
+    /// The base64 encoded public key of the new signature
+    pub key: String,
+}
+
+/// The body of an [`Event::InvoiceSigned`](Event::InvoiceSigned)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvoiceSigned {
+    /// The bindle whose invoice was signed
+    #[serde(with = "id_string")]
+    pub bindle_id: Id,
+    /// The role of the added signature
+    pub role: SignatureRole,
+    /// The base64 encoded public key of the added signature
+    pub key: String,
+}
+
+impl Event {
+    /// Returns the name of the event, as found in its `event` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::InvoiceCreated(_) => "invoice_created",
+            Event::ParcelUploaded(_) => "parcel_created",
+            Event::InvoiceYanked(_) => "invoice_yanked",
+            Event::InvoiceDeleted(_) => "invoice_deleted",
+            Event::KeyRotated(_) => "key_rotated",
+            Event::InvoiceSigned(_) => "invoice_signed",
+        }
+    }
+
+    /// Returns the ID of the bindle the event is about
+    pub fn bindle_id(&self) -> &Id {
+        match self {
+            Event::InvoiceCreated(e) => &e.bindle_id,
+            Event::ParcelUploaded(e) => &e.bindle_id,
+            Event::InvoiceYanked(e) => &e.bindle_id,
+            Event::InvoiceDeleted(e) => &e.bindle_id,
+            Event::KeyRotated(e) => &e.bindle_id,
+            Event::InvoiceSigned(e) => &e.bindle_id,
+        }
+    }
+
+    /// Returns the action recorded in the [`InvoiceHistory`](crate::InvoiceHistory) for the event,
+    /// if it is one that changes the state of an invoice that still exists
+    pub fn history_action(&self) -> Option<HistoryAction> {
+        match self {
+            Event::InvoiceCreated(_) => Some(HistoryAction::Create),
+            Event::InvoiceYanked(_) => Some(HistoryAction::Yank),
+            Event::KeyRotated(_) => Some(HistoryAction::Resign),
+            Event::InvoiceSigned(_) => Some(HistoryAction::Sign),
+            Event::ParcelUploaded(_) | Event::InvoiceDeleted(_) => None,
+        }
+    }
+}
+
+macro_rules! impl_from_body {
+    ($($body:ident),*) => {
+        $(
+            impl From<$body> for Event {
+                fn from(body: $body) -> Self {
+                    Event::$body(body)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_body!(
+    InvoiceCreated,
+    ParcelUploaded,
+    InvoiceYanked,
+    InvoiceDeleted,
+    KeyRotated,
+    InvoiceSigned
+);
+
+/// Bindle IDs are written as strings in events, as they are everywhere outside of invoices
+mod id_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use crate::Id;
+
+    pub fn serialize<S: Serializer>(id: &Id, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(id)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Id, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_schema() {
+        let id: Id = "example.com/foo/1.0.0".parse().unwrap();
+        let event = Event::from(ParcelUploaded {
+            bindle_id: id.clone(),
+            parcel: "abc123".to_owned(),
+        });
+        let json = serde_json::to_value(&event).expect("Event should serialize");
+        assert_eq!(
+            serde_json::json!({
+                "event": "parcel_created",
+                "bindleId": "example.com/foo/1.0.0",
+                "parcel": "abc123",
+            }),
+            json
+        );
+        assert_eq!(event.name(), json["event"]);
+
+        let rotated: Event = serde_json::from_str(
+            r#"{"event": "key_rotated", "bindleId": "example.com/foo/1.0.0", "role": "host", "key": "abc="}"#,
+        )
+        .expect("Event should deserialize");
+        assert!(matches!(
+            &rotated,
+            Event::KeyRotated(KeyRotated { role: SignatureRole::Host, key, .. }) if key == "abc="
+        ));
+        assert_eq!(id.to_string(), rotated.bindle_id().to_string());
+        assert_eq!(Some(HistoryAction::Resign), rotated.history_action());
+
+        assert!(
+            serde_json::from_str::<Event>(r#"{"event": "invoice_created", "bindleId": "nope"}"#)
+                .is_err(),
+            "Invalid bindle IDs should be rejected"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Typed access to the features of parcels.
+//!
+//! The features of a label are a [`FeatureMap`](crate::FeatureMap): a group (such as `wasm`)
+//! containing names with string values. Instead of looking them up by hand, use the accessors on
+//! [`Label`](crate::Label) and [`Parcel`](crate::Parcel), and build new maps with a
+//! [`FeatureBuilder`](FeatureBuilder). Boolean features use the strings `true`/`t` and
+//! `false`/`f`. The well-known features of WebAssembly modules are in the [`wasm`](wasm) module:
+//!
+//! ```
+//! use bindle::features::{wasm, FeatureBuilder};
+//!
+//! let mut label = bindle::Label::new("lib.wasm".to_owned(), "abc".to_owned());
+//! label.feature = Some(FeatureBuilder::new().flag(wasm::GROUP, wasm::LIBRARY, true).build());
+//! label.set_feature("custom", "animal", "narwhal");
+//!
+//! assert!(wasm::is_library(&label));
+//! assert_eq!(Some("narwhal"), label.feature_value("custom", "animal"));
+//! ```
+
+pub mod wasm;
+
+use crate::{FeatureMap, Label, Parcel};
+
+/// Builds a [`FeatureMap`](crate::FeatureMap) one feature at a time
+#[derive(Debug, Clone, Default)]
+pub struct FeatureBuilder {
+    features: FeatureMap,
+}
+
+impl FeatureBuilder {
+    /// Returns a builder without any features
+    pub fn new() -> Self {
+        FeatureBuilder::default()
+    }
+
+    /// Sets the feature with the given name in the group, replacing any previous value
+    pub fn feature(mut self, group: &str, name: &str, value: impl Into<String>) -> Self {
+        insert(&mut self.features, group, name, value.into());
+        self
+    }
+
+    /// Sets a boolean feature to `true` or `false`
+    pub fn flag(self, group: &str, name: &str, value: bool) -> Self {
+        self.feature(group, name, value.to_string())
+    }
+
+    /// Returns the features that were set
+    pub fn build(self) -> FeatureMap {
+        self.features
+    }
+}
+
+impl Label {
+    /// Returns the value of the feature with the given name in the group, if it is set
+    pub fn feature_value(&self, group: &str, name: &str) -> Option<&str> {
+        self.feature
+            .as_ref()
+            .and_then(|f| f.get(group))
+            .and_then(|g| g.get(name))
+            .map(String::as_str)
+    }
+
+    /// Returns the value of a boolean feature. This is `None` if the feature isn't set or isn't
+    /// one of `true`, `t`, `false` or `f`
+    pub fn feature_flag(&self, group: &str, name: &str) -> Option<bool> {
+        self.feature_value(group, name).and_then(parse_flag)
+    }
+
+    /// Sets the feature with the given name in the group, replacing any previous value
+    pub fn set_feature(&mut self, group: &str, name: &str, value: impl Into<String>) {
+        insert(
+            self.feature.get_or_insert_with(FeatureMap::new),
+            group,
+            name,
+            value.into(),
+        )
+    }
+}
+
+impl Parcel {
+    /// Returns the value of the feature with the given name in the group of the parcel's label
+    pub fn feature_value(&self, group: &str, name: &str) -> Option<&str> {
+        self.label.feature_value(group, name)
+    }
+
+    /// Returns the value of a boolean feature of the parcel's label. See
+    /// [`Label::feature_flag`](crate::Label::feature_flag)
+    pub fn feature_flag(&self, group: &str, name: &str) -> Option<bool> {
+        self.label.feature_flag(group, name)
+    }
+}
+
+fn insert(features: &mut FeatureMap, group: &str, name: &str, value: String) {
+    features
+        .entry(group.to_owned())
+        .or_default()
+        .insert(name.to_owned(), value);
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value {
+        "true" | "t" => Some(true),
+        "false" | "f" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_feature_accessors() {
+        let parcel: Parcel = toml::from_str(
+            r#"
+            [label]
+            sha256 = "abc"
+            mediaType = "application/wasm"
+            name = "app.wasm"
+            size = 1
+            [label.feature.wasm]
+            entrypoint = "t"
+            data = "nope"
+            "#,
+        )
+        .expect("parcel should parse");
+        assert_eq!(Some("t"), parcel.feature_value("wasm", "entrypoint"));
+        assert_eq!(Some(true), parcel.feature_flag("wasm", "entrypoint"));
+        assert_eq!(None, parcel.feature_flag("wasm", "data"));
+        assert_eq!(None, parcel.feature_value("wasm", "library"));
+        assert_eq!(None, parcel.feature_value("other", "entrypoint"));
+
+        let mut label = Label::default();
+        assert_eq!(None, label.feature_value("wasm", "library"));
+        label.set_feature("wasm", "library", "false");
+        label.set_feature("wasm", "library", "true");
+        assert_eq!(Some(true), label.feature_flag("wasm", "library"));
+
+        let features = FeatureBuilder::new()
+            .feature("testing", "animal", "narwhal")
+            .flag("testing", "enabled", false)
+            .build();
+        assert_eq!("narwhal", features["testing"]["animal"]);
+        assert_eq!("false", features["testing"]["enabled"]);
+    }
+}
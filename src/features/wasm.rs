@@ -0,0 +1,66 @@
+//! The well-known features of parcels containing WebAssembly, in the `wasm` group. See the
+//! [WebAssembly docs](https://github.com/deislabs/bindle/blob/master/docs/webassembly.md) for how
+//! runtimes use them
+
+use crate::Label;
+
+/// The feature group containing the WebAssembly features
+pub const GROUP: &str = "wasm";
+/// Boolean feature indicating that the parcel contains a library
+pub const LIBRARY: &str = "library";
+/// Boolean feature indicating that the parcel can be executed to start an application
+pub const ENTRYPOINT: &str = "entrypoint";
+/// Boolean feature indicating that the parcel contains opaque data
+pub const DATA: &str = "data";
+/// The name of a UI toolkit that must be present to execute the module. The names are defined by
+/// the runtimes
+pub const UI_KIT: &str = "ui_kit";
+/// Boolean feature indicating whether the module requires WASI. Defaults to `true`
+pub const WASI: &str = "wasi";
+
+/// Returns true if the parcel is marked as a library
+pub fn is_library(label: &Label) -> bool {
+    label.feature_flag(GROUP, LIBRARY).unwrap_or(false)
+}
+
+/// Returns true if the parcel is marked as an entrypoint
+pub fn is_entrypoint(label: &Label) -> bool {
+    label.feature_flag(GROUP, ENTRYPOINT).unwrap_or(false)
+}
+
+/// Returns true if the parcel is marked as data
+pub fn is_data(label: &Label) -> bool {
+    label.feature_flag(GROUP, DATA).unwrap_or(false)
+}
+
+/// Returns the UI toolkit the module requires, if any
+pub fn ui_kit(label: &Label) -> Option<&str> {
+    label.feature_value(GROUP, UI_KIT)
+}
+
+/// Returns true unless the module is explicitly marked as not requiring WASI
+pub fn requires_wasi(label: &Label) -> bool {
+    label.feature_flag(GROUP, WASI).unwrap_or(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wasm_features() {
+        let mut label = Label::default();
+        assert!(!is_library(&label));
+        assert!(!is_entrypoint(&label));
+        assert!(!is_data(&label));
+        assert!(requires_wasi(&label));
+        assert_eq!(None, ui_kit(&label));
+
+        label.set_feature(GROUP, ENTRYPOINT, "t");
+        label.set_feature(GROUP, WASI, "f");
+        label.set_feature(GROUP, UI_KIT, "electron+sgu");
+        assert!(is_entrypoint(&label));
+        assert!(!requires_wasi(&label));
+        assert_eq!(Some("electron+sgu"), ui_kit(&label));
+    }
+}
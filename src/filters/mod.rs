@@ -103,6 +103,8 @@
 //!     .filter();
 //! assert_eq!(2, filter.len());
 //! ```
+pub mod resolution;
+
 use std::collections::HashSet;
 
 use crate::{Invoice, Parcel};
@@ -0,0 +1,691 @@
+//! Resolution of the parcels a client needs from an invoice, taking the `satisfiedBy` criteria of
+//! groups into account.
+//!
+//! Unlike [`BindleFilter`](super::BindleFilter), which includes every parcel of every enabled
+//! group, [`resolve`](resolve) follows the rules of the [invoice
+//! spec](https://github.com/deislabs/bindle/blob/master/docs/invoice-spec.md#groups):
+//!
+//! - The parcels of the global group are always selected
+//! - Every group that is marked as `required`, explicitly requested, or required by a selected
+//!   parcel must be satisfied
+//! - An `allOf` group (the default) is satisfied by selecting all of its parcels
+//! - A `oneOf` group is satisfied by a single parcel. If one of its parcels was already selected
+//!   for another group, that one is reused, otherwise the parcel is chosen according to the
+//!   [`Preference`](Preference), which picks the first parcel of the group by default
+//! - An `optional` group is satisfied without selecting anything, unless it was explicitly
+//!   requested, in which case all of its parcels are selected
+//!
+//! Parcels whose features don't match the requested ones are never selected. The parcels required
+//! by `allOf` groups are resolved before any parcel is chosen for a `oneOf` group, so that the
+//! choices overlap with what is already selected as much as possible.
+//!
+//! ```
+//! use bindle::filters::resolution::{resolve, FeatureSelector};
+//!
+//! let toml = r#"
+//! bindleVersion = "1.0.0"
+//! [bindle]
+//! name = "test/resolution"
+//! version = "0.1.0"
+//!
+//! [[group]]
+//! name = "cli"
+//! satisfiedBy = "oneOf"
+//! required = true
+//!
+//! [[parcel]]
+//! [parcel.label]
+//! name = "cli-en"
+//! sha256 = "12345"
+//! mediaType = "application/octet-stream"
+//! size = 123
+//! [parcel.label.feature.i18n]
+//! lang = "en"
+//! [parcel.conditions]
+//! memberOf = ["cli"]
+//!
+//! [[parcel]]
+//! [parcel.label]
+//! name = "cli-de"
+//! sha256 = "54321"
+//! mediaType = "application/octet-stream"
+//! size = 123
+//! [parcel.label.feature.i18n]
+//! lang = "de"
+//! [parcel.conditions]
+//! memberOf = ["cli"]
+//! "#;
+//! let inv: bindle::Invoice = toml::from_str(toml).expect("test invoice parsed");
+//!
+//! let features: Vec<FeatureSelector> = vec!["lang=de".parse().unwrap()];
+//! let labels = resolve(&inv, &[], &features).expect("should resolve");
+//! assert_eq!(1, labels.len());
+//! assert_eq!("cli-de", labels[0].name);
+//! ```
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::{Group, Invoice, Label, Parcel};
+
+const ALL_OF: &str = "allOf";
+const ONE_OF: &str = "oneOf";
+const OPTIONAL: &str = "optional";
+const ANY_OF: &str = "anyOf";
+
+/// Describes the errors that can occur when resolving the parcels of an invoice
+#[derive(Error, Debug, PartialEq)]
+pub enum ResolutionError {
+    /// A group was requested or required by a parcel, but it isn't defined in the invoice
+    #[error("Group {0} is not defined in the invoice")]
+    UnknownGroup(String),
+    /// A group has a `satisfiedBy` criterion that isn't defined by the spec
+    #[error("Group {group} has an unknown satisfiedBy criterion {criterion}")]
+    UnknownCriterion { group: String, criterion: String },
+    /// A `oneOf` group must be satisfied, but none of its parcels can be selected
+    #[error("Group {0} must be satisfied by one of its parcels, but none of them match")]
+    Unsatisfied(String),
+    /// A feature selector could not be parsed
+    #[error("Invalid feature {0}, expected NAME=VALUE or GROUP.NAME=VALUE")]
+    InvalidFeature(String),
+    /// A preference could not be parsed
+    #[error("Invalid preference {0}, expected first, smallest or largest")]
+    InvalidPreference(String),
+}
+
+/// How a group is satisfied, parsed from its `satisfiedBy` field
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Criterion {
+    AllOf,
+    OneOf,
+    Optional,
+}
+
+impl Criterion {
+    fn of(group: &Group) -> Result<Self, ResolutionError> {
+        match group.satisfied_by.as_deref() {
+            None | Some(ALL_OF) => Ok(Criterion::AllOf),
+            Some(ONE_OF) => Ok(Criterion::OneOf),
+            Some(OPTIONAL) | Some(ANY_OF) => Ok(Criterion::Optional),
+            Some(c) => Err(ResolutionError::UnknownCriterion {
+                group: group.name.clone(),
+                criterion: c.to_owned(),
+            }),
+        }
+    }
+}
+
+/// Selects parcels by the value of one of their features.
+///
+/// A selector parses from `GROUP.NAME=VALUE`, which corresponds to the TOML:
+///
+/// ```toml
+/// [parcel.label.feature.GROUP]
+/// NAME = "VALUE"
+/// ```
+///
+/// The group can be left out (`NAME=VALUE`), in which case the selector applies to the feature
+/// with that name in every group. Parcels that have the feature with a different value are not
+/// selected, while parcels that don't have the feature at all are unaffected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureSelector {
+    pub group: Option<String>,
+    pub name: String,
+    pub value: String,
+}
+
+impl FeatureSelector {
+    /// Returns true if the given label does not have a feature contradicting this selector
+    fn allows(&self, label: &Label) -> bool {
+        let features = match &label.feature {
+            Some(f) => f,
+            None => return true,
+        };
+        features
+            .iter()
+            .filter(|(group, _)| self.group.as_ref().map(|g| g == *group).unwrap_or(true))
+            .filter_map(|(_, values)| values.get(&self.name))
+            .all(|value| *value == self.value)
+    }
+}
+
+impl FromStr for FeatureSelector {
+    type Err = ResolutionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ResolutionError::InvalidFeature(s.to_owned());
+        let index = s.find('=').ok_or_else(invalid)?;
+        let (key, value) = (&s[..index], &s[index + 1..]);
+        let (group, name) = match key.find('.') {
+            Some(i) => (Some(&key[..i]), &key[i + 1..]),
+            None => (None, key),
+        };
+        if name.is_empty() || group.map(str::is_empty).unwrap_or(false) {
+            return Err(invalid());
+        }
+        Ok(FeatureSelector {
+            group: group.map(str::to_owned),
+            name: name.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+}
+
+impl std::fmt::Display for FeatureSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(group) = &self.group {
+            write!(f, "{}.", group)?;
+        }
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+/// Which parcel to choose for a `oneOf` group when none of its parcels is selected yet. Parcels
+/// that don't match the selected features are never chosen, whatever the preference. Parses from
+/// `first`, `smallest` or `largest`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preference {
+    /// The first matching parcel in the invoice, which is the default
+    #[default]
+    First,
+    /// The matching parcel with the smallest size, such as for clients on slow connections
+    Smallest,
+    /// The matching parcel with the largest size, which usually is the most complete one
+    Largest,
+}
+
+impl FromStr for Preference {
+    type Err = ResolutionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Preference::First),
+            "smallest" => Ok(Preference::Smallest),
+            "largest" => Ok(Preference::Largest),
+            _ => Err(ResolutionError::InvalidPreference(s.to_owned())),
+        }
+    }
+}
+
+impl std::fmt::Display for Preference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Preference::First => "first",
+            Preference::Smallest => "smallest",
+            Preference::Largest => "largest",
+        })
+    }
+}
+
+/// Returns the labels of the parcels needed to satisfy the given invoice when the given groups are
+/// requested and the given features are selected, in the order they appear in the invoice. See the
+/// [module documentation](self) for how the parcels are chosen
+pub fn resolve(
+    invoice: &Invoice,
+    groups: &[String],
+    features: &[FeatureSelector],
+) -> Result<Vec<Label>, ResolutionError> {
+    resolve_with_preference(invoice, groups, features, Preference::default())
+}
+
+/// Like [`resolve`](resolve), but chooses the parcels of `oneOf` groups according to the given
+/// preference
+pub fn resolve_with_preference(
+    invoice: &Invoice,
+    groups: &[String],
+    features: &[FeatureSelector],
+    preference: Preference,
+) -> Result<Vec<Label>, ResolutionError> {
+    Ok(Resolver::new(invoice, features, preference)?
+        .resolve(groups)?
+        .into_iter()
+        .map(|p| p.label.clone())
+        .collect())
+}
+
+/// Like [`resolve`](resolve), but returns the parcels themselves, including their conditions
+pub fn resolve_parcels(
+    invoice: &Invoice,
+    groups: &[String],
+    features: &[FeatureSelector],
+) -> Result<Vec<Parcel>, ResolutionError> {
+    Ok(Resolver::new(invoice, features, Preference::default())?
+        .resolve(groups)?
+        .into_iter()
+        .cloned()
+        .collect())
+}
+
+struct Resolver<'a> {
+    groups: HashMap<&'a str, (&'a Group, Criterion)>,
+    /// The parcels that match the features, along with their index in the invoice
+    parcels: Vec<(usize, &'a Parcel)>,
+    preference: Preference,
+    selected: HashSet<usize>,
+    /// The groups that are satisfied or waiting to be satisfied
+    satisfied: HashSet<&'a str>,
+    /// Groups waiting to be satisfied by all of their parcels
+    pending: VecDeque<&'a str>,
+    /// Groups waiting to be satisfied by one or none of their parcels. These are only handled once
+    /// there are no pending groups left
+    deferred: VecDeque<&'a str>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(
+        invoice: &'a Invoice,
+        features: &[FeatureSelector],
+        preference: Preference,
+    ) -> Result<Self, ResolutionError> {
+        let groups = invoice
+            .group
+            .iter()
+            .flatten()
+            .map(|g| Ok((g.name.as_str(), (g, Criterion::of(g)?))))
+            .collect::<Result<_, ResolutionError>>()?;
+        let parcels = invoice
+            .parcel
+            .iter()
+            .flatten()
+            .enumerate()
+            .filter(|(_, p)| features.iter().all(|f| f.allows(&p.label)))
+            .collect();
+        Ok(Resolver {
+            groups,
+            parcels,
+            preference,
+            selected: HashSet::new(),
+            satisfied: HashSet::new(),
+            pending: VecDeque::new(),
+            deferred: VecDeque::new(),
+        })
+    }
+
+    fn resolve(mut self, requested: &[String]) -> Result<Vec<&'a Parcel>, ResolutionError> {
+        // Explicitly requested groups get all of their parcels, whatever their criterion
+        for name in requested {
+            let name = self.group_name(name)?;
+            if self.satisfied.insert(name) {
+                self.select_all(name)?;
+            }
+        }
+
+        let global: Vec<(usize, &Parcel)> = self
+            .parcels
+            .iter()
+            .filter(|(_, p)| p.is_global_group())
+            .copied()
+            .collect();
+        for (index, parcel) in global {
+            self.select(index, parcel)?;
+        }
+
+        let required: Vec<&str> = self
+            .groups
+            .values()
+            .filter(|(g, _)| g.required.unwrap_or(false))
+            .map(|(g, _)| g.name.as_str())
+            .collect();
+        for name in required {
+            self.require(name)?;
+        }
+
+        loop {
+            if let Some(name) = self.pending.pop_front() {
+                self.select_all(name)?;
+            } else if let Some(name) = self.deferred.pop_front() {
+                self.satisfy_deferred(name)?;
+            } else {
+                break;
+            }
+        }
+
+        let selected = &self.selected;
+        Ok(self
+            .parcels
+            .iter()
+            .filter(|(i, _)| selected.contains(i))
+            .map(|(_, p)| *p)
+            .collect())
+    }
+
+    /// Returns the name of the group as borrowed from the invoice, or an error if it isn't defined
+    fn group_name(&self, name: &str) -> Result<&'a str, ResolutionError> {
+        self.groups
+            .get(name)
+            .map(|(g, _)| g.name.as_str())
+            .ok_or_else(|| ResolutionError::UnknownGroup(name.to_owned()))
+    }
+
+    /// Queues the group to be satisfied, unless that already happened
+    fn require(&mut self, name: &str) -> Result<(), ResolutionError> {
+        let name = self.group_name(name)?;
+        if self.satisfied.insert(name) {
+            match self.groups[name].1 {
+                Criterion::AllOf => self.pending.push_back(name),
+                Criterion::OneOf | Criterion::Optional => self.deferred.push_back(name),
+            }
+        }
+        Ok(())
+    }
+
+    fn members(&self, name: &str) -> Vec<(usize, &'a Parcel)> {
+        self.parcels
+            .iter()
+            .filter(|(_, p)| p.member_of(name))
+            .copied()
+            .collect()
+    }
+
+    /// Selects the parcel and queues the groups it requires
+    fn select(&mut self, index: usize, parcel: &'a Parcel) -> Result<(), ResolutionError> {
+        if !self.selected.insert(index) {
+            return Ok(());
+        }
+        let requires = parcel.conditions.as_ref().and_then(|c| c.requires.as_ref());
+        for name in requires.into_iter().flatten() {
+            self.require(name)?;
+        }
+        Ok(())
+    }
+
+    fn select_all(&mut self, name: &str) -> Result<(), ResolutionError> {
+        for (index, parcel) in self.members(name) {
+            self.select(index, parcel)?;
+        }
+        Ok(())
+    }
+
+    fn satisfy_deferred(&mut self, name: &str) -> Result<(), ResolutionError> {
+        if self.groups[name].1 == Criterion::Optional {
+            return Ok(());
+        }
+        let members = self.members(name);
+        if members.iter().any(|(i, _)| self.selected.contains(i)) {
+            return Ok(());
+        }
+        // The members are in invoice order, so ties go to the first of them
+        let chosen = match self.preference {
+            Preference::First => members.first(),
+            Preference::Smallest => members.iter().min_by_key(|(_, p)| p.label.size),
+            Preference::Largest => members
+                .iter()
+                .max_by_key(|(i, p)| (p.label.size, std::cmp::Reverse(*i))),
+        };
+        match chosen {
+            Some((index, parcel)) => self.select(*index, parcel),
+            None => Err(ResolutionError::Unsatisfied(name.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The example from the invoice spec, with an additional required group that is satisfied by
+    // all of its parcels, one of which requires the `server` group
+    const TEST_INVOICE: &str = r#"
+    bindleVersion = "1.0.0"
+
+    [bindle]
+    name = "mybindle"
+    version = "0.1.0"
+
+    [[group]]
+    name = "server"
+    satisfiedBy = "allOf"
+
+    [[group]]
+    name = "cli"
+    satisfiedBy = "oneOf"
+    required = true
+
+    [[group]]
+    name = "utility"
+    satisfiedBy = "optional"
+
+    [[parcel]]
+    [parcel.label]
+    sha256 = "e1706ab0a39ac88094b6d54a3f5cdba41fe5a901"
+    mediaType = "application/bin"
+    name = "daemon"
+    size = 10
+    [parcel.conditions]
+    memberOf = ["server"]
+    requires = ["utility"]
+
+    [[parcel]]
+    [parcel.label]
+    sha256 = "e1706ab0a39ac88094b6d54a3f5cdba41fe5a901"
+    mediaType = "application/bin"
+    name = "first"
+    size = 10
+    [parcel.label.feature.i18n]
+    lang = "en"
+    [parcel.conditions]
+    memberOf = ["cli", "utility"]
+
+    [[parcel]]
+    [parcel.label]
+    sha256 = "a1706ab0a39ac88094b6d54a3f5cdba41fe5a901"
+    mediaType = "application/bin"
+    name = "second"
+    size = 10
+    [parcel.label.feature.i18n]
+    lang = "de"
+    [parcel.conditions]
+    memberOf = ["cli"]
+
+    [[parcel]]
+    [parcel.label]
+    sha256 = "5b992e90b71d5fadab3cd3777230ef370df75f5b"
+    mediaType = "application/x-javascript"
+    name = "third"
+    size = 10
+    [parcel.conditions]
+    memberOf = ["utility"]
+
+    [[parcel]]
+    [parcel.label]
+    sha256 = "6b992e90b71d5fadab3cd3777230ef370df75f5b"
+    mediaType = "text/plain"
+    name = "readme"
+    size = 10
+    "#;
+
+    fn names(invoice: &Invoice, groups: &[&str], features: &[&str]) -> Vec<String> {
+        let groups: Vec<String> = groups.iter().map(|g| (*g).to_owned()).collect();
+        let features: Vec<FeatureSelector> = features
+            .iter()
+            .map(|f| f.parse().expect("valid feature"))
+            .collect();
+        resolve(invoice, &groups, &features)
+            .expect("should resolve")
+            .into_iter()
+            .map(|l| l.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve_groups() {
+        let inv: Invoice = toml::from_str(TEST_INVOICE).expect("test invoice parsed");
+
+        // Only one parcel is needed for the required oneOf group
+        assert_eq!(vec!["first", "readme"], names(&inv, &[], &[]));
+
+        // The daemon requires the optional utility group, which is satisfied without selecting
+        // anything
+        assert_eq!(
+            vec!["daemon", "first", "readme"],
+            names(&inv, &["server"], &[])
+        );
+
+        // Requesting a group explicitly selects all of its parcels
+        assert_eq!(
+            vec!["first", "third", "readme"],
+            names(&inv, &["utility"], &[])
+        );
+        assert_eq!(
+            vec!["first", "second", "readme"],
+            names(&inv, &["cli"], &[])
+        );
+    }
+
+    #[test]
+    fn test_resolve_features() {
+        let inv: Invoice = toml::from_str(TEST_INVOICE).expect("test invoice parsed");
+
+        // The oneOf group is satisfied by the parcel matching the feature
+        assert_eq!(vec!["second", "readme"], names(&inv, &[], &["lang=de"]));
+        assert_eq!(
+            vec!["second", "readme"],
+            names(&inv, &[], &["i18n.lang=de"])
+        );
+        // Features in other groups don't apply
+        assert_eq!(
+            vec!["first", "readme"],
+            names(&inv, &[], &["other.lang=de"])
+        );
+        // Explicitly requested groups are filtered as well
+        assert_eq!(
+            vec!["second", "third", "readme"],
+            names(&inv, &["utility"], &["lang=de"])
+        );
+
+        assert_eq!(
+            Err(ResolutionError::Unsatisfied("cli".to_owned())),
+            resolve(&inv, &[], &["lang=fr".parse().unwrap()])
+        );
+    }
+
+    #[test]
+    fn test_resolve_preference() {
+        let mut inv: Invoice = toml::from_str(TEST_INVOICE).expect("test invoice parsed");
+        inv.parcel.as_mut().unwrap()[2].label.size = 5;
+        let chosen = |inv: &Invoice, groups: &[&str], preference: &str| -> Vec<String> {
+            let groups: Vec<String> = groups.iter().map(|g| (*g).to_owned()).collect();
+            resolve_with_preference(inv, &groups, &[], preference.parse().unwrap())
+                .expect("should resolve")
+                .into_iter()
+                .map(|l| l.name)
+                .collect()
+        };
+
+        assert_eq!(vec!["first", "readme"], chosen(&inv, &[], "first"));
+        assert_eq!(vec!["second", "readme"], chosen(&inv, &[], "smallest"));
+        assert_eq!(vec!["first", "readme"], chosen(&inv, &[], "largest"));
+        // A parcel that is already selected for another group is reused, whatever the preference
+        assert_eq!(
+            vec!["first", "third", "readme"],
+            chosen(&inv, &["utility"], "smallest")
+        );
+
+        // Ties go to the first parcel
+        inv.parcel.as_mut().unwrap()[2].label.size = 10;
+        assert_eq!(vec!["first", "readme"], chosen(&inv, &[], "smallest"));
+        assert_eq!(vec!["first", "readme"], chosen(&inv, &[], "largest"));
+
+        assert_eq!(
+            Err(ResolutionError::InvalidPreference("tiny".to_owned())),
+            "tiny".parse::<Preference>()
+        );
+        assert_eq!("smallest", Preference::Smallest.to_string());
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let mut inv: Invoice = toml::from_str(TEST_INVOICE).expect("test invoice parsed");
+        assert_eq!(
+            Err(ResolutionError::UnknownGroup("nope".to_owned())),
+            resolve(&inv, &["nope".to_owned()], &[])
+        );
+
+        inv.group.as_mut().unwrap()[0].satisfied_by = Some("someOf".to_owned());
+        assert_eq!(
+            Err(ResolutionError::UnknownCriterion {
+                group: "server".to_owned(),
+                criterion: "someOf".to_owned(),
+            }),
+            resolve(&inv, &[], &[])
+        );
+
+        for invalid in &["lang", "=en", ".lang=en", "i18n.=en"] {
+            assert_eq!(
+                Err(ResolutionError::InvalidFeature((*invalid).to_owned())),
+                invalid.parse::<FeatureSelector>()
+            );
+        }
+        let feature: FeatureSelector = "i18n.lang=en=US".parse().unwrap();
+        assert_eq!(Some("i18n"), feature.group.as_deref());
+        assert_eq!("en=US", feature.value);
+        assert_eq!("i18n.lang=en=US", feature.to_string());
+    }
+
+    #[test]
+    fn test_resolve_requires() {
+        let toml = r#"
+        bindleVersion = "1.0.0"
+
+        [bindle]
+        name = "test/requires"
+        version = "0.1.0"
+
+        [[group]]
+        name = "first"
+        required = true
+
+        [[group]]
+        name = "second"
+        satisfiedBy = "oneOf"
+
+        [[group]]
+        name = "third"
+
+        [[parcel]]
+        [parcel.label]
+        name = "p1"
+        sha256 = "12345"
+        mediaType = "application/octet-stream"
+        size = 123
+        [parcel.conditions]
+        memberOf = ["first"]
+        requires = ["second"]
+
+        [[parcel]]
+        [parcel.label]
+        name = "p2"
+        sha256 = "4321"
+        mediaType = "application/octet-stream"
+        size = 321
+        [parcel.conditions]
+        memberOf = ["second"]
+
+        [[parcel]]
+        [parcel.label]
+        name = "p3"
+        sha256 = "4321"
+        mediaType = "application/octet-stream"
+        size = 321
+        [parcel.conditions]
+        memberOf = ["second", "third"]
+        requires = ["first"] # should not cause an infinite loop
+
+        [[parcel]]
+        [parcel.label]
+        name = "p4"
+        sha256 = "4321"
+        mediaType = "application/octet-stream"
+        size = 321
+        [parcel.conditions]
+        memberOf = ["third"]
+        "#;
+        let inv: Invoice = toml::from_str(toml).expect("test invoice parsed");
+
+        assert_eq!(vec!["p1", "p2"], names(&inv, &[], &[]));
+        // The parcel selected for the third group satisfies the second one as well
+        assert_eq!(vec!["p1", "p3", "p4"], names(&inv, &["third"], &[]));
+    }
+}
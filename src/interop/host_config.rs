@@ -0,0 +1,317 @@
+//! Generation of host configurations for WASI hosts that serve HTTP routes with the WebAssembly
+//! modules of a bindle, in the style of [Wagi](https://github.com/deislabs/wagi) and Spin.
+//!
+//! Which modules serve which routes is described with annotations on the labels of their parcels:
+//!
+//! - [`ROUTE`](ROUTE) is the route a module serves, such as `/` or `/static/...`. Only parcels
+//!   with the `application/wasm` media type and a route are modules
+//! - [`ENTRYPOINT`](ENTRYPOINT) is the function of the module to call, if it isn't the default one
+//! - Annotations starting with [`ENV_PREFIX`](ENV_PREFIX) set environment variables for the
+//!   module. They can also be set on the invoice, in which case they apply to every module unless
+//!   the label sets the same variable
+//!
+//! The parcels in the groups a module `requires` (see the [invoice
+//! spec](https://github.com/deislabs/bindle/blob/master/docs/invoice-spec.md)) are made available
+//! to it as files, at the path given by their name. Only the parcels selected for the requested
+//! groups and features are used, so a host gets the same modules as a client resolving the bindle
+//! with [`Invoice::resolve_parcels`](crate::Invoice::resolve_parcels).
+//!
+//! ```
+//! use std::path::PathBuf;
+//!
+//! let inv: bindle::Invoice = toml::from_str(r#"
+//!     bindleVersion = "1.0.0"
+//!     [bindle]
+//!     name = "app"
+//!     version = "1.0.0"
+//!     [[parcel]]
+//!     [parcel.label]
+//!     name = "hello.wasm"
+//!     sha256 = "abc123"
+//!     mediaType = "application/wasm"
+//!     size = 123
+//!     [parcel.label.annotations]
+//!     "bindle.host.route" = "/hello"
+//!     "bindle.host.env.GREETING" = "Hello"
+//! "#).unwrap();
+//!
+//! let config = bindle::interop::host_config::generate(&inv, &[], &Default::default(), |label| {
+//!     PathBuf::from("/var/cache/bindle").join(&label.sha256)
+//! })
+//! .unwrap();
+//! assert_eq!("/hello", config.module[0].route);
+//! assert_eq!(PathBuf::from("/var/cache/bindle/abc123"), config.module[0].module);
+//! assert_eq!("Hello", config.module[0].environment["GREETING"]);
+//! ```
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::filters::resolution::ResolutionError;
+use crate::{FeatureMap, Invoice, Label, Parcel};
+
+/// The label annotation with the route a module serves
+pub const ROUTE: &str = "bindle.host.route";
+/// The label annotation with the function of a module the host should call
+pub const ENTRYPOINT: &str = "bindle.host.entrypoint";
+/// The prefix of invoice and label annotations that set environment variables. The rest of the
+/// key is the name of the variable
+pub const ENV_PREFIX: &str = "bindle.host.env.";
+
+const WASM_MEDIA_TYPE: &str = "application/wasm";
+
+/// Describes the errors that can occur when generating a host configuration
+#[derive(Error, Debug)]
+pub enum HostConfigError {
+    /// The parcels for the requested groups and features could not be resolved
+    #[error("Unable to resolve the parcels of the bindle: {0}")]
+    Resolution(#[from] ResolutionError),
+    /// A route doesn't start with a `/`. Contains the name of the parcel
+    #[error("Route {route} of parcel {parcel} must start with a /")]
+    InvalidRoute { parcel: String, route: String },
+    /// More than one module serves the same route. Contains the route
+    #[error("More than one module serves the route {0}")]
+    DuplicateRoute(String),
+}
+
+/// The configuration of a host, listing the modules it runs. Serialized as TOML, it has the same
+/// shape as the module configuration of Wagi
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    pub module: Vec<ModuleConfig>,
+}
+
+/// A module and the route it serves
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleConfig {
+    /// Where the parcel of the module is stored
+    pub module: PathBuf,
+    pub route: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, String>,
+    /// The parcels the module can read as files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileMount>,
+}
+
+/// A parcel made available to a module as a file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileMount {
+    /// Where the parcel is stored
+    pub source: PathBuf,
+    /// The path the module sees the parcel at, which is the name from its label
+    pub path: String,
+}
+
+/// Generates the host configuration for the parcels selected with the given groups and features
+/// (see [`Invoice::resolve_parcels`](crate::Invoice::resolve_parcels)). The `parcel_path` returns
+/// where the host can find the data of a parcel, such as in the cache it fetched the parcels into.
+/// Modules are listed in the order they appear in the invoice
+pub fn generate<F>(
+    inv: &Invoice,
+    groups: &[&str],
+    features: &FeatureMap,
+    parcel_path: F,
+) -> Result<HostConfig, HostConfigError>
+where
+    F: Fn(&Label) -> PathBuf,
+{
+    let parcels = inv.resolve_parcels(groups, features)?;
+    let mut routes = HashSet::new();
+    let mut config = HostConfig::default();
+    for parcel in parcels.iter() {
+        let route = match parcel.label.annotation(ROUTE) {
+            Some(route) if parcel.label.media_type == WASM_MEDIA_TYPE => route,
+            _ => continue,
+        };
+        if !route.starts_with('/') {
+            return Err(HostConfigError::InvalidRoute {
+                parcel: parcel.label.name.clone(),
+                route: route.to_owned(),
+            });
+        }
+        if !routes.insert(route) {
+            return Err(HostConfigError::DuplicateRoute(route.to_owned()));
+        }
+
+        let mut environment = env_vars(&inv.annotations);
+        environment.extend(env_vars(&parcel.label.annotations));
+        let required = parcel
+            .conditions
+            .as_ref()
+            .and_then(|c| c.requires.as_deref())
+            .unwrap_or_default();
+        let files = parcels
+            .iter()
+            .filter(|p| !is_module(p) && required.iter().any(|group| p.member_of(group)))
+            .map(|p| FileMount {
+                source: parcel_path(&p.label),
+                path: p.label.name.clone(),
+            })
+            .collect();
+        config.module.push(ModuleConfig {
+            module: parcel_path(&parcel.label),
+            route: route.to_owned(),
+            entrypoint: parcel.label.annotation(ENTRYPOINT).map(ToOwned::to_owned),
+            environment,
+            files,
+        });
+    }
+    Ok(config)
+}
+
+fn is_module(parcel: &Parcel) -> bool {
+    parcel.label.media_type == WASM_MEDIA_TYPE && parcel.label.annotation(ROUTE).is_some()
+}
+
+/// Returns the environment variables set by the given annotations
+fn env_vars(annotations: &Option<crate::AnnotationMap>) -> BTreeMap<String, String> {
+    annotations
+        .iter()
+        .flatten()
+        .filter_map(|(key, value)| {
+            key.strip_prefix(ENV_PREFIX)
+                .filter(|name| !name.is_empty())
+                .map(|name| (name.to_owned(), value.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn invoice() -> Invoice {
+        toml::from_str(
+            r#"
+            bindleVersion = "1.0.0"
+            [bindle]
+            name = "example.com/site"
+            version = "1.0.0"
+            [annotations]
+            "bindle.host.env.SITE" = "example.com"
+            "bindle.host.env.LANG" = "en"
+
+            [[group]]
+            name = "assets"
+            [[group]]
+            name = "admin"
+            satisfiedBy = "optional"
+
+            [[parcel]]
+            [parcel.label]
+            name = "site.wasm"
+            sha256 = "aaa"
+            mediaType = "application/wasm"
+            size = 1
+            [parcel.label.annotations]
+            "bindle.host.route" = "/..."
+            "bindle.host.entrypoint" = "serve"
+            "bindle.host.env.LANG" = "de"
+            [parcel.conditions]
+            requires = ["assets"]
+
+            [[parcel]]
+            [parcel.label]
+            name = "lib.wasm"
+            sha256 = "bbb"
+            mediaType = "application/wasm"
+            size = 1
+
+            [[parcel]]
+            [parcel.label]
+            name = "static/index.html"
+            sha256 = "ccc"
+            mediaType = "text/html"
+            size = 1
+            [parcel.conditions]
+            memberOf = ["assets"]
+
+            [[parcel]]
+            [parcel.label]
+            name = "admin.wasm"
+            sha256 = "ddd"
+            mediaType = "application/wasm"
+            size = 1
+            [parcel.label.annotations]
+            "bindle.host.route" = "/admin"
+            [parcel.conditions]
+            memberOf = ["admin"]
+            "#,
+        )
+        .expect("invoice should parse")
+    }
+
+    fn parcel_path(label: &Label) -> PathBuf {
+        PathBuf::from("/cache").join(&label.sha256)
+    }
+
+    #[test]
+    fn test_generate() {
+        let inv = invoice();
+        let config =
+            generate(&inv, &[], &FeatureMap::new(), parcel_path).expect("config should generate");
+        assert_eq!(
+            1,
+            config.module.len(),
+            "Only routed modules should be listed"
+        );
+        let module = &config.module[0];
+        assert_eq!(PathBuf::from("/cache/aaa"), module.module);
+        assert_eq!("/...", module.route);
+        assert_eq!(Some("serve"), module.entrypoint.as_deref());
+        assert_eq!("example.com", module.environment["SITE"]);
+        assert_eq!(
+            "de", module.environment["LANG"],
+            "Label annotations should override invoice annotations"
+        );
+        assert_eq!(
+            vec![FileMount {
+                source: PathBuf::from("/cache/ccc"),
+                path: "static/index.html".to_owned(),
+            }],
+            module.files
+        );
+
+        let config = generate(&inv, &["admin"], &FeatureMap::new(), parcel_path)
+            .expect("config should generate");
+        let routes: Vec<&str> = config.module.iter().map(|m| m.route.as_str()).collect();
+        assert_eq!(vec!["/...", "/admin"], routes);
+        assert!(config.module[1].files.is_empty());
+        assert_eq!(
+            config,
+            toml::from_str(&toml::to_string(&config).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_routes() {
+        let mut inv = invoice();
+        inv.parcel.as_mut().unwrap()[3]
+            .label
+            .set_annotation(ROUTE, "/...");
+        assert!(matches!(
+            generate(&inv, &["admin"], &FeatureMap::new(), parcel_path),
+            Err(HostConfigError::DuplicateRoute(route)) if route == "/..."
+        ));
+
+        inv.parcel.as_mut().unwrap()[3]
+            .label
+            .set_annotation(ROUTE, "admin");
+        assert!(matches!(
+            generate(&inv, &["admin"], &FeatureMap::new(), parcel_path),
+            Err(HostConfigError::InvalidRoute { .. })
+        ));
+
+        assert!(matches!(
+            generate(&inv, &["nonexistent"], &FeatureMap::new(), parcel_path),
+            Err(HostConfigError::Resolution(_))
+        ));
+    }
+}
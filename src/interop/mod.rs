@@ -0,0 +1,8 @@
+//! Adapters between bindles and the tools that consume them.
+//!
+//! Fetching the parcels of a bindle is only half of running it: the host the parcels are run by
+//! also has to be told what to do with them. The modules in here translate invoices into the
+//! configuration formats of such hosts, so every host doesn't have to come up with its own
+//! conventions
+
+pub mod host_config;
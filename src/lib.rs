@@ -7,23 +7,48 @@
 //! This crate is the reference implementation of the [Bindle
 //! Spec](https://github.com/deislabs/bindle/blob/master/docs/bindle-spec.md) and it contains both a
 //! client and a server implementation, along with various other utilities
+//!
+//! Most of the crate is behind feature flags (see the [crate
+//! README](https://github.com/deislabs/bindle/blob/master/docs/crate-readme.md) for the full list).
+//! With `default-features = false`, only the data model is built: invoices, signatures, filters
+//! and the other types shared by clients and servers, without pulling in tokio
+
+#![recursion_limit = "256"]
 
+pub mod annotations;
+#[cfg(feature = "async")]
 pub mod async_util;
+pub mod builder;
 #[cfg(feature = "caching")]
 pub mod cache;
+pub mod chunking;
 #[cfg(feature = "client")]
 pub mod client;
+pub mod compose;
+pub mod events;
+pub mod features;
 mod id;
+pub mod interop;
+#[cfg(feature = "async")]
 pub mod provider;
 #[cfg(feature = "client")]
 pub mod proxy;
+#[cfg(feature = "client")]
+pub mod replication;
 pub mod search;
 #[cfg(feature = "server")]
 pub mod server;
+pub mod signature;
 #[cfg(feature = "client")]
 pub mod standalone;
+#[cfg(feature = "async")]
+pub mod tasks;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
 #[cfg(feature = "test-tools")]
 pub mod testing;
+pub mod trace;
+pub mod transparency;
 
 pub mod filters;
 
@@ -36,6 +61,7 @@ use semver::{Compat, Version, VersionReq};
 use serde::{Deserialize, Serialize};
 
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::hash::Hash;
 
 use search::SearchOptions;
@@ -43,7 +69,8 @@ use search::SearchOptions;
 /// The version string for the v1 Bindle Spec
 pub const BINDLE_VERSION_1: &str = "1.0.0";
 
-/// Alias for feature map in an Invoice's parcel
+/// Alias for feature map in an Invoice's parcel. See the [`features`](features) module for typed
+/// access to it
 pub type FeatureMap = BTreeMap<String, BTreeMap<String, String>>;
 
 /// Alias for annotations map
@@ -64,11 +91,19 @@ pub struct Invoice {
     pub yanked: Option<bool>,
     pub bindle: BindleSpec,
     pub annotations: Option<BTreeMap<String, String>>,
+    pub requires: Option<Vec<Dependency>>,
     pub parcel: Option<Vec<Parcel>>,
     pub group: Option<Vec<Group>>,
+    pub signature: Option<Vec<signature::Signature>>,
 }
 
 impl Invoice {
+    /// Returns a builder for an invoice of the bindle with the given name and version. See the
+    /// [`builder`](builder) module for more details
+    pub fn builder(name: impl Into<String>, version: impl Into<String>) -> builder::InvoiceBuilder {
+        builder::InvoiceBuilder::new(name, version)
+    }
+
     /// produce a slash-delimited "invoice name"
     ///
     /// For example, an invoice with the bindle name "hello" and the bindle version
@@ -101,9 +136,72 @@ impl Invoice {
     ///
     /// In all other cases, if the version satisfies the requirement, this returns true.
     /// And if it fails to satisfy the requirement, this returns false.
-    fn version_in_range(&self, requirement: &str) -> bool {
+    pub fn version_in_range(&self, requirement: &str) -> bool {
         version_compare(self.bindle.id.version(), requirement)
     }
+
+    /// Returns the other bindles this bindle directly depends on. Use
+    /// [`search::dependencies::resolve`](search::dependencies::resolve) to find the bindles
+    /// satisfying them, including the dependencies of those bindles
+    pub fn dependencies(&self) -> &[Dependency] {
+        self.requires.as_deref().unwrap_or_default()
+    }
+
+    /// Returns the parcels needed when the given groups are requested and the given features are
+    /// selected, in the order they appear in the invoice. This includes the global group, all
+    /// required groups and the groups required by the selected parcels, transitively, with each
+    /// group satisfied according to its `satisfiedBy` criterion. See the
+    /// [`resolution`](filters::resolution) module for the details
+    pub fn resolve_parcels(
+        &self,
+        groups: &[&str],
+        features: &FeatureMap,
+    ) -> Result<Vec<Parcel>, filters::resolution::ResolutionError> {
+        let groups: Vec<String> = groups.iter().map(|g| (*g).to_owned()).collect();
+        let features: Vec<filters::resolution::FeatureSelector> = features
+            .iter()
+            .flat_map(|(group, values)| {
+                values
+                    .iter()
+                    .map(move |(name, value)| filters::resolution::FeatureSelector {
+                        group: Some(group.clone()),
+                        name: name.clone(),
+                        value: value.clone(),
+                    })
+            })
+            .collect();
+        filters::resolution::resolve_parcels(self, &groups, &features)
+    }
+}
+
+/// A dependency of a bindle on another bindle, declared in the `requires` list of an invoice
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Dependency {
+    /// The name of the required bindle
+    pub name: String,
+    /// The SemVer range of versions that satisfy the dependency (e.g. `^1.2.0`). An empty range is
+    /// satisfied by any version
+    #[serde(default)]
+    pub version: String,
+}
+
+impl Dependency {
+    /// Returns whether the given invoice satisfies this dependency. Yanked invoices never do
+    pub fn is_satisfied_by(&self, inv: &Invoice) -> bool {
+        !inv.yanked.unwrap_or(false)
+            && inv.bindle.id.name() == self.name
+            && inv.version_in_range(&self.version)
+    }
+}
+
+impl std::fmt::Display for Dependency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.version.is_empty() {
+            return write!(f, "{}", self.name);
+        }
+        write!(f, "{} {}", self.name, self.version)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -217,6 +315,26 @@ pub struct Group {
 pub struct InvoiceCreateResponse {
     pub invoice: Invoice,
     pub missing: Option<Vec<Label>>,
+    /// The outcome of the server's signing policy for the invoice, if one applies to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_policy: Option<SigningPolicyResult>,
+}
+
+/// The outcome of checking an invoice against the signing policy of a server, telling publishers
+/// which signatures are still needed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct SigningPolicyResult {
+    /// The bindle name pattern of the rule that applied
+    pub bindles: String,
+    /// The roles that each need a signature from a trusted key
+    pub required: Vec<signature::SignatureRole>,
+    /// The required roles that don't have a trusted signature yet
+    pub missing: Vec<signature::SignatureRole>,
+    /// Whether all required roles have a trusted signature
+    pub satisfied: bool,
+    /// Whether invoices that don't satisfy the rule are rejected
+    pub enforced: bool,
 }
 
 /// A response to a missing parcels request. TOML doesn't support top level arrays, so they
@@ -227,15 +345,534 @@ pub struct MissingParcelsResponse {
     pub missing: Vec<Label>,
 }
 
+/// A request for which of the given parcel SHAs a server doesn't have, no matter which bindle
+/// they were uploaded for
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct MissingShasRequest {
+    pub shas: Vec<String>,
+}
+
+/// A response to a [`MissingShasRequest`](MissingShasRequest), listing the SHAs the server
+/// doesn't have in the order they were requested
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct MissingShasResponse {
+    pub missing: Vec<String>,
+}
+
+/// A response to a dependency resolution request, listing the IDs of all bindles a bindle depends
+/// on, directly or indirectly. As with [`MissingParcelsResponse`](MissingParcelsResponse), the
+/// list is embedded in a table
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct DependenciesResponse {
+    #[serde(default)]
+    pub resolved: Vec<Id>,
+}
+
+/// An invoice containing a parcel, as found by
+/// [`Search::find_by_parcel`](search::Search::find_by_parcel)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ParcelInvoice {
+    pub yanked: bool,
+    /// The name of the parcel's label in this invoice, as the same data can be named differently
+    /// by different bindles
+    pub name: String,
+    // Tables have to come after plain values in TOML
+    pub id: Id,
+}
+
+/// A response to a request for the invoices containing a parcel. As with
+/// [`MissingParcelsResponse`](MissingParcelsResponse), the list is embedded in a table
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ParcelInvoicesResponse {
+    #[serde(default)]
+    pub invoices: Vec<ParcelInvoice>,
+}
+
+/// A known version of a bindle, as listed by [`Search::versions`](search::Search::versions)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct VersionInfo {
+    pub version: String,
+    pub yanked: bool,
+    /// The UNIX timestamp (in seconds) at which the invoice was created, taken from its earliest
+    /// signature. Not set for unsigned invoices
+    pub created_at: Option<u64>,
+}
+
+impl From<&Invoice> for VersionInfo {
+    fn from(inv: &Invoice) -> Self {
+        VersionInfo {
+            version: inv.bindle.id.version_string(),
+            yanked: inv.yanked.unwrap_or_default(),
+            created_at: inv.signature.iter().flatten().map(|s| s.at).min(),
+        }
+    }
+}
+
+/// A response to a request for the versions of a bindle name. As with
+/// [`MissingParcelsResponse`](MissingParcelsResponse), the list is embedded in a table
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct VersionsResponse {
+    #[serde(default)]
+    pub versions: Vec<VersionInfo>,
+}
+
+/// A response to a parcel selection request, listing the labels of the parcels needed for the
+/// requested groups and features (see the [`resolution`](filters::resolution) module)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ParcelSelectionResponse {
+    #[serde(default)]
+    pub labels: Vec<Label>,
+}
+
+/// A response to a request to permanently delete an invoice
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct InvoiceDeleteResponse {
+    /// The SHAs of the parcels that were removed along with the invoice, as no other invoice
+    /// referenced them
+    #[serde(default)]
+    pub removed_parcels: Vec<String>,
+}
+
+/// The difference between the parcels of two versions of a bindle, used for fetching only what
+/// changed when updating from one version to another. Parcels are compared by their SHA, so a
+/// parcel that was only renamed counts as unchanged. As with
+/// [`MissingParcelsResponse`](MissingParcelsResponse), the lists are embedded in a table
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ParcelDelta {
+    /// Parcels in the new version that are not in the old one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<Label>,
+    /// Parcels in the old version that are not in the new one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<Label>,
+    /// Parcels in both versions, with the labels from the new version
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unchanged: Vec<Label>,
+}
+
+impl ParcelDelta {
+    /// Returns the parcels that changed between the `from` and `to` invoices
+    pub fn between(from: &Invoice, to: &Invoice) -> Self {
+        let shas = |inv: &Invoice| -> std::collections::HashSet<String> {
+            inv.parcel
+                .iter()
+                .flatten()
+                .map(|p| p.label.sha256.clone())
+                .collect()
+        };
+        let (old, new) = (shas(from), shas(to));
+        let (unchanged, added) = to
+            .parcel
+            .iter()
+            .flatten()
+            .map(|p| p.label.clone())
+            .partition(|l| old.contains(&l.sha256));
+        let removed = from
+            .parcel
+            .iter()
+            .flatten()
+            .filter(|p| !new.contains(&p.label.sha256))
+            .map(|p| p.label.clone())
+            .collect();
+        ParcelDelta {
+            added,
+            removed,
+            unchanged,
+        }
+    }
+}
+
+/// The state of a resumable parcel upload. The upload is complete once `offset` reaches `size`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct UploadStatus {
+    /// The ID of the upload, used to send data and resume the upload
+    pub id: String,
+    /// The SHA of the parcel being uploaded
+    pub sha256: String,
+    /// The number of bytes the server has received so far. This is where the next chunk of data
+    /// has to start
+    pub offset: u64,
+    /// The total size of the parcel in bytes
+    pub size: u64,
+}
+
+impl UploadStatus {
+    /// Returns whether all of the parcel's data has been received
+    pub fn is_complete(&self) -> bool {
+        self.offset >= self.size
+    }
+}
+
+/// The optional features supported by a server, so clients can adapt to what a server can do.
+/// Unknown fields are ignored, so newer servers can advertise features that older clients don't
+/// know about
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Capabilities {
+    /// The version of the server software, if the server chooses to share it
+    pub version: Option<String>,
+    /// The version of the Bindle spec the server implements (e.g.
+    /// [`BINDLE_VERSION_1`](BINDLE_VERSION_1)). Servers with a different major version are not
+    /// compatible
+    pub spec_version: Option<String>,
+    /// Whether parcels can be uploaded in chunks with the resumable upload endpoint (`_u`)
+    pub resumable_uploads: bool,
+    /// Whether part of a parcel can be fetched with the `Range` header
+    pub range_requests: bool,
+    /// Whether the parcel delta endpoint (`_r/delta`) is available
+    pub parcel_delta: bool,
+    /// Whether the server can stream events about changes to bindles
+    pub event_stream: bool,
+    /// Whether the server can hand out pre-signed URLs for fetching parcels
+    pub signed_urls: bool,
+    /// The media types the server can send and receive in API requests
+    pub content_types: Vec<String>,
+    /// The authentication schemes the server accepts (e.g. `Basic` or `Bearer`). Empty if the
+    /// server doesn't authenticate requests
+    pub auth_methods: Vec<String>,
+    /// Whether reads are allowed without credentials
+    pub anonymous_read: bool,
+    /// Whether writes are allowed without credentials
+    pub anonymous_write: bool,
+}
+
+impl Default for Capabilities {
+    /// The capabilities of a server that doesn't advertise any, which only supports the core
+    /// protocol
+    fn default() -> Self {
+        Capabilities {
+            version: None,
+            spec_version: None,
+            resumable_uploads: false,
+            range_requests: false,
+            parcel_delta: false,
+            event_stream: false,
+            signed_urls: false,
+            content_types: vec!["application/toml".to_owned()],
+            auth_methods: Vec::new(),
+            anonymous_read: true,
+            anonymous_write: false,
+        }
+    }
+}
+
+/// The audit history of an invoice, listing every state change made to it in the order they
+/// happened. Like [`MissingParcelsResponse`](MissingParcelsResponse), the list is embedded in a
+/// table as TOML doesn't support top level arrays
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct InvoiceHistory {
+    #[serde(default)]
+    pub event: Vec<HistoryEvent>,
+}
+
+/// A single recorded change to the state of an invoice
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct HistoryEvent {
+    /// The change that was made
+    pub action: HistoryAction,
+    /// The UNIX timestamp (in seconds) at which the change was recorded
+    pub at: u64,
+    /// The identity that performed the change, if it is known
+    pub by: Option<String>,
+    /// The signatures the invoice had once the change was made
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<HistorySignature>,
+}
+
+impl HistoryEvent {
+    /// Returns a new event for the given action on the invoice, timestamped with the current time
+    pub fn now(action: HistoryAction, by: Option<String>, inv: &Invoice) -> Self {
+        let at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let signatures = inv
+            .signature
+            .iter()
+            .flatten()
+            .map(|s| HistorySignature {
+                by: s.by.clone(),
+                role: s.role,
+                key: s.key.clone(),
+            })
+            .collect();
+        HistoryEvent {
+            action,
+            at,
+            by,
+            signatures,
+        }
+    }
+}
+
+/// The signer of an invoice as recorded in a [`HistoryEvent`](HistoryEvent). The signature itself
+/// isn't kept, as the invoice is what holds it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct HistorySignature {
+    /// The name of the signer
+    pub by: String,
+    /// The role the signature was made in
+    pub role: signature::SignatureRole,
+    /// The base64 encoded public key of the signer
+    pub key: String,
+}
+
+/// A compact summary of an invoice, for UIs and tools that only need to show what a bindle is
+/// without downloading its (possibly very long) list of parcels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct InvoiceSummary {
+    /// Whether the invoice is yanked
+    pub yanked: bool,
+    /// The number of parcels in the invoice
+    pub parcel_count: u64,
+    /// The combined size of all parcels in bytes
+    pub total_size: u64,
+    /// The names of the groups in the invoice
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// The roles the invoice is signed in, without duplicates. The signatures are not verified
+    #[serde(default)]
+    pub signature_roles: Vec<signature::SignatureRole>,
+    /// The ID, description and authors of the bindle
+    pub bindle: BindleSpec,
+}
+
+impl From<&Invoice> for InvoiceSummary {
+    fn from(inv: &Invoice) -> Self {
+        let parcels = inv.parcel.as_deref().unwrap_or_default();
+        let mut signature_roles = Vec::new();
+        for sig in inv.signature.iter().flatten() {
+            if !signature_roles.contains(&sig.role) {
+                signature_roles.push(sig.role);
+            }
+        }
+        InvoiceSummary {
+            yanked: inv.yanked.unwrap_or_default(),
+            parcel_count: parcels.len() as u64,
+            total_size: parcels.iter().map(|p| p.label.size).sum(),
+            groups: inv.group.iter().flatten().map(|g| g.name.clone()).collect(),
+            signature_roles,
+            bindle: inv.bindle.clone(),
+        }
+    }
+}
+
+/// The metadata of a stored invoice, for registry listings that need to show when bindles were
+/// published without downloading their invoices
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct InvoiceMetadata {
+    /// The UNIX timestamp (in seconds) at which the invoice was created, if the provider knows it
+    pub created_at: Option<u64>,
+    /// The UNIX timestamp (in seconds) of the last change to the invoice, such as yanking it or
+    /// adding a signature, if the provider knows it
+    pub modified_at: Option<u64>,
+    /// Whether the invoice is yanked
+    pub yanked: bool,
+    /// The number of parcels in the invoice
+    pub parcel_count: u64,
+    /// The combined size of all parcels in bytes
+    pub total_size: u64,
+    /// The number of signatures on the invoice. The signatures are not verified
+    pub signature_count: u64,
+}
+
+impl InvoiceMetadata {
+    /// Returns the metadata of the invoice, taking the timestamps from its recorded history. An
+    /// empty history, such as that of an invoice created before history was recorded, leaves the
+    /// timestamps unknown
+    pub fn new(inv: &Invoice, history: &InvoiceHistory) -> Self {
+        let parcels = inv.parcel.as_deref().unwrap_or_default();
+        InvoiceMetadata {
+            created_at: history
+                .event
+                .iter()
+                .find(|e| e.action == HistoryAction::Create)
+                .map(|e| e.at),
+            modified_at: history.event.iter().map(|e| e.at).max(),
+            yanked: inv.yanked.unwrap_or_default(),
+            parcel_count: parcels.len() as u64,
+            total_size: parcels.iter().map(|p| p.label.size).sum(),
+            signature_count: inv.signature.as_deref().unwrap_or_default().len() as u64,
+        }
+    }
+}
+
+/// The progress of the background post-processing of a bindle on a server that has a processing
+/// pipeline. The steps are listed in the order they run, after the other fields as TOML requires
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ProcessingStatus {
+    /// The state of the processing as a whole. It failed if any of its steps failed
+    pub state: ProcessingState,
+    /// The UNIX timestamp (in seconds) at which the invoice was created
+    pub created_at: u64,
+    /// The UNIX timestamp (in seconds) at which the last step finished, if processing is done
+    pub finished_at: Option<u64>,
+    #[serde(default)]
+    pub step: Vec<ProcessingStep>,
+}
+
+/// A single step of the post-processing of a bindle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct ProcessingStep {
+    /// The name of the processor running the step
+    pub name: String,
+    pub state: ProcessingState,
+    /// What the step found if it succeeded, or why it failed
+    pub message: Option<String>,
+}
+
+/// The states processing, and each of its steps, goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProcessingState {
+    /// Waiting for missing parcels to be uploaded or for other bindles to finish processing
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl ProcessingState {
+    /// Returns whether processing is done, successfully or not
+    pub fn is_finished(&self) -> bool {
+        matches!(self, ProcessingState::Succeeded | ProcessingState::Failed)
+    }
+}
+
+/// The kinds of changes that are recorded in an [`InvoiceHistory`](InvoiceHistory)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HistoryAction {
+    /// The invoice was created
+    Create,
+    /// The invoice was yanked
+    Yank,
+    /// The signatures of the invoice were replaced, such as when rotating keys
+    Resign,
+    /// A signature was added to the invoice, such as an approver countersigning it
+    Sign,
+}
+
 /// A string error message returned from the server
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ErrorResponse {
     error: String,
+    /// Why an invoice failed signature verification, if that is why the request failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verification: Option<VerificationFailure>,
+    /// The signing policy outcome, if the request failed because the invoice didn't satisfy it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signing_policy: Option<SigningPolicyResult>,
+    /// The quota that would have been exceeded, if that is why the request failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota: Option<QuotaExceeded>,
 }
 
-/// Available options for the query API
-#[derive(Debug, Serialize, Deserialize, Default)]
-#[serde(deny_unknown_fields)]
+/// Describes why the server rejected an invoice because of its signatures
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationFailure {
+    /// The verification strategy of the server
+    pub strategy: signature::VerificationStrategy,
+    /// Why the signatures of the invoice didn't satisfy the strategy
+    pub reason: String,
+}
+
+/// What a storage quota limits
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaResource {
+    /// The total size of the parcels uploaded
+    Bytes,
+    /// The number of invoices created
+    Bindles,
+}
+
+impl std::fmt::Display for QuotaResource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            QuotaResource::Bytes => "bytes",
+            QuotaResource::Bindles => "bindles",
+        })
+    }
+}
+
+/// Describes why the server rejected a request because it would exceed a storage quota
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaExceeded {
+    /// The user whose quota would be exceeded, or `None` for the quota of the whole server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    /// What would exceed the quota
+    pub resource: QuotaResource,
+    /// The limit of the quota
+    pub limit: u64,
+    /// How much of the quota is already used
+    pub used: u64,
+    /// How much the request needed
+    pub requested: u64,
+}
+
+/// The storage used by a user or by the whole server, along with its quota
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaUsage {
+    /// The user the usage belongs to, or `None` for the whole server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    /// The total size of the parcels uploaded
+    pub bytes: u64,
+    /// The number of invoices created
+    pub bindles: u64,
+    /// The maximum number of bytes, if limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// The maximum number of bindles, if limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bindles: Option<u64>,
+}
+
+/// The storage used on a server, as returned by its quota endpoint
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaReport {
+    /// The usage of the whole server
+    pub total: QuotaUsage,
+    /// The usage of every user that created bindles, ordered by name
+    #[serde(default)]
+    pub user: Vec<QuotaUsage>,
+}
+
+/// The prefix of the query parameters that filter on an annotation of the invoice. The rest of the
+/// parameter name is the annotation key, such as `ann.env=prod`
+pub const ANNOTATION_QUERY_PREFIX: &str = "ann.";
+
+/// Available options for the query API. Any number of annotation filters can be given, each as a
+/// separate query parameter, so the options are (de)serialized as a flat map of parameters
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(
+    try_from = "BTreeMap<String, String>",
+    into = "BTreeMap<String, String>"
+)]
 pub struct QueryOptions {
     #[serde(alias = "q")]
     pub query: Option<String>,
@@ -247,6 +884,79 @@ pub struct QueryOptions {
     pub limit: Option<u8>,
     pub strict: Option<bool>,
     pub yanked: Option<bool>,
+    /// Whether to also query the peer registries of a federated server. Ignored by servers that
+    /// aren't configured for federation
+    pub federated: Option<bool>,
+    /// Whether to only return the latest version of each bindle, which is useful for listing all
+    /// bindles without paging through every version of them
+    pub distinct: Option<bool>,
+    /// Only match bindles whose invoice has all of these annotations, with the same values
+    pub annotations: BTreeMap<String, String>,
+    /// A comma separated list of media types. Only bindles with at least one parcel of one of
+    /// these types match
+    pub media_type: Option<String>,
+}
+
+impl TryFrom<BTreeMap<String, String>> for QueryOptions {
+    type Error = String;
+
+    fn try_from(params: BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        fn parse<T: std::str::FromStr>(name: &str, value: String) -> Result<Option<T>, String> {
+            value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid value {} for query parameter {}", value, name))
+        }
+
+        let mut opts = QueryOptions::default();
+        for (name, value) in params {
+            match name.as_str() {
+                "query" | "q" => opts.query = Some(value),
+                "version" | "v" => opts.version = Some(value),
+                "offset" | "o" => opts.offset = parse(&name, value)?,
+                "limit" | "l" => opts.limit = parse(&name, value)?,
+                "strict" => opts.strict = parse(&name, value)?,
+                "yanked" => opts.yanked = parse(&name, value)?,
+                "federated" => opts.federated = parse(&name, value)?,
+                "distinct" => opts.distinct = parse(&name, value)?,
+                "media_type" => opts.media_type = Some(value),
+                _ => match name.strip_prefix(ANNOTATION_QUERY_PREFIX) {
+                    Some(key) if !key.is_empty() => {
+                        opts.annotations.insert(key.to_owned(), value);
+                    }
+                    _ => return Err(format!("unknown query parameter {}", name)),
+                },
+            }
+        }
+        Ok(opts)
+    }
+}
+
+impl From<QueryOptions> for BTreeMap<String, String> {
+    fn from(opts: QueryOptions) -> Self {
+        let mut params: BTreeMap<String, String> = opts
+            .annotations
+            .into_iter()
+            .map(|(key, value)| (format!("{}{}", ANNOTATION_QUERY_PREFIX, key), value))
+            .collect();
+        let fields = vec![
+            ("query", opts.query),
+            ("version", opts.version),
+            ("offset", opts.offset.map(|o| o.to_string())),
+            ("limit", opts.limit.map(|l| l.to_string())),
+            ("strict", opts.strict.map(|s| s.to_string())),
+            ("yanked", opts.yanked.map(|y| y.to_string())),
+            ("federated", opts.federated.map(|f| f.to_string())),
+            ("distinct", opts.distinct.map(|d| d.to_string())),
+            ("media_type", opts.media_type),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                params.insert(name.to_owned(), value);
+            }
+        }
+        params
+    }
 }
 
 impl From<QueryOptions> for SearchOptions {
@@ -257,6 +967,17 @@ impl From<QueryOptions> for SearchOptions {
             offset: qo.offset.unwrap_or(defaults.offset),
             strict: qo.strict.unwrap_or(defaults.strict),
             yanked: qo.yanked.unwrap_or(defaults.yanked),
+            federated: qo.federated.unwrap_or(defaults.federated),
+            distinct: qo.distinct.unwrap_or(defaults.distinct),
+            annotations: qo.annotations,
+            media_types: qo
+                .media_type
+                .iter()
+                .flat_map(|m| m.split(','))
+                .map(str::trim)
+                .filter(|m| !m.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
         }
     }
 }
@@ -319,6 +1040,7 @@ mod test {
             bindle_version: BINDLE_VERSION_1.to_owned(),
             yanked: None,
             annotations: None,
+            requires: None,
             bindle: BindleSpec {
                 id: "foo/1.2.3".parse().unwrap(),
                 description: Some("bar".to_owned()),
@@ -326,6 +1048,7 @@ mod test {
             },
             parcel: parcels,
             group: None,
+            signature: None,
         };
 
         let res = toml::to_string(&inv).unwrap();
@@ -371,6 +1094,40 @@ mod test {
         //assert_eq!(raw, raw2);
     }
 
+    #[test]
+    fn test_query_options() {
+        let opts: QueryOptions = serde_json::from_value(serde_json::json!({
+            "q": "example.com/app",
+            "l": "10",
+            "yanked": "true",
+            "ann.env": "prod",
+            "ann.team": "web",
+            "media_type": "application/wasm, text/html",
+        }))
+        .expect("query options should parse");
+        assert_eq!(Some("example.com/app"), opts.query.as_deref());
+        assert_eq!(Some(10), opts.limit);
+        assert_eq!(Some(true), opts.yanked);
+        assert_eq!("prod", opts.annotations["env"]);
+
+        let params = serde_json::to_value(opts.clone()).unwrap();
+        assert_eq!("web", params["ann.team"]);
+        assert_eq!("10", params["limit"]);
+        assert!(params.get("offset").is_none());
+
+        let search: SearchOptions = opts.into();
+        assert_eq!(2, search.annotations.len());
+        assert_eq!(vec!["application/wasm", "text/html"], search.media_types);
+
+        for invalid in &[
+            serde_json::json!({"l": "many"}),
+            serde_json::json!({"unknown": "true"}),
+            serde_json::json!({"ann.": "empty"}),
+        ] {
+            assert!(serde_json::from_value::<QueryOptions>(invalid.clone()).is_err());
+        }
+    }
+
     #[test]
     fn test_version_comparisons() {
         // Do not need an exhaustive list of matches -- just a sampling to make sure
@@ -432,4 +1189,28 @@ mod test {
         assert!(txt.is_global_group());
         assert!(!txt.member_of("telescopes"));
     }
+
+    #[test]
+    fn test_resolve_parcels() {
+        let raw = read_to_string("test/data/full-invoice.toml").expect("read file contents");
+        let invoice: Invoice = toml::from_str(&raw).expect("clean parse of invoice");
+        let names = |parcels: Vec<Parcel>| -> Vec<String> {
+            parcels.into_iter().map(|p| p.label.name).collect()
+        };
+
+        let parcels = invoice
+            .resolve_parcels(&[], &FeatureMap::new())
+            .expect("should resolve");
+        assert_eq!(vec!["first"], names(parcels));
+
+        // The daemon requires the utility group, which the first parcel already satisfies
+        let parcels = invoice
+            .resolve_parcels(&["server"], &FeatureMap::new())
+            .expect("should resolve");
+        assert_eq!(vec!["daemon", "first"], names(parcels));
+
+        assert!(invoice
+            .resolve_parcels(&["client"], &FeatureMap::new())
+            .is_err());
+    }
 }
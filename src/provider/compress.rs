@@ -0,0 +1,538 @@
+//! A provider wrapper that stores parcels compressed with zstd, to cut the storage costs of
+//! text-heavy parcels. Only available with the `compression` feature enabled.
+//!
+//! Wrapping a provider in a [`CompressedProvider`](CompressedProvider) doesn't change anything for
+//! its callers: parcels are still created and fetched by the SHA and size of their original data,
+//! and invoices are passed through untouched. As providers verify parcel data against the SHA it
+//! is stored under, the compressed data is stored in the wrapped provider under the SHA of the
+//! compressed data instead. A small sidecar file in the metadata directory records the original
+//! SHA and size of each parcel along with the SHA of its compressed data. Parcels that were
+//! already stored in the wrapped provider before it was wrapped don't have a sidecar file and are
+//! served from it as they are.
+//!
+//! Because the wrapped provider only knows the compressed data by a SHA that no invoice
+//! references, its garbage collection would remove all of it. Garbage collection is therefore
+//! refused, and deleting an invoice leaves its compressed parcels behind
+
+use std::convert::TryInto;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::stream::{Stream, StreamExt};
+
+use super::sidecar::{self, Sidecars};
+use super::{Provider, ProviderError, Result};
+use crate::Id;
+
+const PARTIAL_EXTENSION: &str = "zst.part";
+
+/// What is recorded in the sidecar file of a compressed parcel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct ParcelMetadata {
+    /// The SHA of the original data, which the parcel is known by
+    sha256: String,
+    /// The size of the original data in bytes
+    size: u64,
+    /// The SHA the compressed data is stored under in the wrapped provider
+    compressed_sha256: String,
+    /// The size of the compressed data in bytes
+    compressed_size: u64,
+}
+
+impl sidecar::ParcelMetadata for ParcelMetadata {
+    fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// A provider that compresses parcel data before passing it on to the wrapped provider and
+/// decompresses it again when it is read. Invoices are passed through untouched
+#[derive(Clone)]
+pub struct CompressedProvider<P> {
+    inner: P,
+    sidecars: Sidecars<ParcelMetadata>,
+    level: i32,
+}
+
+impl<P: Provider> CompressedProvider<P> {
+    /// Wraps the given provider, keeping the sidecar files of the compressed parcels in the given
+    /// directory. The directory is created when the first parcel is stored
+    pub fn new<D: AsRef<Path>>(inner: P, metadata_dir: D) -> Self {
+        CompressedProvider {
+            inner,
+            sidecars: Sidecars::new(metadata_dir, PARTIAL_EXTENSION),
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+
+    /// Sets the zstd compression level used for new parcels. Higher levels compress better, but
+    /// take longer. Parcels that were already stored are unaffected
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Returns the wrapped provider. Parcels read from it directly are still compressed
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    /// Compresses the data into the given file and stores it in the wrapped provider, returning
+    /// the sidecar metadata for it
+    async fn compress_and_store<R, B>(
+        &self,
+        bindle_id: Id,
+        parcel_id: &str,
+        mut partial: File,
+        data: R,
+    ) -> Result<ParcelMetadata>
+    where
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), self.level)?;
+        let mut original = Sha256::new();
+        let mut compressed = Sha256::new();
+        let mut size = 0u64;
+        let mut compressed_size = 0u64;
+        // Chunks are turned into bytes right away, as the buffer type isn't guaranteed to be Send
+        let mut data = data.map(|res| res.map(|mut chunk| chunk.to_bytes()));
+        while let Some(res) = data.next().await {
+            let chunk = res?;
+            original.update(&chunk);
+            size += chunk.len() as u64;
+            encoder.write_all(&chunk)?;
+            let out = std::mem::take(encoder.get_mut());
+            compressed.update(&out);
+            compressed_size += out.len() as u64;
+            partial.write_all(&out).await?;
+        }
+        let out = encoder.finish()?;
+        compressed.update(&out);
+        compressed_size += out.len() as u64;
+        partial.write_all(&out).await?;
+        partial.flush().await?;
+        drop(partial);
+
+        let actual = format!("{:x}", original.finalize());
+        if actual != parcel_id {
+            return Err(ProviderError::DigestMismatch {
+                expected: parcel_id.to_owned(),
+                actual,
+            });
+        }
+
+        let metadata = ParcelMetadata {
+            sha256: parcel_id.to_owned(),
+            size,
+            compressed_sha256: format!("{:x}", compressed.finalize()),
+            compressed_size,
+        };
+        debug!(
+            "Compressed parcel {} from {} to {} bytes",
+            parcel_id, size, compressed_size
+        );
+        self.sidecars
+            .store_partial(
+                &self.inner,
+                bindle_id,
+                parcel_id,
+                &metadata.compressed_sha256,
+            )
+            .await?;
+        Ok(metadata)
+    }
+}
+
+/// Decompresses the given stream of compressed data
+fn decompress<S>(data: S) -> Result<impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin + Send + Sync,
+{
+    let decoder = Arc::new(Mutex::new(zstd::stream::write::Decoder::new(Vec::new())?));
+    let finisher = decoder.clone();
+    let chunks = data.map(move |res| {
+        let chunk = res?;
+        let mut decoder = decoder.lock().unwrap();
+        decoder.write_all(&chunk)?;
+        Ok(Bytes::from(std::mem::take(decoder.get_mut())))
+    });
+    // Whatever is left in the decoder once all data was read
+    let rest = futures::stream::once(futures::future::lazy(move |_| {
+        let mut decoder = finisher.lock().unwrap();
+        decoder.flush()?;
+        Ok(Bytes::from(std::mem::take(decoder.get_mut())))
+    }));
+    Ok(chunks
+        .chain(rest)
+        .filter(|res| !matches!(res, Ok(b) if b.is_empty())))
+}
+
+#[async_trait::async_trait]
+impl<P> Provider for CompressedProvider<P>
+where
+    P: Provider + Send + Sync,
+{
+    async fn create_invoice(&self, inv: &crate::Invoice) -> Result<Vec<crate::Label>> {
+        self.create_invoice_by(inv, None).await
+    }
+
+    async fn create_invoice_by(
+        &self,
+        inv: &crate::Invoice,
+        by: Option<String>,
+    ) -> Result<Vec<crate::Label>> {
+        self.sidecars.check_sizes(inv).await?;
+        let missing = self.inner.create_invoice_by(inv, by).await?;
+        self.sidecars.filter_missing(missing).await
+    }
+
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_yanked_invoice(id).await
+    }
+
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.yank_invoice(id).await
+    }
+
+    async fn yank_invoice_by<I>(&self, id: I, by: Option<String>) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.yank_invoice_by(id, by).await
+    }
+
+    async fn add_signature<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.add_signature(id, signature).await
+    }
+
+    async fn add_signature_by<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+        by: Option<String>,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.add_signature_by(id, signature, by).await
+    }
+
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.delete_invoice(id).await
+    }
+
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_invoice_history(id).await
+    }
+
+    async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        if self.parcel_exists(&parsed_id, parcel_id).await? {
+            return Err(ProviderError::Exists);
+        }
+        let partial = self.sidecars.create_partial(parcel_id).await?;
+        let stored = self
+            .compress_and_store(parsed_id, parcel_id, partial, data)
+            .await;
+        self.sidecars.finish(parcel_id, stored).await
+    }
+
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let metadata = match self.sidecars.load_metadata(parcel_id).await? {
+            Some(m) => m,
+            None => return self.inner.get_parcel(bindle_id, parcel_id).await,
+        };
+        let data = self
+            .inner
+            .get_parcel(bindle_id, &metadata.compressed_sha256)
+            .await?;
+        Ok(Box::new(decompress(data)?))
+    }
+
+    // Compressed data can't be read from an offset, so only uncompressed parcels are read as a
+    // range from the wrapped provider
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        if self.sidecars.load_metadata(parcel_id).await?.is_none() {
+            return self
+                .inner
+                .get_parcel_range(bindle_id, parcel_id, offset, length)
+                .await;
+        }
+        let data = self.get_parcel(bindle_id, parcel_id).await?;
+        Ok(Box::new(crate::async_util::slice_stream(
+            data, offset, length,
+        )))
+    }
+
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        if self.sidecars.load_metadata(parcel_id).await?.is_some() {
+            return Ok(true);
+        }
+        self.inner.parcel_exists(bindle_id, parcel_id).await
+    }
+
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        self.sidecars.missing_shas(&self.inner, shas).await
+    }
+
+    async fn collect_garbage(&self, _dry_run: bool) -> Result<super::gc::GcReport> {
+        Err(sidecar::refuse_collect_garbage("compressed"))
+    }
+
+    // Expired invoices are deleted like any other, so this is safe to pass through
+    async fn expire_invoices(
+        &self,
+        action: super::expiry::ExpiryAction,
+        dry_run: bool,
+    ) -> Result<super::expiry::ExpiryReport> {
+        self.inner.expire_invoices(action, dry_run).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(all(test, feature = "provider-file"))]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::provider::test_common::{invoice_fixture, parcel_fixture, read_all};
+    use crate::search::NoopEngine;
+
+    use tempfile::tempdir;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_compressed_roundtrip() {
+        let root = tempdir().expect("create tempdir");
+        let store = CompressedProvider::new(
+            FileProvider::new(root.path().join("store"), NoopEngine::default()).await,
+            root.path().join("compressed"),
+        );
+
+        let content = "a text-heavy parcel that compresses well\n".repeat(200);
+        let (label, data) = parcel_fixture(&content).await;
+        let mut inv = invoice_fixture();
+        inv.parcel = Some(vec![crate::Parcel {
+            label: label.clone(),
+            conditions: None,
+        }]);
+        let missing = store
+            .create_invoice(&inv)
+            .await
+            .expect("invoice should be created");
+        assert_eq!(1, missing.len());
+        store
+            .create_parcel(
+                &inv.bindle.id,
+                &label.sha256,
+                FramedRead::new(data, BytesCodec::new()),
+            )
+            .await
+            .expect("parcel should be created");
+
+        assert!(store
+            .parcel_exists(&inv.bindle.id, &label.sha256)
+            .await
+            .unwrap());
+        assert!(store
+            .missing_shas(std::slice::from_ref(&label.sha256))
+            .await
+            .unwrap()
+            .is_empty());
+        let parcel = store
+            .get_parcel(&inv.bindle.id, &label.sha256)
+            .await
+            .expect("parcel should be served");
+        assert_eq!(
+            content.as_bytes(),
+            read_all(parcel).await.unwrap().as_slice()
+        );
+        let range = store
+            .get_parcel_range(&inv.bindle.id, &label.sha256, 41, Some(10))
+            .await
+            .expect("range should be served");
+        assert_eq!(
+            &content.as_bytes()[41..51],
+            read_all(range).await.unwrap().as_slice()
+        );
+
+        // The wrapped provider only has the smaller, compressed data
+        let metadata = store
+            .sidecars
+            .load_metadata(&label.sha256)
+            .await
+            .unwrap()
+            .expect("sidecar file should exist");
+        assert_eq!(label.size, metadata.size);
+        assert!(metadata.compressed_size < metadata.size);
+        let inner = store.into_inner();
+        assert!(!inner
+            .parcel_exists(&inv.bindle.id, &label.sha256)
+            .await
+            .unwrap());
+        assert!(inner
+            .parcel_exists(&inv.bindle.id, &metadata.compressed_sha256)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_checks() {
+        let root = tempdir().expect("create tempdir");
+        let store = CompressedProvider::new(
+            FileProvider::new(root.path().join("store"), NoopEngine::default()).await,
+            root.path().join("compressed"),
+        );
+        let inv = invoice_fixture();
+
+        let (label, data) = parcel_fixture("some data").await;
+        assert!(matches!(
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    "not-the-sha",
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await,
+            Err(ProviderError::DigestMismatch { .. })
+        ));
+        assert!(!store
+            .parcel_exists(&inv.bindle.id, "not-the-sha")
+            .await
+            .unwrap());
+
+        let (_, data) = parcel_fixture("some data").await;
+        store
+            .create_parcel(
+                &inv.bindle.id,
+                &label.sha256,
+                FramedRead::new(data, BytesCodec::new()),
+            )
+            .await
+            .expect("parcel should be created");
+        let (_, data) = parcel_fixture("some data").await;
+        assert!(matches!(
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &label.sha256,
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await,
+            Err(ProviderError::Exists)
+        ));
+
+        // Sizes are checked against the original data
+        let mut inv = invoice_fixture();
+        let mut wrong_size = label.clone();
+        wrong_size.size += 1;
+        inv.parcel = Some(vec![crate::Parcel {
+            label: wrong_size,
+            conditions: None,
+        }]);
+        assert!(matches!(
+            store.create_invoice(&inv).await,
+            Err(ProviderError::SizeMismatch { .. })
+        ));
+        assert!(store.collect_garbage(true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_uncompressed_parcels_are_served() {
+        let root = tempdir().expect("create tempdir");
+        let inner = FileProvider::new(root.path().join("store"), NoopEngine::default()).await;
+        let inv = invoice_fixture();
+        let (label, data) = parcel_fixture("stored before compression").await;
+        inner
+            .create_parcel(
+                &inv.bindle.id,
+                &label.sha256,
+                FramedRead::new(data, BytesCodec::new()),
+            )
+            .await
+            .expect("parcel should be created");
+
+        let store = CompressedProvider::new(inner, root.path().join("compressed"));
+        assert!(store
+            .parcel_exists(&inv.bindle.id, &label.sha256)
+            .await
+            .unwrap());
+        let parcel = store
+            .get_parcel(&inv.bindle.id, &label.sha256)
+            .await
+            .expect("parcel should be served");
+        assert_eq!(
+            b"stored before compression".to_vec(),
+            read_all(parcel).await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,829 @@
+//! A provider wrapper that encrypts parcels (and optionally parts of invoices) before they reach
+//! the wrapped provider, for deployments where the data must be encrypted at rest.
+//!
+//! Wrapping a provider in an [`EncryptedProvider`](EncryptedProvider) doesn't change anything for
+//! its callers. Parcel data is encrypted with ChaCha20-Poly1305 in chunks of 64 KiB, each with its
+//! own random nonce and bound to the parcel SHA and its position, so chunks can't be reordered,
+//! dropped or moved to another parcel without decryption failing. Like with the
+//! [`compress`](super::compress) wrapper, the encrypted data is stored in the wrapped provider
+//! under its own SHA, and a sidecar file in the metadata directory records the original SHA and
+//! size of each parcel. Parcels that were stored before the provider was wrapped are served as
+//! they are.
+//!
+//! The wrapped provider has to be able to read invoices to index and serve them, so they can't be
+//! encrypted as a whole. With [invoice encryption](EncryptedProvider::with_invoice_encryption)
+//! enabled, the free-form parts of new invoices (the description, the authors and all annotations
+//! outside of the reserved `bindle.` namespace) are removed before the invoice is passed on, and
+//! are stored encrypted next to the parcel sidecar files instead. They are added back whenever the
+//! invoice is read through this provider. Search results come from the wrapped provider, so they
+//! don't contain the encrypted parts.
+//!
+//! The key is a 32 byte [`EncryptionKey`](EncryptionKey). Keys that are managed by a KMS can be
+//! fetched or unwrapped by the caller and passed in with
+//! [`EncryptionKey::from_bytes`](EncryptionKey::from_bytes). Losing the key means losing all of the
+//! encrypted data.
+//!
+//! Garbage collection is refused for the same reason as for compressed parcels: the wrapped
+//! provider only knows the encrypted data by a SHA that no invoice references
+
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use log::{debug, warn};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::stream::{Stream, StreamExt};
+
+use super::sidecar::{self, Sidecars};
+use super::{Provider, ProviderError, Result};
+use crate::{AnnotationMap, Id};
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// The size of the plaintext chunks parcel data is encrypted in
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The size of an encrypted chunk, which is prefixed with its nonce
+const FRAME_SIZE: usize = NONCE_SIZE + CHUNK_SIZE + TAG_SIZE;
+/// The annotation namespace that is left in the clear, as providers act on some of its keys
+const RESERVED_ANNOTATION_PREFIX: &str = "bindle.";
+const PARCEL_DIR: &str = "parcels";
+const INVOICE_DIR: &str = "invoices";
+const PARTIAL_EXTENSION: &str = "enc.part";
+
+/// A key for an [`EncryptedProvider`](EncryptedProvider)
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_SIZE]);
+
+impl EncryptionKey {
+    /// Generates a new random key
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+        EncryptionKey(key)
+    }
+
+    /// Uses the given raw key, which must be exactly 32 bytes long
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        if raw.len() != KEY_SIZE {
+            return Err(ProviderError::Other(format!(
+                "Encryption key must be {} bytes long, got {}",
+                KEY_SIZE,
+                raw.len()
+            )));
+        }
+        let mut key = [0u8; KEY_SIZE];
+        key.copy_from_slice(raw);
+        Ok(EncryptionKey(key))
+    }
+
+    /// Loads a base64 encoded key from the given environment variable
+    pub fn from_env(var: &str) -> Result<Self> {
+        let encoded = std::env::var(var).map_err(|e| {
+            ProviderError::Other(format!("Unable to read encryption key from {}: {}", var, e))
+        })?;
+        let raw = base64::decode(encoded.trim()).map_err(|e| {
+            ProviderError::Other(format!("Encryption key in {} is not base64: {}", var, e))
+        })?;
+        Self::from_bytes(&raw)
+    }
+
+    /// Returns the key as base64, such as for storing a [generated](Self::generate) key in a
+    /// secret store
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0)
+    }
+}
+
+// Keeps keys out of logs
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// What is recorded in the sidecar file of an encrypted parcel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct ParcelMetadata {
+    /// The SHA of the original data, which the parcel is known by
+    sha256: String,
+    /// The size of the original data in bytes
+    size: u64,
+    /// The SHA the encrypted data is stored under in the wrapped provider
+    encrypted_sha256: String,
+}
+
+impl sidecar::ParcelMetadata for ParcelMetadata {
+    fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// The parts of an invoice that are removed before it is passed to the wrapped provider
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InvoiceSecrets {
+    description: Option<String>,
+    authors: Option<Vec<String>>,
+    annotations: Option<AnnotationMap>,
+}
+
+impl InvoiceSecrets {
+    /// Moves the free-form parts out of the invoice
+    fn take(inv: &mut crate::Invoice) -> Self {
+        let (clear, secret): (AnnotationMap, AnnotationMap) = inv
+            .annotations
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .partition(|(k, _)| k.starts_with(RESERVED_ANNOTATION_PREFIX));
+        inv.annotations = Some(clear).filter(|a| !a.is_empty());
+        InvoiceSecrets {
+            description: inv.bindle.description.take(),
+            authors: inv.bindle.authors.take(),
+            annotations: Some(secret).filter(|a| !a.is_empty()),
+        }
+    }
+
+    /// Puts the free-form parts back into the invoice
+    fn restore(self, inv: &mut crate::Invoice) {
+        inv.bindle.description = self.description;
+        inv.bindle.authors = self.authors;
+        if let Some(secret) = self.annotations {
+            inv.annotations
+                .get_or_insert_with(AnnotationMap::new)
+                .extend(secret);
+        }
+    }
+}
+
+/// A provider that encrypts parcel data before passing it on to the wrapped provider and decrypts
+/// it again when it is read
+#[derive(Clone)]
+pub struct EncryptedProvider<P> {
+    inner: P,
+    metadata_dir: PathBuf,
+    sidecars: Sidecars<ParcelMetadata>,
+    cipher: ChaCha20Poly1305,
+    encrypt_invoices: bool,
+}
+
+impl<P: Provider> EncryptedProvider<P> {
+    /// Wraps the given provider, keeping the sidecar files of the encrypted parcels in the given
+    /// directory. The directory is created when the first parcel is stored
+    pub fn new<D: AsRef<Path>>(inner: P, metadata_dir: D, key: EncryptionKey) -> Self {
+        let metadata_dir = metadata_dir.as_ref().to_owned();
+        EncryptedProvider {
+            inner,
+            sidecars: Sidecars::new(metadata_dir.join(PARCEL_DIR), PARTIAL_EXTENSION),
+            metadata_dir,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key.0)),
+            encrypt_invoices: false,
+        }
+    }
+
+    /// Sets whether the free-form parts of new invoices are encrypted as well. Invoices that were
+    /// stored with encrypted parts are always decrypted, whether this is enabled or not
+    pub fn with_invoice_encryption(mut self, enabled: bool) -> Self {
+        self.encrypt_invoices = enabled;
+        self
+    }
+
+    /// Returns the wrapped provider. Parcels read from it directly are still encrypted
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn secrets_path(&self, id: &Id) -> PathBuf {
+        self.metadata_dir
+            .join(INVOICE_DIR)
+            .join(format!("{}.enc", id.sha()))
+    }
+
+    /// Loads and decrypts the encrypted parts of the given invoice, returning `None` if it has
+    /// none
+    async fn load_secrets(&self, id: &Id) -> Result<Option<InvoiceSecrets>> {
+        let sealed = match tokio::fs::read(self.secrets_path(id)).await {
+            Ok(s) => s,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let raw = open(&self.cipher, id.sha().as_bytes(), &sealed)
+            .ok_or_else(|| ProviderError::Other(format!("Unable to decrypt invoice {}", id)))?;
+        Ok(Some(toml::from_slice(&raw)?))
+    }
+
+    async fn store_secrets(&self, id: &Id, secrets: &InvoiceSecrets) -> Result<()> {
+        let path = self.secrets_path(id);
+        tokio::fs::create_dir_all(self.metadata_dir.join(INVOICE_DIR)).await?;
+        let sealed = seal(&self.cipher, id.sha().as_bytes(), &toml::to_vec(secrets)?)?;
+        let temp = path.with_extension("enc.part");
+        tokio::fs::write(&temp, sealed).await?;
+        tokio::fs::rename(&temp, &path).await?;
+        Ok(())
+    }
+
+    async fn remove_secrets(&self, id: &Id) -> Result<()> {
+        match tokio::fs::remove_file(self.secrets_path(id)).await {
+            Ok(_) => Ok(()),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Adds the encrypted parts back into an invoice read from the wrapped provider
+    async fn restore(&self, mut inv: crate::Invoice) -> Result<crate::Invoice> {
+        if let Some(secrets) = self.load_secrets(&inv.bindle.id).await? {
+            secrets.restore(&mut inv);
+        }
+        Ok(inv)
+    }
+
+    /// Encrypts the data into the given file and stores it in the wrapped provider, returning the
+    /// sidecar metadata for it
+    async fn encrypt_and_store<R, B>(
+        &self,
+        bindle_id: Id,
+        parcel_id: &str,
+        mut partial: File,
+        data: R,
+    ) -> Result<ParcelMetadata>
+    where
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        let mut encryptor = Encryptor::new(self.cipher.clone(), parcel_id);
+        let mut original = Sha256::new();
+        let mut encrypted = Sha256::new();
+        let mut size = 0u64;
+        let mut data = data.map(|res| res.map(|mut chunk| chunk.to_bytes()));
+        while let Some(res) = data.next().await {
+            let chunk = res?;
+            original.update(&chunk);
+            size += chunk.len() as u64;
+            let out = encryptor.push(&chunk)?;
+            encrypted.update(&out);
+            partial.write_all(&out).await?;
+        }
+        let out = encryptor.finish()?;
+        encrypted.update(&out);
+        partial.write_all(&out).await?;
+        partial.flush().await?;
+        drop(partial);
+
+        let actual = format!("{:x}", original.finalize());
+        if actual != parcel_id {
+            return Err(ProviderError::DigestMismatch {
+                expected: parcel_id.to_owned(),
+                actual,
+            });
+        }
+
+        let metadata = ParcelMetadata {
+            sha256: parcel_id.to_owned(),
+            size,
+            encrypted_sha256: format!("{:x}", encrypted.finalize()),
+        };
+        debug!(
+            "Encrypted parcel {} as {}",
+            parcel_id, metadata.encrypted_sha256
+        );
+        self.sidecars
+            .store_partial(
+                &self.inner,
+                bindle_id,
+                parcel_id,
+                &metadata.encrypted_sha256,
+            )
+            .await?;
+        Ok(metadata)
+    }
+}
+
+/// Encrypts the data with a random nonce, returning the nonce followed by the encrypted data
+fn seal(cipher: &ChaCha20Poly1305, aad: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let encrypted = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg, aad })
+        .map_err(|_| ProviderError::Other("Unable to encrypt data".to_string()))?;
+    let mut out = nonce.to_vec();
+    out.extend(encrypted);
+    Ok(out)
+}
+
+/// Decrypts data produced by [`seal`](seal), returning `None` if it was tampered with or
+/// encrypted with another key
+fn open(cipher: &ChaCha20Poly1305, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE + TAG_SIZE {
+        return None;
+    }
+    let (nonce, msg) = sealed.split_at(NONCE_SIZE);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+        .ok()
+}
+
+/// The associated data of a chunk, which ties it to its parcel and position. The last chunk is
+/// marked, so a parcel can't be truncated at a chunk boundary
+fn chunk_aad(sha: &str, index: u64, last: bool) -> Vec<u8> {
+    let mut aad = sha.as_bytes().to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+/// Splits plaintext into chunks and encrypts them. The last chunk is only encrypted once all data
+/// was pushed, as it may be anywhere from empty to a full chunk
+struct Encryptor {
+    cipher: ChaCha20Poly1305,
+    sha: String,
+    index: u64,
+    buffer: Vec<u8>,
+}
+
+impl Encryptor {
+    fn new(cipher: ChaCha20Poly1305, sha: &str) -> Self {
+        Encryptor {
+            cipher,
+            sha: sha.to_owned(),
+            index: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut out = Vec::new();
+        while self.buffer.len() > CHUNK_SIZE {
+            let chunk: Vec<u8> = self.buffer.drain(..CHUNK_SIZE).collect();
+            out.extend(seal(
+                &self.cipher,
+                &chunk_aad(&self.sha, self.index, false),
+                &chunk,
+            )?);
+            self.index += 1;
+        }
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let chunk = std::mem::take(&mut self.buffer);
+        seal(
+            &self.cipher,
+            &chunk_aad(&self.sha, self.index, true),
+            &chunk,
+        )
+    }
+}
+
+/// The counterpart of [`Encryptor`](Encryptor)
+struct Decryptor {
+    cipher: ChaCha20Poly1305,
+    sha: String,
+    index: u64,
+    buffer: Vec<u8>,
+}
+
+impl Decryptor {
+    fn new(cipher: ChaCha20Poly1305, sha: &str) -> Self {
+        Decryptor {
+            cipher,
+            sha: sha.to_owned(),
+            index: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn open_chunk(&mut self, frame: &[u8], last: bool) -> Result<Vec<u8>> {
+        let chunk = open(&self.cipher, &chunk_aad(&self.sha, self.index, last), frame).ok_or_else(
+            || ProviderError::Other(format!("Unable to decrypt parcel {}", self.sha)),
+        )?;
+        self.index += 1;
+        Ok(chunk)
+    }
+
+    fn push(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut out = Vec::new();
+        while self.buffer.len() > FRAME_SIZE {
+            let frame: Vec<u8> = self.buffer.drain(..FRAME_SIZE).collect();
+            out.extend(self.open_chunk(&frame, false)?);
+        }
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let frame = std::mem::take(&mut self.buffer);
+        self.open_chunk(&frame, true)
+    }
+}
+
+/// Decrypts the given stream of encrypted data
+fn decrypt<S>(
+    cipher: ChaCha20Poly1305,
+    sha: &str,
+    data: S,
+) -> impl Stream<Item = Result<Bytes>> + Unpin + Send + Sync
+where
+    S: Stream<Item = Result<Bytes>> + Unpin + Send + Sync,
+{
+    let decryptor = Arc::new(Mutex::new(Decryptor::new(cipher, sha)));
+    let finisher = decryptor.clone();
+    let chunks = data.map(move |res| {
+        let chunk = res?;
+        Ok(Bytes::from(decryptor.lock().unwrap().push(&chunk)?))
+    });
+    // The last chunk, which is left in the decryptor once all data was read
+    let rest = futures::stream::once(futures::future::lazy(move |_| {
+        Ok(Bytes::from(finisher.lock().unwrap().finish()?))
+    }));
+    chunks
+        .chain(rest)
+        .filter(|res| !matches!(res, Ok(b) if b.is_empty()))
+}
+
+#[async_trait::async_trait]
+impl<P> Provider for EncryptedProvider<P>
+where
+    P: Provider + Send + Sync,
+{
+    async fn create_invoice(&self, inv: &crate::Invoice) -> Result<Vec<crate::Label>> {
+        self.create_invoice_by(inv, None).await
+    }
+
+    async fn create_invoice_by(
+        &self,
+        inv: &crate::Invoice,
+        by: Option<String>,
+    ) -> Result<Vec<crate::Label>> {
+        self.sidecars.check_sizes(inv).await?;
+        let created = if self.encrypt_invoices {
+            let mut redacted = inv.clone();
+            let secrets = InvoiceSecrets::take(&mut redacted);
+            // The secrets are written first so the invoice is never readable without them. This
+            // must not replace the secrets of an invoice that already exists, and the secrets are
+            // removed again if the wrapped provider doesn't store the invoice
+            match self.inner.get_yanked_invoice(&inv.bindle.id).await {
+                Err(ProviderError::NotFound) => (),
+                Ok(_) => return Err(ProviderError::Exists),
+                Err(e) => return Err(e),
+            }
+            self.store_secrets(&inv.bindle.id, &secrets).await?;
+            match self.inner.create_invoice_by(&redacted, by).await {
+                Ok(created) => created,
+                Err(e) => {
+                    if let Err(cleanup) = self.remove_secrets(&inv.bindle.id).await {
+                        warn!(
+                            "Unable to remove the secrets of invoice {} that wasn't created: {}",
+                            inv.bindle.id, cleanup
+                        );
+                    }
+                    return Err(e);
+                }
+            }
+        } else {
+            self.inner.create_invoice_by(inv, by).await?
+        };
+        self.sidecars.filter_missing(created).await
+    }
+
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let inv = self.inner.get_yanked_invoice(id).await?;
+        self.restore(inv).await
+    }
+
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.yank_invoice(id).await
+    }
+
+    async fn yank_invoice_by<I>(&self, id: I, by: Option<String>) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.yank_invoice_by(id, by).await
+    }
+
+    // Signatures only cover the name, version and parcels of an invoice, so they are valid for
+    // the invoice the wrapped provider has as well
+    async fn add_signature<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let inv = self.inner.add_signature(id, signature).await?;
+        self.restore(inv).await
+    }
+
+    async fn add_signature_by<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+        by: Option<String>,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let inv = self.inner.add_signature_by(id, signature, by).await?;
+        self.restore(inv).await
+    }
+
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let removed = self.inner.delete_invoice(&parsed_id).await?;
+        self.remove_secrets(&parsed_id).await?;
+        Ok(removed)
+    }
+
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_invoice_history(id).await
+    }
+
+    async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        if self.parcel_exists(&parsed_id, parcel_id).await? {
+            return Err(ProviderError::Exists);
+        }
+        let partial = self.sidecars.create_partial(parcel_id).await?;
+        let stored = self
+            .encrypt_and_store(parsed_id, parcel_id, partial, data)
+            .await;
+        self.sidecars.finish(parcel_id, stored).await
+    }
+
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let metadata = match self.sidecars.load_metadata(parcel_id).await? {
+            Some(m) => m,
+            None => return self.inner.get_parcel(bindle_id, parcel_id).await,
+        };
+        let data = self
+            .inner
+            .get_parcel(bindle_id, &metadata.encrypted_sha256)
+            .await?;
+        Ok(Box::new(decrypt(self.cipher.clone(), parcel_id, data)))
+    }
+
+    // Every chunk has to be decrypted from the start to check that none are missing, so only
+    // unencrypted parcels are read as a range from the wrapped provider
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        if self.sidecars.load_metadata(parcel_id).await?.is_none() {
+            return self
+                .inner
+                .get_parcel_range(bindle_id, parcel_id, offset, length)
+                .await;
+        }
+        let data = self.get_parcel(bindle_id, parcel_id).await?;
+        Ok(Box::new(crate::async_util::slice_stream(
+            data, offset, length,
+        )))
+    }
+
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        if self.sidecars.load_metadata(parcel_id).await?.is_some() {
+            return Ok(true);
+        }
+        self.inner.parcel_exists(bindle_id, parcel_id).await
+    }
+
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        self.sidecars.missing_shas(&self.inner, shas).await
+    }
+
+    async fn collect_garbage(&self, _dry_run: bool) -> Result<super::gc::GcReport> {
+        Err(sidecar::refuse_collect_garbage("encrypted"))
+    }
+
+    // The expiry annotation is in the reserved namespace, so the wrapped provider still sees it
+    async fn expire_invoices(
+        &self,
+        action: super::expiry::ExpiryAction,
+        dry_run: bool,
+    ) -> Result<super::expiry::ExpiryReport> {
+        self.inner.expire_invoices(action, dry_run).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(all(test, feature = "provider-file"))]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::provider::test_common::{invoice_fixture, parcel_fixture, read_all};
+    use crate::search::NoopEngine;
+
+    use tempfile::tempdir;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_encrypted_roundtrip() {
+        let root = tempdir().expect("create tempdir");
+        let key = EncryptionKey::generate();
+        let store = EncryptedProvider::new(
+            FileProvider::new(root.path().join("store"), NoopEngine::default()).await,
+            root.path().join("encrypted"),
+            key.clone(),
+        );
+
+        // Large enough to span a few chunks
+        let content = "a secret parcel spanning several chunks\n".repeat(5000);
+        let (label, data) = parcel_fixture(&content).await;
+        let mut inv = invoice_fixture();
+        inv.parcel = Some(vec![crate::Parcel {
+            label: label.clone(),
+            conditions: None,
+        }]);
+        store
+            .create_parcel(
+                &inv.bindle.id,
+                &label.sha256,
+                FramedRead::new(data, BytesCodec::new()),
+            )
+            .await
+            .expect("parcel should be created");
+        assert!(store
+            .create_invoice(&inv)
+            .await
+            .expect("invoice should be created")
+            .is_empty());
+
+        let parcel = store
+            .get_parcel(&inv.bindle.id, &label.sha256)
+            .await
+            .expect("parcel should be served");
+        assert_eq!(
+            content.as_bytes(),
+            read_all(parcel).await.unwrap().as_slice()
+        );
+        let range = store
+            .get_parcel_range(&inv.bindle.id, &label.sha256, 70000, Some(100))
+            .await
+            .expect("range should be served");
+        assert_eq!(
+            &content.as_bytes()[70000..70100],
+            read_all(range).await.unwrap().as_slice()
+        );
+
+        // The wrapped provider only has the encrypted data, which is useless with another key
+        let metadata = store
+            .sidecars
+            .load_metadata(&label.sha256)
+            .await
+            .unwrap()
+            .unwrap();
+        let inner = store.into_inner();
+        let encrypted = read_all(
+            inner
+                .get_parcel(&inv.bindle.id, &metadata.encrypted_sha256)
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(!encrypted
+            .windows(16)
+            .any(|w| w == &content.as_bytes()[..16]));
+        let other = EncryptedProvider::new(
+            inner,
+            root.path().join("encrypted"),
+            EncryptionKey::generate(),
+        );
+        let parcel = other
+            .get_parcel(&inv.bindle.id, &label.sha256)
+            .await
+            .expect("encrypted data should be found");
+        assert!(read_all(parcel).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_invoice() {
+        let root = tempdir().expect("create tempdir");
+        let store = EncryptedProvider::new(
+            FileProvider::new(root.path().join("store"), NoopEngine::default()).await,
+            root.path().join("encrypted"),
+            EncryptionKey::generate(),
+        )
+        .with_invoice_encryption(true);
+
+        let mut inv = invoice_fixture();
+        inv.bindle.description = Some("top secret".to_owned());
+        let mut annotations = AnnotationMap::new();
+        annotations.insert("customer".to_owned(), "ACME".to_owned());
+        annotations.insert(crate::annotations::LICENSE.to_owned(), "MIT".to_owned());
+        inv.annotations = Some(annotations);
+        store
+            .create_invoice(&inv)
+            .await
+            .expect("invoice should be created");
+
+        let stored = store.get_yanked_invoice(&inv.bindle.id).await.unwrap();
+        assert_eq!(Some("top secret"), stored.bindle.description.as_deref());
+        assert_eq!(inv.annotations, stored.annotations);
+
+        // Only the reserved annotations reach the wrapped provider
+        let redacted = store
+            .inner
+            .get_yanked_invoice(&inv.bindle.id)
+            .await
+            .unwrap();
+        assert_eq!(None, redacted.bindle.description);
+        assert_eq!(Some("MIT"), redacted.license());
+        assert_eq!(None, redacted.annotation("customer"));
+
+        // Creating the invoice again must keep the secrets of the stored one
+        let mut duplicate = inv.clone();
+        duplicate.bindle.description = Some("overwritten".to_owned());
+        assert!(matches!(
+            store.create_invoice(&duplicate).await,
+            Err(ProviderError::Exists)
+        ));
+        let stored = store.get_yanked_invoice(&inv.bindle.id).await.unwrap();
+        assert_eq!(Some("top secret"), stored.bindle.description.as_deref());
+
+        // Secrets of an invoice the wrapped provider refuses are removed again
+        let mut yanked = invoice_fixture();
+        yanked.bindle.id = "yanked/1.0.0".parse().unwrap();
+        yanked.bindle.description = Some("never stored".to_owned());
+        yanked.yanked = Some(true);
+        assert!(matches!(
+            store.create_invoice(&yanked).await,
+            Err(ProviderError::CreateYanked)
+        ));
+        assert!(!store.secrets_path(&yanked.bindle.id).exists());
+    }
+
+    #[test]
+    fn test_key_loading() {
+        assert!(EncryptionKey::from_bytes(&[0u8; 16]).is_err());
+        let key = EncryptionKey::generate();
+        std::env::set_var("BINDLE_TEST_ENCRYPTION_KEY", key.to_base64());
+        let loaded = EncryptionKey::from_env("BINDLE_TEST_ENCRYPTION_KEY").unwrap();
+        assert_eq!(key.0, loaded.0);
+        assert!(!format!("{:?}", loaded).contains(&key.to_base64()));
+    }
+}
@@ -0,0 +1,107 @@
+//! Expiry of temporary bindles.
+//!
+//! Bindles that are only needed for a while, such as preview builds of pull requests, can be
+//! given an [`EXPIRES_AT`](crate::annotations::EXPIRES_AT) annotation with the UNIX timestamp at
+//! which they are no longer needed. Providers that can list everything they store sweep expired
+//! invoices in [`Provider::expire_invoices`](super::Provider::expire_invoices), either yanking them
+//! (so existing users can still fetch them) or deleting them along with their parcels, depending
+//! on the [`ExpiryAction`](ExpiryAction). Bindles are only swept when asked to, usually by
+//! [`expire_periodically`](expire_periodically) running in the background, so an expired bindle
+//! can still be served until the next sweep
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::Provider;
+use crate::Id;
+
+/// What happens to an invoice once it expired. Parses from `yank` or `delete`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryAction {
+    /// The invoice is yanked, which is the default. Its parcels are kept, so anything already
+    /// using the bindle keeps working
+    #[default]
+    Yank,
+    /// The invoice is deleted, along with the parcels no other invoice references
+    Delete,
+}
+
+impl FromStr for ExpiryAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yank" => Ok(ExpiryAction::Yank),
+            "delete" => Ok(ExpiryAction::Delete),
+            _ => Err(format!(
+                "Unknown expiry action {}, must be one of yank or delete",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ExpiryAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExpiryAction::Yank => "yank",
+            ExpiryAction::Delete => "delete",
+        })
+    }
+}
+
+/// The outcome of a sweep for expired invoices
+#[derive(Debug, Clone, Default)]
+pub struct ExpiryReport {
+    /// Whether this was a dry run, in which case nothing was actually changed
+    pub dry_run: bool,
+    /// What was done to the expired invoices
+    pub action: ExpiryAction,
+    /// The number of invoices that were checked
+    pub checked: u64,
+    /// The IDs of the invoices that were yanked or deleted (or would have been, for a dry run).
+    /// Invoices that were already yanked are only listed if they were deleted
+    pub expired: Vec<Id>,
+}
+
+/// Returns the current UNIX timestamp in seconds, which expiry timestamps are compared to
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Sweeps the store for expired invoices every `interval`, logging the outcome of each run. This
+/// never returns, so it should be spawned as a background task, which stops when the task is
+/// dropped
+pub async fn expire_periodically<P: Provider + Sync>(
+    store: P,
+    interval: Duration,
+    action: ExpiryAction,
+) {
+    loop {
+        tokio::time::delay_for(interval).await;
+        match store.expire_invoices(action, false).await {
+            Ok(report) if report.expired.is_empty() => (),
+            Ok(report) => info!(
+                "Expired {} of {} invoices ({}): {}",
+                report.expired.len(),
+                report.checked,
+                report.action,
+                report
+                    .expired
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Err(e) => error!("Sweeping expired invoices failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Configurable layouts for where a [`FileProvider`](super::FileProvider) keeps invoices and
+//! parcels on disk.
+//!
+//! By default, every invoice and every parcel gets a directory right below `invoices/` and
+//! `parcels/`. File systems get slow once a single directory holds millions of entries, so a
+//! [`StorageLayout`](StorageLayout) can spread them over shard directories named after parts of
+//! their canonical name (or SHA, for parcels), and can put invoices and parcels on different
+//! volumes. Each kind of object is placed with a [`PathTemplate`](PathTemplate) like
+//! `parcels/{name[0..2]}/{name}`, which puts a parcel with the SHA `abcdef...` in
+//! `parcels/ab/abcdef...`.
+//!
+//! The layout of a store is recorded in a `layout.toml` file at its root when it is created, as
+//! data can't be found with any other layout. Moving data between layouts isn't supported
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tokio::stream::{Stream, StreamExt};
+
+use crate::provider::{ProviderError, Result};
+
+/// The placeholder for the full name of an object
+const NAME_PLACEHOLDER: &str = "{name}";
+
+/// Where invoice and parcel directories are placed, relative to the root of the store. Both
+/// templates must have different base directories that aren't inside each other, otherwise
+/// invoices and parcels would be mixed up
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageLayout {
+    pub invoices: PathTemplate,
+    pub parcels: PathTemplate,
+}
+
+impl Default for StorageLayout {
+    fn default() -> Self {
+        StorageLayout {
+            invoices: "invoices/{name}".parse().unwrap(),
+            parcels: "parcels/{name}".parse().unwrap(),
+        }
+    }
+}
+
+impl StorageLayout {
+    /// Checks that invoices and parcels can't end up in the same directories
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        let (invoices, parcels) = (&self.invoices.base, &self.parcels.base);
+        if invoices.starts_with(parcels) || parcels.starts_with(invoices) {
+            return Err(format!(
+                "The invoice template {} and the parcel template {} must use separate directories",
+                self.invoices, self.parcels
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A template for the path of the directory of an object, with placeholders for its name. The
+/// last directory of the template must be `{name}`, the full name. Shard directories before it
+/// can use `{name[START..END]}`, the characters of the name from `START` up to (not including)
+/// `END`, along with any other text. Directories without placeholders are only allowed before the
+/// first shard directory. Relative templates are resolved against the root of the store, while
+/// absolute ones can point anywhere, such as another volume
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathTemplate {
+    raw: String,
+    /// The directory all objects are placed in
+    base: PathBuf,
+    /// The shard directories between the base directory and the object directory
+    shards: Vec<Vec<Part>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    /// A range of the characters of the name
+    Slice(usize, usize),
+}
+
+impl PathTemplate {
+    /// Returns the directory of the object with the given name in a store with the given root
+    pub(crate) fn path(&self, root: &Path, name: &str) -> PathBuf {
+        let mut path = root.join(&self.base);
+        for shard in &self.shards {
+            let dir: String = shard
+                .iter()
+                .map(|part| match part {
+                    Part::Text(text) => text.clone(),
+                    Part::Slice(start, end) => {
+                        name.chars().skip(*start).take(end - start).collect()
+                    }
+                })
+                .collect();
+            path.push(dir);
+        }
+        path.push(name);
+        path
+    }
+
+    /// Returns the names of all objects stored with this template, or nothing if there aren't any
+    /// yet. The shard directories are read up front, while the objects are read as the stream is
+    /// polled
+    pub(crate) async fn names(
+        &self,
+        root: &Path,
+    ) -> Result<Box<dyn Stream<Item = std::io::Result<String>> + Unpin + Send>> {
+        let mut dirs = vec![root.join(&self.base)];
+        for _ in &self.shards {
+            let mut next = Vec::new();
+            for dir in dirs {
+                let mut entries = match tokio::fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(entry) = entries.next().await {
+                    let entry = entry?;
+                    if entry.file_type().await?.is_dir() {
+                        next.push(entry.path());
+                    }
+                }
+            }
+            dirs = next;
+        }
+        let mut names: Box<dyn Stream<Item = std::io::Result<String>> + Unpin + Send> =
+            Box::new(tokio::stream::empty());
+        for dir in dirs {
+            names = Box::new(names.chain(read_dir_names(&dir).await?));
+        }
+        Ok(names)
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("Invalid path template {}: {}", s, reason);
+        let path = Path::new(s);
+        let mut components: Vec<Component> = path.components().collect();
+        match components.pop() {
+            Some(Component::Normal(last)) if last == NAME_PLACEHOLDER => (),
+            _ => return Err(invalid("it must end with a {name} directory")),
+        }
+        let mut base = PathBuf::new();
+        let mut shards = Vec::new();
+        for component in components {
+            let dir = match component {
+                Component::Normal(dir) => dir
+                    .to_str()
+                    .ok_or_else(|| invalid("it must be valid UTF-8"))?,
+                Component::CurDir => continue,
+                Component::ParentDir => return Err(invalid("it can't contain ..")),
+                // The root (and on Windows the prefix) of absolute templates
+                c => {
+                    base.push(c);
+                    continue;
+                }
+            };
+            let parts = parse_shard(dir).map_err(|e| invalid(&e))?;
+            match parts.as_slice() {
+                [Part::Text(text)] if shards.is_empty() => base.push(text),
+                [Part::Text(_)] => {
+                    return Err(invalid(
+                        "directories without a placeholder must come before the shards",
+                    ))
+                }
+                _ => shards.push(parts),
+            }
+        }
+        if base
+            .components()
+            .all(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(invalid(
+                "objects must be placed in a directory below the root of the store",
+            ));
+        }
+        Ok(PathTemplate {
+            raw: s.to_owned(),
+            base,
+            shards,
+        })
+    }
+}
+
+/// Parses a shard directory into its text and `{name[START..END]}` placeholders
+fn parse_shard(dir: &str) -> std::result::Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut rest = dir;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Text(rest[..start].to_owned()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed placeholder in {}", dir))?
+            + start;
+        let placeholder = &rest[start + 1..end];
+        let range = placeholder
+            .strip_prefix("name[")
+            .and_then(|r| r.strip_suffix(']'))
+            .ok_or_else(|| {
+                format!(
+                    "unknown placeholder {{{}}}, shards can only use {{name[START..END]}}",
+                    placeholder
+                )
+            })?;
+        let (from, to) = match range.find("..") {
+            Some(i) => (&range[..i], &range[i + 2..]),
+            None => return Err(format!("{} is not a range like 0..2", range)),
+        };
+        match (from.parse::<usize>(), to.parse::<usize>()) {
+            (Ok(from), Ok(to)) if from < to => parts.push(Part::Slice(from, to)),
+            _ => return Err(format!("{} is not a range like 0..2", range)),
+        }
+        rest = &rest[end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(format!("unopened placeholder in {}", dir));
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest.to_owned()));
+    }
+    Ok(parts)
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PathTemplate> for String {
+    fn from(template: PathTemplate) -> Self {
+        template.raw
+    }
+}
+
+impl fmt::Display for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+/// Returns the names of the entries in the given directory, or nothing if the directory doesn't
+/// exist yet
+async fn read_dir_names(
+    dir: &Path,
+) -> Result<Box<dyn Stream<Item = std::io::Result<String>> + Unpin + Send>> {
+    match tokio::fs::read_dir(dir).await {
+        Ok(entries) => {
+            Ok(Box::new(entries.map(|e| {
+                e.map(|e| e.file_name().to_string_lossy().into_owned())
+            })))
+        }
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => {
+            Ok(Box::new(tokio::stream::empty()))
+        }
+        Err(e) => Err(ProviderError::from(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path_templates() {
+        let template: PathTemplate = "parcels/{name[0..2]}/x{name[2..4]}/{name}".parse().unwrap();
+        assert_eq!(
+            PathBuf::from("/data/parcels/ab/xcd/abcdef"),
+            template.path(Path::new("/data"), "abcdef")
+        );
+        // Short names get short shards rather than failing
+        assert_eq!(
+            PathBuf::from("/data/parcels/a/x/a"),
+            template.path(Path::new("/data"), "a")
+        );
+
+        let template: PathTemplate = "/mnt/parcels/./{name}".parse().unwrap();
+        assert_eq!(
+            PathBuf::from("/mnt/parcels/abc"),
+            template.path(Path::new("/data"), "abc")
+        );
+
+        for invalid in &[
+            "parcels",
+            "parcels/{name}/data",
+            "{name}",
+            "/{name}",
+            "../parcels/{name}",
+            "parcels/{name[0..2]}/static/{name}",
+            "parcels/{name[2..2]}/{name}",
+            "parcels/{sha[0..2]}/{name}",
+            "parcels/{name[0..2]/{name}",
+        ] {
+            assert!(
+                invalid.parse::<PathTemplate>().is_err(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+
+        let layout: StorageLayout = toml::from_str(
+            r#"
+            invoices = "invoices/{name}"
+            parcels = "invoices/parcels/{name[0..2]}/{name}"
+            "#,
+        )
+        .unwrap();
+        assert!(
+            layout.validate().is_err(),
+            "Nested templates should be invalid"
+        );
+        assert_eq!(
+            layout,
+            toml::from_slice(&toml::to_vec(&layout).unwrap()).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_template_names() {
+        let root = tempfile::tempdir().unwrap();
+        let template: PathTemplate = "parcels/{name[0..2]}/{name}".parse().unwrap();
+        let names = template.names(root.path()).await.unwrap();
+        assert_eq!(0, names.collect::<Vec<_>>().await.len());
+
+        for name in &["abc", "abd", "xyz"] {
+            tokio::fs::create_dir_all(template.path(root.path(), name))
+                .await
+                .unwrap();
+        }
+        let mut names: Vec<String> = template
+            .names(root.path())
+            .await
+            .unwrap()
+            .collect::<std::io::Result<_>>()
+            .await
+            .unwrap();
+        names.sort();
+        assert_eq!(vec!["abc", "abd", "xyz"], names);
+    }
+}
@@ -3,29 +3,45 @@
 //! Bindle repo.
 
 use std::convert::TryInto;
-use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::pin::Pin;
-use std::sync::Mutex;
-use std::task::{Context, Poll};
+use std::sync::Arc;
 
 use log::{debug, error, trace};
-use sha2::{Digest, Sha256};
 use tokio::fs::{create_dir_all, File, OpenOptions};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::stream::{Stream, StreamExt};
+use tokio::sync::RwLock;
 use tokio_util::codec::{BytesCodec, FramedRead};
 
+use crate::provider::expiry::{self, ExpiryAction, ExpiryReport};
+use crate::provider::gc::{GcReport, Marks};
+use crate::provider::naming::{NameMapping, NamingScheme};
 use crate::provider::{Provider, ProviderError, Result};
 use crate::Id;
 use crate::{async_util, search::Search};
 
-/// The folder name for the invoices directory
-const INVOICE_DIRECTORY: &str = "invoices";
-/// The folder name for the parcels directory
-const PARCEL_DIRECTORY: &str = "parcels";
+pub mod layout;
+
+use layout::StorageLayout;
+
 const INVOICE_TOML: &str = "invoice.toml";
+const HISTORY_TOML: &str = "history.toml";
 const PARCEL_DAT: &str = "parcel.dat";
+/// The file containing the naming schemes used for invoice directories
+const NAMING_TOML: &str = "naming.toml";
+/// The file containing the layout of the store
+const LAYOUT_TOML: &str = "layout.toml";
+
+/// The amount of data stored by a [`FileProvider`](FileProvider)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// The number of stored invoices, including yanked ones
+    pub invoices: u64,
+    /// The number of stored parcels
+    pub parcels: u64,
+    /// The combined size in bytes of the stored parcel data
+    pub parcel_bytes: u64,
+}
 
 /// A file system backend for storing and retrieving bindles and parcles.
 ///
@@ -34,9 +50,22 @@ const PARCEL_DAT: &str = "parcel.dat";
 ///
 /// A FileProvider needs a search engine implementation. When invoices are created or yanked,
 /// the index will be updated.
+///
+/// Invoice directories are named using a configurable [`NamingScheme`](NamingScheme). The schemes
+/// used by a store are recorded in a `naming.toml` file at its root, so invoices written with a
+/// previous scheme can still be found after switching to a new one.
+///
+/// Where the invoice and parcel directories are placed is set by a
+/// [`StorageLayout`](StorageLayout), which is recorded in a `layout.toml` file at the root.
 pub struct FileProvider<T> {
     root: PathBuf,
     index: T,
+    naming: Arc<NameMapping>,
+    layout: Arc<StorageLayout>,
+    /// Held for writing while collecting garbage, so no invoice can start referencing a parcel
+    /// between it being found unreferenced and removed
+    gc_lock: Arc<RwLock<()>>,
+    verify_reads: bool,
 }
 
 impl<T: Clone> Clone for FileProvider<T> {
@@ -44,15 +73,214 @@ impl<T: Clone> Clone for FileProvider<T> {
         FileProvider {
             root: self.root.clone(),
             index: self.index.clone(),
+            naming: self.naming.clone(),
+            layout: self.layout.clone(),
+            gc_lock: self.gc_lock.clone(),
+            verify_reads: self.verify_reads,
         }
     }
 }
 
 impl<T: Search + Send + Sync> FileProvider<T> {
+    /// Creates a new provider rooted at the given path. Invoices are named using the naming scheme
+    /// stored in the directory, or the default scheme if the store doesn't have one. The same goes
+    /// for the storage layout
     pub async fn new<P: AsRef<Path>>(path: P, index: T) -> Self {
+        let root = path.as_ref().to_owned();
+        let naming = match load_naming(&root).await {
+            Ok(n) => n,
+            Err(e) => {
+                log::error!("Error loading naming schemes, using the default: {}", e);
+                NameMapping::default()
+            }
+        };
+        let layout = match load_layout(&root).await {
+            Ok(l) => l.unwrap_or_default(),
+            Err(e) => {
+                log::error!("Error loading storage layout, using the default: {}", e);
+                StorageLayout::default()
+            }
+        };
+        Self::with_mapping(root, index, naming, layout).await
+    }
+
+    /// Creates a new provider rooted at the given path that stores invoices and parcels using the
+    /// given layout. A new store records the layout, while an existing store must have been created
+    /// with the same layout, as its data can't be found otherwise. The naming scheme is loaded like
+    /// in [`new`](FileProvider::new)
+    pub async fn with_layout<P: AsRef<Path>>(
+        path: P,
+        index: T,
+        layout: StorageLayout,
+    ) -> anyhow::Result<Self> {
+        layout.validate().map_err(anyhow::Error::msg)?;
+        let root = path.as_ref().to_owned();
+        match load_layout(&root).await? {
+            Some(recorded) if recorded == layout => (),
+            Some(recorded) => anyhow::bail!(
+                "{} was created with the storage layout {:?}, which can't be changed to {:?}",
+                root.display(),
+                recorded,
+                layout
+            ),
+            None => {
+                // Stores created before layouts were recorded use the default one
+                let default = StorageLayout::default();
+                if layout != default && has_data(&root, &default).await? {
+                    anyhow::bail!(
+                        "{} already contains data stored with the default storage layout",
+                        root.display()
+                    );
+                }
+                debug!(
+                    "Recording storage layout {:?} in {}",
+                    layout,
+                    root.display()
+                );
+                create_dir_all(&root).await?;
+                tokio::fs::write(root.join(LAYOUT_TOML), toml::to_vec(&layout)?).await?;
+            }
+        }
+        let naming = load_naming(&root).await?;
+        Ok(Self::with_mapping(root, index, naming, layout).await)
+    }
+
+    /// Creates a new provider rooted at the given path that names new invoices using the given
+    /// scheme. If the store previously used a different scheme, it is migrated to the new one,
+    /// and existing invoices are still found using the old scheme
+    pub async fn with_naming_scheme<P: AsRef<Path>>(
+        path: P,
+        index: T,
+        scheme: NamingScheme,
+    ) -> anyhow::Result<Self> {
+        let root = path.as_ref().to_owned();
+        let layout = load_layout(&root).await?.unwrap_or_default();
+        let mut naming = load_naming(&root).await?;
+        if naming.migrate_to(scheme) {
+            debug!("Migrating {} to naming scheme {:?}", root.display(), scheme);
+            create_dir_all(&root).await?;
+            tokio::fs::write(root.join(NAMING_TOML), toml::to_vec(&naming)?).await?;
+        }
+        Ok(Self::with_mapping(root, index, naming, layout).await)
+    }
+
+    /// Sets whether the SHA-256 of parcel data is verified again every time a whole parcel is read,
+    /// to keep data that was corrupted on disk from being served. The data is hashed as it is
+    /// streamed out, and the last chunk is held back until the hash is checked. On a mismatch, the
+    /// stream ends with a [`DigestMismatch`](ProviderError::DigestMismatch) error in its place, so
+    /// a response built from it is aborted before it is complete. Range reads can't be verified and
+    /// are served as they are. Defaults to `false`
+    pub fn with_read_verification(mut self, verify: bool) -> Self {
+        self.verify_reads = verify;
+        self
+    }
+
+    /// Returns a fingerprint of the stored invoices, which changes whenever an invoice is created,
+    /// changed or removed. It is computed from the names and modification times of the invoice
+    /// files, without reading them
+    pub async fn fingerprint(&self) -> Result<String> {
+        use sha2::{Digest, Sha256};
+
+        let mut entries = Vec::new();
+        let mut invoices = self.layout.invoices.names(&self.root).await?;
+        while let Some(name) = invoices.next().await {
+            let name = name?;
+            let modified = tokio::fs::metadata(self.invoice_toml_path(&name))
+                .await?
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or_default();
+            entries.push((name, modified));
+        }
+        entries.sort_unstable();
+        let mut hasher = Sha256::new();
+        for (name, modified) in entries {
+            hasher.update(format!("{} {}\n", name, modified));
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Saves a snapshot of the search index along with the [`fingerprint`](FileProvider::fingerprint)
+    /// of the store, such as when a server shuts down. The next provider created for this store
+    /// restores the index from the snapshot instead of reading every invoice, unless the store
+    /// changed in the meantime. Whether and where snapshots are saved depends on the search engine
+    pub async fn save_index_snapshot(&self) -> anyhow::Result<()> {
+        let fingerprint = self.fingerprint().await?;
+        self.index.save_snapshot(&fingerprint).await
+    }
+
+    /// Returns the IDs of all stored invoices, including yanked ones, sorted by their string form
+    pub async fn invoice_ids(&self) -> Result<Vec<Id>> {
+        let mut ids = Vec::new();
+        let mut invoices = self.layout.invoices.names(&self.root).await?;
+        while let Some(name) = invoices.next().await {
+            let raw = tokio::fs::read(self.invoice_toml_path(&name?)).await?;
+            let inv: crate::Invoice = toml::from_slice(&raw)?;
+            ids.push(inv.bindle.id);
+        }
+        ids.sort_by_key(|id| id.to_string());
+        Ok(ids)
+    }
+
+    /// Returns how much is stored, counting the invoice and parcel directories and the size of the
+    /// parcel data on disk. This reads every directory, so it takes longer the more is stored
+    pub async fn storage_stats(&self) -> Result<StorageStats> {
+        let mut stats = StorageStats::default();
+        let mut invoices = self.layout.invoices.names(&self.root).await?;
+        while let Some(name) = invoices.next().await {
+            name?;
+            stats.invoices += 1;
+        }
+        let mut parcels = self.layout.parcels.names(&self.root).await?;
+        while let Some(sha) = parcels.next().await {
+            // Parcels can be removed while counting, so missing data is simply not counted
+            if let Ok(meta) = tokio::fs::metadata(self.parcel_data_path(&sha?)).await {
+                stats.parcels += 1;
+                stats.parcel_bytes += meta.len();
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Replaces the signatures of the given invoice, which may be yanked, leaving everything else
+    /// untouched. This is meant for rotating the keys invoices are signed with, so the new
+    /// signatures are not checked. As the signed data doesn't include other signatures, adding or
+    /// removing signatures doesn't invalidate the remaining ones
+    pub async fn replace_signatures<I>(
+        &self,
+        id: I,
+        signatures: Vec<crate::signature::Signature>,
+    ) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let mut inv = self.get_yanked_invoice(id).await?;
+        inv.signature = if signatures.is_empty() {
+            None
+        } else {
+            Some(signatures)
+        };
+
+        trace!("Replacing signatures of invoice {:?}", inv.bindle.id);
+        self.rewrite_invoice(&inv, crate::HistoryAction::Resign, None)
+            .await
+    }
+
+    async fn with_mapping(
+        root: PathBuf,
+        index: T,
+        naming: NameMapping,
+        layout: StorageLayout,
+    ) -> Self {
         let fs = FileProvider {
-            root: path.as_ref().to_owned(),
+            root,
             index,
+            naming: Arc::new(naming),
+            layout: Arc::new(layout),
+            gc_lock: Arc::new(RwLock::new(())),
+            verify_reads: false,
         };
         if let Err(e) = fs.warm_index().await {
             log::error!("Error warming index: {}", e);
@@ -69,30 +297,33 @@ impl<T: Search + Send + Sync> FileProvider<T> {
     /// about. The storage engine merely needs to store any non-duplicates. So we can
     /// safely insert, but ignore errors that come back because of duplicate entries.
     async fn warm_index(&self) -> anyhow::Result<()> {
+        // Restoring a snapshot of the index is much faster than reading every invoice, but it is
+        // only possible if nothing changed since it was taken
+        match self.fingerprint().await {
+            Ok(fingerprint) => match self.index.restore_snapshot(&fingerprint).await {
+                Ok(true) => {
+                    debug!("Restored index of {} from snapshot", self.root.display());
+                    return Ok(());
+                }
+                Ok(false) => (),
+                Err(e) => log::warn!("Unable to restore index snapshot, reindexing: {}", e),
+            },
+            Err(e) => log::warn!("Unable to compute fingerprint of store, reindexing: {}", e),
+        }
         // Read all invoices
         debug!("Beginning index warm from {}", self.root.display());
         let mut total_indexed: u64 = 0;
-        // Check if the invoice directory exists. If it doesn't, this is likely the first time and
-        // we should just return
-        let invoice_path = self.invoice_path("");
-        match tokio::fs::metadata(&invoice_path).await {
-            Ok(_) => (),
-            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => return Ok(()),
-            Err(e) => return Err(e.into()),
-        };
-        let mut readdir = tokio::fs::read_dir(invoice_path).await?;
-        while let Some(e) = readdir.next().await {
-            let p = match e {
-                Ok(path) => path.path(),
+        // If the invoice directory doesn't exist, this is likely the first time and there is
+        // nothing to read
+        let mut invoices = self.layout.invoices.names(&self.root).await?;
+        while let Some(e) = invoices.next().await {
+            let sha = match e {
+                Ok(sha) => sha,
                 Err(e) => {
                     error!("Error while reading directory entry: {:?}", e);
                     continue;
                 }
             };
-            let sha = match p.file_name().map(|f| f.to_string_lossy()) {
-                Some(sha_opt) => sha_opt,
-                None => continue,
-            };
             log::info!("Loading invoice {}/invoice.toml into search index", sha);
             // Load invoice
             let inv_path = self.invoice_toml_path(&sha);
@@ -101,12 +332,12 @@ impl<T: Search + Send + Sync> FileProvider<T> {
 
             // Parse
             let invoice: crate::Invoice = toml::from_str(inv_toml.as_str())?;
-            let digest = invoice.canonical_name();
-            if sha != digest {
+            let candidates = self.naming.candidate_names(&invoice.bindle.id);
+            if !candidates.contains(&sha) {
                 return Err(anyhow::anyhow!(
                     "SHA {} did not match computed digest {}. Delete this record.",
                     sha,
-                    digest
+                    candidates[0]
                 ));
             }
 
@@ -119,37 +350,131 @@ impl<T: Search + Send + Sync> FileProvider<T> {
         Ok(())
     }
 
+    /// Overwrites a stored invoice whose signatures changed and records the change in its history
+    async fn rewrite_invoice(
+        &self,
+        inv: &crate::Invoice,
+        action: crate::HistoryAction,
+        by: Option<String>,
+    ) -> Result<()> {
+        let invoice_id = self.invoice_name(&inv.bindle.id).await;
+        if let Err(e) = self.index.index(inv).await {
+            log::error!("Error indexing {}: {}", invoice_id, e);
+        }
+        tokio::fs::write(self.invoice_toml_path(&invoice_id), toml::to_vec(inv)?).await?;
+        self.record_history(&invoice_id, crate::HistoryEvent::now(action, by, inv))
+            .await
+    }
+
+    /// Returns the name of the directory containing the given invoice. If the invoice doesn't exist
+    /// under any known naming scheme, the name using the current scheme is returned
+    async fn invoice_name(&self, id: &Id) -> String {
+        let candidates = self.naming.candidate_names(id);
+        for name in candidates.iter() {
+            if tokio::fs::metadata(self.invoice_toml_path(name))
+                .await
+                .is_ok()
+            {
+                return name.clone();
+            }
+        }
+        // candidate_names always contains the current scheme first
+        candidates.into_iter().next().unwrap_or_default()
+    }
+
     /// Return the path to the invoice directory for a particular bindle.
     fn invoice_path(&self, invoice_id: &str) -> PathBuf {
-        let mut path = self.root.join(INVOICE_DIRECTORY);
-        path.push(invoice_id);
-        path
+        self.layout.invoices.path(&self.root, invoice_id)
     }
     /// Return the path for an invoice.toml for a particular bindle.
     fn invoice_toml_path(&self, invoice_id: &str) -> PathBuf {
         self.invoice_path(invoice_id).join(INVOICE_TOML)
     }
+    /// Return the path for the history.toml of a particular bindle.
+    fn history_toml_path(&self, invoice_id: &str) -> PathBuf {
+        self.invoice_path(invoice_id).join(HISTORY_TOML)
+    }
     /// Return the parcel-specific path for storing a parcel.
     fn parcel_path(&self, parcel_id: &str) -> PathBuf {
-        let mut path = self.root.join(PARCEL_DIRECTORY);
-        path.push(parcel_id);
-        path
+        self.layout.parcels.path(&self.root, parcel_id)
     }
     /// Return the path to the parcel.dat file for the given box ID
     fn parcel_data_path(&self, parcel_id: &str) -> PathBuf {
         self.parcel_path(parcel_id).join(PARCEL_DAT)
     }
+
+    /// Loads the history for the given invoice. Invoices created before history was recorded will
+    /// have an empty history
+    async fn load_history(&self, invoice_id: &str) -> Result<crate::InvoiceHistory> {
+        match tokio::fs::read(self.history_toml_path(invoice_id)).await {
+            Ok(raw) => Ok(toml::from_slice(&raw)?),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => {
+                Ok(crate::InvoiceHistory::default())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Appends the given event to the history of the invoice.
+    async fn record_history(&self, invoice_id: &str, event: crate::HistoryEvent) -> Result<()> {
+        let mut history = self.load_history(invoice_id).await?;
+        trace!(
+            "Recording {:?} event for invoice {}",
+            event.action,
+            invoice_id
+        );
+        history.event.push(event);
+        tokio::fs::write(self.history_toml_path(invoice_id), toml::to_vec(&history)?).await?;
+        Ok(())
+    }
+
+    /// Marks the parcels referenced by every stored invoice. If any invoice can't be read, it is
+    /// impossible to know which parcels are still needed, so an error is returned. Callers must
+    /// hold the write side of the GC lock
+    async fn mark(&self) -> Result<Marks> {
+        let mut marks = Marks::default();
+        let mut invoices = self.layout.invoices.names(&self.root).await?;
+        while let Some(name) = invoices.next().await {
+            let raw = tokio::fs::read(self.invoice_toml_path(&name?)).await?;
+            marks.mark(&toml::from_slice(&raw)?);
+        }
+        Ok(marks)
+    }
 }
 
 #[async_trait::async_trait]
 impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
     async fn create_invoice(&self, inv: &crate::Invoice) -> Result<Vec<crate::Label>> {
+        self.create_invoice_by(inv, None).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(bindle = %inv.bindle.id))]
+    async fn create_invoice_by(
+        &self,
+        inv: &crate::Invoice,
+        by: Option<String>,
+    ) -> Result<Vec<crate::Label>> {
         // It is illegal to create a yanked invoice.
         if inv.yanked.unwrap_or(false) {
             return Err(ProviderError::CreateYanked);
         }
+        let _gc_guard = self.gc_lock.read().await;
+
+        // Parcels that already exist must have the size given in the invoice, otherwise the invoice
+        // would describe different data than what is served for it
+        for label in inv.parcel.iter().flatten().map(|p| &p.label) {
+            if let Ok(m) = tokio::fs::metadata(self.parcel_data_path(&label.sha256)).await {
+                if m.len() != label.size {
+                    return Err(ProviderError::SizeMismatch {
+                        sha256: label.sha256.clone(),
+                        expected: label.size,
+                        actual: m.len(),
+                    });
+                }
+            }
+        }
 
-        let invoice_id = inv.canonical_name();
+        let invoice_id = self.invoice_name(&inv.bindle.id).await;
 
         // Create the base path if necessary
         let inv_path = self.invoice_path(&invoice_id);
@@ -182,6 +507,12 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         let data = toml::to_vec(inv)?;
         out.write_all(data.as_slice()).await?;
 
+        self.record_history(
+            &invoice_id,
+            crate::HistoryEvent::now(crate::HistoryAction::Create, by, inv),
+        )
+        .await?;
+
         // Attempt to update the index. Right now, we log an error if the index update
         // fails.
         if let Err(e) = self.index.index(&inv).await {
@@ -223,6 +554,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
             .collect())
     }
 
+    #[tracing::instrument(level = "debug", skip_all)]
     async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
     where
         I: TryInto<Id> + Send,
@@ -231,7 +563,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
         trace!("Getting invoice {:?}", parsed_id);
 
-        let invoice_id = parsed_id.sha();
+        let invoice_id = self.invoice_name(&parsed_id).await;
 
         // Now construct a path and read it
         let invoice_path = self.invoice_toml_path(&invoice_id);
@@ -254,14 +586,24 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
     }
 
     async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.yank_invoice_by(id, None).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn yank_invoice_by<I>(&self, id: I, by: Option<String>) -> Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
     {
         let mut inv = self.get_yanked_invoice(id).await?;
+        let already_yanked = inv.yanked.unwrap_or(false);
         inv.yanked = Some(true);
 
-        let invoice_id = inv.canonical_name();
+        let invoice_id = self.invoice_name(&inv.bindle.id).await;
         trace!("Yanking invoice {:?}", invoice_id);
 
         // Attempt to update the index. Right now, we log an error if the index update
@@ -282,9 +624,104 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         // this behavior with OpenOptions.
 
         tokio::fs::write(dest, data).await?;
+
+        // Yanking a yanked invoice is a no-op, so only record the first yank
+        if !already_yanked {
+            self.record_history(
+                &invoice_id,
+                crate::HistoryEvent::now(crate::HistoryAction::Yank, by, &inv),
+            )
+            .await?;
+        }
         Ok(())
     }
 
+    async fn add_signature<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.add_signature_by(id, signature, None).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all)]
+    async fn add_signature_by<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+        by: Option<String>,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let mut inv = self.get_invoice(id).await?;
+        if inv.add_signature(signature)? {
+            trace!("Adding a signature to invoice {:?}", inv.bindle.id);
+            self.rewrite_invoice(&inv, crate::HistoryAction::Sign, by)
+                .await?;
+        }
+        Ok(inv)
+    }
+
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        // Nothing can be created while deleting, otherwise a new invoice could start referencing
+        // a parcel after it was found to be unreferenced
+        let _gc_guard = self.gc_lock.write().await;
+        let inv = self.get_yanked_invoice(&parsed_id).await?;
+
+        let invoice_id = self.invoice_name(&parsed_id).await;
+        debug!("Deleting invoice {:?}", invoice_id);
+        // This also removes the history, as there shouldn't be any trace of the content left
+        tokio::fs::remove_dir_all(self.invoice_path(&invoice_id)).await?;
+
+        // Same as when indexing, a failed index update is only logged
+        if let Err(e) = self.index.remove(&parsed_id).await {
+            log::error!("Error removing {} from the index: {}", invoice_id, e);
+        }
+
+        let marks = self.mark().await?;
+        let mut removed: Vec<String> = Vec::new();
+        for sha in inv.parcel.iter().flatten().map(|p| &p.label.sha256) {
+            if marks.is_marked(sha) || removed.contains(sha) {
+                continue;
+            }
+            trace!("Removing parcel {} of deleted invoice {}", sha, invoice_id);
+            match tokio::fs::remove_dir_all(self.parcel_path(sha)).await {
+                Ok(_) => removed.push(sha.clone()),
+                // Parcels listed in an invoice don't have to have been uploaded
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let invoice_id = self.invoice_name(&parsed_id).await;
+        // Make sure the invoice actually exists so we don't return an empty history for something
+        // that was never created
+        tokio::fs::metadata(self.invoice_toml_path(&invoice_id))
+            .await
+            .map_err(map_io_error)?;
+        self.load_history(&invoice_id).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(sha = parcel_id))]
     async fn create_parcel<I, R, B>(&self, _bindle_id: I, parcel_id: &str, data: R) -> Result<()>
     where
         I: TryInto<Id> + Send,
@@ -293,6 +730,7 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         B: bytes::Buf,
     {
         debug!("Creating parcel with SHA {}", parcel_id);
+        let _gc_guard = self.gc_lock.read().await;
 
         // Test if a dir with that SHA exists. If so, this is an error.
         let par_path = self.parcel_path(parcel_id);
@@ -300,40 +738,40 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
             return Err(ProviderError::Exists);
         }
         // Create box dir
-        create_dir_all(par_path).await?;
+        create_dir_all(&par_path).await?;
 
-        // Write data
-        {
-            let data_file = self.parcel_data_path(parcel_id);
-            trace!(
-                "Writing parcel data for SHA {} at {}",
-                parcel_id,
-                data_file.display()
-            );
-            let mut out = OpenOptions::new()
-                // TODO: Right now, if we write the file and then sha validation fails, the user
-                // will not be able to create this parcel again because the file already exists
-                .create_new(true)
-                .write(true)
-                .read(true)
-                .open(data_file.clone())
-                .await?;
-
-            tokio::io::copy(&mut async_util::BodyReadBuffer(data), &mut out).await?;
-            // Verify parcel by rewinding the parcel and then hashing it.
-            // This MUST be after the last write to out, otherwise the results will
-            // not be correct.
-            out.flush();
-            out.seek(std::io::SeekFrom::Start(0)).await?;
-            trace!("Validating data for SHA {}", parcel_id);
-            validate_sha256(&mut out, parcel_id).await?;
-            trace!("SHA {} data validated", parcel_id);
-            // TODO: Should we also validate length? We use it for returning the proper content length
+        let data_file = self.parcel_data_path(parcel_id);
+        trace!(
+            "Writing parcel data for SHA {} at {}",
+            parcel_id,
+            data_file.display()
+        );
+        let mut out = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(data_file)
+            .await?;
+        if let Err(e) = write_verified(&mut out, parcel_id, data).await {
+            // Don't leave partial or corrupt data behind, otherwise the parcel could never be
+            // uploaded again
+            drop(out);
+            if let Err(e) = tokio::fs::remove_dir_all(&par_path).await {
+                error!(
+                    "Unable to clean up failed parcel {} at {}: {}",
+                    parcel_id,
+                    par_path.display(),
+                    e
+                );
+            }
+            return Err(e);
         }
+        trace!("SHA {} data validated", parcel_id);
+        // TODO: Should we also validate length? We use it for returning the proper content length
 
         Ok(())
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(sha = parcel_id))]
     async fn get_parcel<I>(
         &self,
         _bindle_id: I,
@@ -348,12 +786,52 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
         debug!("Getting parcel with SHA {}", parcel_id);
         let name = self.parcel_data_path(parcel_id);
         let reader = File::open(name).await.map_err(map_io_error)?;
+        let data = FramedRead::new(reader, BytesCodec::new()).map(|res| res.map(|b| b.freeze()));
+        if !self.verify_reads {
+            return Ok(Box::new(data.map(|res| res.map_err(map_io_error))));
+        }
+
+        let data = async_util::VerifyingStream::new(data, parcel_id, None);
+        let failure = data.failure();
+        let sha = parcel_id.to_owned();
+        Ok(Box::new(async_util::HoldLast::new(data).map(move |res| {
+            res.map_err(|e| match failure.lock().unwrap().take() {
+                Some(m) => {
+                    error!("Parcel {} is corrupted on disk: {}", sha, m);
+                    m.into()
+                }
+                None => map_io_error(e),
+            })
+        })))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(sha = parcel_id, offset))]
+    async fn get_parcel_range<I>(
+        &self,
+        _bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!(
+            "Getting range of parcel with SHA {} starting at {}",
+            parcel_id, offset
+        );
+        let name = self.parcel_data_path(parcel_id);
+        let mut reader = File::open(name).await.map_err(map_io_error)?;
+        reader.seek(std::io::SeekFrom::Start(offset)).await?;
+        let reader = reader.take(length.unwrap_or(u64::MAX));
         Ok(Box::new(
             FramedRead::new(reader, BytesCodec::new())
                 .map(|res| res.map_err(map_io_error).map(|b| b.freeze())),
         ))
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(sha = parcel_id))]
     async fn parcel_exists<I>(&self, _bindle_id: I, parcel_id: &str) -> Result<bool>
     where
         I: TryInto<Id> + Send,
@@ -367,92 +845,169 @@ impl<T: crate::search::Search + Send + Sync> Provider for FileProvider<T> {
             Err(e) => Err(e.into()),
         }
     }
-}
 
-fn map_io_error(e: std::io::Error) -> ProviderError {
-    if matches!(e.kind(), std::io::ErrorKind::NotFound) {
-        return ProviderError::NotFound;
+    // Parcels are stored by SHA alone, so the bindle ID was never needed
+    #[tracing::instrument(level = "debug", skip_all, fields(count = shas.len()))]
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for sha in shas {
+            match tokio::fs::metadata(self.parcel_data_path(sha)).await {
+                Ok(m) if m.is_file() => continue,
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+            missing.push(sha.clone());
+        }
+        debug!("{} of {} parcels are missing", missing.len(), shas.len());
+        Ok(missing)
     }
-    ProviderError::from(e)
-}
 
-/// An internal wrapper to implement `AsyncWrite` on Sha256
-pub(crate) struct AsyncSha256 {
-    inner: Mutex<Sha256>,
-}
-
-impl AsyncSha256 {
-    /// Equivalent to the `Sha256::new()` function
-    pub(crate) fn new() -> Self {
-        AsyncSha256 {
-            inner: Mutex::new(Sha256::new()),
+    #[tracing::instrument(level = "debug", skip_all, fields(dry_run))]
+    async fn collect_garbage(&self, dry_run: bool) -> Result<GcReport> {
+        let _gc_guard = self.gc_lock.write().await;
+        debug!("Collecting garbage in {}", self.root.display());
+        // Sweeping with a different layout than the data was stored with would find none of the
+        // invoices and remove every parcel, so make sure nothing changed it under us
+        match load_layout(&self.root).await.map(Option::unwrap_or_default) {
+            Ok(recorded) if recorded == *self.layout => (),
+            Ok(_) => {
+                return Err(ProviderError::Other(
+                    "The storage layout was changed, refusing to collect garbage".into(),
+                ))
+            }
+            Err(e) => {
+                return Err(ProviderError::Other(format!(
+                    "Unable to check the storage layout, refusing to collect garbage: {}",
+                    e
+                )))
+            }
         }
-    }
 
-    /// Consumes self and returns the bare Sha256. This should only be called once you are done
-    /// writing. This will only return an error if for some reason the underlying mutex was poisoned
-    pub(crate) fn into_inner(self) -> std::sync::LockResult<Sha256> {
-        self.inner.into_inner()
+        let marks = self.mark().await?;
+
+        // Sweep
+        let mut report = marks.report(dry_run);
+        let mut parcels = self.layout.parcels.names(&self.root).await?;
+        while let Some(sha) = parcels.next().await {
+            let sha = sha?;
+            if marks.is_marked(&sha) {
+                report.retained += 1;
+                continue;
+            }
+            // The data may be missing if a previous upload failed badly, so there is nothing to
+            // count
+            let size = tokio::fs::metadata(self.parcel_data_path(&sha))
+                .await
+                .map(|m| m.len())
+                .unwrap_or_default();
+            if !dry_run {
+                trace!("Removing unreferenced parcel {}", sha);
+                tokio::fs::remove_dir_all(self.parcel_path(&sha)).await?;
+            }
+            report.removed_bytes += size;
+            report.removed.push(sha);
+        }
+        debug!(
+            "Collected {} unreferenced parcels in {} (dry run: {})",
+            report.removed.len(),
+            self.root.display(),
+            dry_run
+        );
+        Ok(report)
     }
-}
 
-impl tokio::io::AsyncWrite for AsyncSha256 {
-    fn poll_write(
-        self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<std::result::Result<usize, std::io::Error>> {
-        // Because the hasher is all in memory, we only need to make sure only one caller at a time
-        // can write using the mutex
-        let mut inner = match self.inner.try_lock() {
-            Ok(l) => l,
-            Err(_) => return Poll::Pending,
+    async fn expire_invoices(&self, action: ExpiryAction, dry_run: bool) -> Result<ExpiryReport> {
+        let now = expiry::now();
+        let mut report = ExpiryReport {
+            dry_run,
+            action,
+            ..ExpiryReport::default()
         };
-
-        Poll::Ready(inner.write(buf))
+        for id in self.invoice_ids().await? {
+            let inv = match self.get_yanked_invoice(&id).await {
+                Ok(inv) => inv,
+                // Deleted since it was listed
+                Err(ProviderError::NotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            report.checked += 1;
+            if !inv.is_expired_at(now)
+                || (action == ExpiryAction::Yank && inv.yanked.unwrap_or(false))
+            {
+                continue;
+            }
+            if !dry_run {
+                debug!("Invoice {} expired (action: {})", id, action);
+                match action {
+                    ExpiryAction::Yank => self.yank_invoice(&id).await?,
+                    ExpiryAction::Delete => {
+                        self.delete_invoice(&id).await?;
+                    }
+                }
+            }
+            report.expired.push(id);
+        }
+        Ok(report)
     }
 
-    fn poll_flush(
-        self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-    ) -> Poll<std::result::Result<(), std::io::Error>> {
-        let mut inner = match self.inner.try_lock() {
-            Ok(l) => l,
-            Err(_) => return Poll::Pending,
-        };
+    /// Saves a snapshot of the search index (see
+    /// [`save_index_snapshot`](FileProvider::save_index_snapshot)), if the search engine is
+    /// configured to keep one
+    async fn flush(&self) -> Result<()> {
+        self.save_index_snapshot()
+            .await
+            .map_err(|e| ProviderError::Other(format!("Unable to save index snapshot: {}", e)))
+    }
+}
 
-        Poll::Ready(inner.flush())
+/// Loads the naming schemes stored in the given directory, returning the default mapping if there
+/// is none
+async fn load_naming(root: &Path) -> anyhow::Result<NameMapping> {
+    match tokio::fs::read(root.join(NAMING_TOML)).await {
+        Ok(raw) => Ok(toml::from_slice(&raw)?),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(NameMapping::default()),
+        Err(e) => Err(e.into()),
     }
+}
 
-    fn poll_shutdown(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<std::result::Result<(), std::io::Error>> {
-        // There are no actual shutdown tasks to perform, so just flush things as defined in the
-        // trait documentation
-        self.poll_flush(cx)
+/// Loads the storage layout recorded in the given directory, if any
+async fn load_layout(root: &Path) -> anyhow::Result<Option<StorageLayout>> {
+    match tokio::fs::read(root.join(LAYOUT_TOML)).await {
+        Ok(raw) => Ok(Some(toml::from_slice(&raw)?)),
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(None),
+        Err(e) => Err(e.into()),
     }
 }
 
-/// Validate that the File path matches the given SHA256
-async fn validate_sha256(file: &mut File, sha: &str) -> Result<()> {
-    let mut hasher = AsyncSha256::new();
-    tokio::io::copy(file, &mut hasher).await?;
-    let hasher = match hasher.into_inner() {
-        Ok(h) => h,
-        Err(_) => {
-            return Err(ProviderError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "data write corruption, mutex poisoned",
-            )))
-        }
-    };
-    let result = hasher.finalize();
+/// Returns whether any invoices or parcels are stored in the given directory with the given layout
+async fn has_data(root: &Path, layout: &StorageLayout) -> Result<bool> {
+    Ok(layout.invoices.names(root).await?.next().await.is_some()
+        || layout.parcels.names(root).await?.next().await.is_some())
+}
 
-    if format!("{:x}", result) != sha {
-        return Err(ProviderError::DigestMismatch);
+fn map_io_error(e: std::io::Error) -> ProviderError {
+    if matches!(e.kind(), std::io::ErrorKind::NotFound) {
+        return ProviderError::NotFound;
     }
+    ProviderError::from(e)
+}
 
+/// Writes the data to the file, verifying that it matches the given SHA as it is written
+async fn write_verified<R, B>(out: &mut File, sha: &str, data: R) -> Result<()>
+where
+    R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+    B: bytes::Buf,
+{
+    let data = async_util::VerifyingStream::new(data, sha, None);
+    let failure = data.failure();
+    let res = tokio::io::copy(&mut async_util::BodyReadBuffer::new(data), out).await;
+    // A failed verification shows up as an IO error, so return the real reason
+    if let Some(m) = failure.lock().unwrap().take() {
+        return Err(m.into());
+    }
+    res?;
+    out.flush().await?;
     Ok(())
 }
 
@@ -504,12 +1059,238 @@ mod test {
         assert!(inv2.yanked.unwrap_or(false));
 
         // Sanity check that this produces an error
-        assert!(store.get_invoice(inv.bindle.id).await.is_err());
+        assert!(store.get_invoice(&inv.bindle.id).await.is_err());
+
+        // Yanking again is a no-op, so it shouldn't show up in the history twice
+        store.yank_invoice(&inv.bindle.id).await.unwrap();
+        let history = store.get_invoice_history(&inv.bindle.id).await.unwrap();
+        let actions: Vec<crate::HistoryAction> = history.event.iter().map(|e| e.action).collect();
+        assert_eq!(
+            vec![crate::HistoryAction::Create, crate::HistoryAction::Yank],
+            actions
+        );
+
+        // A nonexistent invoice has no history
+        assert!(matches!(
+            store.get_invoice_history("non/existent/1.0.0").await,
+            Err(ProviderError::NotFound)
+        ));
 
         // Drop the temporary directory
         assert!(root.close().is_ok());
     }
 
+    #[tokio::test]
+    async fn test_should_restore_index_snapshot() {
+        use crate::search::StrictEngine;
+
+        let root = tempdir().unwrap();
+        let snapshot = root.path().join("index-snapshot.json");
+        let inv = invoice_fixture();
+        let store = FileProvider::new(
+            root.path(),
+            StrictEngine::default().with_snapshot(&snapshot),
+        )
+        .await;
+        store.create_invoice(&inv).await.unwrap();
+        store.save_index_snapshot().await.unwrap();
+        let fingerprint = store.fingerprint().await.unwrap();
+        assert!(StrictEngine::default()
+            .with_snapshot(&snapshot)
+            .restore_snapshot(&fingerprint)
+            .await
+            .unwrap());
+
+        // To tell a restored index from a rebuilt one, the snapshot is replaced by one with an
+        // invoice that isn't in the store
+        let index = StrictEngine::default().with_snapshot(&snapshot);
+        let mut extra = inv.clone();
+        extra.bindle.id = "example.com/extra/1.0.0".parse().unwrap();
+        index.index(&inv).await.unwrap();
+        index.index(&extra).await.unwrap();
+        index.save_snapshot(&fingerprint).await.unwrap();
+
+        let restored = StrictEngine::default().with_snapshot(&snapshot);
+        FileProvider::new(root.path(), restored.clone()).await;
+        assert_eq!(2, restored.stats().await.invoices);
+
+        // Once the store changes, the snapshot is stale and the index is rebuilt
+        store.yank_invoice(&inv.bindle.id).await.unwrap();
+        assert_ne!(fingerprint, store.fingerprint().await.unwrap());
+        let rebuilt = StrictEngine::default().with_snapshot(&snapshot);
+        FileProvider::new(root.path(), rebuilt.clone()).await;
+        let stats = rebuilt.stats().await;
+        assert_eq!(1, stats.invoices);
+        assert_eq!(1, stats.yanked);
+    }
+
+    #[tokio::test]
+    async fn test_should_migrate_naming_scheme() {
+        use crate::provider::naming::{Encoding, HashAlgorithm};
+
+        let root = tempdir().unwrap();
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+        let old = invoice_fixture();
+        store.create_invoice(&old).await.unwrap();
+        assert!(store.invoice_toml_path(&old.canonical_name()).exists());
+
+        let scheme = NamingScheme {
+            algorithm: HashAlgorithm::Sha256,
+            encoding: Encoding::Base32,
+        };
+        let store = FileProvider::with_naming_scheme(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+            scheme,
+        )
+        .await
+        .expect("migrate naming scheme");
+
+        // New invoices use the new scheme, existing ones are still found using the old one
+        let mut new = invoice_fixture();
+        new.bindle.id = "foo/2.0.0".parse().unwrap();
+        store.create_invoice(&new).await.unwrap();
+        assert!(store
+            .invoice_toml_path(&scheme.canonical_name(&new.bindle.id))
+            .exists());
+        store.get_invoice(&old.bindle.id).await.unwrap();
+        assert!(matches!(
+            store.create_invoice(&old).await,
+            Err(ProviderError::Exists)
+        ));
+        store.yank_invoice(&old.bindle.id).await.unwrap();
+        assert!(!store
+            .invoice_toml_path(&scheme.canonical_name(&old.bindle.id))
+            .exists());
+
+        // Reopening the store should pick up the stored scheme and warm the index from both
+        let index = crate::search::StrictEngine::default();
+        let store = FileProvider::new(root.path().to_owned(), index.clone()).await;
+        assert_eq!(scheme, store.naming.current);
+        let matches = index
+            .query(
+                "foo".to_owned(),
+                String::new(),
+                crate::search::SearchOptions {
+                    yanked: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(2, matches.total);
+
+        assert!(root.close().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_should_use_storage_layout() {
+        let root = tempdir().unwrap();
+        let volume = tempdir().unwrap();
+        let layout = StorageLayout {
+            invoices: "store/invoices/{name[0..2]}/{name}".parse().unwrap(),
+            parcels: format!("{}/{{name[0..2]}}/{{name}}", volume.path().display())
+                .parse()
+                .unwrap(),
+        };
+        let store = FileProvider::with_layout(
+            root.path(),
+            crate::search::StrictEngine::default(),
+            layout.clone(),
+        )
+        .await
+        .expect("create store with layout");
+
+        let (kept, kept_data) = parcel_fixture("referenced").await;
+        let (orphan, orphan_data) = parcel_fixture("orphaned").await;
+        let mut inv = invoice_fixture();
+        inv.parcel = Some(vec![crate::Parcel {
+            label: kept.clone(),
+            conditions: None,
+        }]);
+        store.create_invoice(&inv).await.expect("create invoice");
+        for (label, data) in [(&kept, kept_data), (&orphan, orphan_data)] {
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &label.sha256,
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await
+                .expect("create parcel");
+        }
+        let name = inv.canonical_name();
+        assert!(root
+            .path()
+            .join("store/invoices")
+            .join(&name[..2])
+            .join(&name)
+            .join(INVOICE_TOML)
+            .exists());
+        assert!(volume
+            .path()
+            .join(&kept.sha256[..2])
+            .join(&kept.sha256)
+            .join(PARCEL_DAT)
+            .exists());
+        assert!(!root.path().join("parcels").exists());
+
+        let ids = store.invoice_ids().await.unwrap();
+        assert_eq!(1, ids.len());
+        assert_eq!(inv.bindle.id.to_string(), ids[0].to_string());
+        let report = store.collect_garbage(false).await.expect("collect garbage");
+        assert_eq!(1, report.retained);
+        assert_eq!(vec![orphan.sha256.clone()], report.removed);
+
+        // Reopening the store picks up the recorded layout, which can't be changed
+        let index = crate::search::StrictEngine::default();
+        let store = FileProvider::new(root.path(), index.clone()).await;
+        store
+            .get_invoice(&inv.bindle.id)
+            .await
+            .expect("get invoice");
+        assert!(store
+            .parcel_exists(&inv.bindle.id, &kept.sha256)
+            .await
+            .unwrap());
+        assert_eq!(1, index.stats().await.invoices);
+        FileProvider::with_layout(root.path(), index.clone(), layout)
+            .await
+            .expect("reopen store with the same layout");
+        assert!(
+            FileProvider::with_layout(root.path(), index.clone(), StorageLayout::default())
+                .await
+                .is_err(),
+            "Changing the layout should fail"
+        );
+
+        // Stores created before layouts were recorded use the default layout
+        let old = tempdir().unwrap();
+        FileProvider::new(old.path(), index.clone())
+            .await
+            .create_invoice(&inv)
+            .await
+            .unwrap();
+        assert!(FileProvider::with_layout(
+            old.path(),
+            index.clone(),
+            StorageLayout {
+                invoices: "invoices/{name[0..2]}/{name}".parse().unwrap(),
+                parcels: "parcels/{name[0..2]}/{name}".parse().unwrap(),
+            }
+        )
+        .await
+        .is_err());
+        FileProvider::with_layout(old.path(), index, StorageLayout::default())
+            .await
+            .expect("record default layout");
+        assert!(old.path().join(LAYOUT_TOML).exists());
+    }
+
     #[tokio::test]
     async fn test_should_reject_yanked_invoice() {
         // Create a temporary directory
@@ -558,7 +1339,7 @@ mod test {
             .get_parcel("doesn't matter", id)
             .await
             .expect("load parcel data");
-        let mut reader = crate::async_util::BodyReadBuffer(stream);
+        let mut reader = crate::async_util::BodyReadBuffer::new(stream);
         reader
             .read_to_string(&mut data)
             .await
@@ -566,6 +1347,388 @@ mod test {
         assert_eq!(data, content);
     }
 
+    #[tokio::test]
+    async fn test_should_verify_parcel_on_read() {
+        let content = "abcdef1234567890987654321";
+        let (label, data) = parcel_fixture(content).await;
+        let id = label.sha256.as_str();
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(root.path(), crate::search::StrictEngine::default())
+            .await
+            .with_read_verification(true);
+        store
+            .create_parcel("not_needed", id, FramedRead::new(data, BytesCodec::new()))
+            .await
+            .expect("create parcel");
+
+        let read = |store: FileProvider<_>| async move {
+            store
+                .get_parcel("doesn't matter", id)
+                .await
+                .expect("load parcel data")
+                .collect::<Vec<_>>()
+                .await
+        };
+        let chunks = read(store.clone()).await;
+        assert!(chunks.iter().all(|c| c.is_ok()));
+        let data: Vec<u8> = chunks.into_iter().flat_map(|c| c.unwrap()).collect();
+        assert_eq!(content.as_bytes(), data.as_slice());
+
+        // Corrupt the data at rest
+        tokio::fs::write(store.parcel_data_path(id), "abcdef1234567890987654322")
+            .await
+            .expect("unable to overwrite parcel");
+        let chunks = read(store.clone()).await;
+        let bytes_served: usize = chunks.iter().flatten().map(|c| c.len()).sum();
+        assert!(bytes_served < content.len(), "Corrupt data was served");
+        assert!(matches!(
+            chunks.last(),
+            Some(Err(ProviderError::DigestMismatch { expected, .. })) if expected == id
+        ));
+
+        // Reads aren't checked unless asked for
+        let chunks = read(store.with_read_verification(false)).await;
+        assert!(chunks.iter().all(|c| c.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_should_reject_parcel_with_wrong_digest() {
+        let (label, _) = parcel_fixture("abcdef1234567890987654321").await;
+        let id = label.sha256.as_str();
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+
+        let wrong = std::io::Cursor::new(b"not the content".to_vec());
+        match store
+            .create_parcel("not_needed", id, FramedRead::new(wrong, BytesCodec::new()))
+            .await
+        {
+            Err(ProviderError::DigestMismatch { expected, .. }) => assert_eq!(expected, id),
+            res => panic!("Expected a digest mismatch, got {:?}", res),
+        }
+        assert!(
+            !store
+                .parcel_exists("not_needed", id)
+                .await
+                .expect("check for parcel existence"),
+            "A parcel with the wrong content should not be stored"
+        );
+
+        // The failed upload shouldn't get in the way of uploading the right content
+        let (_, data) = parcel_fixture("abcdef1234567890987654321").await;
+        store
+            .create_parcel("not_needed", id, FramedRead::new(data, BytesCodec::new()))
+            .await
+            .expect("create parcel");
+    }
+
+    #[tokio::test]
+    async fn test_should_collect_garbage() {
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+
+        // An empty store has nothing to collect
+        let report = store
+            .collect_garbage(false)
+            .await
+            .expect("collect garbage in empty store");
+        assert_eq!(GcReport::default(), report);
+        assert_eq!(
+            StorageStats::default(),
+            store.storage_stats().await.expect("count empty store")
+        );
+
+        let (kept, kept_data) = parcel_fixture("referenced").await;
+        let (orphan, orphan_data) = parcel_fixture("orphaned").await;
+        let mut inv = invoice_fixture();
+        inv.parcel = Some(vec![crate::Parcel {
+            label: kept.clone(),
+            conditions: None,
+        }]);
+        store.create_invoice(&inv).await.expect("create invoice");
+        // Yanked invoices can still be fetched, so their parcels are still needed
+        store
+            .yank_invoice(&inv.bindle.id)
+            .await
+            .expect("yank invoice");
+        for (label, data) in [(&kept, kept_data), (&orphan, orphan_data)] {
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &label.sha256,
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await
+                .expect("create parcel");
+        }
+
+        assert_eq!(
+            StorageStats {
+                invoices: 1,
+                parcels: 2,
+                parcel_bytes: ("referenced".len() + "orphaned".len()) as u64,
+            },
+            store.storage_stats().await.expect("count store")
+        );
+
+        let report = store
+            .collect_garbage(true)
+            .await
+            .expect("dry run garbage collection");
+        assert!(report.dry_run);
+        assert_eq!(1, report.invoices);
+        assert_eq!(1, report.retained);
+        assert_eq!(vec![orphan.sha256.clone()], report.removed);
+        assert_eq!("orphaned".len() as u64, report.removed_bytes);
+        assert!(
+            store
+                .parcel_exists(&inv.bindle.id, &orphan.sha256)
+                .await
+                .unwrap(),
+            "A dry run should not remove anything"
+        );
+
+        let report = store.collect_garbage(false).await.expect("collect garbage");
+        assert!(!report.dry_run);
+        assert_eq!(vec![orphan.sha256.clone()], report.removed);
+        assert!(!store
+            .parcel_exists(&inv.bindle.id, &orphan.sha256)
+            .await
+            .unwrap());
+        assert!(store
+            .parcel_exists(&inv.bindle.id, &kept.sha256)
+            .await
+            .unwrap());
+        assert_eq!(1, store.storage_stats().await.unwrap().parcels);
+    }
+
+    #[tokio::test]
+    async fn test_should_expire_invoices() {
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+
+        let mut ids = Vec::new();
+        for (version, expires_at) in [
+            ("1.0.0", Some(1)),
+            ("1.0.1", Some(u64::MAX)),
+            ("1.0.2", None),
+        ] {
+            let mut inv = invoice_fixture();
+            inv.parcel = None;
+            inv.bindle.id = format!("{}/{}", inv.bindle.id.name(), version)
+                .parse()
+                .unwrap();
+            if let Some(at) = expires_at {
+                inv.set_expires_at(at);
+            }
+            store.create_invoice(&inv).await.expect("create invoice");
+            ids.push(inv.bindle.id);
+        }
+        let expired = ids[0].to_string();
+
+        let report = store
+            .expire_invoices(ExpiryAction::Yank, true)
+            .await
+            .expect("dry run expiry");
+        assert_eq!(3, report.checked);
+        assert_eq!(
+            vec![expired.clone()],
+            report
+                .expired
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert!(
+            store.get_invoice(&ids[0]).await.is_ok(),
+            "A dry run should not yank anything"
+        );
+
+        let report = store
+            .expire_invoices(ExpiryAction::Yank, false)
+            .await
+            .expect("yank expired invoices");
+        assert_eq!(1, report.expired.len());
+        assert!(matches!(
+            store.get_invoice(&ids[0]).await,
+            Err(ProviderError::Yanked)
+        ));
+        for id in &ids[1..] {
+            store
+                .get_invoice(id)
+                .await
+                .expect("invoice should not expire");
+        }
+        // Yanked invoices are not yanked again
+        let report = store
+            .expire_invoices(ExpiryAction::Yank, false)
+            .await
+            .expect("yank expired invoices");
+        assert!(report.expired.is_empty());
+
+        let report = store
+            .expire_invoices(ExpiryAction::Delete, false)
+            .await
+            .expect("delete expired invoices");
+        assert_eq!(1, report.expired.len());
+        assert!(matches!(
+            store.get_yanked_invoice(&ids[0]).await,
+            Err(ProviderError::NotFound)
+        ));
+        assert_eq!(2, store.invoice_ids().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_should_delete_invoice() {
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+
+        let (shared, shared_data) = parcel_fixture("shared").await;
+        let (own, own_data) = parcel_fixture("only used once").await;
+        let mut inv = invoice_fixture();
+        inv.parcel = Some(
+            [&shared, &own]
+                .iter()
+                .map(|l| crate::Parcel {
+                    label: (*l).clone(),
+                    conditions: None,
+                })
+                .collect(),
+        );
+        let mut other = invoice_fixture();
+        other.bindle.id = "foo/1.2.4".parse().unwrap();
+        other.parcel = Some(vec![crate::Parcel {
+            label: shared.clone(),
+            conditions: None,
+        }]);
+        store.create_invoice(&inv).await.expect("create invoice");
+        store.create_invoice(&other).await.expect("create invoice");
+        for (label, data) in [(&shared, shared_data), (&own, own_data)] {
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &label.sha256,
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await
+                .expect("create parcel");
+        }
+
+        // Yanked invoices can be deleted as well
+        store
+            .yank_invoice(&inv.bindle.id)
+            .await
+            .expect("yank invoice");
+        let removed = store
+            .delete_invoice(&inv.bindle.id)
+            .await
+            .expect("delete invoice");
+        assert_eq!(vec![own.sha256.clone()], removed);
+
+        assert!(matches!(
+            store.get_yanked_invoice(&inv.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+        assert!(matches!(
+            store.get_invoice_history(&inv.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+        assert!(!store
+            .parcel_exists(&inv.bindle.id, &own.sha256)
+            .await
+            .unwrap());
+        assert!(store
+            .parcel_exists(&other.bindle.id, &shared.sha256)
+            .await
+            .unwrap());
+        let matches = store
+            .index
+            .query("foo".to_owned(), "".to_owned(), Default::default())
+            .await
+            .expect("query index");
+        assert_eq!(1, matches.invoices.len());
+
+        assert!(matches!(
+            store.delete_invoice(&inv.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_should_replace_signatures() {
+        use crate::signature::{SecretKeyEntry, SignatureRole};
+
+        let root = tempdir().expect("create tempdir");
+        let store = FileProvider::new(
+            root.path().to_owned(),
+            crate::search::StrictEngine::default(),
+        )
+        .await;
+        let old_key = SecretKeyEntry::generate("old", vec![SignatureRole::Host]);
+        let new_key = SecretKeyEntry::generate("new", vec![SignatureRole::Host]);
+
+        let mut inv = invoice_fixture();
+        inv.sign_with_key(SignatureRole::Host, &old_key)
+            .expect("sign invoice");
+        let mut other = invoice_fixture();
+        other.bindle.id = "foo/1.2.4".parse().unwrap();
+        store.create_invoice(&other).await.expect("create invoice");
+        store.create_invoice(&inv).await.expect("create invoice");
+        store
+            .yank_invoice(&inv.bindle.id)
+            .await
+            .expect("yank invoice");
+        let ids: Vec<String> = store
+            .invoice_ids()
+            .await
+            .expect("list invoices")
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        assert_eq!(vec!["foo/1.2.3", "foo/1.2.4"], ids);
+
+        let mut resigned = inv.clone();
+        resigned.signature = None;
+        resigned
+            .sign_with_key(SignatureRole::Host, &new_key)
+            .expect("sign invoice");
+        store
+            .replace_signatures(&inv.bindle.id, resigned.signature.clone().unwrap())
+            .await
+            .expect("replace signatures");
+
+        let stored = store
+            .get_yanked_invoice(&inv.bindle.id)
+            .await
+            .expect("get invoice");
+        assert_eq!(resigned.signature, stored.signature);
+        assert_eq!(Some(true), stored.yanked);
+        let history = store
+            .get_invoice_history(&inv.bindle.id)
+            .await
+            .expect("get history");
+        assert_eq!(
+            crate::HistoryAction::Resign,
+            history.event.last().unwrap().action
+        );
+    }
+
     #[tokio::test]
     async fn test_should_store_and_retrieve_bindle() {
         let root = tempdir().expect("create tempdir");
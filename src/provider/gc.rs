@@ -0,0 +1,82 @@
+//! Garbage collection of orphaned parcels.
+//!
+//! Parcels are stored separately from the invoices that list them, so a parcel can outlive every
+//! invoice referencing it, such as after an abandoned push or when invoices are removed from
+//! storage by other means. Nothing will ever fetch such a parcel again, but it takes up space
+//! forever. Providers that can list everything they store remove these parcels with a
+//! mark-and-sweep in [`Provider::collect_garbage`](super::Provider::collect_garbage): first every
+//! parcel referenced by an invoice is marked, then every parcel that wasn't marked is swept.
+//! Yanked invoices count as references, as they can still be fetched
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::Provider;
+use crate::Invoice;
+
+/// The outcome of a garbage collection run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct GcReport {
+    /// Whether this was a dry run, in which case nothing was actually removed
+    pub dry_run: bool,
+    /// The number of invoices whose parcels were marked
+    pub invoices: u64,
+    /// The number of parcels that are still referenced and were kept
+    pub retained: u64,
+    /// The SHAs of the unreferenced parcels that were removed (or would have been, for a dry run)
+    #[serde(default)]
+    pub removed: Vec<String>,
+    /// The combined size of the removed parcels in bytes
+    pub removed_bytes: u64,
+}
+
+/// The parcels referenced by the invoices seen during the mark phase
+#[derive(Debug, Clone, Default)]
+pub struct Marks {
+    invoices: u64,
+    parcels: HashSet<String>,
+}
+
+impl Marks {
+    /// Marks all of the parcels listed in the invoice as referenced
+    pub fn mark(&mut self, inv: &Invoice) {
+        self.invoices += 1;
+        self.parcels
+            .extend(inv.parcel.iter().flatten().map(|p| p.label.sha256.clone()));
+    }
+
+    /// Returns whether the parcel with the given SHA is referenced by any of the marked invoices
+    pub fn is_marked(&self, sha: &str) -> bool {
+        self.parcels.contains(sha)
+    }
+
+    /// Returns an empty report for a collection using these marks
+    pub fn report(&self, dry_run: bool) -> GcReport {
+        GcReport {
+            dry_run,
+            invoices: self.invoices,
+            ..GcReport::default()
+        }
+    }
+}
+
+/// Collects garbage in the store every `interval`, logging the outcome of each run. This never
+/// returns, so it should be spawned as a background task, which stops when the task is dropped
+pub async fn collect_periodically<P: Provider + Sync>(store: P, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+        match store.collect_garbage(false).await {
+            Ok(report) => info!(
+                "Garbage collection removed {} unreferenced parcels ({} bytes), {} parcels retained",
+                report.removed.len(),
+                report.removed_bytes,
+                report.retained
+            ),
+            Err(e) => error!("Garbage collection failed: {}", e),
+        }
+    }
+}
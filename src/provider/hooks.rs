@@ -0,0 +1,565 @@
+//! Hooks that are notified whenever a provider creates, yanks or deletes bindles.
+//!
+//! Wrapping a provider in a [`HookedProvider`](HookedProvider) sends an [`Event`](Event) to every
+//! configured [`EventHook`](EventHook) after each successful change. This is meant for integrations with external caching layers, such as purging
+//! a yanked invoice from a CDN or pre-warming edge caches with newly created parcels. Hooks are run
+//! in the background, so a slow or failing hook never delays or fails the request that triggered
+//! it. The [`HttpHook`](HttpHook) implementation posts each event as JSON to a URL, retrying failed
+//! deliveries. Its deliveries can be signed with a shared secret, so receivers can check with
+//! [`verify_signature`](verify_signature) that an event really came from the server
+
+use std::convert::TryInto;
+use std::sync::Arc;
+
+#[cfg(feature = "client")]
+use std::time::Duration;
+
+use hmac::{Hmac, Mac, NewMac};
+#[cfg(feature = "client")]
+use log::{debug, warn};
+use sha2::Sha256;
+use tokio::stream::Stream;
+
+use super::{Provider, ProviderError, Result};
+use crate::events::{
+    Event, InvoiceCreated, InvoiceDeleted, InvoiceSigned, InvoiceYanked, ParcelUploaded,
+};
+use crate::tasks::TaskRegistry;
+use crate::Id;
+
+/// The header containing the signature of a signed event delivery
+pub const SIGNATURE_HEADER: &str = "X-Bindle-Signature";
+
+/// Returns the signature of the given payload in the format used for the
+/// [`SIGNATURE_HEADER`](SIGNATURE_HEADER): `sha256=` followed by the hex encoded HMAC-SHA256 of
+/// the payload with the secret as key
+pub fn sign_payload(secret: &[u8], payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Checks that the signature from the [`SIGNATURE_HEADER`](SIGNATURE_HEADER) of a delivery was
+/// made for the payload with the given secret. The comparison takes constant time
+pub fn verify_signature(secret: &[u8], payload: &[u8], signature: &str) -> bool {
+    let expected = sign_payload(secret, payload);
+    expected.len() == signature.len()
+        && expected
+            .bytes()
+            .zip(signature.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Something that wants to be notified about the changes made by a provider
+#[async_trait::async_trait]
+pub trait EventHook {
+    /// Handles the event. Implementations are responsible for reporting their own errors, as there
+    /// is no request left to fail by the time the hook runs
+    async fn on_event(&self, event: &Event);
+}
+
+/// A provider that sends an event to each of its hooks after every successful change to the
+/// wrapped provider. All reads are passed through untouched
+#[derive(Clone)]
+pub struct HookedProvider<P> {
+    inner: P,
+    hooks: Vec<Arc<dyn EventHook + Send + Sync>>,
+    tasks: TaskRegistry,
+}
+
+impl<P: Provider> HookedProvider<P> {
+    /// Wraps the given provider without any hooks
+    pub fn new(inner: P) -> Self {
+        HookedProvider {
+            inner,
+            hooks: Vec::new(),
+            tasks: TaskRegistry::default(),
+        }
+    }
+
+    /// Runs the hooks with the given registry, so deliveries that are still in flight get a
+    /// chance to finish when the server shuts down
+    pub fn with_tasks(mut self, tasks: TaskRegistry) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    /// Adds a hook that is notified of every event
+    pub fn with_hook(mut self, hook: impl EventHook + Send + Sync + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    fn notify(&self, event: impl Into<Event>) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let event = Arc::new(event.into());
+        for hook in &self.hooks {
+            let hook = hook.clone();
+            let event = event.clone();
+            let name = format!("hook delivery of {}", event.name());
+            self.tasks
+                .spawn(name, async move { hook.on_event(&event).await });
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<P> Provider for HookedProvider<P>
+where
+    P: Provider + Send + Sync,
+{
+    async fn create_invoice(&self, inv: &crate::Invoice) -> Result<Vec<crate::Label>> {
+        self.create_invoice_by(inv, None).await
+    }
+
+    async fn create_invoice_by(
+        &self,
+        inv: &crate::Invoice,
+        by: Option<String>,
+    ) -> Result<Vec<crate::Label>> {
+        let missing = self.inner.create_invoice_by(inv, by).await?;
+        self.notify(InvoiceCreated {
+            bindle_id: inv.bindle.id.clone(),
+        });
+        Ok(missing)
+    }
+
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_yanked_invoice(id).await
+    }
+
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.yank_invoice_by(id, None).await
+    }
+
+    async fn yank_invoice_by<I>(&self, id: I, by: Option<String>) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        self.inner.yank_invoice_by(&parsed_id, by).await?;
+        self.notify(InvoiceYanked {
+            bindle_id: parsed_id,
+        });
+        Ok(())
+    }
+
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let removed = self.inner.delete_invoice(&parsed_id).await?;
+        self.notify(InvoiceDeleted {
+            bindle_id: parsed_id,
+        });
+        Ok(removed)
+    }
+
+    async fn add_signature<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.add_signature_by(id, signature, None).await
+    }
+
+    async fn add_signature_by<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+        by: Option<String>,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let (role, key) = (signature.role, signature.key.clone());
+        let inv = self
+            .inner
+            .add_signature_by(&parsed_id, signature, by)
+            .await?;
+        self.notify(InvoiceSigned {
+            bindle_id: parsed_id,
+            role,
+            key,
+        });
+        Ok(inv)
+    }
+
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_invoice_history(id).await
+    }
+
+    async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        self.inner
+            .create_parcel(&parsed_id, parcel_id, data)
+            .await?;
+        self.notify(ParcelUploaded {
+            bindle_id: parsed_id,
+            parcel: parcel_id.to_owned(),
+        });
+        Ok(())
+    }
+
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_parcel(bindle_id, parcel_id).await
+    }
+
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .get_parcel_range(bindle_id, parcel_id, offset, length)
+            .await
+    }
+
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.parcel_exists(bindle_id, parcel_id).await
+    }
+
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        self.inner.missing_shas(shas).await
+    }
+
+    async fn collect_garbage(&self, dry_run: bool) -> Result<super::gc::GcReport> {
+        self.inner.collect_garbage(dry_run).await
+    }
+
+    // The expired invoices are changed by the wrapped provider, so their events are sent here
+    async fn expire_invoices(
+        &self,
+        action: super::expiry::ExpiryAction,
+        dry_run: bool,
+    ) -> Result<super::expiry::ExpiryReport> {
+        let report = self.inner.expire_invoices(action, dry_run).await?;
+        if !dry_run {
+            for id in &report.expired {
+                match action {
+                    super::expiry::ExpiryAction::Yank => self.notify(InvoiceYanked {
+                        bindle_id: id.clone(),
+                    }),
+                    super::expiry::ExpiryAction::Delete => self.notify(InvoiceDeleted {
+                        bindle_id: id.clone(),
+                    }),
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+/// A hook that posts every event as JSON to a URL, such as the purge API of a CDN or a small
+/// service that translates events for one. The body is the event in the format described in the
+/// [`events`](crate::events) module. Any response other than a 2XX is treated as a
+/// failure, and failed deliveries are retried with an exponential backoff. If a secret is set, the
+/// body is signed and the signature sent in the [`SIGNATURE_HEADER`](SIGNATURE_HEADER)
+#[cfg(feature = "client")]
+#[derive(Clone)]
+pub struct HttpHook {
+    client: reqwest::Client,
+    url: url::Url,
+    retries: u32,
+    backoff: Duration,
+    secret: Option<Arc<[u8]>>,
+}
+
+#[cfg(feature = "client")]
+impl HttpHook {
+    /// The number of times a failed delivery is retried by default
+    pub const DEFAULT_RETRIES: u32 = 3;
+    /// The delay before the first retry by default. It doubles with every retry
+    pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(500);
+    /// How long a single delivery may take
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Creates a hook that posts events to the given URL
+    pub fn new(url: &str) -> std::result::Result<Self, url::ParseError> {
+        Ok(HttpHook {
+            client: reqwest::Client::builder()
+                .timeout(Self::TIMEOUT)
+                .build()
+                .expect("Unable to build HTTP client"),
+            url: url.parse()?,
+            retries: Self::DEFAULT_RETRIES,
+            backoff: Self::DEFAULT_BACKOFF,
+            secret: None,
+        })
+    }
+
+    /// Sets how many times a failed delivery is retried
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the delay before the first retry, which doubles with every following retry
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Signs every delivery with the given secret, which must be shared with the receiver
+    pub fn secret(mut self, secret: impl AsRef<[u8]>) -> Self {
+        self.secret = Some(Arc::from(secret.as_ref()));
+        self
+    }
+
+    async fn deliver(&self, event: &Event) -> std::result::Result<(), String> {
+        let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            req = req.header(SIGNATURE_HEADER, sign_payload(secret, &body));
+        }
+        let resp = req.body(body).send().await.map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("Received status {}", resp.status()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "client")]
+#[async_trait::async_trait]
+impl EventHook for HttpHook {
+    async fn on_event(&self, event: &Event) {
+        let mut delay = self.backoff;
+        for attempt in 0..=self.retries {
+            match self.deliver(event).await {
+                Ok(()) => {
+                    debug!("Delivered {} event to {}", event.name(), self.url);
+                    return;
+                }
+                Err(e) if attempt < self.retries => {
+                    debug!(
+                        "Delivering {} event to {} failed, retrying in {:?}: {}",
+                        event.name(),
+                        self.url,
+                        delay,
+                        e
+                    );
+                    tokio::time::delay_for(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => warn!(
+                    "Giving up on delivering {} event for {} to {} after {} attempts: {}",
+                    event.name(),
+                    event.bindle_id(),
+                    self.url,
+                    attempt + 1,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    use crate::testing;
+
+    struct ChannelHook(mpsc::UnboundedSender<Event>);
+
+    #[async_trait::async_trait]
+    impl EventHook for ChannelHook {
+        async fn on_event(&self, event: &Event) {
+            self.0.send(event.clone()).unwrap();
+        }
+    }
+
+    async fn next_event(rx: &mut mpsc::UnboundedReceiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("Timed out waiting for event")
+            .expect("Hook should still be alive")
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let (store, _) = testing::setup().await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let store = HookedProvider::new(store).with_hook(ChannelHook(tx));
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let id = scaffold.invoice.bindle.id.clone();
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to create invoice");
+        assert!(matches!(
+            next_event(&mut rx).await,
+            Event::InvoiceCreated(created) if created.bindle_id.to_string() == id.to_string()
+        ));
+
+        let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
+        store
+            .create_parcel(
+                &id,
+                &parcel.sha,
+                FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+            )
+            .await
+            .expect("Unable to create parcel");
+        assert!(matches!(
+            next_event(&mut rx).await,
+            Event::ParcelUploaded(uploaded) if uploaded.parcel == parcel.sha
+        ));
+
+        store
+            .yank_invoice(&id)
+            .await
+            .expect("Unable to yank invoice");
+        assert!(matches!(
+            next_event(&mut rx).await,
+            Event::InvoiceYanked(yanked) if yanked.bindle_id.to_string() == id.to_string()
+        ));
+
+        // Failed changes don't trigger events
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect_err("Creating an existing invoice should fail");
+        store
+            .get_yanked_invoice(&id)
+            .await
+            .expect("Reads should pass through");
+        assert!(rx.try_recv().is_err(), "No further events should be sent");
+    }
+
+    #[test]
+    fn test_signature() {
+        // Test case 2 of RFC 4231
+        let signature = sign_payload(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            signature
+        );
+        assert!(verify_signature(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            &signature
+        ));
+        assert!(!verify_signature(b"Jefe", b"what do ya want?", &signature));
+        assert!(!verify_signature(
+            b"Jeff",
+            b"what do ya want for nothing?",
+            &signature
+        ));
+        assert!(!verify_signature(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            "sha256="
+        ));
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[tokio::test]
+    async fn test_http_hook() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use warp::Filter;
+
+        // Fail the first delivery to check that it is retried
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let counter = attempts.clone();
+        let routes = warp::post()
+            .and(warp::header::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |signature: String, body: bytes::Bytes| {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return warp::http::StatusCode::SERVICE_UNAVAILABLE;
+                }
+                assert!(verify_signature(b"hunter2", &body, &signature));
+                tx.send(serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+                    .unwrap();
+                warp::http::StatusCode::NO_CONTENT
+            });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let hook = HttpHook::new(&format!("http://{}/purge", addr))
+            .expect("Invalid URL")
+            .backoff(Duration::from_millis(10))
+            .secret("hunter2");
+        let id: Id = "example.com/foo/1.0.0".parse().unwrap();
+        hook.on_event(&Event::from(ParcelUploaded {
+            bindle_id: id,
+            parcel: "abc123".to_owned(),
+        }))
+        .await;
+
+        let body = rx.try_recv().expect("Event should have been delivered");
+        assert_eq!(2, attempts.load(Ordering::SeqCst));
+        assert_eq!(
+            serde_json::json!({
+                "event": "parcel_created",
+                "bindleId": "example.com/foo/1.0.0",
+                "parcel": "abc123",
+            }),
+            body
+        );
+    }
+}
@@ -0,0 +1,634 @@
+//! A provider that keeps everything in memory, for embedding a bindle server in tests or
+//! short-lived tools without touching the file system.
+//!
+//! An [`InMemoryProvider`](InMemoryProvider) behaves like the
+//! [`FileProvider`](super::file::FileProvider): parcels are verified against their SHA on upload,
+//! invoice history is recorded, and garbage collection and expiry are supported. Everything is lost
+//! once the last clone of the provider is dropped, so it isn't meant for data that has to outlive
+//! the process
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, RwLock};
+
+use bytes::{Bytes, BytesMut};
+use log::{debug, trace};
+use sha2::{Digest, Sha256};
+use tokio::stream::{Stream, StreamExt};
+
+use crate::provider::expiry::{self, ExpiryAction, ExpiryReport};
+use crate::provider::gc::{GcReport, Marks};
+use crate::provider::{Provider, ProviderError, Result};
+use crate::search::Search;
+use crate::Id;
+
+/// A stored invoice along with its recorded history
+struct StoredInvoice {
+    invoice: crate::Invoice,
+    history: crate::InvoiceHistory,
+}
+
+/// Everything the provider stores. Invoices are keyed by the SHA of their ID, like the invoice
+/// directories of the file provider
+#[derive(Default)]
+struct State {
+    invoices: HashMap<String, StoredInvoice>,
+    parcels: HashMap<String, Bytes>,
+}
+
+/// An in-memory backend for storing and retrieving bindles and parcels.
+///
+/// Like the file provider, it needs a search engine implementation, whose index is updated when
+/// invoices are created, yanked or deleted. Clones share the same storage
+pub struct InMemoryProvider<T> {
+    index: T,
+    state: Arc<RwLock<State>>,
+}
+
+impl<T: Clone> Clone for InMemoryProvider<T> {
+    fn clone(&self) -> Self {
+        InMemoryProvider {
+            index: self.index.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Search + Send + Sync> InMemoryProvider<T> {
+    /// Creates an empty provider using the given search engine
+    pub fn new(index: T) -> Self {
+        InMemoryProvider {
+            index,
+            state: Arc::new(RwLock::new(State::default())),
+        }
+    }
+
+    /// Returns the IDs of all stored invoices, including yanked ones
+    pub fn invoice_ids(&self) -> Vec<Id> {
+        self.state
+            .read()
+            .unwrap()
+            .invoices
+            .values()
+            .map(|s| s.invoice.bindle.id.clone())
+            .collect()
+    }
+
+    /// Applies the update to the stored invoice and returns the updated invoice
+    fn update_invoice<F>(&self, id: &Id, update: F) -> Result<crate::Invoice>
+    where
+        F: FnOnce(&mut StoredInvoice) -> Result<()>,
+    {
+        let mut state = self.state.write().unwrap();
+        let stored = state
+            .invoices
+            .get_mut(&id.sha())
+            .ok_or(ProviderError::NotFound)?;
+        update(stored)?;
+        Ok(stored.invoice.clone())
+    }
+
+    /// Marks the parcels referenced by every stored invoice
+    fn mark(state: &State) -> Marks {
+        let mut marks = Marks::default();
+        for stored in state.invoices.values() {
+            marks.mark(&stored.invoice);
+        }
+        marks
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Search + Send + Sync> Provider for InMemoryProvider<T> {
+    async fn create_invoice(&self, inv: &crate::Invoice) -> Result<Vec<crate::Label>> {
+        self.create_invoice_by(inv, None).await
+    }
+
+    async fn create_invoice_by(
+        &self,
+        inv: &crate::Invoice,
+        by: Option<String>,
+    ) -> Result<Vec<crate::Label>> {
+        // It is illegal to create a yanked invoice.
+        if inv.yanked.unwrap_or(false) {
+            return Err(ProviderError::CreateYanked);
+        }
+        let missing = {
+            let mut state = self.state.write().unwrap();
+            for label in inv.parcel.iter().flatten().map(|p| &p.label) {
+                if let Some(data) = state.parcels.get(&label.sha256) {
+                    if data.len() as u64 != label.size {
+                        return Err(ProviderError::SizeMismatch {
+                            sha256: label.sha256.clone(),
+                            expected: label.size,
+                            actual: data.len() as u64,
+                        });
+                    }
+                }
+            }
+            let invoice_id = inv.bindle.id.sha();
+            if state.invoices.contains_key(&invoice_id) {
+                return Err(ProviderError::Exists);
+            }
+            debug!("Storing invoice with ID {:?}", inv.bindle.id);
+            state.invoices.insert(
+                invoice_id,
+                StoredInvoice {
+                    invoice: inv.clone(),
+                    history: crate::InvoiceHistory {
+                        event: vec![crate::HistoryEvent::now(
+                            crate::HistoryAction::Create,
+                            by,
+                            inv,
+                        )],
+                    },
+                },
+            );
+            inv.parcel
+                .iter()
+                .flatten()
+                .filter(|p| !state.parcels.contains_key(&p.label.sha256))
+                .map(|p| p.label.clone())
+                .collect()
+        };
+
+        // Same as the file provider, a failed index update is only logged
+        if let Err(e) = self.index.index(inv).await {
+            log::error!("Error indexing {:?}: {}", inv.bindle.id, e);
+        }
+        Ok(missing)
+    }
+
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        trace!("Getting invoice {:?}", parsed_id);
+        self.state
+            .read()
+            .unwrap()
+            .invoices
+            .get(&parsed_id.sha())
+            .map(|s| s.invoice.clone())
+            .ok_or(ProviderError::NotFound)
+    }
+
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.yank_invoice_by(id, None).await
+    }
+
+    async fn yank_invoice_by<I>(&self, id: I, by: Option<String>) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        trace!("Yanking invoice {:?}", parsed_id);
+        let inv = self.update_invoice(&parsed_id, |stored| {
+            // Yanking a yanked invoice is a no-op, so only record the first yank
+            if !stored.invoice.yanked.unwrap_or(false) {
+                stored.invoice.yanked = Some(true);
+                let event =
+                    crate::HistoryEvent::now(crate::HistoryAction::Yank, by, &stored.invoice);
+                stored.history.event.push(event);
+            }
+            Ok(())
+        })?;
+        if let Err(e) = self.index.index(&inv).await {
+            log::error!("Error indexing {:?}: {}", parsed_id, e);
+        }
+        Ok(())
+    }
+
+    async fn add_signature<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.add_signature_by(id, signature, None).await
+    }
+
+    async fn add_signature_by<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+        by: Option<String>,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let mut changed = false;
+        let inv = self.update_invoice(&parsed_id, |stored| {
+            if stored.invoice.yanked.unwrap_or(false) {
+                return Err(ProviderError::Yanked);
+            }
+            changed = stored.invoice.add_signature(signature)?;
+            if changed {
+                trace!("Adding a signature to invoice {:?}", parsed_id);
+                let event =
+                    crate::HistoryEvent::now(crate::HistoryAction::Sign, by, &stored.invoice);
+                stored.history.event.push(event);
+            }
+            Ok(())
+        })?;
+        if changed {
+            if let Err(e) = self.index.index(&inv).await {
+                log::error!("Error indexing {:?}: {}", parsed_id, e);
+            }
+        }
+        Ok(inv)
+    }
+
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        let removed = {
+            let mut state = self.state.write().unwrap();
+            let stored = state
+                .invoices
+                .remove(&parsed_id.sha())
+                .ok_or(ProviderError::NotFound)?;
+            debug!("Deleting invoice {:?}", parsed_id);
+            let marks = Self::mark(&state);
+            let mut removed: Vec<String> = Vec::new();
+            for sha in stored
+                .invoice
+                .parcel
+                .iter()
+                .flatten()
+                .map(|p| &p.label.sha256)
+            {
+                // Parcels listed in an invoice don't have to have been uploaded
+                if !marks.is_marked(sha) && state.parcels.remove(sha).is_some() {
+                    removed.push(sha.clone());
+                }
+            }
+            removed
+        };
+
+        if let Err(e) = self.index.remove(&parsed_id).await {
+            log::error!("Error removing {:?} from the index: {}", parsed_id, e);
+        }
+        Ok(removed)
+    }
+
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        self.state
+            .read()
+            .unwrap()
+            .invoices
+            .get(&parsed_id.sha())
+            .map(|s| s.history.clone())
+            .ok_or(ProviderError::NotFound)
+    }
+
+    async fn create_parcel<I, R, B>(&self, _bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        debug!("Creating parcel with SHA {}", parcel_id);
+        if self.state.read().unwrap().parcels.contains_key(parcel_id) {
+            return Err(ProviderError::Exists);
+        }
+
+        // Chunks are turned into bytes right away, as the buffer type isn't guaranteed to be Send
+        let mut data = data.map(|res| res.map(|mut chunk| chunk.to_bytes()));
+        let mut buffer = BytesMut::new();
+        let mut hasher = Sha256::new();
+        while let Some(res) = data.next().await {
+            let chunk = res?;
+            hasher.update(&chunk);
+            buffer.extend_from_slice(&chunk);
+        }
+        let actual = format!("{:x}", hasher.finalize());
+        if actual != parcel_id {
+            return Err(ProviderError::DigestMismatch {
+                expected: parcel_id.to_owned(),
+                actual,
+            });
+        }
+
+        // Another upload of the same parcel could have finished while this one was read
+        let mut state = self.state.write().unwrap();
+        if state.parcels.contains_key(parcel_id) {
+            return Err(ProviderError::Exists);
+        }
+        state.parcels.insert(parcel_id.to_owned(), buffer.freeze());
+        Ok(())
+    }
+
+    async fn get_parcel<I>(
+        &self,
+        _bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        debug!("Getting parcel with SHA {}", parcel_id);
+        let data = self
+            .state
+            .read()
+            .unwrap()
+            .parcels
+            .get(parcel_id)
+            .cloned()
+            .ok_or(ProviderError::NotFound)?;
+        Ok(Box::new(tokio::stream::once(Ok(data))))
+    }
+
+    async fn get_parcel_range<I>(
+        &self,
+        _bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let data = self
+            .state
+            .read()
+            .unwrap()
+            .parcels
+            .get(parcel_id)
+            .cloned()
+            .ok_or(ProviderError::NotFound)?;
+        let start = std::cmp::min(offset, data.len() as u64) as usize;
+        let end = length.map_or(data.len(), |l| {
+            std::cmp::min(offset.saturating_add(l), data.len() as u64) as usize
+        });
+        Ok(Box::new(tokio::stream::once(Ok(data.slice(start..end)))))
+    }
+
+    async fn parcel_exists<I>(&self, _bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        Ok(self.state.read().unwrap().parcels.contains_key(parcel_id))
+    }
+
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        let state = self.state.read().unwrap();
+        Ok(shas
+            .iter()
+            .filter(|sha| !state.parcels.contains_key(sha.as_str()))
+            .cloned()
+            .collect())
+    }
+
+    async fn collect_garbage(&self, dry_run: bool) -> Result<GcReport> {
+        let mut state = self.state.write().unwrap();
+        let marks = Self::mark(&state);
+        let mut report = marks.report(dry_run);
+        let unreferenced: Vec<String> = state
+            .parcels
+            .keys()
+            .filter(|sha| !marks.is_marked(sha))
+            .cloned()
+            .collect();
+        report.retained = (state.parcels.len() - unreferenced.len()) as u64;
+        for sha in unreferenced {
+            let size = if dry_run {
+                state.parcels.get(&sha).map(|d| d.len())
+            } else {
+                state.parcels.remove(&sha).map(|d| d.len())
+            };
+            report.removed_bytes += size.unwrap_or_default() as u64;
+            report.removed.push(sha);
+        }
+        debug!(
+            "Collected {} unreferenced parcels (dry run: {})",
+            report.removed.len(),
+            dry_run
+        );
+        Ok(report)
+    }
+
+    async fn expire_invoices(&self, action: ExpiryAction, dry_run: bool) -> Result<ExpiryReport> {
+        let now = expiry::now();
+        let mut report = ExpiryReport {
+            dry_run,
+            action,
+            ..ExpiryReport::default()
+        };
+        let invoices: Vec<crate::Invoice> = self
+            .state
+            .read()
+            .unwrap()
+            .invoices
+            .values()
+            .map(|s| s.invoice.clone())
+            .collect();
+        for inv in invoices {
+            report.checked += 1;
+            if !inv.is_expired_at(now)
+                || (action == ExpiryAction::Yank && inv.yanked.unwrap_or(false))
+            {
+                continue;
+            }
+            let id = inv.bindle.id;
+            if !dry_run {
+                debug!("Invoice {} expired (action: {})", id, action);
+                match action {
+                    ExpiryAction::Yank => self.yank_invoice(&id).await?,
+                    ExpiryAction::Delete => {
+                        self.delete_invoice(&id).await?;
+                    }
+                }
+            }
+            report.expired.push(id);
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::test_common::*;
+    use crate::search::StrictEngine;
+
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_should_create_yank_invoice() {
+        let index = StrictEngine::default();
+        let store = InMemoryProvider::new(index.clone());
+        let inv = invoice_fixture();
+
+        let missing = store
+            .create_invoice(&inv)
+            .await
+            .expect("invoice should be created");
+        assert_eq!(3, missing.len());
+        assert!(matches!(
+            store.create_invoice(&inv).await,
+            Err(ProviderError::Exists)
+        ));
+        let matches = index
+            .query(
+                inv.bindle.id.name().to_owned(),
+                String::new(),
+                crate::search::SearchOptions::default(),
+            )
+            .await
+            .expect("query should succeed");
+        assert_eq!(1, matches.invoices.len());
+
+        store
+            .yank_invoice_by(&inv.bindle.id, Some("admin".to_owned()))
+            .await
+            .expect("invoice should be yanked");
+        assert!(matches!(
+            store.get_invoice(&inv.bindle.id).await,
+            Err(ProviderError::Yanked)
+        ));
+        assert!(store
+            .get_yanked_invoice(&inv.bindle.id)
+            .await
+            .expect("yanked invoice should be loaded")
+            .yanked
+            .unwrap());
+        let history = store.get_invoice_history(&inv.bindle.id).await.unwrap();
+        assert_eq!(2, history.event.len());
+        assert_eq!(crate::HistoryAction::Yank, history.event[1].action);
+        assert_eq!(Some("admin"), history.event[1].by.as_deref());
+
+        let mut yanked = invoice_fixture();
+        yanked.yanked = Some(true);
+        assert!(matches!(
+            InMemoryProvider::new(StrictEngine::default())
+                .create_invoice(&yanked)
+                .await,
+            Err(ProviderError::CreateYanked)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_should_write_read_parcel() {
+        let store = InMemoryProvider::new(StrictEngine::default());
+        let inv = invoice_fixture();
+        let (label, data) = parcel_fixture("some parcel data").await;
+
+        let (_, wrong) = parcel_fixture("some other data").await;
+        assert!(matches!(
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &label.sha256,
+                    FramedRead::new(wrong, BytesCodec::new()),
+                )
+                .await,
+            Err(ProviderError::DigestMismatch { .. })
+        ));
+
+        store
+            .create_parcel(
+                &inv.bindle.id,
+                &label.sha256,
+                FramedRead::new(data, BytesCodec::new()),
+            )
+            .await
+            .expect("parcel should be created");
+        assert!(store
+            .parcel_exists(&inv.bindle.id, &label.sha256)
+            .await
+            .unwrap());
+        assert_eq!(
+            vec!["nope".to_owned()],
+            store
+                .missing_shas(&[label.sha256.clone(), "nope".to_owned()])
+                .await
+                .unwrap()
+        );
+        let parcel = store
+            .get_parcel(&inv.bindle.id, &label.sha256)
+            .await
+            .unwrap();
+        assert_eq!(
+            b"some parcel data".to_vec(),
+            read_all(parcel).await.unwrap()
+        );
+        let range = store
+            .get_parcel_range(&inv.bindle.id, &label.sha256, 5, Some(6))
+            .await
+            .unwrap();
+        assert_eq!(b"parcel".to_vec(), read_all(range).await.unwrap());
+        assert!(matches!(
+            store.get_parcel(&inv.bindle.id, "nope").await,
+            Err(ProviderError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_should_delete_and_collect_garbage() {
+        let store = InMemoryProvider::new(StrictEngine::default());
+        let (label, data) = parcel_fixture("referenced").await;
+        let (orphan, orphan_data) = parcel_fixture("unreferenced").await;
+        let mut inv = invoice_fixture();
+        inv.parcel = Some(vec![crate::Parcel {
+            label: label.clone(),
+            conditions: None,
+        }]);
+        store.create_invoice(&inv).await.unwrap();
+        for (sha, data) in [(&label.sha256, data), (&orphan.sha256, orphan_data)] {
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    sha,
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await
+                .unwrap();
+        }
+
+        let report = store.collect_garbage(true).await.unwrap();
+        assert_eq!(vec![orphan.sha256.clone()], report.removed);
+        assert_eq!(1, report.retained);
+        assert!(store
+            .parcel_exists(&inv.bindle.id, &orphan.sha256)
+            .await
+            .unwrap());
+        store.collect_garbage(false).await.unwrap();
+        assert!(!store
+            .parcel_exists(&inv.bindle.id, &orphan.sha256)
+            .await
+            .unwrap());
+
+        let removed = store.delete_invoice(&inv.bindle.id).await.unwrap();
+        assert_eq!(vec![label.sha256.clone()], removed);
+        assert!(store.invoice_ids().is_empty());
+        assert!(matches!(
+            store.get_yanked_invoice(&inv.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,394 @@
+//! A provider that lets a server act as a pull-through mirror of another Bindle server. This
+//! requires the `client` feature to be enabled
+//!
+//! Invoices and parcels are served from local storage. Anything that isn't stored locally yet is
+//! fetched from the upstream server, stored locally and then served, so every bindle only has to
+//! be transferred once. The mirror is read-only: bindles can only be created on the upstream
+//! server. Yanks made upstream after a bindle was mirrored are not picked up, as the mirrored copy
+//! is never checked against the upstream one again
+
+use std::convert::TryInto;
+
+use log::{debug, info, warn};
+use tokio::stream::{Stream, StreamExt};
+
+use crate::client::{Client, ClientError};
+use crate::provider::{Provider, ProviderError, Result};
+use crate::proxy::Proxy;
+use crate::Id;
+
+/// A provider that serves bindles from local storage, fetching and storing the ones it doesn't
+/// have yet from a remote provider (usually a [`Proxy`](crate::proxy::Proxy) to another server)
+#[derive(Clone)]
+pub struct MirrorProvider<Local, Remote = Proxy> {
+    remote: Remote,
+    local: Local,
+}
+
+impl<Local: Provider + Clone, Remote: Provider + Clone> MirrorProvider<Local, Remote> {
+    pub fn new(remote: Remote, local: Local) -> MirrorProvider<Local, Remote> {
+        MirrorProvider { remote, local }
+    }
+}
+
+impl<Local: Provider + Clone> MirrorProvider<Local, Proxy> {
+    /// Returns a mirror of the server the given client talks to, storing the mirrored bindles in
+    /// the given provider
+    pub fn from_client(client: Client, local: Local) -> MirrorProvider<Local, Proxy> {
+        MirrorProvider::new(Proxy::new(client), local)
+    }
+}
+
+/// The client reports missing resources with its own errors, which would otherwise be served as
+/// internal errors instead of as a missing resource
+fn from_remote(e: ProviderError) -> ProviderError {
+    match e {
+        ProviderError::ProxyError(ClientError::InvoiceNotFound)
+        | ProviderError::ProxyError(ClientError::ParcelNotFound) => ProviderError::NotFound,
+        e => e,
+    }
+}
+
+#[async_trait::async_trait]
+impl<Local, Remote> Provider for MirrorProvider<Local, Remote>
+where
+    Local: Provider + Send + Sync + Clone,
+    Remote: Provider + Send + Sync + Clone,
+{
+    async fn create_invoice(&self, _: &crate::Invoice) -> Result<Vec<crate::Label>> {
+        Err(ProviderError::Other(
+            "A mirror does not allow for creation of invoices, create them upstream instead"
+                .to_string(),
+        ))
+    }
+
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        match self.local.get_yanked_invoice(&parsed_id).await {
+            Err(ProviderError::NotFound) => (),
+            res => return res,
+        }
+        info!(
+            "Invoice {} is not mirrored yet, fetching it upstream",
+            parsed_id
+        );
+        let inv = self
+            .remote
+            .get_yanked_invoice(parsed_id)
+            .await
+            .map_err(from_remote)?;
+        // Yanked invoices can't be stored, so they are fetched again every time. Failing to store
+        // an invoice only means that it will be fetched again, so it is returned anyway
+        if inv.yanked.unwrap_or(false) {
+            debug!("Not mirroring yanked invoice {}", inv.bindle.id);
+        } else if let Err(e) = self.local.create_invoice(&inv).await {
+            warn!(
+                "Fetched invoice {} upstream, but was unable to store it: {:?}",
+                inv.bindle.id, e
+            );
+        }
+        Ok(inv)
+    }
+
+    // This only yanks the mirrored copy, so that a mirror can stop serving a bindle without
+    // waiting for the upstream server
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.local.yank_invoice(id).await
+    }
+
+    async fn yank_invoice_by<I>(&self, id: I, by: Option<String>) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.local.yank_invoice_by(id, by).await
+    }
+
+    // Same as yanking, this only removes the mirrored copy. It will be fetched again the next time
+    // it is requested, unless it was removed upstream as well
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.local.delete_invoice(id).await
+    }
+
+    // The history of a bindle is only authoritative on the upstream server
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.remote
+            .get_invoice_history(id)
+            .await
+            .map_err(from_remote)
+    }
+
+    async fn create_parcel<I, R, B>(&self, _: I, _: &str, _: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        Err(ProviderError::Other(
+            "A mirror does not allow for creation of parcels, create them upstream instead"
+                .to_string(),
+        ))
+    }
+
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        match self.local.get_parcel(&parsed_id, parcel_id).await {
+            Err(ProviderError::NotFound) => (),
+            res => return res,
+        }
+        info!(
+            "Parcel {} is not mirrored yet, fetching it upstream",
+            parcel_id
+        );
+        let data = self
+            .remote
+            .get_parcel(&parsed_id, parcel_id)
+            .await
+            .map_err(from_remote)?
+            .map(|res| res.map_err(std::io::Error::other));
+        match self.local.create_parcel(&parsed_id, parcel_id, data).await {
+            // Another request may have stored the parcel in the meantime
+            Ok(_) | Err(ProviderError::Exists) => self.local.get_parcel(parsed_id, parcel_id).await,
+            // Data that doesn't match its digest must never be served
+            Err(e @ ProviderError::DigestMismatch { .. }) => Err(e),
+            // The data has already been read while trying to store it, so it has to be fetched
+            // again
+            Err(e) => {
+                warn!(
+                    "Fetched parcel {} upstream, but was unable to store it: {:?}",
+                    parcel_id, e
+                );
+                self.remote
+                    .get_parcel(parsed_id, parcel_id)
+                    .await
+                    .map_err(from_remote)
+            }
+        }
+    }
+
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        match self
+            .local
+            .get_parcel_range(&parsed_id, parcel_id, offset, length)
+            .await
+        {
+            Err(ProviderError::NotFound) => (),
+            res => return res,
+        }
+        // The whole parcel is mirrored, so that later requests for other ranges are served locally
+        let data = self.get_parcel(parsed_id, parcel_id).await?;
+        Ok(Box::new(crate::async_util::slice_stream(
+            data, offset, length,
+        )))
+    }
+
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        if self.local.parcel_exists(&parsed_id, parcel_id).await? {
+            return Ok(true);
+        }
+        self.remote
+            .parcel_exists(parsed_id, parcel_id)
+            .await
+            .map_err(from_remote)
+    }
+
+    /// Parcels that aren't mirrored yet are checked on the remote provider
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        let missing = self.local.missing_shas(shas).await?;
+        if missing.is_empty() {
+            return Ok(missing);
+        }
+        self.remote
+            .missing_shas(&missing)
+            .await
+            .map_err(from_remote)
+    }
+
+    /// Collects garbage in the local storage. Parcels are mirrored separately from their invoices,
+    /// so this also removes mirrored parcels whose invoice isn't mirrored
+    async fn collect_garbage(&self, dry_run: bool) -> Result<super::gc::GcReport> {
+        self.local.collect_garbage(dry_run).await
+    }
+
+    /// Expires invoices in the local storage. Expired invoices that are still on the remote
+    /// provider are mirrored again the next time they are requested
+    async fn expire_invoices(
+        &self,
+        action: super::expiry::ExpiryAction,
+        dry_run: bool,
+    ) -> Result<super::expiry::ExpiryReport> {
+        self.local.expire_invoices(action, dry_run).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.local.flush().await
+    }
+}
+
+#[cfg(all(test, feature = "provider-file"))]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::provider::test_common::{invoice_fixture, parcel_fixture, read_all};
+    use crate::search::NoopEngine;
+
+    use tempfile::{tempdir, TempDir};
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    const CONTENT: &str = "mirrored content";
+
+    type TestMirror = MirrorProvider<FileProvider<NoopEngine>, FileProvider<NoopEngine>>;
+
+    /// Creates a mirror of a remote provider that contains a single bindle with one parcel
+    async fn setup() -> (
+        TestMirror,
+        FileProvider<NoopEngine>,
+        crate::Invoice,
+        Vec<TempDir>,
+    ) {
+        let (remote_dir, local_dir) = (tempdir().unwrap(), tempdir().unwrap());
+        let remote = FileProvider::new(remote_dir.path(), NoopEngine::default()).await;
+        let local = FileProvider::new(local_dir.path(), NoopEngine::default()).await;
+
+        let (label, data) = parcel_fixture(CONTENT).await;
+        let mut inv = invoice_fixture();
+        inv.parcel = Some(vec![crate::Parcel {
+            label: label.clone(),
+            conditions: None,
+        }]);
+        remote
+            .create_invoice(&inv)
+            .await
+            .expect("unable to create invoice");
+        remote
+            .create_parcel(
+                &inv.bindle.id,
+                &label.sha256,
+                FramedRead::new(data, BytesCodec::new()),
+            )
+            .await
+            .expect("unable to create parcel");
+        (
+            MirrorProvider::new(remote, local.clone()),
+            local,
+            inv,
+            vec![remote_dir, local_dir],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pulls_through() {
+        let (mirror, local, inv, _dirs) = setup().await;
+        let sha = inv.parcel.as_ref().unwrap()[0].label.sha256.clone();
+
+        assert!(matches!(
+            local.get_invoice(&inv.bindle.id).await,
+            Err(ProviderError::NotFound)
+        ));
+        assert!(mirror
+            .parcel_exists(&inv.bindle.id, &sha)
+            .await
+            .expect("unable to check parcel"));
+
+        let fetched = mirror
+            .get_invoice(&inv.bindle.id)
+            .await
+            .expect("invoice should be fetched upstream");
+        assert_eq!(inv.bindle.id.to_string(), fetched.bindle.id.to_string());
+        local
+            .get_invoice(&inv.bindle.id)
+            .await
+            .expect("invoice should be stored locally");
+
+        let range = mirror
+            .get_parcel_range(&inv.bindle.id, &sha, 9, None)
+            .await
+            .expect("parcel should be fetched upstream");
+        assert_eq!(b"content".to_vec(), read_all(range).await.unwrap());
+        let stored = local
+            .get_parcel(&inv.bindle.id, &sha)
+            .await
+            .expect("parcel should be stored locally");
+        assert_eq!(
+            CONTENT.as_bytes(),
+            read_all(stored).await.unwrap().as_slice()
+        );
+        assert_eq!(
+            CONTENT.as_bytes(),
+            read_all(mirror.get_parcel(&inv.bindle.id, &sha).await.unwrap())
+                .await
+                .unwrap()
+                .as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let (mirror, _, inv, _dirs) = setup().await;
+
+        assert!(matches!(
+            mirror.get_invoice("missing/1.0.0").await,
+            Err(ProviderError::NotFound)
+        ));
+        assert!(mirror.create_invoice(&inv).await.is_err());
+
+        // Yanking only affects the mirrored copy
+        mirror.get_invoice(&inv.bindle.id).await.unwrap();
+        mirror
+            .yank_invoice(&inv.bindle.id)
+            .await
+            .expect("unable to yank invoice");
+        assert!(matches!(
+            mirror.get_invoice(&inv.bindle.id).await,
+            Err(ProviderError::Yanked)
+        ));
+        assert!(!mirror
+            .remote
+            .get_yanked_invoice(&inv.bindle.id)
+            .await
+            .unwrap()
+            .yanked
+            .unwrap_or(false));
+    }
+}
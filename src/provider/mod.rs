@@ -21,7 +21,20 @@
 //! will generally contain another Provider implementation or an HTTP client to talk to another
 //! server upstream
 
+#[cfg(feature = "compression")]
+pub mod compress;
+pub mod encrypt;
+#[cfg(feature = "provider-file")]
+pub mod expiry;
 pub mod file;
+pub mod gc;
+pub mod hooks;
+pub mod memory;
+#[cfg(feature = "client")]
+pub mod mirror;
+pub mod naming;
+mod sidecar;
+pub mod worm;
 
 #[cfg(test)]
 pub(crate) mod test_common;
@@ -46,9 +59,24 @@ pub trait Provider {
     /// This takes an invoice and creates it in storage.
     ///
     /// It must verify that each referenced parcel is present in storage. Any parcel that is not
-    /// present must be returned in the list of labels.
+    /// present must be returned in the list of labels. Providers that know the size of the stored
+    /// parcels should also reject invoices whose labels don't match the size of a present parcel
+    /// with a [`SizeMismatch`](ProviderError::SizeMismatch) error.
     async fn create_invoice(&self, inv: &super::Invoice) -> Result<Vec<super::Label>>;
 
+    /// Same as [`create_invoice`](Provider::create_invoice), but records the given identity as
+    /// the one that created the invoice in its [history](Provider::get_invoice_history).
+    ///
+    /// The default implementation drops the identity, which is fine for providers that don't
+    /// record history
+    async fn create_invoice_by(
+        &self,
+        inv: &super::Invoice,
+        _by: Option<String>,
+    ) -> Result<Vec<super::Label>> {
+        self.create_invoice(inv).await
+    }
+
     /// Load an invoice and return it
     ///
     /// This will return an invoice if the bindle exists and is not yanked. The default
@@ -79,6 +107,106 @@ pub trait Provider {
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
 
+    /// Same as [`yank_invoice`](Provider::yank_invoice), but records the given identity as the
+    /// one that yanked the invoice in its history. The default implementation drops the identity
+    async fn yank_invoice_by<I>(&self, id: I, _by: Option<String>) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.yank_invoice(id).await
+    }
+
+    /// Permanently removes an invoice, whether it is yanked or not, along with all of its parcels
+    /// that aren't referenced by any other invoice. Returns the SHAs of the removed parcels.
+    /// Unlike yanking, this cannot be undone, so it is meant for cases like legal takedowns where
+    /// the content must not be served anymore.
+    ///
+    /// The default implementation returns an error, as not every provider is able to delete what
+    /// it stores
+    async fn delete_invoice<I>(&self, _id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        Err(ProviderError::Other(
+            "This provider does not support deleting invoices".to_string(),
+        ))
+    }
+
+    /// Returns the recorded history of state changes (such as creation and yanking) for the given
+    /// invoice. This works for yanked invoices as well, as that is when the history is most useful.
+    ///
+    /// The default implementation returns an error, as not every provider is able to keep an audit
+    /// trail
+    async fn get_invoice_history<I>(&self, _id: I) -> Result<super::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        Err(ProviderError::Other(
+            "This provider does not record invoice history".to_string(),
+        ))
+    }
+
+    /// Returns the metadata of the given invoice, such as when it was created and last modified.
+    /// This works for yanked invoices as well, as the metadata states whether the invoice is
+    /// yanked.
+    ///
+    /// The default implementation takes the timestamps from the
+    /// [invoice history](Provider::get_invoice_history), leaving them unknown for providers that
+    /// don't record history
+    async fn get_metadata<I>(&self, id: I) -> Result<super::InvoiceMetadata>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let inv = self.get_yanked_invoice(parsed_id.clone()).await?;
+        let history = self
+            .get_invoice_history(parsed_id)
+            .await
+            .unwrap_or_default();
+        Ok(super::InvoiceMetadata::new(&inv, &history))
+    }
+
+    /// Adds the signature to the stored invoice, which must not be yanked, and returns the
+    /// updated invoice. This is how an invoice is countersigned after it was created. The signature
+    /// must be valid for the invoice, so it can't have been made over different parcels. Adding a
+    /// signature the invoice already has in the same role with the same key changes nothing.
+    ///
+    /// The default implementation returns an error, as not every provider is able to change what
+    /// it stores
+    async fn add_signature<I>(
+        &self,
+        _id: I,
+        _signature: crate::signature::Signature,
+    ) -> Result<super::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        Err(ProviderError::Other(
+            "This provider does not support adding signatures".to_string(),
+        ))
+    }
+
+    /// Same as [`add_signature`](Provider::add_signature), but records the given identity as the
+    /// one that added the signature in the history of the invoice. The default implementation
+    /// drops the identity
+    async fn add_signature_by<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+        _by: Option<String>,
+    ) -> Result<super::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.add_signature(id, signature).await
+    }
+
     /// Creates a parcel with the associated sha. The parcel can be anything that implements
     /// `Stream`
     ///
@@ -104,6 +232,29 @@ pub trait Provider {
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
 
+    /// Get a section of a specific parcel using its SHA, starting at the given byte offset and
+    /// containing at most `length` bytes (or the rest of the parcel if `length` is `None`). Callers
+    /// are responsible for making sure the range is within the bounds of the parcel.
+    ///
+    /// The default implementation reads the whole parcel and discards the data outside of the
+    /// range. Providers that can read from an offset directly should override it
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let data = self.get_parcel(bindle_id, parcel_id).await?;
+        Ok(Box::new(crate::async_util::slice_stream(
+            data, offset, length,
+        )))
+    }
+
     /// Checks if the given parcel exists in storage.
     ///
     /// This should not load the full parcel but only indicate if the parcel exists. For some
@@ -112,6 +263,55 @@ pub trait Provider {
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>;
+
+    /// Returns which of the given parcel SHAs aren't stored for any bindle, in the order they were
+    /// given. Clients use this to skip uploading parcels that another bindle already brought along.
+    ///
+    /// The default implementation returns an error, as only providers that store parcels
+    /// independently of their bindles can check them without a bindle ID
+    async fn missing_shas(&self, _shas: &[String]) -> Result<Vec<String>> {
+        Err(ProviderError::Other(
+            "This provider does not support checking parcels without a bindle".to_string(),
+        ))
+    }
+
+    /// Removes all parcels that are not referenced by any invoice, including yanked ones, and
+    /// returns what was removed (see the [`gc`](gc) module). If `dry_run` is set, nothing is
+    /// removed, but the report still lists the parcels that would have been.
+    ///
+    /// The default implementation returns an error, as only providers that can list everything
+    /// they store are able to tell which parcels are unreferenced
+    async fn collect_garbage(&self, _dry_run: bool) -> Result<gc::GcReport> {
+        Err(ProviderError::Other(
+            "This provider does not support garbage collection".to_string(),
+        ))
+    }
+
+    /// Yanks or deletes every invoice whose [`EXPIRES_AT`](crate::annotations::EXPIRES_AT)
+    /// annotation has passed (see the [`expiry`](expiry) module) and returns which ones were. If
+    /// `dry_run` is set, nothing is changed, but the report still lists the invoices that would
+    /// have been.
+    ///
+    /// The default implementation returns an error, as only providers that can list everything
+    /// they store are able to find the expired invoices
+    async fn expire_invoices(
+        &self,
+        _action: expiry::ExpiryAction,
+        _dry_run: bool,
+    ) -> Result<expiry::ExpiryReport> {
+        Err(ProviderError::Other(
+            "This provider does not support expiring invoices".to_string(),
+        ))
+    }
+
+    /// Persists anything the provider only keeps in memory, such as a snapshot of its search
+    /// index, so nothing is lost when the provider is dropped. Servers call this once they stopped
+    /// serving requests.
+    ///
+    /// The default implementation does nothing, which suits providers that write everything through
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// ProviderError describes the possible error states when storing and retrieving bindles.
@@ -135,9 +335,25 @@ pub enum ProviderError {
     /// The error returned when the given `Id` was invalid and unable to be parsed
     #[error("invalid ID given")]
     InvalidId,
-    /// An uploaded parcel does not match the SHA-256 sum provided with its label
-    #[error("digest does not match")]
-    DigestMismatch,
+    /// An uploaded parcel does not match the SHA-256 sum provided with its label. Contains the
+    /// expected and the actual sum of the data
+    #[error("digest does not match: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+    /// The data of a parcel does not have the size given in its label. Contains the SHA of the
+    /// parcel along with the expected and actual size in bytes
+    #[error("size of parcel {sha256} does not match: expected {expected} bytes, got {actual}")]
+    SizeMismatch {
+        sha256: String,
+        expected: u64,
+        actual: u64,
+    },
+    /// The operation would change or remove data in write-once storage (see the
+    /// [`worm`](worm) module). Contains a description of the refused operation
+    #[error("storage is write-once, unable to {0}")]
+    WriteOnce(String),
+    /// A signature added to an invoice is not valid for it
+    #[error("invalid signature: {0}")]
+    InvalidSignature(#[from] crate::signature::SignatureError),
     /// An error that occurs when the provider implementation uses a proxy and that proxy request
     /// encounters an error. Only available with the `client` feature enabled
     #[cfg(feature = "client")]
@@ -165,6 +381,19 @@ impl From<ParseError> for ProviderError {
     }
 }
 
+#[cfg(any(feature = "client", feature = "server", feature = "provider-file"))]
+impl From<crate::async_util::Mismatch> for ProviderError {
+    fn from(m: crate::async_util::Mismatch) -> ProviderError {
+        match m {
+            crate::async_util::Mismatch::Digest { expected, actual } => {
+                ProviderError::DigestMismatch { expected, actual }
+            }
+            // Providers don't know the size of parcels, so they only ever check digests
+            m => ProviderError::Other(m.to_string()),
+        }
+    }
+}
+
 impl From<std::convert::Infallible> for ProviderError {
     fn from(_: std::convert::Infallible) -> ProviderError {
         // This can never happen (by definition of infallible), so it doesn't matter what we return
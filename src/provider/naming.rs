@@ -0,0 +1,179 @@
+//! Configurable schemes for deriving the canonical (storage) name of a bindle from its ID.
+//!
+//! Terminal providers don't store bindles using their name and version directly, as that would
+//! impose naming constraints and security issues on the storage layout. Instead they use an opaque
+//! canonical name derived from the ID. By default, this is the hex encoded SHA-256 sum as returned
+//! by [`Id::sha`](crate::Id::sha), but providers can choose a different algorithm or encoding (for
+//! example, to get shorter keys). A [`NameMapping`](NameMapping) keeps track of the current scheme
+//! and all schemes previously used by a store so existing data can still be found after a change.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::Id;
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// The hashing algorithm used to derive a canonical name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+/// The encoding used to turn the hash into a string. All encodings are guaranteed to only contain
+/// characters that are safe to use in paths and URLs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Lowercase hexadecimal
+    Hex,
+    /// Lowercase, unpadded base32 using the RFC 4648 alphabet. This produces names that are
+    /// roughly 20% shorter than hex
+    Base32,
+}
+
+/// A combination of algorithm and encoding used for deriving canonical names. The default scheme
+/// (SHA-256 and hex) matches [`Id::sha`](crate::Id::sha)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamingScheme {
+    pub algorithm: HashAlgorithm,
+    pub encoding: Encoding,
+}
+
+impl Default for NamingScheme {
+    fn default() -> Self {
+        NamingScheme {
+            algorithm: HashAlgorithm::Sha256,
+            encoding: Encoding::Hex,
+        }
+    }
+}
+
+impl NamingScheme {
+    /// Returns the canonical name for the given ID using this scheme
+    pub fn canonical_name(&self, id: &Id) -> String {
+        // Hash the same "name/version" data as `Id::sha` so the default scheme is compatible
+        let data = format!("{}/{}", id.name(), id.version_string());
+        let digest = match self.algorithm {
+            HashAlgorithm::Sha256 => Sha256::digest(data.as_bytes()).to_vec(),
+            HashAlgorithm::Sha512 => Sha512::digest(data.as_bytes()).to_vec(),
+        };
+        match self.encoding {
+            Encoding::Hex => digest.iter().map(|b| format!("{:02x}", b)).collect(),
+            Encoding::Base32 => base32(&digest),
+        }
+    }
+}
+
+/// The record of naming schemes used by a store. New data is always written using the `current`
+/// scheme, while lookups fall back to the `previous` schemes (most recent first) so that data
+/// written before a migration remains accessible
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NameMapping {
+    pub current: NamingScheme,
+    #[serde(default)]
+    pub previous: Vec<NamingScheme>,
+}
+
+impl NameMapping {
+    /// Switches the mapping over to the given scheme, retaining the current scheme for lookups.
+    /// Returns `true` if the mapping was changed
+    pub fn migrate_to(&mut self, scheme: NamingScheme) -> bool {
+        if self.current == scheme {
+            return false;
+        }
+        self.previous.retain(|s| *s != scheme);
+        self.previous.insert(0, self.current);
+        self.current = scheme;
+        true
+    }
+
+    /// Returns the canonical name of the given ID under the current scheme
+    pub fn canonical_name(&self, id: &Id) -> String {
+        self.current.canonical_name(id)
+    }
+
+    /// Returns the canonical names of the given ID under every known scheme, starting with the
+    /// current one
+    pub fn candidate_names(&self, id: &Id) -> Vec<String> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .map(|s| s.canonical_name(id))
+            .collect()
+    }
+}
+
+fn base32(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 8 / 5 + 1);
+    let mut buffer: u16 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_scheme_matches_id_sha() {
+        let id: Id = "example.com/foo/1.2.3".parse().unwrap();
+        assert_eq!(id.sha(), NamingScheme::default().canonical_name(&id));
+    }
+
+    #[test]
+    fn test_base32() {
+        // Test vectors from RFC 4648, minus the padding
+        assert_eq!("", base32(b""));
+        assert_eq!("my", base32(b"f"));
+        assert_eq!("mzxq", base32(b"fo"));
+        assert_eq!("mzxw6", base32(b"foo"));
+        assert_eq!("mzxw6yq", base32(b"foob"));
+        assert_eq!("mzxw6ytboi", base32(b"foobar"));
+
+        let id: Id = "example.com/foo/1.2.3".parse().unwrap();
+        let name = NamingScheme {
+            algorithm: HashAlgorithm::Sha256,
+            encoding: Encoding::Base32,
+        }
+        .canonical_name(&id);
+        assert_eq!(52, name.len());
+    }
+
+    #[test]
+    fn test_migration() {
+        let base32 = NamingScheme {
+            algorithm: HashAlgorithm::Sha256,
+            encoding: Encoding::Base32,
+        };
+        let mut mapping = NameMapping::default();
+        assert!(!mapping.migrate_to(NamingScheme::default()));
+        assert!(mapping.migrate_to(base32));
+        assert_eq!(base32, mapping.current);
+        assert_eq!(vec![NamingScheme::default()], mapping.previous);
+
+        // Migrating back shouldn't leave duplicates around
+        assert!(mapping.migrate_to(NamingScheme::default()));
+        assert_eq!(vec![base32], mapping.previous);
+
+        let id: Id = "foo/1.0.0".parse().unwrap();
+        assert_eq!(
+            vec![id.sha(), base32.canonical_name(&id)],
+            mapping.candidate_names(&id)
+        );
+    }
+}
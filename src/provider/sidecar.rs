@@ -0,0 +1,215 @@
+//! Shared plumbing for provider wrappers that transform parcel data (such as compressing or
+//! encrypting it) before storing it in the wrapped provider.
+//!
+//! As providers verify parcel data against the SHA it is stored under, the transformed data is
+//! stored in the wrapped provider under its own SHA. A small sidecar file per parcel records the
+//! original SHA and size along with whatever the wrapper needs to read the data back. Parcels
+//! without a sidecar file were stored before the provider was wrapped and are passed through as
+//! they are.
+//!
+//! Because the wrapped provider only knows the transformed data by a SHA that no invoice
+//! references, its garbage collection would remove all of it. Wrappers using sidecar files
+//! therefore refuse to collect garbage. For the same reason, the wrapped provider can't tell
+//! whether deleted invoices shared transformed data with others, so deleting leaves it behind.
+
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use super::{Provider, ProviderError, Result};
+use crate::Id;
+
+const METADATA_EXTENSION: &str = "toml";
+
+/// The metadata recorded in the sidecar file of a parcel
+pub(crate) trait ParcelMetadata: Serialize + DeserializeOwned + Send + Sync {
+    /// The SHA of the original data, which the parcel is known by
+    fn sha256(&self) -> &str;
+    /// The size of the original data in bytes
+    fn size(&self) -> u64;
+}
+
+/// A directory of sidecar files, along with the partial files of parcels that are being stored
+pub(crate) struct Sidecars<M> {
+    dir: PathBuf,
+    partial_extension: &'static str,
+    metadata: PhantomData<fn() -> M>,
+}
+
+impl<M> Clone for Sidecars<M> {
+    fn clone(&self) -> Self {
+        Sidecars {
+            dir: self.dir.clone(),
+            partial_extension: self.partial_extension,
+            metadata: PhantomData,
+        }
+    }
+}
+
+impl<M: ParcelMetadata> Sidecars<M> {
+    /// Keeps the sidecar files in the given directory, which is created when the first parcel is
+    /// stored. Partial files are named after the parcel SHA with the given extension
+    pub(crate) fn new<D: AsRef<Path>>(dir: D, partial_extension: &'static str) -> Self {
+        Sidecars {
+            dir: dir.as_ref().to_owned(),
+            partial_extension,
+            metadata: PhantomData,
+        }
+    }
+
+    fn metadata_path(&self, sha: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", sha, METADATA_EXTENSION))
+    }
+
+    fn partial_path(&self, sha: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", sha, self.partial_extension))
+    }
+
+    /// Loads the sidecar file of the given parcel, returning `None` if the parcel wasn't stored
+    /// through the wrapper
+    pub(crate) async fn load_metadata(&self, sha: &str) -> Result<Option<M>> {
+        match tokio::fs::read(self.metadata_path(sha)).await {
+            Ok(raw) => Ok(Some(toml::from_slice(&raw)?)),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the sidecar file under a temporary name first, so a parcel never shows up before
+    /// its metadata is complete
+    async fn store_metadata(&self, metadata: &M) -> Result<()> {
+        let path = self.metadata_path(metadata.sha256());
+        let temp = path.with_extension(format!("{}.part", METADATA_EXTENSION));
+        tokio::fs::write(&temp, toml::to_vec(metadata)?).await?;
+        tokio::fs::rename(&temp, &path).await?;
+        Ok(())
+    }
+
+    /// Creates the partial file the transformed data of the given parcel is written to. Only one
+    /// upload of the same parcel can hold the partial file at a time, so an
+    /// [`Exists`](ProviderError::Exists) error is returned if it is already there
+    pub(crate) async fn create_partial(&self, sha: &str) -> Result<File> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        match OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(self.partial_path(sha))
+            .await
+        {
+            Ok(f) => Ok(f),
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::AlreadyExists) => {
+                Err(ProviderError::Exists)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stores the contents of the partial file of the given parcel in the wrapped provider under
+    /// the SHA of the transformed data
+    pub(crate) async fn store_partial<P>(
+        &self,
+        inner: &P,
+        bindle_id: Id,
+        sha: &str,
+        stored_sha: &str,
+    ) -> Result<()>
+    where
+        P: Provider,
+    {
+        let reader = File::open(self.partial_path(sha)).await?;
+        match inner
+            .create_parcel(
+                bindle_id,
+                stored_sha,
+                FramedRead::new(reader, BytesCodec::new()),
+            )
+            .await
+        {
+            // Data that already exists under its SHA is the same data, left over from an earlier
+            // attempt where storing the sidecar file failed
+            Ok(_) | Err(ProviderError::Exists) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records the metadata of a parcel once its transformed data was stored, and removes the
+    /// partial file whether or not that succeeded
+    pub(crate) async fn finish(&self, sha: &str, stored: Result<M>) -> Result<()> {
+        let res = match stored {
+            Ok(metadata) => self.store_metadata(&metadata).await,
+            Err(e) => Err(e),
+        };
+        let partial_path = self.partial_path(sha);
+        if let Err(e) = tokio::fs::remove_file(&partial_path).await {
+            warn!(
+                "Unable to clean up partial parcel at {}: {}",
+                partial_path.display(),
+                e
+            );
+        }
+        res
+    }
+
+    /// Checks the labels of a new invoice against the parcels stored through the wrapper, which
+    /// the wrapped provider can't check as it only knows their transformed data
+    pub(crate) async fn check_sizes(&self, inv: &crate::Invoice) -> Result<()> {
+        for label in inv.parcel.iter().flatten().map(|p| &p.label) {
+            if let Some(metadata) = self.load_metadata(&label.sha256).await? {
+                if metadata.size() != label.size {
+                    return Err(ProviderError::SizeMismatch {
+                        sha256: label.sha256.clone(),
+                        expected: label.size,
+                        actual: metadata.size(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the parcels stored through the wrapper from the labels the wrapped provider reported
+    /// as missing
+    pub(crate) async fn filter_missing(
+        &self,
+        labels: Vec<crate::Label>,
+    ) -> Result<Vec<crate::Label>> {
+        let mut missing = Vec::new();
+        for label in labels {
+            if self.load_metadata(&label.sha256).await?.is_none() {
+                missing.push(label);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Returns which of the given SHAs are neither stored through the wrapper nor in the wrapped
+    /// provider
+    pub(crate) async fn missing_shas<P>(&self, inner: &P, shas: &[String]) -> Result<Vec<String>>
+    where
+        P: Provider + Sync,
+    {
+        let mut untransformed = Vec::new();
+        for sha in shas {
+            if self.load_metadata(sha).await?.is_none() {
+                untransformed.push(sha.clone());
+            }
+        }
+        if untransformed.is_empty() {
+            return Ok(untransformed);
+        }
+        inner.missing_shas(&untransformed).await
+    }
+}
+
+/// Returns the error for refusing to collect garbage, as even a dry run would report all of the
+/// transformed data as unreferenced. `kind` describes the parcels, e.g. `compressed`
+pub(crate) fn refuse_collect_garbage(kind: &str) -> ProviderError {
+    ProviderError::Other(format!(
+        "Garbage collection is not supported for {} parcels",
+        kind
+    ))
+}
@@ -3,6 +3,7 @@ use std::io::SeekFrom;
 use sha2::{Digest, Sha256};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
+use tokio::stream::{Stream, StreamExt};
 
 /// Creates a fake parcel in memory using the given content for use in testing
 pub async fn parcel_fixture(content: &str) -> (crate::Label, tokio::fs::File) {
@@ -21,7 +22,7 @@ pub async fn parcel_fixture(content: &str) -> (crate::Label, tokio::fs::File) {
             sha256: sha.to_owned(),
             media_type: "text/toml".to_owned(),
             name: "foo.toml".to_owned(),
-            size: 6,
+            size: content.len() as u64,
             ..Default::default()
         },
         data,
@@ -58,6 +59,7 @@ pub fn invoice_fixture() -> crate::Invoice {
         bindle_version: crate::BINDLE_VERSION_1.to_owned(),
         yanked: None,
         annotations: None,
+        requires: None,
         bindle: crate::BindleSpec {
             id: "foo/1.2.3".parse().unwrap(),
             description: Some("bar".to_owned()),
@@ -73,5 +75,17 @@ pub fn invoice_fixture() -> crate::Invoice {
                 .collect(),
         ),
         group: None,
+        signature: None,
     }
 }
+
+/// Reads a parcel stream returned by a provider to the end, returning the first error it yields
+pub async fn read_all(
+    mut stream: impl Stream<Item = super::Result<bytes::Bytes>> + Unpin,
+) -> super::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
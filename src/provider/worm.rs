@@ -0,0 +1,281 @@
+//! A provider wrapper that enforces write-once, read-many (WORM) semantics, such as for release
+//! archives that must be kept unchanged for compliance reasons.
+//!
+//! Wrapping a provider in a [`WormProvider`](WormProvider) makes sure that nothing can be changed
+//! or removed once it was written, no matter which code path asks for it. Yanking or deleting
+//! invoices and collecting garbage are refused with a [`WriteOnce`](ProviderError::WriteOnce)
+//! error. Creating an invoice or a parcel that already exists is refused with an
+//! [`Exists`](ProviderError::Exists) error as usual, but regardless of whether the wrapped provider
+//! would overwrite it. Every refused attempt is logged as a warning. The check for existing data
+//! and the write that follows aren't atomic, so the wrapped provider should still refuse to
+//! overwrite anything on its own, like all of the built-in providers do
+
+use std::convert::TryInto;
+
+use log::warn;
+use tokio::stream::Stream;
+
+use super::{Provider, ProviderError, Result};
+use crate::Id;
+
+/// A provider that only passes writes of new invoices and parcels on to the wrapped provider, and
+/// refuses anything that would change or remove what was already written. All reads are passed
+/// through untouched
+#[derive(Clone)]
+pub struct WormProvider<P> {
+    inner: P,
+}
+
+impl<P: Provider> WormProvider<P> {
+    pub fn new(inner: P) -> Self {
+        WormProvider { inner }
+    }
+
+    /// Returns the wrapped provider. Anything done with it directly is not checked
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+/// Logs the refused operation and returns the error for it
+fn refuse(operation: String) -> ProviderError {
+    warn!("Refused to {} in write-once storage", operation);
+    ProviderError::WriteOnce(operation)
+}
+
+#[async_trait::async_trait]
+impl<P> Provider for WormProvider<P>
+where
+    P: Provider + Send + Sync,
+{
+    async fn create_invoice(&self, inv: &crate::Invoice) -> Result<Vec<crate::Label>> {
+        self.create_invoice_by(inv, None).await
+    }
+
+    async fn create_invoice_by(
+        &self,
+        inv: &crate::Invoice,
+        by: Option<String>,
+    ) -> Result<Vec<crate::Label>> {
+        match self.inner.get_yanked_invoice(&inv.bindle.id).await {
+            Err(ProviderError::NotFound) => self.inner.create_invoice_by(inv, by).await,
+            Ok(_) => {
+                warn!(
+                    "Refused to overwrite invoice {} in write-once storage",
+                    inv.bindle.id
+                );
+                Err(ProviderError::Exists)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_yanked_invoice(id).await
+    }
+
+    // Yanking is refused as well, as it changes the stored invoice
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        Err(refuse(format!("yank invoice {}", parsed_id)))
+    }
+
+    async fn add_signature<I>(
+        &self,
+        id: I,
+        _signature: crate::signature::Signature,
+    ) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        Err(refuse(format!("add a signature to invoice {}", parsed_id)))
+    }
+
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id: Id = id.try_into().map_err(|e| e.into())?;
+        Err(refuse(format!("delete invoice {}", parsed_id)))
+    }
+
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_invoice_history(id).await
+    }
+
+    async fn create_parcel<I, R, B>(&self, bindle_id: I, parcel_id: &str, data: R) -> Result<()>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+        R: Stream<Item = std::io::Result<B>> + Unpin + Send + Sync + 'static,
+        B: bytes::Buf,
+    {
+        let parsed_id: Id = bindle_id.try_into().map_err(|e| e.into())?;
+        if self.inner.parcel_exists(&parsed_id, parcel_id).await? {
+            warn!(
+                "Refused to overwrite parcel {} in write-once storage",
+                parcel_id
+            );
+            return Err(ProviderError::Exists);
+        }
+        self.inner.create_parcel(parsed_id, parcel_id, data).await
+    }
+
+    async fn get_parcel<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.get_parcel(bindle_id, parcel_id).await
+    }
+
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner
+            .get_parcel_range(bindle_id, parcel_id, offset, length)
+            .await
+    }
+
+    async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        self.inner.parcel_exists(bindle_id, parcel_id).await
+    }
+
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        self.inner.missing_shas(shas).await
+    }
+
+    // A dry run doesn't remove anything, so it can still be used to find unreferenced parcels
+    async fn collect_garbage(&self, dry_run: bool) -> Result<super::gc::GcReport> {
+        if !dry_run {
+            return Err(refuse("collect garbage".to_owned()));
+        }
+        self.inner.collect_garbage(dry_run).await
+    }
+
+    // Like garbage collection, only a dry run is allowed
+    async fn expire_invoices(
+        &self,
+        action: super::expiry::ExpiryAction,
+        dry_run: bool,
+    ) -> Result<super::expiry::ExpiryReport> {
+        if !dry_run {
+            return Err(refuse("expire invoices".to_owned()));
+        }
+        self.inner.expire_invoices(action, dry_run).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[cfg(all(test, feature = "provider-file"))]
+mod test {
+    use super::*;
+    use crate::provider::file::FileProvider;
+    use crate::provider::test_common::{invoice_fixture, parcel_fixture};
+    use crate::search::NoopEngine;
+
+    use tempfile::tempdir;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_write_once() {
+        let root = tempdir().expect("create tempdir");
+        let store = WormProvider::new(FileProvider::new(root.path(), NoopEngine::default()).await);
+
+        let (label, data) = parcel_fixture("written once").await;
+        let mut inv = invoice_fixture();
+        inv.parcel = Some(vec![crate::Parcel {
+            label: label.clone(),
+            conditions: None,
+        }]);
+        store
+            .create_invoice(&inv)
+            .await
+            .expect("new invoices should be created");
+        store
+            .create_parcel(
+                &inv.bindle.id,
+                &label.sha256,
+                FramedRead::new(data, BytesCodec::new()),
+            )
+            .await
+            .expect("new parcels should be created");
+
+        assert!(matches!(
+            store.create_invoice(&inv).await,
+            Err(ProviderError::Exists)
+        ));
+        let (_, data) = parcel_fixture("written once").await;
+        assert!(matches!(
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &label.sha256,
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await,
+            Err(ProviderError::Exists)
+        ));
+        assert!(matches!(
+            store.yank_invoice(&inv.bindle.id).await,
+            Err(ProviderError::WriteOnce(_))
+        ));
+        assert!(matches!(
+            store.delete_invoice(&inv.bindle.id).await,
+            Err(ProviderError::WriteOnce(_))
+        ));
+        assert!(matches!(
+            store.collect_garbage(false).await,
+            Err(ProviderError::WriteOnce(_))
+        ));
+        store
+            .collect_garbage(true)
+            .await
+            .expect("dry runs should be allowed");
+
+        // Everything is still there and unchanged
+        let stored = store
+            .get_invoice(&inv.bindle.id)
+            .await
+            .expect("invoice should still be served");
+        assert!(!stored.yanked.unwrap_or(false));
+        assert!(store
+            .parcel_exists(&inv.bindle.id, &label.sha256)
+            .await
+            .unwrap());
+    }
+}
@@ -1,34 +1,86 @@
 //! A proxy provider implementation that forwards all requests to another server using the Bindle
 //! client. This requires the `client` feature to be enabled
+//!
+//! A proxy can be given a signing key with [`Proxy::with_signing_key`](Proxy::with_signing_key),
+//! in which case it signs every invoice it relays in the `proxy` role, both when creating invoices
+//! upstream and when returning invoices fetched from upstream. This adds the proxy to the chain of
+//! signatures described in the signing spec
 
 use std::convert::TryInto;
+use std::sync::Arc;
 
 use reqwest::StatusCode;
 use tokio::stream::{Stream, StreamExt};
 
 use crate::client::{Client, ClientError};
 use crate::provider::{Provider, ProviderError, Result};
+use crate::signature::{SecretKeyEntry, SignatureError, SignatureRole};
 use crate::Id;
 
 #[derive(Clone)]
 pub struct Proxy {
     client: Client,
+    signing_key: Option<Arc<SecretKeyEntry>>,
 }
 
 impl Proxy {
     pub fn new(client: Client) -> Self {
-        Proxy { client }
+        Proxy {
+            client,
+            signing_key: None,
+        }
+    }
+
+    /// Returns a proxy that signs every invoice it relays in the `proxy` role with the given key.
+    /// Returns an error if the key isn't meant to sign in the `proxy` role
+    pub fn with_signing_key(
+        client: Client,
+        key: SecretKeyEntry,
+    ) -> std::result::Result<Self, SignatureError> {
+        if !key.roles.contains(&SignatureRole::Proxy) {
+            return Err(SignatureError::UnsupportedRole(
+                key.label,
+                SignatureRole::Proxy,
+            ));
+        }
+        Ok(Proxy {
+            client,
+            signing_key: Some(Arc::new(key)),
+        })
+    }
+
+    /// Adds a proxy signature to the invoice if the proxy has a signing key
+    fn sign(&self, inv: &mut crate::Invoice) -> Result<()> {
+        if let Some(key) = &self.signing_key {
+            inv.sign_with_key(SignatureRole::Proxy, key)
+                .map_err(ClientError::from)?;
+        }
+        Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl Provider for Proxy {
     async fn create_invoice(&self, inv: &crate::Invoice) -> Result<Vec<crate::Label>> {
-        let res = self.client.create_invoice(inv.to_owned()).await?;
+        let mut inv = inv.to_owned();
+        self.sign(&mut inv)?;
+        let res = self.client.create_invoice(inv).await?;
         Ok(res.missing.unwrap_or_default())
     }
 
     async fn get_yanked_invoice<I>(&self, id: I) -> Result<crate::Invoice>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let mut inv = self.client.get_yanked_invoice(parsed_id).await?;
+        self.sign(&mut inv)?;
+        Ok(inv)
+    }
+
+    async fn yank_invoice<I>(&self, id: I) -> Result<()>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
@@ -36,12 +88,16 @@ impl Provider for Proxy {
         // Parse the ID now because the error type constraint doesn't match that of the client
         let parsed_id = id.try_into().map_err(|e| e.into())?;
         self.client
-            .get_yanked_invoice(parsed_id)
+            .yank_invoice(parsed_id)
             .await
             .map_err(|e| e.into())
     }
 
-    async fn yank_invoice<I>(&self, id: I) -> Result<()>
+    async fn add_signature<I>(
+        &self,
+        id: I,
+        signature: crate::signature::Signature,
+    ) -> Result<crate::Invoice>
     where
         I: TryInto<Id> + Send,
         I::Error: Into<ProviderError>,
@@ -49,7 +105,44 @@ impl Provider for Proxy {
         // Parse the ID now because the error type constraint doesn't match that of the client
         let parsed_id = id.try_into().map_err(|e| e.into())?;
         self.client
-            .yank_invoice(parsed_id)
+            .add_signature(parsed_id, signature)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn delete_invoice<I>(&self, id: I) -> Result<Vec<String>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        let resp = self.client.delete_invoice(parsed_id).await?;
+        Ok(resp.removed_parcels)
+    }
+
+    async fn get_invoice_history<I>(&self, id: I) -> Result<crate::InvoiceHistory>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        self.client
+            .get_invoice_history(parsed_id)
+            .await
+            .map_err(|e| e.into())
+    }
+
+    async fn get_metadata<I>(&self, id: I) -> Result<crate::InvoiceMetadata>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        // Parse the ID now because the error type constraint doesn't match that of the client
+        let parsed_id = id.try_into().map_err(|e| e.into())?;
+        self.client
+            .get_invoice_metadata(parsed_id)
             .await
             .map_err(|e| e.into())
     }
@@ -64,7 +157,7 @@ impl Provider for Proxy {
         // Parse the ID now because the error type constraint doesn't match that of the client
         let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
         self.client
-            .create_parcel_from_stream(parsed_id, parcel_id, data)
+            .create_parcel_from_stream(parsed_id, parcel_id, None, data)
             .await
             .map_err(|e| e.into())
     }
@@ -84,6 +177,35 @@ impl Provider for Proxy {
         Ok(Box::new(stream.map(|res| res.map_err(|e| e.into()))))
     }
 
+    async fn get_parcel_range<I>(
+        &self,
+        bindle_id: I,
+        parcel_id: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<Box<dyn Stream<Item = Result<bytes::Bytes>> + Unpin + Send + Sync>>
+    where
+        I: TryInto<Id> + Send,
+        I::Error: Into<ProviderError>,
+    {
+        let parsed_id = bindle_id.try_into().map_err(|e| e.into())?;
+        let range = match length {
+            Some(len) => (
+                std::ops::Bound::Included(offset),
+                std::ops::Bound::Excluded(offset + len),
+            ),
+            None => (
+                std::ops::Bound::Included(offset),
+                std::ops::Bound::Unbounded,
+            ),
+        };
+        let stream = self
+            .client
+            .get_parcel_range_stream(parsed_id, parcel_id, range)
+            .await?;
+        Ok(Box::new(stream.map(|res| res.map_err(|e| e.into()))))
+    }
+
     async fn parcel_exists<I>(&self, bindle_id: I, parcel_id: &str) -> Result<bool>
     where
         I: TryInto<Id> + Send,
@@ -113,4 +235,8 @@ impl Provider for Proxy {
             })),
         }
     }
+
+    async fn missing_shas(&self, shas: &[String]) -> Result<Vec<String>> {
+        Ok(self.client.get_missing_shas(shas).await?)
+    }
 }
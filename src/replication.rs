@@ -0,0 +1,371 @@
+//! Replication of bindles from a primary server to a replica. This requires the `client` feature
+//! to be enabled
+//!
+//! A [`Replicator`](Replicator) copies new invoices and their parcels from the primary server to
+//! the provider of the replica. It finds out about new bindles in two ways: by polling the query
+//! endpoint of the primary with the configured queries, and by being notified through a
+//! [`ReplicationHandle`](ReplicationHandle), such as when the primary posts an
+//! [`Event`](crate::events::Event) from an [`HttpHook`](crate::provider::hooks::HttpHook) to the
+//! replica. Notifications only tell the replicator which bindle to look at, as everything is
+//! fetched from the primary. The replica only accepts events signed with the secret set with
+//! [`with_event_secret`](Replicator::with_event_secret), and at most
+//! [`MAX_PENDING`](MAX_PENDING) bindles wait to be replicated at a time.
+//!
+//! Conflicts between the two servers are resolved with the following rules:
+//!
+//! - Yanks are copied to the replica, including for bindles that are yanked before they are ever
+//!   replicated
+//! - Yanks are never undone, so a bindle yanked on the replica but not on the primary stays yanked
+//!   and is counted as a conflict in the [`ReplicationStatus`](ReplicationStatus)
+//! - A bindle that was deleted on the primary is deleted on the replica once the replicator is
+//!   notified about it. Polling can't find deleted bindles, as they aren't returned by queries
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::stream::StreamExt;
+use tokio::sync::Notify;
+
+use crate::client::{Client, ClientError};
+use crate::provider::{Provider, ProviderError};
+use crate::{Id, Invoice, QueryOptions};
+
+/// How long the replicator waits between polls of the primary by default
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How many bindles can wait to be replicated after being notified about. Notifications about
+/// other bindles are dropped until the replicator catches up
+pub const MAX_PENDING: usize = 10_000;
+
+/// Describes the errors that can occur when replicating a bindle
+#[derive(Error, Debug)]
+pub enum ReplicationError {
+    /// The primary server could not be reached or returned an error
+    #[error("Unable to fetch from the primary: {0}")]
+    Primary(#[from] ClientError),
+    /// The replica was unable to store a bindle
+    #[error("Unable to update the replica: {0}")]
+    Replica(#[from] ProviderError),
+}
+
+/// The state of the replication, as reported by the admin endpoint of the replica
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicationStatus {
+    /// The base URL of the primary server
+    pub primary: String,
+    /// The UNIX timestamp (in seconds) at which the last successful poll of the primary finished
+    pub last_poll: Option<u64>,
+    /// The number of invoices copied to the replica
+    pub invoices_copied: u64,
+    /// The number of parcels copied to the replica
+    pub parcels_copied: u64,
+    /// The number of yanks copied to the replica
+    pub yanks_copied: u64,
+    /// The number of bindles deleted on the replica because they were deleted on the primary
+    pub deletes_copied: u64,
+    /// The number of times a bindle was found to be yanked on the replica but not on the primary
+    pub conflicts: u64,
+    /// The number of bindles the replicator was notified about that it hasn't replicated yet
+    pub pending: u64,
+    /// The number of failed attempts to replicate a bindle or poll the primary
+    pub failures: u64,
+    /// The error of the last failed attempt, if any
+    pub last_error: Option<String>,
+}
+
+/// The state shared between a replicator and its handles
+#[derive(Debug, Default)]
+struct Shared {
+    status: Mutex<ReplicationStatus>,
+    pending: Mutex<BTreeSet<String>>,
+    wake: Notify,
+}
+
+/// A handle to a running [`Replicator`](Replicator), used to check its status and to notify it of
+/// changes on the primary
+#[derive(Debug, Clone)]
+pub struct ReplicationHandle {
+    shared: Arc<Shared>,
+    event_secret: Option<Arc<Vec<u8>>>,
+}
+
+impl ReplicationHandle {
+    /// Returns the current status of the replication
+    pub fn status(&self) -> ReplicationStatus {
+        let mut status = self.shared.status.lock().unwrap().clone();
+        status.pending = self.shared.pending.lock().unwrap().len() as u64;
+        status
+    }
+
+    /// Asks the replicator to replicate the given bindle as soon as possible. Notifications about
+    /// the same bindle are merged until it is replicated. Returns false if the notification was
+    /// dropped because [`MAX_PENDING`](MAX_PENDING) other bindles are already waiting
+    pub fn notify(&self, id: &Id) -> bool {
+        let id = id.to_string();
+        let mut pending = self.shared.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING && !pending.contains(&id) {
+            return false;
+        }
+        pending.insert(id);
+        drop(pending);
+        self.shared.wake.notify();
+        true
+    }
+
+    /// Checks that an event posted to the replica was signed with the event secret (see
+    /// [`verify_signature`](crate::provider::hooks::verify_signature)). Without an event secret,
+    /// no event is accepted
+    pub fn verify_event(&self, payload: &[u8], signature: Option<&str>) -> bool {
+        match (&self.event_secret, signature) {
+            (Some(secret), Some(signature)) => {
+                crate::provider::hooks::verify_signature(secret, payload, signature)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Copies bindles from a primary server to the provider of a replica. Clones share the same status
+/// and notifications
+#[derive(Clone)]
+pub struct Replicator<P> {
+    primary: Client,
+    replica: P,
+    queries: Vec<QueryOptions>,
+    interval: Duration,
+    event_secret: Option<Arc<Vec<u8>>>,
+    shared: Arc<Shared>,
+}
+
+impl<P: Provider + Send + Sync> Replicator<P> {
+    /// Creates a replicator that copies bindles from the server the client talks to into the given
+    /// provider. Without any queries, it only replicates the bindles it is notified about
+    pub fn new(primary: Client, replica: P) -> Self {
+        let shared = Shared::default();
+        shared.status.lock().unwrap().primary = primary.base_url().to_owned();
+        Replicator {
+            primary,
+            replica,
+            queries: Vec::new(),
+            interval: DEFAULT_POLL_INTERVAL,
+            event_secret: None,
+            shared: Arc::new(shared),
+        }
+    }
+
+    /// Adds a query that is run against the primary on every poll. All bindles it returns,
+    /// including yanked ones, are replicated. Servers using the strict search mode only return the
+    /// bindles with exactly the queried name, so every bindle name needs its own query there
+    pub fn with_query(mut self, query: QueryOptions) -> Self {
+        self.queries.push(query);
+        self
+    }
+
+    /// Sets how long to wait between polls of the primary
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the secret shared with the event hook of the primary. Events posted to the replica
+    /// are only accepted if they are signed with it, so without a secret the replicator can only
+    /// be notified directly through its handle
+    pub fn with_event_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.event_secret = Some(Arc::new(secret.into()));
+        self
+    }
+
+    /// Returns a handle for checking the status of the replicator and notifying it of changes
+    pub fn handle(&self) -> ReplicationHandle {
+        ReplicationHandle {
+            shared: self.shared.clone(),
+            event_secret: self.event_secret.clone(),
+        }
+    }
+
+    /// Replicates bindles until the returned future is dropped, polling the primary every
+    /// interval and replicating the bindles it is notified about in between. Failures are logged
+    /// and recorded in the status. Bindles that failed are replicated again once they are found by
+    /// a poll or notified about again
+    pub async fn run(self) {
+        let mut next_poll = tokio::time::Instant::now();
+        loop {
+            let pending = std::mem::take(&mut *self.shared.pending.lock().unwrap());
+            for id in pending {
+                // The IDs were all parsed before they were added
+                let result = match id.parse::<Id>() {
+                    Ok(id) => self.replicate(&id).await,
+                    Err(e) => Err(ProviderError::from(e).into()),
+                };
+                if let Err(e) = result {
+                    self.failed(&format!("Unable to replicate {}", id), e);
+                }
+            }
+
+            if self.queries.is_empty() {
+                self.shared.wake.notified().await;
+                continue;
+            }
+            if tokio::time::Instant::now() >= next_poll {
+                if let Err(e) = self.poll().await {
+                    self.failed("Unable to poll the primary", e);
+                }
+                next_poll = tokio::time::Instant::now() + self.interval;
+            }
+            tokio::select! {
+                _ = self.shared.wake.notified() => (),
+                _ = tokio::time::delay_until(next_poll) => (),
+            }
+        }
+    }
+
+    /// Runs all queries against the primary and replicates every bindle they return. Failures to
+    /// replicate a single bindle are recorded in the status without stopping the poll
+    pub async fn poll(&self) -> Result<(), ReplicationError> {
+        for query in &self.queries {
+            let mut offset = query.offset.unwrap_or(0);
+            loop {
+                let matches = self
+                    .primary
+                    .query_invoices(QueryOptions {
+                        offset: Some(offset),
+                        yanked: Some(true),
+                        ..query.clone()
+                    })
+                    .await?;
+                offset += matches.invoices.len() as u64;
+                for inv in &matches.invoices {
+                    if let Err(e) = self.copy(inv).await {
+                        self.failed(&format!("Unable to replicate {}", inv.bindle.id), e);
+                    }
+                }
+                if !matches.more || matches.invoices.is_empty() {
+                    break;
+                }
+            }
+        }
+        self.shared.status.lock().unwrap().last_poll = Some(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        );
+        Ok(())
+    }
+
+    /// Brings the bindle with the given ID on the replica up to date with the primary, deleting it
+    /// if it doesn't exist on the primary anymore
+    pub async fn replicate(&self, id: &Id) -> Result<(), ReplicationError> {
+        match self.primary.get_yanked_invoice(id).await {
+            Ok(inv) => self.copy(&inv).await,
+            Err(ClientError::InvoiceNotFound) if !self.exists_on_primary(id).await? => {
+                match self.replica.delete_invoice(id).await {
+                    Ok(_) => {
+                        info!("Deleted {}, as it was deleted on the primary", id);
+                        self.record(|s| s.deletes_copied += 1);
+                        Ok(())
+                    }
+                    Err(ProviderError::NotFound) => Ok(()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The client reports invoices it isn't allowed to see as missing as well, so a bindle is only
+    /// deleted on the replica once the primary confirms that it doesn't exist
+    async fn exists_on_primary(&self, id: &Id) -> Result<bool, ReplicationError> {
+        let resp = self
+            .primary
+            .raw(
+                reqwest::Method::GET,
+                &format!("{}/{}?yanked=true", crate::client::INVOICE_ENDPOINT, id),
+                None::<reqwest::Body>,
+            )
+            .await
+            .map_err(|e| {
+                e.downcast::<ClientError>()
+                    .unwrap_or_else(|e| ClientError::ServerError(Some(e.to_string())))
+            })?;
+        match resp.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(ClientError::InvalidRequest {
+                status_code: status,
+                message: None,
+            }
+            .into()),
+        }
+    }
+
+    /// Copies the given invoice from the primary and any of its parcels the replica doesn't have
+    /// yet, then applies the conflict rules for yanks
+    async fn copy(&self, inv: &Invoice) -> Result<(), ReplicationError> {
+        let id = &inv.bindle.id;
+        let stored = match self.replica.get_yanked_invoice(id).await {
+            Ok(stored) => Some(stored),
+            Err(ProviderError::NotFound) => None,
+            Err(e) => return Err(e.into()),
+        };
+        if stored.is_none() {
+            // Yanked invoices can't be created, so they are yanked once everything is copied
+            let mut unyanked = inv.clone();
+            unyanked.yanked = None;
+            self.replica.create_invoice(&unyanked).await?;
+            debug!("Copied invoice {}", id);
+            self.record(|s| s.invoices_copied += 1);
+        }
+
+        for parcel in inv.parcel.iter().flatten() {
+            let sha = &parcel.label.sha256;
+            if self.replica.parcel_exists(id, sha).await? {
+                continue;
+            }
+            let data = self
+                .primary
+                .get_parcel_stream(id.clone(), sha)
+                .await?
+                .map(|res| res.map_err(std::io::Error::other));
+            match self.replica.create_parcel(id, sha, Box::pin(data)).await {
+                // The parcel may have been copied for another bindle in the meantime
+                Ok(_) | Err(ProviderError::Exists) => (),
+                Err(e) => return Err(e.into()),
+            }
+            debug!("Copied parcel {} of {}", sha, id);
+            self.record(|s| s.parcels_copied += 1);
+        }
+
+        let yanked = inv.yanked.unwrap_or(false);
+        let stored_yanked = stored.and_then(|s| s.yanked).unwrap_or(false);
+        if yanked && !stored_yanked {
+            self.replica.yank_invoice(id).await?;
+            info!("Yanked {}, as it was yanked on the primary", id);
+            self.record(|s| s.yanks_copied += 1);
+        } else if !yanked && stored_yanked {
+            warn!(
+                "{} is yanked on the replica but not on the primary, keeping it yanked",
+                id
+            );
+            self.record(|s| s.conflicts += 1);
+        }
+        Ok(())
+    }
+
+    fn record(&self, update: impl FnOnce(&mut ReplicationStatus)) {
+        update(&mut self.shared.status.lock().unwrap())
+    }
+
+    fn failed(&self, context: &str, e: ReplicationError) {
+        warn!("{}: {}", context, e);
+        self.record(|s| {
+            s.failures += 1;
+            s.last_error = Some(format!("{}: {}", context, e));
+        });
+    }
+}
@@ -0,0 +1,231 @@
+//! Resolution of the dependencies between bindles.
+//!
+//! A bindle declares the bindles it depends on in the `requires` list of its invoice, each with a
+//! SemVer range of acceptable versions. [`resolve`](resolve) finds the bindles satisfying these
+//! using a search engine, then does the same for the dependencies of those bindles until the whole
+//! transitive closure is known.
+//!
+//! Resolution is intentionally simple: for every dependency, the highest version that isn't yanked
+//! is chosen, and only one version of each bindle is allowed. If a later dependency isn't
+//! satisfied by the version already chosen for a bindle, resolution fails with a
+//! [`Conflict`](ResolveError::Conflict) instead of backtracking
+
+use std::collections::{HashMap, VecDeque};
+
+use thiserror::Error;
+
+use super::{Search, SearchOptions};
+use crate::{Dependency, Id, Invoice};
+
+/// Describes the errors that can occur when resolving dependencies
+#[derive(Error, Debug)]
+pub enum ResolveError {
+    /// No bindle satisfies a dependency
+    #[error("No bindle satisfies the dependency on {dependency} required by {required_by}")]
+    Unsatisfied {
+        dependency: Dependency,
+        required_by: Id,
+    },
+    /// A dependency isn't satisfied by the version already chosen for the same bindle
+    #[error("{required_by} depends on {dependency}, but version {chosen} was already chosen")]
+    Conflict {
+        dependency: Dependency,
+        required_by: Id,
+        chosen: Id,
+    },
+    /// The search engine failed to look up a dependency
+    #[error("Unable to search for dependencies: {0}")]
+    Search(#[from] anyhow::Error),
+}
+
+/// Returns the invoices of all bindles the given invoice depends on, directly or indirectly, in the
+/// order they were resolved. The given invoice itself is not part of the result
+pub async fn resolve<S: Search + Sync + ?Sized>(
+    engine: &S,
+    invoice: &Invoice,
+) -> Result<Vec<Invoice>, ResolveError> {
+    // The chosen version of every bindle seen so far, including the root so that cycles back to it
+    // are satisfied by it
+    let mut chosen: HashMap<String, Id> = HashMap::new();
+    chosen.insert(
+        invoice.bindle.id.name().to_owned(),
+        invoice.bindle.id.clone(),
+    );
+    let mut resolved = Vec::new();
+    let mut pending: VecDeque<(Id, Dependency)> = invoice
+        .dependencies()
+        .iter()
+        .map(|d| (invoice.bindle.id.clone(), d.clone()))
+        .collect();
+
+    while let Some((required_by, dependency)) = pending.pop_front() {
+        if let Some(id) = chosen.get(&dependency.name) {
+            if crate::version_compare(id.version(), &dependency.version) {
+                continue;
+            }
+            return Err(ResolveError::Conflict {
+                chosen: id.clone(),
+                dependency,
+                required_by,
+            });
+        }
+
+        let found = match find_highest(engine, &dependency).await? {
+            Some(inv) => inv,
+            None => {
+                return Err(ResolveError::Unsatisfied {
+                    dependency,
+                    required_by,
+                })
+            }
+        };
+        pending.extend(
+            found
+                .dependencies()
+                .iter()
+                .map(|d| (found.bindle.id.clone(), d.clone())),
+        );
+        chosen.insert(dependency.name, found.bindle.id.clone());
+        resolved.push(found);
+    }
+    Ok(resolved)
+}
+
+/// Returns the highest version satisfying the dependency, paging through all of the matches
+async fn find_highest<S: Search + Sync + ?Sized>(
+    engine: &S,
+    dependency: &Dependency,
+) -> anyhow::Result<Option<Invoice>> {
+    let mut highest: Option<Invoice> = None;
+    let mut offset = 0;
+    loop {
+        let matches = engine
+            .query(
+                dependency.name.clone(),
+                dependency.version.clone(),
+                SearchOptions {
+                    offset,
+                    strict: true,
+                    ..SearchOptions::default()
+                },
+            )
+            .await?;
+        offset += matches.invoices.len() as u64;
+        // Not every engine filters out yanked bindles, so everything is checked again here
+        for inv in matches
+            .invoices
+            .into_iter()
+            .filter(|i| dependency.is_satisfied_by(i))
+        {
+            if highest
+                .as_ref()
+                .map(|h| h.bindle.id.version() < inv.bindle.id.version())
+                .unwrap_or(true)
+            {
+                highest = Some(inv);
+            }
+        }
+        if !matches.more {
+            return Ok(highest);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "search-strict"))]
+mod test {
+    use super::*;
+    use crate::search::StrictEngine;
+
+    fn invoice(id: &str, requires: &[(&str, &str)]) -> Invoice {
+        let id: Id = id.parse().unwrap();
+        let mut inv: Invoice = toml::from_str(&format!(
+            "bindleVersion = \"1.0.0\"\n[bindle]\nname = \"{}\"\nversion = \"{}\"",
+            id.name(),
+            id.version()
+        ))
+        .unwrap();
+        inv.requires = Some(
+            requires
+                .iter()
+                .map(|(name, version)| Dependency {
+                    name: (*name).to_owned(),
+                    version: (*version).to_owned(),
+                })
+                .collect(),
+        );
+        inv
+    }
+
+    async fn engine(invoices: &[Invoice]) -> StrictEngine {
+        let engine = StrictEngine::default();
+        for inv in invoices {
+            engine.index(inv).await.expect("unable to index invoice");
+        }
+        engine
+    }
+
+    fn ids(invoices: &[Invoice]) -> Vec<String> {
+        invoices.iter().map(|i| i.bindle.id.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_resolve_transitive() {
+        let mut yanked = invoice("lib/1.3.0", &[]);
+        yanked.yanked = Some(true);
+        let engine = engine(&[
+            invoice("lib/1.1.0", &[("base", "^2.0.0")]),
+            invoice("lib/1.2.0", &[("base", "^2.0.0"), ("app", "")]),
+            yanked,
+            invoice("lib/2.0.0", &[]),
+            invoice("base/2.0.0", &[]),
+            invoice("base/2.1.0", &[]),
+        ])
+        .await;
+
+        // The cycle back to the app is satisfied by the app itself
+        let app = invoice("app/1.0.0", &[("lib", "^1.0.0")]);
+        let resolved = resolve(&engine, &app).await.expect("should resolve");
+        assert_eq!(vec!["lib/1.2.0", "base/2.1.0"], ids(&resolved));
+
+        assert!(resolve(&engine, &invoice("app/1.0.0", &[]))
+            .await
+            .expect("should resolve")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_errors() {
+        let engine = engine(&[
+            invoice("lib/1.0.0", &[("base", "^1.0.0")]),
+            invoice("base/2.0.0", &[]),
+        ])
+        .await;
+
+        match resolve(&engine, &invoice("app/1.0.0", &[("lib", "^2.0.0")])).await {
+            Err(ResolveError::Unsatisfied {
+                dependency,
+                required_by,
+            }) => {
+                assert_eq!("lib", dependency.name);
+                assert_eq!("app/1.0.0", required_by.to_string());
+            }
+            r => panic!(
+                "Expected an unsatisfied dependency, got {:?}",
+                r.map(|r| ids(&r))
+            ),
+        }
+
+        let app = invoice("app/1.0.0", &[("base", "^2.0.0"), ("lib", "")]);
+        match resolve(&engine, &app).await {
+            Err(ResolveError::Conflict {
+                required_by,
+                chosen,
+                ..
+            }) => {
+                assert_eq!("lib/1.0.0", required_by.to_string());
+                assert_eq!("base/2.0.0", chosen.to_string());
+            }
+            r => panic!("Expected a conflict, got {:?}", r.map(|r| ids(&r))),
+        }
+    }
+}
@@ -0,0 +1,398 @@
+//! A search engine that can fan queries out to peer registries.
+//!
+//! [`FederatedSearch`](FederatedSearch) wraps the local search engine of a server. Queries are
+//! answered by the local engine unless they set the `federated` option, in which case the same
+//! query is also sent to all configured [`Peer`](Peer)s. The results are merged and deduplicated
+//! by bindle ID, and the registries each bindle was found in are returned in
+//! [`Matches::origins`](super::Matches::origins). Peers are always queried without the `federated`
+//! option, so federated servers can't cause query loops.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{trace, warn};
+
+use super::{Matches, Search, SearchOptions};
+use crate::client::Client;
+use crate::Invoice;
+
+/// The name used for the local registry in the origins of federated results, unless configured
+/// otherwise
+pub const DEFAULT_LOCAL_NAME: &str = "local";
+const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A peer registry that federated queries are sent to
+#[derive(Clone)]
+pub struct Peer {
+    name: String,
+    client: Client,
+}
+
+impl Peer {
+    /// Creates a new peer with the given name, which is used to attribute results to it, and a
+    /// client for talking to it
+    pub fn new(name: impl Into<String>, client: Client) -> Self {
+        Peer {
+            name: name.into(),
+            client,
+        }
+    }
+
+    /// Returns the name of the peer
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// A search engine that answers federated queries using both a local engine and a set of peer
+/// registries. All other operations are passed through to the local engine
+#[derive(Clone)]
+pub struct FederatedSearch<S> {
+    local: S,
+    local_name: String,
+    peers: Arc<Vec<Peer>>,
+    peer_timeout: Duration,
+}
+
+impl<S: Search + Send + Sync> FederatedSearch<S> {
+    /// Wraps the given local search engine, sending federated queries to the given peers
+    pub fn new(local: S, peers: Vec<Peer>) -> Self {
+        FederatedSearch {
+            local,
+            local_name: DEFAULT_LOCAL_NAME.to_owned(),
+            peers: Arc::new(peers),
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+        }
+    }
+
+    /// Sets the name used for the local registry in the origins of federated results
+    pub fn with_local_name(mut self, name: impl Into<String>) -> Self {
+        self.local_name = name.into();
+        self
+    }
+
+    /// Sets how long to wait for each peer to answer a query. Peers that don't answer in time (or
+    /// return an error) are left out of the results. Defaults to 10 seconds
+    pub fn with_peer_timeout(mut self, timeout: Duration) -> Self {
+        self.peer_timeout = timeout;
+        self
+    }
+
+    async fn query_peer(
+        &self,
+        peer: &Peer,
+        term: &str,
+        filter: &str,
+        options: &SearchOptions,
+        needed: u64,
+    ) -> Option<(Vec<Invoice>, u64)> {
+        let fetch = collect(needed, |offset, limit| async move {
+            let matches = peer
+                .client
+                .query_invoices(crate::QueryOptions {
+                    query: Some(term.to_owned()),
+                    version: Some(filter.to_owned()),
+                    offset: Some(offset),
+                    limit: Some(limit),
+                    strict: Some(options.strict),
+                    yanked: Some(options.yanked),
+                    federated: None,
+                    distinct: Some(options.distinct),
+                    annotations: options.annotations.clone(),
+                    media_type: Some(options.media_types.join(",")).filter(|m| !m.is_empty()),
+                })
+                .await?;
+            Ok(matches)
+        });
+        match tokio::time::timeout(self.peer_timeout, fetch).await {
+            Ok(Ok(res)) => Some(res),
+            Ok(Err(e)) => {
+                warn!("Unable to query peer {}: {}", peer.name, e);
+                None
+            }
+            Err(_) => {
+                warn!("Timed out querying peer {}", peer.name);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: Search + Send + Sync> Search for FederatedSearch<S> {
+    async fn query(
+        &self,
+        term: String,
+        filter: String,
+        options: SearchOptions,
+    ) -> anyhow::Result<Matches> {
+        if !options.federated || self.peers.is_empty() {
+            return self.local.query(term, filter, options).await;
+        }
+        trace!(
+            "Federating query for term {} and version {} to {} peers",
+            term,
+            filter,
+            self.peers.len()
+        );
+
+        // Each registry has to return everything up to the end of the requested page, otherwise
+        // the page boundaries of the merged results would be wrong
+        let needed = options.offset + options.limit as u64;
+        let local = collect(needed, |offset, limit| {
+            self.local.query(
+                term.clone(),
+                filter.clone(),
+                SearchOptions {
+                    offset,
+                    limit,
+                    strict: options.strict,
+                    yanked: options.yanked,
+                    federated: false,
+                    distinct: options.distinct,
+                    annotations: options.annotations.clone(),
+                    media_types: options.media_types.clone(),
+                },
+            )
+        });
+        let peers = futures::future::join_all(
+            self.peers
+                .iter()
+                .map(|peer| self.query_peer(peer, &term, &filter, &options, needed)),
+        );
+        let (local, peers) = futures::future::join(local, peers).await;
+
+        let sources = std::iter::once((self.local_name.as_str(), Some(local?)))
+            .chain(self.peers.iter().map(|p| p.name.as_str()).zip(peers));
+
+        // Key by name and version so results are sorted the same way across all registries
+        let mut merged: BTreeMap<(String, semver::Version), (Invoice, Vec<String>)> =
+            BTreeMap::new();
+        let mut unfetched = 0;
+        for (origin, result) in sources {
+            let (invoices, total) = match result {
+                Some(r) => r,
+                None => continue,
+            };
+            unfetched += total.saturating_sub(invoices.len() as u64);
+            for inv in invoices {
+                let key = (
+                    inv.bindle.id.name().to_owned(),
+                    inv.bindle.id.version().clone(),
+                );
+                // The first registry to return a bindle (starting with the local one) wins
+                merged
+                    .entry(key)
+                    .or_insert_with(|| (inv, Vec::new()))
+                    .1
+                    .push(origin.to_owned());
+            }
+        }
+
+        // For distinct queries, each registry only returned its own latest versions, which may
+        // still be older than the ones of another registry
+        let mut results: Vec<(Invoice, Vec<String>)> = Vec::with_capacity(merged.len());
+        for (_, result) in merged {
+            results.push(result);
+        }
+        if options.distinct {
+            results = super::latest_versions(results, |(inv, _)| inv);
+        }
+
+        let mut matches = Matches::new(&options, term);
+        // Duplicates that weren't fetched can't be detected, so this is an upper bound
+        matches.total = results.len() as u64 + unfetched;
+        matches.more = matches.total > needed;
+        let mut origins = BTreeMap::new();
+        matches.invoices = results
+            .into_iter()
+            .skip(options.offset as usize)
+            .take(options.limit as usize)
+            .map(|(inv, found_in)| {
+                origins.insert(inv.bindle.id.to_string(), found_in);
+                inv
+            })
+            .collect();
+        matches.origins = Some(origins);
+        Ok(matches)
+    }
+
+    async fn index(&self, document: &crate::Invoice) -> anyhow::Result<()> {
+        self.local.index(document).await
+    }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        self.local.remove(id).await
+    }
+
+    async fn save_snapshot(&self, fingerprint: &str) -> anyhow::Result<()> {
+        self.local.save_snapshot(fingerprint).await
+    }
+
+    async fn restore_snapshot(&self, fingerprint: &str) -> anyhow::Result<bool> {
+        self.local.restore_snapshot(fingerprint).await
+    }
+
+    /// Only lists the versions known to the local engine, as peers are only asked when queried
+    async fn versions(&self, name: &str) -> anyhow::Result<Vec<crate::VersionInfo>> {
+        self.local.versions(name).await
+    }
+
+    /// Only finds invoices known to the local engine, as peers are only asked when queried
+    async fn find_by_parcel(&self, sha: &str) -> anyhow::Result<Vec<Invoice>> {
+        self.local.find_by_parcel(sha).await
+    }
+}
+
+/// Pages through the results of a single registry using the given fetch function until at least
+/// `needed` invoices are found or there are no more results. Returns the invoices along with the
+/// total number of matches reported by the registry
+async fn collect<F, Fut>(needed: u64, mut fetch: F) -> anyhow::Result<(Vec<Invoice>, u64)>
+where
+    F: FnMut(u64, u8) -> Fut,
+    Fut: Future<Output = anyhow::Result<Matches>>,
+{
+    let mut invoices = Vec::new();
+    let mut total = 0;
+    while (invoices.len() as u64) < needed {
+        let limit = std::cmp::min(needed - invoices.len() as u64, u8::MAX as u64) as u8;
+        let matches = fetch(invoices.len() as u64, limit).await?;
+        total = matches.total;
+        let done = !matches.more || matches.invoices.is_empty();
+        invoices.extend(matches.invoices);
+        if done {
+            break;
+        }
+    }
+    Ok((invoices, total))
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+
+    use crate::provider::Provider;
+    use crate::search::StrictEngine;
+    use crate::server::{start_in_process, ServerHandle};
+    use crate::testing;
+
+    const NAME: &str = "example.com/federated";
+
+    fn invoice(version: &str) -> Invoice {
+        toml::from_str(&format!(
+            r#"
+            bindleVersion = "1.0.0"
+
+            [bindle]
+            name = "{}"
+            version = "{}"
+            "#,
+            NAME, version
+        ))
+        .expect("invoice should parse")
+    }
+
+    async fn peer_with(versions: &[&str]) -> ServerHandle {
+        let (store, index) = testing::setup().await;
+        for v in versions {
+            store
+                .create_invoice(&invoice(v))
+                .await
+                .expect("unable to create invoice");
+        }
+        start_in_process(store, index, Default::default()).expect("server should start")
+    }
+
+    fn options(offset: u64, limit: u8, federated: bool) -> SearchOptions {
+        SearchOptions {
+            offset,
+            limit,
+            strict: true,
+            yanked: false,
+            federated,
+            distinct: false,
+            ..SearchOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_federated_query() {
+        let local = StrictEngine::default();
+        for v in &["1.0.0", "2.0.0"] {
+            local.index(&invoice(v)).await.unwrap();
+        }
+        let team_a = peer_with(&["2.0.0", "3.0.0"]).await;
+        // A peer that is down shouldn't break the query
+        let down = peer_with(&[]).await;
+        let down_url = down.base_url();
+        down.shutdown().await;
+
+        let search = FederatedSearch::new(
+            local,
+            vec![
+                Peer::new("team-a", Client::new(&team_a.base_url()).unwrap()),
+                Peer::new("down", Client::new(&down_url).unwrap()),
+            ],
+        )
+        .with_peer_timeout(Duration::from_secs(5));
+
+        // Non-federated queries only return local results
+        let matches = search
+            .query(NAME.to_owned(), String::new(), options(0, 50, false))
+            .await
+            .expect("query should succeed");
+        assert_eq!(2, matches.invoices.len());
+        assert!(matches.origins.is_none());
+
+        let matches = search
+            .query(NAME.to_owned(), String::new(), options(0, 50, true))
+            .await
+            .expect("query should succeed");
+        let versions: Vec<String> = matches
+            .invoices
+            .iter()
+            .map(|i| i.bindle.id.version_string())
+            .collect();
+        assert_eq!(vec!["1.0.0", "2.0.0", "3.0.0"], versions);
+        assert_eq!(3, matches.total);
+        assert!(!matches.more);
+        let origins = matches.origins.expect("origins should be set");
+        assert_eq!(
+            vec!["local"],
+            origins[&format!("{}/1.0.0", NAME)],
+            "Origins: {:?}",
+            origins
+        );
+        assert_eq!(vec!["local", "team-a"], origins[&format!("{}/2.0.0", NAME)]);
+        assert_eq!(vec!["team-a"], origins[&format!("{}/3.0.0", NAME)]);
+
+        // Distinct queries only return the latest version across all registries
+        let matches = search
+            .query(
+                NAME.to_owned(),
+                String::new(),
+                SearchOptions {
+                    distinct: true,
+                    ..options(0, 50, true)
+                },
+            )
+            .await
+            .expect("query should succeed");
+        assert_eq!(1, matches.invoices.len());
+        assert_eq!("3.0.0", matches.invoices[0].bindle.id.version_string());
+        assert!(matches.distinct);
+
+        // Pages should be taken from the merged results
+        let matches = search
+            .query(NAME.to_owned(), String::new(), options(1, 1, true))
+            .await
+            .expect("query should succeed");
+        assert_eq!(1, matches.invoices.len());
+        assert_eq!("2.0.0", matches.invoices[0].bindle.id.version_string());
+        assert!(matches.more);
+
+        team_a.shutdown().await;
+    }
+}
@@ -1,11 +1,24 @@
 //! Common types and traits for use in implementing query functionality for a Bindle server. Note
 //! that this functionality is quite likely to change
+use std::collections::{BTreeMap, HashMap};
+
 use serde::{Deserialize, Serialize};
 
+pub mod dependencies;
+#[cfg(feature = "client")]
+pub mod federated;
 mod noop;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "search-strict")]
 mod strict;
 
+#[cfg(feature = "client")]
+pub use federated::{FederatedSearch, Peer};
 pub use noop::NoopEngine;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresEngine;
+#[cfg(feature = "search-strict")]
 pub use strict::StrictEngine;
 
 #[derive(Debug)]
@@ -19,6 +32,35 @@ pub struct SearchOptions {
     pub strict: bool,
     /// Whether to return yanked bindles
     pub yanked: bool,
+    /// Whether to include results from peer registries. Only used by engines that support
+    /// federation, such as [`FederatedSearch`](FederatedSearch)
+    pub federated: bool,
+    /// Whether to only return the latest version of each bindle. Versions that aren't yanked are
+    /// preferred, even if `yanked` is set
+    pub distinct: bool,
+    /// Only return bindles whose invoice has all of these annotations, with the same values
+    pub annotations: BTreeMap<String, String>,
+    /// Only return bindles with at least one parcel of one of these media types. If empty, the
+    /// media types of the parcels don't matter
+    pub media_types: Vec<String>,
+}
+
+impl SearchOptions {
+    /// Returns whether the invoice matches the annotation and media type filters of these options,
+    /// for engines that apply the filters to the invoices they find
+    pub fn matches_filters(&self, inv: &crate::Invoice) -> bool {
+        let annotations_match = self
+            .annotations
+            .iter()
+            .all(|(key, value)| inv.annotation(key) == Some(value.as_str()));
+        let media_types_match = self.media_types.is_empty()
+            || inv
+                .parcel
+                .iter()
+                .flatten()
+                .any(|p| self.media_types.contains(&p.label.media_type));
+        annotations_match && media_types_match
+    }
 }
 
 impl Default for SearchOptions {
@@ -28,6 +70,10 @@ impl Default for SearchOptions {
             limit: 50,
             strict: false,
             yanked: false,
+            federated: false,
+            distinct: false,
+            annotations: BTreeMap::new(),
+            media_types: Vec::new(),
         }
     }
 }
@@ -51,6 +97,13 @@ pub struct Matches {
     pub more: bool,
     /// Whether this list includes potentially yanked invoices
     pub yanked: bool,
+    /// Whether only the latest version of each bindle was returned
+    #[serde(default)]
+    pub distinct: bool,
+    /// For federated queries, the names of the registries each returned bindle (by ID) was found
+    /// in. This is not set for queries that only search a single registry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origins: Option<BTreeMap<String, Vec<String>>>,
     /// The list of invoices returned as this part of the query
     ///
     /// The length of this Vec will be less than or equal to the limit.
@@ -68,13 +121,54 @@ impl Matches {
             offset: opts.offset,
             limit: opts.limit,
             yanked: opts.yanked,
+            distinct: opts.distinct,
 
             // Defaults
             invoices: vec![],
             more: false,
             total: 0,
+            origins: None,
+        }
+    }
+}
+
+/// Statistics about the invoices in a search index
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    /// The number of indexed invoices, including yanked ones
+    pub invoices: u64,
+    /// The number of indexed invoices that are yanked
+    pub yanked: u64,
+}
+
+/// Reduces the given results to the latest version of each bindle, preferring versions that aren't
+/// yanked, for engines implementing [`distinct`](SearchOptions::distinct) queries. The given
+/// function returns the invoice of a result. The results are kept in the order in which each bindle
+/// name first appears
+pub fn latest_versions<T>(results: Vec<T>, invoice: impl Fn(&T) -> &crate::Invoice) -> Vec<T> {
+    let rank = |inv: &crate::Invoice| {
+        (
+            !inv.yanked.unwrap_or(false),
+            inv.bindle.id.version().clone(),
+        )
+    };
+    let mut latest: Vec<T> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for result in results {
+        let name = invoice(&result).bindle.id.name().to_owned();
+        match positions.get(&name) {
+            Some(&i) => {
+                if rank(invoice(&result)) > rank(invoice(&latest[i])) {
+                    latest[i] = result;
+                }
+            }
+            None => {
+                positions.insert(name, latest.len());
+                latest.push(result);
+            }
         }
     }
+    latest
 }
 
 /// This trait describes the minimal set of features a Bindle provider must implement to provide
@@ -88,7 +182,9 @@ pub trait Search {
     /// A high-level function that can take raw search strings (queries and filters) and options.
     ///
     /// This will parse the terms and filters according to its internal rules, and return
-    /// a set of matches.
+    /// a set of matches. Only invoices matching the annotation and media type filters of the
+    /// options (see [`matches_filters`](SearchOptions::matches_filters)) are returned, and the
+    /// total only counts those.
     ///
     /// An error is returned if either there is something incorrect in the terms/filters,
     /// or if the search engine itself fails to process the query.
@@ -112,4 +208,86 @@ pub trait Search {
     /// as such, following the protocol specification's requirements for yanked
     /// invoices.
     async fn index(&self, document: &crate::Invoice) -> anyhow::Result<()>;
+
+    /// Removes the invoice with the given ID from the index, so it no longer shows up in any
+    /// results. Removing an invoice that isn't indexed is not an error.
+    ///
+    /// This is only used when an invoice is permanently deleted. Yanked invoices stay in the index
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()>;
+
+    /// Persists the index, such as when a server shuts down, so it can be restored with
+    /// [`restore_snapshot`](Search::restore_snapshot) instead of indexing every invoice again. The
+    /// fingerprint identifies the state of the store the index was built from.
+    ///
+    /// The default implementation does nothing, which suits engines that persist their index
+    /// anyway
+    async fn save_snapshot(&self, _fingerprint: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Restores the index from the snapshot saved with [`save_snapshot`](Search::save_snapshot),
+    /// but only if the snapshot was taken of a store with the given fingerprint. Returns whether
+    /// the index was restored. If it wasn't, because there is no snapshot or it is stale, the
+    /// store has to index all of its invoices.
+    ///
+    /// The default implementation never restores anything
+    async fn restore_snapshot(&self, _fingerprint: &str) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Returns all indexed versions of the bindle with the given name, including yanked ones, in
+    /// ascending version order. An unknown name returns an empty list.
+    ///
+    /// The default implementation pages through strict queries for the name, so engines that can
+    /// look up the versions of a name directly should override it
+    async fn versions(&self, name: &str) -> anyhow::Result<Vec<crate::VersionInfo>> {
+        let mut found = Vec::new();
+        let mut offset = 0;
+        loop {
+            let matches = self
+                .query(
+                    name.to_owned(),
+                    String::new(),
+                    SearchOptions {
+                        offset,
+                        strict: true,
+                        yanked: true,
+                        ..SearchOptions::default()
+                    },
+                )
+                .await?;
+            let more = matches.more && !matches.invoices.is_empty();
+            offset += matches.invoices.len() as u64;
+            found.extend(matches.invoices);
+            if !more {
+                break;
+            }
+        }
+        Ok(version_list(found.iter(), name))
+    }
+
+    /// Returns all indexed invoices, including yanked ones, that contain a parcel with the given
+    /// SHA, ordered by name and version. This answers questions like which bindles ship a parcel
+    /// that turned out to be vulnerable, so engines are expected to index the parcel SHAs of
+    /// every invoice passed to [`index`](Search::index).
+    ///
+    /// The default implementation returns an error, as queries can't find invoices by parcel
+    async fn find_by_parcel(&self, _sha: &str) -> anyhow::Result<Vec<crate::Invoice>> {
+        Err(anyhow::anyhow!(
+            "This search engine does not support finding invoices by parcel"
+        ))
+    }
+}
+
+/// Returns the versions of the invoices with the given name, in ascending version order, for
+/// implementations of [`Search::versions`](Search::versions)
+pub fn version_list<'a>(
+    invoices: impl Iterator<Item = &'a crate::Invoice>,
+    name: &str,
+) -> Vec<crate::VersionInfo> {
+    let mut invoices: Vec<&crate::Invoice> =
+        invoices.filter(|i| i.bindle.id.name() == name).collect();
+    invoices.sort_by(|a, b| a.bindle.id.version().cmp(b.bindle.id.version()));
+    invoices.dedup_by(|a, b| a.bindle.id.version() == b.bindle.id.version());
+    invoices.into_iter().map(crate::VersionInfo::from).collect()
 }
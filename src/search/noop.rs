@@ -20,4 +20,12 @@ impl Search for NoopEngine {
     async fn index(&self, _: &crate::Invoice) -> anyhow::Result<()> {
         Ok(())
     }
+
+    async fn remove(&self, _: &crate::Id) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn find_by_parcel(&self, _: &str) -> anyhow::Result<Vec<crate::Invoice>> {
+        Ok(Vec::new())
+    }
 }
@@ -0,0 +1,470 @@
+//! A query engine implementation backed by Postgres. Unlike the in memory engines, the index
+//! survives restarts and can be shared between multiple server processes. This requires the
+//! `postgres` feature to be enabled
+
+use std::sync::Arc;
+
+use log::{error, trace};
+use tokio::sync::Mutex;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls};
+
+use crate::search::{Matches, Search, SearchOptions};
+
+const CREATE_TABLE: &str = r#"
+CREATE TABLE IF NOT EXISTS bindle_invoices (
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    yanked BOOLEAN NOT NULL DEFAULT FALSE,
+    annotations TEXT[] NOT NULL DEFAULT '{}',
+    media_types TEXT[] NOT NULL DEFAULT '{}',
+    invoice TEXT NOT NULL,
+    PRIMARY KEY (name, version)
+);
+ALTER TABLE bindle_invoices ADD COLUMN IF NOT EXISTS annotations TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE bindle_invoices ADD COLUMN IF NOT EXISTS media_types TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX IF NOT EXISTS bindle_invoices_annotations ON bindle_invoices USING GIN (annotations);
+CREATE INDEX IF NOT EXISTS bindle_invoices_media_types ON bindle_invoices USING GIN (media_types);
+CREATE TABLE IF NOT EXISTS bindle_parcels (
+    sha TEXT NOT NULL,
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    PRIMARY KEY (sha, name, version),
+    FOREIGN KEY (name, version) REFERENCES bindle_invoices (name, version) ON DELETE CASCADE
+)
+"#;
+
+const UPSERT_INVOICE: &str = r#"
+INSERT INTO bindle_invoices (name, version, yanked, annotations, media_types, invoice)
+VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (name, version) DO UPDATE SET yanked = EXCLUDED.yanked,
+    annotations = EXCLUDED.annotations, media_types = EXCLUDED.media_types,
+    invoice = EXCLUDED.invoice
+"#;
+
+const DELETE_INVOICE: &str = "DELETE FROM bindle_invoices WHERE name = $1 AND version = $2";
+
+const DELETE_PARCELS: &str = "DELETE FROM bindle_parcels WHERE name = $1 AND version = $2";
+
+const INSERT_PARCELS: &str = r#"
+INSERT INTO bindle_parcels (sha, name, version) SELECT DISTINCT unnest($3::text[]), $1, $2
+ON CONFLICT DO NOTHING
+"#;
+
+const FIND_BY_PARCEL: &str = r#"
+SELECT i.invoice FROM bindle_invoices i
+JOIN bindle_parcels p ON p.name = i.name AND p.version = i.version
+WHERE p.sha = $1 ORDER BY i.name, i.version
+"#;
+
+/// Implements query processing on top of a Postgres database, persisting the metadata of all
+/// indexed invoices in a `bindle_invoices` table and the SHAs of their parcels in a
+/// `bindle_parcels` table.
+///
+/// The annotations and parcel media types of each invoice are stored in their own columns, so
+/// those filters, exact versions and paging are handled by the database. Queries for a version
+/// range or for distinct bindles are filtered and paged after loading all rows matching the rest
+/// of the query, as versions are stored as text. Rows indexed before the columns were added only
+/// match the filters once they are indexed again, such as when a provider warms the index.
+///
+/// Both strict and standard modes are supported. In strict mode, the query term must exactly match
+/// the bindle name. In standard mode, any bindle whose name contains the query term matches.
+///
+/// All statements go through a single connection. As an invoice is indexed in a transaction, which
+/// needs the connection to itself, the connection is shared behind a lock.
+#[derive(Clone)]
+pub struct PostgresEngine {
+    client: Arc<Mutex<Client>>,
+}
+
+impl PostgresEngine {
+    /// Connects to the database described by the given connection string (e.g.
+    /// `host=localhost user=bindle dbname=bindle` or `postgresql://bindle@localhost/bindle`) and
+    /// creates the index table if it does not yet exist.
+    ///
+    /// The connection is driven by a task spawned on the current tokio runtime
+    pub async fn connect(config: &str) -> anyhow::Result<Self> {
+        let (client, connection) = tokio_postgres::connect(config, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("Postgres connection error: {}", e);
+            }
+        });
+        client.batch_execute(CREATE_TABLE).await?;
+        Ok(PostgresEngine {
+            client: Arc::new(Mutex::new(client)),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Search for PostgresEngine {
+    async fn query(
+        &self,
+        term: String,
+        filter: String,
+        options: SearchOptions,
+    ) -> anyhow::Result<Matches> {
+        trace!(
+            "beginning postgres search with term {}, version {}, and options {:?}",
+            term,
+            filter,
+            options
+        );
+        let pattern = if options.strict {
+            term.clone()
+        } else {
+            format!("%{}%", escape_like(&term))
+        };
+        let exact_version = exact_version(&filter);
+        let annotations = annotation_pairs(&options.annotations);
+
+        let mut sql = format!(
+            "FROM bindle_invoices WHERE name {} $1",
+            if options.strict { "=" } else { "LIKE" }
+        );
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&pattern];
+        if !options.yanked {
+            sql.push_str(" AND NOT yanked");
+        }
+        if let Some(version) = exact_version.as_ref() {
+            params.push(version);
+            sql.push_str(&format!(" AND version = ${}", params.len()));
+        }
+        if !annotations.is_empty() {
+            params.push(&annotations);
+            sql.push_str(&format!(" AND annotations @> ${}::text[]", params.len()));
+        }
+        if !options.media_types.is_empty() {
+            params.push(&options.media_types);
+            sql.push_str(&format!(" AND media_types && ${}::text[]", params.len()));
+        }
+
+        let mut matches = Matches::new(&options, term);
+        let client = self.client.lock().await;
+        // SemVer ranges can't be expressed in SQL and versions are stored as text, so they can't
+        // be compared by the database either. Those queries are filtered and paged here
+        if (filter.is_empty() || exact_version.is_some()) && !options.distinct {
+            let count = client
+                .query_one(format!("SELECT COUNT(*) {}", sql).as_str(), &params)
+                .await?;
+            let total: i64 = count.get(0);
+            matches.total = total as u64;
+
+            let (limit, offset) = (matches.limit as i64, matches.offset as i64);
+            params.push(&limit);
+            params.push(&offset);
+            let paged = format!(
+                "SELECT invoice {} ORDER BY name, version LIMIT ${} OFFSET ${}",
+                sql,
+                params.len() - 1,
+                params.len()
+            );
+            for row in client.query(paged.as_str(), &params).await? {
+                let raw: String = row.get(0);
+                matches.invoices.push(toml::from_str(&raw)?);
+            }
+        } else {
+            let all = format!("SELECT invoice {} ORDER BY name, version", sql);
+            let mut found = Vec::new();
+            for row in client.query(all.as_str(), &params).await? {
+                let raw: String = row.get(0);
+                let invoice: crate::Invoice = toml::from_str(&raw)?;
+                if invoice.version_in_range(&filter) {
+                    found.push(invoice);
+                }
+            }
+            if options.distinct {
+                found = super::latest_versions(found, |i| i);
+            }
+            matches.total = found.len() as u64;
+            matches.invoices = found
+                .into_iter()
+                .skip(matches.offset as usize)
+                .take(matches.limit as usize)
+                .collect();
+        }
+        trace!("Found {} total matches", matches.total);
+        matches.more = matches.total > matches.offset + matches.limit as u64;
+        trace!("Returning {} found invoices", matches.invoices.len());
+
+        Ok(matches)
+    }
+
+    async fn index(&self, invoice: &crate::Invoice) -> anyhow::Result<()> {
+        let raw = toml::to_string(invoice)?;
+        let name = invoice.bindle.id.name();
+        let version = invoice.bindle.id.version_string();
+        let annotations = annotation_pairs(invoice.annotations.iter().flatten());
+        let mut media_types: Vec<&str> = invoice
+            .parcel
+            .iter()
+            .flatten()
+            .map(|p| p.label.media_type.as_str())
+            .collect();
+        media_types.sort_unstable();
+        media_types.dedup();
+        // Updates can't change the parcels of a stored invoice, but the index doesn't rely on it
+        let shas: Vec<&str> = invoice
+            .parcel
+            .iter()
+            .flatten()
+            .map(|p| p.label.sha256.as_str())
+            .collect();
+
+        // The invoice and its parcels are replaced together, so a failure halfway through doesn't
+        // leave an invoice without its parcels
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await?;
+        transaction
+            .execute(
+                UPSERT_INVOICE,
+                &[
+                    &name,
+                    &version,
+                    &invoice.yanked.unwrap_or(false),
+                    &annotations,
+                    &media_types,
+                    &raw,
+                ],
+            )
+            .await?;
+        transaction
+            .execute(DELETE_PARCELS, &[&name, &version])
+            .await?;
+        transaction
+            .execute(INSERT_PARCELS, &[&name, &version, &shas])
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        let (name, version) = (id.name(), id.version_string());
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await?;
+        transaction
+            .execute(DELETE_PARCELS, &[&name, &version])
+            .await?;
+        transaction
+            .execute(DELETE_INVOICE, &[&name, &version])
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn find_by_parcel(&self, sha: &str) -> anyhow::Result<Vec<crate::Invoice>> {
+        let mut found = Vec::new();
+        for row in self
+            .client
+            .lock()
+            .await
+            .query(FIND_BY_PARCEL, &[&sha])
+            .await?
+        {
+            let raw: String = row.get(0);
+            found.push(toml::from_str(&raw)?);
+        }
+        Ok(found)
+    }
+}
+
+/// Returns the version if the filter only matches that exact version, which is the case for plain
+/// versions without build metadata (see [`Invoice::version_in_range`](crate::Invoice::version_in_range))
+fn exact_version(filter: &str) -> Option<String> {
+    match semver::Version::parse(filter) {
+        Ok(v) if v.build.is_empty() => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Encodes each annotation as a JSON array of its key and value, so the annotations can be stored
+/// in and matched against a text array without the keys and values being ambiguous
+fn annotation_pairs<'a>(
+    annotations: impl IntoIterator<Item = (&'a String, &'a String)>,
+) -> Vec<String> {
+    annotations
+        .into_iter()
+        .map(|pair| serde_json::to_string(&pair).unwrap_or_default())
+        .collect()
+}
+
+/// Escapes all of the special characters in a `LIKE` pattern so the term is matched literally
+fn escape_like(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The environment variable containing the connection string of a database to use for testing.
+    /// If it is not set, tests that require a database are skipped
+    const TEST_DATABASE_ENV: &str = "BINDLE_TEST_POSTGRES_URL";
+
+    #[test]
+    fn test_escape_like() {
+        assert_eq!("example.com/foo", escape_like("example.com/foo"));
+        assert_eq!("my\\_bindle\\%", escape_like("my_bindle%"));
+        assert_eq!("back\\\\slash", escape_like("back\\slash"));
+    }
+
+    #[test]
+    fn test_exact_version() {
+        assert_eq!(Some("1.2.3".to_owned()), exact_version("1.2.3"));
+        assert_eq!(Some("1.2.3-rc.1".to_owned()), exact_version("1.2.3-rc.1"));
+        assert_eq!(None, exact_version("^1.2.3"));
+        assert_eq!(None, exact_version("1.2.3+build"));
+        assert_eq!(None, exact_version(""));
+    }
+
+    #[test]
+    fn test_annotation_pairs() {
+        let mut annotations = std::collections::BTreeMap::new();
+        annotations.insert("a=b".to_owned(), "c".to_owned());
+        annotations.insert("a".to_owned(), "b=c".to_owned());
+        assert_eq!(
+            vec![r#"["a","b=c"]"#.to_owned(), r#"["a=b","c"]"#.to_owned()],
+            annotation_pairs(&annotations)
+        );
+    }
+
+    #[tokio::test]
+    async fn postgres_engine_should_index() {
+        let url = match std::env::var(TEST_DATABASE_ENV) {
+            Ok(u) => u,
+            Err(_) => {
+                eprintln!("{} is not set, skipping postgres tests", TEST_DATABASE_ENV);
+                return;
+            }
+        };
+        let searcher = PostgresEngine::connect(&url)
+            .await
+            .expect("unable to connect to test database");
+        searcher
+            .client
+            .lock()
+            .await
+            .execute(
+                "DELETE FROM bindle_invoices WHERE name LIKE 'pgtest/%'",
+                &[],
+            )
+            .await
+            .expect("unable to clean up test data");
+
+        let mut inv = crate::provider::test_common::invoice_fixture();
+        inv.set_annotation("team", "core");
+        for version in &["1.2.3", "1.3.0", "2.0.0"] {
+            inv.bindle.id = format!("pgtest/bindle/{}", version).parse().unwrap();
+            searcher.index(&inv).await.expect("unable to index invoice");
+        }
+        // Yank the last one
+        inv.yanked = Some(true);
+        searcher.index(&inv).await.expect("unable to update index");
+
+        let strict = || SearchOptions {
+            strict: true,
+            ..Default::default()
+        };
+
+        let matches = searcher
+            .query("pgtest/bindle".to_owned(), "^1.2.3".to_owned(), strict())
+            .await
+            .expect("found some matches");
+        assert_eq!(2, matches.invoices.len());
+
+        // Yanked invoices should only be returned when requested
+        let matches = searcher
+            .query("pgtest/bindle".to_owned(), String::new(), strict())
+            .await
+            .expect("found some matches");
+        assert_eq!(2, matches.total);
+        let matches = searcher
+            .query(
+                "pgtest/bindle".to_owned(),
+                String::new(),
+                SearchOptions {
+                    yanked: true,
+                    ..strict()
+                },
+            )
+            .await
+            .expect("found some matches");
+        assert_eq!(3, matches.total);
+
+        // Standard mode should match substrings, but a strict search shouldn't
+        let matches = searcher
+            .query(
+                "pgtest/".to_owned(),
+                String::new(),
+                SearchOptions::default(),
+            )
+            .await
+            .expect("found some matches");
+        assert_eq!(2, matches.total);
+        let matches = searcher
+            .query("pgtest/".to_owned(), String::new(), strict())
+            .await
+            .expect("found some matches");
+        assert!(matches.invoices.is_empty());
+
+        // Offsets and limits
+        let matches = searcher
+            .query(
+                "pgtest/bindle".to_owned(),
+                String::new(),
+                SearchOptions {
+                    offset: 1,
+                    limit: 1,
+                    ..strict()
+                },
+            )
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.invoices.len());
+        assert!(!matches.more);
+        assert_eq!("1.3.0", matches.invoices[0].bindle.id.version_string());
+
+        // Exact versions, annotations and media types are filtered by the database
+        let matches = searcher
+            .query("pgtest/bindle".to_owned(), "1.3.0".to_owned(), strict())
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.total);
+        let filtered = |annotation: &str, media_type: &str| {
+            let mut options = strict();
+            options
+                .annotations
+                .insert("team".to_owned(), annotation.to_owned());
+            options.media_types = vec![media_type.to_owned()];
+            searcher.query("pgtest/bindle".to_owned(), String::new(), options)
+        };
+        assert_eq!(2, filtered("core", "text/toml").await.unwrap().total);
+        assert_eq!(0, filtered("other", "text/toml").await.unwrap().total);
+        assert_eq!(0, filtered("core", "image/png").await.unwrap().total);
+
+        // Parcels are indexed for all invoices, including yanked ones
+        let test_invoices = |found: Vec<crate::Invoice>| {
+            found
+                .into_iter()
+                .filter(|i| i.bindle.id.name() == "pgtest/bindle")
+                .count()
+        };
+        let sha = "abcdef1234567890987654321";
+        let found = searcher.find_by_parcel(sha).await.expect("found parcels");
+        assert_eq!(3, test_invoices(found));
+        searcher
+            .remove(&"pgtest/bindle/1.2.3".parse().unwrap())
+            .await
+            .expect("unable to remove invoice");
+        let found = searcher.find_by_parcel(sha).await.expect("found parcels");
+        assert_eq!(2, test_invoices(found));
+    }
+}
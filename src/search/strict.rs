@@ -1,13 +1,18 @@
 //! A strict query engine implementation. It always expects a strict match of query terms
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use log::trace;
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
-use crate::search::{Matches, Search, SearchOptions};
+use crate::search::{IndexStats, Matches, Search, SearchOptions};
+
+/// The version of the snapshot format. Snapshots of other versions are ignored
+const SNAPSHOT_VERSION: &str = "1.0";
 
 /// Implements strict query processing.
 #[derive(Clone)]
@@ -16,12 +21,81 @@ pub struct StrictEngine {
     // search results predictable. This greatly simplifies the process of doing offsets
     // and limits.
     index: Arc<RwLock<BTreeMap<String, crate::Invoice>>>,
+    // The keys of the invoices containing each parcel SHA. It is always locked after the index,
+    // so the two can't get out of sync
+    parcels: Arc<RwLock<HashMap<String, BTreeSet<String>>>>,
+    snapshot: Option<Arc<PathBuf>>,
 }
 
 impl Default for StrictEngine {
     fn default() -> Self {
         StrictEngine {
             index: Arc::new(RwLock::new(BTreeMap::new())),
+            parcels: Arc::new(RwLock::new(HashMap::new())),
+            snapshot: None,
+        }
+    }
+}
+
+/// A snapshot of the index as it is saved. JSON is used as it is much faster to parse than TOML
+/// for the large number of invoices a snapshot can contain
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotRef<'a> {
+    version: &'a str,
+    fingerprint: &'a str,
+    invoices: Vec<&'a crate::Invoice>,
+}
+
+/// A snapshot of the index as it is restored
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Snapshot {
+    version: String,
+    fingerprint: String,
+    invoices: Vec<crate::Invoice>,
+}
+
+impl StrictEngine {
+    /// Saves snapshots of the index to the given file and restores them from it. Without a
+    /// snapshot file, the index has to be rebuilt every time it is created
+    pub fn with_snapshot(mut self, path: impl AsRef<Path>) -> Self {
+        self.snapshot = Some(Arc::new(path.as_ref().to_owned()));
+        self
+    }
+
+    /// Returns statistics about the indexed invoices
+    pub async fn stats(&self) -> IndexStats {
+        let index = self.index.read().await;
+        IndexStats {
+            invoices: index.len() as u64,
+            yanked: index
+                .values()
+                .filter(|inv| inv.yanked.unwrap_or(false))
+                .count() as u64,
+        }
+    }
+}
+
+/// Adds the parcels of the invoice to the parcel index
+fn index_parcels(parcels: &mut HashMap<String, BTreeSet<String>>, invoice: &crate::Invoice) {
+    for parcel in invoice.parcel.iter().flatten() {
+        parcels
+            .entry(parcel.label.sha256.clone())
+            .or_default()
+            .insert(invoice.name());
+    }
+}
+
+/// Removes the parcels of the invoice from the parcel index
+fn unindex_parcels(parcels: &mut HashMap<String, BTreeSet<String>>, invoice: &crate::Invoice) {
+    let key = invoice.name();
+    for parcel in invoice.parcel.iter().flatten() {
+        if let Some(keys) = parcels.get_mut(&parcel.label.sha256) {
+            keys.remove(&key);
+            if keys.is_empty() {
+                parcels.remove(&parcel.label.sha256);
+            }
         }
     }
 }
@@ -48,10 +122,15 @@ impl Search for StrictEngine {
             .filter(|(_, i)| {
                 // Term and version have to be exact matches.
                 // TODO: Version should have matching turned on.
-                i.bindle.id.name() == term && i.version_in_range(&filter)
+                i.bindle.id.name() == term
+                    && i.version_in_range(&filter)
+                    && options.matches_filters(i)
             })
             .map(|(_, v)| (*v).clone())
             .collect();
+        if options.distinct {
+            found = super::latest_versions(found, |i| i);
+        }
 
         trace!("Found {} total matches", found.len());
         let mut matches = Matches::new(&options, term);
@@ -80,12 +159,98 @@ impl Search for StrictEngine {
     }
 
     async fn index(&self, invoice: &crate::Invoice) -> anyhow::Result<()> {
-        self.index
-            .write()
-            .await
-            .insert(invoice.name(), invoice.clone());
+        let mut index = self.index.write().await;
+        let mut parcels = self.parcels.write().await;
+        if let Some(previous) = index.insert(invoice.name(), invoice.clone()) {
+            unindex_parcels(&mut parcels, &previous);
+        }
+        index_parcels(&mut parcels, invoice);
+        Ok(())
+    }
+
+    async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+        let mut index = self.index.write().await;
+        let mut parcels = self.parcels.write().await;
+        // Keyed the same way as `Invoice::name`
+        if let Some(previous) = index.remove(&format!("{}/{}", id.name(), id.version())) {
+            unindex_parcels(&mut parcels, &previous);
+        }
+        Ok(())
+    }
+
+    async fn versions(&self, name: &str) -> anyhow::Result<Vec<crate::VersionInfo>> {
+        let index = self.index.read().await;
+        Ok(super::version_list(index.values(), name))
+    }
+
+    async fn find_by_parcel(&self, sha: &str) -> anyhow::Result<Vec<crate::Invoice>> {
+        let index = self.index.read().await;
+        let parcels = self.parcels.read().await;
+        Ok(parcels
+            .get(sha)
+            .into_iter()
+            .flatten()
+            .filter_map(|key| index.get(key).cloned())
+            .collect())
+    }
+
+    async fn save_snapshot(&self, fingerprint: &str) -> anyhow::Result<()> {
+        let path = match &self.snapshot {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let index = self.index.read().await;
+        let raw = serde_json::to_vec(&SnapshotRef {
+            version: SNAPSHOT_VERSION,
+            fingerprint,
+            invoices: index.values().collect(),
+        })?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write to a temporary file first, so a snapshot is never only partially written
+        let temp = path.with_extension("tmp");
+        tokio::fs::write(&temp, raw).await?;
+        tokio::fs::rename(&temp, path.as_ref()).await?;
+        debug!(
+            "Saved snapshot of {} invoices to {}",
+            index.len(),
+            path.display()
+        );
         Ok(())
     }
+
+    async fn restore_snapshot(&self, fingerprint: &str) -> anyhow::Result<bool> {
+        let path = match &self.snapshot {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+        let raw = match tokio::fs::read(path.as_ref()).await {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot: Snapshot = serde_json::from_slice(&raw)?;
+        if snapshot.version != SNAPSHOT_VERSION || snapshot.fingerprint != fingerprint {
+            debug!("Ignoring stale index snapshot {}", path.display());
+            return Ok(false);
+        }
+        let mut index = self.index.write().await;
+        let mut parcels = self.parcels.write().await;
+        // Only the invoices are saved, so the parcel index is rebuilt from them
+        for inv in snapshot.invoices {
+            if let Some(previous) = index.insert(inv.name(), inv.clone()) {
+                unindex_parcels(&mut parcels, &previous);
+            }
+            index_parcels(&mut parcels, &inv);
+        }
+        debug!(
+            "Restored {} invoices from snapshot {}",
+            index.len(),
+            path.display()
+        );
+        Ok(true)
+    }
 }
 
 #[cfg(test)]
@@ -154,9 +319,262 @@ mod test {
             .expect("found some matches");
         assert!(matches.invoices.is_empty());
 
+        // Distinct queries should only return the latest version that isn't yanked
+        let distinct = || SearchOptions {
+            distinct: true,
+            ..SearchOptions::default()
+        };
+        let matches = searcher
+            .query("my/bindle".to_owned(), "^1.2.3".to_owned(), distinct())
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.total);
+        assert_eq!("1.3.0", matches.invoices[0].bindle.id.version_string());
+        let mut yanked = inv2.clone();
+        yanked.yanked = Some(true);
+        searcher.index(&yanked).await.expect("succesfully yanked");
+        assert_eq!(
+            IndexStats {
+                invoices: 2,
+                yanked: 1
+            },
+            searcher.stats().await
+        );
+        let matches = searcher
+            .query("my/bindle".to_owned(), "^1.2.3".to_owned(), distinct())
+            .await
+            .expect("found some matches");
+        assert_eq!("1.2.3", matches.invoices[0].bindle.id.version_string());
+
+        // Only invoices with the given annotations and parcel media types should match
+        let mut annotated = invoice_fixture("my/bindle".to_owned(), "1.4.0".to_owned());
+        annotated.set_annotation("env", "prod");
+        searcher
+            .index(&annotated)
+            .await
+            .expect("succesfully indexed");
+        let filtered = |env: &str, media_type: &str| SearchOptions {
+            annotations: vec![("env".to_owned(), env.to_owned())]
+                .into_iter()
+                .collect(),
+            media_types: vec![media_type.to_owned(), "image/png".to_owned()],
+            ..SearchOptions::default()
+        };
+        let matches = searcher
+            .query(
+                "my/bindle".to_owned(),
+                "".to_owned(),
+                filtered("prod", "text/toml"),
+            )
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.total);
+        assert_eq!("1.4.0", matches.invoices[0].bindle.id.version_string());
+        for (env, media_type) in &[("dev", "text/toml"), ("prod", "text/html")] {
+            let matches = searcher
+                .query(
+                    "my/bindle".to_owned(),
+                    "".to_owned(),
+                    filtered(env, media_type),
+                )
+                .await
+                .expect("found some matches");
+            assert_eq!(0, matches.total);
+        }
+        searcher
+            .remove(&annotated.bindle.id)
+            .await
+            .expect("succesfully removed");
+
+        // Removed invoices should no longer be found
+        searcher
+            .remove(&inv.bindle.id)
+            .await
+            .expect("succesfully removed my/bindle/1.2.3");
+        let matches = searcher
+            .query(
+                "my/bindle".to_owned(),
+                "^1.2.3".to_owned(),
+                SearchOptions::default(),
+            )
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.invoices.len());
+
         // TODO: Need to test yanked bindles
     }
 
+    /// Only implements the required methods, so the default implementation of `versions` is used
+    struct Paged(StrictEngine);
+
+    #[async_trait::async_trait]
+    impl Search for Paged {
+        async fn query(
+            &self,
+            term: String,
+            filter: String,
+            options: SearchOptions,
+        ) -> anyhow::Result<Matches> {
+            let options = SearchOptions {
+                limit: 1,
+                ..options
+            };
+            self.0.query(term, filter, options).await
+        }
+
+        async fn index(&self, invoice: &crate::Invoice) -> anyhow::Result<()> {
+            self.0.index(invoice).await
+        }
+
+        async fn remove(&self, id: &crate::Id) -> anyhow::Result<()> {
+            self.0.remove(id).await
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_engine_should_list_versions() {
+        let searcher = StrictEngine::default();
+        for version in &["1.10.0", "1.2.0", "1.9.0"] {
+            searcher
+                .index(&invoice_fixture(
+                    "my/bindle".to_owned(),
+                    version.to_string(),
+                ))
+                .await
+                .expect("succesfully indexed");
+        }
+        let mut yanked = invoice_fixture("my/bindle".to_owned(), "1.2.0".to_owned());
+        yanked.yanked = Some(true);
+        yanked.signature = Some(vec![crate::signature::Signature {
+            by: "Test".to_owned(),
+            signature: String::new(),
+            key: String::new(),
+            role: crate::signature::SignatureRole::Creator,
+            at: 1234,
+            algorithm: Default::default(),
+            log_entry: None,
+        }]);
+        searcher.index(&yanked).await.expect("succesfully yanked");
+        searcher
+            .index(&invoice_fixture(
+                "my/bindle2".to_owned(),
+                "2.0.0".to_owned(),
+            ))
+            .await
+            .expect("succesfully indexed");
+
+        let paged = Paged(searcher.clone());
+        for versions in &[
+            searcher
+                .versions("my/bindle")
+                .await
+                .expect("versions listed"),
+            paged.versions("my/bindle").await.expect("versions listed"),
+        ] {
+            assert_eq!(
+                vec!["1.2.0", "1.9.0", "1.10.0"],
+                versions
+                    .iter()
+                    .map(|v| v.version.as_str())
+                    .collect::<Vec<_>>(),
+                "Versions should be sorted by semver"
+            );
+            assert!(versions[0].yanked);
+            assert_eq!(Some(1234), versions[0].created_at);
+            assert!(!versions[1].yanked);
+            assert_eq!(None, versions[1].created_at);
+        }
+        assert!(searcher
+            .versions("my")
+            .await
+            .expect("versions listed")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_engine_should_restore_snapshot() {
+        let dir = tempfile::tempdir().expect("unable to create tempdir");
+        let path = dir.path().join("snapshot.json");
+
+        let searcher = StrictEngine::default().with_snapshot(&path);
+        // Nothing to restore yet
+        assert!(!searcher.restore_snapshot("store").await.unwrap());
+        let mut inv = invoice_fixture("my/bindle".to_owned(), "1.2.3".to_owned());
+        inv.yanked = Some(true);
+        searcher.index(&inv).await.unwrap();
+        searcher
+            .save_snapshot("store")
+            .await
+            .expect("snapshot should be saved");
+
+        // Snapshots of a store in another state are stale
+        let restored = StrictEngine::default().with_snapshot(&path);
+        assert!(!restored.restore_snapshot("changed").await.unwrap());
+        assert!(restored.index.read().await.is_empty());
+
+        assert!(restored.restore_snapshot("store").await.unwrap());
+        let matches = restored
+            .query(
+                "my/bindle".to_owned(),
+                "1.2.3".to_owned(),
+                SearchOptions {
+                    yanked: true,
+                    ..SearchOptions::default()
+                },
+            )
+            .await
+            .expect("found some matches");
+        assert_eq!(1, matches.invoices.len());
+        assert_eq!(Some(true), matches.invoices[0].yanked);
+        let found = restored
+            .find_by_parcel("abcdef1234567890987654321")
+            .await
+            .unwrap();
+        assert_eq!(1, found.len(), "parcels should be indexed again");
+
+        // Without a snapshot file, nothing is saved or restored
+        let searcher = StrictEngine::default();
+        searcher.save_snapshot("store").await.unwrap();
+        assert!(!searcher.restore_snapshot("store").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn strict_engine_should_find_by_parcel() {
+        let searcher = StrictEngine::default();
+        let shared = "abcdef1234567890987654321";
+        let first = invoice_fixture("my/bindle".to_owned(), "1.0.0".to_owned());
+        let mut second = invoice_fixture("other/bindle".to_owned(), "2.0.0".to_owned());
+        // The second invoice only shares the first parcel
+        second.parcel.as_mut().unwrap()[1].label.sha256 = "0bcdef".to_owned();
+        for inv in &[&second, &first] {
+            searcher.index(inv).await.expect("indexed");
+        }
+
+        let names =
+            |found: Vec<Invoice>| -> Vec<String> { found.iter().map(Invoice::name).collect() };
+        let found = searcher.find_by_parcel(shared).await.unwrap();
+        assert_eq!(vec!["my/bindle/1.0.0", "other/bindle/2.0.0"], names(found));
+        let found = searcher
+            .find_by_parcel("bbcdef1234567890987654321")
+            .await
+            .unwrap();
+        assert_eq!(vec!["my/bindle/1.0.0"], names(found));
+        assert!(searcher.find_by_parcel("unknown").await.unwrap().is_empty());
+
+        // Yanked invoices are still found, and updates replace the indexed parcels
+        let mut yanked = second.clone();
+        yanked.yanked = Some(true);
+        yanked.parcel.as_mut().unwrap().remove(0);
+        searcher.index(&yanked).await.expect("indexed");
+        let found = searcher.find_by_parcel(shared).await.unwrap();
+        assert_eq!(vec!["my/bindle/1.0.0"], names(found));
+        let found = searcher.find_by_parcel("0bcdef").await.unwrap();
+        assert_eq!(Some(true), found[0].yanked);
+
+        searcher.remove(&first.bindle.id).await.expect("removed");
+        assert!(searcher.find_by_parcel(shared).await.unwrap().is_empty());
+    }
+
     fn invoice_fixture(name: String, version: String) -> Invoice {
         let labels = vec![
             crate::Label {
@@ -186,6 +604,7 @@ mod test {
             bindle_version: crate::BINDLE_VERSION_1.to_owned(),
             yanked: None,
             annotations: None,
+            requires: None,
             bindle: crate::BindleSpec {
                 id: format!("{}/{}", name, version).parse().unwrap(),
                 description: Some("bar".to_owned()),
@@ -201,6 +620,7 @@ mod test {
                     .collect(),
             ),
             group: None,
+            signature: None,
         }
     }
 }
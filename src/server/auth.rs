@@ -0,0 +1,470 @@
+//! Authentication for the Bindle server.
+//!
+//! Authentication is handled by an [`Authenticator`](Authenticator), which verifies the contents of
+//! a request's `Authorization` header and returns the [`Identity`](Identity) of the caller. This
+//! module contains implementations for HTTP Basic auth (using an htpasswd file) and bearer tokens,
+//! as well as the [`NoopAuthenticator`](NoopAuthenticator), which lets every request through and is
+//! what the server uses when no authentication is configured.
+//!
+//! By default, the provided authenticators only require credentials for requests that modify data
+//! (creating invoices and parcels, or yanking invoices). Reads can be protected as well by calling
+//! `protect_reads` on the authenticator.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{debug, trace};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use warp::reject::{custom, Reject, Rejection};
+use warp::Filter;
+
+const BASIC_PREFIX: &str = "Basic ";
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// The identity of the caller making a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// The name of the user or the token owner. This is `None` for anonymous requests
+    pub name: Option<String>,
+}
+
+impl Identity {
+    /// Returns the identity used for requests without credentials
+    pub fn anonymous() -> Self {
+        Identity { name: None }
+    }
+
+    /// Returns an identity for the given user name
+    pub fn named(name: impl Into<String>) -> Self {
+        Identity {
+            name: Some(name.into()),
+        }
+    }
+
+    /// Returns true if this identity belongs to a request without credentials
+    pub fn is_anonymous(&self) -> bool {
+        self.name.is_none()
+    }
+}
+
+/// The kind of access a request needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Fetching or querying data
+    Read,
+    /// Creating or modifying data
+    Write,
+}
+
+/// Describes the various errors that can be returned when authenticating a request
+#[derive(Error, Debug)]
+pub enum AuthError {
+    /// The request did not contain any credentials, but they are required for the requested access
+    #[error("Authentication is required")]
+    MissingCredentials,
+    /// The given credentials were malformed or did not match
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    /// The `Authorization` header used an unsupported scheme
+    #[error("Unsupported authorization scheme")]
+    UnsupportedScheme,
+    /// A catch-all for uncategorized errors. Contains an error message describing the underlying
+    /// issue
+    #[error("{0}")]
+    Other(String),
+}
+
+/// The basic functionality required for authenticating requests to a Bindle server
+#[async_trait::async_trait]
+pub trait Authenticator {
+    /// Authenticates a request using the value of its `Authorization` header. Implementations
+    /// should return an anonymous [`Identity`](Identity) if the header is `None` and an error if
+    /// any given credentials are invalid
+    async fn authenticate(&self, authorization: Option<&str>) -> Result<Identity, AuthError>;
+
+    /// Returns whether requests without credentials are allowed for the given kind of access. By
+    /// default, only reads are allowed anonymously
+    fn allow_anonymous(&self, access: Access) -> bool {
+        access == Access::Read
+    }
+
+    /// The value returned in the `WWW-Authenticate` header when a request is rejected
+    fn challenge(&self) -> &str {
+        "Basic realm=\"bindle\""
+    }
+
+    /// The authentication schemes accepted by the authenticator, as advertised to clients. By
+    /// default, this is the scheme of the [`challenge`](Authenticator::challenge)
+    fn schemes(&self) -> Vec<String> {
+        self.challenge()
+            .split_whitespace()
+            .next()
+            .map(|s| vec![s.to_owned()])
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait::async_trait]
+impl<A: Authenticator + Send + Sync + ?Sized> Authenticator for Arc<A> {
+    async fn authenticate(&self, authorization: Option<&str>) -> Result<Identity, AuthError> {
+        self.as_ref().authenticate(authorization).await
+    }
+
+    fn allow_anonymous(&self, access: Access) -> bool {
+        self.as_ref().allow_anonymous(access)
+    }
+
+    fn challenge(&self) -> &str {
+        self.as_ref().challenge()
+    }
+
+    fn schemes(&self) -> Vec<String> {
+        self.as_ref().schemes()
+    }
+}
+
+/// An authenticator that doesn't check anything. Every request is allowed and treated as
+/// anonymous, which means the server is completely open
+#[derive(Debug, Clone, Default)]
+pub struct NoopAuthenticator;
+
+#[async_trait::async_trait]
+impl Authenticator for NoopAuthenticator {
+    async fn authenticate(&self, _authorization: Option<&str>) -> Result<Identity, AuthError> {
+        Ok(Identity::anonymous())
+    }
+
+    fn allow_anonymous(&self, _access: Access) -> bool {
+        true
+    }
+
+    fn schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// An authenticator for HTTP Basic auth. Users are loaded from an htpasswd file, and only bcrypt
+/// hashed passwords (generated with `htpasswd -B`) are supported
+#[derive(Debug, Clone)]
+pub struct BasicAuthenticator {
+    users: Arc<HashMap<String, String>>,
+    protect_reads: bool,
+}
+
+impl BasicAuthenticator {
+    /// Creates a new authenticator from a map of user names to bcrypt password hashes
+    pub fn new(users: HashMap<String, String>) -> Self {
+        BasicAuthenticator {
+            users: Arc::new(users),
+            protect_reads: false,
+        }
+    }
+
+    /// Loads all users from the htpasswd file at the given path. Each line of the file should
+    /// contain a user name and a bcrypt hash separated by a colon (`:`)
+    pub async fn from_htpasswd(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read_to_string(path).await?;
+        let users = parse_colon_separated(&raw)?
+            .into_iter()
+            .map(|(user, hash)| {
+                if !hash.starts_with("$2") {
+                    return Err(anyhow::anyhow!(
+                        "Password for user {} is not a bcrypt hash",
+                        user
+                    ));
+                }
+                Ok((user, hash))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self::new(users))
+    }
+
+    /// Requires credentials for reads as well as writes
+    pub fn protect_reads(mut self) -> Self {
+        self.protect_reads = true;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticator for BasicAuthenticator {
+    async fn authenticate(&self, authorization: Option<&str>) -> Result<Identity, AuthError> {
+        let encoded = match authorization {
+            None => return Ok(Identity::anonymous()),
+            Some(a) => a
+                .strip_prefix(BASIC_PREFIX)
+                .ok_or(AuthError::UnsupportedScheme)?,
+        };
+        let decoded = base64::decode(encoded.trim())
+            .ok()
+            .and_then(|raw| String::from_utf8(raw).ok())
+            .ok_or(AuthError::InvalidCredentials)?;
+        let mut parts = decoded.splitn(2, ':');
+        let (user, password) = match (parts.next(), parts.next()) {
+            (Some(u), Some(p)) => (u, p),
+            _ => return Err(AuthError::InvalidCredentials),
+        };
+        let hash = self
+            .users
+            .get(user)
+            .ok_or(AuthError::InvalidCredentials)?
+            .clone();
+        let password = password.to_owned();
+        // bcrypt is intentionally slow, so don't block the executor while verifying
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .map_err(|e| AuthError::Other(e.to_string()))?
+            .map_err(|e| AuthError::Other(e.to_string()))?;
+        if !valid {
+            debug!("Invalid password given for user {}", user);
+            return Err(AuthError::InvalidCredentials);
+        }
+        trace!("Authenticated user {}", user);
+        Ok(Identity::named(user))
+    }
+
+    fn allow_anonymous(&self, access: Access) -> bool {
+        access == Access::Read && !self.protect_reads
+    }
+}
+
+/// An authenticator for bearer tokens. Tokens are stored as hex encoded SHA-256 hashes along with
+/// the name of the identity they belong to
+#[derive(Debug, Clone)]
+pub struct BearerAuthenticator {
+    tokens: Arc<HashMap<String, String>>,
+    protect_reads: bool,
+}
+
+impl BearerAuthenticator {
+    /// Creates a new authenticator from a map of hex encoded SHA-256 token hashes to the name of
+    /// the identity they belong to
+    pub fn new(tokens: HashMap<String, String>) -> Self {
+        BearerAuthenticator {
+            tokens: Arc::new(tokens),
+            protect_reads: false,
+        }
+    }
+
+    /// Loads all tokens from the file at the given path. Each line of the file should contain the
+    /// name of the token owner and the hex encoded SHA-256 hash of the token separated by a colon
+    /// (`:`)
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read_to_string(path).await?;
+        let tokens = parse_colon_separated(&raw)?
+            .into_iter()
+            .map(|(name, hash)| (hash.to_lowercase(), name))
+            .collect();
+        Ok(Self::new(tokens))
+    }
+
+    /// Requires credentials for reads as well as writes
+    pub fn protect_reads(mut self) -> Self {
+        self.protect_reads = true;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticator for BearerAuthenticator {
+    async fn authenticate(&self, authorization: Option<&str>) -> Result<Identity, AuthError> {
+        let token = match authorization {
+            None => return Ok(Identity::anonymous()),
+            Some(a) => a
+                .strip_prefix(BEARER_PREFIX)
+                .ok_or(AuthError::UnsupportedScheme)?,
+        };
+        let hash = format!("{:x}", Sha256::digest(token.trim().as_bytes()));
+        match self.tokens.get(&hash) {
+            Some(name) => {
+                trace!("Authenticated token owned by {}", name);
+                Ok(Identity::named(name.as_str()))
+            }
+            None => Err(AuthError::InvalidCredentials),
+        }
+    }
+
+    fn allow_anonymous(&self, access: Access) -> bool {
+        access == Access::Read && !self.protect_reads
+    }
+
+    fn challenge(&self) -> &str {
+        "Bearer realm=\"bindle\""
+    }
+}
+
+/// A warp filter that authenticates the request using the given authenticator, rejecting it if
+/// the credentials are invalid or if they are missing and required for the given access
+pub fn authenticate<A>(
+    authenticator: A,
+    access: Access,
+) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone
+where
+    A: Authenticator + Clone + Send + Sync + 'static,
+{
+    warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+        let authenticator = authenticator.clone();
+        async move {
+            let identity = authenticator
+                .authenticate(header.as_deref())
+                .await
+                .map_err(|e| reject(&authenticator, e))?;
+            if identity.is_anonymous() && !authenticator.allow_anonymous(access) {
+                return Err(reject(&authenticator, AuthError::MissingCredentials));
+            }
+            Ok(identity)
+        }
+    })
+}
+
+/// Same as [`authenticate`](authenticate), but doesn't extract the identity
+pub(crate) fn require<A>(
+    authenticator: A,
+    access: Access,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone
+where
+    A: Authenticator + Clone + Send + Sync + 'static,
+{
+    authenticate(authenticator, access)
+        .map(|_| ())
+        .untuple_one()
+}
+
+/// Converts authentication rejections into a 401 response with a TOML error body
+pub(crate) async fn handle_auth_rejection(
+    err: warp::Rejection,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let Some(e) = err.find::<Unauthorized>() {
+        Ok(warp::reply::with_header(
+            crate::server::reply::reply_from_error(&e.error, warp::http::StatusCode::UNAUTHORIZED),
+            warp::http::header::WWW_AUTHENTICATE,
+            e.challenge.as_str(),
+        ))
+    } else {
+        Err(err)
+    }
+}
+
+fn reject<A: Authenticator>(authenticator: &A, error: AuthError) -> Rejection {
+    debug!("Rejecting request: {}", error);
+    custom(Unauthorized {
+        error: error.to_string(),
+        challenge: authenticator.challenge().to_owned(),
+    })
+}
+
+#[derive(Debug)]
+struct Unauthorized {
+    error: String,
+    challenge: String,
+}
+
+impl Reject for Unauthorized {}
+
+/// Parses lines of `key:value` pairs, skipping empty lines and comments
+fn parse_colon_separated(raw: &str) -> anyhow::Result<Vec<(String, String)>> {
+    raw.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if !k.is_empty() && !v.is_empty() => {
+                    Ok((k.to_owned(), v.to_owned()))
+                }
+                _ => Err(anyhow::anyhow!("Invalid line: {}", line)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn basic_header(user: &str, password: &str) -> String {
+        format!(
+            "{}{}",
+            BASIC_PREFIX,
+            base64::encode(format!("{}:{}", user, password))
+        )
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let mut users = HashMap::new();
+        users.insert(
+            "admin".to_owned(),
+            bcrypt::hash("sw0rdf1sh", 4).expect("unable to hash password"),
+        );
+        let auth = BasicAuthenticator::new(users);
+
+        assert_eq!(
+            Identity::named("admin"),
+            auth.authenticate(Some(&basic_header("admin", "sw0rdf1sh")))
+                .await
+                .expect("valid credentials should authenticate")
+        );
+        assert!(auth.authenticate(None).await.unwrap().is_anonymous());
+        assert!(matches!(
+            auth.authenticate(Some(&basic_header("admin", "password")))
+                .await,
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            auth.authenticate(Some(&basic_header("nobody", "sw0rdf1sh")))
+                .await,
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            auth.authenticate(Some("Bearer foo")).await,
+            Err(AuthError::UnsupportedScheme)
+        ));
+
+        assert!(auth.allow_anonymous(Access::Read));
+        assert!(!auth.allow_anonymous(Access::Write));
+        assert!(!auth.protect_reads().allow_anonymous(Access::Read));
+    }
+
+    #[tokio::test]
+    async fn test_bearer_auth() {
+        let mut tokens = HashMap::new();
+        tokens.insert(
+            format!("{:x}", Sha256::digest(b"my-token")),
+            "ci".to_owned(),
+        );
+        let auth = BearerAuthenticator::new(tokens);
+
+        assert_eq!(
+            Identity::named("ci"),
+            auth.authenticate(Some("Bearer my-token"))
+                .await
+                .expect("valid token should authenticate")
+        );
+        assert!(matches!(
+            auth.authenticate(Some("Bearer not-my-token")).await,
+            Err(AuthError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            auth.authenticate(Some(&basic_header("ci", "my-token")))
+                .await,
+            Err(AuthError::UnsupportedScheme)
+        ));
+    }
+
+    #[test]
+    fn test_parse_colon_separated() {
+        let parsed =
+            parse_colon_separated("# A comment\nfoo:bar\n\nbaz:qux:quux\n").expect("should parse");
+        assert_eq!(
+            vec![
+                ("foo".to_owned(), "bar".to_owned()),
+                ("baz".to_owned(), "qux:quux".to_owned())
+            ],
+            parsed
+        );
+        assert!(parse_colon_separated("nocolon").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Authorization for the Bindle server.
+//!
+//! Once a request has been authenticated (see the [`auth`](super::auth) module), an
+//! [`Authorizer`](Authorizer) decides whether the [`Identity`](super::auth::Identity) is allowed to
+//! perform the requested [`Action`](Action) on a bindle. The [`RolePolicy`](RolePolicy) authorizer
+//! grants [`Role`](Role)s to identities for all bindles matching a name pattern, which makes it
+//! possible to, for example, only allow certain users to create `mycompany/*` bindles. Policies
+//! can be loaded from a TOML file that looks like this:
+//!
+//! ```toml
+//! [[grant]]
+//! identity = "*"
+//! bindles = "*"
+//! role = "reader"
+//!
+//! [[grant]]
+//! identity = "alice"
+//! bindles = "mycompany/*"
+//! role = "admin"
+//! ```
+//!
+//! Authorization is currently enforced when creating invoices and parcels, when yanking invoices
+//! and when collecting garbage. Access to reads is controlled by the authenticator.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use warp::Filter;
+
+use super::auth::Identity;
+
+/// Matches any identity (including anonymous ones) or any bindle name
+pub(crate) const WILDCARD: &str = "*";
+
+/// An action performed on a bindle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Fetching a bindle or its parcels
+    Read,
+    /// Creating an invoice or uploading one of its parcels
+    Create,
+    /// Adding a signature to an existing invoice, such as an approver countersigning it
+    Sign,
+    /// Yanking an invoice
+    Yank,
+    /// Permanently deleting an invoice along with its parcels
+    Delete,
+    /// Removing parcels that aren't referenced by any invoice. This affects the whole store, so it
+    /// is authorized against the bindle name `*`, which only a grant for all bindles matches
+    CollectGarbage,
+    /// Checking the status of the replication from a primary server. Like collecting garbage, this
+    /// is authorized against the bindle name `*`
+    ViewReplication,
+    /// Scraping the metrics of the server, which cover the whole store. Like collecting garbage,
+    /// this is authorized against the bindle name `*`
+    ViewMetrics,
+    /// Inspecting the storage used by every user and their quotas. Like collecting garbage, this
+    /// is authorized against the bindle name `*`
+    ViewQuotas,
+}
+
+impl Action {
+    /// Returns the least privileged role that is allowed to perform this action
+    pub fn required_role(&self) -> Role {
+        match self {
+            Action::Read => Role::Reader,
+            Action::Create | Action::Sign => Role::Creator,
+            Action::Yank
+            | Action::Delete
+            | Action::CollectGarbage
+            | Action::ViewReplication
+            | Action::ViewMetrics
+            | Action::ViewQuotas => Role::Admin,
+        }
+    }
+}
+
+/// A role that can be granted to an identity. Each role includes all of the permissions of the
+/// roles before it: readers can read bindles, creators can also create them, and admins can also
+/// yank or delete them, collect garbage, check the status of replication, scrape metrics and
+/// inspect quotas
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Creator,
+    Admin,
+}
+
+/// Describes the errors that can be returned when authorizing a request
+#[derive(Error, Debug)]
+pub enum AuthzError {
+    /// The identity is not allowed to perform the action on the bindle
+    #[error("{identity} is not allowed to {action:?} bindle {bindle}")]
+    Forbidden {
+        identity: String,
+        action: Action,
+        bindle: String,
+    },
+}
+
+/// The basic functionality required for authorizing requests to a Bindle server
+pub trait Authorizer {
+    /// Returns an error if the given identity is not allowed to perform the action on the bindle
+    /// with the given name
+    fn authorize(
+        &self,
+        identity: &Identity,
+        bindle_name: &str,
+        action: Action,
+    ) -> Result<(), AuthzError>;
+}
+
+impl<Z: Authorizer + ?Sized> Authorizer for Arc<Z> {
+    fn authorize(
+        &self,
+        identity: &Identity,
+        bindle_name: &str,
+        action: Action,
+    ) -> Result<(), AuthzError> {
+        self.as_ref().authorize(identity, bindle_name, action)
+    }
+}
+
+/// An authorizer that allows every identity to do everything. This is the default when no policy
+/// is configured
+#[derive(Debug, Clone, Default)]
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize(&self, _: &Identity, _: &str, _: Action) -> Result<(), AuthzError> {
+        Ok(())
+    }
+}
+
+/// Grants a role to an identity for all bindles matching a pattern
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Grant {
+    /// The name of the identity, or `*` to match anyone (including anonymous requests)
+    pub identity: String,
+    /// The name of the bindles this grant applies to. A trailing `*` matches any bindle starting
+    /// with the given prefix, so `mycompany/*` matches `mycompany/foo` and `*` matches all bindles
+    pub bindles: String,
+    pub role: Role,
+}
+
+impl Grant {
+    fn matches(&self, identity: &Identity, bindle_name: &str) -> bool {
+        let identity_matches =
+            self.identity == WILDCARD || identity.name.as_deref() == Some(self.identity.as_str());
+        identity_matches && matches_pattern(&self.bindles, bindle_name)
+    }
+}
+
+/// Returns whether the bindle name matches the pattern. A trailing `*` matches any bindle starting
+/// with the rest of the pattern, otherwise the name has to match exactly
+pub(crate) fn matches_pattern(pattern: &str, bindle_name: &str) -> bool {
+    match pattern.strip_suffix(WILDCARD) {
+        Some(prefix) => bindle_name.starts_with(prefix),
+        None => bindle_name == pattern,
+    }
+}
+
+/// An authorizer using a list of [`Grant`](Grant)s. An identity is allowed to perform an action if
+/// any matching grant has a role that includes the action. Anything that isn't granted is denied
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RolePolicy {
+    #[serde(default)]
+    pub grant: Vec<Grant>,
+}
+
+impl RolePolicy {
+    /// Loads a policy from the TOML file at the given path
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read(path).await?;
+        Ok(toml::from_slice(&raw)?)
+    }
+}
+
+impl Authorizer for RolePolicy {
+    fn authorize(
+        &self,
+        identity: &Identity,
+        bindle_name: &str,
+        action: Action,
+    ) -> Result<(), AuthzError> {
+        let required = action.required_role();
+        if self
+            .grant
+            .iter()
+            .any(|g| g.role >= required && g.matches(identity, bindle_name))
+        {
+            return Ok(());
+        }
+        Err(AuthzError::Forbidden {
+            identity: identity
+                .name
+                .clone()
+                .unwrap_or_else(|| "anonymous user".to_owned()),
+            action,
+            bindle: bindle_name.to_owned(),
+        })
+    }
+}
+
+pub(crate) fn with_authorizer<Z>(
+    authorizer: Z,
+) -> impl Filter<Extract = (Z,), Error = std::convert::Infallible> + Clone
+where
+    Z: Authorizer + Clone + Send,
+{
+    warp::any().map(move || authorizer.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_role_policy() {
+        let policy: RolePolicy = toml::from_str(
+            r#"
+            [[grant]]
+            identity = "*"
+            bindles = "*"
+            role = "reader"
+
+            [[grant]]
+            identity = "ci"
+            bindles = "mycompany/*"
+            role = "creator"
+
+            [[grant]]
+            identity = "alice"
+            bindles = "mycompany/*"
+            role = "admin"
+
+            [[grant]]
+            identity = "bob"
+            bindles = "example.com/hello"
+            role = "creator"
+            "#,
+        )
+        .expect("policy should parse");
+
+        let anonymous = Identity::anonymous();
+        let ci = Identity::named("ci");
+        let alice = Identity::named("alice");
+        let bob = Identity::named("bob");
+
+        assert!(policy
+            .authorize(&anonymous, "mycompany/foo", Action::Read)
+            .is_ok());
+        assert!(policy
+            .authorize(&anonymous, "mycompany/foo", Action::Create)
+            .is_err());
+
+        assert!(policy
+            .authorize(&ci, "mycompany/foo", Action::Create)
+            .is_ok());
+        assert!(policy
+            .authorize(&ci, "mycompany/foo", Action::Yank)
+            .is_err());
+        assert!(policy
+            .authorize(&ci, "othercompany/foo", Action::Create)
+            .is_err());
+
+        assert!(policy
+            .authorize(&alice, "mycompany/foo", Action::Yank)
+            .is_ok());
+        assert!(policy
+            .authorize(&alice, "mycompanyfoo", Action::Create)
+            .is_err());
+
+        // Patterns without a wildcard have to match exactly
+        assert!(policy
+            .authorize(&bob, "example.com/hello", Action::Create)
+            .is_ok());
+        assert!(policy
+            .authorize(&bob, "example.com/hello2", Action::Create)
+            .is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//! Configuration files for the server.
+//!
+//! Instead of passing everything on the command line, the server can be configured with a TOML or
+//! YAML file (chosen by its `.yaml` or `.yml` extension) that deserializes into a
+//! [`ServerConfig`](ServerConfig). Every field is optional, so a file only needs to contain what it
+//! changes. Applications embedding a server can use the same struct with any serde format. A TOML
+//! file looks like this:
+//!
+//! ```toml
+//! address = "0.0.0.0:8080"
+//!
+//! [storage]
+//! backend = "file"
+//! directory = "/var/lib/bindle"
+//!
+//! [tls]
+//! certPath = "/etc/bindle/tls.crt"
+//! keyPath = "/etc/bindle/tls.key"
+//!
+//! [auth]
+//! tokenFile = "/etc/bindle/tokens"
+//! policyFile = "/etc/bindle/policy.toml"
+//!
+//! [limits]
+//! slowRequestThreshold = 10
+//! largeParcelThreshold = 104857600
+//! quotaPolicy = "/etc/bindle/quotas.toml"
+//!
+//! [signing]
+//! keys = "/etc/bindle/signing-keys.toml"
+//!
+//! [tracing]
+//! otlpEndpoint = "http://localhost:4317"
+//! ```
+//!
+//! The `bindle-server` binary reads the file given with `--config` (or `BINDLE_CONFIG`). Command
+//! line flags and their environment variables take precedence over the file
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::TlsConfig;
+
+/// The configuration of a server, as loaded from a configuration file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ServerConfig {
+    /// The IP address and port to listen on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<SocketAddr>,
+    pub storage: StorageConfig,
+    /// Serves the API over TLS. If not set, the server uses plain HTTP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    pub auth: AuthConfig,
+    pub limits: LimitsConfig,
+    pub signing: SigningConfig,
+    pub tracing: TracingConfig,
+}
+
+/// The storage backends a server can store bindles in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Bindles are stored as files in a directory, see the
+    /// [`FileProvider`](crate::provider::file::FileProvider)
+    #[default]
+    File,
+}
+
+/// Where bindles are stored and indexed
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// The directory bindles are stored in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directory: Option<PathBuf>,
+    /// The connection string of a Postgres database to persist the search index in. If not set,
+    /// the index is kept in memory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postgres_url: Option<String>,
+    /// Whether to save the in-memory search index when the server shuts down and restore it on the
+    /// next start
+    pub snapshot_index: bool,
+}
+
+/// How requests are authenticated and authorized
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AuthConfig {
+    /// An htpasswd file (with bcrypt hashed passwords) for HTTP Basic authentication
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub htpasswd_file: Option<PathBuf>,
+    /// A file of `NAME:SHA256_OF_TOKEN` lines for bearer token authentication
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<PathBuf>,
+    /// Whether reading bindles requires credentials as well
+    pub protect_reads: bool,
+    /// A TOML file granting roles to identities for bindles
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy_file: Option<PathBuf>,
+}
+
+/// Thresholds for reporting requests and limits on what can be stored
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LimitsConfig {
+    /// Requests that take longer than this many seconds are logged. 0 disables the warning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_request_threshold: Option<u64>,
+    /// Invoices larger than this many bytes are logged. 0 disables the warning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub large_invoice_threshold: Option<u64>,
+    /// Parcel uploads larger than this many bytes are logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub large_parcel_threshold: Option<u64>,
+    /// A TOML file with the storage quotas of the server and its users, see the
+    /// [`quotas`](super::quotas) module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_policy: Option<PathBuf>,
+}
+
+/// The key new invoices are signed with in the host role
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SigningConfig {
+    /// A secret key file containing the host key. Its passphrase is never part of the
+    /// configuration and has to be passed in the `BINDLE_SIGNING_KEY_PASSPHRASE` environment
+    /// variable instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keys: Option<PathBuf>,
+    /// The label or base64 encoded public key of the key to sign with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+/// Where traces of requests are exported to
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TracingConfig {
+    /// The OTLP endpoint of an OpenTelemetry collector
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub otlp_endpoint: Option<String>,
+}
+
+impl ServerConfig {
+    /// Loads the configuration from a YAML file if its extension is `.yaml` or `.yml`, or from a
+    /// TOML file otherwise
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let raw = tokio::fs::read(path).await?;
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => serde_yaml::from_slice(&raw)?,
+            _ => toml::from_slice(&raw)?,
+        };
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_from_file() {
+        let dir = tempfile::tempdir().expect("unable to create tempdir");
+        let toml_path = dir.path().join("bindle.toml");
+        tokio::fs::write(
+            &toml_path,
+            r#"
+address = "0.0.0.0:8080"
+
+[storage]
+directory = "/var/lib/bindle"
+
+[tls]
+certPath = "tls.crt"
+keyPath = "tls.key"
+
+[auth]
+tokenFile = "tokens"
+protectReads = true
+
+[limits]
+slowRequestThreshold = 10
+"#,
+        )
+        .await
+        .unwrap();
+        let yaml_path = dir.path().join("bindle.yaml");
+        tokio::fs::write(
+            &yaml_path,
+            r#"
+address: "0.0.0.0:8080"
+storage:
+  directory: /var/lib/bindle
+tls:
+  certPath: tls.crt
+  keyPath: tls.key
+auth:
+  tokenFile: tokens
+  protectReads: true
+limits:
+  slowRequestThreshold: 10
+"#,
+        )
+        .await
+        .unwrap();
+
+        let config = ServerConfig::from_file(&toml_path)
+            .await
+            .expect("TOML config should load");
+        assert_eq!(Some(([0, 0, 0, 0], 8080).into()), config.address);
+        assert_eq!(StorageBackend::File, config.storage.backend);
+        assert_eq!(
+            Some(PathBuf::from("/var/lib/bindle")),
+            config.storage.directory
+        );
+        let tls = config.tls.as_ref().expect("TLS should be configured");
+        assert_eq!(PathBuf::from("tls.crt"), tls.cert_path);
+        assert!(tls.client_ca_path.is_none());
+        assert!(config.auth.protect_reads);
+        assert_eq!(Some(10), config.limits.slow_request_threshold);
+        assert!(config.limits.large_invoice_threshold.is_none());
+
+        let yaml = ServerConfig::from_file(&yaml_path)
+            .await
+            .expect("YAML config should load");
+        assert_eq!(config, yaml);
+
+        tokio::fs::write(&toml_path, "[storage]\nbackend = \"s3\"")
+            .await
+            .unwrap();
+        assert!(
+            ServerConfig::from_file(&toml_path).await.is_err(),
+            "Unknown backends should be rejected"
+        );
+    }
+}
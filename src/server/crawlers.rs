@@ -0,0 +1,274 @@
+//! A `robots.txt` and throttling of crawlers.
+//!
+//! Public servers get crawled by bots following every invoice and parcel URL they find. A
+//! [`CrawlerPolicy`](CrawlerPolicy) tells them what to do with a `robots.txt` and slows down the
+//! ones that don't listen by limiting how often each user agent can read from the API. Policies can
+//! be loaded from a TOML file that looks like this:
+//!
+//! ```toml
+//! robots = """
+//! User-agent: *
+//! Disallow: /v1/_i/
+//! """
+//!
+//! [[agent]]
+//! userAgent = "Googlebot"
+//! crawlDelay = 5
+//!
+//! [[agent]]
+//! userAgent = "bot"
+//! crawlDelay = 30
+//! ```
+//!
+//! The first rule whose user agent is contained in the `User-Agent` header of a `GET` or `HEAD`
+//! request applies, ignoring case. A rule for `*` applies to all clients, including the Bindle
+//! client. Reads from the same user agent that come sooner than the crawl delay after its last
+//! accepted read are rejected with `429 Too Many Requests`. If `robots` isn't set, the `robots.txt`
+//! is generated from the rules, giving each of them a `Crawl-delay`
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use warp::reject::{custom, Reject};
+use warp::{Filter, Rejection};
+
+/// The number of user agents remembered before the ones that may read again are forgotten
+const MAX_TRACKED_AGENTS: usize = 1024;
+
+/// The minimum time between reads for all user agents containing a string
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct AgentRule {
+    /// The string the `User-Agent` header has to contain, ignoring case, or `*` for any user agent
+    pub user_agent: String,
+    /// The number of seconds a user agent has to wait between reads
+    pub crawl_delay: u64,
+}
+
+impl AgentRule {
+    fn matches(&self, user_agent: &str) -> bool {
+        self.user_agent == "*"
+            || user_agent
+                .to_ascii_lowercase()
+                .contains(&self.user_agent.to_ascii_lowercase())
+    }
+}
+
+/// The `robots.txt` of a server and a list of [`AgentRule`](AgentRule)s, of which the first one
+/// matching the user agent of a read applies
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CrawlerPolicy {
+    /// The `robots.txt` served as is. If not set, it is generated from the rules
+    pub robots: Option<String>,
+    #[serde(default)]
+    pub agent: Vec<AgentRule>,
+}
+
+impl CrawlerPolicy {
+    /// Loads a policy from the TOML file at the given path
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read(path).await?;
+        Ok(toml::from_slice(&raw)?)
+    }
+
+    /// Returns the rule that applies to the given user agent, if any
+    pub fn rule_for(&self, user_agent: &str) -> Option<&AgentRule> {
+        self.agent.iter().find(|r| r.matches(user_agent))
+    }
+
+    /// Returns the `robots.txt` to serve, or `None` if the policy is empty
+    pub fn robots_txt(&self) -> Option<String> {
+        if let Some(robots) = &self.robots {
+            return Some(robots.clone());
+        }
+        if self.agent.is_empty() {
+            return None;
+        }
+        let groups: Vec<String> = self
+            .agent
+            .iter()
+            .map(|r| {
+                format!(
+                    "User-agent: {}\nCrawl-delay: {}\nAllow: /\n",
+                    r.user_agent, r.crawl_delay
+                )
+            })
+            .collect();
+        Some(groups.join("\n"))
+    }
+}
+
+/// Keeps track of when each user agent last read from the API. Clones share the same state
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Throttle {
+    policy: Arc<CrawlerPolicy>,
+    last_reads: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(policy: Arc<CrawlerPolicy>) -> Self {
+        Throttle {
+            policy,
+            last_reads: Arc::default(),
+        }
+    }
+
+    /// Records a read by the given user agent, or returns how much longer it has to wait if the
+    /// read comes too soon after the last one
+    fn check(&self, user_agent: &str, now: Instant) -> Result<(), Duration> {
+        let delay = match self.policy.rule_for(user_agent) {
+            Some(rule) => Duration::from_secs(rule.crawl_delay),
+            None => return Ok(()),
+        };
+        let mut last_reads = self.last_reads.lock().unwrap();
+        if let Some(last) = last_reads.get(user_agent) {
+            let elapsed = now.saturating_duration_since(*last);
+            if elapsed < delay {
+                return Err(delay - elapsed);
+            }
+        }
+        if last_reads.len() >= MAX_TRACKED_AGENTS {
+            // Agents that already waited long enough are allowed to read no matter what, so they
+            // don't need to be remembered. A rule may have changed, so this uses the longest delay
+            let max_delay = self.policy.agent.iter().map(|r| r.crawl_delay).max();
+            let max_delay = Duration::from_secs(max_delay.unwrap_or_default());
+            last_reads.retain(|_, last| now.saturating_duration_since(*last) < max_delay);
+        }
+        last_reads.insert(user_agent.to_owned(), now);
+        Ok(())
+    }
+}
+
+/// Returns a filter that rejects `GET` and `HEAD` requests from user agents reading more often than
+/// the policy allows. Other requests are never throttled
+pub(crate) fn throttle(throttle: Throttle) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::header::optional::<String>("user-agent"))
+        .and_then(
+            move |method: warp::http::Method, user_agent: Option<String>| {
+                let throttle = throttle.clone();
+                async move {
+                    let user_agent = match user_agent {
+                        Some(ua) if method == "GET" || method == "HEAD" => ua,
+                        _ => return Ok(()),
+                    };
+                    throttle
+                        .check(&user_agent, Instant::now())
+                        .map_err(|retry_after| {
+                            log::debug!(
+                                "Throttling user agent {} for {:?}",
+                                user_agent,
+                                retry_after
+                            );
+                            custom(Throttled { retry_after })
+                        })
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Converts throttling rejections into a 429 response with a `Retry-After` header
+pub(crate) async fn handle_throttle_rejection(
+    err: Rejection,
+) -> Result<impl warp::Reply, Rejection> {
+    if let Some(e) = err.find::<Throttled>() {
+        // Partial seconds are rounded up, so clients retrying right away aren't throttled again
+        let seconds = e.retry_after.as_secs() + u64::from(e.retry_after.subsec_nanos() > 0);
+        Ok(warp::reply::with_header(
+            crate::server::reply::reply_from_error(
+                "Too many requests from this user agent",
+                warp::http::StatusCode::TOO_MANY_REQUESTS,
+            ),
+            warp::http::header::RETRY_AFTER,
+            seconds.to_string(),
+        ))
+    } else {
+        Err(err)
+    }
+}
+
+#[derive(Debug)]
+struct Throttled {
+    retry_after: Duration,
+}
+
+impl Reject for Throttled {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> CrawlerPolicy {
+        toml::from_str(
+            r#"
+            [[agent]]
+            userAgent = "Googlebot"
+            crawlDelay = 5
+
+            [[agent]]
+            userAgent = "bot"
+            crawlDelay = 30
+            "#,
+        )
+        .expect("policy should parse")
+    }
+
+    #[test]
+    fn test_rule_for() {
+        let policy = policy();
+        assert_eq!(
+            5,
+            policy
+                .rule_for("Mozilla/5.0 (compatible; googlebot/2.1)")
+                .unwrap()
+                .crawl_delay
+        );
+        assert_eq!(30, policy.rule_for("SomeBot/1.0").unwrap().crawl_delay);
+        assert!(policy.rule_for("bindle-client/0.2.0").is_none());
+        assert!(CrawlerPolicy::default().rule_for("SomeBot/1.0").is_none());
+    }
+
+    #[test]
+    fn test_robots_txt() {
+        assert_eq!(
+            "User-agent: Googlebot\nCrawl-delay: 5\nAllow: /\n\nUser-agent: bot\nCrawl-delay: 30\nAllow: /\n",
+            policy().robots_txt().unwrap()
+        );
+        let policy = CrawlerPolicy {
+            robots: Some("User-agent: *\nDisallow: /\n".to_owned()),
+            ..policy()
+        };
+        assert_eq!("User-agent: *\nDisallow: /\n", policy.robots_txt().unwrap());
+        assert!(CrawlerPolicy::default().robots_txt().is_none());
+    }
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(Arc::new(policy()));
+        let start = Instant::now();
+        throttle
+            .check("Googlebot", start)
+            .expect("first read should be allowed");
+        assert_eq!(
+            Err(Duration::from_secs(3)),
+            throttle.check("Googlebot", start + Duration::from_secs(2))
+        );
+        // Each user agent is throttled on its own
+        throttle
+            .check("OtherBot", start + Duration::from_secs(2))
+            .expect("first read of another agent should be allowed");
+        throttle
+            .check("Googlebot", start + Duration::from_secs(5))
+            .expect("read after the delay should be allowed");
+        for _ in 0..3 {
+            throttle
+                .check("bindle-client", start)
+                .expect("agents without a rule should never be throttled");
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! `Content-Disposition` headers for parcel downloads.
+//!
+//! Parcels are normally sent without a `Content-Disposition`, which leaves it up to browsers
+//! whether to display a parcel or save it. A [`DispositionPolicy`](DispositionPolicy) decides this
+//! based on the media type of the parcel, so that, for example, images and text are shown inline
+//! while binaries are downloaded. Either way, the name from the parcel's label is suggested as the
+//! file name. Policies can be loaded from a TOML file that looks like this:
+//!
+//! ```toml
+//! [[rule]]
+//! mediaTypes = "image/*"
+//! disposition = "inline"
+//!
+//! [[rule]]
+//! mediaTypes = "text/plain"
+//! disposition = "inline"
+//!
+//! [[rule]]
+//! mediaTypes = "*"
+//! disposition = "attachment"
+//! ```
+//!
+//! The first rule matching the media type of a parcel applies. Parcels that don't match any rule
+//! are sent without the header
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::authz::matches_pattern;
+use crate::Label;
+
+/// How a browser should handle a parcel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposition {
+    /// Display the parcel, if the browser can
+    Inline,
+    /// Save the parcel as a file
+    Attachment,
+}
+
+impl std::fmt::Display for Disposition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Disposition::Inline => write!(f, "inline"),
+            Disposition::Attachment => write!(f, "attachment"),
+        }
+    }
+}
+
+/// The disposition of all parcels with a media type matching a pattern
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct DispositionRule {
+    /// The media types this rule applies to. A trailing `*` matches any media type starting with
+    /// the rest of the pattern (e.g. `image/*`), otherwise the media type has to match exactly.
+    /// Parameters like `charset` are ignored
+    pub media_types: String,
+    pub disposition: Disposition,
+}
+
+/// A list of [`DispositionRule`](DispositionRule)s, of which the first one matching the media type
+/// of a parcel applies
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DispositionPolicy {
+    #[serde(default)]
+    pub rule: Vec<DispositionRule>,
+}
+
+impl DispositionPolicy {
+    /// Loads a policy from the TOML file at the given path
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read(path).await?;
+        Ok(toml::from_slice(&raw)?)
+    }
+
+    /// Returns the rule that applies to the given media type, if any
+    pub fn rule_for(&self, media_type: &str) -> Option<&DispositionRule> {
+        let media_type = media_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.rule
+            .iter()
+            .find(|r| matches_pattern(&r.media_types.to_ascii_lowercase(), &media_type))
+    }
+
+    /// Returns the value of the `Content-Disposition` header for the parcel with the given label,
+    /// or `None` if no rule applies to it
+    pub fn header_for(&self, label: &Label) -> Option<String> {
+        let rule = self.rule_for(&label.media_type)?;
+        Some(header_value(rule.disposition, &label.name))
+    }
+}
+
+fn header_value(disposition: Disposition, name: &str) -> String {
+    // Parcels can be named with a path, but browsers only want the name of the file
+    let name = name.rsplit(&['/', '\\'][..]).next().unwrap_or_default();
+    if name.is_empty() {
+        return disposition.to_string();
+    }
+    // A quoted file name can only contain printable ASCII, so names with anything else also get
+    // the RFC 6266 `filename*` parameter, which browsers prefer if they understand it
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    if fallback == name {
+        return format!("{}; filename=\"{}\"", disposition, name);
+    }
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        fallback,
+        percent_encode(name)
+    )
+}
+
+/// Percent encodes everything but the characters allowed in an RFC 5987 extended value
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn label(name: &str, media_type: &str) -> Label {
+        Label {
+            name: name.to_owned(),
+            media_type: media_type.to_owned(),
+            ..Label::default()
+        }
+    }
+
+    #[test]
+    fn test_header_for() {
+        let policy: DispositionPolicy = toml::from_str(
+            r#"
+            [[rule]]
+            mediaTypes = "image/*"
+            disposition = "inline"
+
+            [[rule]]
+            mediaTypes = "text/plain"
+            disposition = "inline"
+
+            [[rule]]
+            mediaTypes = "application/*"
+            disposition = "attachment"
+            "#,
+        )
+        .expect("policy should parse");
+
+        assert_eq!(
+            Some("inline; filename=\"logo.png\"".to_owned()),
+            policy.header_for(&label("logo.png", "image/png"))
+        );
+        assert_eq!(
+            Some("inline; filename=\"README.txt\"".to_owned()),
+            policy.header_for(&label("docs/README.txt", "Text/Plain; charset=utf-8"))
+        );
+        assert_eq!(
+            Some("attachment; filename=\"app.wasm\"".to_owned()),
+            policy.header_for(&label("app.wasm", "application/wasm"))
+        );
+        assert_eq!(None, policy.header_for(&label("index.html", "text/html")));
+        assert_eq!(
+            Some("attachment".to_owned()),
+            policy.header_for(&label("bin/", "application/octet-stream"))
+        );
+        assert_eq!(
+            Some(
+                "attachment; filename=\"_bad_ _.bin\"; filename*=UTF-8''%22bad%22%20%C3%A9.bin"
+                    .to_owned()
+            ),
+            policy.header_for(&label("\"bad\" é.bin", "application/octet-stream"))
+        );
+
+        assert!(DispositionPolicy::default()
+            .header_for(&label("logo.png", "image/png"))
+            .is_none());
+    }
+}
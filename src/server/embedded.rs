@@ -0,0 +1,359 @@
+//! Support for running a Bindle server inside of another application
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::future::FutureExt;
+
+use log::{debug, error};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use warp::Filter;
+
+use super::auth::{Authenticator, NoopAuthenticator};
+use super::authz::{AllowAll, Authorizer};
+use super::{ApiOptions, RequestMonitor, TlsConfig, DEFAULT_DRAIN_TIMEOUT};
+use crate::provider::Provider;
+use crate::search::Search;
+
+/// Options for a server started with [`start_in_process`](start_in_process) or
+/// [`server`](super::server)
+#[derive(Debug, Clone)]
+pub struct InProcessOptions<A = NoopAuthenticator, Z = AllowAll> {
+    /// The address to listen on. Defaults to an ephemeral port on localhost
+    pub address: SocketAddr,
+    /// The authenticator used for all requests. Defaults to no authentication
+    pub authenticator: A,
+    /// The authorizer used for all requests. Defaults to allowing everything
+    pub authorizer: Z,
+    /// Optional TLS configuration, which needs the `server-tls` feature. Defaults to plain HTTP
+    pub tls: Option<TlsConfig>,
+    /// Reports requests that are unusually slow or large. Defaults to the default thresholds
+    pub monitor: RequestMonitor,
+    /// Optional features of the API. Defaults to none of them being enabled
+    pub api: ApiOptions,
+    /// How long in flight requests are given to complete when the server shuts down. Defaults to
+    /// [`DEFAULT_DRAIN_TIMEOUT`](super::DEFAULT_DRAIN_TIMEOUT)
+    pub drain_timeout: Duration,
+}
+
+impl Default for InProcessOptions<NoopAuthenticator, AllowAll> {
+    fn default() -> Self {
+        InProcessOptions {
+            address: ([127, 0, 0, 1], 0).into(),
+            authenticator: NoopAuthenticator,
+            authorizer: AllowAll,
+            tls: None,
+            monitor: RequestMonitor::default(),
+            api: ApiOptions::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        }
+    }
+}
+
+/// A builder for a server of the full API, for running it inside of another application with full
+/// control over its lifecycle. [`build`](Server::build) binds the listener and returns a future
+/// that runs the server, along with a [`ShutdownHandle`](ShutdownHandle) to stop it. Unlike
+/// [`server`](super::server), no signals are handled, so the caller decides when the server stops:
+///
+/// ```no_run
+/// # #[tokio::main]
+/// # async fn main() {
+/// # let (store, index) = bindle::testing::setup().await;
+/// let (server, shutdown) = bindle::server::Server::new(store, index)
+///     .address(([127, 0, 0, 1], 8080))
+///     .build()
+///     .expect("unable to start server");
+/// let running = tokio::spawn(server);
+/// // Do other things until it is time to stop...
+/// shutdown.shutdown();
+/// running.await.unwrap();
+/// # }
+/// ```
+pub struct Server<P, I, A = NoopAuthenticator, Z = AllowAll> {
+    store: P,
+    index: I,
+    opts: InProcessOptions<A, Z>,
+}
+
+impl<P, I> Server<P, I>
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: Search + Clone + Send + Sync + 'static,
+{
+    /// Returns a builder for a server of the given store and index, with the same defaults as
+    /// [`InProcessOptions`](InProcessOptions)
+    pub fn new(store: P, index: I) -> Self {
+        Server {
+            store,
+            index,
+            opts: InProcessOptions::default(),
+        }
+    }
+}
+
+impl<P, I, A, Z> Server<P, I, A, Z>
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+{
+    /// Sets the address to listen on. Use port 0 to listen on an ephemeral port
+    pub fn address(mut self, address: impl Into<SocketAddr>) -> Self {
+        self.opts.address = address.into();
+        self
+    }
+
+    /// Serves the API over TLS, which needs the `server-tls` feature
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.opts.tls = Some(config);
+        self
+    }
+
+    /// Sets the authenticator used for all requests
+    pub fn authenticator<A2>(self, authenticator: A2) -> Server<P, I, A2, Z>
+    where
+        A2: Authenticator + Clone + Send + Sync + 'static,
+    {
+        let opts = self.opts;
+        Server {
+            store: self.store,
+            index: self.index,
+            opts: InProcessOptions {
+                address: opts.address,
+                authenticator,
+                authorizer: opts.authorizer,
+                tls: opts.tls,
+                monitor: opts.monitor,
+                api: opts.api,
+                drain_timeout: opts.drain_timeout,
+            },
+        }
+    }
+
+    /// Sets the authorizer used for all requests
+    pub fn authorizer<Z2>(self, authorizer: Z2) -> Server<P, I, A, Z2>
+    where
+        Z2: Authorizer + Clone + Send + Sync + 'static,
+    {
+        let opts = self.opts;
+        Server {
+            store: self.store,
+            index: self.index,
+            opts: InProcessOptions {
+                address: opts.address,
+                authenticator: opts.authenticator,
+                authorizer,
+                tls: opts.tls,
+                monitor: opts.monitor,
+                api: opts.api,
+                drain_timeout: opts.drain_timeout,
+            },
+        }
+    }
+
+    /// Sets the monitor that reports requests that are unusually slow or large
+    pub fn monitor(mut self, monitor: RequestMonitor) -> Self {
+        self.opts.monitor = monitor;
+        self
+    }
+
+    /// Sets the optional features of the API
+    pub fn options(mut self, options: ApiOptions) -> Self {
+        self.opts.api = options;
+        self
+    }
+
+    /// Sets how long in flight requests, such as parcel uploads, are given to complete once the
+    /// server is shut down before their connections are closed
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.opts.drain_timeout = timeout;
+        self
+    }
+
+    /// Binds the listener and returns a future that serves the API until it is shut down using the
+    /// returned handle. The future does nothing unless it is polled, so it should be awaited or
+    /// spawned onto a tokio runtime. Once shut down, it waits for in flight requests to complete
+    /// or for the [drain timeout](Server::drain_timeout) to pass, then
+    /// [flushes](crate::provider::Provider::flush) the store before it completes. Errors if the
+    /// address can't be bound or if the TLS configuration is invalid
+    pub fn build(
+        self,
+    ) -> anyhow::Result<(impl Future<Output = ()> + Send + 'static, ShutdownHandle)> {
+        let (tx, rx) = oneshot::channel::<()>();
+        // A dropped handle can't stop the server anymore, so keep serving until the future is
+        // dropped instead
+        let signal = async {
+            if rx.await.is_err() {
+                futures::future::pending::<()>().await
+            }
+        }
+        .shared();
+        let opts = self.opts;
+        let store = self.store.clone();
+        let server = warp::serve(
+            super::routes::api_with_options(
+                self.store,
+                self.index,
+                opts.authenticator,
+                opts.authorizer,
+                opts.api,
+            )
+            .with(opts.monitor.filter()),
+        );
+        let (addr, fut): (_, Pin<Box<dyn Future<Output = ()> + Send>>) = match &opts.tls {
+            None => {
+                let (addr, fut) =
+                    server.try_bind_with_graceful_shutdown(opts.address, signal.clone())?;
+                (addr, Box::pin(fut))
+            }
+            #[cfg(feature = "server-tls")]
+            Some(config) => {
+                let (addr, incoming) = super::tls::bind(opts.address, config)?;
+                let fut = server.serve_incoming_with_graceful_shutdown(incoming, signal.clone());
+                (addr, Box::pin(fut))
+            }
+            #[cfg(not(feature = "server-tls"))]
+            Some(_) => anyhow::bail!("TLS support requires the server-tls feature"),
+        };
+        debug!("Bound server to {}", addr);
+        let drain_timeout = opts.drain_timeout;
+        let fut = async move {
+            super::drain(fut, signal, drain_timeout).await;
+            if let Err(e) = store.flush().await {
+                error!("Unable to flush the store after shutting down: {}", e);
+            }
+            debug!("Server at {} stopped", addr);
+        };
+        Ok((
+            fut,
+            ShutdownHandle {
+                addr,
+                tls: opts.tls.is_some(),
+                tx,
+            },
+        ))
+    }
+}
+
+/// A handle for stopping a server built with [`Server`](Server). Dropping the handle does not stop
+/// the server, but leaves it running until its future is dropped
+#[derive(Debug)]
+pub struct ShutdownHandle {
+    addr: SocketAddr,
+    tls: bool,
+    tx: oneshot::Sender<()>,
+}
+
+impl ShutdownHandle {
+    /// Returns the address the server is listening on. This contains the actual port if the server
+    /// was started on an ephemeral port
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the URL of the v1 API, suitable for passing to
+    /// [`Client::new`](crate::client::Client::new)
+    pub fn base_url(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}/v1/", scheme, self.addr)
+    }
+
+    /// Signals the server to stop accepting new connections. Its future completes once all in
+    /// flight requests are done or the drain timeout passed, and the store was flushed
+    pub fn shutdown(self) {
+        debug!("Shutting down server at {}", self.addr);
+        // The server could have already stopped, in which case there is nothing to do
+        let _ = self.tx.send(());
+    }
+}
+
+/// A handle to a server started with [`start_in_process`](start_in_process). The server is
+/// gracefully shut down when [`shutdown`](ServerHandle::shutdown) is called or when the handle is
+/// dropped
+pub struct ServerHandle {
+    addr: SocketAddr,
+    tls: bool,
+    shutdown: Option<ShutdownHandle>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Returns the address the server is listening on. This contains the actual port if the server
+    /// was started on an ephemeral port
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the URL of the v1 API, suitable for passing to
+    /// [`Client::new`](crate::client::Client::new)
+    pub fn base_url(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}/v1/", scheme, self.addr)
+    }
+
+    /// Stops accepting new connections, waits for all in flight requests, such as parcel uploads,
+    /// to complete or for the [drain timeout](InProcessOptions::drain_timeout) to pass, and
+    /// [flushes](crate::provider::Provider::flush) the store, such as to save a snapshot of its
+    /// search index
+    pub async fn shutdown(mut self) {
+        self.signal_shutdown();
+        if let Some(task) = self.task.take() {
+            if let Err(e) = task.await {
+                error!("Server task did not shut down cleanly: {}", e);
+            }
+        }
+    }
+
+    fn signal_shutdown(&mut self) {
+        if let Some(handle) = self.shutdown.take() {
+            handle.shutdown();
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.signal_shutdown();
+    }
+}
+
+/// Starts a server for the full API on the caller's tokio runtime and returns a handle to it once
+/// it is listening. Unlike [`server`](super::server), this does not handle any signals, so it is
+/// up to the caller to shut the server down using the returned handle. This is useful for
+/// self-hosting a registry inside of an application or for tests:
+///
+/// ```no_run
+/// # #[tokio::main]
+/// # async fn main() {
+/// # let (store, index) = bindle::testing::setup().await;
+/// let handle = bindle::server::start_in_process(store, index, Default::default())
+///     .expect("unable to start server");
+/// let client = bindle::client::Client::new(&handle.base_url()).unwrap();
+/// // Use the client...
+/// handle.shutdown().await;
+/// # }
+/// ```
+pub fn start_in_process<P, I, A, Z>(
+    store: P,
+    index: I,
+    opts: InProcessOptions<A, Z>,
+) -> anyhow::Result<ServerHandle>
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+{
+    let (fut, shutdown) = Server { store, index, opts }.build()?;
+    debug!("Started in process server at {}", shutdown.addr());
+    Ok(ServerHandle {
+        addr: shutdown.addr(),
+        tls: shutdown.tls,
+        shutdown: Some(shutdown),
+        task: Some(tokio::spawn(fut)),
+    })
+}
@@ -0,0 +1,107 @@
+//! Events the server emits whenever a bindle changes, such as for triggering a deployment once a new
+//! version of a bindle lands.
+//!
+//! Events are emitted by wrapping the provider of the server in a
+//! [`HookedProvider`](HookedProvider), which passes every successful change on to its hooks. Each
+//! hook is a sink for the events:
+//!
+//! - [`HttpHook`](crate::provider::hooks::HttpHook) posts them to a webhook (requires the `client`
+//!   feature). With a [secret](crate::provider::hooks::HttpHook::secret), every delivery is signed
+//!   with an HMAC, which receivers check with [`verify_signature`](verify_signature)
+//! - [`EventChannel`](EventChannel) sends them to any number of subscribers in the same process,
+//!   for applications that embed the server
+//!
+//! ```no_run
+//! # async fn example(store: impl bindle::provider::Provider) {
+//! use bindle::server::events::{Event, EventChannel, HookedProvider};
+//!
+//! let events = EventChannel::default();
+//! let mut subscriber = events.subscribe();
+//! let store = HookedProvider::new(store).with_hook(events);
+//! // Serve the store, for example with `start_in_process`
+//!
+//! while let Ok(event) = subscriber.recv().await {
+//!     if let Event::InvoiceCreated(created) = event {
+//!         println!("Deploying {}", created.bindle_id);
+//!     }
+//! }
+//! # }
+//! ```
+
+use tokio::sync::broadcast;
+
+pub use crate::events::Event;
+pub use crate::provider::hooks::{
+    sign_payload, verify_signature, EventHook, HookedProvider, SIGNATURE_HEADER,
+};
+
+/// A hook that sends every event to all subscribers of the channel. Subscribers that fall more than
+/// the capacity of the channel behind miss the oldest events, which they are told about by a
+/// [`Lagged`](broadcast::RecvError::Lagged) error. Events are dropped while there are no
+/// subscribers
+#[derive(Clone)]
+pub struct EventChannel {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventChannel {
+    /// The number of events kept for slow subscribers by default
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    /// Creates a channel that keeps up to the given number of events for slow subscribers
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventChannel { sender }
+    }
+
+    /// Returns a receiver for all events sent after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventChannel {
+    fn default() -> Self {
+        EventChannel::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+#[async_trait::async_trait]
+impl EventHook for EventChannel {
+    async fn on_event(&self, event: &Event) {
+        // Sending only fails if nobody is subscribed, in which case nobody is missing the event
+        let _ = self.sender.send(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::Provider;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_event_channel() {
+        let (store, _) = testing::setup().await;
+        let events = EventChannel::new(4);
+        let first = events.subscribe();
+        let second = events.subscribe();
+        let store = HookedProvider::new(store).with_hook(events);
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to create invoice");
+        for subscriber in &mut [first, second] {
+            let event = tokio::time::timeout(std::time::Duration::from_secs(5), subscriber.recv())
+                .await
+                .expect("Timed out waiting for event")
+                .expect("Channel should still be open");
+            assert!(matches!(
+                event,
+                Event::InvoiceCreated(created) if created.bindle_id.to_string() == scaffold.invoice.bindle.id.to_string()
+            ));
+        }
+    }
+}
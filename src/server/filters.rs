@@ -3,35 +3,231 @@ use std::fmt;
 use std::io::Read;
 
 use bytes::buf::BufExt;
+use log::warn;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use warp::http::header::{self, HeaderValue};
 use warp::reject::{custom, Reject, Rejection};
+use warp::reply::Response;
 use warp::Filter;
+use warp::Reply;
 
-use super::TOML_MIME_TYPE;
+use super::{JSON_MIME_TYPE, NDJSON_MIME_TYPE, TOML_MIME_TYPE};
+use crate::trace::{TraceParent, REQUEST_ID_HEADER, TRACEPARENT_HEADER};
 
 /// Query string options for the invoice endpoint
 #[derive(Debug, Deserialize)]
 pub struct InvoiceQuery {
     pub yanked: Option<bool>,
+    /// A comma separated list of groups to select, only used for parcel selections
+    pub groups: Option<String>,
+    /// A comma separated list of features to select, only used for parcel selections
+    pub features: Option<String>,
+    /// Which parcel to choose for `oneOf` groups, only used for parcel selections
+    pub prefer: Option<String>,
 }
 
-/// A warp filter that parses the body of a request from TOML to the specified type
+/// Query string options for deleting an invoice. Without `purge`, the invoice is only yanked
+#[derive(Debug, Deserialize)]
+pub struct DeleteQuery {
+    pub purge: Option<bool>,
+}
+
+/// Query string options for the garbage collection endpoint
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GcQuery {
+    pub dry_run: Option<bool>,
+}
+
+/// Query string options for the parcel delta endpoint
+#[derive(Debug, Deserialize)]
+pub struct DeltaQuery {
+    /// The ID of the bindle being updated from
+    pub from: String,
+}
+
+/// Returns the span a request to the API runs in, for use with [`warp::trace`](warp::trace). It
+/// records the operation along with the request ID given by the client or a new one, and the ID of
+/// the trace the request belongs to. A trace is continued if the client sent a `traceparent` header
+pub(crate) fn request_span(info: warp::trace::Info) -> tracing::Span {
+    let headers = info.request_headers();
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    let incoming = header(TRACEPARENT_HEADER).and_then(TraceParent::parse);
+    let request_id = header(REQUEST_ID_HEADER)
+        .map(str::to_owned)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let (operation, bindle_id) = super::monitor::operation(info.method(), info.path());
+    let span = tracing::info_span!(
+        "request",
+        %request_id,
+        trace_id = tracing::field::Empty,
+        operation,
+        bindle = bindle_id.unwrap_or("-"),
+        method = %info.method(),
+        path = info.path(),
+    );
+    let trace_id = match incoming {
+        Some(parent) => {
+            #[cfg(feature = "otlp")]
+            crate::trace::otlp::set_parent(&span, &parent);
+            Some(parent.trace_id())
+        }
+        // Exported spans already belong to a new trace, which is the one to record
+        #[cfg(feature = "otlp")]
+        None => crate::trace::otlp::from_span(&span).map(|t| t.trace_id()),
+        #[cfg(not(feature = "otlp"))]
+        None => None,
+    };
+    let trace_id = trace_id.unwrap_or_else(|| TraceParent::new_root().trace_id());
+    span.record("trace_id", &trace_id.as_str());
+    span
+}
+
+/// A warp filter that parses the body of a request to the specified type from either TOML or JSON,
+/// depending on its `Content-Type`. Any other content type is rejected with a
+/// `415 Unsupported Media Type`
 // Lovingly borrowed from https://docs.rs/warp/0.2.5/src/warp/filters/body.rs.html
-pub fn toml<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Copy {
-    // We can't use the http type constant here because clippy is warning about it having internal
-    // mutability.
-    warp::filters::header::exact_ignore_case("Content-Type", TOML_MIME_TYPE)
+pub fn body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Copy {
+    warp::header::optional::<String>("content-type")
         .and(warp::body::aggregate())
-        .and_then(parse_toml)
+        .and_then(parse_body)
 }
 
-async fn parse_toml<T: DeserializeOwned + Send>(buf: impl warp::Buf) -> Result<T, Rejection> {
+async fn parse_body<T: DeserializeOwned + Send>(
+    content_type: Option<String>,
+    buf: impl warp::Buf,
+) -> Result<T, Rejection> {
+    let media_type = content_type.as_deref().map(media_type);
+    let raw = read_body(buf)?;
+    let parsed: Result<T, Box<dyn Error + Send + Sync>> = match media_type.as_deref() {
+        Some(TOML_MIME_TYPE) => toml::from_slice(&raw).map_err(|e| e.into()),
+        Some(JSON_MIME_TYPE) => serde_json::from_slice(&raw).map_err(|e| e.into()),
+        _ => return Err(custom(UnsupportedMediaType(content_type))),
+    };
+    parsed.map_err(|cause| custom(BodyDeserializeError { cause }))
+}
+
+fn read_body(buf: impl warp::Buf) -> Result<Vec<u8>, Rejection> {
     let mut raw = Vec::new();
     buf.reader()
         .read_to_end(&mut raw)
         .map_err(|err| custom(BodyDeserializeError { cause: err.into() }))?;
-    toml::from_slice(&raw).map_err(|err| custom(BodyDeserializeError { cause: err.into() }))
+    Ok(raw)
+}
+
+/// Returns the media type of a `Content-Type` or `Accept` value, without any parameters
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Wraps a filter so that its replies are sent as JSON to clients whose `Accept` header prefers
+/// `application/json` over TOML. Handlers (including the ones for rejections) always reply with
+/// TOML, so the body is converted here rather than deciding the format in every handler. Replies
+/// that aren't TOML, such as parcel data, are left alone
+pub(crate) fn negotiate<F, R>(
+    filter: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static,
+{
+    warp::header::optional::<String>("accept")
+        .and(filter)
+        .and_then(negotiate_reply)
+}
+
+async fn negotiate_reply<R: Reply>(
+    accept: Option<String>,
+    reply: R,
+) -> Result<Response, Rejection> {
+    let mut res = reply.into_response();
+    res.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("Accept"));
+    let is_toml = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| media_type(v) == TOML_MIME_TYPE)
+        .unwrap_or(false);
+    if !is_toml || !prefers_json(accept.as_deref()) {
+        return Ok(res);
+    }
+
+    let (mut parts, body) = res.into_parts();
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(JSON_MIME_TYPE),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let raw = match hyper::body::to_bytes(body).await {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Unable to read reply body for conversion to JSON: {}", e);
+            return Ok(warp::http::StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    // HEAD replies have the headers of a body that isn't there
+    if raw.is_empty() {
+        return Ok(Response::from_parts(parts, raw.into()));
+    }
+    let json = toml::from_slice::<toml::Value>(&raw)
+        .map_err(|e| e.to_string())
+        .and_then(|v| serde_json::to_vec(&v).map_err(|e| e.to_string()));
+    match json {
+        Ok(body) => Ok(Response::from_parts(parts, body.into())),
+        Err(e) => {
+            warn!("Unable to convert reply body to JSON: {}", e);
+            Ok(warp::http::StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+/// Returns whether an `Accept` header ranks JSON above TOML. TOML wins ties, so clients that don't
+/// say what they want (or accept anything) keep getting TOML
+fn prefers_json(accept: Option<&str>) -> bool {
+    let accept = match accept {
+        Some(a) => a,
+        None => return false,
+    };
+    let (mut toml_q, mut json_q, mut any_q) = (None, None, None);
+    for range in accept.split(',') {
+        let q = range
+            .split(';')
+            .skip(1)
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media_type(range).as_str() {
+            TOML_MIME_TYPE => toml_q = Some(q),
+            JSON_MIME_TYPE => json_q = Some(q),
+            "*/*" | "application/*" => any_q = Some(any_q.map_or(q, |a: f32| a.max(q))),
+            _ => (),
+        }
+    }
+    json_q.or(any_q).unwrap_or(0.0) > toml_q.or(any_q).unwrap_or(0.0)
+}
+
+/// Returns whether an `Accept` header asks for query results to be streamed as newline delimited
+/// JSON. Unlike JSON, this changes how the reply is built, so it has to be asked for explicitly
+pub(crate) fn accepts_ndjson(accept: Option<&str>) -> bool {
+    accept.unwrap_or_default().split(',').any(|range| {
+        let refused = range
+            .split(';')
+            .skip(1)
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .any(|q| q.trim().parse::<f32>().map(|q| q <= 0.0).unwrap_or(false));
+        media_type(range) == NDJSON_MIME_TYPE && !refused
+    })
 }
 
 pub(crate) async fn handle_deserialize_rejection(
@@ -42,6 +238,11 @@ pub(crate) async fn handle_deserialize_rejection(
             e,
             warp::http::StatusCode::BAD_REQUEST,
         ))
+    } else if let Some(e) = err.find::<UnsupportedMediaType>() {
+        Ok(crate::server::reply::reply_from_error(
+            e,
+            warp::http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ))
     } else {
         Err(err)
     }
@@ -54,7 +255,7 @@ struct BodyDeserializeError {
 
 impl fmt::Display for BodyDeserializeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Request body toml deserialize error: {}", self.cause)
+        write!(f, "Request body deserialize error: {}", self.cause)
     }
 }
 
@@ -65,3 +266,60 @@ impl Error for BodyDeserializeError {
 }
 
 impl Reject for BodyDeserializeError {}
+
+#[derive(Debug)]
+struct UnsupportedMediaType(Option<String>);
+
+impl fmt::Display for UnsupportedMediaType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(t) => write!(
+                f,
+                "Unsupported content type {}, expected {} or {}",
+                t, TOML_MIME_TYPE, JSON_MIME_TYPE
+            ),
+            None => write!(
+                f,
+                "Missing content type, expected {} or {}",
+                TOML_MIME_TYPE, JSON_MIME_TYPE
+            ),
+        }
+    }
+}
+
+impl Error for UnsupportedMediaType {}
+
+impl Reject for UnsupportedMediaType {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prefers_json() {
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(!prefers_json(Some(TOML_MIME_TYPE)));
+        assert!(!prefers_json(Some("application/json, application/toml")));
+        assert!(!prefers_json(Some("text/html")));
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("Application/JSON; charset=utf-8")));
+        assert!(prefers_json(Some(
+            "application/toml;q=0.5, application/json"
+        )));
+        assert!(prefers_json(Some("application/json, */*;q=0.1")));
+        assert!(!prefers_json(Some("application/json;q=0.1, */*")));
+    }
+
+    #[test]
+    fn test_accepts_ndjson() {
+        assert!(!accepts_ndjson(None));
+        assert!(!accepts_ndjson(Some("*/*")));
+        assert!(!accepts_ndjson(Some(JSON_MIME_TYPE)));
+        assert!(!accepts_ndjson(Some("application/x-ndjson;q=0")));
+        assert!(accepts_ndjson(Some("application/x-ndjson")));
+        assert!(accepts_ndjson(Some(
+            "application/toml, Application/X-NDJSON;q=0.5"
+        )));
+    }
+}
@@ -1,21 +1,108 @@
 use std::convert::Infallible;
+use std::sync::Arc;
 
-use log::trace;
+use log::{debug, trace, warn};
 use warp::Reply;
 
-use super::filters::InvoiceQuery;
+use super::auth::{Access, Authenticator, Identity};
+use super::authz::{Action, Authorizer};
+use super::filters::{self, DeleteQuery, DeltaQuery, GcQuery, InvoiceQuery};
+use super::keyrings::KeyRingStore;
+use super::processing::Pipeline;
+use super::quotas::{Quotas, Reservation};
 use super::reply;
-use crate::provider::Provider;
-use crate::search::Search;
+use super::uploads::{self, AppendError, UploadStore};
+use super::{ApiOptions, Metrics, JSON_MIME_TYPE, NDJSON_MIME_TYPE, TOML_MIME_TYPE};
+use crate::filters::resolution::{self, FeatureSelector, ResolutionError};
+use crate::provider::{Provider, ProviderError};
+use crate::search::{dependencies::ResolveError, Search};
+use crate::signature::SignatureRole;
+use crate::transparency::TransparencyLog;
 
 pub mod v1 {
     use super::*;
 
     use crate::QueryOptions;
-    use reqwest::Method;
     use tokio::stream::{self, StreamExt};
+    use tokio_util::codec::{BytesCodec, FramedRead};
+    use warp::http::Method;
 
     const PARCEL_ID_SEPARATOR: char = '@';
+    const SUBRESOURCE_PREFIX: &str = "/_";
+    const HISTORY_SUBRESOURCE: &str = "history";
+    const SUMMARY_SUBRESOURCE: &str = "summary";
+    const METADATA_SUBRESOURCE: &str = "meta";
+    const SELECTION_SUBRESOURCE: &str = "selection";
+    const STATUS_SUBRESOURCE: &str = "status";
+    const SIGNATURE_SUBRESOURCE: &str = "signature";
+
+    /// Splits a path tail like `example.com/foo/1.0.0/_history` into the bindle ID and the name of
+    /// the invoice subresource (without the leading `_`). Returns `None` if the tail does not end
+    /// with a subresource. As bindle versions can never start with an `_`, this is unambiguous
+    fn split_subresource(tail: &str) -> Option<(&str, &str)> {
+        let index = tail.rfind(SUBRESOURCE_PREFIX)?;
+        let (id, subresource) = tail.split_at(index);
+        let subresource = subresource.trim_start_matches(SUBRESOURCE_PREFIX);
+        if id.is_empty() || subresource.is_empty() || subresource.contains('/') {
+            return None;
+        }
+        Some((id, subresource))
+    }
+
+    /// Splits a comma separated list from a query string, skipping empty entries
+    fn split_list(list: Option<&str>) -> impl Iterator<Item = &str> {
+        list.unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+    }
+
+    /// Returns whether the string is a hex encoded SHA-256, as parcel SHAs from requests end up
+    /// in storage paths and queries
+    fn is_sha256(sha: &str) -> bool {
+        sha.len() == 64 && sha.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    /// The part of a parcel requested with a `Range` header
+    #[derive(Debug, PartialEq)]
+    enum ParcelRange {
+        /// The whole parcel, either because no range was requested or because the range isn't
+        /// supported. Servers are allowed to ignore ranges, so this is not an error
+        Full,
+        /// The bytes from the start to the end of the range, both inclusive
+        Partial(u64, u64),
+        /// The range doesn't overlap with the parcel
+        Unsatisfiable,
+    }
+
+    /// Parses a `Range` header for a parcel of the given size. Only single byte ranges are
+    /// supported, as multiple ranges would require a multipart response
+    fn parse_range(header: Option<&str>, size: u64) -> ParcelRange {
+        let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+            Some(s) if !s.contains(',') => s.trim(),
+            _ => return ParcelRange::Full,
+        };
+        let (start, end) = match spec.find('-') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => return ParcelRange::Full,
+        };
+        let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // A suffix range, containing the last `end` bytes
+            (Err(_), Ok(suffix)) if start.is_empty() => {
+                if suffix == 0 || size == 0 {
+                    return ParcelRange::Unsatisfiable;
+                }
+                (size.saturating_sub(suffix), size - 1)
+            }
+            (Ok(start), Err(_)) if end.is_empty() => (start, u64::MAX),
+            (Ok(start), Ok(end)) if start <= end => (start, end),
+            _ => return ParcelRange::Full,
+        };
+        if start >= size {
+            return ParcelRange::Unsatisfiable;
+        }
+        ParcelRange::Partial(start, std::cmp::min(end, size - 1))
+    }
 
     /// Due to subpathed parcel support, we need to check what is in the tail of a GET request in order to route the request to the appropriate handler
     pub async fn request_router<P: Provider + Sync>(
@@ -23,7 +110,34 @@ pub mod v1 {
         query: InvoiceQuery,
         store: P,
         method: Method,
+        range: Option<String>,
+        options: ApiOptions,
     ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        if let Some((id, subresource)) = split_subresource(tail.as_str()) {
+            trace!(
+                "Matched bindle ID {} and subresource {}, routing to subresource handler",
+                id,
+                subresource
+            );
+            if method != Method::GET {
+                return Ok(Box::new(reply::reply_from_error(
+                    "Got invalid method",
+                    warp::http::StatusCode::METHOD_NOT_ALLOWED,
+                )));
+            }
+            return match subresource {
+                HISTORY_SUBRESOURCE => get_invoice_history(id, store).await,
+                SUMMARY_SUBRESOURCE => get_invoice_summary(id, store).await,
+                METADATA_SUBRESOURCE => get_invoice_metadata(id, store).await,
+                SELECTION_SUBRESOURCE => get_parcel_selection(id, query, store).await,
+                STATUS_SUBRESOURCE => get_processing_status(id, options.processing),
+                _ => Ok(Box::new(reply::reply_from_error(
+                    format!("Unknown invoice subresource {}", subresource),
+                    warp::http::StatusCode::NOT_FOUND,
+                ))),
+            };
+        }
+
         let split: Vec<&str> = tail.as_str().split(PARCEL_ID_SEPARATOR).collect();
 
         match split.len() {
@@ -48,8 +162,8 @@ pub mod v1 {
                     split[1]
                 );
                 match method {
-                    Method::HEAD => head_parcel(split[0], split[1], store).await,
-                    Method::GET => get_parcel(split[0], split[1], store).await,
+                    Method::HEAD => head_parcel(split[0], split[1], range, store, &options).await,
+                    Method::GET => get_parcel(split[0], split[1], range, store, &options).await,
                     _ => Ok(Box::new(reply::reply_from_error(
                         "Got invalid method",
                         warp::http::StatusCode::METHOD_NOT_ALLOWED,
@@ -64,41 +178,196 @@ pub mod v1 {
     }
 
     //////////// Invoice Functions ////////////
-    pub async fn query_invoices<S: Search>(
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub async fn query_invoices<S: Search + Clone + Send + Sync + 'static>(
         options: QueryOptions,
+        accept: Option<String>,
         index: S,
-    ) -> Result<impl warp::Reply, Infallible> {
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
         trace!("Query invoice request with options: {:?}", options);
         let term = options.query.clone().unwrap_or_default();
         let version = options.version.clone().unwrap_or_default();
-        let matches = match index.query(term, version, options.into()).await {
+        let matches = match index
+            .query(term.clone(), version.clone(), options.clone().into())
+            .await
+        {
             Ok(m) => m,
             Err(e) => {
                 trace!("Got bad query request: {:?}", e);
-                return Ok(reply::reply_from_error(
+                return Ok(Box::new(reply::reply_from_error(
                     e,
                     warp::http::StatusCode::BAD_REQUEST,
-                ));
+                )));
             }
         };
 
-        Ok(warp::reply::with_status(
+        if filters::accepts_ndjson(accept.as_deref()) {
+            let invoices = stream_matches(matches, options, term, version, index);
+            let res = warp::http::Response::builder()
+                .header(warp::http::header::CONTENT_TYPE, NDJSON_MIME_TYPE)
+                .body(hyper::Body::wrap_stream(invoices));
+            return Ok(match res {
+                Ok(r) => Box::new(r),
+                Err(e) => Box::new(reply::reply_from_error(
+                    e,
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                )),
+            });
+        }
+
+        Ok(Box::new(warp::reply::with_status(
             reply::toml(&matches),
             warp::http::StatusCode::OK,
-        ))
+        )))
+    }
+
+    /// Turns the first page of matches of a query into a stream of all matching invoices, one JSON
+    /// document per line. The limit of the query is used as the page size, and the following pages
+    /// are only fetched once the previous one is sent, so the whole result set is never held in
+    /// memory. As the index isn't locked between pages, invoices indexed or removed while streaming
+    /// may be skipped or sent twice. An error fetching a page aborts the response
+    fn stream_matches<S: Search + Clone + Send + Sync + 'static>(
+        first: crate::search::Matches,
+        options: QueryOptions,
+        term: String,
+        version: String,
+        index: S,
+    ) -> impl stream::Stream<Item = anyhow::Result<bytes::Bytes>> + Send + 'static {
+        use futures::TryStreamExt;
+
+        let pages = futures::stream::try_unfold(
+            (Some(first), None),
+            move |(page, offset): (Option<crate::search::Matches>, Option<u64>)| {
+                let (index, term, version) = (index.clone(), term.clone(), version.clone());
+                let mut options = options.clone();
+                async move {
+                    let page = match (page, offset) {
+                        (Some(page), _) => page,
+                        (None, Some(offset)) => {
+                            options.offset = Some(offset);
+                            index
+                                .query(term, version, options.into())
+                                .await
+                                .map_err(|e| {
+                                    warn!("Aborting streamed query: {}", e);
+                                    e
+                                })?
+                        }
+                        (None, None) => return Ok(None),
+                    };
+                    // An empty page can't move the offset, so it is the last one even if the
+                    // engine claims there is more
+                    let next = if page.more && !page.invoices.is_empty() {
+                        Some(page.offset + page.invoices.len() as u64)
+                    } else {
+                        None
+                    };
+                    Ok::<_, anyhow::Error>(Some((page.invoices, (None, next))))
+                }
+            },
+        );
+        pages
+            .map_ok(|invoices| {
+                stream::iter(invoices.into_iter().map(|inv| {
+                    let mut line = serde_json::to_vec(&inv)?;
+                    line.push(b'\n');
+                    Ok(bytes::Bytes::from(line))
+                }))
+            })
+            .try_flatten()
     }
 
-    pub async fn create_invoice<P: Provider>(
+    #[tracing::instrument(level = "debug", skip_all, fields(bindle = %inv.bindle.id))]
+    pub async fn create_invoice<P, Z>(
+        identity: Identity,
+        authorizer: Z,
         store: P,
-        inv: crate::Invoice,
-    ) -> Result<impl warp::Reply, Infallible> {
+        options: ApiOptions,
+        mut inv: crate::Invoice,
+    ) -> Result<impl warp::Reply, Infallible>
+    where
+        P: Provider + Clone + Send + Sync + 'static,
+        Z: Authorizer,
+    {
         trace!("Create invoice request with invoice: {:?}", inv);
-        let labels = match store.create_invoice(&inv).await {
+        if let Err(e) = authorize(&authorizer, &identity, inv.bindle.id.name(), Action::Create) {
+            return Ok(e);
+        }
+        let strategy = options.verification_strategy;
+        let verified = tracing::debug_span!("verify_invoice", %strategy)
+            .in_scope(|| strategy.verify(&inv, &options.keyring, &options.revocations));
+        if let Err(e) = verified {
+            debug!(
+                "Invoice {:?} failed verification with strategy {}: {}",
+                inv.bindle.id, strategy, e
+            );
+            return Ok(reply::verification_failure(strategy, e));
+        }
+        // The host signature vouches for the invoice as it was accepted, so it is added before the
+        // invoice is stored
+        if let Some(key) = options.signing_key {
+            trace!(
+                "Signing invoice {:?} as host with key {}",
+                inv.bindle.id,
+                key.label
+            );
+            if let Err(e) = inv.sign(SignatureRole::Host, &key.label, key.keypair()) {
+                return Ok(reply::reply_from_error(
+                    e,
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+        }
+        // Recording happens after signing, so the host signature is recorded as well
+        if let Some(log) = &options.transparency_log {
+            match inv.record_signatures(log.as_ref()).await {
+                Ok(recorded) => trace!(
+                    "Recorded {} signatures of invoice {:?} in {:?}",
+                    recorded,
+                    inv.bindle.id,
+                    log
+                ),
+                Err(e) => {
+                    debug!(
+                        "Unable to record signatures of invoice {:?}: {}",
+                        inv.bindle.id, e
+                    );
+                    return Ok(reply::reply_from_error(
+                        e,
+                        warp::http::StatusCode::BAD_GATEWAY,
+                    ));
+                }
+            }
+        }
+        // The policy is checked after the host signature is added, so policies can require it
+        let signing_policy = options.signing_policy.evaluate(&inv, &options.keyring);
+        if let Some(result) = &signing_policy {
+            if result.enforced && !result.satisfied {
+                debug!(
+                    "Invoice {:?} is missing signatures required by the signing policy: {:?}",
+                    inv.bindle.id, result.missing
+                );
+                return Ok(reply::signing_policy_failure(result.clone()));
+            }
+        }
+        // The bindle is reserved last, so nothing is counted for invoices rejected for another
+        // reason. The reservation is given back if the invoice can't be stored
+        let reservation = match reserve_quota(options.quotas.as_ref(), &identity, 0, 1) {
+            Ok(r) => r,
+            Err(e) => return Ok(e),
+        };
+        let labels = match store.create_invoice_by(&inv, identity.name.clone()).await {
             Ok(l) => l,
             Err(e) => {
                 return Ok(reply::into_reply(e));
             }
         };
+        if let Some(r) = reservation {
+            r.commit().await;
+        }
+        if let Some(pipeline) = &options.processing {
+            pipeline.invoice_created(&inv, labels.len(), store);
+        }
         // If there are missing parcels that still need to be created, return a 202 to indicate that
         // things were accepted, but will not be fetchable until further action is taken
         if !labels.is_empty() {
@@ -111,6 +380,7 @@ pub mod v1 {
                 reply::toml(&crate::InvoiceCreateResponse {
                     invoice: inv,
                     missing: Some(labels),
+                    signing_policy,
                 }),
                 warp::http::StatusCode::ACCEPTED,
             ))
@@ -123,12 +393,14 @@ pub mod v1 {
                 reply::toml(&crate::InvoiceCreateResponse {
                     invoice: inv,
                     missing: None,
+                    signing_policy,
                 }),
                 warp::http::StatusCode::CREATED,
             ))
         }
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(bindle = tail.as_str()))]
     pub async fn get_invoice<P: Provider + Sync>(
         tail: warp::path::Tail,
         query: InvoiceQuery,
@@ -152,19 +424,31 @@ pub mod v1 {
                 return Ok(Box::new(reply::into_reply(e)));
             }
         };
-        Ok(Box::new(warp::reply::with_status(
-            reply::toml(&inv),
-            warp::http::StatusCode::OK,
+        let etag = invoice_etag(&inv);
+        Ok(Box::new(warp::reply::with_header(
+            warp::reply::with_status(reply::toml(&inv), warp::http::StatusCode::OK),
+            warp::http::header::ETAG,
+            etag,
         )))
     }
 
-    pub async fn yank_invoice<P: Provider>(
+    #[tracing::instrument(level = "debug", skip_all, fields(bindle = tail.as_str()))]
+    pub async fn yank_invoice<P: Provider + Sync, Z: Authorizer>(
         tail: warp::path::Tail,
+        identity: Identity,
+        authorizer: Z,
+        query: DeleteQuery,
         store: P,
     ) -> Result<impl warp::Reply, Infallible> {
         let id = tail.as_str();
+        if query.purge.unwrap_or(false) {
+            return Ok(delete_invoice(id, identity, authorizer, store).await);
+        }
         trace!("Yank invoice request for {}", id);
-        if let Err(e) = store.yank_invoice(id).await {
+        if let Err(e) = authorize_id(&authorizer, &identity, id, Action::Yank) {
+            return Ok(e);
+        }
+        if let Err(e) = store.yank_invoice_by(id, identity.name).await {
             trace!("Got error during yank invoice request: {:?}", e);
             return Ok(reply::into_reply(e));
         }
@@ -177,6 +461,245 @@ pub mod v1 {
         ))
     }
 
+    /// Adds a signature to a stored invoice. The signature must be valid for the invoice as it is
+    /// stored, so an approver can't countersign different parcels than the creator did. Whether
+    /// the key is trusted is left to whoever verifies the invoice, as it is for the other
+    /// signatures. If a transparency log is configured, the signature is recorded in it before it
+    /// is added
+    pub async fn add_signature<P: Provider + Sync, Z: Authorizer>(
+        tail: warp::path::Tail,
+        identity: Identity,
+        authorizer: Z,
+        store: P,
+        transparency_log: Option<Arc<dyn TransparencyLog + Send + Sync>>,
+        mut signature: crate::signature::Signature,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = match split_subresource(tail.as_str()) {
+            Some((id, SIGNATURE_SUBRESOURCE)) => id,
+            _ => {
+                return Ok(reply::reply_from_error(
+                    "Signatures can only be added to the _signature subresource of an invoice",
+                    warp::http::StatusCode::NOT_FOUND,
+                ))
+            }
+        };
+        trace!("Add signature request for {} by {}", id, signature.by);
+        if let Err(e) = authorize_id(&authorizer, &identity, id, Action::Sign) {
+            return Ok(e);
+        }
+        if let Some(log) = transparency_log {
+            // The signature is checked against the stored invoice first, so only valid signatures
+            // end up in the log
+            let mut inv = match store.get_yanked_invoice(id).await {
+                Ok(inv) => inv,
+                Err(e) => return Ok(reply::into_reply(e)),
+            };
+            inv.signature = None;
+            signature.log_entry = None;
+            if let Err(e) = inv.add_signature(signature) {
+                return Ok(reply::reply_from_error(
+                    e,
+                    warp::http::StatusCode::BAD_REQUEST,
+                ));
+            }
+            if let Err(e) = inv.record_signatures(log.as_ref()).await {
+                debug!("Unable to record signature for invoice {}: {}", id, e);
+                return Ok(reply::reply_from_error(
+                    e,
+                    warp::http::StatusCode::BAD_GATEWAY,
+                ));
+            }
+            signature = inv.signature.unwrap_or_default().remove(0);
+        }
+        match store.add_signature_by(id, signature, identity.name).await {
+            Ok(inv) => Ok(warp::reply::with_status(
+                reply::toml(&inv),
+                warp::http::StatusCode::OK,
+            )),
+            Err(e) => {
+                debug!("Unable to add signature to invoice {}: {}", id, e);
+                Ok(reply::into_reply(e))
+            }
+        }
+    }
+
+    async fn delete_invoice<P: Provider + Sync, Z: Authorizer>(
+        id: &str,
+        identity: Identity,
+        authorizer: Z,
+        store: P,
+    ) -> warp::reply::WithStatus<reply::Toml> {
+        trace!("Delete invoice request for {}", id);
+        if let Err(e) = authorize_id(&authorizer, &identity, id, Action::Delete) {
+            return e;
+        }
+        match store.delete_invoice(id).await {
+            Ok(removed_parcels) => warp::reply::with_status(
+                reply::toml(&crate::InvoiceDeleteResponse { removed_parcels }),
+                warp::http::StatusCode::OK,
+            ),
+            Err(e) => {
+                trace!("Got error during delete invoice request: {:?}", e);
+                reply::into_reply(e)
+            }
+        }
+    }
+
+    pub async fn get_invoice_history<P: Provider + Sync>(
+        id: &str,
+        store: P,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Get invoice history request for {}", id);
+        let history = match store.get_invoice_history(id).await {
+            Ok(h) => h,
+            Err(e) => {
+                trace!("Got error during get invoice history request: {:?}", e);
+                return Ok(Box::new(reply::into_reply(e)));
+            }
+        };
+        Ok(Box::new(warp::reply::with_status(
+            reply::toml(&history),
+            warp::http::StatusCode::OK,
+        )))
+    }
+
+    /// Returns a summary of the invoice. Yanked invoices are summarized as well, as the summary
+    /// states whether the invoice is yanked
+    pub async fn get_invoice_summary<P: Provider + Sync>(
+        id: &str,
+        store: P,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Get invoice summary request for {}", id);
+        let inv = match store.get_yanked_invoice(id).await {
+            Ok(i) => i,
+            Err(e) => {
+                trace!("Got error during get invoice summary request: {:?}", e);
+                return Ok(Box::new(reply::into_reply(e)));
+            }
+        };
+        Ok(Box::new(warp::reply::with_status(
+            reply::toml(&crate::InvoiceSummary::from(&inv)),
+            warp::http::StatusCode::OK,
+        )))
+    }
+
+    pub async fn get_invoice_metadata<P: Provider + Sync>(
+        id: &str,
+        store: P,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Get invoice metadata request for {}", id);
+        let metadata = match store.get_metadata(id).await {
+            Ok(m) => m,
+            Err(e) => {
+                trace!("Got error during get invoice metadata request: {:?}", e);
+                return Ok(Box::new(reply::into_reply(e)));
+            }
+        };
+        Ok(Box::new(warp::reply::with_status(
+            reply::toml(&metadata),
+            warp::http::StatusCode::OK,
+        )))
+    }
+
+    /// Returns the status of the background processing of the bindle. Bindles that were created
+    /// before the server started, or while processing was disabled, have no status
+    pub fn get_processing_status(
+        id: &str,
+        processing: Option<Pipeline>,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Get processing status request for {}", id);
+        let pipeline = match processing {
+            Some(p) => p,
+            None => {
+                return Ok(Box::new(reply::reply_from_error(
+                    "Processing is not enabled on this server",
+                    warp::http::StatusCode::NOT_FOUND,
+                )))
+            }
+        };
+        let id: crate::Id = match id.parse() {
+            Ok(id) => id,
+            Err(e) => return Ok(Box::new(reply::into_reply(ProviderError::from(e)))),
+        };
+        match pipeline.status(&id) {
+            Some(status) => Ok(Box::new(warp::reply::with_status(
+                reply::toml(&status),
+                warp::http::StatusCode::OK,
+            ))),
+            None => Ok(Box::new(reply::into_reply(ProviderError::NotFound))),
+        }
+    }
+
+    /// Returns the labels of the parcels needed for the groups, features and preference given in
+    /// the query, as resolved by the [`resolution`](crate::filters::resolution) module
+    pub async fn get_parcel_selection<P: Provider + Sync>(
+        id: &str,
+        query: InvoiceQuery,
+        store: P,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!(
+            "Get parcel selection request for {} with groups {:?}, features {:?} and preference {:?}",
+            id,
+            query.groups,
+            query.features,
+            query.prefer
+        );
+        let groups: Vec<String> = split_list(query.groups.as_deref())
+            .map(str::to_owned)
+            .collect();
+        let features = match split_list(query.features.as_deref())
+            .map(str::parse)
+            .collect::<Result<Vec<FeatureSelector>, _>>()
+        {
+            Ok(f) => f,
+            Err(e) => {
+                return Ok(Box::new(reply::reply_from_error(
+                    e,
+                    warp::http::StatusCode::BAD_REQUEST,
+                )))
+            }
+        };
+        let preference = match query.prefer.as_deref().map(str::parse).transpose() {
+            Ok(p) => p.unwrap_or_default(),
+            Err(e) => {
+                return Ok(Box::new(reply::reply_from_error(
+                    e,
+                    warp::http::StatusCode::BAD_REQUEST,
+                )))
+            }
+        };
+
+        let res = if query.yanked.unwrap_or_default() {
+            store.get_yanked_invoice(id)
+        } else {
+            store.get_invoice(id)
+        };
+        let inv = match res.await {
+            Ok(i) => i,
+            Err(e) => {
+                trace!("Got error during get parcel selection request: {:?}", e);
+                return Ok(Box::new(reply::into_reply(e)));
+            }
+        };
+
+        match resolution::resolve_with_preference(&inv, &groups, &features, preference) {
+            Ok(labels) => Ok(Box::new(warp::reply::with_status(
+                reply::toml(&crate::ParcelSelectionResponse { labels }),
+                warp::http::StatusCode::OK,
+            ))),
+            Err(e) => {
+                debug!("Unable to select parcels of {}: {}", id, e);
+                let status = match e {
+                    ResolutionError::UnknownGroup(_)
+                    | ResolutionError::InvalidFeature(_)
+                    | ResolutionError::InvalidPreference(_) => warp::http::StatusCode::BAD_REQUEST,
+                    _ => warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+                };
+                Ok(Box::new(reply::reply_from_error(e, status)))
+            }
+        }
+    }
+
     pub async fn head_invoice<P: Provider + Sync>(
         tail: warp::path::Tail,
         query: InvoiceQuery,
@@ -195,13 +718,19 @@ pub mod v1 {
 
     //////////// Parcel Functions ////////////
 
-    pub async fn create_parcel<P, B, D>(
+    #[tracing::instrument(level = "debug", skip_all, fields(parcel = tail.as_str()))]
+    pub async fn create_parcel<P, Z, B, D>(
         tail: warp::path::Tail,
+        identity: Identity,
+        authorizer: Z,
+        if_match: Option<String>,
         body: B,
         store: P,
+        options: ApiOptions,
     ) -> Result<impl warp::Reply, Infallible>
     where
-        P: Provider + Sync,
+        P: Provider + Clone + Send + Sync + 'static,
+        Z: Authorizer,
         B: stream::Stream<Item = Result<D, warp::Error>> + Send + Sync + Unpin + 'static,
         D: bytes::Buf,
     {
@@ -219,36 +748,299 @@ pub mod v1 {
 
         trace!("Got SHA {} and bindle id {}", sha, bindle_id);
 
+        if let Err(e) = authorize_id(&authorizer, &identity, bindle_id, Action::Create) {
+            return Ok(e);
+        }
+
+        if let Err(e) = check_if_match(&store, bindle_id, if_match.as_deref()).await {
+            return Ok(e);
+        }
+
         // Validate that this sha belongs
-        if let Err(e) = parcel_in_bindle(&store, bindle_id, sha).await {
+        let label = match parcel_in_bindle(&store, bindle_id, sha).await {
+            Ok(l) => l,
+            Err(e) => return Ok(e),
+        };
+        let reservation = match reserve_quota(options.quotas.as_ref(), &identity, label.size, 0) {
+            Ok(r) => r,
+            Err(e) => return Ok(e),
+        };
+
+        // The data is hashed and counted as it is passed to the provider, so data that doesn't
+        // match the label is never completely written, no matter which provider is used
+        let metrics = options.metrics;
+        let body = crate::async_util::VerifyingStream::new(
+            body.map(move |res| {
+                if let (Ok(data), Some(m)) = (&res, &metrics) {
+                    m.record_upload(data.remaining() as u64);
+                }
+                res.map_err(|e| std::io::Error::other(e.to_string()))
+            }),
+            sha,
+            Some(label.size),
+        );
+        let failure = body.failure();
+        let res = store.create_parcel(bindle_id, sha, body).await;
+        // A failed verification shows up as an IO error from the provider, so return the real reason
+        if let Some(m) = failure.lock().unwrap().take() {
+            debug!("Rejecting data for parcel {}: {}", sha, m);
+            let err = match m {
+                crate::async_util::Mismatch::Size { expected, actual } => {
+                    ProviderError::SizeMismatch {
+                        sha256: sha.to_owned(),
+                        expected,
+                        actual,
+                    }
+                }
+                m => m.into(),
+            };
+            return Ok(reply::into_reply(err));
+        }
+        if let Err(e) = res {
+            return Ok(reply::into_reply(e));
+        }
+        if let Some(r) = reservation {
+            r.commit().await;
+        }
+        if let Some(pipeline) = options.processing {
+            pipeline.parcel_created(bindle_id, store);
+        }
+
+        let mut resp = std::collections::HashMap::new();
+        resp.insert("message", "parcel created");
+        Ok(warp::reply::with_status(
+            reply::toml(&resp),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
+    //////////// Upload Functions ////////////
+
+    pub async fn start_upload<P: Provider + Sync, Z: Authorizer>(
+        tail: warp::path::Tail,
+        identity: Identity,
+        authorizer: Z,
+        if_match: Option<String>,
+        uploads: UploadStore,
+        store: P,
+        quotas: Option<Quotas>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let split: Vec<&str> = tail.as_str().split(PARCEL_ID_SEPARATOR).collect();
+        if split.len() != 2 {
+            return Ok(reply::reply_from_error(
+                format!("Unable to parse parcel SHA from request. The SHA should be separated from the bindle ID by a single '{}' character", PARCEL_ID_SEPARATOR),
+                warp::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+        let (bindle_id, sha) = (split[0], split[1]);
+        trace!("Start upload request for parcel {} in {}", sha, bindle_id);
+
+        if let Err(e) = authorize_id(&authorizer, &identity, bindle_id, Action::Create) {
+            return Ok(e);
+        }
+        if let Err(e) = check_if_match(&store, bindle_id, if_match.as_deref()).await {
+            return Ok(e);
+        }
+        let label = match parcel_in_bindle(&store, bindle_id, sha).await {
+            Ok(l) => l,
+            Err(e) => return Ok(e),
+        };
+        match store.parcel_exists(bindle_id, sha).await {
+            Ok(false) => (),
+            Ok(true) => return Ok(reply::into_reply(ProviderError::Exists)),
+            Err(e) => return Ok(reply::into_reply(e)),
+        }
+        // The parcel is only counted once the upload completes, but there is no point in sending
+        // data that would exceed a quota
+        if let Err(e) = reserve_quota(quotas.as_ref(), &identity, label.size, 0) {
             return Ok(e);
         }
 
+        match uploads.start(bindle_id, &label, if_match).await {
+            Ok(status) => Ok(warp::reply::with_status(
+                reply::toml(&status),
+                warp::http::StatusCode::CREATED,
+            )),
+            Err(e) => Ok(reply::into_reply(e.into())),
+        }
+    }
+
+    pub async fn get_upload(
+        id: String,
+        uploads: UploadStore,
+    ) -> Result<impl warp::Reply, Infallible> {
+        trace!("Get upload request for {}", id);
+        match uploads.get(&id).await {
+            Ok(Some((session, offset))) => Ok(warp::reply::with_status(
+                reply::toml(&uploads::status(&session, offset)),
+                warp::http::StatusCode::OK,
+            )),
+            Ok(None) => Ok(reply::into_reply(ProviderError::NotFound)),
+            Err(e) => Ok(reply::into_reply(e.into())),
+        }
+    }
+
+    // The upload ID and offset are passed together, as they describe where the data goes
+    #[tracing::instrument(level = "debug", skip_all, fields(upload = %id, offset))]
+    pub async fn append_upload<P, Z, B, D>(
+        (id, offset): (String, u64),
+        identity: Identity,
+        authorizer: Z,
+        body: B,
+        uploads: UploadStore,
+        store: P,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible>
+    where
+        P: Provider + Clone + Send + Sync + 'static,
+        Z: Authorizer,
+        B: stream::Stream<Item = Result<D, warp::Error>> + Send + Sync + Unpin + 'static,
+        D: bytes::Buf,
+    {
+        trace!("Append upload request for {} at offset {}", id, offset);
+        let session = match uploads.get(&id).await {
+            Ok(Some((session, _))) => session,
+            Ok(None) => return Ok(reply::into_reply(ProviderError::NotFound)),
+            Err(e) => return Ok(reply::into_reply(e.into())),
+        };
+        if let Err(e) = authorize_id(&authorizer, &identity, &session.bindle_id, Action::Create) {
+            return Ok(e);
+        }
+        let _active = match uploads.activate(&id) {
+            Some(a) => a,
+            None => {
+                return Ok(reply::reply_from_error(
+                    "Upload is already receiving data",
+                    warp::http::StatusCode::CONFLICT,
+                ))
+            }
+        };
+
+        let offset = match uploads.append(&session, offset, body).await {
+            Ok(o) => {
+                if let Some(m) = &options.metrics {
+                    m.record_upload(o - offset);
+                }
+                o
+            }
+            Err(AppendError::WrongOffset(current)) => {
+                return Ok(reply::reply_from_error(
+                    format!(
+                        "Data must start at the current offset of the upload, which is {}",
+                        current
+                    ),
+                    warp::http::StatusCode::CONFLICT,
+                ))
+            }
+            Err(AppendError::TooLarge) => {
+                return Ok(reply::reply_from_error(
+                    format!("Parcel is only {} bytes", session.size),
+                    warp::http::StatusCode::PAYLOAD_TOO_LARGE,
+                ))
+            }
+            Err(AppendError::Io(e)) => return Ok(reply::into_reply(e.into())),
+        };
+        if offset < session.size {
+            return Ok(warp::reply::with_status(
+                reply::toml(&uploads::status(&session, offset)),
+                warp::http::StatusCode::OK,
+            ));
+        }
+
+        // All data is here, so verify it before it goes anywhere near the provider. A mismatch
+        // means the data is wrong somewhere, so the upload can't be resumed
+        trace!("Upload {} complete, verifying data", id);
+        match uploads.digest(&session).await {
+            Ok(actual) if actual == session.sha256 => (),
+            Ok(actual) => {
+                if let Err(e) = uploads.remove(&id).await {
+                    warn!("Unable to remove failed upload {}: {}", id, e);
+                }
+                return Ok(reply::into_reply(ProviderError::DigestMismatch {
+                    expected: session.sha256.clone(),
+                    actual,
+                }));
+            }
+            Err(e) => return Ok(reply::into_reply(e.into())),
+        }
+        // The invoice could have changed while the data was being sent, so check the condition
+        // the upload was started with again
+        if let Err(e) =
+            check_if_match(&store, &session.bindle_id, session.if_match.as_deref()).await
+        {
+            if let Err(e) = uploads.remove(&id).await {
+                warn!("Unable to remove failed upload {}: {}", id, e);
+            }
+            return Ok(e);
+        }
+        // Other parcels may have used up the quota while the data was being sent. The upload is
+        // kept, so it can be completed once there is room again
+        let reservation = match reserve_quota(options.quotas.as_ref(), &identity, session.size, 0) {
+            Ok(r) => r,
+            Err(e) => return Ok(e),
+        };
+        let data = match uploads.open(&session).await {
+            Ok(f) => FramedRead::new(f, BytesCodec::new()),
+            Err(e) => return Ok(reply::into_reply(e.into())),
+        };
         if let Err(e) = store
-            .create_parcel(
-                bindle_id,
-                sha,
-                body.map(|res| {
-                    res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
-                }),
-            )
+            .create_parcel(session.bindle_id.as_str(), &session.sha256, data)
             .await
         {
             return Ok(reply::into_reply(e));
         }
+        if let Some(r) = reservation {
+            r.commit().await;
+        }
+        if let Some(pipeline) = options.processing {
+            pipeline.parcel_created(&session.bindle_id, store);
+        }
+        if let Err(e) = uploads.remove(&id).await {
+            warn!("Unable to remove completed upload {}: {}", id, e);
+        }
+        Ok(warp::reply::with_status(
+            reply::toml(&uploads::status(&session, offset)),
+            warp::http::StatusCode::OK,
+        ))
+    }
 
-        let mut resp = std::collections::HashMap::new();
-        resp.insert("message", "parcel created");
+    pub async fn cancel_upload<Z: Authorizer>(
+        id: String,
+        identity: Identity,
+        authorizer: Z,
+        uploads: UploadStore,
+    ) -> Result<impl warp::Reply, Infallible> {
+        trace!("Cancel upload request for {}", id);
+        let session = match uploads.get(&id).await {
+            Ok(Some((session, _))) => session,
+            Ok(None) => return Ok(reply::into_reply(ProviderError::NotFound)),
+            Err(e) => return Ok(reply::into_reply(e.into())),
+        };
+        if let Err(e) = authorize_id(&authorizer, &identity, &session.bindle_id, Action::Create) {
+            return Ok(e);
+        }
+        if uploads.activate(&id).is_none() {
+            return Ok(reply::reply_from_error(
+                "Upload is receiving data",
+                warp::http::StatusCode::CONFLICT,
+            ));
+        }
+        if let Err(e) = uploads.remove(&id).await {
+            return Ok(reply::into_reply(e.into()));
+        }
         Ok(warp::reply::with_status(
-            reply::toml(&resp),
+            reply::toml(&uploads::status(&session, 0)),
             warp::http::StatusCode::OK,
         ))
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(bindle = bindle_id, sha = id))]
     pub async fn get_parcel<P: Provider + Sync>(
         bindle_id: &str,
         id: &str,
+        range: Option<String>,
         store: P,
+        options: &ApiOptions,
     ) -> Result<Box<dyn warp::Reply>, Infallible> {
         trace!("Get parcel request for {}", id);
         // Get parcel label to ascertain content type and length, and validate that it does exist
@@ -256,6 +1048,56 @@ pub mod v1 {
             Ok(l) => l,
             Err(e) => return Ok(Box::new(e)),
         };
+        let disposition = options.disposition_policy.header_for(&label);
+
+        let (start, end) = match parse_range(range.as_deref(), label.size) {
+            ParcelRange::Full => (0, None),
+            ParcelRange::Partial(start, end) => (start, Some(end)),
+            ParcelRange::Unsatisfiable => {
+                return Ok(Box::new(warp::reply::with_header(
+                    reply::reply_from_error(
+                        format!(
+                            "Range is not satisfiable for a parcel of {} bytes",
+                            label.size
+                        ),
+                        warp::http::StatusCode::RANGE_NOT_SATISFIABLE,
+                    ),
+                    warp::http::header::CONTENT_RANGE,
+                    format!("bytes */{}", label.size),
+                )))
+            }
+        };
+
+        if let Some(end) = end {
+            trace!("Getting bytes {}-{} of parcel {}", start, end, id);
+            let data = match store
+                .get_parcel_range(bindle_id, id, start, Some(end - start + 1))
+                .await
+            {
+                Ok(reader) => reader,
+                Err(e) => {
+                    return Ok(Box::new(reply::into_reply(e)));
+                }
+            };
+            let mut builder = warp::http::Response::builder()
+                .header(warp::http::header::CONTENT_TYPE, label.media_type)
+                .header(warp::http::header::CONTENT_LENGTH, end - start + 1)
+                .header(
+                    warp::http::header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, label.size),
+                )
+                .header(warp::http::header::ACCEPT_RANGES, "bytes");
+            if let Some(d) = disposition {
+                builder = builder.header(warp::http::header::CONTENT_DISPOSITION, d);
+            }
+            let resp = builder
+                .body(hyper::Body::wrap_stream(count_download(data, options)))
+                .unwrap();
+            return Ok(Box::new(warp::reply::with_status(
+                resp,
+                warp::http::StatusCode::PARTIAL_CONTENT,
+            )));
+        }
 
         let data = match store.get_parcel(bindle_id, id).await {
             Ok(reader) => reader,
@@ -267,10 +1109,15 @@ pub mod v1 {
         // TODO: If we start to use compression on the body, we'll need a new custom header for
         // _actual_ size of the parcel, so the client can reconstruct the label data from headers
         // without needing to read the whole (possibly large) file
-        let resp = warp::http::Response::builder()
+        let mut builder = warp::http::Response::builder()
             .header(warp::http::header::CONTENT_TYPE, label.media_type)
             .header(warp::http::header::CONTENT_LENGTH, label.size)
-            .body(hyper::Body::wrap_stream(data))
+            .header(warp::http::header::ACCEPT_RANGES, "bytes");
+        if let Some(d) = disposition {
+            builder = builder.header(warp::http::header::CONTENT_DISPOSITION, d);
+        }
+        let resp = builder
+            .body(hyper::Body::wrap_stream(count_download(data, options)))
             .unwrap();
 
         // Gotta box because this is not a toml reply type (which we use for sending error messages to the user)
@@ -283,10 +1130,12 @@ pub mod v1 {
     pub async fn head_parcel<P: Provider + Sync>(
         bindle_id: &str,
         id: &str,
+        range: Option<String>,
         store: P,
+        options: &ApiOptions,
     ) -> Result<Box<dyn warp::Reply>, Infallible> {
         trace!("Head parcel request for {}", id);
-        let inv = get_parcel(bindle_id, id, store).await?;
+        let inv = get_parcel(bindle_id, id, range, store, options).await?;
 
         // Consume the response to we can take the headers
         let (parts, _) = inv.into_response().into_parts();
@@ -298,6 +1147,102 @@ pub mod v1 {
 
     //////////// Relationship Functions ////////////
 
+    pub async fn get_delta<P: Provider + Sync>(
+        tail: warp::path::Tail,
+        query: DeltaQuery,
+        store: P,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = tail.as_str();
+        trace!("Get parcel delta request from {} to {}", query.from, id);
+
+        // It is fine to update away from a yanked bindle, but not to one
+        let from = match store.get_yanked_invoice(query.from.as_str()).await {
+            Ok(i) => i,
+            Err(e) => {
+                trace!("Got error fetching the old invoice for delta: {:?}", e);
+                return Ok(reply::into_reply(e));
+            }
+        };
+        let to = match store.get_invoice(id).await {
+            Ok(i) => i,
+            Err(e) => {
+                trace!("Got error fetching the new invoice for delta: {:?}", e);
+                return Ok(reply::into_reply(e));
+            }
+        };
+
+        Ok(warp::reply::with_status(
+            reply::toml(&crate::ParcelDelta::between(&from, &to)),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(bindle = tail.as_str()))]
+    pub async fn resolve_dependencies<P: Provider + Sync, S: Search + Sync>(
+        tail: warp::path::Tail,
+        store: P,
+        index: S,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = tail.as_str();
+        trace!("Resolve dependencies request for {}", id);
+
+        let inv = match store.get_invoice(id).await {
+            Ok(i) => i,
+            Err(e) => {
+                trace!("Got error during resolve dependencies request: {:?}", e);
+                return Ok(reply::into_reply(e));
+            }
+        };
+        let resolved = match crate::search::dependencies::resolve(&index, &inv).await {
+            Ok(r) => r,
+            Err(e) => {
+                trace!("Unable to resolve dependencies of {}: {:?}", id, e);
+                let status = match e {
+                    ResolveError::Search(_) => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+                };
+                return Ok(reply::reply_from_error(e, status));
+            }
+        };
+
+        Ok(warp::reply::with_status(
+            reply::toml(&crate::DependenciesResponse {
+                resolved: resolved.into_iter().map(|i| i.bindle.id).collect(),
+            }),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
+    /// Lists all known versions of the bindle name in the tail, as returned by
+    /// [`Search::versions`](crate::search::Search::versions)
+    pub async fn list_versions<S: Search + Sync>(
+        tail: warp::path::Tail,
+        index: S,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let name = tail.as_str();
+        trace!("List versions request for {}", name);
+
+        let versions = match index.versions(name).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Unable to list versions of {}: {:?}", name, e);
+                return Ok(reply::reply_from_error(
+                    e,
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+        };
+        if versions.is_empty() {
+            return Ok(reply::into_reply(ProviderError::NotFound));
+        }
+
+        Ok(warp::reply::with_status(
+            reply::toml(&crate::VersionsResponse { versions }),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(bindle = tail.as_str()))]
     pub async fn get_missing<P: Provider + Sync + Clone>(
         tail: warp::path::Tail,
         store: P,
@@ -351,10 +1296,428 @@ pub mod v1 {
         ))
     }
 
+    /// Returns which of the requested SHAs aren't stored for any bindle, so clients can skip
+    /// uploading parcels another bindle already brought along
+    pub async fn get_missing_shas<P: Provider + Sync>(
+        store: P,
+        req: crate::MissingShasRequest,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Missing SHAs request for {} parcels", req.shas.len());
+        // The SHAs end up in storage paths, so anything that isn't one is rejected up front
+        if let Some(invalid) = req.shas.iter().find(|sha| !is_sha256(sha)) {
+            return Ok(Box::new(reply::reply_from_error(
+                format!("{:?} is not a valid parcel SHA", invalid),
+                warp::http::StatusCode::BAD_REQUEST,
+            )));
+        }
+        match store.missing_shas(&req.shas).await {
+            Ok(missing) => Ok(Box::new(reply::toml(&crate::MissingShasResponse {
+                missing,
+            }))),
+            Err(e) => {
+                trace!("Got error during missing SHAs request: {:?}", e);
+                Ok(Box::new(reply::into_reply(e)))
+            }
+        }
+    }
+
+    /// Lists the invoices containing the parcel, including yanked ones. A parcel that no invoice
+    /// contains gets an empty list rather than a 404, as that is the answer to the question
+    pub async fn find_invoices_by_parcel<S: Search + Sync>(
+        sha: String,
+        index: S,
+    ) -> Result<impl warp::Reply, Infallible> {
+        trace!("Find invoices request for parcel {}", sha);
+        if !is_sha256(&sha) {
+            return Ok(reply::reply_from_error(
+                format!("{:?} is not a valid parcel SHA", sha),
+                warp::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+        let found = match index.find_by_parcel(&sha).await {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Unable to find invoices of parcel {}: {:?}", sha, e);
+                return Ok(reply::reply_from_error(
+                    e,
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+        };
+        let invoices = found
+            .into_iter()
+            .filter_map(|inv| {
+                let name = inv
+                    .parcel
+                    .iter()
+                    .flatten()
+                    .find(|p| p.label.sha256 == sha)?
+                    .label
+                    .name
+                    .clone();
+                Some(crate::ParcelInvoice {
+                    yanked: inv.yanked.unwrap_or_default(),
+                    id: inv.bindle.id,
+                    name,
+                })
+            })
+            .collect();
+        Ok(warp::reply::with_status(
+            reply::toml(&crate::ParcelInvoicesResponse { invoices }),
+            warp::http::StatusCode::OK,
+        ))
+    }
+
+    //////////// Keyring Functions ////////////
+
+    pub(crate) async fn get_keyring(
+        identity: Identity,
+        keyrings: Option<KeyRingStore>,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Get keyring request for {:?}", identity.name);
+        let (name, keyrings) = match keyring_owner(identity, keyrings) {
+            Ok(v) => v,
+            Err(e) => return Ok(Box::new(e)),
+        };
+        match keyrings.get(&name).await {
+            Ok(Some(data)) => Ok(Box::new(warp::reply::with_header(
+                data,
+                warp::http::header::CONTENT_TYPE,
+                "application/octet-stream",
+            ))),
+            Ok(None) => Ok(Box::new(reply::reply_from_error(
+                "No keyring has been stored",
+                warp::http::StatusCode::NOT_FOUND,
+            ))),
+            Err(e) => {
+                warn!("Unable to read keyring for {}: {}", name, e);
+                Ok(Box::new(reply::reply_from_error(
+                    "Unable to read keyring",
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                )))
+            }
+        }
+    }
+
+    pub(crate) async fn put_keyring(
+        identity: Identity,
+        keyrings: Option<KeyRingStore>,
+        data: bytes::Bytes,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Put keyring request for {:?}", identity.name);
+        let (name, keyrings) = match keyring_owner(identity, keyrings) {
+            Ok(v) => v,
+            Err(e) => return Ok(Box::new(e)),
+        };
+        if let Err(e) = keyrings.put(&name, &data).await {
+            warn!("Unable to store keyring for {}: {}", name, e);
+            return Ok(Box::new(reply::reply_from_error(
+                "Unable to store keyring",
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            )));
+        }
+        Ok(Box::new(warp::http::StatusCode::NO_CONTENT))
+    }
+
+    /// Returns the name of the identity a keyring belongs to along with the store, or an error
+    /// reply if keyring storage is disabled or the request is anonymous
+    fn keyring_owner(
+        identity: Identity,
+        keyrings: Option<KeyRingStore>,
+    ) -> std::result::Result<(String, KeyRingStore), warp::reply::WithStatus<reply::Toml>> {
+        let keyrings = keyrings.ok_or_else(|| {
+            reply::reply_from_error(
+                "Keyring storage is not enabled on this server",
+                warp::http::StatusCode::NOT_IMPLEMENTED,
+            )
+        })?;
+        let name = identity.name.ok_or_else(|| {
+            reply::reply_from_error(
+                "Keyrings are stored per user, so authentication is required",
+                warp::http::StatusCode::UNAUTHORIZED,
+            )
+        })?;
+        Ok((name, keyrings))
+    }
+
+    //////////// Capability Functions ////////////
+
+    pub async fn collect_garbage<P: Provider + Sync, Z: Authorizer>(
+        identity: Identity,
+        authorizer: Z,
+        query: GcQuery,
+        store: P,
+    ) -> Result<impl warp::Reply, Infallible> {
+        trace!("Collect garbage request");
+        if let Err(e) = authorize(&authorizer, &identity, "*", Action::CollectGarbage) {
+            return Ok(e);
+        }
+        match store.collect_garbage(query.dry_run.unwrap_or(false)).await {
+            Ok(report) => Ok(warp::reply::with_status(
+                reply::toml(&report),
+                warp::http::StatusCode::OK,
+            )),
+            Err(e) => {
+                trace!("Got error during collect garbage request: {:?}", e);
+                Ok(reply::into_reply(e))
+            }
+        }
+    }
+
+    #[cfg(feature = "client")]
+    pub async fn get_replication_status<Z: Authorizer>(
+        identity: Identity,
+        authorizer: Z,
+        replication: Option<crate::replication::ReplicationHandle>,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Get replication status request");
+        if let Err(e) = authorize(&authorizer, &identity, "*", Action::ViewReplication) {
+            return Ok(Box::new(e));
+        }
+        match replication {
+            Some(replication) => Ok(Box::new(reply::toml(&replication.status()))),
+            None => Ok(Box::new(replication_disabled())),
+        }
+    }
+
+    #[cfg(feature = "client")]
+    pub async fn notify_replication(
+        replication: Option<crate::replication::ReplicationHandle>,
+        signature: Option<String>,
+        body: bytes::Bytes,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        let replication = match replication {
+            Some(r) => r,
+            None => return Ok(Box::new(replication_disabled())),
+        };
+        if !replication.verify_event(&body, signature.as_deref()) {
+            debug!("Rejecting replication notification with a missing or invalid signature");
+            return Ok(Box::new(reply::reply_from_error(
+                "Events must be signed with the event secret of the replica",
+                warp::http::StatusCode::UNAUTHORIZED,
+            )));
+        }
+        let event: crate::events::Event = match serde_json::from_slice(&body) {
+            Ok(e) => e,
+            Err(e) => {
+                return Ok(Box::new(reply::reply_from_error(
+                    format!("Invalid event: {}", e),
+                    warp::http::StatusCode::BAD_REQUEST,
+                )))
+            }
+        };
+        trace!(
+            "Replication notification for {} ({})",
+            event.bindle_id(),
+            event.name()
+        );
+        if !replication.notify(event.bindle_id()) {
+            warn!(
+                "Dropping replication notification for {}, too many bindles are pending",
+                event.bindle_id()
+            );
+            // Hooks retry failed deliveries, by which time the replicator may have caught up
+            return Ok(Box::new(reply::reply_from_error(
+                "Too many bindles are waiting to be replicated",
+                warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            )));
+        }
+        Ok(Box::new(warp::reply::with_status(
+            warp::reply(),
+            warp::http::StatusCode::ACCEPTED,
+        )))
+    }
+
+    pub async fn get_capabilities<A: Authenticator>(
+        authenticator: A,
+    ) -> Result<impl warp::Reply, Infallible> {
+        trace!("Get capabilities request");
+        Ok(reply::toml(&crate::Capabilities {
+            version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            spec_version: Some(crate::BINDLE_VERSION_1.to_owned()),
+            resumable_uploads: true,
+            range_requests: true,
+            parcel_delta: true,
+            content_types: vec![TOML_MIME_TYPE.to_owned(), JSON_MIME_TYPE.to_owned()],
+            auth_methods: authenticator.schemes(),
+            anonymous_read: authenticator.allow_anonymous(Access::Read),
+            anonymous_write: authenticator.allow_anonymous(Access::Write),
+            ..crate::Capabilities::default()
+        }))
+    }
+
+    pub async fn get_metrics<Z: Authorizer>(
+        identity: Identity,
+        authorizer: Z,
+        metrics: Option<Metrics>,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Get metrics request");
+        if let Err(e) = authorize(&authorizer, &identity, "*", Action::ViewMetrics) {
+            return Ok(Box::new(e));
+        }
+        match metrics {
+            Some(metrics) => Ok(Box::new(warp::reply::with_header(
+                metrics.render().await,
+                warp::http::header::CONTENT_TYPE,
+                crate::server::metrics::CONTENT_TYPE,
+            ))),
+            None => Ok(Box::new(reply::reply_from_error(
+                "Metrics are not enabled on this server",
+                warp::http::StatusCode::NOT_IMPLEMENTED,
+            ))),
+        }
+    }
+
+    pub async fn get_quotas<Z: Authorizer>(
+        identity: Identity,
+        authorizer: Z,
+        quotas: Option<Quotas>,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        trace!("Get quotas request");
+        if let Err(e) = authorize(&authorizer, &identity, "*", Action::ViewQuotas) {
+            return Ok(Box::new(e));
+        }
+        match quotas {
+            Some(quotas) => Ok(Box::new(reply::toml(&quotas.report()))),
+            None => Ok(Box::new(reply::reply_from_error(
+                "Quotas are not enabled on this server",
+                warp::http::StatusCode::NOT_IMPLEMENTED,
+            ))),
+        }
+    }
+
     //////////// Helper Functions ////////////
 
+    /// Counts the parcel data sent to the client as it is streamed, if metrics are enabled. Data
+    /// that is never sent, like for HEAD requests, isn't counted
+    fn count_download(
+        data: Box<
+            dyn stream::Stream<Item = crate::provider::Result<bytes::Bytes>> + Unpin + Send + Sync,
+        >,
+        options: &ApiOptions,
+    ) -> impl stream::Stream<Item = crate::provider::Result<bytes::Bytes>> {
+        let metrics = options.metrics.clone();
+        data.map(move |res| {
+            if let (Ok(chunk), Some(m)) = (&res, &metrics) {
+                m.record_download(chunk.len() as u64);
+            }
+            res
+        })
+    }
+
+    #[cfg(feature = "client")]
+    fn replication_disabled() -> warp::reply::WithStatus<reply::Toml> {
+        reply::reply_from_error(
+            "This server does not replicate another server",
+            warp::http::StatusCode::NOT_IMPLEMENTED,
+        )
+    }
+
+    /// Checks that the identity is allowed to perform the action on the named bindle. Returns a
+    /// result where the Error variant is a warp reply containing the error. Anonymous requests get
+    /// a 401 so clients know they should authenticate, while everyone else gets a 403
+    fn authorize<Z: Authorizer>(
+        authorizer: &Z,
+        identity: &Identity,
+        bindle_name: &str,
+        action: Action,
+    ) -> std::result::Result<(), warp::reply::WithStatus<reply::Toml>> {
+        authorizer
+            .authorize(identity, bindle_name, action)
+            .map_err(|e| {
+                trace!("Denied request: {}", e);
+                let status = if identity.is_anonymous() {
+                    warp::http::StatusCode::UNAUTHORIZED
+                } else {
+                    warp::http::StatusCode::FORBIDDEN
+                };
+                reply::reply_from_error(e, status)
+            })
+    }
+
+    /// Reserves the given number of bytes and bindles for the identity if the server has quotas.
+    /// Returns the reply for the quota that would be exceeded otherwise
+    fn reserve_quota(
+        quotas: Option<&Quotas>,
+        identity: &Identity,
+        bytes: u64,
+        bindles: u64,
+    ) -> std::result::Result<Option<Reservation>, warp::reply::WithStatus<reply::Toml>> {
+        match quotas.map(|q| q.reserve(identity, bytes, bindles)) {
+            Some(Ok(r)) => Ok(Some(r)),
+            Some(Err(e)) => {
+                debug!("Request would exceed a quota: {:?}", e);
+                Err(reply::quota_exceeded(e))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Same as [`authorize`](authorize), but takes a full bindle ID
+    fn authorize_id<Z: Authorizer>(
+        authorizer: &Z,
+        identity: &Identity,
+        bindle_id: &str,
+        action: Action,
+    ) -> std::result::Result<(), warp::reply::WithStatus<reply::Toml>> {
+        let id: crate::Id = bindle_id
+            .parse()
+            .map_err(|e: crate::id::ParseError| reply::into_reply(e.into()))?;
+        authorize(authorizer, identity, id.name(), action)
+    }
+
     /// Fetches an invoice from the given store and checks that the given SHA exists within that
     /// invoice. Returns a result where the Error variant is a warp reply containing the error
+    /// Returns the entity tag of an invoice, which is the SHA-256 sum of the invoice as stored. Any
+    /// change to the invoice, including yanking it, results in a new tag
+    fn invoice_etag(inv: &crate::Invoice) -> String {
+        use sha2::{Digest, Sha256};
+        // Invoices always serialize, as they are sent as TOML in the first place
+        let raw = toml::to_vec(inv).unwrap_or_default();
+        format!("\"{:x}\"", Sha256::digest(&raw))
+    }
+
+    /// Checks an `If-Match` header against the current state of the invoice, returning a
+    /// `412 Precondition Failed` response if none of the given tags match. Tags only match the
+    /// invoice exactly (weak tags never match), while `*` matches any invoice that exists, yanked or
+    /// not. If no header was sent, there is nothing to check
+    async fn check_if_match<P: Provider + Sync>(
+        store: &P,
+        bindle_id: &str,
+        if_match: Option<&str>,
+    ) -> std::result::Result<(), warp::reply::WithStatus<crate::server::reply::Toml>> {
+        let if_match = match if_match {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        let etag = match store.get_yanked_invoice(bindle_id).await {
+            Ok(inv) => Some(invoice_etag(&inv)),
+            Err(ProviderError::NotFound) => None,
+            Err(e) => return Err(reply::into_reply(e)),
+        };
+        if let Some(etag) = etag {
+            if if_match
+                .split(',')
+                .map(|t| t.trim())
+                .any(|t| t == "*" || t == etag)
+            {
+                return Ok(());
+            }
+        }
+        trace!(
+            "If-Match precondition {} failed for {}",
+            if_match,
+            bindle_id
+        );
+        Err(reply::reply_from_error(
+            format!(
+                "Invoice {} has changed or no longer exists, so it does not match {}",
+                bindle_id, if_match
+            ),
+            warp::http::StatusCode::PRECONDITION_FAILED,
+        ))
+    }
+
     async fn parcel_in_bindle<P: Provider + Sync>(
         store: &P,
         bindle_id: &str,
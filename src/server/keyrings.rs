@@ -0,0 +1,49 @@
+//! Storage for the personal keyrings of users, so they can keep the same trusted keys across
+//! machines.
+//!
+//! Keyrings are encrypted by the client before they are uploaded, so the server only ever stores
+//! opaque blobs. Each blob is stored in a file named after the SHA-256 of the identity it belongs
+//! to, which keeps arbitrary user names from escaping the directory
+
+use std::path::PathBuf;
+
+use log::debug;
+use sha2::{Digest, Sha256};
+
+/// A handle to the directory keyrings are stored in
+#[derive(Debug, Clone)]
+pub(crate) struct KeyRingStore {
+    dir: PathBuf,
+}
+
+impl KeyRingStore {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> Self {
+        KeyRingStore { dir: dir.into() }
+    }
+
+    /// Returns the keyring stored for the given identity, or `None` if there isn't one
+    pub(crate) async fn get(&self, identity: &str) -> std::io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(identity)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stores the keyring for the given identity, replacing any existing one
+    pub(crate) async fn put(&self, identity: &str, data: &[u8]) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.path(identity);
+        // Write to a temporary file first so a failed write doesn't destroy the existing keyring
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        debug!("Stored keyring for {} ({} bytes)", identity, data.len());
+        Ok(())
+    }
+
+    fn path(&self, identity: &str) -> PathBuf {
+        self.dir
+            .join(format!("{:x}.keyring", Sha256::digest(identity.as_bytes())))
+    }
+}
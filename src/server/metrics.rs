@@ -0,0 +1,369 @@
+//! Metrics for monitoring a server with [Prometheus](https://prometheus.io).
+//!
+//! A [`Metrics`](Metrics) registry counts the requests to each API operation by status along with
+//! how long they took, and the parcel data uploaded and downloaded. Everything else is collected
+//! from [`MetricsSource`](MetricsSource)s when the metrics are scraped, such as the size of a
+//! [`FileProvider`](crate::provider::file::FileProvider), the invoices in a
+//! [`StrictEngine`](crate::search::StrictEngine) or the usage of an
+//! [`LruCache`](crate::cache::LruCache). Setting [`ApiOptions::metrics`](super::ApiOptions::metrics)
+//! records the requests to the API and serves the metrics at `/metrics` in the Prometheus text
+//! format
+//!
+//! The duration of a request is measured until its response starts, so it doesn't include
+//! streaming the data of a parcel
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use warp::http::Method;
+
+/// The upper bounds in seconds of the buckets that request durations are sorted into
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The content type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Something that adds its own metrics when the metrics are scraped
+#[async_trait::async_trait]
+pub trait MetricsSource {
+    /// Writes the current values of the metrics
+    async fn collect(&self, out: &mut MetricsWriter);
+}
+
+/// Collects the metrics of a server. Clones share the same metrics
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    operations: Mutex<BTreeMap<&'static str, OperationStats>>,
+    uploaded_bytes: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    sources: RwLock<Vec<Arc<dyn MetricsSource + Send + Sync>>>,
+}
+
+#[derive(Default)]
+struct OperationStats {
+    statuses: BTreeMap<u16, u64>,
+    /// The number of requests in each duration bucket, not including the ones in smaller buckets
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    seconds: f64,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics")
+            .field("sources", &self.inner.sources.read().unwrap().len())
+            .finish()
+    }
+}
+
+impl Metrics {
+    /// Adds a source whose metrics are included every time the metrics are scraped
+    pub fn register(&self, source: impl MetricsSource + Send + Sync + 'static) {
+        self.inner.sources.write().unwrap().push(Arc::new(source));
+    }
+
+    /// Records a request to the API, which is done for all requests if
+    /// [`ApiOptions::metrics`](super::ApiOptions::metrics) is set
+    pub(crate) fn record(&self, info: &warp::log::Info) {
+        self.record_request(
+            info.method(),
+            info.path(),
+            info.status().as_u16(),
+            info.elapsed(),
+        )
+    }
+
+    /// Counts parcel data received from a client
+    pub(crate) fn record_upload(&self, bytes: u64) {
+        self.inner
+            .uploaded_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts parcel data sent to a client
+    pub(crate) fn record_download(&self, bytes: u64) {
+        self.inner
+            .downloaded_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn record_request(&self, method: &Method, path: &str, status: u16, elapsed: Duration) {
+        let (operation, _) = super::monitor::operation(method, path);
+        let seconds = elapsed.as_secs_f64();
+        let mut operations = self.inner.operations.lock().unwrap();
+        let stats = operations.entry(operation).or_default();
+        *stats.statuses.entry(status).or_default() += 1;
+        if let Some(i) = DURATION_BUCKETS.iter().position(|le| seconds <= *le) {
+            stats.buckets[i] += 1;
+        }
+        stats.count += 1;
+        stats.seconds += seconds;
+    }
+
+    /// Returns all metrics in the Prometheus text format
+    pub async fn render(&self) -> String {
+        let mut out = MetricsWriter::default();
+        self.write_requests(&mut out);
+        out.counter(
+            "bindle_parcel_uploaded_bytes_total",
+            "Parcel data received from clients in bytes",
+            self.inner.uploaded_bytes.load(Ordering::Relaxed),
+        );
+        out.counter(
+            "bindle_parcel_downloaded_bytes_total",
+            "Parcel data sent to clients in bytes",
+            self.inner.downloaded_bytes.load(Ordering::Relaxed),
+        );
+        // The lock can't be held across awaits
+        let sources = self.inner.sources.read().unwrap().clone();
+        for source in sources {
+            source.collect(&mut out).await;
+        }
+        out.finish()
+    }
+
+    fn write_requests(&self, out: &mut MetricsWriter) {
+        let operations = self.inner.operations.lock().unwrap();
+        out.header(
+            "bindle_http_requests_total",
+            "counter",
+            "Requests by API operation and response status",
+        );
+        for (operation, stats) in operations.iter() {
+            for (status, count) in &stats.statuses {
+                out.sample(
+                    "bindle_http_requests_total",
+                    &[("operation", operation), ("status", &status.to_string())],
+                    *count,
+                );
+            }
+        }
+        out.header(
+            "bindle_http_request_duration_seconds",
+            "histogram",
+            "Time until the response to a request started by API operation",
+        );
+        for (operation, stats) in operations.iter() {
+            let mut cumulative = 0;
+            for (le, count) in DURATION_BUCKETS.iter().zip(stats.buckets.iter()) {
+                cumulative += count;
+                out.sample(
+                    "bindle_http_request_duration_seconds_bucket",
+                    &[("operation", operation), ("le", &le.to_string())],
+                    cumulative,
+                );
+            }
+            out.sample(
+                "bindle_http_request_duration_seconds_bucket",
+                &[("operation", operation), ("le", "+Inf")],
+                stats.count,
+            );
+            out.sample(
+                "bindle_http_request_duration_seconds_sum",
+                &[("operation", operation)],
+                stats.seconds,
+            );
+            out.sample(
+                "bindle_http_request_duration_seconds_count",
+                &[("operation", operation)],
+                stats.count,
+            );
+        }
+    }
+}
+
+/// Writes metrics in the Prometheus text format
+#[derive(Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    /// Writes a counter, which only ever goes up
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "counter", help);
+        self.sample(name, &[], value);
+    }
+
+    /// Writes a gauge, which can go up and down
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        // Writing to a string can't fail
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(feature = "provider-file")]
+#[async_trait::async_trait]
+impl<T: crate::search::Search + Send + Sync> MetricsSource
+    for crate::provider::file::FileProvider<T>
+{
+    async fn collect(&self, out: &mut MetricsWriter) {
+        let stats = match self.storage_stats().await {
+            Ok(stats) => stats,
+            // Leaving the metrics out makes the failure visible without reporting wrong values
+            Err(e) => {
+                log::warn!("Unable to collect storage metrics: {}", e);
+                return;
+            }
+        };
+        out.gauge(
+            "bindle_storage_invoices",
+            "Stored invoices, including yanked ones",
+            stats.invoices,
+        );
+        out.gauge("bindle_storage_parcels", "Stored parcels", stats.parcels);
+        out.gauge(
+            "bindle_storage_parcel_bytes",
+            "Size of the stored parcel data in bytes",
+            stats.parcel_bytes,
+        );
+    }
+}
+
+#[cfg(feature = "search-strict")]
+#[async_trait::async_trait]
+impl MetricsSource for crate::search::StrictEngine {
+    async fn collect(&self, out: &mut MetricsWriter) {
+        let stats = self.stats().await;
+        out.gauge(
+            "bindle_search_index_invoices",
+            "Indexed invoices, including yanked ones",
+            stats.invoices,
+        );
+        out.gauge(
+            "bindle_search_index_yanked_invoices",
+            "Indexed invoices that are yanked",
+            stats.yanked,
+        );
+    }
+}
+
+#[cfg(feature = "caching")]
+#[async_trait::async_trait]
+impl<Local, Remote> MetricsSource for crate::cache::LruCache<Local, Remote>
+where
+    Local: crate::provider::Provider + Clone + Send + Sync,
+    Remote: crate::provider::Provider + Clone + Send + Sync,
+{
+    async fn collect(&self, out: &mut MetricsWriter) {
+        let metrics = self.metrics();
+        out.counter(
+            "bindle_cache_hits_total",
+            "Invoices and parcels served from the cache",
+            metrics.hits,
+        );
+        out.counter(
+            "bindle_cache_misses_total",
+            "Invoices and parcels fetched from the remote provider",
+            metrics.misses,
+        );
+        out.counter(
+            "bindle_cache_evictions_total",
+            "Invoices evicted from the cache along with their parcels",
+            metrics.evictions,
+        );
+        out.gauge(
+            "bindle_cache_invoices",
+            "Invoices tracked by the cache",
+            metrics.invoices,
+        );
+        out.gauge(
+            "bindle_cache_parcel_bytes",
+            "Size of the parcels tracked by the cache in bytes",
+            metrics.parcel_bytes,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::search::Search;
+
+    #[tokio::test]
+    async fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_request(&Method::GET, "/v1/_q", 200, Duration::from_millis(20));
+        metrics.record_request(&Method::GET, "/v1/_q", 200, Duration::from_secs(20));
+        metrics.record_request(&Method::GET, "/v1/_i/foo/1.0.0", 404, Duration::default());
+        metrics.record_upload(10);
+        metrics.record_download(5);
+        metrics.record_download(5);
+        let index = crate::search::StrictEngine::default();
+        metrics.register(index.clone());
+
+        let rendered = metrics.render().await;
+        let lines: Vec<&str> = rendered.lines().collect();
+        for expected in &[
+            "# TYPE bindle_http_requests_total counter",
+            r#"bindle_http_requests_total{operation="query",status="200"} 2"#,
+            r#"bindle_http_requests_total{operation="get_invoice",status="404"} 1"#,
+            "# TYPE bindle_http_request_duration_seconds histogram",
+            r#"bindle_http_request_duration_seconds_bucket{operation="query",le="0.01"} 0"#,
+            r#"bindle_http_request_duration_seconds_bucket{operation="query",le="0.025"} 1"#,
+            r#"bindle_http_request_duration_seconds_bucket{operation="query",le="10"} 1"#,
+            r#"bindle_http_request_duration_seconds_bucket{operation="query",le="+Inf"} 2"#,
+            r#"bindle_http_request_duration_seconds_count{operation="query"} 2"#,
+            "bindle_parcel_uploaded_bytes_total 10",
+            "bindle_parcel_downloaded_bytes_total 10",
+            "bindle_search_index_invoices 0",
+        ] {
+            assert!(
+                lines.contains(expected),
+                "Missing {} in:\n{}",
+                expected,
+                rendered
+            );
+        }
+
+        // Sources are collected on every scrape
+        index
+            .index(&crate::provider::test_common::invoice_fixture())
+            .await
+            .unwrap();
+        assert!(metrics
+            .render()
+            .await
+            .lines()
+            .any(|l| l == "bindle_search_index_invoices 1"));
+    }
+}
@@ -2,63 +2,296 @@
 //! Spec](https://github.com/deislabs/bindle/blob/master/docs/protocol-spec.md), with associated
 //! HTTP handlers and functions
 
+pub mod auth;
+pub mod authz;
+pub mod config;
+pub mod crawlers;
+pub mod disposition;
+mod embedded;
+pub mod events;
 mod filters;
 mod handlers;
+mod keyrings;
+pub mod metrics;
+pub mod monitor;
+pub mod processing;
+pub mod quotas;
 mod reply;
 
 mod routes;
-
+pub mod signing_policy;
+pub mod tenancy;
+#[cfg(feature = "server-tls")]
+mod tls;
+mod uploads;
+
+pub use config::ServerConfig;
+pub use crawlers::CrawlerPolicy;
+pub use disposition::DispositionPolicy;
+pub use embedded::{start_in_process, InProcessOptions, Server, ServerHandle, ShutdownHandle};
+pub use metrics::{Metrics, MetricsSource};
+pub use monitor::{RequestMonitor, RequestThresholds};
+pub use quotas::{QuotaPolicy, Quotas};
+pub use signing_policy::SigningPolicy;
+
+use std::convert::Infallible;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{Either, FutureExt};
+use log::{info, warn};
+use warp::Filter;
 
 use super::provider::Provider;
 use crate::search::Search;
+use crate::signature::{KeyRing, RevocationList, SecretKeyEntry, VerificationStrategy};
+use auth::Authenticator;
+use authz::Authorizer;
 
 pub(crate) const TOML_MIME_TYPE: &str = "application/toml";
+pub(crate) const JSON_MIME_TYPE: &str = "application/json";
+/// Newline delimited JSON, which query results can be streamed as
+pub(crate) const NDJSON_MIME_TYPE: &str = "application/x-ndjson";
+
+/// How long in flight requests, such as parcel uploads, are given to complete once a server is
+/// shutting down before their connections are closed
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// The configuration required for running with TLS enabled
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TlsConfig {
+    /// The path to the PEM encoded certificate (chain) the server presents to clients
     pub cert_path: PathBuf,
+    /// The path to the PEM encoded private key for the certificate, either in PKCS8 or RSA format
     pub key_path: PathBuf,
+    /// The path to one or more PEM encoded CA certificates. If set, clients are required to present
+    /// a certificate signed by one of these CAs (mutual TLS)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// Optional features of the API that need extra configuration
+#[derive(Debug, Clone, Default)]
+pub struct ApiOptions {
+    /// A key used to sign every newly created invoice in the `host` role. If not set, invoices are
+    /// stored as they were uploaded
+    pub signing_key: Option<Arc<SecretKeyEntry>>,
+    /// The directory the personal keyrings of authenticated users are stored in. If not set,
+    /// keyring storage is disabled
+    pub keyring_dir: Option<PathBuf>,
+    /// How the signatures of newly created invoices are checked. Invoices that fail are rejected.
+    /// Defaults to not checking signatures
+    pub verification_strategy: VerificationStrategy,
+    /// The keys trusted when verifying signatures
+    pub keyring: Arc<KeyRing>,
+    /// The keys whose signatures are rejected when verifying newly created invoices, from the time
+    /// they were revoked. Only checked with a verification strategy other than `None`
+    pub revocations: Arc<RevocationList>,
+    /// The signatures required for newly created invoices in each namespace, checked against the
+    /// keyring. Defaults to no requirements
+    pub signing_policy: Arc<SigningPolicy>,
+    /// Which parcels browsers are told to display or download, based on their media type.
+    /// Defaults to not sending a `Content-Disposition` header
+    pub disposition_policy: Arc<DispositionPolicy>,
+    /// The `robots.txt` served at `/robots.txt` and how often crawlers may read from the API.
+    /// Defaults to serving no `robots.txt` and not throttling any clients
+    pub crawler_policy: Arc<CrawlerPolicy>,
+    /// The metrics requests to the API are recorded in. If set, admins can scrape them from
+    /// `/metrics`
+    pub metrics: Option<Metrics>,
+    /// The pipeline new bindles are processed by in the background once they are complete. If set,
+    /// clients can follow the processing at `/_i/{id}/_status`
+    pub processing: Option<processing::Pipeline>,
+    /// The transparency log the signatures of newly created invoices are recorded in, after the
+    /// host signature is added. Invoices are rejected if their signatures can't be recorded. If not
+    /// set, signatures are not recorded
+    pub transparency_log: Option<Arc<dyn crate::transparency::TransparencyLog + Send + Sync>>,
+    /// The storage quotas of the server and its users. If set, requests that would exceed them are
+    /// rejected and admins can inspect the usage at `/_quota`. Defaults to no quotas
+    pub quotas: Option<Quotas>,
+    /// The replication of this server from a primary server. If set, admins can check its status
+    /// and the primary can notify it of changes. Only available with the `client` feature enabled
+    #[cfg(feature = "client")]
+    pub replication: Option<crate::replication::ReplicationHandle>,
 }
 
-/// Returns a future that runs a server until it receives a SIGINT to stop. If optional TLS
-/// configuration is given, the server will be configured to use TLS. Otherwise it will use plain
-/// HTTP
-pub async fn server<P, I>(
+/// Returns a future that runs a server until it receives a SIGINT to stop. The address,
+/// authentication, authorization, TLS, request monitoring and optional API features are configured
+/// with the given [`InProcessOptions`](InProcessOptions), using the same defaults as in-process
+/// servers. Use [`NoopAuthenticator`](auth::NoopAuthenticator) to disable authentication. TLS
+/// fails if the crate was built without the `server-tls` feature. To control the lifecycle of the
+/// server yourself, use the [`Server`](Server) builder instead
+pub async fn server<P, I, A, Z>(
     store: P,
     index: I,
-    addr: impl Into<SocketAddr> + 'static,
-    tls: Option<TlsConfig>,
+    options: InProcessOptions<A, Z>,
 ) -> anyhow::Result<()>
 where
     P: Provider + Clone + Send + Sync + 'static,
     I: Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
 {
     // V1 API paths, currently the only version
-    let api = routes::api(store, index);
+    let api = routes::api_with_options(
+        store,
+        index,
+        options.authenticator,
+        options.authorizer,
+        options.api,
+    )
+    .with(options.monitor.filter())
+    .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+    .boxed();
+    serve(api, options.address, options.tls, options.drain_timeout).await
+}
 
+/// Serves the API until the server receives a SIGINT, with TLS if it is configured. The API is
+/// boxed so the single and multi-tenant servers can share this
+async fn serve(
+    api: warp::filters::BoxedFilter<(Box<dyn warp::Reply>,)>,
+    addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    drain_timeout: Duration,
+) -> anyhow::Result<()> {
     let server = warp::serve(api);
-    match tls {
-        None => {
+    let signal = shutdown_signal().shared();
+    let fut: Pin<Box<dyn Future<Output = ()> + Send>> = match tls {
+        None => Box::pin(
             server
-                .try_bind_with_graceful_shutdown(addr, shutdown_signal())?
-                .1
-                .await
-        }
+                .try_bind_with_graceful_shutdown(addr, signal.clone())?
+                .1,
+        ),
+        #[cfg(feature = "server-tls")]
         Some(config) => {
-            server
-                .tls()
-                .key_path(config.key_path)
-                .cert_path(config.cert_path)
-                .bind_with_graceful_shutdown(addr, shutdown_signal())
-                .1
-                .await
+            let (_, incoming) = tls::bind(addr, &config)?;
+            Box::pin(server.serve_incoming_with_graceful_shutdown(incoming, signal.clone()))
         }
+        #[cfg(not(feature = "server-tls"))]
+        Some(_) => anyhow::bail!("TLS support requires the server-tls feature"),
     };
+    drain(fut, signal, drain_timeout).await;
     Ok(())
 }
 
+/// Runs a server that was started with a graceful shutdown signal until it stopped. Once the
+/// signal fires, the server stops accepting connections and in flight requests, such as parcel
+/// uploads, are given the timeout to complete before the remaining connections are closed
+async fn drain(
+    server: Pin<Box<dyn Future<Output = ()> + Send>>,
+    signal: impl Future<Output = ()> + Send,
+    timeout: Duration,
+) {
+    let deadline = Box::pin(async move {
+        signal.await;
+        info!("Shutting down, waiting for in flight requests to complete");
+        tokio::time::delay_for(timeout).await
+    });
+    if let Either::Right(_) = futures::future::select(server, deadline).await {
+        warn!(
+            "In flight requests did not complete within {:?}, closing their connections",
+            timeout
+        );
+    }
+}
+
+/// Returns the complete Bindle API as a warp filter that only matches requests under the given
+/// path prefix (e.g. `/registry` serves the API at `/registry/v1/...`). An empty prefix or `/`
+/// mounts the API at the root. This can be combined with the other filters of an existing warp
+/// application to embed a Bindle server into it
+///
+/// ```no_run
+/// use warp::Filter;
+/// # #[tokio::main]
+/// # async fn main() {
+/// # let (store, index) = bindle::testing::setup().await;
+/// let health = warp::path("healthz").map(|| "OK");
+/// let bindle = bindle::server::api_at(
+///     "/registry",
+///     store,
+///     index,
+///     bindle::server::auth::NoopAuthenticator,
+///     bindle::server::authz::AllowAll,
+/// );
+/// warp::serve(health.or(bindle)).run(([127, 0, 0, 1], 8080)).await;
+/// # }
+/// ```
+pub fn api_at<P, I, A, Z>(
+    prefix: &str,
+    store: P,
+    index: I,
+    authenticator: A,
+    authorizer: Z,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+{
+    // The number of segments is only known at runtime, so the prefix filter has to be boxed
+    let prefix = prefix
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_owned())).boxed()
+        });
+    prefix.and(routes::api(store, index, authenticator, authorizer))
+}
+
+/// Returns the complete Bindle API mounted at the given path prefix (see [`api_at`](api_at)) as a
+/// `tower::Service`. This can be used with any server or framework built on top of hyper that
+/// accepts tower services, such as hyper's own `Server`:
+///
+/// ```no_run
+/// use std::convert::Infallible;
+/// # #[tokio::main]
+/// # async fn main() {
+/// # let (store, index) = bindle::testing::setup().await;
+/// let svc = bindle::server::service(
+///     "/registry",
+///     store,
+///     index,
+///     bindle::server::auth::NoopAuthenticator,
+///     bindle::server::authz::AllowAll,
+/// );
+/// let make_svc = hyper::service::make_service_fn(move |_| {
+///     let svc = svc.clone();
+///     async move { Ok::<_, Infallible>(svc) }
+/// });
+/// hyper::Server::bind(&([127, 0, 0, 1], 8080).into())
+///     .serve(make_svc)
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+pub fn service<P, I, A, Z>(
+    prefix: &str,
+    store: P,
+    index: I,
+    authenticator: A,
+    authorizer: Z,
+) -> impl hyper::service::Service<
+    hyper::Request<hyper::Body>,
+    Response = hyper::Response<hyper::Body>,
+    Error = Infallible,
+    Future = impl std::future::Future<Output = Result<hyper::Response<hyper::Body>, Infallible>> + Send,
+> + Clone
+       + Send
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+{
+    warp::service(api_at(prefix, store, index, authenticator, authorizer))
+}
+
 async fn shutdown_signal() {
     // Wait for the CTRL+C signal
     tokio::signal::ctrl_c()
@@ -81,7 +314,12 @@ mod test {
         let bindles = testing::load_all_files().await;
         let (store, index) = testing::setup().await;
 
-        let api = super::routes::api(store, index);
+        let api = super::routes::api(
+            store,
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
 
         // Now that we can't upload parcels before invoices exist, we need to create a bindle that shares some parcels
 
@@ -236,7 +474,12 @@ mod test {
     async fn test_yank() {
         let (store, index) = testing::setup().await;
 
-        let api = super::routes::api(store.clone(), index);
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
         // Insert an invoice
         let scaffold = testing::Scaffold::load("incomplete").await;
         store
@@ -284,6 +527,399 @@ mod test {
         toml::from_slice::<crate::Invoice>(res.body()).expect("should be valid invoice TOML");
     }
 
+    #[tokio::test]
+    async fn test_history() {
+        let (store, index) = testing::setup().await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        let scaffold = testing::Scaffold::load("incomplete").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Should be able to insert invoice");
+
+        let inv_path = format!("/v1/_i/{}", scaffold.invoice.name());
+        let res = warp::test::request()
+            .method("DELETE")
+            .path(&inv_path)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // The yank should be recorded in the history
+        let res = warp::test::request()
+            .path(&format!("{}/_history", inv_path))
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let history: crate::InvoiceHistory =
+            toml::from_slice(res.body()).expect("should be valid history TOML");
+        assert_eq!(
+            history
+                .event
+                .last()
+                .expect("history should not be empty")
+                .action,
+            crate::HistoryAction::Yank
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_identity() {
+        let (store, index) = testing::setup().await;
+        let mut users = std::collections::HashMap::new();
+        users.insert(
+            "admin".to_owned(),
+            bcrypt::hash("sw0rdf1sh", 4).expect("unable to hash password"),
+        );
+        let credentials = format!("Basic {}", base64::encode("admin:sw0rdf1sh"));
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::BasicAuthenticator::new(users),
+            super::authz::AllowAll,
+        );
+        let scaffold = testing::Scaffold::load("incomplete").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Should be able to insert invoice");
+
+        let inv_path = format!("/v1/_i/{}", scaffold.invoice.name());
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &credentials)
+            .path(&inv_path)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // The yank should be attributed to the authenticated user
+        let history = store
+            .get_invoice_history(&scaffold.invoice.bindle.id)
+            .await
+            .expect("history should be loaded");
+        let yank = history.event.last().expect("history should not be empty");
+        assert_eq!(crate::HistoryAction::Yank, yank.action);
+        assert_eq!(Some("admin"), yank.by.as_deref());
+    }
+
+    #[tokio::test]
+    async fn test_mount_at_prefix() {
+        let (store, index) = testing::setup().await;
+        let api = super::api_at(
+            "/registry/bindle/",
+            store.clone(),
+            index.clone(),
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let res = warp::test::request()
+            .path("/registry/bindle/v1/_q")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let res = warp::test::request().path("/v1/_q").reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+
+        // The service should behave the same
+        let mut svc = super::service(
+            "registry",
+            store,
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        futures::future::poll_fn(|cx| hyper::service::Service::poll_ready(&mut svc, cx))
+            .await
+            .expect("service should be ready");
+        let req = hyper::Request::get("/registry/v1/_q")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let res = hyper::service::Service::call(&mut svc, req)
+            .await
+            .expect("request should not fail");
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_start_in_process() {
+        let (store, index) = testing::setup().await;
+        let scaffold = Scaffold::load("valid_v1").await;
+        let handle = super::start_in_process(store, index, Default::default())
+            .expect("should be able to start server");
+        assert_ne!(0, handle.addr().port());
+
+        let client = crate::client::Client::new(&handle.base_url()).unwrap();
+        client
+            .create_invoice(scaffold.invoice.clone())
+            .await
+            .expect("should be able to create invoice");
+        client
+            .get_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .expect("should be able to fetch invoice");
+
+        handle.shutdown().await;
+        assert!(client
+            .get_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_builder() {
+        let (store, index) = testing::setup().await;
+        let scaffold = Scaffold::load("valid_v1").await;
+        let (server, shutdown) = super::Server::new(store, index)
+            .address(([127, 0, 0, 1], 0))
+            .authorizer(super::authz::AllowAll)
+            .options(super::ApiOptions::default())
+            .build()
+            .expect("should be able to build server");
+        assert_ne!(0, shutdown.addr().port());
+        let running = tokio::spawn(server);
+
+        let client = crate::client::Client::new(&shutdown.base_url()).unwrap();
+        client
+            .create_invoice(scaffold.invoice.clone())
+            .await
+            .expect("should be able to create invoice");
+
+        shutdown.shutdown();
+        running.await.expect("server should shut down cleanly");
+        assert!(client
+            .get_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_and_flushes() {
+        use tokio::io::AsyncWriteExt;
+
+        let dir = tempfile::tempdir().expect("unable to create tempdir");
+        let snapshot = dir.path().join("index-snapshot.json");
+        let index = crate::search::StrictEngine::default().with_snapshot(&snapshot);
+        let store =
+            crate::provider::file::FileProvider::new(dir.path().join("store"), index.clone()).await;
+        let scaffold = Scaffold::load("valid_v1").await;
+        let parcel = &scaffold.parcel_files["parcel"];
+        let (server, shutdown) = super::Server::new(store, index)
+            .drain_timeout(std::time::Duration::from_millis(200))
+            .build()
+            .expect("should be able to build server");
+        let addr = shutdown.addr();
+        let running = tokio::spawn(server);
+
+        let client = crate::client::Client::new(&shutdown.base_url()).unwrap();
+        client
+            .create_invoice(scaffold.invoice.clone())
+            .await
+            .expect("should be able to create invoice");
+
+        // An upload that never sends all of its data is still in flight when shutting down
+        let mut conn = tokio::net::TcpStream::connect(addr)
+            .await
+            .expect("should be able to connect");
+        let request = format!(
+            "POST /v1/_i/{}@{} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\nabc",
+            scaffold.invoice.bindle.id,
+            parcel.sha,
+            addr,
+            parcel.data.len()
+        );
+        conn.write_all(request.as_bytes()).await.unwrap();
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+
+        shutdown.shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(5), running)
+            .await
+            .expect("server should stop once the drain timeout passed")
+            .expect("server should shut down cleanly");
+        assert!(
+            snapshot.exists(),
+            "the index should be flushed to a snapshot"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let bindles = testing::load_all_files().await;
+        let (store, index) = testing::setup().await;
+        let mut users = std::collections::HashMap::new();
+        users.insert(
+            "admin".to_owned(),
+            bcrypt::hash("sw0rdf1sh", 4).expect("unable to hash password"),
+        );
+        let authenticator = super::auth::BasicAuthenticator::new(users);
+        let credentials = format!("Basic {}", base64::encode("admin:sw0rdf1sh"));
+
+        let api = super::routes::api(
+            store.clone(),
+            index.clone(),
+            authenticator.clone(),
+            super::authz::AllowAll,
+        );
+        let valid_v1 = bindles.get("valid_v1").expect("Missing scaffold");
+        let create = || {
+            warp::test::request()
+                .method("POST")
+                .header("Content-Type", "application/toml")
+                .path("/v1/_i")
+                .body(&valid_v1.invoice)
+        };
+
+        // Writes should be rejected without valid credentials
+        let res = create().reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::UNAUTHORIZED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert!(res
+            .headers()
+            .contains_key(warp::http::header::WWW_AUTHENTICATE));
+        let res = create()
+            .header(
+                "Authorization",
+                format!("Basic {}", base64::encode("admin:wrong")),
+            )
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::UNAUTHORIZED);
+
+        let res = create()
+            .header("Authorization", &credentials)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Reads are anonymous unless they are protected
+        let inv: crate::Invoice = toml::from_slice(&valid_v1.invoice).unwrap();
+        let inv_path = format!("/v1/_i/{}", inv.bindle.id);
+        let res = warp::test::request().path(&inv_path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+
+        let api = super::routes::api(
+            store,
+            index,
+            authenticator.protect_reads(),
+            super::authz::AllowAll,
+        );
+        let res = warp::test::request().path(&inv_path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::UNAUTHORIZED);
+        let res = warp::test::request()
+            .path(&inv_path)
+            .header("Authorization", &credentials)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_authz() {
+        let bindles = testing::load_all_files().await;
+        let (store, index) = testing::setup().await;
+        let mut users = std::collections::HashMap::new();
+        users.insert(
+            "ci".to_owned(),
+            bcrypt::hash("sw0rdf1sh", 4).expect("unable to hash password"),
+        );
+        let authenticator = super::auth::BasicAuthenticator::new(users);
+        let credentials = format!("Basic {}", base64::encode("ci:sw0rdf1sh"));
+        let policy = |bindles: &str| super::authz::RolePolicy {
+            grant: vec![super::authz::Grant {
+                identity: "ci".to_owned(),
+                bindles: bindles.to_owned(),
+                role: super::authz::Role::Creator,
+            }],
+        };
+
+        let valid_v1 = bindles.get("valid_v1").expect("Missing scaffold");
+        let create = || {
+            warp::test::request()
+                .method("POST")
+                .header("Content-Type", "application/toml")
+                .header("Authorization", &credentials)
+                .path("/v1/_i")
+                .body(&valid_v1.invoice)
+        };
+
+        // Creating a bindle outside of the granted prefix should be forbidden
+        let api = super::routes::api(
+            store.clone(),
+            index.clone(),
+            authenticator.clone(),
+            policy("mycompany/*"),
+        );
+        let res = create().reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::FORBIDDEN,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let api = super::routes::api(store, index, authenticator, policy("enterprise.com/*"));
+        let res = create().reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Creators can't yank bindles
+        let inv: crate::Invoice = toml::from_slice(&valid_v1.invoice).unwrap();
+        let inv_path = format!("/v1/_i/{}", inv.bindle.id);
+        let res = warp::test::request()
+            .method("DELETE")
+            .header("Authorization", &credentials)
+            .path(&inv_path)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::FORBIDDEN);
+
+        // Reads are still allowed for everyone
+        let res = warp::test::request().path(&inv_path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+    }
+
     #[tokio::test]
     // This isn't meant to test all of the possible validation failures (that should be done in a unit
     // test for storage), just the main validation failures from the API
@@ -291,7 +927,12 @@ mod test {
         let bindles = testing::load_all_files().await;
         let (store, index) = testing::setup().await;
 
-        let api = super::routes::api(store.clone(), index);
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
         let valid_raw = bindles.get("valid_v1").expect("Missing scaffold");
         let valid = testing::Scaffold::from(valid_raw.clone());
         store
@@ -321,7 +962,12 @@ mod test {
     async fn test_parcel_validation() {
         let (store, index) = testing::setup().await;
 
-        let api = super::routes::api(store.clone(), index);
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
         // Insert a parcel
         let scaffold = testing::Scaffold::load("valid_v1").await;
         let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
@@ -393,7 +1039,12 @@ mod test {
         // Insert data into store
         let (store, index) = testing::setup().await;
 
-        let api = super::routes::api(store.clone(), index);
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
         let bindles_to_insert = vec!["incomplete", "valid_v1", "valid_v2"];
 
         for b in bindles_to_insert.into_iter() {
@@ -484,62 +1135,2219 @@ mod test {
     }
 
     #[tokio::test]
-    async fn test_missing() {
+    async fn test_streamed_queries() {
         let (store, index) = testing::setup().await;
 
-        let api = super::routes::api(store.clone(), index);
-
-        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
-        store
-            .create_invoice(&scaffold.invoice)
-            .await
-            .expect("Unable to load in invoice");
-        let parcel = scaffold
-            .parcel_files
-            .get("parcel")
-            .expect("parcel doesn't exist");
-        let parcel_data = std::io::Cursor::new(parcel.data.clone());
-        store
-            .create_parcel(
-                &scaffold.invoice.bindle.id,
-                &parcel.sha,
-                FramedRead::new(parcel_data, BytesCodec::default()),
-            )
-            .await
-            .expect("Unable to create parcel");
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        for b in ["incomplete", "valid_v1", "valid_v2"] {
+            let current = testing::Scaffold::load(b).await;
+            store
+                .create_invoice(&current.invoice)
+                .await
+                .expect("Unable to create invoice");
+        }
 
+        // Streamed queries page through all matches, using the limit as the page size
         let res = warp::test::request()
-            .method("GET")
-            .path(&format!("/v1/_r/missing/{}", scaffold.invoice.bindle.id))
+            .path("/v1/_q?q=enterprise.com/warpcore&limit=1")
+            .header("Accept", super::NDJSON_MIME_TYPE)
             .reply(&api)
             .await;
-
         assert_eq!(
             res.status(),
             warp::http::StatusCode::OK,
             "Body: {}",
             String::from_utf8_lossy(res.body())
         );
-
-        let resp: crate::MissingParcelsResponse =
-            toml::from_slice(res.body()).expect("should be valid invoice response TOML");
-
         assert_eq!(
-            resp.missing.len(),
-            2,
-            "Expected 2 missing parcels, got {}",
-            resp.missing.len()
+            super::NDJSON_MIME_TYPE,
+            res.headers()[warp::http::header::CONTENT_TYPE]
         );
-
-        assert!(
-            resp.missing.iter().any(|l| l.name.contains("crate")),
-            "Missing labels does not contain correct data: {:?}",
-            resp.missing
+        let invoices: Vec<crate::Invoice> = String::from_utf8_lossy(res.body())
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Unable to deserialize line"))
+            .collect();
+        assert_eq!(2, invoices.len(), "Expected to get every invoice match");
+        assert_ne!(
+            invoices[0].bindle.id.to_string(),
+            invoices[1].bindle.id.to_string()
         );
-        assert!(
+    }
+
+    #[tokio::test]
+    async fn test_query_filters() {
+        let (store, index) = testing::setup().await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        for b in ["incomplete", "valid_v1", "valid_v2"] {
+            let current = testing::Scaffold::load(b).await;
+            store
+                .create_invoice(&current.invoice)
+                .await
+                .expect("Unable to create invoice");
+        }
+
+        // Filters on annotations and media types are passed to the search engine
+        let res = warp::test::request()
+            .path("/v1/_q?q=enterprise.com/warpcore&media_type=application/x-nonexistent")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let matches: crate::search::Matches =
+            toml::from_slice(res.body()).expect("Unable to deserialize response");
+        assert_eq!(0, matches.total);
+        let res = warp::test::request()
+            .path("/v1/_q?q=enterprise.com/warpcore&ann.nonexistent=true")
+            .reply(&api)
+            .await;
+        let matches: crate::search::Matches =
+            toml::from_slice(res.body()).expect("Unable to deserialize response");
+        assert_eq!(0, matches.total);
+    }
+
+    #[tokio::test]
+    async fn test_missing() {
+        let (store, index) = testing::setup().await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold
+            .parcel_files
+            .get("parcel")
+            .expect("parcel doesn't exist");
+        let parcel_data = std::io::Cursor::new(parcel.data.clone());
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(parcel_data, BytesCodec::default()),
+            )
+            .await
+            .expect("Unable to create parcel");
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!("/v1/_r/missing/{}", scaffold.invoice.bindle.id))
+            .reply(&api)
+            .await;
+
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let resp: crate::MissingParcelsResponse =
+            toml::from_slice(res.body()).expect("should be valid invoice response TOML");
+
+        assert_eq!(
+            resp.missing.len(),
+            2,
+            "Expected 2 missing parcels, got {}",
+            resp.missing.len()
+        );
+
+        assert!(
+            resp.missing.iter().any(|l| l.name.contains("crate")),
+            "Missing labels does not contain correct data: {:?}",
+            resp.missing
+        );
+        assert!(
             resp.missing.iter().any(|l| l.name.contains("barrel")),
             "Missing labels does not contain correct data: {:?}",
             resp.missing
         );
     }
+
+    #[tokio::test]
+    async fn test_missing_shas() {
+        let (store, index) = testing::setup().await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold
+            .parcel_files
+            .get("parcel")
+            .expect("parcel doesn't exist");
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(
+                    std::io::Cursor::new(parcel.data.clone()),
+                    BytesCodec::default(),
+                ),
+            )
+            .await
+            .expect("Unable to create parcel");
+
+        // Parcels are checked no matter which bindle they were uploaded for, including SHAs no
+        // invoice refers to
+        let unknown = "a".repeat(64);
+        let barrel = scaffold.parcel_files.get("barrel").unwrap().sha.clone();
+        let req = crate::MissingShasRequest {
+            shas: vec![parcel.sha.clone(), unknown.clone(), barrel.clone()],
+        };
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/_b/_missing")
+            .header("Content-Type", "application/toml")
+            .body(toml::to_vec(&req).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let resp: crate::MissingShasResponse =
+            toml::from_slice(res.body()).expect("should be valid missing SHAs response TOML");
+        assert_eq!(vec![unknown, barrel], resp.missing);
+
+        // Anything that isn't a SHA never reaches the storage
+        let req = crate::MissingShasRequest {
+            shas: vec!["../../invoices".to_owned()],
+        };
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/_b/_missing")
+            .header("Content-Type", "application/toml")
+            .body(toml::to_vec(&req).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_invoices_by_parcel() {
+        let (store, index) = testing::setup().await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        let mut other = scaffold.invoice.clone();
+        other.bindle.id = "other.com/bundle/0.1.0".parse().unwrap();
+        other.parcel.as_mut().unwrap()[0].label.name = "renamed.txt".to_owned();
+        let shared = other.parcel.as_ref().unwrap()[0].label.clone();
+        other.parcel.as_mut().unwrap().truncate(1);
+        for inv in &[&scaffold.invoice, &other] {
+            store
+                .create_invoice(inv)
+                .await
+                .expect("Unable to load in invoice");
+        }
+        store
+            .yank_invoice(&other.bindle.id)
+            .await
+            .expect("Unable to yank invoice");
+
+        let get = |sha: &str| {
+            warp::test::request()
+                .method("GET")
+                .path(&format!("/v1/_b/{}/_invoices", sha))
+                .reply(&api)
+        };
+        let res = get(&shared.sha256).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let resp: crate::ParcelInvoicesResponse =
+            toml::from_slice(res.body()).expect("should be valid parcel invoices response TOML");
+        assert_eq!(2, resp.invoices.len());
+        // Ordered by name, and yanked invoices are included with the name they gave the parcel
+        let original = &scaffold.invoice.parcel.as_ref().unwrap()[0].label.name;
+        assert_eq!(
+            scaffold.invoice.bindle.id.to_string(),
+            resp.invoices[0].id.to_string()
+        );
+        assert_eq!(*original, resp.invoices[0].name);
+        assert!(!resp.invoices[0].yanked);
+        assert_eq!(other.bindle.id.to_string(), resp.invoices[1].id.to_string());
+        assert_eq!(shared.name, resp.invoices[1].name);
+        assert!(resp.invoices[1].yanked);
+
+        // Parcels that no invoice contains aren't an error
+        let res = get(&"a".repeat(64)).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        let resp: crate::ParcelInvoicesResponse = toml::from_slice(res.body()).unwrap();
+        assert!(resp.invoices.is_empty());
+
+        let res = get("not-a-sha").await;
+        assert_eq!(res.status(), warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_parcel_range() {
+        let (store, index) = testing::setup().await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold
+            .parcel_files
+            .get("parcel")
+            .expect("parcel doesn't exist");
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(
+                    std::io::Cursor::new(parcel.data.clone()),
+                    BytesCodec::default(),
+                ),
+            )
+            .await
+            .expect("Unable to create parcel");
+        let path = format!("/v1/_i/{}@{}", scaffold.invoice.bindle.id, parcel.sha);
+        let size = parcel.data.len();
+        assert!(size > 4, "Test parcel is too small");
+
+        let get = |range: &str| {
+            warp::test::request()
+                .path(&path)
+                .header("Range", range)
+                .reply(&api)
+        };
+
+        let res = get("bytes=1-3").await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::PARTIAL_CONTENT,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert_eq!(res.body().as_ref(), &parcel.data[1..4]);
+        assert_eq!(
+            res.headers()["Content-Range"],
+            format!("bytes 1-3/{}", size).as_str()
+        );
+        assert_eq!(res.headers()["Content-Length"], "3");
+
+        // Open ended and suffix ranges
+        let res = get("bytes=2-").await;
+        assert_eq!(res.status(), warp::http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.body().as_ref(), &parcel.data[2..]);
+        let res = get("bytes=-2").await;
+        assert_eq!(res.status(), warp::http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.body().as_ref(), &parcel.data[size - 2..]);
+
+        // Ranges past the end are truncated
+        let res = get(&format!("bytes=1-{}", size + 100)).await;
+        assert_eq!(res.status(), warp::http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.body().as_ref(), &parcel.data[1..]);
+
+        let res = get(&format!("bytes={}-", size)).await;
+        assert_eq!(res.status(), warp::http::StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            res.headers()["Content-Range"],
+            format!("bytes */{}", size).as_str()
+        );
+
+        // Multiple and invalid ranges are ignored
+        for range in &["bytes=0-1,3-4", "bytes=3-1", "lines=1-2"] {
+            let res = get(range).await;
+            assert_eq!(res.status(), warp::http::StatusCode::OK, "Range: {}", range);
+            assert_eq!(res.body().as_ref(), parcel.data.as_slice());
+            assert_eq!(res.headers()["Accept-Ranges"], "bytes");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resumable_upload() {
+        let (store, index) = testing::setup().await;
+
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold
+            .parcel_files
+            .get("parcel")
+            .expect("parcel doesn't exist");
+        let size = parcel.data.len();
+        assert!(size > 4, "Test parcel is too small");
+
+        let start = || {
+            warp::test::request()
+                .method("POST")
+                .path(&format!(
+                    "/v1/_u/{}@{}",
+                    scaffold.invoice.bindle.id, parcel.sha
+                ))
+                .reply(&api)
+        };
+        let res = start().await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::CREATED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let status: crate::UploadStatus =
+            toml::from_slice(res.body()).expect("Unable to parse upload status");
+        assert_eq!(status.offset, 0);
+        assert_eq!(status.size, size as u64);
+        let path = format!("/v1/_u/{}", status.id);
+
+        let append = |offset: usize, data: &[u8]| {
+            warp::test::request()
+                .method("PATCH")
+                .path(&path)
+                .header("Upload-Offset", offset)
+                .body(data)
+                .reply(&api)
+        };
+
+        // Send part of the data, as if the upload was interrupted
+        let res = append(0, &parcel.data[..2]).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let res = warp::test::request().path(&path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        let status: crate::UploadStatus =
+            toml::from_slice(res.body()).expect("Unable to parse upload status");
+        assert_eq!(status.offset, 2);
+        assert!(!status.is_complete());
+
+        // Data has to pick up where the upload left off
+        let res = append(0, &parcel.data).await;
+        assert_eq!(res.status(), warp::http::StatusCode::CONFLICT);
+        let res = append(2, &parcel.data).await;
+        assert_eq!(res.status(), warp::http::StatusCode::PAYLOAD_TOO_LARGE);
+
+        let res = append(2, &parcel.data[2..]).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let status: crate::UploadStatus =
+            toml::from_slice(res.body()).expect("Unable to parse upload status");
+        assert!(status.is_complete());
+
+        // The parcel should now exist and the upload should be gone
+        let res = warp::test::request()
+            .path(&format!(
+                "/v1/_i/{}@{}",
+                scaffold.invoice.bindle.id, parcel.sha
+            ))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(res.body().as_ref(), parcel.data.as_slice());
+        let res = warp::test::request().path(&path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+        assert_eq!(start().await.status(), warp::http::StatusCode::CONFLICT);
+
+        // Data that doesn't match the SHA is rejected once the upload is complete
+        let other = scaffold
+            .parcel_files
+            .get("crate")
+            .expect("crate doesn't exist");
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!(
+                "/v1/_u/{}@{}",
+                scaffold.invoice.bindle.id, other.sha
+            ))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::CREATED);
+        let status: crate::UploadStatus =
+            toml::from_slice(res.body()).expect("Unable to parse upload status");
+        let res = warp::test::request()
+            .method("PATCH")
+            .path(&format!("/v1/_u/{}", status.id))
+            .header("Upload-Offset", 0)
+            .body(vec![b'x'; other.data.len()])
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::BAD_REQUEST);
+        assert!(!store
+            .parcel_exists(scaffold.invoice.bindle.id.clone(), &other.sha)
+            .await
+            .expect("Unable to check parcel"));
+
+        // Unknown uploads and IDs that aren't valid don't exist
+        for id in &["deadbeef", "..%2Finvoice"] {
+            let res = warp::test::request()
+                .path(&format!("/v1/_u/{}", id))
+                .reply(&api)
+                .await;
+            assert_eq!(
+                res.status(),
+                warp::http::StatusCode::NOT_FOUND,
+                "ID: {}",
+                id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        let (store, index) = testing::setup().await;
+        let authenticator =
+            super::auth::BearerAuthenticator::new(std::collections::HashMap::new()).protect_reads();
+        let api = super::routes::api(store, index, authenticator, super::authz::AllowAll);
+
+        // Capabilities are always available, even when reads need credentials
+        let res = warp::test::request()
+            .path("/v1/_capabilities")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let capabilities: crate::Capabilities =
+            toml::from_slice(res.body()).expect("Unable to parse capabilities");
+        assert!(capabilities.resumable_uploads);
+        assert!(capabilities.range_requests);
+        assert!(capabilities.parcel_delta);
+        assert_eq!(
+            Some(crate::BINDLE_VERSION_1),
+            capabilities.spec_version.as_deref()
+        );
+        assert_eq!(vec!["Bearer".to_owned()], capabilities.auth_methods);
+        assert!(!capabilities.anonymous_read);
+        assert!(!capabilities.anonymous_write);
+        assert!(capabilities
+            .content_types
+            .contains(&"application/toml".to_owned()));
+        assert!(capabilities
+            .content_types
+            .contains(&"application/json".to_owned()));
+
+        // Unknown fields from newer servers are ignored
+        let parsed: crate::Capabilities =
+            toml::from_str("resumableUploads = true\nteleportation = true")
+                .expect("Unknown fields should be ignored");
+        assert!(parsed.resumable_uploads);
+        assert!(!parsed.range_requests);
+    }
+
+    #[tokio::test]
+    async fn test_host_signing() {
+        use crate::signature::{KeyRing, RevocationList, SecretKeyEntry, SignatureRole};
+
+        let bindles = testing::load_all_files().await;
+        let (store, index) = testing::setup().await;
+        let key = SecretKeyEntry::generate("Test Host", vec![SignatureRole::Host]);
+        let keyring = KeyRing::new(vec![key.key_entry()]);
+        let api = super::routes::api_with_options(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                signing_key: Some(std::sync::Arc::new(key)),
+                ..Default::default()
+            },
+        );
+
+        let valid_v1 = bindles.get("valid_v1").expect("Missing scaffold");
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&valid_v1.invoice)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let create_res: crate::InvoiceCreateResponse =
+            toml::from_slice(res.body()).expect("should be valid invoice response TOML");
+        create_res
+            .invoice
+            .verify(&keyring, &RevocationList::default())
+            .expect("Returned invoice should be signed by the host");
+        let signatures = create_res.invoice.signature.as_ref().unwrap();
+        assert_eq!(1, signatures.len());
+        assert_eq!(SignatureRole::Host, signatures[0].role);
+        assert_eq!("Test Host", signatures[0].by);
+
+        // The signature is stored along with the invoice
+        let stored = store
+            .get_yanked_invoice(&create_res.invoice.bindle.id)
+            .await
+            .expect("Invoice should exist");
+        stored
+            .verify(&keyring, &RevocationList::default())
+            .expect("Stored invoice should be signed by the host");
+    }
+
+    #[tokio::test]
+    async fn test_transparency_log() {
+        use crate::signature::{SecretKeyEntry, SignatureRole};
+        use crate::transparency::MemoryLog;
+        use std::sync::Arc;
+
+        let (store, index) = testing::setup().await;
+        let log = Arc::new(MemoryLog::new(ed25519_dalek::Keypair::generate(
+            &mut rand::rngs::OsRng {},
+        )));
+        let host = SecretKeyEntry::generate("Test Host", vec![SignatureRole::Host]);
+        let api = super::routes::api_with_options(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                signing_key: Some(Arc::new(host)),
+                transparency_log: Some(log.clone()),
+                ..Default::default()
+            },
+        );
+
+        // The host signature of a new invoice is recorded before it is stored
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(toml::to_vec(&scaffold.invoice).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let stored = store
+            .get_yanked_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .expect("Invoice should exist");
+        stored
+            .verify_log_entries(&log.public_key())
+            .expect("Stored invoice should be recorded in the log");
+        assert!(stored
+            .signature
+            .iter()
+            .flatten()
+            .all(|s| s.log_entry.is_some()));
+
+        // Countersignatures are recorded as well
+        let key = SecretKeyEntry::generate("Approver", vec![SignatureRole::Approver]);
+        let mut approved = stored.clone();
+        approved.signature = None;
+        approved
+            .sign_with_key(SignatureRole::Approver, &key)
+            .expect("Should be able to sign invoice");
+        let signature = approved.signature.unwrap().remove(0);
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path(&format!("/v1/_i/{}/_signature", stored.name()))
+            .body(toml::to_vec(&signature).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let inv: crate::Invoice =
+            toml::from_slice(res.body()).expect("should be valid invoice TOML");
+        let countersignature = inv
+            .signature
+            .iter()
+            .flatten()
+            .find(|s| s.role == SignatureRole::Approver)
+            .expect("Countersignature should be added");
+        assert!(countersignature.log_entry.is_some());
+        inv.verify_log_entries(&log.public_key())
+            .expect("Countersignature should be recorded in the log");
+    }
+
+    #[tokio::test]
+    async fn test_countersigning() {
+        use crate::signature::{SecretKeyEntry, SignatureRole};
+
+        let (store, index) = testing::setup().await;
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Should be able to insert invoice");
+        let sign_path = format!("/v1/_i/{}/_signature", scaffold.invoice.name());
+
+        // The approver only sends their signature, which is made over the stored invoice
+        let key = SecretKeyEntry::generate("Approver", vec![SignatureRole::Approver]);
+        let mut approved = scaffold.invoice.clone();
+        approved.signature = None;
+        approved
+            .sign_with_key(SignatureRole::Approver, &key)
+            .expect("Should be able to sign invoice");
+        let signature = approved.signature.unwrap().remove(0);
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path(&sign_path)
+            .body(toml::to_vec(&signature).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let inv: crate::Invoice =
+            toml::from_slice(res.body()).expect("should be valid invoice TOML");
+        assert!(inv.signature.unwrap().contains(&signature));
+        let stored = store
+            .get_invoice(&scaffold.invoice.bindle.id)
+            .await
+            .expect("Invoice should exist");
+        assert!(stored.signature.unwrap().contains(&signature));
+        let history = store
+            .get_invoice_history(&scaffold.invoice.bindle.id)
+            .await
+            .expect("History should exist");
+        let event = history.event.last().unwrap();
+        assert_eq!(crate::HistoryAction::Sign, event.action);
+        // The event lists the signers the invoice had after the signature was added
+        assert!(event
+            .signatures
+            .iter()
+            .any(|s| s.role == SignatureRole::Approver && s.key == signature.key));
+
+        // A signature made over different parcels is rejected
+        let mut modified = scaffold.invoice.clone();
+        modified.signature = None;
+        modified.parcel.as_mut().unwrap()[0].label.sha256 = "abc123".to_owned();
+        modified
+            .sign_with_key(SignatureRole::Approver, &key)
+            .expect("Should be able to sign invoice");
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path(&sign_path)
+            .body(toml::to_vec(&modified.signature.unwrap()[0]).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::BAD_REQUEST,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Invoices that don't exist can't be signed
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i/nonexistent/1.0.0/_signature")
+            .body(toml::to_vec(&signature).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::NOT_FOUND,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quotas() {
+        use sha2::Digest;
+
+        let (store, index) = testing::setup().await;
+        let mut tokens = std::collections::HashMap::new();
+        for (token, name) in &[("alice-token", "alice"), ("bob-token", "bob")] {
+            tokens.insert(
+                format!("{:x}", sha2::Sha256::digest(token.as_bytes())),
+                name.to_string(),
+            );
+        }
+        let policy: super::QuotaPolicy = toml::from_str(
+            r#"
+            [[user]]
+            identity = "alice"
+            maxBindles = 1
+
+            [[user]]
+            identity = "*"
+            maxBytes = 10
+            "#,
+        )
+        .unwrap();
+        let api = super::routes::api_with_options(
+            store.clone(),
+            index.clone(),
+            super::auth::BearerAuthenticator::new(tokens),
+            super::authz::AllowAll,
+            super::ApiOptions {
+                quotas: Some(super::Quotas::new(policy)),
+                ..Default::default()
+            },
+        );
+        let create = |token: &'static str, inv: &crate::Invoice| {
+            warp::test::request()
+                .method("POST")
+                .header("Content-Type", "application/toml")
+                .header("Authorization", format!("Bearer {}", token))
+                .path("/v1/_i")
+                .body(toml::to_vec(inv).unwrap())
+        };
+        let quota_error = |body: &[u8]| {
+            toml::from_slice::<crate::ErrorResponse>(body)
+                .expect("should be a valid error response")
+                .quota
+                .expect("should describe the quota")
+        };
+
+        let v1 = testing::Scaffold::load("valid_v1").await;
+        let res = create("alice-token", &v1.invoice).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::ACCEPTED);
+        let parcel = &v1.parcel_files["parcel"];
+        let res = warp::test::request()
+            .method("POST")
+            .header("Authorization", "Bearer alice-token")
+            .path(&format!("/v1/_i/{}@{}", v1.invoice.bindle.id, parcel.sha))
+            .body(parcel.data.clone())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+
+        // Alice may only create a single bindle
+        let v2 = testing::Scaffold::load("valid_v2").await;
+        let res = create("alice-token", &v2.invoice).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::TOO_MANY_REQUESTS);
+        let quota = quota_error(res.body());
+        assert_eq!(Some("alice"), quota.identity.as_deref());
+        assert_eq!(crate::QuotaResource::Bindles, quota.resource);
+        assert_eq!((1, 1, 1), (quota.limit, quota.used, quota.requested));
+        assert!(store.get_invoice(&v2.invoice.bindle.id).await.is_err());
+
+        // Bob may create it, but the new parcel of 11 bytes doesn't fit in his 10 bytes, whether
+        // it is sent all at once or in an upload
+        let res = create("bob-token", &v2.invoice).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::ACCEPTED);
+        let parcel = &v2.parcel_files["parcel"];
+        for path in &[
+            format!("/v1/_i/{}@{}", v2.invoice.bindle.id, parcel.sha),
+            format!("/v1/_u/{}@{}", v2.invoice.bindle.id, parcel.sha),
+        ] {
+            let res = warp::test::request()
+                .method("POST")
+                .header("Authorization", "Bearer bob-token")
+                .path(path)
+                .body(parcel.data.clone())
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), warp::http::StatusCode::PAYLOAD_TOO_LARGE);
+            let quota = quota_error(res.body());
+            assert_eq!(crate::QuotaResource::Bytes, quota.resource);
+            assert_eq!((10, 0, 11), (quota.limit, quota.used, quota.requested));
+        }
+
+        let res = warp::test::request()
+            .header("Authorization", "Bearer alice-token")
+            .path("/v1/_quota")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        let report: crate::QuotaReport = toml::from_slice(res.body()).unwrap();
+        assert_eq!((9, 2), (report.total.bytes, report.total.bindles));
+        assert_eq!(2, report.user.len());
+        assert_eq!(Some("alice"), report.user[0].identity.as_deref());
+        assert_eq!((9, 1), (report.user[0].bytes, report.user[0].bindles));
+        assert_eq!(Some(1), report.user[0].max_bindles);
+        assert_eq!((0, 1), (report.user[1].bytes, report.user[1].bindles));
+        assert_eq!(Some(10), report.user[1].max_bytes);
+
+        let api = super::routes::api(
+            store,
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        let res = warp::test::request().path("/v1/_quota").reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn test_keyring_storage() {
+        use sha2::Digest;
+
+        let (store, index) = testing::setup().await;
+        let mut tokens = std::collections::HashMap::new();
+        for (token, name) in &[("alice-token", "alice"), ("bob-token", "bob")] {
+            tokens.insert(
+                format!("{:x}", sha2::Sha256::digest(token.as_bytes())),
+                name.to_string(),
+            );
+        }
+        let authenticator = super::auth::BearerAuthenticator::new(tokens);
+        let dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let api = super::routes::api_with_options(
+            store.clone(),
+            index.clone(),
+            authenticator.clone(),
+            super::authz::AllowAll,
+            super::ApiOptions {
+                keyring_dir: Some(dir.path().to_owned()),
+                ..Default::default()
+            },
+        );
+
+        // Keyrings belong to a user, so anonymous requests can't have one
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/v1/_keyring")
+            .body("encrypted")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::UNAUTHORIZED);
+
+        let res = warp::test::request()
+            .path("/v1/_keyring")
+            .header("Authorization", "Bearer alice-token")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/v1/_keyring")
+            .header("Authorization", "Bearer alice-token")
+            .body("encrypted")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::NO_CONTENT,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let res = warp::test::request()
+            .path("/v1/_keyring")
+            .header("Authorization", "Bearer alice-token")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(res.body().as_ref(), b"encrypted");
+
+        // Other users have their own keyring
+        let res = warp::test::request()
+            .path("/v1/_keyring")
+            .header("Authorization", "Bearer bob-token")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+
+        // Servers without keyring storage say so
+        let api = super::routes::api(store, index, authenticator, super::authz::AllowAll);
+        let res = warp::test::request()
+            .path("/v1/_keyring")
+            .header("Authorization", "Bearer alice-token")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_IMPLEMENTED);
+    }
+
+    #[tokio::test]
+    async fn test_verification_strategy() {
+        use crate::signature::{
+            Revocation, RevocationList, SecretKeyEntry, SignatureRole, VerificationStrategy,
+        };
+
+        let bindles = testing::load_all_files().await;
+        let (store, index) = testing::setup().await;
+        let creator = SecretKeyEntry::generate("Creator", vec![SignatureRole::Creator]);
+        let api = super::routes::api_with_options(
+            store,
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                verification_strategy: VerificationStrategy::CreativeIntegrity,
+                keyring: std::sync::Arc::new(crate::signature::KeyRing::new(vec![
+                    creator.key_entry()
+                ])),
+                ..Default::default()
+            },
+        );
+
+        let valid_v1 = bindles.get("valid_v1").expect("Missing scaffold");
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(&valid_v1.invoice)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::BAD_REQUEST);
+        let err: crate::ErrorResponse =
+            toml::from_slice(res.body()).expect("should be valid error TOML");
+        let failure = err.verification.expect("Error should describe the failure");
+        assert_eq!(VerificationStrategy::CreativeIntegrity, failure.strategy);
+        assert!(failure.reason.contains("not signed"));
+
+        let mut inv: crate::Invoice =
+            toml::from_slice(&valid_v1.invoice).expect("Unable to parse invoice");
+        inv.sign_with_key(SignatureRole::Creator, &creator)
+            .expect("Unable to sign invoice");
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(toml::to_vec(&inv).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Signatures made with a revoked key are rejected even though the keyring trusts the key
+        let (store, index) = testing::setup().await;
+        let mut revocations = RevocationList::default();
+        revocations
+            .revoke(Revocation {
+                key: creator.key_entry().key,
+                algorithm: Default::default(),
+                at: 0,
+                reason: None,
+            })
+            .unwrap();
+        let api = super::routes::api_with_options(
+            store,
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                verification_strategy: VerificationStrategy::CreativeIntegrity,
+                keyring: std::sync::Arc::new(crate::signature::KeyRing::new(vec![
+                    creator.key_entry()
+                ])),
+                revocations: std::sync::Arc::new(revocations),
+                ..Default::default()
+            },
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(toml::to_vec(&inv).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::BAD_REQUEST);
+        let err: crate::ErrorResponse =
+            toml::from_slice(res.body()).expect("should be valid error TOML");
+        let failure = err.verification.expect("Error should describe the failure");
+        assert!(failure.reason.contains("revoked key"));
+    }
+
+    #[tokio::test]
+    async fn test_invoice_summary() {
+        use crate::signature::{SecretKeyEntry, SignatureRole};
+
+        let (store, index) = testing::setup().await;
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let mut inv = testing::Scaffold::load("valid_v2").await.invoice;
+        inv.group = Some(vec![crate::Group {
+            name: "server".to_owned(),
+            required: None,
+            satisfied_by: None,
+        }]);
+        let creator = SecretKeyEntry::generate("creator", vec![SignatureRole::Creator]);
+        inv.sign_with_key(SignatureRole::Creator, &creator)
+            .expect("Unable to sign invoice");
+        store
+            .create_invoice(&inv)
+            .await
+            .expect("Should be able to insert invoice");
+        store
+            .yank_invoice(&inv.bindle.id)
+            .await
+            .expect("Should be able to yank invoice");
+
+        let res = warp::test::request()
+            .path(&format!("/v1/_i/{}/_summary", inv.name()))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let summary: crate::InvoiceSummary =
+            toml::from_slice(res.body()).expect("should be valid summary TOML");
+        assert!(summary.yanked);
+        assert_eq!(2, summary.parcel_count);
+        assert_eq!(20, summary.total_size);
+        assert_eq!(vec!["server".to_owned()], summary.groups);
+        assert_eq!(vec![SignatureRole::Creator], summary.signature_roles);
+        assert_eq!(inv.bindle.id.to_string(), summary.bindle.id.to_string());
+        assert_eq!(inv.bindle.description, summary.bindle.description);
+
+        let res = warp::test::request()
+            .path("/v1/_i/enterprise.com/warpcore/9.9.9/_summary")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_invoice_metadata() {
+        use crate::signature::{SecretKeyEntry, SignatureRole};
+
+        let (store, index) = testing::setup().await;
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let mut inv = testing::Scaffold::load("valid_v2").await.invoice;
+        let creator = SecretKeyEntry::generate("creator", vec![SignatureRole::Creator]);
+        inv.sign_with_key(SignatureRole::Creator, &creator)
+            .expect("Unable to sign invoice");
+        store
+            .create_invoice(&inv)
+            .await
+            .expect("Should be able to insert invoice");
+
+        let get_metadata = || async {
+            let res = warp::test::request()
+                .path(&format!("/v1/_i/{}/_meta", inv.name()))
+                .reply(&api)
+                .await;
+            assert_eq!(
+                res.status(),
+                warp::http::StatusCode::OK,
+                "Body: {}",
+                String::from_utf8_lossy(res.body())
+            );
+            toml::from_slice::<crate::InvoiceMetadata>(res.body())
+                .expect("should be valid metadata TOML")
+        };
+
+        let metadata = get_metadata().await;
+        assert!(metadata.created_at.is_some());
+        assert_eq!(metadata.created_at, metadata.modified_at);
+        assert!(!metadata.yanked);
+        assert_eq!(2, metadata.parcel_count);
+        assert_eq!(20, metadata.total_size);
+        assert_eq!(1, metadata.signature_count);
+
+        // Yanking modifies the invoice, and the metadata is still available afterwards
+        store
+            .yank_invoice(&inv.bindle.id)
+            .await
+            .expect("Should be able to yank invoice");
+        let yanked = get_metadata().await;
+        assert!(yanked.yanked);
+        assert_eq!(metadata.created_at, yanked.created_at);
+        assert!(yanked.modified_at >= metadata.modified_at);
+
+        let res = warp::test::request()
+            .path("/v1/_i/enterprise.com/warpcore/9.9.9/_meta")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_list_versions() {
+        use crate::signature::{SecretKeyEntry, SignatureRole};
+
+        let (store, index) = testing::setup().await;
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let v1 = testing::Scaffold::load("valid_v1").await.invoice;
+        let mut v2 = testing::Scaffold::load("valid_v2").await.invoice;
+        let creator = SecretKeyEntry::generate("creator", vec![SignatureRole::Creator]);
+        v2.sign_with_key(SignatureRole::Creator, &creator)
+            .expect("Unable to sign invoice");
+        for inv in &[&v2, &v1] {
+            store
+                .create_invoice(inv)
+                .await
+                .expect("Should be able to insert invoice");
+        }
+        store
+            .yank_invoice(&v1.bindle.id)
+            .await
+            .expect("Should be able to yank invoice");
+
+        let res = warp::test::request()
+            .path("/v1/_r/versions/enterprise.com/warpcore")
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let versions = toml::from_slice::<crate::VersionsResponse>(res.body())
+            .expect("should be valid versions TOML")
+            .versions;
+        assert_eq!(
+            vec!["1.0.0", "2.0.0"],
+            versions
+                .iter()
+                .map(|v| v.version.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(versions[0].yanked);
+        assert_eq!(None, versions[0].created_at);
+        assert!(!versions[1].yanked);
+        assert_eq!(
+            v2.signature.as_ref().map(|s| s[0].at),
+            versions[1].created_at
+        );
+
+        let res = warp::test::request()
+            .path("/v1/_r/versions/enterprise.com/transporter")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_processing_status() {
+        use super::processing::{ParcelDigestCheck, Pipeline};
+
+        let (store, index) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let status_path = format!("/v1/_i/{}/_status", scaffold.invoice.bindle.id);
+        let api = super::routes::api(
+            store.clone(),
+            index.clone(),
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        let res = warp::test::request().path(&status_path).reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::NOT_FOUND,
+            "Servers without a pipeline shouldn't have statuses"
+        );
+
+        let api = super::routes::api_with_options(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                processing: Some(Pipeline::default().with_processor(ParcelDigestCheck)),
+                ..Default::default()
+            },
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(toml::to_vec(&scaffold.invoice).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::ACCEPTED);
+
+        let res = warp::test::request().path(&status_path).reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        let status: crate::ProcessingStatus =
+            toml::from_slice(res.body()).expect("should be valid status TOML");
+        assert_eq!(
+            crate::ProcessingState::Pending,
+            status.state,
+            "Processing should wait for the missing parcels"
+        );
+
+        for parcel in scaffold.parcel_files.values() {
+            let res = warp::test::request()
+                .method("POST")
+                .path(&format!(
+                    "/v1/_i/{}@{}",
+                    scaffold.invoice.bindle.id, parcel.sha
+                ))
+                .body(parcel.data.clone())
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), warp::http::StatusCode::OK);
+        }
+
+        let mut status = None;
+        for _ in 0..100 {
+            let res = warp::test::request().path(&status_path).reply(&api).await;
+            let current: crate::ProcessingStatus =
+                toml::from_slice(res.body()).expect("should be valid status TOML");
+            if current.state.is_finished() {
+                status = Some(current);
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+        }
+        let status = status.expect("Processing should finish");
+        assert_eq!(crate::ProcessingState::Succeeded, status.state);
+        assert_eq!("parcel-digests", status.step[0].name);
+
+        let res = warp::test::request()
+            .path("/v1/_i/enterprise.com/warpcore/9.9.9/_status")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_signing_policy() {
+        use super::SigningPolicy;
+        use crate::signature::{KeyRing, SecretKeyEntry, SignatureRole};
+        use std::sync::Arc;
+
+        let (store, index) = testing::setup().await;
+        let creator = SecretKeyEntry::generate("Creator", vec![SignatureRole::Creator]);
+        let policy: SigningPolicy = toml::from_str(
+            r#"
+            [[rule]]
+            bindles = "enterprise.com/*"
+            require = ["creator", "approver"]
+            enforce = true
+
+            [[rule]]
+            bindles = "*"
+            require = ["creator", "approver"]
+            "#,
+        )
+        .unwrap();
+        let api = super::routes::api_with_options(
+            store,
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                keyring: Arc::new(KeyRing::new(vec![creator.key_entry()])),
+                signing_policy: Arc::new(policy),
+                ..Default::default()
+            },
+        );
+
+        // Unenforced rules only report what is missing
+        let mut inv = Scaffold::load("valid_v1").await.invoice;
+        inv.bindle.id = "example.com/unenforced/1.0.0".parse().unwrap();
+        inv.sign_with_key(SignatureRole::Creator, &creator)
+            .expect("Unable to sign invoice");
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(toml::to_vec(&inv).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let create_res: crate::InvoiceCreateResponse =
+            toml::from_slice(res.body()).expect("should be valid create response TOML");
+        let result = create_res
+            .signing_policy
+            .expect("Response should contain the policy outcome");
+        assert_eq!("*", result.bindles);
+        assert!(!result.satisfied);
+        assert_eq!(vec![SignatureRole::Approver], result.missing);
+
+        // Enforced rules reject the invoice with the same outcome
+        let mut inv = Scaffold::load("valid_v2").await.invoice;
+        inv.sign_with_key(SignatureRole::Creator, &creator)
+            .expect("Unable to sign invoice");
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(toml::to_vec(&inv).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::BAD_REQUEST);
+        let err: crate::ErrorResponse =
+            toml::from_slice(res.body()).expect("should be valid error TOML");
+        let result = err
+            .signing_policy
+            .expect("Error should contain the policy outcome");
+        assert_eq!("enterprise.com/*", result.bindles);
+        assert!(result.enforced);
+        assert_eq!(vec![SignatureRole::Approver], result.missing);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_uploads() {
+        let (store, index) = testing::setup().await;
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let scaffold = testing::Scaffold::load("valid_v2").await;
+        let id = scaffold.invoice.bindle.id.clone();
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let other = scaffold.parcel_files.get("other").unwrap();
+
+        let res = warp::test::request()
+            .path(&format!("/v1/_i/{}", id))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        let etag = res
+            .headers()
+            .get("ETag")
+            .expect("Invoice should have an ETag")
+            .to_str()
+            .unwrap()
+            .to_owned();
+        // HEAD requests should return the same tag
+        let res = warp::test::request()
+            .method("HEAD")
+            .path(&format!("/v1/_i/{}", id))
+            .reply(&api)
+            .await;
+        assert_eq!(etag, res.headers().get("ETag").unwrap().to_str().unwrap());
+
+        let create = |sha: &str, data: &[u8], if_match: &str| {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/v1/_i/{}@{}", id, sha))
+                .header("If-Match", if_match)
+                .body(data)
+                .reply(&api)
+        };
+        let res = create(&parcel.sha, &parcel.data, "\"nope\"").await;
+        assert_eq!(res.status(), warp::http::StatusCode::PRECONDITION_FAILED);
+        let res = create(&parcel.sha, &parcel.data, &format!("\"nope\", {}", etag)).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        // Start an upload, then yank the invoice before all of the data is sent
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/v1/_u/{}@{}", id, other.sha))
+            .header("If-Match", &etag)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::CREATED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let status: crate::UploadStatus =
+            toml::from_slice(res.body()).expect("Unable to parse upload status");
+        store.yank_invoice(&id).await.expect("Unable to yank");
+
+        let res = warp::test::request()
+            .method("PATCH")
+            .path(&format!("/v1/_u/{}", status.id))
+            .header("Upload-Offset", 0)
+            .body(&other.data)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::PRECONDITION_FAILED);
+        assert!(
+            !store.parcel_exists(&id, &other.sha).await.unwrap(),
+            "Parcel should not have been created"
+        );
+        // The upload can't succeed anymore, so it should be gone
+        let res = warp::test::request()
+            .path(&format!("/v1/_u/{}", status.id))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+
+        // The old tag no longer matches now that the invoice is yanked, but any tag still does
+        let res = create(&other.sha, &other.data, &etag).await;
+        assert_eq!(res.status(), warp::http::StatusCode::PRECONDITION_FAILED);
+        let res = create(&other.sha, &other.data, "*").await;
+        assert_eq!(res.status(), warp::http::StatusCode::FORBIDDEN);
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/v1/_i/example.com/nope/1.0.0@{}", other.sha))
+            .header("If-Match", "*")
+            .body(&other.data)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn test_content_negotiation() {
+        let (store, index) = testing::setup().await;
+        let api = super::routes::api(
+            store,
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        let inv = testing::Scaffold::load("valid_v1").await.invoice;
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/_i")
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .body(serde_json::to_vec(&inv).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert_eq!(
+            "application/json",
+            res.headers().get("Content-Type").unwrap()
+        );
+        let created: crate::InvoiceCreateResponse =
+            serde_json::from_slice(res.body()).expect("should be valid JSON");
+        assert_eq!(
+            inv.bindle.id.to_string(),
+            created.invoice.bindle.id.to_string()
+        );
+        assert_eq!(
+            inv.parcel.as_ref().unwrap().len(),
+            created.missing.unwrap().len()
+        );
+
+        // JSON is only sent to clients that prefer it
+        let get = |accept: &str| {
+            warp::test::request()
+                .path(&format!("/v1/_i/{}", inv.bindle.id))
+                .header("Accept", accept)
+                .reply(&api)
+        };
+        for accept in &["application/toml", "*/*", "application/json;q=0.5, */*"] {
+            let res = get(accept).await;
+            assert_eq!(res.status(), warp::http::StatusCode::OK);
+            assert_eq!(
+                "application/toml",
+                res.headers().get("Content-Type").unwrap()
+            );
+            toml::from_slice::<crate::Invoice>(res.body()).expect("should be valid TOML");
+        }
+        let res = get("application/json").await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!("Accept", res.headers().get("Vary").unwrap());
+        let fetched: crate::Invoice =
+            serde_json::from_slice(res.body()).expect("should be valid JSON");
+        assert_eq!(inv.parcel, fetched.parcel);
+
+        // Errors are negotiated too
+        let res = warp::test::request()
+            .path("/v1/_i/enterprise.com/warpcore/9.9.9")
+            .header("Accept", "application/json")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+        let err: crate::ErrorResponse =
+            serde_json::from_slice(res.body()).expect("should be valid JSON");
+        assert!(!err.error.is_empty());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/_i")
+            .header("Content-Type", "text/plain")
+            .body(toml::to_vec(&inv).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/_i")
+            .header("Content-Type", "application/json")
+            .body("{")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_content_disposition() {
+        use super::DispositionPolicy;
+        use std::sync::Arc;
+
+        let (store, index) = testing::setup().await;
+        let policy: DispositionPolicy = toml::from_str(
+            r#"
+            [[rule]]
+            mediaTypes = "text/*"
+            disposition = "inline"
+            "#,
+        )
+        .unwrap();
+        let api = super::routes::api_with_options(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                disposition_policy: Arc::new(policy),
+                ..Default::default()
+            },
+        );
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(
+                    std::io::Cursor::new(parcel.data.clone()),
+                    BytesCodec::default(),
+                ),
+            )
+            .await
+            .expect("Unable to create parcel");
+
+        for method in &["GET", "HEAD"] {
+            let res = warp::test::request()
+                .method(method)
+                .path(&format!(
+                    "/v1/_i/{}@{}",
+                    scaffold.invoice.bindle.id, parcel.sha
+                ))
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), warp::http::StatusCode::OK);
+            assert_eq!(
+                "inline; filename=\"isolinear_chip.txt\"",
+                res.headers()
+                    .get("Content-Disposition")
+                    .expect("Parcel should have a Content-Disposition")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_garbage_collection() {
+        use crate::provider::gc::GcReport;
+        use sha2::{Digest, Sha256};
+
+        let (store, index) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        let orphan = b"left behind".to_vec();
+        let orphan_sha = format!("{:x}", Sha256::digest(&orphan));
+        for (sha, data) in [(&parcel.sha, parcel.data.clone()), (&orphan_sha, orphan)] {
+            store
+                .create_parcel(
+                    &scaffold.invoice.bindle.id,
+                    sha,
+                    FramedRead::new(std::io::Cursor::new(data), BytesCodec::default()),
+                )
+                .await
+                .expect("Unable to create parcel");
+        }
+
+        let mut users = std::collections::HashMap::new();
+        users.insert(
+            "ops".to_owned(),
+            bcrypt::hash("sw0rdf1sh", 4).expect("unable to hash password"),
+        );
+        let authenticator = super::auth::BasicAuthenticator::new(users);
+        let credentials = format!("Basic {}", base64::encode("ops:sw0rdf1sh"));
+        let policy = |bindles: &str| super::authz::RolePolicy {
+            grant: vec![super::authz::Grant {
+                identity: "ops".to_owned(),
+                bindles: bindles.to_owned(),
+                role: super::authz::Role::Admin,
+            }],
+        };
+        let collect = |path: &str| {
+            warp::test::request()
+                .method("POST")
+                .header("Authorization", &credentials)
+                .path(path)
+        };
+
+        // Garbage collection affects every bindle, so it needs admin rights for all of them
+        let api = super::routes::api(
+            store.clone(),
+            index.clone(),
+            authenticator.clone(),
+            policy("enterprise.com/*"),
+        );
+        let res = collect("/v1/_gc").reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::FORBIDDEN);
+
+        let api = super::routes::api(store.clone(), index, authenticator, policy("*"));
+        let res = collect("/v1/_gc?dryRun=true").reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let report: GcReport = toml::from_slice(res.body()).expect("should be a valid report");
+        assert!(report.dry_run);
+        assert_eq!(vec![orphan_sha.clone()], report.removed);
+        assert!(store
+            .parcel_exists(&scaffold.invoice.bindle.id, &orphan_sha)
+            .await
+            .unwrap());
+
+        let res = collect("/v1/_gc").reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        let report: GcReport = toml::from_slice(res.body()).expect("should be a valid report");
+        assert_eq!(vec![orphan_sha.clone()], report.removed);
+        assert_eq!(1, report.retained);
+        assert!(!store
+            .parcel_exists(&scaffold.invoice.bindle.id, &orphan_sha)
+            .await
+            .unwrap());
+        assert!(store
+            .parcel_exists(&scaffold.invoice.bindle.id, &parcel.sha)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_delete_invoice() {
+        use crate::search::Search;
+
+        let (store, index) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(
+                    std::io::Cursor::new(parcel.data.clone()),
+                    BytesCodec::default(),
+                ),
+            )
+            .await
+            .expect("Unable to create parcel");
+
+        let mut users = std::collections::HashMap::new();
+        users.insert(
+            "ops".to_owned(),
+            bcrypt::hash("sw0rdf1sh", 4).expect("unable to hash password"),
+        );
+        let authenticator = super::auth::BasicAuthenticator::new(users);
+        let credentials = format!("Basic {}", base64::encode("ops:sw0rdf1sh"));
+        let policy = |role: super::authz::Role| super::authz::RolePolicy {
+            grant: vec![super::authz::Grant {
+                identity: "ops".to_owned(),
+                bindles: "*".to_owned(),
+                role,
+            }],
+        };
+        let path = format!("/v1/_i/{}?purge=true", scaffold.invoice.bindle.id);
+        let delete = || {
+            warp::test::request()
+                .method("DELETE")
+                .header("Authorization", &credentials)
+                .path(&path)
+        };
+
+        // Deleting can't be undone, so unlike creating it needs admin rights
+        let api = super::routes::api(
+            store.clone(),
+            index.clone(),
+            authenticator.clone(),
+            policy(super::authz::Role::Creator),
+        );
+        let res = delete().reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::FORBIDDEN);
+
+        let api = super::routes::api(
+            store.clone(),
+            index.clone(),
+            authenticator,
+            policy(super::authz::Role::Admin),
+        );
+        let res = delete().reply(&api).await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::OK,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        let resp: crate::InvoiceDeleteResponse =
+            toml::from_slice(res.body()).expect("should be a valid response");
+        assert_eq!(vec![parcel.sha.clone()], resp.removed_parcels);
+
+        assert!(matches!(
+            store.get_yanked_invoice(&scaffold.invoice.bindle.id).await,
+            Err(crate::provider::ProviderError::NotFound)
+        ));
+        assert!(!store
+            .parcel_exists(&scaffold.invoice.bindle.id, &parcel.sha)
+            .await
+            .unwrap());
+        let matches = index
+            .query(
+                scaffold.invoice.bindle.id.name().to_owned(),
+                String::new(),
+                crate::search::SearchOptions::default(),
+            )
+            .await
+            .expect("Unable to query index");
+        assert!(matches.invoices.is_empty());
+
+        let res = delete().reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_parcel_size_validation() {
+        use sha2::{Digest, Sha256};
+
+        let (store, index) = testing::setup().await;
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to create invoice");
+        store
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                FramedRead::new(
+                    std::io::Cursor::new(parcel.data.clone()),
+                    BytesCodec::default(),
+                ),
+            )
+            .await
+            .expect("Unable to create parcel");
+
+        // A label with the wrong size for data that was never uploaded
+        let data = b"more data than the label says".to_vec();
+        let sha = format!("{:x}", Sha256::digest(&data));
+        let mut inv = scaffold.invoice.clone();
+        inv.bindle.id = "enterprise.com/warpcore/1.1.0".parse().unwrap();
+        inv.parcel = Some(vec![crate::Parcel {
+            label: crate::Label {
+                size: 4,
+                ..crate::Label::new("short.txt".to_owned(), sha.clone())
+            },
+            conditions: None,
+        }]);
+        store
+            .create_invoice(&inv)
+            .await
+            .expect("Unable to create invoice");
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/v1/_i/{}@{}", inv.bindle.id, sha))
+            .body(data)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::BAD_REQUEST,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert!(String::from_utf8_lossy(res.body()).contains("expected 4 bytes"));
+        assert!(!store.parcel_exists(&inv.bindle.id, &sha).await.unwrap());
+
+        // An invoice with the wrong size for a parcel that already exists
+        let mut inv = scaffold.invoice.clone();
+        inv.bindle.id = "enterprise.com/warpcore/1.2.0".parse().unwrap();
+        if let Some(p) = inv.parcel.as_mut().and_then(|p| p.first_mut()) {
+            p.label.size += 1;
+        }
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/v1/_i")
+            .body(toml::to_vec(&inv).expect("serialization shouldn't fail"))
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::BAD_REQUEST,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+        assert!(String::from_utf8_lossy(res.body()).contains(&parcel.sha));
+        assert!(matches!(
+            store.get_yanked_invoice(&inv.bindle.id).await,
+            Err(crate::provider::ProviderError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_parcel_selection() {
+        let (store, index) = testing::setup().await;
+        let api = super::routes::api(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+
+        // Both chips are members of a required group that is satisfied by one of them
+        let mut inv = testing::Scaffold::load("valid_v2").await.invoice;
+        inv.group = Some(vec![crate::Group {
+            name: "chips".to_owned(),
+            required: Some(true),
+            satisfied_by: Some("oneOf".to_owned()),
+        }]);
+        for (i, parcel) in inv.parcel.as_mut().unwrap().iter_mut().enumerate() {
+            parcel.conditions = Some(crate::Condition {
+                member_of: Some(vec!["chips".to_owned()]),
+                requires: None,
+            });
+            let mut features = std::collections::BTreeMap::new();
+            features.insert("version".to_owned(), (i + 1).to_string());
+            let mut feature = crate::FeatureMap::new();
+            feature.insert("chip".to_owned(), features);
+            parcel.label.feature = Some(feature);
+        }
+        store
+            .create_invoice(&inv)
+            .await
+            .expect("Should be able to insert invoice");
+
+        let select = |query: &str| {
+            warp::test::request()
+                .path(&format!("/v1/_i/{}/_selection{}", inv.name(), query))
+                .reply(&api)
+        };
+        let names = |body: &[u8]| -> Vec<String> {
+            toml::from_slice::<crate::ParcelSelectionResponse>(body)
+                .expect("should be valid selection TOML")
+                .labels
+                .into_iter()
+                .map(|l| l.name)
+                .collect()
+        };
+
+        for (query, expected) in &[
+            ("", vec!["isolinear_chip.txt"]),
+            ("?features=version=2", vec!["isolinear_chip_v2.txt"]),
+            ("?features=chip.version=2", vec!["isolinear_chip_v2.txt"]),
+            (
+                "?groups=chips",
+                vec!["isolinear_chip.txt", "isolinear_chip_v2.txt"],
+            ),
+            ("?prefer=smallest", vec!["isolinear_chip.txt"]),
+            ("?prefer=largest", vec!["isolinear_chip_v2.txt"]),
+            (
+                "?prefer=largest&features=version=1",
+                vec!["isolinear_chip.txt"],
+            ),
+        ] {
+            let res = select(query).await;
+            assert_eq!(
+                res.status(),
+                warp::http::StatusCode::OK,
+                "Body: {}",
+                String::from_utf8_lossy(res.body())
+            );
+            assert_eq!(*expected, names(res.body()), "Query: {}", query);
+        }
+
+        for (query, status) in &[
+            ("?groups=nope", warp::http::StatusCode::BAD_REQUEST),
+            ("?features=version", warp::http::StatusCode::BAD_REQUEST),
+            ("?prefer=tiny", warp::http::StatusCode::BAD_REQUEST),
+            (
+                "?features=version=3",
+                warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ] {
+            let res = select(query).await;
+            assert_eq!(res.status(), *status, "Query: {}", query);
+        }
+
+        let res = warp::test::request()
+            .path("/v1/_i/enterprise.com/warpcore/9.9.9/_selection")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (store, index) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+        let parcel = scaffold.parcel_files.get("parcel").unwrap();
+
+        let disabled = super::routes::api(
+            store.clone(),
+            index.clone(),
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        let res = warp::test::request()
+            .path("/metrics")
+            .reply(&disabled)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_IMPLEMENTED);
+
+        let metrics = super::Metrics::default();
+        metrics.register(store.clone());
+        metrics.register(index.clone());
+        let api = super::routes::api_with_options(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                metrics: Some(metrics),
+                ..Default::default()
+            },
+        );
+        let path = format!("/v1/_i/{}@{}", scaffold.invoice.bindle.id, parcel.sha);
+        let res = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .body(parcel.data.clone())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        for _ in 0..2 {
+            let res = warp::test::request().path(&path).reply(&api).await;
+            assert_eq!(res.status(), warp::http::StatusCode::OK);
+        }
+        let res = warp::test::request()
+            .path("/v1/_i/enterprise.com/warpcore/9.9.9")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+
+        let res = warp::test::request().path("/metrics").reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(
+            super::metrics::CONTENT_TYPE,
+            res.headers()[warp::http::header::CONTENT_TYPE]
+        );
+        let body = String::from_utf8_lossy(res.body());
+        let size = parcel.data.len();
+        for expected in &[
+            r#"bindle_http_requests_total{operation="create_parcel",status="200"} 1"#.to_owned(),
+            r#"bindle_http_requests_total{operation="get_parcel",status="200"} 2"#.to_owned(),
+            r#"bindle_http_requests_total{operation="get_invoice",status="404"} 1"#.to_owned(),
+            r#"bindle_http_request_duration_seconds_count{operation="get_parcel"} 2"#.to_owned(),
+            format!("bindle_parcel_uploaded_bytes_total {}", size),
+            format!("bindle_parcel_downloaded_bytes_total {}", size * 2),
+            "bindle_storage_invoices 1".to_owned(),
+            "bindle_storage_parcels 1".to_owned(),
+            format!("bindle_storage_parcel_bytes {}", size),
+            "bindle_search_index_invoices 1".to_owned(),
+        ] {
+            assert!(
+                body.lines().any(|l| l == expected),
+                "Missing {} in:\n{}",
+                expected,
+                body
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_crawler_policy() {
+        let (store, index) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Unable to load in invoice");
+
+        let api = super::routes::api(
+            store.clone(),
+            index.clone(),
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+        );
+        let res = warp::test::request().path("/robots.txt").reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+
+        let policy: super::CrawlerPolicy = toml::from_str(
+            r#"
+            [[agent]]
+            userAgent = "bot"
+            crawlDelay = 60
+            "#,
+        )
+        .unwrap();
+        let api = super::routes::api_with_options(
+            store.clone(),
+            index,
+            super::auth::NoopAuthenticator,
+            super::authz::AllowAll,
+            super::ApiOptions {
+                crawler_policy: std::sync::Arc::new(policy),
+                ..Default::default()
+            },
+        );
+        let res = warp::test::request().path("/robots.txt").reply(&api).await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        assert_eq!(
+            "User-agent: bot\nCrawl-delay: 60\nAllow: /\n",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let path = format!("/v1/_i/{}", scaffold.invoice.bindle.id);
+        let res = warp::test::request()
+            .path(&path)
+            .header("User-Agent", "CrawlerBot/1.0")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        let res = warp::test::request()
+            .path(&path)
+            .header("User-Agent", "CrawlerBot/1.0")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!("60", res.headers()[warp::http::header::RETRY_AFTER]);
+
+        // Other clients and writes aren't throttled
+        let res = warp::test::request()
+            .path(&path)
+            .header("User-Agent", "bindle-client")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/_i")
+            .header("User-Agent", "CrawlerBot/1.0")
+            .header("Content-Type", "application/toml")
+            .body(toml::to_vec(&scaffold.invoice).unwrap())
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::CONFLICT);
+    }
 }
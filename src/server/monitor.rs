@@ -0,0 +1,328 @@
+//! Warnings for requests that are unusually slow or large.
+//!
+//! A [`RequestMonitor`](RequestMonitor) logs a warning for every request that takes longer or has a
+//! larger body than its [`RequestThresholds`](RequestThresholds) allow. Each warning contains the
+//! operation, bindle ID, status and the measured value as `key=value` pairs, so they are easy to
+//! search for and parse. The number of requests that exceeded each threshold is also counted, which
+//! can be used to spot abusive clients and pathological invoices
+//!
+//! The body size is taken from the `Content-Length` header, so bodies sent with chunked encoding are
+//! not checked
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use warp::http::{header, Method};
+
+const INVOICE_PATH: &str = "/_i/";
+const PARCEL_SEPARATOR: char = '@';
+
+/// The limits above which a request is reported. `None` disables the check
+#[derive(Debug, Clone)]
+pub struct RequestThresholds {
+    /// Requests that take longer than this are reported. Defaults to 5 seconds
+    pub slow_request: Option<Duration>,
+    /// Invoice bodies larger than this many bytes are reported. Defaults to 1 MiB
+    pub large_invoice: Option<u64>,
+    /// Parcel bodies larger than this many bytes are reported. Parcels are often large, so this is
+    /// disabled by default
+    pub large_parcel: Option<u64>,
+}
+
+impl Default for RequestThresholds {
+    fn default() -> Self {
+        RequestThresholds {
+            slow_request: Some(Duration::from_secs(5)),
+            large_invoice: Some(1024 * 1024),
+            large_parcel: None,
+        }
+    }
+}
+
+/// Reports requests that exceed the configured thresholds. Clones share the same counters
+#[derive(Debug, Clone, Default)]
+pub struct RequestMonitor {
+    thresholds: RequestThresholds,
+    slow_requests: Arc<AtomicU64>,
+    large_bodies: Arc<AtomicU64>,
+}
+
+impl RequestMonitor {
+    /// Creates a monitor with the given thresholds
+    pub fn new(thresholds: RequestThresholds) -> Self {
+        RequestMonitor {
+            thresholds,
+            ..RequestMonitor::default()
+        }
+    }
+
+    /// Returns the number of requests that were slower than the threshold
+    pub fn slow_requests(&self) -> u64 {
+        self.slow_requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that had a body larger than the threshold
+    pub fn large_bodies(&self) -> u64 {
+        self.large_bodies.load(Ordering::Relaxed)
+    }
+
+    /// Returns a filter that checks every request it wraps against the thresholds. It is meant to
+    /// be used with [`Filter::with`](warp::Filter::with)
+    pub fn filter(&self) -> warp::log::Log<impl Fn(warp::log::Info) + Clone> {
+        let monitor = self.clone();
+        warp::log::custom(move |info| {
+            let content_length = info
+                .request_headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            monitor.check(
+                info.method(),
+                info.path(),
+                info.status().as_u16(),
+                info.elapsed(),
+                content_length,
+            )
+        })
+    }
+
+    fn check(
+        &self,
+        method: &Method,
+        path: &str,
+        status: u16,
+        elapsed: Duration,
+        content_length: Option<u64>,
+    ) {
+        let (operation, bindle_id) = operation(method, path);
+        let bindle_id = bindle_id.unwrap_or("-");
+        if matches!(self.thresholds.slow_request, Some(max) if elapsed > max) {
+            self.slow_requests.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Slow request: operation={} bindle={} status={} elapsed_ms={}",
+                operation,
+                bindle_id,
+                status,
+                elapsed.as_millis()
+            );
+        }
+        let threshold = match operation {
+            "create_invoice" => self.thresholds.large_invoice,
+            "create_parcel" | "append_upload" => self.thresholds.large_parcel,
+            _ => None,
+        };
+        if let (Some(max), Some(size)) = (threshold, content_length) {
+            if size > max {
+                self.large_bodies.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Large request body: operation={} bindle={} status={} size={}",
+                    operation, bindle_id, status, size
+                );
+            }
+        }
+    }
+}
+
+/// Returns the name of the API operation for a request along with the bindle ID, if the path
+/// contains one
+pub(super) fn operation<'a>(method: &Method, path: &'a str) -> (&'static str, Option<&'a str>) {
+    // The API can be mounted under any prefix, so look for the endpoint anywhere in the path
+    if let Some(index) = path.find(INVOICE_PATH) {
+        let tail = &path[index + INVOICE_PATH.len()..];
+        if let Some((bindle_id, _)) = split_once(tail, PARCEL_SEPARATOR) {
+            let op = match *method {
+                Method::POST => "create_parcel",
+                Method::HEAD => "head_parcel",
+                _ => "get_parcel",
+            };
+            return (op, Some(bindle_id));
+        }
+        if let Some(bindle_id) = tail.strip_suffix("/_history") {
+            return ("get_invoice_history", Some(bindle_id));
+        }
+        if let Some(bindle_id) = tail.strip_suffix("/_summary") {
+            return ("get_invoice_summary", Some(bindle_id));
+        }
+        if let Some(bindle_id) = tail.strip_suffix("/_selection") {
+            return ("get_parcel_selection", Some(bindle_id));
+        }
+        let op = match *method {
+            Method::DELETE => "yank_invoice",
+            Method::HEAD => "head_invoice",
+            _ => "get_invoice",
+        };
+        return (op, Some(tail));
+    }
+    if path.ends_with("/_i") {
+        return ("create_invoice", None);
+    }
+    if let Some(index) = path.find("/_u/") {
+        let tail = &path[index + 4..];
+        return match *method {
+            Method::POST => (
+                "start_upload",
+                split_once(tail, PARCEL_SEPARATOR).map(|(id, _)| id),
+            ),
+            Method::PATCH => ("append_upload", None),
+            Method::DELETE => ("cancel_upload", None),
+            _ => ("get_upload", None),
+        };
+    }
+    for (marker, op) in &[
+        ("/_r/missing/", "get_missing"),
+        ("/_r/delta/", "get_delta"),
+        ("/_r/dependencies/", "resolve_dependencies"),
+        ("/_r/versions/", "list_versions"),
+    ] {
+        if let Some(index) = path.find(marker) {
+            return (op, Some(&path[index + marker.len()..]));
+        }
+    }
+    if path.ends_with("/_q") {
+        return ("query", None);
+    }
+    ("other", None)
+}
+
+fn split_once(s: &str, separator: char) -> Option<(&str, &str)> {
+    let index = s.find(separator)?;
+    Some((&s[..index], &s[index + 1..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_operation() {
+        let cases = vec![
+            (
+                Method::GET,
+                "/v1/_i/foo/1.0.0",
+                "get_invoice",
+                Some("foo/1.0.0"),
+            ),
+            (
+                Method::DELETE,
+                "/v1/_i/foo/1.0.0",
+                "yank_invoice",
+                Some("foo/1.0.0"),
+            ),
+            (
+                Method::GET,
+                "/v1/_i/foo/1.0.0/_history",
+                "get_invoice_history",
+                Some("foo/1.0.0"),
+            ),
+            (
+                Method::GET,
+                "/v1/_i/foo/1.0.0/_summary",
+                "get_invoice_summary",
+                Some("foo/1.0.0"),
+            ),
+            (
+                Method::GET,
+                "/v1/_i/foo/1.0.0/_selection",
+                "get_parcel_selection",
+                Some("foo/1.0.0"),
+            ),
+            (
+                Method::POST,
+                "/v1/_i/foo/1.0.0@abc",
+                "create_parcel",
+                Some("foo/1.0.0"),
+            ),
+            (Method::POST, "/registry/v1/_i", "create_invoice", None),
+            (
+                Method::POST,
+                "/v1/_u/foo/1.0.0@abc",
+                "start_upload",
+                Some("foo/1.0.0"),
+            ),
+            (Method::PATCH, "/v1/_u/1234", "append_upload", None),
+            (
+                Method::GET,
+                "/v1/_r/missing/foo/1.0.0",
+                "get_missing",
+                Some("foo/1.0.0"),
+            ),
+            (
+                Method::GET,
+                "/v1/_r/dependencies/foo/1.0.0",
+                "resolve_dependencies",
+                Some("foo/1.0.0"),
+            ),
+            (
+                Method::GET,
+                "/v1/_r/versions/example.com/foo",
+                "list_versions",
+                Some("example.com/foo"),
+            ),
+            (Method::GET, "/v1/_q", "query", None),
+            (Method::GET, "/healthz", "other", None),
+        ];
+        for (method, path, op, id) in cases {
+            assert_eq!((op, id), operation(&method, path), "Path: {}", path);
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let monitor = RequestMonitor::new(RequestThresholds {
+            slow_request: Some(Duration::from_millis(100)),
+            large_invoice: Some(10),
+            large_parcel: None,
+        });
+        let clone = monitor.clone();
+
+        monitor.check(&Method::GET, "/v1/_q", 200, Duration::from_millis(10), None);
+        monitor.check(&Method::GET, "/v1/_q", 200, Duration::from_secs(1), None);
+        assert_eq!(1, clone.slow_requests());
+
+        monitor.check(&Method::POST, "/v1/_i", 201, Duration::default(), Some(5));
+        monitor.check(&Method::POST, "/v1/_i", 201, Duration::default(), Some(50));
+        // Parcels are not checked as there is no threshold
+        monitor.check(
+            &Method::POST,
+            "/v1/_i/foo/1.0.0@abc",
+            200,
+            Duration::default(),
+            Some(5000),
+        );
+        assert_eq!(1, clone.large_bodies());
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        use warp::Filter;
+
+        let (store, index) = crate::testing::setup().await;
+        let monitor = RequestMonitor::new(RequestThresholds {
+            slow_request: Some(Duration::from_secs(0)),
+            large_invoice: Some(1),
+            large_parcel: None,
+        });
+        let api = crate::server::routes::api(
+            store,
+            index,
+            crate::server::auth::NoopAuthenticator,
+            crate::server::authz::AllowAll,
+        )
+        .with(monitor.filter());
+
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .header("Content-Length", 2)
+            .path("/v1/_i")
+            .body("[]")
+            .reply(&api)
+            .await;
+        // The invoice is invalid, but it should still be reported
+        assert_eq!(res.status(), warp::http::StatusCode::BAD_REQUEST);
+        assert_eq!(1, monitor.slow_requests());
+        assert_eq!(1, monitor.large_bodies());
+    }
+}
@@ -0,0 +1,503 @@
+//! Post-processing of new bindles in the background, such as checking signatures, extracting an
+//! SBOM or scanning parcels, so that heavy work doesn't hold up the requests creating them.
+//!
+//! A [`Pipeline`](Pipeline) runs its [`Processor`](Processor)s one after another once a bindle is
+//! complete: right after its invoice is created if all of its parcels already exist, otherwise
+//! once the last missing parcel is uploaded. Every step runs even if an earlier one failed. The
+//! progress of each bindle is tracked as a [`ProcessingStatus`](crate::ProcessingStatus), which
+//! clients get from `GET /_i/{id}/_status`. Statuses are only kept in memory, for the most recent
+//! bindles, so they are lost when the server restarts.
+//!
+//! Processing is enabled by setting [`ApiOptions::processing`](super::ApiOptions::processing):
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use bindle::server::processing::{ParcelDigestCheck, Pipeline, SignatureCheck};
+//! use bindle::server::ApiOptions;
+//! use bindle::signature::KeyRing;
+//!
+//! let options = ApiOptions {
+//!     processing: Some(
+//!         Pipeline::default()
+//!             .with_processor(SignatureCheck::new(Arc::new(KeyRing::default())))
+//!             .with_processor(ParcelDigestCheck),
+//!     ),
+//!     ..Default::default()
+//! };
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+
+use futures::FutureExt;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use tokio::stream::StreamExt;
+use tokio::sync::Semaphore;
+
+use crate::provider::{Provider, ProviderError};
+use crate::signature::{KeyRing, RevocationList};
+use crate::tasks::TaskRegistry;
+use crate::{Id, Invoice, ProcessingState, ProcessingStatus, ProcessingStep};
+
+/// The number of bindles whose statuses are kept. The statuses of the oldest bindles are dropped
+/// first
+const MAX_STATUSES: usize = 10_000;
+
+/// A step of a [`Pipeline`](Pipeline). Processors run in a background task, so they can take as
+/// long as they need
+#[async_trait::async_trait]
+pub trait Processor: Send + Sync {
+    /// The name the step is listed under in the status of a bindle
+    fn name(&self) -> &str;
+
+    /// Processes a complete bindle, returning a short message about the result, such as what was
+    /// found. An error fails the step, and its message is shown in the status
+    async fn process(
+        &self,
+        invoice: &Invoice,
+        parcels: &dyn ParcelSource,
+    ) -> anyhow::Result<String>;
+}
+
+/// Gives processors access to the data of the parcels of the bindle being processed
+#[async_trait::async_trait]
+pub trait ParcelSource: Send + Sync {
+    /// Reads the whole parcel with the given SHA into memory
+    async fn read_parcel(&self, sha: &str) -> Result<Vec<u8>, ProviderError>;
+}
+
+struct StoreParcels<P> {
+    store: P,
+    bindle_id: Id,
+}
+
+#[async_trait::async_trait]
+impl<P: Provider + Send + Sync> ParcelSource for StoreParcels<P> {
+    async fn read_parcel(&self, sha: &str) -> Result<Vec<u8>, ProviderError> {
+        let mut stream = self.store.get_parcel(&self.bindle_id, sha).await?;
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
+}
+
+/// Runs processors on new bindles in the background and tracks their status. Clones share the same
+/// processors and statuses
+#[derive(Clone)]
+pub struct Pipeline {
+    processors: Vec<Arc<dyn Processor>>,
+    statuses: Arc<Mutex<Statuses>>,
+    permits: Arc<Semaphore>,
+    tasks: TaskRegistry,
+}
+
+#[derive(Default)]
+struct Statuses {
+    by_id: HashMap<String, Tracked>,
+    order: VecDeque<String>,
+}
+
+struct Tracked {
+    status: ProcessingStatus,
+    /// Whether processing was started, as it stays pending until the pipeline has a free slot
+    started: bool,
+}
+
+impl std::fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline")
+            .field(
+                "processors",
+                &self.processors.iter().map(|p| p.name()).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new(Pipeline::DEFAULT_CONCURRENCY)
+    }
+}
+
+impl Pipeline {
+    /// The number of bindles processed at the same time by default
+    pub const DEFAULT_CONCURRENCY: usize = 2;
+
+    /// Creates a pipeline without processors that processes up to the given number of bindles at
+    /// the same time. Other bindles wait until one of them is done
+    pub fn new(concurrency: usize) -> Self {
+        Pipeline {
+            processors: Vec::new(),
+            statuses: Arc::new(Mutex::new(Statuses::default())),
+            permits: Arc::new(Semaphore::new(std::cmp::max(concurrency, 1))),
+            tasks: TaskRegistry::default(),
+        }
+    }
+
+    /// Runs the processing with the given registry, so bindles that are being processed get a
+    /// chance to finish when the server shuts down
+    pub fn with_tasks(mut self, tasks: TaskRegistry) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    /// Adds a processor that runs after the ones already added
+    pub fn with_processor(mut self, processor: impl Processor + 'static) -> Self {
+        self.processors.push(Arc::new(processor));
+        self
+    }
+
+    /// Returns the processing status of the bindle with the given ID, if it was created since the
+    /// server started and is still among the most recent bindles
+    pub fn status(&self, id: &Id) -> Option<ProcessingStatus> {
+        self.statuses
+            .lock()
+            .unwrap()
+            .by_id
+            .get(&id.to_string())
+            .map(|t| t.status.clone())
+    }
+
+    /// Starts tracking a newly created invoice. Processing starts right away if no parcels are
+    /// missing, otherwise it waits for [`parcel_created`](Pipeline::parcel_created)
+    pub(crate) fn invoice_created<P>(&self, invoice: &Invoice, missing: usize, store: P)
+    where
+        P: Provider + Clone + Send + Sync + 'static,
+    {
+        let key = invoice.bindle.id.to_string();
+        let status = ProcessingStatus {
+            state: ProcessingState::Pending,
+            created_at: now(),
+            finished_at: None,
+            step: self
+                .processors
+                .iter()
+                .map(|p| ProcessingStep {
+                    name: p.name().to_owned(),
+                    state: ProcessingState::Pending,
+                    message: None,
+                })
+                .collect(),
+        };
+        {
+            let mut statuses = self.statuses.lock().unwrap();
+            let tracked = Tracked {
+                status,
+                started: false,
+            };
+            if statuses.by_id.insert(key.clone(), tracked).is_none() {
+                statuses.order.push_back(key);
+            }
+            while statuses.order.len() > MAX_STATUSES {
+                if let Some(oldest) = statuses.order.pop_front() {
+                    statuses.by_id.remove(&oldest);
+                }
+            }
+        }
+        if missing == 0 {
+            self.start(invoice.clone(), store);
+        } else {
+            debug!(
+                "Processing of {} waits for {} missing parcels",
+                invoice.bindle.id, missing
+            );
+        }
+    }
+
+    /// Starts processing the bindle with the given ID if it was waiting for parcels and all of
+    /// them exist now
+    pub(crate) fn parcel_created<P>(&self, bindle_id: &str, store: P)
+    where
+        P: Provider + Clone + Send + Sync + 'static,
+    {
+        let id: Id = match bindle_id.parse() {
+            Ok(id) => id,
+            Err(_) => return,
+        };
+        let waiting = matches!(
+            self.statuses.lock().unwrap().by_id.get(&id.to_string()),
+            Some(t) if !t.started
+        );
+        if !waiting {
+            return;
+        }
+        let pipeline = self.clone();
+        let name = format!("processing check of {}", id);
+        self.tasks.spawn(name, async move {
+            let invoice = match store.get_invoice(&id).await {
+                Ok(inv) => inv,
+                Err(e) => {
+                    debug!("Unable to load invoice {} for processing: {}", id, e);
+                    return;
+                }
+            };
+            for parcel in invoice.parcel.iter().flatten() {
+                match store.parcel_exists(&id, &parcel.label.sha256).await {
+                    Ok(true) => (),
+                    Ok(false) => return,
+                    Err(e) => {
+                        debug!("Unable to check parcels of {} for processing: {}", id, e);
+                        return;
+                    }
+                }
+            }
+            pipeline.start(invoice, store);
+        });
+    }
+
+    /// Spawns the processing of a complete bindle, unless it already started. This is checked
+    /// under the lock, so concurrent uploads of the last parcels only start it once
+    fn start<P>(&self, invoice: Invoice, store: P)
+    where
+        P: Provider + Clone + Send + Sync + 'static,
+    {
+        let key = invoice.bindle.id.to_string();
+        let start = match self.statuses.lock().unwrap().by_id.get_mut(&key) {
+            Some(t) if !t.started => {
+                t.started = true;
+                true
+            }
+            _ => false,
+        };
+        if !start {
+            return;
+        }
+        let name = format!("processing of {}", key);
+        self.tasks
+            .spawn(name, self.clone().run(key, invoice, store));
+    }
+
+    async fn run<P>(self, key: String, invoice: Invoice, store: P)
+    where
+        P: Provider + Clone + Send + Sync + 'static,
+    {
+        let _permit = self.permits.acquire().await;
+        debug!("Processing {}", invoice.bindle.id);
+        self.update(&key, |status| status.state = ProcessingState::Running);
+        let invoice = Arc::new(invoice);
+        let parcels: Arc<dyn ParcelSource> = Arc::new(StoreParcels {
+            store,
+            bindle_id: invoice.bindle.id.clone(),
+        });
+        let mut failed = false;
+        for (i, processor) in self.processors.iter().enumerate() {
+            self.update(&key, |status| {
+                status.step[i].state = ProcessingState::Running
+            });
+            let (processor, inv, parcels) = (processor.clone(), invoice.clone(), parcels.clone());
+            // Panics are caught per step, so a panicking processor only fails its step
+            let res =
+                AssertUnwindSafe(async move { processor.process(&inv, parcels.as_ref()).await })
+                    .catch_unwind()
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Processor panicked")));
+            let (state, message) = match res {
+                Ok(message) => (ProcessingState::Succeeded, message),
+                Err(e) => {
+                    warn!(
+                        "Processing step {} failed for {}: {:#}",
+                        self.processors[i].name(),
+                        invoice.bindle.id,
+                        e
+                    );
+                    failed = true;
+                    (ProcessingState::Failed, format!("{:#}", e))
+                }
+            };
+            self.update(&key, |status| {
+                status.step[i].state = state;
+                status.step[i].message = Some(message).filter(|m| !m.is_empty());
+            });
+        }
+        self.update(&key, |status| {
+            status.state = if failed {
+                ProcessingState::Failed
+            } else {
+                ProcessingState::Succeeded
+            };
+            status.finished_at = Some(now());
+        });
+        debug!("Finished processing {}", invoice.bindle.id);
+    }
+
+    /// Changes the status of the bindle, if it is still tracked
+    fn update(&self, key: &str, f: impl FnOnce(&mut ProcessingStatus)) {
+        if let Some(t) = self.statuses.lock().unwrap().by_id.get_mut(key) {
+            f(&mut t.status)
+        }
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Checks the signatures of new invoices against a keyring. The step fails if any signature is
+/// invalid or none of them is trusted, and lists the roles with trusted signatures otherwise. This
+/// is useful for flagging bindles on servers that accept invoices without verifying them
+pub struct SignatureCheck {
+    keyring: Arc<KeyRing>,
+    revocations: RevocationList,
+}
+
+impl SignatureCheck {
+    pub fn new(keyring: Arc<KeyRing>) -> Self {
+        SignatureCheck {
+            keyring,
+            revocations: RevocationList::default(),
+        }
+    }
+
+    /// Fails the check for signatures made with keys from the revocation list
+    pub fn with_revocations(mut self, revocations: RevocationList) -> Self {
+        self.revocations = revocations;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Processor for SignatureCheck {
+    fn name(&self) -> &str {
+        "signatures"
+    }
+
+    async fn process(&self, invoice: &Invoice, _: &dyn ParcelSource) -> anyhow::Result<String> {
+        invoice.verify(&self.keyring, &self.revocations)?;
+        let roles: Vec<String> = invoice
+            .trusted_roles(&self.keyring)
+            .iter()
+            .map(|r| r.to_string())
+            .collect();
+        Ok(format!("Trusted signatures in roles: {}", roles.join(", ")))
+    }
+}
+
+/// Reads every parcel of new bindles and checks it against the SHA-256 and size of its label, to
+/// catch data that was corrupted after it was uploaded
+pub struct ParcelDigestCheck;
+
+#[async_trait::async_trait]
+impl Processor for ParcelDigestCheck {
+    fn name(&self) -> &str {
+        "parcel-digests"
+    }
+
+    async fn process(
+        &self,
+        invoice: &Invoice,
+        parcels: &dyn ParcelSource,
+    ) -> anyhow::Result<String> {
+        let labels: Vec<_> = invoice.parcel.iter().flatten().map(|p| &p.label).collect();
+        for label in &labels {
+            let data = parcels.read_parcel(&label.sha256).await?;
+            let actual = format!("{:x}", Sha256::digest(&data));
+            if actual != label.sha256 {
+                anyhow::bail!("Parcel {} has a SHA-256 of {}", label.sha256, actual);
+            }
+            if data.len() as u64 != label.size {
+                anyhow::bail!(
+                    "Parcel {} is {} bytes instead of {}",
+                    label.sha256,
+                    data.len(),
+                    label.size
+                );
+            }
+        }
+        Ok(format!("{} parcels verified", labels.len()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::testing;
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    struct Fail;
+
+    #[async_trait::async_trait]
+    impl Processor for Fail {
+        fn name(&self) -> &str {
+            "fail"
+        }
+
+        async fn process(&self, _: &Invoice, _: &dyn ParcelSource) -> anyhow::Result<String> {
+            anyhow::bail!("Nope")
+        }
+    }
+
+    async fn wait_for(pipeline: &Pipeline, id: &Id) -> ProcessingStatus {
+        for _ in 0..100 {
+            match pipeline.status(id) {
+                Some(s) if s.state.is_finished() => return s,
+                _ => tokio::time::delay_for(std::time::Duration::from_millis(20)).await,
+            }
+        }
+        panic!("Processing of {} didn't finish", id)
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_waits_for_parcels() {
+        let (store, _) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let id = scaffold.invoice.bindle.id.clone();
+        let pipeline = Pipeline::default()
+            .with_processor(ParcelDigestCheck)
+            .with_processor(Fail);
+
+        let missing = store
+            .create_invoice(&scaffold.invoice)
+            .await
+            .expect("Invoice should be created");
+        pipeline.invoice_created(&scaffold.invoice, missing.len(), store.clone());
+        let status = pipeline.status(&id).expect("Status should be tracked");
+        assert_eq!(ProcessingState::Pending, status.state);
+        assert_eq!(
+            vec!["parcel-digests", "fail"],
+            status
+                .step
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        for parcel in scaffold.parcel_files.values() {
+            store
+                .create_parcel(
+                    &id,
+                    &parcel.sha,
+                    FramedRead::new(std::io::Cursor::new(parcel.data.clone()), BytesCodec::new()),
+                )
+                .await
+                .expect("Parcel should be created");
+            pipeline.parcel_created(&id.to_string(), store.clone());
+        }
+
+        let status = wait_for(&pipeline, &id).await;
+        assert_eq!(ProcessingState::Failed, status.state);
+        assert!(status.finished_at.is_some());
+        assert_eq!(ProcessingState::Succeeded, status.step[0].state);
+        assert_eq!(
+            Some(format!("{} parcels verified", scaffold.parcel_files.len())),
+            status.step[0].message
+        );
+        assert_eq!(ProcessingState::Failed, status.step[1].state);
+        assert_eq!(Some("Nope".to_owned()), status.step[1].message);
+        assert!(
+            pipeline
+                .status(&"example.com/other/1.0.0".parse().unwrap())
+                .is_none(),
+            "Untracked bindles shouldn't have a status"
+        );
+    }
+}
@@ -0,0 +1,425 @@
+//! Storage quotas for the whole server and for each user.
+//!
+//! A [`QuotaPolicy`](QuotaPolicy) limits the total size of the parcels uploaded and the number of
+//! invoices created, both for the whole server and for each authenticated user. Policies can be
+//! loaded from a TOML file that looks like this:
+//!
+//! ```toml
+//! [total]
+//! maxBytes = 107374182400
+//! maxBindles = 100000
+//!
+//! [[user]]
+//! identity = "ci"
+//! maxBytes = 10737418240
+//!
+//! [[user]]
+//! identity = "*"
+//! maxBytes = 1073741824
+//! maxBindles = 100
+//! ```
+//!
+//! The first rule whose identity is the name of the user, or `*`, applies. A rule for `*` gives
+//! every user the same quota of their own rather than one shared by all of them. Anonymous requests
+//! only count towards the total. Requests that would exceed a quota are rejected before anything
+//! is stored (see [`reply::quota_exceeded`](super::reply::quota_exceeded)).
+//!
+//! Usage is counted by the server as bindles are created, as stores don't record who created what.
+//! Parcels are counted once, by whoever uploads them first, and yanking or deleting bindles doesn't
+//! give back any of the quota. [`Quotas`](Quotas) can keep the usage in a file, so it survives
+//! restarts
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::auth::Identity;
+use super::authz::WILDCARD;
+use crate::{QuotaExceeded, QuotaReport, QuotaResource, QuotaUsage};
+
+/// Limits on the storage used by a user or by the whole server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct QuotaLimits {
+    /// The maximum total size of the uploaded parcels, in bytes. Unlimited if not set
+    pub max_bytes: Option<u64>,
+    /// The maximum number of invoices created. Unlimited if not set
+    pub max_bindles: Option<u64>,
+}
+
+/// The quota of the users matching an identity
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct QuotaRule {
+    /// The name of the user this rule applies to, or `*` for any authenticated user
+    pub identity: String,
+    /// The maximum total size of the parcels uploaded by each user, in bytes. Unlimited if not set
+    pub max_bytes: Option<u64>,
+    /// The maximum number of invoices created by each user. Unlimited if not set
+    pub max_bindles: Option<u64>,
+}
+
+impl QuotaRule {
+    fn limits(&self) -> QuotaLimits {
+        QuotaLimits {
+            max_bytes: self.max_bytes,
+            max_bindles: self.max_bindles,
+        }
+    }
+}
+
+/// The quota of the whole server and a list of [`QuotaRule`](QuotaRule)s, of which the first one
+/// matching a user applies
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaPolicy {
+    #[serde(default)]
+    pub total: QuotaLimits,
+    #[serde(default)]
+    pub user: Vec<QuotaRule>,
+}
+
+impl QuotaPolicy {
+    /// Loads a policy from the TOML file at the given path
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read(path).await?;
+        Ok(toml::from_slice(&raw)?)
+    }
+
+    /// Returns the limits of the user with the given name, if a rule applies to them
+    pub fn limits_for(&self, user: &str) -> Option<QuotaLimits> {
+        self.user
+            .iter()
+            .find(|r| r.identity == WILDCARD || r.identity == user)
+            .map(QuotaRule::limits)
+    }
+}
+
+/// The storage used by a user or by the whole server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Usage {
+    bytes: u64,
+    bindles: u64,
+}
+
+impl Usage {
+    /// Returns the quota that adding the given amounts would exceed, if any
+    fn check(
+        &self,
+        limits: &QuotaLimits,
+        bytes: u64,
+        bindles: u64,
+        identity: Option<&str>,
+    ) -> Result<(), QuotaExceeded> {
+        let checks = [
+            (QuotaResource::Bytes, limits.max_bytes, self.bytes, bytes),
+            (
+                QuotaResource::Bindles,
+                limits.max_bindles,
+                self.bindles,
+                bindles,
+            ),
+        ];
+        for (resource, limit, used, requested) in checks.iter().copied() {
+            match limit {
+                Some(limit) if requested > 0 && used.saturating_add(requested) > limit => {
+                    return Err(QuotaExceeded {
+                        identity: identity.map(str::to_owned),
+                        resource,
+                        limit,
+                        used,
+                        requested,
+                    })
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, bytes: u64, bindles: u64) {
+        self.bytes = self.bytes.saturating_add(bytes);
+        self.bindles = self.bindles.saturating_add(bindles);
+    }
+
+    fn remove(&mut self, bytes: u64, bindles: u64) {
+        self.bytes = self.bytes.saturating_sub(bytes);
+        self.bindles = self.bindles.saturating_sub(bindles);
+    }
+
+    fn report(&self, identity: Option<&str>, limits: Option<QuotaLimits>) -> QuotaUsage {
+        let limits = limits.unwrap_or_default();
+        QuotaUsage {
+            identity: identity.map(str::to_owned),
+            bytes: self.bytes,
+            bindles: self.bindles,
+            max_bytes: limits.max_bytes,
+            max_bindles: limits.max_bindles,
+        }
+    }
+}
+
+/// The usage of the whole server and of every user, as stored in the usage file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct UsageData {
+    total: Usage,
+    #[serde(default)]
+    user: BTreeMap<String, Usage>,
+}
+
+/// Enforces a [`QuotaPolicy`](QuotaPolicy), keeping track of the storage used. Clones share the
+/// same usage
+#[derive(Debug, Clone)]
+pub struct Quotas {
+    policy: Arc<QuotaPolicy>,
+    usage: Arc<Mutex<UsageData>>,
+    file: Option<Arc<PathBuf>>,
+}
+
+impl Quotas {
+    /// Returns quotas enforcing the given policy, starting without any usage that is only kept in
+    /// memory
+    pub fn new(policy: impl Into<Arc<QuotaPolicy>>) -> Self {
+        Quotas {
+            policy: policy.into(),
+            usage: Arc::default(),
+            file: None,
+        }
+    }
+
+    /// Returns quotas enforcing the given policy that keep the usage in the given TOML file,
+    /// starting with the usage in it if it exists
+    pub async fn load(
+        policy: impl Into<Arc<QuotaPolicy>>,
+        usage_file: impl Into<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        let path = usage_file.into();
+        let usage = match tokio::fs::read(&path).await {
+            Ok(raw) => toml::from_slice(&raw)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => UsageData::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Quotas {
+            policy: policy.into(),
+            usage: Arc::new(Mutex::new(usage)),
+            file: Some(Arc::new(path)),
+        })
+    }
+
+    /// Returns the usage of the whole server and of every user, along with their limits
+    pub fn report(&self) -> QuotaReport {
+        let usage = self.usage.lock().unwrap();
+        QuotaReport {
+            total: usage.total.report(None, Some(self.policy.total)),
+            user: usage
+                .user
+                .iter()
+                .map(|(name, u)| u.report(Some(name), self.policy.limits_for(name)))
+                .collect(),
+        }
+    }
+
+    /// Reserves the given number of bytes and bindles for the identity, or returns the quota it
+    /// would exceed. The reservation is given back when the returned [`Reservation`](Reservation)
+    /// is dropped, unless it is [committed](Reservation::commit) once the data is stored
+    pub(crate) fn reserve(
+        &self,
+        identity: &Identity,
+        bytes: u64,
+        bindles: u64,
+    ) -> Result<Reservation, QuotaExceeded> {
+        let mut usage = self.usage.lock().unwrap();
+        usage
+            .total
+            .check(&self.policy.total, bytes, bindles, None)?;
+        let user = identity.name.clone();
+        if let Some(name) = &user {
+            if let Some(limits) = self.policy.limits_for(name) {
+                usage.user.get(name).copied().unwrap_or_default().check(
+                    &limits,
+                    bytes,
+                    bindles,
+                    Some(name),
+                )?;
+            }
+        }
+        usage.total.add(bytes, bindles);
+        if let Some(name) = &user {
+            usage
+                .user
+                .entry(name.clone())
+                .or_default()
+                .add(bytes, bindles);
+        }
+        Ok(Reservation {
+            quotas: self.clone(),
+            user,
+            bytes,
+            bindles,
+            committed: false,
+        })
+    }
+
+    /// Writes the usage to the usage file, if there is one. Failing to write it only loses the
+    /// usage since the last write when the server restarts, so errors are only logged
+    async fn save(&self) {
+        let path = match &self.file {
+            Some(p) => p,
+            None => return,
+        };
+        let usage = self.usage.lock().unwrap().clone();
+        let raw = match toml::to_vec(&usage) {
+            Ok(raw) => raw,
+            Err(e) => {
+                warn!("Unable to serialize quota usage: {}", e);
+                return;
+            }
+        };
+        // Write to a temporary file first so a failed write doesn't destroy the existing usage
+        let tmp = path.with_extension("tmp");
+        let res = match tokio::fs::write(&tmp, raw).await {
+            Ok(()) => tokio::fs::rename(&tmp, path.as_ref()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            warn!("Unable to save quota usage to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Storage reserved by [`Quotas::reserve`](Quotas::reserve), which is given back when dropped
+/// unless it is committed
+pub(crate) struct Reservation {
+    quotas: Quotas,
+    user: Option<String>,
+    bytes: u64,
+    bindles: u64,
+    committed: bool,
+}
+
+impl Reservation {
+    /// Keeps the reserved storage as used, once the data it was reserved for is stored
+    pub(crate) async fn commit(mut self) {
+        self.committed = true;
+        self.quotas.save().await;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        let mut usage = self.quotas.usage.lock().unwrap();
+        usage.total.remove(self.bytes, self.bindles);
+        if let Some(name) = &self.user {
+            if let Some(u) = usage.user.get_mut(name) {
+                u.remove(self.bytes, self.bindles);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> QuotaPolicy {
+        toml::from_str(
+            r#"
+            [total]
+            maxBindles = 3
+
+            [[user]]
+            identity = "admin"
+
+            [[user]]
+            identity = "*"
+            maxBytes = 100
+            maxBindles = 2
+            "#,
+        )
+        .expect("policy should parse")
+    }
+
+    #[test]
+    fn test_reserve() {
+        let quotas = Quotas::new(policy());
+        let alice = Identity::named("alice");
+
+        quotas
+            .reserve(&alice, 60, 1)
+            .expect("should be within quota")
+            .committed = true;
+        let err = quotas
+            .reserve(&alice, 50, 0)
+            .err()
+            .expect("should exceed the quota of the user");
+        assert_eq!(Some("alice"), err.identity.as_deref());
+        assert_eq!(QuotaResource::Bytes, err.resource);
+        assert_eq!((100, 60, 50), (err.limit, err.used, err.requested));
+
+        // Dropping a reservation gives it back
+        drop(
+            quotas
+                .reserve(&alice, 40, 1)
+                .expect("should be within quota"),
+        );
+        assert_eq!(60, quotas.report().user[0].bytes);
+
+        // Every user has a quota of their own, but they all count towards the total
+        quotas
+            .reserve(&Identity::named("bob"), 100, 1)
+            .unwrap()
+            .committed = true;
+        quotas
+            .reserve(&Identity::named("admin"), 1000, 0)
+            .unwrap()
+            .committed = true;
+        let err = quotas
+            .reserve(&Identity::anonymous(), 0, 2)
+            .err()
+            .expect("should exceed the total quota");
+        assert_eq!(None, err.identity);
+        assert_eq!(QuotaResource::Bindles, err.resource);
+
+        let report = quotas.report();
+        assert_eq!(1160, report.total.bytes);
+        assert_eq!(Some(3), report.total.max_bindles);
+        let names: Vec<_> = report.user.iter().map(|u| u.identity.clone()).collect();
+        assert_eq!(
+            vec![
+                Some("admin".to_owned()),
+                Some("alice".to_owned()),
+                Some("bob".to_owned())
+            ],
+            names
+        );
+        assert_eq!(None, report.user[0].max_bytes);
+        assert_eq!(Some(100), report.user[1].max_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_usage_file() {
+        let dir = tempfile::tempdir().expect("unable to create tempdir");
+        let path = dir.path().join("quota-usage.toml");
+        let policy = Arc::new(policy());
+
+        let quotas = Quotas::load(policy.clone(), &path)
+            .await
+            .expect("should load");
+        quotas
+            .reserve(&Identity::named("alice"), 10, 1)
+            .unwrap()
+            .commit()
+            .await;
+
+        let quotas = Quotas::load(policy, &path).await.expect("should load");
+        let report = quotas.report();
+        assert_eq!((10, 1), (report.total.bytes, report.total.bindles));
+        assert_eq!(10, report.user[0].bytes);
+    }
+}
@@ -5,6 +5,7 @@ use warp::Reply;
 
 use super::TOML_MIME_TYPE;
 use crate::provider::ProviderError;
+use crate::signature::{SignatureError, VerificationStrategy};
 
 // Borrowed and modified from https://docs.rs/warp/0.2.5/src/warp/reply.rs.html#102
 pub fn toml<T>(val: &T) -> Toml
@@ -58,10 +59,12 @@ pub fn into_reply(error: ProviderError) -> warp::reply::WithStatus<Toml> {
         ProviderError::Exists => StatusCode::CONFLICT,
         ProviderError::Malformed(_)
         | ProviderError::Unserializable(_)
-        | ProviderError::DigestMismatch
+        | ProviderError::DigestMismatch { .. }
+        | ProviderError::SizeMismatch { .. }
+        | ProviderError::InvalidSignature(_)
         | ProviderError::InvalidId => StatusCode::BAD_REQUEST,
-        ProviderError::Yanked => StatusCode::FORBIDDEN,
-        #[cfg(feature = "caching")]
+        ProviderError::Yanked | ProviderError::WriteOnce(_) => StatusCode::FORBIDDEN,
+        #[cfg(feature = "client")]
         ProviderError::ProxyError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         ProviderError::Other(_) | ProviderError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
     };
@@ -78,6 +81,74 @@ pub fn reply_from_error(
     warp::reply::with_status(
         toml(&crate::ErrorResponse {
             error: error.to_string(),
+            verification: None,
+            signing_policy: None,
+            quota: None,
+        }),
+        status_code,
+    )
+}
+
+/// Builds a reply for an invoice that failed signature verification, including the structured
+/// [`VerificationFailure`](crate::VerificationFailure) so clients can tell what is missing
+pub fn verification_failure(
+    strategy: VerificationStrategy,
+    error: SignatureError,
+) -> warp::reply::WithStatus<Toml> {
+    let reason = error.to_string();
+    warp::reply::with_status(
+        toml(&crate::ErrorResponse {
+            error: format!("Invoice failed signature verification: {}", reason),
+            verification: Some(crate::VerificationFailure { strategy, reason }),
+            signing_policy: None,
+            quota: None,
+        }),
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+/// Builds a reply for an invoice that doesn't satisfy an enforced signing policy, including the
+/// [`SigningPolicyResult`](crate::SigningPolicyResult) so clients can tell which signatures are
+/// still needed
+pub fn signing_policy_failure(result: crate::SigningPolicyResult) -> warp::reply::WithStatus<Toml> {
+    let missing: Vec<String> = result.missing.iter().map(|r| r.to_string()).collect();
+    warp::reply::with_status(
+        toml(&crate::ErrorResponse {
+            error: format!(
+                "Invoice does not satisfy the signing policy for {}, it is missing trusted signatures in the roles: {}",
+                result.bindles,
+                missing.join(", ")
+            ),
+            verification: None,
+            signing_policy: Some(result),
+            quota: None,
+        }),
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+/// Builds a reply for a request that would exceed a storage quota, including the
+/// [`QuotaExceeded`](crate::QuotaExceeded) so clients can tell which quota it is. Requests that
+/// would store too many bytes are rejected with `413 Payload Too Large`, and requests that would
+/// create too many bindles with `429 Too Many Requests`
+pub fn quota_exceeded(quota: crate::QuotaExceeded) -> warp::reply::WithStatus<Toml> {
+    let whose = match &quota.identity {
+        Some(identity) => format!("the quota of {}", identity),
+        None => "the quota of the server".to_owned(),
+    };
+    let status_code = match quota.resource {
+        crate::QuotaResource::Bytes => StatusCode::PAYLOAD_TOO_LARGE,
+        crate::QuotaResource::Bindles => StatusCode::TOO_MANY_REQUESTS,
+    };
+    warp::reply::with_status(
+        toml(&crate::ErrorResponse {
+            error: format!(
+                "Request would exceed {} of {} {}, of which {} are used",
+                whose, quota.limit, quota.resource, quota.used
+            ),
+            verification: None,
+            signing_policy: None,
+            quota: Some(quota),
         }),
         status_code,
     )
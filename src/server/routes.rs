@@ -1,145 +1,775 @@
 use warp::Filter;
 
+use crate::server::auth::{self, Authenticator};
+use crate::server::authz::Authorizer;
+use crate::server::crawlers::{self, Throttle};
+use crate::server::filters;
+use crate::server::keyrings::KeyRingStore;
+use crate::server::uploads::UploadStore;
+use crate::server::ApiOptions;
+
 /// A helper function that aggregates all routes into a complete API filter. If you only wish to
 /// serve specific endpoints or versions, you can assemble them with the individual submodules
-pub fn api<P, I>(
+pub fn api<P, I, A, Z>(
     store: P,
     index: I,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    authenticator: A,
+    authorizer: Z,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
     P: crate::provider::Provider + Clone + Send + Sync + 'static,
     I: crate::search::Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
 {
-    warp::path("v1").and(
-        v1::invoice::query(index)
-            .or(v1::invoice::create(store.clone()))
-            .or(v1::invoice::get(store.clone()))
-            .or(v1::invoice::head(store.clone()))
-            .or(v1::invoice::yank(store.clone()))
-            .or(v1::parcel::create(store.clone()))
-            .or(v1::relationships::get_missing_parcels(store)),
+    api_with_options(
+        store,
+        index,
+        authenticator,
+        authorizer,
+        ApiOptions::default(),
     )
 }
 
+/// The same as [`api`](api), but with the optional features enabled by the given
+/// [`ApiOptions`](ApiOptions)
+pub fn api_with_options<P, I, A, Z>(
+    store: P,
+    index: I,
+    authenticator: A,
+    authorizer: Z,
+    options: ApiOptions,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+where
+    P: crate::provider::Provider + Clone + Send + Sync + 'static,
+    I: crate::search::Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+{
+    let uploads = UploadStore::default();
+    let keyrings = options.keyring_dir.clone().map(KeyRingStore::new);
+    let endpoints = v1::invoice::query(index.clone(), authenticator.clone())
+        .or(v1::invoice::create(
+            store.clone(),
+            authenticator.clone(),
+            authorizer.clone(),
+            options.clone(),
+        ))
+        .or(v1::invoice::get(
+            store.clone(),
+            authenticator.clone(),
+            options.clone(),
+        ))
+        .or(v1::invoice::head(
+            store.clone(),
+            authenticator.clone(),
+            options.clone(),
+        ))
+        .or(v1::invoice::yank(
+            store.clone(),
+            authenticator.clone(),
+            authorizer.clone(),
+        ))
+        .or(v1::invoice::sign(
+            store.clone(),
+            authenticator.clone(),
+            authorizer.clone(),
+            options.transparency_log.clone(),
+        ))
+        .or(v1::parcel::create(
+            store.clone(),
+            authenticator.clone(),
+            authorizer.clone(),
+            options.clone(),
+        ))
+        .or(v1::parcel::missing(store.clone(), authenticator.clone()))
+        .or(v1::relationships::get_missing_parcels(
+            store.clone(),
+            authenticator.clone(),
+        ))
+        .or(v1::relationships::get_parcel_delta(
+            store.clone(),
+            authenticator.clone(),
+        ))
+        .or(v1::relationships::get_dependencies(
+            store.clone(),
+            index.clone(),
+            authenticator.clone(),
+        ))
+        .or(v1::relationships::get_versions(
+            index.clone(),
+            authenticator.clone(),
+        ))
+        .or(v1::parcel::invoices(index, authenticator.clone()))
+        .or(v1::upload::start(
+            store.clone(),
+            uploads.clone(),
+            authenticator.clone(),
+            authorizer.clone(),
+            options.quotas.clone(),
+        ))
+        .or(v1::upload::status(uploads.clone(), authenticator.clone()))
+        .or(v1::upload::append(
+            store.clone(),
+            uploads.clone(),
+            authenticator.clone(),
+            authorizer.clone(),
+            options.clone(),
+        ))
+        .or(v1::upload::cancel(
+            uploads,
+            authenticator.clone(),
+            authorizer.clone(),
+        ))
+        .or(v1::gc::collect(
+            store,
+            authenticator.clone(),
+            authorizer.clone(),
+        ))
+        .or(v1::quota::get(
+            options.quotas.clone(),
+            authenticator.clone(),
+            authorizer.clone(),
+        ))
+        .or(v1::keyring::get(keyrings.clone(), authenticator.clone()))
+        .or(v1::keyring::put(keyrings, authenticator.clone()))
+        .or(v1::capabilities::get(authenticator.clone()));
+    // Replication needs a client to talk to the primary
+    #[cfg(feature = "client")]
+    let endpoints = endpoints
+        .or(v1::replication::status(
+            options.replication.clone(),
+            authenticator.clone(),
+            authorizer.clone(),
+        ))
+        .or(v1::replication::notify(options.replication.clone()));
+    // Crawlers are throttled before any endpoint does work for them
+    let routes = warp::path("v1")
+        .and(crawlers::throttle(Throttle::new(
+            options.crawler_policy.clone(),
+        )))
+        .and(endpoints)
+        .recover(auth::handle_auth_rejection)
+        .recover(crawlers::handle_throttle_rejection);
+    // Metrics are served in their own format, so they skip content negotiation. Recording is
+    // skipped as well if no metrics are configured
+    let metrics = options.metrics;
+    filters::negotiate(routes)
+        .or(metrics::get(metrics.clone(), authenticator, authorizer)
+            .recover(auth::handle_auth_rejection))
+        .or(robots::get(options.crawler_policy))
+        .with(warp::log::custom(move |info| {
+            if let Some(m) = &metrics {
+                m.record(&info)
+            }
+        }))
+        .with(warp::trace(filters::request_span))
+}
+
+/// The metrics endpoint, which is served outside of the versioned API as Prometheus expects
+pub mod metrics {
+    use crate::server::auth::{authenticate, Access, Authenticator};
+    use crate::server::authz::{with_authorizer, Authorizer};
+    use crate::server::handlers::v1::get_metrics;
+    use crate::server::Metrics;
+
+    use warp::Filter;
+
+    pub fn get<A, Z>(
+        metrics: Option<Metrics>,
+        authenticator: A,
+        authorizer: Z,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+    where
+        A: Authenticator + Clone + Send + Sync + 'static,
+        Z: Authorizer + Clone + Send + Sync + 'static,
+    {
+        warp::path("metrics")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and(authenticate(authenticator, Access::Read))
+            .and(with_authorizer(authorizer))
+            .and(warp::any().map(move || metrics.clone()))
+            .and_then(get_metrics)
+    }
+}
+
+/// The `robots.txt`, which is served at the root like the metrics
+pub mod robots {
+    use std::sync::Arc;
+
+    use crate::server::crawlers::CrawlerPolicy;
+
+    use warp::Filter;
+
+    /// Serves the `robots.txt` of the given policy, if it has one
+    pub fn get(
+        policy: Arc<CrawlerPolicy>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path("robots.txt")
+            .and(warp::path::end())
+            .and(warp::get())
+            .and_then(move || {
+                let robots = policy.robots_txt();
+                async move {
+                    robots
+                        .map(|r| {
+                            warp::reply::with_header(
+                                r,
+                                warp::http::header::CONTENT_TYPE,
+                                "text/plain; charset=utf-8",
+                            )
+                        })
+                        .ok_or_else(warp::reject::not_found)
+                }
+            })
+    }
+}
+
 pub mod v1 {
     use crate::provider::Provider;
     use crate::search::Search;
+    use crate::server::auth::{authenticate, require, Access, Authenticator};
+    use crate::server::authz::{with_authorizer, Authorizer};
     use crate::server::handlers::v1::*;
-    use crate::server::{filters, routes::with_store};
+    use crate::server::{filters, routes::with_store, ApiOptions, Quotas};
+    use crate::transparency::TransparencyLog;
+
+    use std::sync::Arc;
 
     use warp::Filter;
 
     pub mod invoice {
         use super::*;
 
-        pub fn query<S>(
+        pub fn query<S, A>(
             index: S,
+            authenticator: A,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            S: Search + Clone + Send + Sync,
+            S: Search + Clone + Send + Sync + 'static,
+            A: Authenticator + Clone + Send + Sync + 'static,
         {
             warp::path("_q")
                 .and(warp::get())
+                .and(require(authenticator, Access::Read))
                 .and(warp::query::<crate::QueryOptions>())
+                .and(warp::header::optional::<String>("accept"))
                 .and(warp::any().map(move || index.clone()))
                 .and_then(query_invoices)
         }
 
-        pub fn create<P>(
+        /// Creates invoices, verifying and signing them as configured in the options
+        pub fn create<P, A, Z>(
             store: P,
+            authenticator: A,
+            authorizer: Z,
+            options: ApiOptions,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
         {
             warp::path("_i")
                 .and(warp::path::end())
                 .and(warp::post())
+                .and(authenticate(authenticator, Access::Write))
+                .and(with_authorizer(authorizer))
                 .and(with_store(store))
-                .and(filters::toml())
+                .and(warp::any().map(move || options.clone()))
+                .and(filters::body())
                 .and_then(create_invoice)
                 .recover(filters::handle_deserialize_rejection)
         }
 
         // The GET and HEAD endpoints handle both parcels and invoices through the request router function
-        pub fn get<P>(
+        pub fn get<P, A>(
             store: P,
+            authenticator: A,
+            options: ApiOptions,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
             P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
         {
             warp::path("_i")
                 .and(warp::path::tail())
                 .and(warp::get())
+                .and(require(authenticator, Access::Read))
                 .and(warp::query::<filters::InvoiceQuery>())
                 .and(with_store(store))
                 .and(warp::method())
+                .and(warp::header::optional::<String>("range"))
+                .and(warp::any().map(move || options.clone()))
                 .and_then(request_router)
         }
 
-        pub fn head<P>(
+        pub fn head<P, A>(
             store: P,
+            authenticator: A,
+            options: ApiOptions,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
             P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
         {
             warp::path("_i")
                 .and(warp::path::tail())
                 .and(warp::head())
+                .and(require(authenticator, Access::Read))
                 .and(warp::query::<filters::InvoiceQuery>())
                 .and(with_store(store))
                 .and(warp::method())
+                .and(warp::header::optional::<String>("range"))
+                .and(warp::any().map(move || options.clone()))
                 .and_then(request_router)
         }
 
-        pub fn yank<P>(
+        pub fn yank<P, A, Z>(
             store: P,
+            authenticator: A,
+            authorizer: Z,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
             P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
         {
             warp::path("_i")
                 .and(warp::path::tail())
                 .and(warp::delete())
+                .and(authenticate(authenticator, Access::Write))
+                .and(with_authorizer(authorizer))
+                .and(warp::query::<filters::DeleteQuery>())
                 .and(with_store(store))
                 .and_then(yank_invoice)
         }
+
+        /// Adds signatures to stored invoices. Parcels are created with a POST to the same path
+        /// prefix, so this only matches paths ending with the `_signature` subresource
+        pub fn sign<P, A, Z>(
+            store: P,
+            authenticator: A,
+            authorizer: Z,
+            transparency_log: Option<Arc<dyn TransparencyLog + Send + Sync>>,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
+        {
+            warp::path("_i")
+                .and(warp::path::tail())
+                .and(warp::post())
+                .and_then(|tail: warp::path::Tail| async move {
+                    if tail.as_str().ends_with("/_signature") {
+                        Ok(tail)
+                    } else {
+                        Err(warp::reject::not_found())
+                    }
+                })
+                .and(authenticate(authenticator, Access::Write))
+                .and(with_authorizer(authorizer))
+                .and(with_store(store))
+                .and(warp::any().map(move || transparency_log.clone()))
+                .and(filters::body())
+                .and_then(add_signature)
+                .recover(filters::handle_deserialize_rejection)
+        }
     }
 
     pub mod parcel {
         use super::*;
 
-        pub fn create<P>(
+        /// Creates parcels, counting the received data in the metrics if configured in the
+        /// options. Bindles waiting for their parcels are processed by the pipeline, if configured,
+        /// once they are complete. Parcels that would exceed the quotas, if configured, are
+        /// rejected
+        pub fn create<P, A, Z>(
             store: P,
+            authenticator: A,
+            authorizer: Z,
+            options: ApiOptions,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
-            P: Provider + Clone + Send + Sync,
+            P: Provider + Clone + Send + Sync + 'static,
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
         {
             warp::path("_i")
                 .and(warp::path::tail())
                 .and(warp::post())
+                .and(authenticate(authenticator, Access::Write))
+                .and(with_authorizer(authorizer))
+                .and(warp::header::optional::<String>("if-match"))
                 .and(warp::body::stream())
                 .and(with_store(store))
+                .and(warp::any().map(move || options.clone()))
                 .and_then(create_parcel)
         }
+
+        /// Checks which of a batch of parcel SHAs aren't stored for any bindle. This reveals whether
+        /// data exists on the server at all, so it requires the same access as uploading
+        pub fn missing<P, A>(
+            store: P,
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            warp::path("_b")
+                .and(warp::path("_missing"))
+                .and(warp::path::end())
+                .and(warp::post())
+                .and(require(authenticator, Access::Write))
+                .and(with_store(store))
+                .and(filters::body())
+                .and_then(get_missing_shas)
+                .recover(filters::handle_deserialize_rejection)
+        }
+
+        /// Lists the invoices containing a parcel, as found by the search index
+        pub fn invoices<S, A>(
+            index: S,
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            S: Search + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            warp::path("_b")
+                .and(warp::path::param::<String>())
+                .and(warp::path("_invoices"))
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(require(authenticator, Access::Read))
+                .and(warp::any().map(move || index.clone()))
+                .and_then(find_invoices_by_parcel)
+        }
+    }
+
+    pub mod upload {
+        use super::*;
+
+        use crate::server::uploads::UploadStore;
+
+        pub(crate) fn start<P, A, Z>(
+            store: P,
+            uploads: UploadStore,
+            authenticator: A,
+            authorizer: Z,
+            quotas: Option<Quotas>,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
+        {
+            warp::path("_u")
+                .and(warp::path::tail())
+                .and(warp::post())
+                .and(authenticate(authenticator, Access::Write))
+                .and(with_authorizer(authorizer))
+                .and(warp::header::optional::<String>("if-match"))
+                .and(with_uploads(uploads))
+                .and(with_store(store))
+                .and(warp::any().map(move || quotas.clone()))
+                .and_then(start_upload)
+        }
+
+        pub(crate) fn status<A>(
+            uploads: UploadStore,
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            warp::path("_u")
+                .and(warp::path::param::<String>())
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(require(authenticator, Access::Write))
+                .and(with_uploads(uploads))
+                .and_then(get_upload)
+        }
+
+        pub(crate) fn append<P, A, Z>(
+            store: P,
+            uploads: UploadStore,
+            authenticator: A,
+            authorizer: Z,
+            options: ApiOptions,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync + 'static,
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
+        {
+            warp::path("_u")
+                .and(warp::path::param::<String>())
+                .and(warp::path::end())
+                .and(warp::patch())
+                .and(warp::header::<u64>("upload-offset"))
+                .map(|id, offset| (id, offset))
+                .and(authenticate(authenticator, Access::Write))
+                .and(with_authorizer(authorizer))
+                .and(warp::body::stream())
+                .and(with_uploads(uploads))
+                .and(with_store(store))
+                .and(warp::any().map(move || options.clone()))
+                .and_then(append_upload)
+        }
+
+        pub(crate) fn cancel<A, Z>(
+            uploads: UploadStore,
+            authenticator: A,
+            authorizer: Z,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
+        {
+            warp::path("_u")
+                .and(warp::path::param::<String>())
+                .and(warp::path::end())
+                .and(warp::delete())
+                .and(authenticate(authenticator, Access::Write))
+                .and(with_authorizer(authorizer))
+                .and(with_uploads(uploads))
+                .and_then(cancel_upload)
+        }
+
+        fn with_uploads(
+            uploads: UploadStore,
+        ) -> impl Filter<Extract = (UploadStore,), Error = std::convert::Infallible> + Clone
+        {
+            warp::any().map(move || uploads.clone())
+        }
+    }
+
+    pub mod gc {
+        use super::*;
+
+        pub fn collect<P, A, Z>(
+            store: P,
+            authenticator: A,
+            authorizer: Z,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
+        {
+            warp::path("_gc")
+                .and(warp::path::end())
+                .and(warp::post())
+                .and(authenticate(authenticator, Access::Write))
+                .and(with_authorizer(authorizer))
+                .and(warp::query::<filters::GcQuery>())
+                .and(with_store(store))
+                .and_then(collect_garbage)
+        }
+    }
+
+    pub mod quota {
+        use super::*;
+
+        pub fn get<A, Z>(
+            quotas: Option<Quotas>,
+            authenticator: A,
+            authorizer: Z,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
+        {
+            warp::path("_quota")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(authenticate(authenticator, Access::Read))
+                .and(with_authorizer(authorizer))
+                .and(warp::any().map(move || quotas.clone()))
+                .and_then(get_quotas)
+        }
+    }
+
+    pub mod keyring {
+        use super::*;
+        use crate::server::keyrings::KeyRingStore;
+
+        /// The largest keyring that can be stored. Keyrings only contain public keys, so this is
+        /// plenty
+        const MAX_KEYRING_SIZE: u64 = 1024 * 1024;
+
+        pub(crate) fn get<A>(
+            keyrings: Option<KeyRingStore>,
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            warp::path("_keyring")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(authenticate(authenticator, Access::Read))
+                .and(warp::any().map(move || keyrings.clone()))
+                .and_then(get_keyring)
+        }
+
+        pub(crate) fn put<A>(
+            keyrings: Option<KeyRingStore>,
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            warp::path("_keyring")
+                .and(warp::path::end())
+                .and(warp::put())
+                .and(authenticate(authenticator, Access::Write))
+                .and(warp::any().map(move || keyrings.clone()))
+                .and(warp::body::content_length_limit(MAX_KEYRING_SIZE))
+                .and(warp::body::bytes())
+                .and_then(put_keyring)
+        }
+    }
+
+    pub mod capabilities {
+        use super::*;
+
+        pub fn get<A>(
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            // Clients need to know how to authenticate before they can, so this is always public
+            warp::path("_capabilities")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(warp::any().map(move || authenticator.clone()))
+                .and_then(get_capabilities)
+        }
+    }
+
+    #[cfg(feature = "client")]
+    pub mod replication {
+        use super::*;
+        use crate::replication::ReplicationHandle;
+
+        pub fn status<A, Z>(
+            replication: Option<ReplicationHandle>,
+            authenticator: A,
+            authorizer: Z,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            A: Authenticator + Clone + Send + Sync + 'static,
+            Z: Authorizer + Clone + Send + Sync + 'static,
+        {
+            warp::path("_replication")
+                .and(warp::path::end())
+                .and(warp::get())
+                .and(authenticate(authenticator, Access::Read))
+                .and(with_authorizer(authorizer))
+                .and(warp::any().map(move || replication.clone()))
+                .and_then(get_replication_status)
+        }
+
+        // The event hooks of the primary don't authenticate, so events are checked against the
+        // signature they were sent with instead
+        pub fn notify(
+            replication: Option<ReplicationHandle>,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+            warp::path("_replication")
+                .and(warp::path("events"))
+                .and(warp::path::end())
+                .and(warp::post())
+                .and(warp::any().map(move || replication.clone()))
+                .and(warp::header::optional::<String>(
+                    crate::provider::hooks::SIGNATURE_HEADER,
+                ))
+                .and(warp::body::bytes())
+                .and_then(notify_replication)
+        }
     }
 
     pub mod relationships {
         use super::*;
 
-        pub fn get_missing_parcels<P>(
+        pub fn get_missing_parcels<P, A>(
             store: P,
+            authenticator: A,
         ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
         where
             P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
         {
             // For some reason, using the `path!` macro here was causing matching problems
             warp::path("_r")
                 .and(warp::path("missing"))
                 .and(warp::path::tail())
                 .and(warp::get())
+                .and(require(authenticator, Access::Read))
                 .and(with_store(store))
                 .and_then(get_missing)
         }
+
+        pub fn get_parcel_delta<P, A>(
+            store: P,
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            warp::path("_r")
+                .and(warp::path("delta"))
+                .and(warp::path::tail())
+                .and(warp::get())
+                .and(require(authenticator, Access::Read))
+                .and(warp::query::<filters::DeltaQuery>())
+                .and(with_store(store))
+                .and_then(get_delta)
+        }
+
+        pub fn get_dependencies<P, S, A>(
+            store: P,
+            index: S,
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            P: Provider + Clone + Send + Sync,
+            S: Search + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            warp::path("_r")
+                .and(warp::path("dependencies"))
+                .and(warp::path::tail())
+                .and(warp::get())
+                .and(require(authenticator, Access::Read))
+                .and(with_store(store))
+                .and(warp::any().map(move || index.clone()))
+                .and_then(resolve_dependencies)
+        }
+
+        pub fn get_versions<S, A>(
+            index: S,
+            authenticator: A,
+        ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone
+        where
+            S: Search + Clone + Send + Sync,
+            A: Authenticator + Clone + Send + Sync + 'static,
+        {
+            warp::path("_r")
+                .and(warp::path("versions"))
+                .and(warp::path::tail())
+                .and(warp::get())
+                .and(require(authenticator, Access::Read))
+                .and(warp::any().map(move || index.clone()))
+                .and_then(list_versions)
+        }
     }
 }
 
@@ -0,0 +1,151 @@
+//! Per-namespace signing requirements for new invoices.
+//!
+//! A [`SigningPolicy`](SigningPolicy) lists the signature roles that invoices in a namespace need
+//! to be signed in by trusted keys, which makes it possible to, for example, require an approver
+//! for everything under `prod/*` while leaving other bindles alone. Policies can be loaded from a
+//! TOML file that looks like this:
+//!
+//! ```toml
+//! [[rule]]
+//! bindles = "prod/*"
+//! require = ["creator", "approver"]
+//! enforce = true
+//!
+//! [[rule]]
+//! bindles = "*"
+//! require = ["creator"]
+//! ```
+//!
+//! The first rule matching the name of a bindle applies. Unlike a
+//! [`VerificationStrategy`](crate::signature::VerificationStrategy), where any one of the roles is
+//! enough, every required role needs a trusted signature. The outcome is returned in the
+//! [`InvoiceCreateResponse`](crate::InvoiceCreateResponse) so publishers can see which signatures
+//! are still missing. Invoices that don't satisfy an enforced rule are rejected, with the same
+//! outcome in the error response
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::authz::matches_pattern;
+use crate::signature::{KeyRing, SignatureRole};
+use crate::{Invoice, SigningPolicyResult};
+
+/// The signatures required for all bindles matching a pattern
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningRule {
+    /// The name of the bindles this rule applies to, with the same syntax as
+    /// [`Grant::bindles`](super::authz::Grant::bindles)
+    pub bindles: String,
+    /// The roles that each need a signature from a trusted key
+    pub require: Vec<SignatureRole>,
+    /// Whether invoices that don't satisfy the rule are rejected. If not set, they are accepted and
+    /// the missing roles are only reported
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+/// A list of [`SigningRule`](SigningRule)s, of which the first one matching a bindle applies
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningPolicy {
+    #[serde(default)]
+    pub rule: Vec<SigningRule>,
+}
+
+impl SigningPolicy {
+    /// Loads a policy from the TOML file at the given path
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read(path).await?;
+        Ok(toml::from_slice(&raw)?)
+    }
+
+    /// Returns the rule that applies to the bindle with the given name, if any
+    pub fn rule_for(&self, bindle_name: &str) -> Option<&SigningRule> {
+        self.rule
+            .iter()
+            .find(|r| matches_pattern(&r.bindles, bindle_name))
+    }
+
+    /// Evaluates the invoice against the rule that applies to it, using the keyring to decide which
+    /// signatures are trusted. Returns `None` if no rule applies
+    pub fn evaluate(&self, inv: &Invoice, keyring: &KeyRing) -> Option<SigningPolicyResult> {
+        let rule = self.rule_for(inv.bindle.id.name())?;
+        let trusted = inv.trusted_roles(keyring);
+        let missing: Vec<SignatureRole> = rule
+            .require
+            .iter()
+            .filter(|r| !trusted.contains(r))
+            .copied()
+            .collect();
+        Some(SigningPolicyResult {
+            bindles: rule.bindles.clone(),
+            required: rule.require.clone(),
+            satisfied: missing.is_empty(),
+            missing,
+            enforced: rule.enforce,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::signature::SecretKeyEntry;
+
+    #[tokio::test]
+    async fn test_evaluate() {
+        let policy: SigningPolicy = toml::from_str(
+            r#"
+            [[rule]]
+            bindles = "enterprise.com/*"
+            require = ["creator", "approver"]
+            enforce = true
+
+            [[rule]]
+            bindles = "*"
+            require = ["creator"]
+            "#,
+        )
+        .expect("policy should parse");
+
+        let creator = SecretKeyEntry::generate("creator", vec![SignatureRole::Creator]);
+        let approver = SecretKeyEntry::generate("approver", vec![SignatureRole::Approver]);
+        let keyring = KeyRing::new(vec![creator.key_entry()]);
+
+        // valid_v2 is named enterprise.com/warpcore
+        let mut inv = crate::testing::Scaffold::load("valid_v2").await.invoice;
+        let result = policy
+            .evaluate(&inv, &keyring)
+            .expect("A rule should apply");
+        assert_eq!("enterprise.com/*", result.bindles);
+        assert!(!result.satisfied);
+        assert!(result.enforced);
+        assert_eq!(
+            vec![SignatureRole::Creator, SignatureRole::Approver],
+            result.missing
+        );
+
+        inv.sign_with_key(SignatureRole::Creator, &creator).unwrap();
+        // The approver key isn't in the keyring, so its signature doesn't count
+        inv.sign_with_key(SignatureRole::Approver, &approver)
+            .unwrap();
+        let result = policy.evaluate(&inv, &keyring).unwrap();
+        assert_eq!(vec![SignatureRole::Approver], result.missing);
+
+        let keyring = KeyRing::new(vec![creator.key_entry(), approver.key_entry()]);
+        assert!(policy.evaluate(&inv, &keyring).unwrap().satisfied);
+
+        // Other bindles fall through to the catch-all rule
+        inv.bindle.id = "example.com/foo/1.0.0".parse().unwrap();
+        let result = policy.evaluate(&inv, &keyring).unwrap();
+        assert_eq!("*", result.bindles);
+        assert!(!result.enforced);
+        // Changing the ID invalidates the signatures
+        assert_eq!(vec![SignatureRole::Creator], result.missing);
+
+        assert!(SigningPolicy::default().evaluate(&inv, &keyring).is_none());
+    }
+}
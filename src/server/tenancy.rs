@@ -0,0 +1,275 @@
+//! Multi-tenancy for the Bindle server.
+//!
+//! A single server can host several isolated [`Tenant`](Tenant)s, such as one per team, instead
+//! of running one server for each of them. Every tenant has its own store, search index,
+//! [`Authorizer`](super::authz::Authorizer) and [`ApiOptions`](super::ApiOptions), and its API is
+//! served under its name: the invoices of the tenant `team-a` are at `/team-a/v1/_i/...`. Requests
+//! only ever reach the store and index of the tenant in their path, so a tenant can't see or
+//! change the bindles of another one, even if they have the same name.
+//!
+//! Requests are authenticated by the same [`Authenticator`](super::auth::Authenticator) for all
+//! tenants, so identities are shared by the whole server, but what an identity is allowed to do is
+//! decided by the authorizer of each tenant. Tenants using a
+//! [`FileProvider`](crate::provider::file::FileProvider) need their own storage root, which
+//! [`storage_root`](storage_root) returns for a directory shared by all tenants.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use warp::filters::BoxedFilter;
+use warp::Filter;
+
+use super::auth::Authenticator;
+use super::authz::Authorizer;
+use super::{routes, ApiOptions, RequestMonitor, TlsConfig};
+use crate::provider::Provider;
+use crate::search::Search;
+
+/// The directory below a shared storage directory that the storage roots of tenants are in
+const TENANTS_DIR: &str = "tenants";
+
+/// Top level paths of the server that tenant names would be confused with
+const RESERVED_NAMES: &[&str] = &["v1", "metrics", "robots.txt", TENANTS_DIR];
+
+/// A tenant of a server, with its own store, search index and authorizer
+#[derive(Clone)]
+pub struct Tenant<P, I, Z> {
+    name: String,
+    store: P,
+    index: I,
+    authorizer: Z,
+    options: ApiOptions,
+}
+
+impl<P, I, Z> Tenant<P, I, Z>
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: Search + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+{
+    /// Returns a tenant with the default [`ApiOptions`](ApiOptions). Fails if the name isn't a
+    /// valid tenant name (see [`validate_name`](validate_name))
+    pub fn new(name: impl Into<String>, store: P, index: I, authorizer: Z) -> anyhow::Result<Self> {
+        let name = name.into();
+        validate_name(&name)?;
+        Ok(Tenant {
+            name,
+            store,
+            index,
+            authorizer,
+            options: ApiOptions::default(),
+        })
+    }
+
+    /// Enables the optional features of the API of this tenant
+    pub fn with_options(mut self, options: ApiOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Returns the name of the tenant, which is the first segment of the paths it is served at
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the API of this tenant, only matching requests under its name
+    fn api<A>(self, authenticator: A) -> BoxedFilter<(Box<dyn warp::Reply>,)>
+    where
+        A: Authenticator + Clone + Send + Sync + 'static,
+    {
+        warp::path(self.name)
+            .and(routes::api_with_options(
+                self.store,
+                self.index,
+                authenticator,
+                self.authorizer,
+                self.options,
+            ))
+            .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+            .boxed()
+    }
+}
+
+/// Returns an error if the name can't be used for a tenant. Names are used as path segments and
+/// directory names, so they may only contain ASCII letters, digits, `-`, `_` and `.`, and must
+/// start with a letter or digit. Names of the top level paths of the server, such as `v1`, are
+/// reserved
+pub fn validate_name(name: &str) -> anyhow::Result<()> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    let valid_start = name
+        .chars()
+        .next()
+        .map(|c| c.is_ascii_alphanumeric())
+        .unwrap_or(false);
+    if !valid_chars || !valid_start {
+        anyhow::bail!("Invalid tenant name {:?}", name);
+    }
+    if RESERVED_NAMES.contains(&name) {
+        anyhow::bail!("Tenant name {} is reserved", name);
+    }
+    Ok(())
+}
+
+/// Returns the storage root of the tenant with the given name below a directory shared by all
+/// tenants, such as `/var/lib/bindle/tenants/team-a` for `/var/lib/bindle`
+pub fn storage_root(dir: impl AsRef<Path>, name: &str) -> anyhow::Result<PathBuf> {
+    validate_name(name)?;
+    Ok(dir.as_ref().join(TENANTS_DIR).join(name))
+}
+
+/// Returns the APIs of all of the tenants as a single filter. Requests for a tenant that doesn't
+/// exist are rejected as not found. If several tenants have the same name, only the first one is
+/// served
+pub fn api<P, I, A, Z>(
+    tenants: Vec<Tenant<P, I, Z>>,
+    authenticator: A,
+) -> BoxedFilter<(Box<dyn warp::Reply>,)>
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+{
+    // The number of tenants is only known at runtime, so the filters have to be boxed
+    let none = warp::any()
+        .and_then(|| async { Err::<Box<dyn warp::Reply>, _>(warp::reject::not_found()) })
+        .boxed();
+    tenants.into_iter().fold(none, |filter, tenant| {
+        filter.or(tenant.api(authenticator.clone())).unify().boxed()
+    })
+}
+
+/// Returns a future that runs a server for all of the tenants until it receives a SIGINT to stop.
+/// This is the same as [`server`](super::server), except that every tenant is served with its own
+/// store, index, authorizer and options
+pub async fn server<P, I, A, Z>(
+    tenants: Vec<Tenant<P, I, Z>>,
+    authenticator: A,
+    addr: impl Into<SocketAddr> + 'static,
+    tls: Option<TlsConfig>,
+    monitor: RequestMonitor,
+) -> anyhow::Result<()>
+where
+    P: Provider + Clone + Send + Sync + 'static,
+    I: Search + Clone + Send + Sync + 'static,
+    A: Authenticator + Clone + Send + Sync + 'static,
+    Z: Authorizer + Clone + Send + Sync + 'static,
+{
+    let mut names: Vec<&str> = tenants.iter().map(Tenant::name).collect();
+    names.sort_unstable();
+    if let Some(pair) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+        anyhow::bail!("Tenant {} was given more than once", pair[0]);
+    }
+    let api = api(tenants, authenticator)
+        .with(monitor.filter())
+        .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+        .boxed();
+    super::serve(api, addr.into(), tls, super::DEFAULT_DRAIN_TIMEOUT).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::server::auth::NoopAuthenticator;
+    use crate::server::authz::{AllowAll, RolePolicy};
+    use crate::testing;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("team-a").is_ok());
+        assert!(validate_name("team_b.example.com").is_ok());
+
+        assert!(validate_name("").is_err());
+        assert!(validate_name("..").is_err());
+        assert!(validate_name("_i").is_err());
+        assert!(validate_name("team/a").is_err());
+        assert!(validate_name("v1").is_err());
+        assert!(validate_name("metrics").is_err());
+
+        assert_eq!(
+            Path::new("/data/tenants/team-a"),
+            storage_root("/data", "team-a").unwrap()
+        );
+        assert!(storage_root("/data", "../team-a").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_isolated_tenants() {
+        let (store_a, index_a) = testing::setup().await;
+        let (store_b, index_b) = testing::setup().await;
+        // Tenant b only allows reading
+        let readers: RolePolicy = toml::from_str(
+            r#"
+            [[grant]]
+            identity = "*"
+            bindles = "*"
+            role = "reader"
+            "#,
+        )
+        .unwrap();
+        let tenants = vec![
+            Tenant::new(
+                "team-a",
+                store_a,
+                index_a,
+                Arc::new(AllowAll) as Arc<dyn Authorizer + Send + Sync>,
+            )
+            .unwrap(),
+            Tenant::new("team-b", store_b, index_b, Arc::new(readers) as _).unwrap(),
+        ];
+        let api = api(tenants, NoopAuthenticator);
+
+        let scaffold = testing::Scaffold::load("valid_v1").await;
+        let body = toml::to_vec(&scaffold.invoice).unwrap();
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/team-a/v1/_i")
+            .body(&body)
+            .reply(&api)
+            .await;
+        assert_eq!(
+            res.status(),
+            warp::http::StatusCode::ACCEPTED,
+            "Body: {}",
+            String::from_utf8_lossy(res.body())
+        );
+
+        let path = |tenant: &str| format!("/{}/v1/_i/{}", tenant, scaffold.invoice.bindle.id);
+        let res = warp::test::request()
+            .path(&path("team-a"))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::OK);
+
+        // The bindle only exists in the store of the tenant it was created in
+        let res = warp::test::request()
+            .path(&path("team-b"))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+
+        // Every tenant has its own authorization policy, and anonymous users can't create bindles
+        // in tenant b
+        let res = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/toml")
+            .path("/team-b/v1/_i")
+            .body(&body)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), warp::http::StatusCode::UNAUTHORIZED);
+
+        for path in &[
+            path("team-c"),
+            format!("/v1/_i/{}", scaffold.invoice.bindle.id),
+        ] {
+            let res = warp::test::request().path(path).reply(&api).await;
+            assert_eq!(res.status(), warp::http::StatusCode::NOT_FOUND);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! TLS termination for the server, including optional client certificate verification (mTLS)
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::{Stream, StreamExt};
+use log::{debug, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use super::TlsConfig;
+
+/// The maximum number of TLS handshakes that can be in progress at the same time
+const MAX_PENDING_HANDSHAKES: usize = 64;
+/// How long a client has to complete the TLS handshake before the connection is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Binds to the given address and returns the bound address along with a stream of established
+/// TLS connections, suitable for passing to `warp::Server::serve_incoming`. Connections that fail
+/// the handshake (e.g. because they did not present a trusted client certificate) are logged and
+/// dropped. This must be called from within a tokio runtime
+pub(crate) fn bind(
+    addr: SocketAddr,
+    config: &TlsConfig,
+) -> anyhow::Result<(
+    SocketAddr,
+    impl Stream<Item = Result<TlsStream<TcpStream>, std::io::Error>>,
+)> {
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(config)?));
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let addr = listener.local_addr()?;
+
+    let incoming = futures::stream::unfold(listener, |mut listener| async move {
+        let conn = listener.accept().await;
+        Some((conn, listener))
+    })
+    .map(move |conn| {
+        let acceptor = acceptor.clone();
+        async move {
+            let (stream, peer) = match conn {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("Unable to accept connection: {}", e);
+                    return None;
+                }
+            };
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => Some(Ok(stream)),
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    None
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", peer);
+                    None
+                }
+            }
+        }
+    })
+    .buffer_unordered(MAX_PENDING_HANDSHAKES)
+    .filter_map(futures::future::ready);
+
+    Ok((addr, incoming))
+}
+
+/// Loads the certificates and keys from the given configuration into a rustls server config
+fn server_config(config: &TlsConfig) -> anyhow::Result<ServerConfig> {
+    let certs = pemfile::certs(&mut open(&config.cert_path)?).map_err(|_| {
+        anyhow::anyhow!(
+            "Unable to parse certificates in {}",
+            config.cert_path.display()
+        )
+    })?;
+    let key = load_key(&config.key_path)?;
+
+    let mut server_config = match &config.client_ca_path {
+        None => ServerConfig::new(NoClientAuth::new()),
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (valid, _) = roots.add_pem_file(&mut open(path)?).map_err(|_| {
+                anyhow::anyhow!("Unable to parse CA certificates in {}", path.display())
+            })?;
+            if valid == 0 {
+                anyhow::bail!("No valid CA certificates found in {}", path.display());
+            }
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+        }
+    };
+    server_config
+        .set_single_cert(certs, key)
+        .context("Invalid certificate or key")?;
+    server_config.set_protocols(&["h2".into(), "http/1.1".into()]);
+    Ok(server_config)
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let parse_err = || anyhow::anyhow!("Unable to parse private key in {}", path.display());
+    // Try PKCS8 first and fall back to RSA keys, which is the same as what warp does
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(|_| parse_err())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(|_| parse_err())?;
+    }
+    if keys.is_empty() {
+        anyhow::bail!("No private key found in {}", path.display());
+    }
+    Ok(keys.remove(0))
+}
+
+fn open(path: &Path) -> anyhow::Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path).with_context(|| {
+        format!("Unable to open {}", path.display())
+    })?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+
+    use crate::server::{start_in_process, InProcessOptions};
+    use crate::testing;
+
+    fn tls_file(name: &str) -> PathBuf {
+        let root = std::env::var("CARGO_MANIFEST_DIR").expect("Unable to get project directory");
+        PathBuf::from(root).join("test/data/tls").join(name)
+    }
+
+    fn tls_config(client_ca: bool) -> TlsConfig {
+        TlsConfig {
+            cert_path: tls_file("server.crt"),
+            key_path: tls_file("server.key"),
+            client_ca_path: if client_ca {
+                Some(tls_file("ca.crt"))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Makes a raw HTTP request for the query endpoint and returns the full response
+    async fn query(addr: SocketAddr, client_cert: bool) -> std::io::Result<String> {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut open(&tls_file("ca.crt")).unwrap())
+            .unwrap();
+        if client_cert {
+            let certs = pemfile::certs(&mut open(&tls_file("client.crt")).unwrap()).unwrap();
+            let key = load_key(&tls_file("client.key")).unwrap();
+            config.set_single_client_cert(certs, key).unwrap();
+        }
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(domain, stream)
+            .await?;
+        stream
+            .write_all(b"GET /v1/_q HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await?;
+        Ok(resp)
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let (store, index) = testing::setup().await;
+        let handle = start_in_process(
+            store,
+            index,
+            InProcessOptions {
+                tls: Some(tls_config(false)),
+                ..Default::default()
+            },
+        )
+        .expect("server should start");
+        assert!(handle.base_url().starts_with("https://"));
+
+        let resp = query(handle.addr(), false)
+            .await
+            .expect("request should succeed");
+        assert!(resp.starts_with("HTTP/1.1 200"), "Response: {}", resp);
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_mtls() {
+        let (store, index) = testing::setup().await;
+        let handle = start_in_process(
+            store,
+            index,
+            InProcessOptions {
+                tls: Some(tls_config(true)),
+                ..Default::default()
+            },
+        )
+        .expect("server should start");
+
+        let resp = query(handle.addr(), true)
+            .await
+            .expect("request with a client certificate should succeed");
+        assert!(resp.starts_with("HTTP/1.1 200"), "Response: {}", resp);
+
+        // Depending on the TLS version, the client may only find out it was rejected when reading
+        if let Ok(resp) = query(handle.addr(), false).await {
+            assert!(
+                !resp.starts_with("HTTP/1.1 200"),
+                "Request without a client certificate should fail"
+            );
+        }
+        handle.shutdown().await;
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut config = tls_config(true);
+        config.client_ca_path = Some(tls_file("README.md"));
+        assert!(server_config(&config).is_err());
+
+        let mut config = tls_config(false);
+        config.key_path = tls_file("server.crt");
+        assert!(server_config(&config).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+//! Storage for resumable parcel uploads.
+//!
+//! Each upload is kept in a staging directory as a metadata file (`{id}.toml`) and a file with the
+//! data received so far (`{id}.part`). The offset of an upload is the length of its data file, so
+//! uploads can be resumed even after the server restarts. Once all data has been received, it is
+//! verified and handed to the provider like a regular parcel upload
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::stream::{Stream, StreamExt};
+
+use crate::{Label, UploadStatus};
+
+/// Uploads that haven't received any data for this long are removed when a new upload starts
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The persisted metadata of an upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UploadSession {
+    pub id: String,
+    pub bindle_id: String,
+    pub sha256: String,
+    pub size: u64,
+    /// The `If-Match` condition the upload was started with, which is checked again before the
+    /// parcel is created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_match: Option<String>,
+}
+
+/// The errors that can occur when appending to an upload
+#[derive(Debug)]
+pub(crate) enum AppendError {
+    /// The data doesn't start at the current offset of the upload, which is included
+    WrongOffset(u64),
+    /// More data was sent than the size of the parcel
+    TooLarge,
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for AppendError {
+    fn from(e: std::io::Error) -> Self {
+        AppendError::Io(e)
+    }
+}
+
+/// A handle to the staging directory for uploads
+#[derive(Clone)]
+pub(crate) struct UploadStore {
+    dir: PathBuf,
+    // Uploads that are currently receiving data, to stop concurrent writes to the same upload
+    active: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Default for UploadStore {
+    fn default() -> Self {
+        UploadStore::new(std::env::temp_dir().join("bindle-uploads"))
+    }
+}
+
+/// Marks an upload as active until it is dropped
+pub(crate) struct ActiveUpload {
+    id: String,
+    active: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for ActiveUpload {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl UploadStore {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> Self {
+        UploadStore {
+            dir: dir.into(),
+            active: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Starts a new upload for the parcel with the given label
+    pub(crate) async fn start(
+        &self,
+        bindle_id: &str,
+        label: &Label,
+        if_match: Option<String>,
+    ) -> std::io::Result<UploadStatus> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        if let Err(e) = self.remove_stale().await {
+            warn!("Unable to clean up stale uploads: {}", e);
+        }
+        let session = UploadSession {
+            id: new_id(bindle_id, &label.sha256),
+            bindle_id: bindle_id.to_owned(),
+            sha256: label.sha256.clone(),
+            size: label.size,
+            if_match,
+        };
+        tokio::fs::File::create(self.data_path(&session.id)).await?;
+        let meta = toml::to_vec(&session)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        tokio::fs::write(self.meta_path(&session.id), meta).await?;
+        debug!(
+            "Started upload {} for parcel {}",
+            session.id, session.sha256
+        );
+        Ok(status(&session, 0))
+    }
+
+    /// Returns the session and current offset of the given upload, or `None` if it doesn't exist
+    pub(crate) async fn get(&self, id: &str) -> std::io::Result<Option<(UploadSession, u64)>> {
+        // IDs are always hex, so anything else can't exist (and could escape the directory)
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let raw = match tokio::fs::read(self.meta_path(id)).await {
+            Ok(r) => r,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let session: UploadSession = toml::from_slice(&raw)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let offset = tokio::fs::metadata(self.data_path(id)).await?.len();
+        Ok(Some((session, offset)))
+    }
+
+    /// Marks the given upload as active, returning `None` if it already is
+    pub(crate) fn activate(&self, id: &str) -> Option<ActiveUpload> {
+        if !self.active.lock().unwrap().insert(id.to_owned()) {
+            return None;
+        }
+        Some(ActiveUpload {
+            id: id.to_owned(),
+            active: self.active.clone(),
+        })
+    }
+
+    /// Appends the given data to the upload, which must start at the given offset. The upload must
+    /// be [activated](UploadStore::activate) first. If the data stream fails part of the way
+    /// through, the data received up to that point is kept so the upload can be resumed. Returns
+    /// the new offset
+    pub(crate) async fn append<S, B, E>(
+        &self,
+        session: &UploadSession,
+        offset: u64,
+        data: S,
+    ) -> Result<u64, AppendError>
+    where
+        S: Stream<Item = Result<B, E>> + Unpin,
+        B: bytes::Buf,
+        E: std::fmt::Display,
+    {
+        let path = self.data_path(&session.id);
+        let current = tokio::fs::metadata(&path).await?.len();
+        if offset != current {
+            return Err(AppendError::WrongOffset(current));
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await?;
+        let mut written = current;
+        let mut data = data;
+        while let Some(chunk) = data.next().await {
+            let mut chunk = match chunk {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("Upload {} was interrupted: {}", session.id, e);
+                    break;
+                }
+            };
+            let bytes = chunk.to_bytes();
+            if written + bytes.len() as u64 > session.size {
+                // Throw away everything from this request, it can't be right
+                file.flush().await?;
+                file.set_len(current).await?;
+                return Err(AppendError::TooLarge);
+            }
+            file.write_all(&bytes).await?;
+            written += bytes.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
+    }
+
+    /// Returns the SHA-256 sum of the data received for the upload
+    pub(crate) async fn digest(&self, session: &UploadSession) -> std::io::Result<String> {
+        let mut file = tokio::fs::File::open(self.data_path(&session.id)).await?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Opens the data received for the given upload
+    pub(crate) async fn open(&self, session: &UploadSession) -> std::io::Result<tokio::fs::File> {
+        tokio::fs::File::open(self.data_path(&session.id)).await
+    }
+
+    /// Removes the given upload along with its data
+    pub(crate) async fn remove(&self, id: &str) -> std::io::Result<()> {
+        for path in &[self.data_path(id), self.meta_path(id)] {
+            match tokio::fs::remove_file(path).await {
+                Ok(_) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_stale(&self) -> std::io::Result<()> {
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map(|e| e != "toml").unwrap_or(true) {
+                continue;
+            }
+            let id = match path.file_stem() {
+                Some(s) => s.to_string_lossy().to_string(),
+                None => continue,
+            };
+            // The data file is touched on every write, so use it to tell when the upload was last
+            // active
+            let modified = match tokio::fs::metadata(self.data_path(&id)).await {
+                Ok(m) => m.modified()?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => SystemTime::UNIX_EPOCH,
+                Err(e) => return Err(e),
+            };
+            let stale = SystemTime::now()
+                .duration_since(modified)
+                .map(|age| age > STALE_AFTER)
+                .unwrap_or(false);
+            if stale && !self.active.lock().unwrap().contains(&id) {
+                debug!("Removing stale upload {}", id);
+                self.remove(&id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.toml", id))
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+}
+
+/// Returns the status of an upload with the given offset
+pub(crate) fn status(session: &UploadSession, offset: u64) -> UploadStatus {
+    UploadStatus {
+        id: session.id.clone(),
+        sha256: session.sha256.clone(),
+        offset,
+        size: session.size,
+    }
+}
+
+/// Generates a new unique upload ID. It only needs to be unguessable enough that uploads can't be
+/// stumbled upon, as every request is still authorized against the bindle
+fn new_id(bindle_id: &str, sha: &str) -> String {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(bindle_id.as_bytes());
+    hasher.update(sha.as_bytes());
+    hasher.update(now.as_nanos().to_le_bytes());
+    hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    format!("{:x}", hasher.finalize())[..32].to_owned()
+}
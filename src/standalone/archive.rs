@@ -0,0 +1,189 @@
+//! Reading and writing standalone bindles as a single gzipped tarball.
+//!
+//! An archive contains the same files as a standalone bindle directory, inside a directory named
+//! after the SHA of the bindle ID:
+//!
+//! ```text
+//! <bindle sha>/invoice.toml
+//! <bindle sha>/parcels/<parcel sha>.dat
+//! ```
+//!
+//! So extracting an archive with `tar` gives the directory [`StandaloneWrite`](super::StandaloneWrite)
+//! would have written. Archives containing anything else are rejected when read
+
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::{INVOICE_FILE, PARCEL_DIR};
+use crate::client::{ClientError, Result};
+
+/// The file extension of archives
+pub const ARCHIVE_EXTENSION: &str = "bindle.tar.gz";
+
+/// The size of the blocks a tarball is made of
+const BLOCK_SIZE: usize = 512;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Writes the entries of an archive one after another. The data is compressed in memory and
+/// written to the file as it comes, so only one buffer of each entry is held at a time
+pub(super) struct ArchiveWriter {
+    file: tokio::fs::File,
+    encoder: GzEncoder<Vec<u8>>,
+}
+
+impl ArchiveWriter {
+    /// Creates a new archive at the given path, which must not exist yet
+    pub(super) async fn create(path: &Path) -> Result<Self> {
+        debug!("Creating archive {}", path.display());
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true) // Make sure we aren't overwriting
+            .open(path)
+            .await?;
+        Ok(ArchiveWriter {
+            file,
+            encoder: GzEncoder::new(Vec::new(), Compression::default()),
+        })
+    }
+
+    /// Adds a file at the given path within the archive. The data has to be exactly `size` bytes
+    /// long, as the size is written before the data
+    pub(super) async fn append<R: AsyncRead + Unpin>(
+        &mut self,
+        path: &str,
+        size: u64,
+        mut data: R,
+    ) -> Result<()> {
+        debug!("Adding {} to archive", path);
+        let mut header = tar::Header::new_ustar();
+        header.set_path(path)?;
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        );
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        self.write(header.as_bytes()).await?;
+
+        let mut buf = vec![0; READ_BUFFER_SIZE];
+        let mut written = 0u64;
+        loop {
+            let n = data.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            written += n as u64;
+            if written > size {
+                break;
+            }
+            self.write(&buf[..n]).await?;
+        }
+        if written != size {
+            return Err(ClientError::SizeMismatch {
+                expected: size,
+                actual: written,
+            });
+        }
+        let padding = (BLOCK_SIZE - (size as usize % BLOCK_SIZE)) % BLOCK_SIZE;
+        self.write(&vec![0; padding]).await
+    }
+
+    /// Ends the archive and flushes everything to the file
+    pub(super) async fn finish(mut self) -> Result<()> {
+        // A tarball ends with two empty blocks
+        self.write(&[0; 2 * BLOCK_SIZE]).await?;
+        let rest = self.encoder.finish()?;
+        self.file.write_all(&rest).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.encoder.write_all(data)?;
+        let compressed = std::mem::take(self.encoder.get_mut());
+        self.file.write_all(&compressed).await?;
+        Ok(())
+    }
+}
+
+/// Extracts the archive at the given path into the given directory, returning the path of the
+/// extracted standalone bindle. Existing files are never overwritten. This does blocking IO, so it
+/// should be run with `spawn_blocking`
+pub(super) fn extract(archive: &Path, dest: &Path) -> Result<PathBuf> {
+    debug!(
+        "Extracting archive {} to {}",
+        archive.display(),
+        dest.display()
+    );
+    let file = std::fs::File::open(archive)?;
+    std::fs::create_dir_all(dest)?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    archive.set_overwrite(false);
+    let mut bindle_dir: Option<String> = None;
+    let mut has_invoice = false;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let parts: Vec<&str> = path
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .map(|c| match c {
+                Component::Normal(part) => part.to_str(),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid_entry(&path))?;
+        let (dir, rest) = parts.split_first().ok_or_else(|| invalid_entry(&path))?;
+        if bindle_dir.get_or_insert_with(|| dir.to_string()) != dir {
+            return Err(ClientError::Other(format!(
+                "Archive contains more than one bindle: found {} and {}",
+                bindle_dir.unwrap_or_default(),
+                dir
+            )));
+        }
+        let entry_type = entry.header().entry_type();
+        let parcel_dir = PARCEL_DIR.trim_end_matches('/');
+        let valid = match (entry_type, rest) {
+            (tar::EntryType::Directory, []) => true,
+            (tar::EntryType::Directory, [d]) => *d == parcel_dir,
+            (tar::EntryType::Regular, [f]) if *f == INVOICE_FILE => {
+                has_invoice = true;
+                true
+            }
+            (tar::EntryType::Regular, [d, f]) => *d == parcel_dir && f.ends_with(".dat"),
+            _ => false,
+        };
+        if !valid {
+            return Err(invalid_entry(&path));
+        }
+        entry.unpack_in(dest)?;
+    }
+    let bindle_dir = match bindle_dir {
+        Some(dir) if has_invoice => dest.join(dir),
+        _ => {
+            return Err(ClientError::Other(format!(
+                "Archive doesn't contain an {}",
+                INVOICE_FILE
+            )))
+        }
+    };
+    // Parcels are optional, but readers expect the directory
+    std::fs::create_dir_all(bindle_dir.join(PARCEL_DIR))?;
+    Ok(bindle_dir)
+}
+
+fn invalid_entry(path: &Path) -> ClientError {
+    ClientError::Other(format!(
+        "Archive contains {}, which isn't part of a standalone bindle",
+        path.display()
+    ))
+}
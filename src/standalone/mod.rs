@@ -1,14 +1,24 @@
-//! Functions and types for reading and writing to standalone bindles
+//! Functions and types for reading and writing to standalone bindles, either as a directory or as a
+//! single gzipped tarball (see the [`archive`](archive) module for its layout)
+pub mod archive;
+pub mod pack;
+pub mod unpack;
+
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use log::{debug, info};
+use log::{debug, error, info, trace, warn};
 use tokio::io::{AsyncRead, AsyncWriteExt};
 use tokio::stream::{Stream, StreamExt};
 
+use crate::client::progress::{ParcelStatus, ProgressTracker};
 use crate::client::{Client, ClientError, Result};
 use crate::Id;
+use archive::{ArchiveWriter, ARCHIVE_EXTENSION};
+
+pub use crate::client::progress::{Progress, ProgressCallback, ProgressReporter};
 
 /// The name of the invoice file
 pub const INVOICE_FILE: &str = "invoice.toml";
@@ -46,6 +56,14 @@ impl StandaloneRead {
         let base = base_path
             .as_ref()
             .join(bindle_id.try_into().map_err(|e| e.into())?.sha());
+        StandaloneRead::from_dir(base).await
+    }
+
+    /// Returns a new StandaloneRead for the standalone bindle in the given directory, which is the
+    /// one containing the invoice file, such as the path returned by
+    /// [`Client::export_standalone`](crate::client::Client::export_standalone)
+    pub async fn from_dir<P: AsRef<Path>>(path: P) -> Result<StandaloneRead> {
+        let base = path.as_ref();
         let invoice_file = base.join(INVOICE_FILE);
         let parcel_dir = base.join(PARCEL_DIR);
         let stream = tokio::fs::read_dir(&parcel_dir).await?;
@@ -60,34 +78,113 @@ impl StandaloneRead {
         })
     }
 
-    // TODO: from a tarball
+    /// Extracts the standalone bindle archive at the given path, as written by
+    /// [`StandaloneWrite::write_archive`](StandaloneWrite::write_archive), into the given base path
+    /// and returns a `StandaloneRead` for the extracted files. Existing files are never overwritten
+    ///
+    /// ```no_run
+    /// use bindle::standalone::StandaloneRead;
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let read = StandaloneRead::from_archive("/foo/baz.bindle.tar.gz", "/foo/bar")
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn from_archive<A, P>(archive_path: A, base_path: P) -> Result<StandaloneRead>
+    where
+        A: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        let archive_path = archive_path.as_ref().to_owned();
+        let base = base_path.as_ref().to_owned();
+        let dir = tokio::task::spawn_blocking(move || archive::extract(&archive_path, &base))
+            .await
+            .map_err(|e| ClientError::Other(format!("Unable to extract archive: {}", e)))??;
+        let inv: crate::Invoice = crate::client::load::toml(dir.join(INVOICE_FILE)).await?;
+        // The directory is named after the bindle, which anyone can get wrong when building an
+        // archive by hand
+        let expected = inv.bindle.id.sha();
+        if dir.file_name().and_then(|n| n.to_str()) != Some(expected.as_str()) {
+            return Err(ClientError::Other(format!(
+                "Archive directory {} doesn't match the SHA of bindle {}, which is {}",
+                dir.display(),
+                inv.bindle.id,
+                expected
+            )));
+        }
+        StandaloneRead::new(base_path, &inv.bindle.id).await
+    }
+
+    /// Push this standalone bindle to a bindle server using the given client and the default
+    /// [`PushOptions`](PushOptions). This function will automatically handle cases where the
+    /// invoice or some of the parcels already exist on the target bindle server
+    pub async fn push(&self, client: &Client) -> Result<PushReport> {
+        self.push_with_options(client, PushOptions::default()).await
+    }
 
-    /// Push this standalone bindle to a bindle server using the given client. This function will
-    /// automatically handle cases where the invoice or some of the parcels already exist on the
-    /// target bindle server
-    pub async fn push(&self, client: &Client) -> Result<()> {
-        let inv_create = create_or_get_invoice(client, &self.invoice_file).await?;
+    /// Push this standalone bindle to a bindle server using the given client and options.
+    ///
+    /// The push happens in three ordered stages: first the invoice is created (or fetched if it
+    /// already exists), then all missing parcels are uploaded with bounded concurrency (retrying
+    /// transient failures), and finally the server's list of missing parcels is checked to confirm
+    /// the bindle is complete. An error is only returned if the invoice stage fails. Failures of
+    /// individual parcels are recorded in the returned [`PushReport`](PushReport), which should be
+    /// checked using [`PushReport::is_complete`](PushReport::is_complete).
+    ///
+    /// Before uploading, the server is asked which of the missing parcels it already has for other
+    /// bindles (see [`Client::get_missing_shas`](Client::get_missing_shas)), and those are skipped.
+    /// Servers that can't tell get all missing parcels uploaded
+    pub async fn push_with_options(
+        &self,
+        client: &Client,
+        options: PushOptions,
+    ) -> Result<PushReport> {
+        let start = Instant::now();
+        // The client reports the bytes of each upload
+        let client = &match &options.progress {
+            Some(reporter) => client.clone().with_progress_reporter(reporter.clone()),
+            None => client.clone(),
+        };
+        let (inv_create, invoice_created) =
+            create_or_get_invoice(client, &self.invoice_file).await?;
         let missing = inv_create.missing.unwrap_or_default();
         let inv = inv_create.invoice;
-        let to_upload: Vec<(String, PathBuf)> = self
-            .parcels
-            .iter()
-            .filter_map(|path| {
-                let sha = match path.file_stem() {
-                    Some(s) => s.to_string_lossy().to_string(),
-                    None => return None,
-                };
-                Some((sha, path))
-            })
-            .filter_map(|(sha, path)| {
-                if let Some(label) = missing.iter().find(|label| label.sha256 == sha) {
-                    Some((label.sha256.clone(), path.clone()))
-                } else {
-                    info!("Parcel {} not in missing parcels, skipping...", sha);
-                    None
+
+        let tracker = ProgressTracker::new(self.parcels.len(), options.progress.clone());
+        let mut parcels = Vec::new();
+        let mut to_upload: Vec<(String, PathBuf)> = Vec::new();
+        for path in self.parcels.iter() {
+            let sha = match path.file_stem() {
+                Some(s) => s.to_string_lossy().to_string(),
+                None => continue,
+            };
+            if missing.iter().any(|label| label.sha256 == sha) {
+                to_upload.push((sha, path.clone()));
+            } else {
+                info!("Parcel {} not in missing parcels, skipping...", sha);
+                skip_parcel(&tracker, &mut parcels, sha);
+            }
+        }
+
+        // Parcels missing for this bindle may still have been uploaded for another one
+        if !to_upload.is_empty() {
+            let shas: Vec<String> = to_upload.iter().map(|(sha, _)| sha.clone()).collect();
+            match client.get_missing_shas(&shas).await {
+                Ok(missing) => {
+                    let (upload, stored): (Vec<_>, Vec<_>) = to_upload
+                        .into_iter()
+                        .partition(|(sha, _)| missing.contains(sha));
+                    for (sha, _) in stored {
+                        info!("Parcel {} already exists on the server, skipping...", sha);
+                        skip_parcel(&tracker, &mut parcels, sha);
+                    }
+                    to_upload = upload;
                 }
-            })
-            .collect();
+                // Servers that can't check parcels across bindles get everything uploaded
+                Err(e) => debug!("Unable to check which parcels the server has: {}", e),
+            }
+        }
 
         debug!(
             "Found {} parcels in this bindle that do not yet exist on the server: {:?}",
@@ -95,38 +192,268 @@ impl StandaloneRead {
             to_upload
         );
 
-        let parcel_futures = to_upload
+        let semaphore = tokio::sync::Semaphore::new(options.concurrency.max(1));
+        let uploads = to_upload.into_iter().map(|(sha, path)| {
+            let bindle_id = inv.bindle.id.clone();
+            let options = &options;
+            let semaphore = &semaphore;
+            let tracker = &tracker;
+            async move {
+                let _permit = semaphore.acquire().await;
+                let report = upload_parcel(client, bindle_id, sha, path, options).await;
+                let (status, bytes) = match &report.status {
+                    ParcelPushStatus::Uploaded => (ParcelStatus::Transferred, report.bytes),
+                    ParcelPushStatus::AlreadyExists => (ParcelStatus::Skipped, 0),
+                    ParcelPushStatus::Failed(e) => (ParcelStatus::Failed(e.clone()), 0),
+                };
+                tracker.parcel_done(&report.sha, status, bytes);
+                report
+            }
+        });
+        parcels.extend(futures::future::join_all(uploads).await);
+
+        // Only trust the server when it comes to whether or not the bindle is complete
+        trace!(
+            "Confirming all parcels for {} exist on the server",
+            inv.bindle.id
+        );
+        let missing = client
+            .get_missing_parcels(&inv.bindle.id)
+            .await?
             .into_iter()
-            .map(|(sha, path)| (sha, path, inv.bindle.id.clone(), client.clone()))
-            .map(|(sha, path, bindle_id, client)| async move {
-                info!("Uploading parcel {} to server", sha);
-                client
-                    .create_parcel_from_file(bindle_id, &sha, path)
-                    .await?;
-                info!("Finished uploading parcel {} to server", sha);
-                Ok(())
-            });
-
-        futures::future::join_all(parcel_futures)
+            .map(|label| label.sha256)
+            .collect();
+
+        Ok(PushReport {
+            bindle_id: inv.bindle.id,
+            invoice_created,
+            parcels,
+            missing,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+/// Options for controlling how a standalone bindle is pushed to a server
+#[derive(Clone)]
+pub struct PushOptions {
+    /// The maximum number of parcels uploaded at the same time. Defaults to 4
+    pub concurrency: usize,
+    /// The number of times an upload is retried after a transient (network or server) error.
+    /// Defaults to 3
+    pub retries: u32,
+    /// The delay before the first retry, doubling with every following attempt. Defaults to 500ms
+    pub retry_delay: Duration,
+    /// Reports the bytes of each upload, and each parcel once it is uploaded, skipped because the
+    /// server already has it, or failed. Defaults to `None`
+    pub progress: Option<ProgressCallback>,
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        PushOptions {
+            concurrency: 4,
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            progress: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for PushOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PushOptions")
+            .field("concurrency", &self.concurrency)
+            .field("retries", &self.retries)
+            .field("retry_delay", &self.retry_delay)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+/// Options for controlling how a bindle is exported from a server as a standalone bindle
+#[derive(Clone)]
+pub struct ExportOptions {
+    /// The maximum number of parcels downloaded at the same time. Defaults to 4
+    pub concurrency: usize,
+    /// Whether to export the bindle even if it is yanked. Defaults to `false`
+    pub yanked: bool,
+    /// Reports the bytes of each download, and each parcel once it is written. Defaults to `None`
+    pub progress: Option<ProgressCallback>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            concurrency: 4,
+            yanked: false,
+            progress: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for ExportOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExportOptions")
+            .field("concurrency", &self.concurrency)
+            .field("yanked", &self.yanked)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+/// The result of pushing a standalone bindle to a server
+#[derive(Debug, Clone)]
+pub struct PushReport {
+    /// The ID of the pushed bindle
+    pub bindle_id: Id,
+    /// Whether the invoice was created by this push. This is `false` if it already existed
+    pub invoice_created: bool,
+    /// The outcome for each parcel in the standalone bindle
+    pub parcels: Vec<ParcelPushReport>,
+    /// The SHAs of any parcels the server reported as still missing once all uploads were done
+    pub missing: Vec<String>,
+    /// The total time taken by the push
+    pub elapsed: Duration,
+}
+
+impl PushReport {
+    /// Returns true if the server has all parcels of the bindle
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Returns the total number of bytes uploaded
+    pub fn bytes_uploaded(&self) -> u64 {
+        self.parcels
+            .iter()
+            .filter(|p| matches!(p.status, ParcelPushStatus::Uploaded))
+            .map(|p| p.bytes)
+            .sum()
+    }
+}
+
+/// The outcome of pushing a single parcel
+#[derive(Debug, Clone)]
+pub struct ParcelPushReport {
+    /// The SHA of the parcel
+    pub sha: String,
+    pub status: ParcelPushStatus,
+    /// The number of upload attempts made. This is 0 if no upload was needed
+    pub attempts: u32,
+    /// The size of the parcel in bytes. This is 0 if no upload was needed
+    pub bytes: u64,
+    /// The time spent on this parcel, including any retries
+    pub elapsed: Duration,
+}
+
+/// The possible states of a parcel after a push
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParcelPushStatus {
+    /// The parcel was uploaded by this push
+    Uploaded,
+    /// The parcel already existed on the server, so it was not uploaded
+    AlreadyExists,
+    /// The parcel could not be uploaded. Contains the message of the last error
+    Failed(String),
+}
+
+/// Records a parcel that doesn't need to be uploaded because the server already has it
+fn skip_parcel(tracker: &ProgressTracker, parcels: &mut Vec<ParcelPushReport>, sha: String) {
+    tracker.parcel_done(&sha, ParcelStatus::Skipped, 0);
+    parcels.push(ParcelPushReport {
+        sha,
+        status: ParcelPushStatus::AlreadyExists,
+        attempts: 0,
+        bytes: 0,
+        elapsed: Duration::default(),
+    });
+}
+
+/// Uploads a single parcel, retrying transient errors as configured in the given options
+async fn upload_parcel(
+    client: &Client,
+    bindle_id: Id,
+    sha: String,
+    path: PathBuf,
+    options: &PushOptions,
+) -> ParcelPushReport {
+    let start = Instant::now();
+    let mut report = ParcelPushReport {
+        sha,
+        status: ParcelPushStatus::Uploaded,
+        attempts: 0,
+        bytes: 0,
+        elapsed: Duration::default(),
+    };
+    report.bytes = match tokio::fs::metadata(&path).await {
+        Ok(m) => m.len(),
+        Err(e) => {
+            report.status = ParcelPushStatus::Failed(ClientError::from(e).to_string());
+            return report;
+        }
+    };
+
+    let mut delay = options.retry_delay;
+    loop {
+        report.attempts += 1;
+        info!(
+            "Uploading parcel {} to server (attempt {})",
+            report.sha, report.attempts
+        );
+        match client
+            .create_parcel_from_file(bindle_id.clone(), &report.sha, &path)
             .await
-            .into_iter()
-            .collect::<Result<Vec<_>>>()?;
-        Ok(())
+        {
+            Ok(_) => {
+                info!("Finished uploading parcel {} to server", report.sha);
+                break;
+            }
+            // Someone else could have uploaded it in the meantime
+            Err(ClientError::ParcelAlreadyExists) => {
+                report.status = ParcelPushStatus::AlreadyExists;
+                break;
+            }
+            Err(e) if is_transient(&e) && report.attempts <= options.retries => {
+                warn!(
+                    "Error uploading parcel {}, retrying in {:?}: {}",
+                    report.sha, delay, e
+                );
+                tokio::time::delay_for(delay).await;
+                delay *= 2;
+            }
+            Err(e) => {
+                error!("Unable to upload parcel {}: {}", report.sha, e);
+                report.status = ParcelPushStatus::Failed(e.to_string());
+                break;
+            }
+        }
     }
+    report.elapsed = start.elapsed();
+    report
+}
+
+/// Returns whether the given error could go away by retrying the request
+pub(crate) fn is_transient(e: &ClientError) -> bool {
+    matches!(
+        e,
+        ClientError::HttpClientError(_) | ClientError::ServerError(_)
+    )
 }
 
 /// Helper function for creating an invoice or fetching it if it already exists. For security
 /// reasons, we need to fetch the invoice (and its missing parcels) if it already exists as the user
-/// submitted one could be incorrect (intentionally or unintentionally)
+/// submitted one could be incorrect (intentionally or unintentionally). The returned boolean is
+/// true if the invoice was created
 async fn create_or_get_invoice(
     client: &Client,
     invoice_path: &PathBuf,
-) -> Result<crate::InvoiceCreateResponse> {
+) -> Result<(crate::InvoiceCreateResponse, bool)> {
     // Load the invoice into memory so we can have access to its ID for fetching if needed
     let inv: crate::Invoice = crate::client::load::toml(invoice_path).await?;
     let id = inv.bindle.id.clone();
     match client.create_invoice(inv).await {
-        Ok(resp) => Ok(resp),
+        Ok(resp) => Ok((resp, true)),
         Err(e) if matches!(e, crate::client::ClientError::InvoiceAlreadyExists) => {
             info!("Invoice {} already exists on the bindle server. Fetching existing invoice and missing parcels list", id);
             let invoice = client.get_invoice(&id).await?;
@@ -136,7 +463,14 @@ async fn create_or_get_invoice(
             } else {
                 Some(missing)
             };
-            Ok(crate::InvoiceCreateResponse { invoice, missing })
+            Ok((
+                crate::InvoiceCreateResponse {
+                    invoice,
+                    missing,
+                    signing_policy: None,
+                },
+                false,
+            ))
         }
         Err(e) => Err(e),
     }
@@ -178,7 +512,64 @@ impl StandaloneWrite {
         self.base_path.as_ref()
     }
 
-    // TODO: From a tarball
+    /// Returns the path of the archive written by
+    /// [`write_archive`](StandaloneWrite::write_archive), which is the output directory with the
+    /// `.bindle.tar.gz` extension
+    pub fn archive_path(&self) -> PathBuf {
+        let mut path = self.base_path.clone().into_os_string();
+        path.push(".");
+        path.push(ARCHIVE_EXTENSION);
+        path.into()
+    }
+
+    /// Writes the given invoice and `HashMap` of parcels (as readers) as a single gzipped tarball at
+    /// the [`archive_path`](StandaloneWrite::archive_path) instead of a directory, returning its
+    /// path. The key of the `HashMap` should be the SHA of the parcel. Each parcel has to be the
+    /// size given in its label
+    pub async fn write_archive<T: AsyncRead + Unpin + Send + Sync>(
+        &self,
+        inv: crate::Invoice,
+        parcels: HashMap<String, T>,
+    ) -> Result<PathBuf> {
+        validate_shas(&inv, parcels.keys())?;
+        let dir = self
+            .base_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_owned();
+
+        let path = self.archive_path();
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut archive = ArchiveWriter::create(&path).await?;
+        let invoice = toml::to_vec(&inv)?;
+        archive
+            .append(
+                &format!("{}/{}", dir, INVOICE_FILE),
+                invoice.len() as u64,
+                invoice.as_slice(),
+            )
+            .await?;
+        // Entries are written one at a time, in a stable order
+        let mut parcels: Vec<(String, T)> = parcels.into_iter().collect();
+        parcels.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (sha, reader) in parcels {
+            let size = inv
+                .parcel
+                .iter()
+                .flatten()
+                .find(|p| p.label.sha256 == sha)
+                .map(|p| p.label.size)
+                .unwrap_or_default();
+            archive
+                .append(&format!("{}/{}{}.dat", dir, PARCEL_DIR, sha), size, reader)
+                .await?;
+        }
+        archive.finish().await?;
+        Ok(path)
+    }
 
     /// Writes the given invoice and `HashMap` of parcels (as readers). The key
     /// of the `HashMap` should be the SHA of the parcel
@@ -266,6 +657,68 @@ impl StandaloneWrite {
     }
 }
 
+/// Fetches the bindle with the given ID and all of its parcels from a server and writes them as a
+/// standalone bindle in the given base path, returning the directory of the bindle. Fails if any
+/// parcel can't be fetched, as the standalone bindle wouldn't be complete
+pub(crate) async fn export(
+    client: &Client,
+    id: Id,
+    base_path: &Path,
+    options: ExportOptions,
+) -> Result<PathBuf> {
+    let standalone = StandaloneWrite::new(base_path, &id)?;
+    let client = &match &options.progress {
+        Some(reporter) => client.clone().with_progress_reporter(reporter.clone()),
+        None => client.clone(),
+    };
+    let inv = if options.yanked {
+        client.get_yanked_invoice(&id).await?
+    } else {
+        client.get_invoice(&id).await?
+    };
+
+    tokio::fs::create_dir_all(standalone.base_path.join(PARCEL_DIR)).await?;
+    write_invoice(&standalone.base_path, &inv).await?;
+
+    let labels = inv.parcel.as_deref().unwrap_or_default();
+    let tracker = ProgressTracker::new(labels.len(), options.progress.clone());
+    let semaphore = tokio::sync::Semaphore::new(options.concurrency.max(1));
+    let downloads = labels.iter().map(|parcel| {
+        let sha = &parcel.label.sha256;
+        let (id, semaphore, tracker, standalone) = (&id, &semaphore, &tracker, &standalone);
+        async move {
+            let _permit = semaphore.acquire().await;
+            let mut stream = client.get_parcel_stream(id, sha).await?;
+            let path = standalone
+                .base_path
+                .join(PARCEL_DIR)
+                .join(format!("{}.dat", sha));
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true) // Make sure we aren't overwriting
+                .open(&path)
+                .await?;
+
+            debug!("Writing parcel to {}", path.display());
+            let mut bytes = 0;
+            while let Some(b) = stream.next().await {
+                let b = b?;
+                bytes += b.len() as u64;
+                file.write_all(&b).await?;
+            }
+            file.flush().await?;
+            debug!("Finished writing parcel to {}", path.display());
+            tracker.parcel_done(sha, ParcelStatus::Transferred, bytes);
+            Ok(())
+        }
+    });
+    futures::future::join_all(downloads)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    Ok(standalone.base_path)
+}
+
 async fn write_invoice(base_path: impl AsRef<Path>, inv: &crate::Invoice) -> Result<()> {
     debug!("Writing invoice file into {}", base_path.as_ref().display());
     tokio::fs::OpenOptions::new()
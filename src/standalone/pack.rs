@@ -0,0 +1,232 @@
+//! Packing a directory of files into a standalone bindle.
+//!
+//! Every file in the directory becomes a parcel named after its path relative to the directory,
+//! with a label generated from its contents (see
+//! [`LabelBuilder::from_file`](crate::builder::LabelBuilder::from_file)). The bindle itself is
+//! described by a small [`PackManifest`](PackManifest), which is read from
+//! [`MANIFEST_FILE`](MANIFEST_FILE) in the directory by default:
+//!
+//! ```toml
+//! name = "example.com/app"
+//! version = "1.0.0"
+//! description = "An example app"
+//! exclude = ["**/*.map"]
+//!
+//! [[group]]
+//! name = "server"
+//! required = true
+//! files = ["*.wasm"]
+//!
+//! [[group]]
+//! name = "static"
+//! files = ["static/**"]
+//! ```
+//!
+//! Files matching the patterns of a group become members of it, and files not matching any group
+//! are in the global group. Patterns match the `/` separated relative path of a file: `*` matches
+//! anything but a `/`, `**` matches anything (including `/`) and `?` matches a single character
+
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::StandaloneWrite;
+use crate::builder::{InvoiceBuilder, LabelBuilder, ParcelBuilder};
+use crate::client::{ClientError, Result};
+use crate::{AnnotationMap, Group};
+
+/// The name of the manifest file that is used if no other manifest is given. It is never packed
+/// as a parcel
+pub const MANIFEST_FILE: &str = "bindle-pack.toml";
+
+/// Describes the bindle to pack a directory into
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct PackManifest {
+    /// The name of the bindle
+    pub name: String,
+    /// The SemVer version of the bindle
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<String>,
+    /// Annotations added to the invoice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<AnnotationMap>,
+    /// Patterns of files that aren't packed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// The groups of the bindle, along with the files that are members of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub group: Vec<PackGroup>,
+}
+
+/// A group of a [`PackManifest`](PackManifest)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct PackGroup {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub satisfied_by: Option<String>,
+    /// Patterns of the files that are members of this group
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl PackManifest {
+    /// Loads a manifest from the TOML file at the given path
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        crate::client::load::toml(path).await
+    }
+}
+
+/// Packs the given directory into a standalone bindle in the given base path, using the manifest in
+/// its [`MANIFEST_FILE`](MANIFEST_FILE). Returns the directory of the standalone bindle
+pub async fn pack(dir: impl AsRef<Path>, base_path: impl AsRef<Path>) -> Result<PathBuf> {
+    let manifest = PackManifest::load(dir.as_ref().join(MANIFEST_FILE)).await?;
+    pack_with_manifest(dir, &manifest, base_path).await
+}
+
+/// Same as [`pack`](pack), but with the given manifest instead of the one in the directory
+pub async fn pack_with_manifest(
+    dir: impl AsRef<Path>,
+    manifest: &PackManifest,
+    base_path: impl AsRef<Path>,
+) -> Result<PathBuf> {
+    let dir = dir.as_ref().to_owned();
+    let files = {
+        let dir = dir.clone();
+        tokio::task::spawn_blocking(move || list_files(&dir))
+            .await
+            .map_err(|e| ClientError::Other(format!("Unable to list files: {}", e)))??
+    };
+
+    let mut builder = InvoiceBuilder::new(&manifest.name, &manifest.version);
+    if let Some(description) = &manifest.description {
+        builder = builder.description(description);
+    }
+    for author in &manifest.authors {
+        builder = builder.author(author);
+    }
+    for (key, value) in manifest.annotations.iter().flatten() {
+        builder = builder.annotation(key, value);
+    }
+    for group in &manifest.group {
+        builder = builder.group_with(Group {
+            name: group.name.clone(),
+            required: group.required,
+            satisfied_by: group.satisfied_by.clone(),
+        });
+    }
+
+    let mut parcels = std::collections::HashMap::new();
+    for name in files {
+        if name == MANIFEST_FILE || manifest.exclude.iter().any(|p| matches_glob(p, &name)) {
+            debug!("Skipping {}", name);
+            continue;
+        }
+        let path = dir.join(&name);
+        let label = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || LabelBuilder::from_file(path))
+                .await
+                .map_err(|e| ClientError::Other(format!("Unable to hash file: {}", e)))??
+                .name(name.as_str())
+                .build()
+        };
+        if parcels.contains_key(&label.sha256) {
+            return Err(ClientError::Other(format!(
+                "{} has the same contents as another file, which can't be packed into the same bindle",
+                name
+            )));
+        }
+        let mut parcel = ParcelBuilder::new(label);
+        for group in &manifest.group {
+            if group.files.iter().any(|p| matches_glob(p, &name)) {
+                parcel = parcel.member_of(group.name.as_str());
+            }
+        }
+        let parcel = parcel.build();
+        debug!("Packing {} as parcel {}", name, parcel.label.sha256);
+        parcels.insert(
+            parcel.label.sha256.clone(),
+            tokio::fs::File::open(&path).await?,
+        );
+        builder = builder.parcel(parcel);
+    }
+
+    let inv = builder
+        .build()
+        .map_err(|e| ClientError::InvalidConfig(format!("Invalid pack manifest: {}", e)))?;
+    let standalone = StandaloneWrite::new(base_path, &inv.bindle.id)?;
+    standalone.write(inv, parcels).await?;
+    Ok(standalone.path().to_owned())
+}
+
+/// Returns the `/` separated paths of all files in the directory and its subdirectories, relative
+/// to it and sorted
+fn list_files(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        for entry in std::fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else {
+                let name: Vec<_> = path
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect();
+                files.push(name.join("/"));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Returns whether the `/` separated path matches the glob pattern
+fn matches_glob(pattern: &str, path: &str) -> bool {
+    fn matches(pattern: &[u8], path: &[u8]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            [b'*', b'*', rest @ ..] => {
+                // `**/` also matches no directories at all
+                let rest_without_slash = rest.strip_prefix(b"/").unwrap_or(rest);
+                matches(rest_without_slash, path)
+                    || (0..=path.len()).any(|i| matches(rest, &path[i..]))
+            }
+            [b'*', rest @ ..] => {
+                matches(rest, path)
+                    || (!path.is_empty() && path[0] != b'/' && matches(pattern, &path[1..]))
+            }
+            [b'?', rest @ ..] => !path.is_empty() && path[0] != b'/' && matches(rest, &path[1..]),
+            [c, rest @ ..] => path.first() == Some(c) && matches(rest, &path[1..]),
+        }
+    }
+    matches(pattern.as_bytes(), path.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches_glob() {
+        assert!(matches_glob("*.wasm", "app.wasm"));
+        assert!(!matches_glob("*.wasm", "lib/app.wasm"));
+        assert!(matches_glob("**/*.wasm", "lib/app.wasm"));
+        assert!(matches_glob("**/*.wasm", "app.wasm"));
+        assert!(matches_glob("static/**", "static/css/site.css"));
+        assert!(!matches_glob("static/**", "other/site.css"));
+        assert!(matches_glob("app.???", "app.css"));
+        assert!(!matches_glob("app.???", "app.wasm"));
+        assert!(matches_glob("README.md", "README.md"));
+    }
+}
@@ -0,0 +1,348 @@
+//! Unpacking the parcels of a bindle into plain files.
+//!
+//! Where [`StandaloneWrite`](super::StandaloneWrite) and the [`Downloader`](crate::client::downloader::Downloader)
+//! store parcels by their SHA, [`unpack`](unpack) writes each parcel to a file named after its
+//! label, so a bindle packed with [`pack`](super::pack) comes back as the directory it was packed
+//! from. Label names can contain `/` to put files in subdirectories, but names that would escape
+//! the destination directory (such as absolute paths or ones containing `..`) are rejected. What
+//! happens when two parcels have the same name, or a file already exists, is chosen with
+//! [`Collision`](Collision). Use [`Client::unpack`](crate::client::Client::unpack) to unpack a
+//! bindle straight from a server
+
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::stream::StreamExt;
+
+use crate::client::{ClientError, Result};
+use crate::provider::{Provider, ProviderError};
+use crate::{FeatureMap, Invoice, Label, Parcel};
+
+/// What to do when a parcel would be unpacked to a path that is already taken, either by an
+/// existing file or by another parcel with the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Collision {
+    /// Fail before anything is written
+    #[default]
+    Error,
+    /// Replace existing files. If parcels have the same name, the last one in the invoice wins
+    Overwrite,
+    /// Add the first 8 characters of the parcel's SHA to the file name, such as
+    /// `styles-4cb04826.css`
+    Rename,
+}
+
+/// Options for controlling which parcels are unpacked and how
+#[derive(Debug, Clone)]
+pub struct UnpackOptions {
+    /// If set, only the parcels needed for these groups and the selected features are unpacked,
+    /// as resolved by [`Invoice::resolve_parcels`](crate::Invoice::resolve_parcels). Otherwise
+    /// every parcel is. Defaults to `None`
+    pub groups: Option<Vec<String>>,
+    /// The features used when resolving the parcels of the `groups`. Defaults to none
+    pub features: FeatureMap,
+    /// What to do when a path is already taken. Defaults to [`Collision::Error`](Collision::Error)
+    pub collision: Collision,
+    /// The maximum number of parcels fetched at the same time. Defaults to 4
+    pub concurrency: usize,
+}
+
+impl Default for UnpackOptions {
+    fn default() -> Self {
+        UnpackOptions {
+            groups: None,
+            features: FeatureMap::new(),
+            collision: Collision::default(),
+            concurrency: 4,
+        }
+    }
+}
+
+/// The outcome of unpacking a bindle
+#[derive(Debug, Default)]
+pub struct UnpackReport {
+    /// The parcels that were unpacked, along with the files they were written to
+    pub files: Vec<(Label, PathBuf)>,
+    /// Parcels that don't exist. By design, an invoice can contain parcels that don't exist yet
+    pub missing: Vec<Label>,
+}
+
+impl UnpackReport {
+    /// Returns true if every selected parcel was unpacked
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Fetches the parcels of the given invoice from the provider and writes them to files named after
+/// their labels in the given directory, which is created if it doesn't exist. Each file is
+/// verified against the SHA of its label before it is moved into place
+pub async fn unpack<P: Provider + Sync>(
+    provider: &P,
+    inv: &Invoice,
+    dest: impl AsRef<Path>,
+    options: &UnpackOptions,
+) -> Result<UnpackReport> {
+    let dest = dest.as_ref();
+    let parcels = match &options.groups {
+        Some(groups) => {
+            let groups: Vec<&str> = groups.iter().map(String::as_str).collect();
+            inv.resolve_parcels(&groups, &options.features)
+                .map_err(|e| ClientError::Other(format!("Unable to select parcels: {}", e)))?
+        }
+        None => inv.parcel.clone().unwrap_or_default(),
+    };
+    let targets = plan(dest, &parcels, options.collision).await?;
+
+    let semaphore = tokio::sync::Semaphore::new(options.concurrency.max(1));
+    let writes = targets.into_iter().map(|(label, path)| {
+        let semaphore = &semaphore;
+        async move {
+            let _permit = semaphore.acquire().await;
+            let written = write_parcel(provider, inv, &label, &path).await?;
+            Ok::<_, ClientError>((label, path, written))
+        }
+    });
+    let mut report = UnpackReport::default();
+    for res in futures::future::join_all(writes).await {
+        let (label, path, written) = res?;
+        if written {
+            report.files.push((label, path));
+        } else {
+            report.missing.push(label);
+        }
+    }
+    Ok(report)
+}
+
+/// Works out the path of every parcel, handling collisions. Nothing is written yet, so a
+/// collision error leaves the destination untouched
+async fn plan(
+    dest: &Path,
+    parcels: &[Parcel],
+    collision: Collision,
+) -> Result<Vec<(Label, PathBuf)>> {
+    let mut taken = HashSet::new();
+    let mut targets: Vec<(Label, PathBuf)> = Vec::with_capacity(parcels.len());
+    for parcel in parcels {
+        let label = &parcel.label;
+        let relative = relative_path(&label.name)?;
+        let mut path = dest.join(&relative);
+        let exists = taken.contains(&path) || tokio::fs::metadata(&path).await.is_ok();
+        if exists {
+            match collision {
+                Collision::Error => {
+                    return Err(ClientError::Other(format!(
+                        "Unable to unpack parcel {} to {}, as the path is already taken",
+                        label.sha256,
+                        path.display()
+                    )))
+                }
+                Collision::Overwrite => {
+                    // A later parcel with the same name replaces the earlier one
+                    targets.retain(|(_, p)| p != &path);
+                }
+                Collision::Rename => {
+                    path = dest.join(renamed(&relative, &label.sha256));
+                    debug!("Renaming parcel {} to {}", label.sha256, path.display());
+                }
+            }
+        }
+        taken.insert(path.clone());
+        targets.push((label.clone(), path));
+    }
+    Ok(targets)
+}
+
+/// Writes a single parcel to a partial file next to the path and moves it into place once it is
+/// verified. Returns false if the parcel doesn't exist
+async fn write_parcel<P: Provider + Sync>(
+    provider: &P,
+    inv: &Invoice,
+    label: &Label,
+    path: &Path,
+) -> Result<bool> {
+    let mut stream = match provider.get_parcel(&inv.bindle.id, &label.sha256).await {
+        Ok(s) => s,
+        Err(ProviderError::NotFound)
+        | Err(ProviderError::ProxyError(ClientError::ParcelNotFound)) => {
+            warn!("Parcel {} does not exist", label.sha256);
+            return Ok(false);
+        }
+        Err(e) => return Err(map_provider_error(e)),
+    };
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let mut file = tokio::fs::File::create(&partial).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(map_provider_error)?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    drop(file);
+
+    let actual = format!("{:x}", hasher.finalize());
+    if actual != label.sha256 {
+        tokio::fs::remove_file(&partial).await?;
+        return Err(ClientError::DigestMismatch {
+            expected: label.sha256.clone(),
+            actual,
+        });
+    }
+    tokio::fs::rename(&partial, path).await?;
+    debug!("Unpacked parcel {} to {}", label.sha256, path.display());
+    Ok(true)
+}
+
+/// Turns a label name into a path relative to the destination, refusing anything that could
+/// escape it
+fn relative_path(name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    let valid = !name.is_empty() && path.components().all(|c| matches!(c, Component::Normal(_)));
+    if !valid {
+        return Err(ClientError::Other(format!(
+            "Parcel name {:?} is not a relative path within the destination",
+            name
+        )));
+    }
+    Ok(path.to_owned())
+}
+
+/// Adds the start of the SHA to the file name, before its extension
+fn renamed(relative: &Path, sha: &str) -> PathBuf {
+    let short = &sha[..sha.len().min(8)];
+    let stem = relative
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match relative.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, short, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, short),
+    };
+    relative.with_file_name(name)
+}
+
+fn map_provider_error(e: ProviderError) -> ClientError {
+    match e {
+        ProviderError::ProxyError(e) => e,
+        ProviderError::Io(e) => e.into(),
+        e => ClientError::Other(format!("Unable to get parcel: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    use tokio_util::codec::{BytesCodec, FramedRead};
+
+    #[tokio::test]
+    async fn test_unpack() {
+        let (store, _) = testing::setup().await;
+        let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+        let mut inv = scaffold.invoice.clone();
+        // Put one parcel in a group, so it can be filtered out
+        inv.group = Some(vec![crate::Group {
+            name: "extras".to_owned(),
+            required: Some(false),
+            satisfied_by: None,
+        }]);
+        let parcels = inv.parcel.as_mut().expect("scaffold should have parcels");
+        parcels[0].conditions = Some(crate::Condition {
+            member_of: Some(vec!["extras".to_owned()]),
+            requires: None,
+        });
+        let extra = parcels[0].label.clone();
+        store
+            .create_invoice(&inv)
+            .await
+            .expect("Unable to create invoice");
+        for info in scaffold.parcel_files.values() {
+            let data = std::io::Cursor::new(info.data.clone());
+            store
+                .create_parcel(
+                    &inv.bindle.id,
+                    &info.sha,
+                    FramedRead::new(data, BytesCodec::new()),
+                )
+                .await
+                .expect("Unable to create parcel");
+        }
+
+        let dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let options = UnpackOptions {
+            groups: Some(Vec::new()),
+            ..UnpackOptions::default()
+        };
+        let report = unpack(&store, &inv, dir.path(), &options)
+            .await
+            .expect("unpack should succeed");
+        assert!(report.is_complete());
+        let total = inv.parcel.as_ref().unwrap().len();
+        assert_eq!(total - 1, report.files.len());
+        assert!(!dir.path().join(&extra.name).exists());
+        for (label, path) in &report.files {
+            assert_eq!(dir.path().join(&label.name), *path);
+            let data = tokio::fs::read(path).await.expect("file should exist");
+            assert_eq!(label.size, data.len() as u64);
+        }
+
+        // Everything is unpacked without a group filter, but existing files collide
+        let err = unpack(&store, &inv, dir.path(), &UnpackOptions::default()).await;
+        assert!(err.is_err(), "existing files should collide");
+        assert!(!dir.path().join(&extra.name).exists());
+
+        let options = UnpackOptions {
+            collision: Collision::Rename,
+            ..UnpackOptions::default()
+        };
+        let report = unpack(&store, &inv, dir.path(), &options)
+            .await
+            .expect("unpack should succeed");
+        assert_eq!(total, report.files.len());
+        assert!(dir.path().join(&extra.name).exists());
+        let renamed = report
+            .files
+            .iter()
+            .filter(|(l, p)| *p != dir.path().join(&l.name))
+            .count();
+        assert_eq!(total - 1, renamed);
+
+        let options = UnpackOptions {
+            collision: Collision::Overwrite,
+            ..UnpackOptions::default()
+        };
+        let report = unpack(&store, &inv, dir.path(), &options)
+            .await
+            .expect("unpack should succeed");
+        assert_eq!(total, report.files.len());
+    }
+
+    #[test]
+    fn test_paths() {
+        assert!(relative_path("static/site.css").is_ok());
+        assert!(relative_path("../escape").is_err());
+        assert!(relative_path("/etc/passwd").is_err());
+        assert!(relative_path("a/./b").is_ok());
+        assert!(relative_path("").is_err());
+        assert_eq!(
+            Path::new("static/site-abcdef12.css"),
+            renamed(Path::new("static/site.css"), "abcdef1234")
+        );
+        assert_eq!(
+            Path::new("LICENSE-abc"),
+            renamed(Path::new("LICENSE"), "abc")
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Supervision of the tasks that run in the background of a server.
+//!
+//! Everything that outlives the request that started it is spawned through a
+//! [`TaskRegistry`](TaskRegistry), so that it is tracked and can be stopped when the server shuts
+//! down, instead of being cut off halfway when the runtime is dropped. There are two kinds of
+//! tasks:
+//!
+//! - Services, such as periodic garbage collection or replication, run until the server stops.
+//!   They are restarted according to their [`RestartPolicy`](RestartPolicy) if they panic, and are
+//!   cancelled as soon as the registry shuts down
+//! - Jobs, such as processing a new bindle or delivering an event to a hook, do a single piece of
+//!   work. When the registry shuts down, they are given a grace period to finish before they are
+//!   cancelled
+//!
+//! A panic in a task is logged and never takes anything else down with it. Once the registry is
+//! shut down, new tasks are no longer started
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{abortable, AbortHandle, Aborted};
+use futures::FutureExt;
+use log::{debug, error, warn};
+use tokio::sync::Notify;
+
+/// What happens when a service panics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// The service stays stopped
+    Never,
+    /// The service is started again after the given delay, up to `max_restarts` times
+    OnPanic { max_restarts: u32, delay: Duration },
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::OnPanic {
+            max_restarts: 5,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Tracks the background tasks of a server. Clones share the same tasks
+#[derive(Clone, Default)]
+pub struct TaskRegistry {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Mutex<State>,
+    /// Notified whenever a task finishes
+    finished: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    closed: bool,
+    next_id: u64,
+    tasks: HashMap<u64, Task>,
+}
+
+struct Task {
+    name: String,
+    service: bool,
+    /// Cancels the current run of the task. Not set while a service waits to be restarted
+    abort: Option<AbortHandle>,
+}
+
+impl TaskRegistry {
+    /// Spawns a job, which is given a grace period to finish when the registry shuts down. Jobs
+    /// spawned after the registry was shut down are dropped without running
+    pub fn spawn<F>(&self, name: impl Into<String>, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        let (job, abort) = abortable(job);
+        let id = match self.register(&name, false, Some(abort)) {
+            Some(id) => id,
+            None => return,
+        };
+        let registry = self.clone();
+        tokio::spawn(async move {
+            match AssertUnwindSafe(job).catch_unwind().await {
+                Ok(Ok(())) => (),
+                Ok(Err(Aborted)) => debug!("Task {} was cancelled", name),
+                Err(_) => error!("Task {} panicked", name),
+            }
+            registry.finished(id);
+        });
+    }
+
+    /// Spawns a service, which runs the futures returned by `start` until one of them finishes on
+    /// its own, the registry shuts down, or it panics more often than the policy allows
+    pub fn spawn_service<F, Fut>(&self, name: impl Into<String>, policy: RestartPolicy, start: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        let id = match self.register(&name, true, None) {
+            Some(id) => id,
+            None => return,
+        };
+        let registry = self.clone();
+        tokio::spawn(async move {
+            let mut restarts = 0;
+            loop {
+                let (run, abort) = abortable(start());
+                if !registry.set_abort(id, abort) {
+                    break;
+                }
+                match AssertUnwindSafe(run).catch_unwind().await {
+                    Ok(Ok(())) => {
+                        debug!("Service {} finished", name);
+                        break;
+                    }
+                    Ok(Err(Aborted)) => {
+                        debug!("Service {} was cancelled", name);
+                        break;
+                    }
+                    Err(_) => error!("Service {} panicked", name),
+                }
+                match policy {
+                    RestartPolicy::OnPanic {
+                        max_restarts,
+                        delay,
+                    } if restarts < max_restarts => {
+                        restarts += 1;
+                        warn!(
+                            "Restarting service {} in {:?} (restart {} of {})",
+                            name, delay, restarts, max_restarts
+                        );
+                        registry.clear_abort(id);
+                        tokio::time::delay_for(delay).await;
+                    }
+                    _ => {
+                        error!("Service {} stopped after panicking", name);
+                        break;
+                    }
+                }
+            }
+            registry.finished(id);
+        });
+    }
+
+    /// Returns the names of the tasks that are currently tracked, sorted by name
+    pub fn running(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .tasks
+            .values()
+            .map(|t| t.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Stops all tasks. Services are cancelled right away, while jobs get up to `grace` to finish
+    /// before they are cancelled as well. Returns once every task has stopped
+    pub async fn shutdown(&self, grace: Duration) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            state.closed = true;
+            for task in state.tasks.values().filter(|t| t.service) {
+                if let Some(abort) = &task.abort {
+                    abort.abort();
+                }
+            }
+        }
+        if tokio::time::timeout(grace, self.wait()).await.is_ok() {
+            return;
+        }
+        {
+            let state = self.inner.state.lock().unwrap();
+            for task in state.tasks.values() {
+                warn!("Cancelling task {} as it did not finish in time", task.name);
+                if let Some(abort) = &task.abort {
+                    abort.abort();
+                }
+            }
+        }
+        self.wait().await
+    }
+
+    /// Waits until no tasks are tracked anymore
+    async fn wait(&self) {
+        loop {
+            if self.inner.state.lock().unwrap().tasks.is_empty() {
+                return;
+            }
+            // A task finishing before this is awaited leaves a permit, so it isn't missed
+            self.inner.finished.notified().await;
+        }
+    }
+
+    fn register(&self, name: &str, service: bool, abort: Option<AbortHandle>) -> Option<u64> {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.closed {
+            debug!("Not starting task {} as the server is shutting down", name);
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.tasks.insert(
+            id,
+            Task {
+                name: name.to_owned(),
+                service,
+                abort,
+            },
+        );
+        Some(id)
+    }
+
+    /// Sets the handle cancelling the current run of a service. Returns false if the registry was
+    /// shut down in the meantime, in which case the run is cancelled right away
+    fn set_abort(&self, id: u64, abort: AbortHandle) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.closed {
+            abort.abort();
+            return false;
+        }
+        if let Some(task) = state.tasks.get_mut(&id) {
+            task.abort = Some(abort);
+        }
+        true
+    }
+
+    fn clear_abort(&self, id: u64) {
+        if let Some(task) = self.inner.state.lock().unwrap().tasks.get_mut(&id) {
+            task.abort = None;
+        }
+    }
+
+    fn finished(&self, id: u64) {
+        self.inner.state.lock().unwrap().tasks.remove(&id);
+        self.inner.finished.notify();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let tasks = TaskRegistry::default();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tasks.spawn("quick", async move {
+            tx.send(()).unwrap();
+        });
+        tasks.spawn("panicking", async { panic!("job failed") });
+        tasks.spawn("stuck", futures::future::pending());
+        rx.await.expect("job should run");
+
+        // The stuck job is cancelled once the grace period is over
+        tasks.shutdown(Duration::from_millis(50)).await;
+        assert!(tasks.running().is_empty());
+
+        let ran = Arc::new(AtomicU32::new(0));
+        let counter = ran.clone();
+        tasks.spawn("late", async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(0, ran.load(Ordering::SeqCst));
+        assert!(tasks.running().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_services() {
+        let tasks = TaskRegistry::default();
+        let starts = Arc::new(AtomicU32::new(0));
+        let counter = starts.clone();
+        tasks.spawn_service(
+            "flaky",
+            RestartPolicy::OnPanic {
+                max_restarts: 2,
+                delay: Duration::from_millis(1),
+            },
+            move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    panic!("service failed");
+                }
+            },
+        );
+        tasks.spawn_service("forever", RestartPolicy::Never, || {
+            futures::future::pending()
+        });
+        while tasks.running().len() > 1 {
+            tokio::time::delay_for(Duration::from_millis(5)).await;
+        }
+        assert_eq!(
+            3,
+            starts.load(Ordering::SeqCst),
+            "Should start once and restart twice"
+        );
+        assert_eq!(vec!["forever"], tasks.running());
+
+        // Services are cancelled without waiting for the grace period
+        let shutdown = tasks.shutdown(Duration::from_secs(60));
+        tokio::time::timeout(Duration::from_secs(5), shutdown)
+            .await
+            .expect("services should be cancelled right away");
+        assert!(tasks.running().is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+//! Opt-in usage telemetry for command line tools built on bindle.
+//!
+//! Telemetry is off until a user turns it on (with `bindle telemetry enable` for the `bindle` CLI).
+//! Once enabled, only anonymous counts are kept: how often each command was run and how often it
+//! failed with each category of error. Arguments, bindle names, server URLs and error messages are
+//! never recorded. The counts are only stored in a local file (see [`Telemetry`](Telemetry)) and are
+//! never sent anywhere; users can export them as a [`UsageReport`](UsageReport) to share with the
+//! maintainers if they want to.
+//!
+//! Setting the `DO_NOT_TRACK` or `BINDLE_TELEMETRY_DISABLED` environment variable to anything
+//! other than `0` or an empty string disables telemetry even if it was enabled. Tools that embed
+//! bindle can leave out this module entirely by disabling the `telemetry` feature
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+/// The current version of the telemetry file format
+pub const TELEMETRY_FILE_VERSION: &str = "1.0";
+/// The environment variables that disable telemetry when set
+pub const DISABLE_ENV_VARS: &[&str] = &["DO_NOT_TRACK", "BINDLE_TELEMETRY_DISABLED"];
+
+/// The usage data stored in the telemetry file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct UsageData {
+    pub version: String,
+    /// Whether the user has opted in to telemetry
+    #[serde(default)]
+    pub enabled: bool,
+    /// The UNIX timestamp (in seconds) at which data started to be collected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    /// How many times each command was run
+    #[serde(default)]
+    pub commands: BTreeMap<String, u64>,
+    /// How many times commands failed with each category of error
+    #[serde(default)]
+    pub errors: BTreeMap<String, u64>,
+}
+
+impl Default for UsageData {
+    fn default() -> Self {
+        UsageData {
+            version: TELEMETRY_FILE_VERSION.to_owned(),
+            enabled: false,
+            since: None,
+            commands: BTreeMap::new(),
+            errors: BTreeMap::new(),
+        }
+    }
+}
+
+/// A report of the collected usage data, meant to be shared with the maintainers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageReport {
+    /// The version of bindle that created the report
+    pub bindle_version: String,
+    /// The UNIX timestamp (in seconds) at which the report was created
+    pub generated_at: u64,
+    /// The UNIX timestamp (in seconds) at which data started to be collected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    /// How many times each command was run
+    pub commands: BTreeMap<String, u64>,
+    /// How many times commands failed with each category of error
+    pub errors: BTreeMap<String, u64>,
+}
+
+/// The telemetry of a command line tool, backed by a TOML file. Nothing is recorded unless the
+/// user has enabled telemetry and it isn't disabled by the environment (see
+/// [`disabled_by_env`](disabled_by_env))
+#[derive(Debug, Clone)]
+pub struct Telemetry {
+    path: PathBuf,
+    data: UsageData,
+}
+
+impl Telemetry {
+    /// Loads the telemetry file at the given path. Telemetry is disabled if the file doesn't exist
+    pub async fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let data = match tokio::fs::read(path.as_ref()).await {
+            Ok(raw) => toml::from_slice(&raw)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => UsageData::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Telemetry {
+            path: path.as_ref().to_owned(),
+            data,
+        })
+    }
+
+    /// Writes the telemetry file, creating its parent directory if needed
+    pub async fn save(&self) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let raw = toml::to_vec(&self.data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        tokio::fs::write(&self.path, raw).await
+    }
+
+    /// Returns the collected data
+    pub fn data(&self) -> &UsageData {
+        &self.data
+    }
+
+    /// Returns whether usage is recorded: the user has enabled telemetry and it isn't disabled by
+    /// the environment
+    pub fn is_enabled(&self) -> bool {
+        self.data.enabled && !disabled_by_env()
+    }
+
+    /// Opts in to telemetry. Data is collected from now on
+    pub fn enable(&mut self) {
+        if !self.data.enabled {
+            self.data.enabled = true;
+            self.data.since = Some(now());
+        }
+    }
+
+    /// Opts out of telemetry, discarding all data collected so far
+    pub fn disable(&mut self) {
+        self.data = UsageData::default();
+    }
+
+    /// Discards all data collected so far, without changing whether telemetry is enabled
+    pub fn clear(&mut self) {
+        self.data.commands.clear();
+        self.data.errors.clear();
+        self.data.since = Some(now()).filter(|_| self.data.enabled);
+    }
+
+    /// Records that the command with the given name was run. Does nothing unless telemetry is
+    /// enabled
+    pub fn record_command(&mut self, command: &str) {
+        if self.is_enabled() {
+            *self.data.commands.entry(command.to_owned()).or_default() += 1;
+        }
+    }
+
+    /// Records that a command failed with the given category of error. Does nothing unless
+    /// telemetry is enabled
+    pub fn record_error(&mut self, category: &str) {
+        if self.is_enabled() {
+            *self.data.errors.entry(category.to_owned()).or_default() += 1;
+        }
+    }
+
+    /// Returns a report of the data collected so far
+    pub fn report(&self) -> UsageReport {
+        UsageReport {
+            bindle_version: env!("CARGO_PKG_VERSION").to_owned(),
+            generated_at: now(),
+            since: self.data.since,
+            commands: self.data.commands.clone(),
+            errors: self.data.errors.clone(),
+        }
+    }
+}
+
+/// Returns whether telemetry is disabled by one of the [`DISABLE_ENV_VARS`](DISABLE_ENV_VARS)
+pub fn disabled_by_env() -> bool {
+    DISABLE_ENV_VARS.iter().any(|name| {
+        std::env::var(name)
+            .map(|v| !v.is_empty() && v != "0")
+            .unwrap_or(false)
+    })
+}
+
+/// Returns the category of a client error that is recorded in place of the error itself, which
+/// could contain names or URLs
+#[cfg(feature = "client")]
+pub fn error_category(error: &crate::client::ClientError) -> &'static str {
+    use crate::client::ClientError;
+
+    match error {
+        ClientError::InvalidURL(_) | ClientError::InvalidConfig(_) => "invalid_config",
+        ClientError::Io(_) => "io",
+        ClientError::InvalidToml(_)
+        | ClientError::TomlSerializationError(_)
+        | ClientError::InvalidJson(_) => "serialization",
+        ClientError::HttpClientError(_) => "http",
+        ClientError::InvalidId(_) => "invalid_id",
+        ClientError::SignatureError(_) => "signature",
+        ClientError::TransparencyError(_) => "transparency",
+        ClientError::InvoiceNotFound | ClientError::ParcelNotFound => "not_found",
+        ClientError::InvoiceAlreadyExists | ClientError::ParcelAlreadyExists => "already_exists",
+        ClientError::VerificationFailed(_) => "verification",
+        ClientError::SigningPolicyNotSatisfied(_) => "signing_policy",
+        ClientError::QuotaExceeded(_) => "quota_exceeded",
+        ClientError::PreconditionFailed => "precondition_failed",
+        ClientError::InvalidRequest { .. } => "invalid_request",
+        ClientError::DigestMismatch { .. } | ClientError::SizeMismatch { .. } => "data_mismatch",
+        ClientError::ServerError(_) => "server_error",
+        ClientError::Unauthorized | ClientError::TokenError(_) => "unauthorized",
+        ClientError::IncompatibleServer { .. } => "incompatible_server",
+        ClientError::Other(_) => "other",
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_opt_in() {
+        let dir = tempfile::tempdir().expect("unable to create tempdir");
+        let path = dir.path().join("nested").join("telemetry.toml");
+
+        // Nothing is recorded until telemetry is enabled
+        let mut telemetry = Telemetry::load(&path).await.expect("should load");
+        assert!(!telemetry.is_enabled());
+        telemetry.record_command("push");
+        telemetry.record_error("io");
+        assert_eq!(UsageData::default(), *telemetry.data());
+
+        telemetry.enable();
+        telemetry.record_command("push");
+        telemetry.record_command("push");
+        telemetry.record_command("get");
+        telemetry.record_error("not_found");
+        telemetry.save().await.expect("should save");
+
+        let mut telemetry = Telemetry::load(&path).await.expect("should load");
+        assert!(telemetry.is_enabled());
+        let report = telemetry.report();
+        assert_eq!(Some(&2), report.commands.get("push"));
+        assert_eq!(Some(&1), report.commands.get("get"));
+        assert_eq!(Some(&1), report.errors.get("not_found"));
+        assert!(report.since.is_some());
+
+        telemetry.clear();
+        assert!(telemetry.data().commands.is_empty());
+        assert!(telemetry.is_enabled());
+
+        // Opting out discards everything
+        telemetry.record_command("push");
+        telemetry.disable();
+        assert!(!telemetry.is_enabled());
+        assert_eq!(UsageData::default(), *telemetry.data());
+    }
+}
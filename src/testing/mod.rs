@@ -12,6 +12,7 @@ use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 use crate::provider::file::FileProvider;
+use crate::provider::memory::InMemoryProvider;
 use crate::search::StrictEngine;
 
 use sha2::{Digest, Sha256};
@@ -149,6 +150,85 @@ impl From<RawScaffold> for Scaffold {
     }
 }
 
+/// Downloads the invoice with the given ID and all of its parcels from a running server and writes
+/// them as a new scaffold into the scaffolds directory, so a real-world bindle can be turned into a
+/// reproducible test fixture. Returns the name of the scaffold, for use with
+/// [`Scaffold::load`](Scaffold::load), which is the bindle ID with every character that isn't
+/// alphanumeric replaced by `_` (e.g. `example_com_app_1_0_0`). An existing scaffold with the same
+/// name is overwritten.
+///
+/// The invoice is written exactly as the server returned it, signatures included. Each parcel is
+/// stored under its SHA, as parcel names can contain characters that aren't valid in file names
+#[cfg(feature = "client")]
+pub async fn snapshot_from_server<I>(client: &crate::client::Client, id: I) -> String
+where
+    I: std::convert::TryInto<crate::Id>,
+    I::Error: Into<crate::client::ClientError>,
+{
+    let id: crate::Id = id
+        .try_into()
+        .map_err(Into::<crate::client::ClientError>::into)
+        .expect("Invalid bindle ID");
+    let name: String = id
+        .to_string()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    snapshot_from_server_to(client, &id, scaffold_dir().join(&name)).await;
+    name
+}
+
+/// The same as [`snapshot_from_server`](snapshot_from_server), but writes the scaffold into the
+/// given directory, which is created if it doesn't exist. Parcels of a scaffold that was in the
+/// directory before are removed
+#[cfg(feature = "client")]
+pub async fn snapshot_from_server_to<I>(
+    client: &crate::client::Client,
+    id: I,
+    dir: impl AsRef<Path>,
+) where
+    I: std::convert::TryInto<crate::Id>,
+    I::Error: Into<crate::client::ClientError>,
+{
+    let id: crate::Id = id
+        .try_into()
+        .map_err(Into::<crate::client::ClientError>::into)
+        .expect("Invalid bindle ID");
+    let invoice = client
+        .get_invoice(&id)
+        .await
+        .expect("Unable to fetch invoice from server");
+
+    let parcel_dir = dir.as_ref().join(PARCEL_DIR);
+    if parcel_dir.is_dir() {
+        tokio::fs::remove_dir_all(&parcel_dir)
+            .await
+            .expect("Unable to remove old parcel directory");
+    }
+    tokio::fs::create_dir_all(&parcel_dir)
+        .await
+        .expect("Unable to create scaffold directory");
+    let invoice_toml = toml::to_vec(&invoice).expect("Reserialization shouldn't fail");
+    tokio::fs::write(dir.as_ref().join(INVOICE_FILE), invoice_toml)
+        .await
+        .expect("Unable to write invoice file");
+
+    for label in invoice.parcel.iter().flatten().map(|p| &p.label) {
+        let path = parcel_dir.join(format!("{}.{}", label.sha256, PARCEL_EXTENSION));
+        if path.is_file() {
+            // The same parcel can be listed more than once
+            continue;
+        }
+        let data = client
+            .get_parcel(&id, &label.sha256)
+            .await
+            .expect("Unable to fetch parcel from server");
+        tokio::fs::write(&path, data)
+            .await
+            .expect("Unable to write parcel file");
+    }
+}
+
 /// Returns a file `Store` implementation configured with a temporary directory and strict Search
 /// implementation for use in testing API endpoints
 pub async fn setup() -> (FileProvider<StrictEngine>, StrictEngine) {
@@ -158,6 +238,14 @@ pub async fn setup() -> (FileProvider<StrictEngine>, StrictEngine) {
     (store, index)
 }
 
+/// The same as [`setup`](setup), but returns an [`InMemoryProvider`](InMemoryProvider) instead, for
+/// tests that don't need anything written to disk
+pub fn setup_in_memory() -> (InMemoryProvider<StrictEngine>, StrictEngine) {
+    let index = StrictEngine::default();
+    let store = InMemoryProvider::new(index.clone());
+    (store, index)
+}
+
 /// Loads all scaffolds in the scaffolds directory, returning them as a hashmap with the directory
 /// name as the key and a `RawScaffold` as a value. There is not an equivalent for loading all
 /// scaffolds as a `Scaffold` object, because some of them may be invalid on will not deserialize
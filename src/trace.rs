@@ -0,0 +1,266 @@
+//! Correlating the work done for a request across the client, server and storage.
+//!
+//! The server and client create [`tracing`](https://docs.rs/tracing) spans for requests and storage
+//! operations. Requests carry a [W3C Trace Context](https://www.w3.org/TR/trace-context/)
+//! `traceparent` header, so every span of the server has the `trace_id` of the client request that
+//! caused it, along with a `request_id` that is unique for each request. A client sends the trace of
+//! the [`scope`](scope) it runs in, so all requests made for one task, such as pushing a bindle,
+//! share the same trace ID. Requests outside of a scope each start a new trace
+//!
+//! With the `otlp` feature, [`otlp::install`](otlp::install) exports the spans to an OpenTelemetry
+//! collector, where the spans of the client and server are joined into a single trace
+
+use std::fmt;
+
+/// The header that carries the trace context of a request
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+/// The header a request ID can be given in. If not given, the server generates one
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The only version of the `traceparent` header format
+const VERSION: &str = "00";
+const SAMPLED_FLAG: u8 = 0x01;
+
+/// The position of an operation in a trace, in the format of a `traceparent` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    /// The ID of the whole trace, which is never 0
+    pub trace_id: u128,
+    /// The ID of the operation within the trace, which is never 0
+    pub parent_id: u64,
+    /// Whether the operation is recorded by the caller
+    pub sampled: bool,
+}
+
+impl TraceParent {
+    /// Starts a new trace
+    pub fn new_root() -> Self {
+        TraceParent {
+            trace_id: rand::random::<u128>().max(1),
+            parent_id: rand::random::<u64>().max(1),
+            sampled: true,
+        }
+    }
+
+    /// Returns a new operation within the same trace
+    pub fn child(&self) -> Self {
+        TraceParent {
+            parent_id: rand::random::<u64>().max(1),
+            ..*self
+        }
+    }
+
+    /// Returns the trace ID as it is written in the header
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    /// Returns the ID of the operation as it is written in the header
+    pub fn parent_id(&self) -> String {
+        format!("{:016x}", self.parent_id)
+    }
+
+    /// Parses the value of a `traceparent` header, returning `None` if it is invalid. Later versions
+    /// of the format are read as far as they are compatible with the current one
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next()?;
+        let (trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?);
+        let valid_version = version.len() == 2 && is_lower_hex(version) && version != "ff";
+        if !valid_version
+            || (version == VERSION && parts.next().is_some())
+            || trace_id.len() != 32
+            || parent_id.len() != 16
+            || flags.len() != 2
+            || ![trace_id, parent_id, flags].iter().all(|p| is_lower_hex(p))
+        {
+            return None;
+        }
+        let parsed = TraceParent {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            parent_id: u64::from_str_radix(parent_id, 16).ok()?,
+            sampled: u8::from_str_radix(flags, 16).ok()? & SAMPLED_FLAG != 0,
+        };
+        if parsed.trace_id == 0 || parsed.parent_id == 0 {
+            return None;
+        }
+        Some(parsed)
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{:032x}-{:016x}-{:02x}",
+            VERSION,
+            self.trace_id,
+            self.parent_id,
+            if self.sampled { SAMPLED_FLAG } else { 0 }
+        )
+    }
+}
+
+fn is_lower_hex(value: &str) -> bool {
+    value
+        .chars()
+        .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+#[cfg(feature = "async")]
+tokio::task_local! {
+    static CURRENT: TraceParent;
+}
+
+/// Runs the given future as part of the given trace. Requests made by a
+/// [`Client`](crate::client::Client) within the future carry the trace
+#[cfg(feature = "async")]
+pub async fn scope<F: std::future::Future>(trace: TraceParent, f: F) -> F::Output {
+    CURRENT.scope(trace, f).await
+}
+
+/// Returns the trace of the [`scope`](scope) the caller runs in, if any
+#[cfg(feature = "async")]
+pub fn current() -> Option<TraceParent> {
+    CURRENT.try_with(|t| *t).ok()
+}
+
+/// Returns the trace context to send with an outgoing request, which is made in its own span: the
+/// current OpenTelemetry span if spans are exported, otherwise a child of the current
+/// [`scope`](scope) or a new trace
+#[cfg(feature = "client")]
+pub(crate) fn outgoing() -> TraceParent {
+    #[cfg(feature = "otlp")]
+    {
+        if let Some(span) = otlp::from_span(&tracing::Span::current()) {
+            return span;
+        }
+    }
+    current()
+        .map(|t| t.child())
+        .unwrap_or_else(TraceParent::new_root)
+}
+
+/// Exporting spans to an [OpenTelemetry](https://opentelemetry.io) collector using OTLP. Only
+/// available with the `otlp` feature
+#[cfg(feature = "otlp")]
+pub mod otlp {
+    use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceId, TraceState};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::TraceParent;
+
+    /// Flushes the remaining spans to the collector when dropped
+    #[must_use]
+    pub struct Uninstall(#[allow(dead_code)] opentelemetry_otlp::Uninstall);
+
+    impl std::fmt::Debug for Uninstall {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Uninstall").finish()
+        }
+    }
+
+    /// Installs a global `tracing` subscriber that exports all spans to the OTLP collector at the
+    /// given endpoint (e.g. `http://localhost:4317`) as the given service. Events, including the
+    /// ones logged with the `log` crate, are written to stderr filtered by `RUST_LOG`, so this
+    /// replaces a logger like `env_logger`. Must be called from within a tokio runtime
+    pub fn install(endpoint: &str, service_name: &str) -> anyhow::Result<Uninstall> {
+        let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+            .with_endpoint(endpoint)
+            .with_trace_config(opentelemetry::sdk::trace::config().with_resource(
+                opentelemetry::sdk::Resource::new(vec![opentelemetry::KeyValue::new(
+                    "service.name",
+                    service_name.to_owned(),
+                )]),
+            ))
+            .install()?;
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_subscriber::EnvFilter::from_default_env())
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::set_global_default(subscriber)?;
+        tracing_log::LogTracer::init()?;
+        Ok(Uninstall(uninstall))
+    }
+
+    /// Makes the given span a child of the operation in the given trace
+    pub(crate) fn set_parent(span: &tracing::Span, parent: &TraceParent) {
+        let context = SpanContext::new(
+            TraceId::from_u128(parent.trace_id),
+            SpanId::from_u64(parent.parent_id),
+            if parent.sampled {
+                super::SAMPLED_FLAG
+            } else {
+                0
+            },
+            true,
+            TraceState::default(),
+        );
+        span.set_parent(opentelemetry::Context::new().with_remote_span_context(context));
+    }
+
+    /// Returns the position of the given span in its trace, if it is exported
+    pub(crate) fn from_span(span: &tracing::Span) -> Option<TraceParent> {
+        let context = span.context();
+        let span_context = context.span().span_context();
+        if !span_context.is_valid() {
+            return None;
+        }
+        Some(TraceParent {
+            trace_id: span_context.trace_id().to_u128(),
+            parent_id: span_context.span_id().to_u64(),
+            sampled: span_context.is_sampled(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let header = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let parsed = TraceParent::parse(header).expect("header should parse");
+        assert_eq!(0x0af7651916cd43dd8448eb211c80319c, parsed.trace_id);
+        assert_eq!(0xb7ad6b7169203331, parsed.parent_id);
+        assert!(parsed.sampled);
+        assert_eq!(header, parsed.to_string());
+
+        let child = parsed.child();
+        assert_eq!(parsed.trace_id, child.trace_id);
+        assert_ne!(parsed.parent_id, child.parent_id);
+
+        // Later versions may add fields
+        assert!(TraceParent::parse(
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00-extra"
+        )
+        .is_some());
+        for invalid in &[
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+        ] {
+            assert!(
+                TraceParent::parse(invalid).is_none(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_scope() {
+        assert!(current().is_none());
+        let trace = TraceParent::new_root();
+        let inner = scope(trace, async { current() }).await;
+        assert_eq!(Some(trace), inner);
+    }
+}
@@ -0,0 +1,555 @@
+//! Recording invoice signatures in a transparency log and verifying that they were recorded.
+//!
+//! A transparency log is an append-only log (in the style of
+//! [Rekor](https://github.com/sigstore/rekor)) that anyone can audit. When a server is configured
+//! with a [`TransparencyLog`](TransparencyLog), the digest of every signature of a new invoice is
+//! recorded in it before the invoice is stored, and the [`LogEntry`](LogEntry) the log returns is
+//! attached to the signature. A signature that was recorded can't be swapped for another one or
+//! quietly removed from the log later without it being noticed, which gives published bindles
+//! tamper-evident provenance.
+//!
+//! A log entry contains an inclusion proof, which shows that the digest is part of the Merkle tree
+//! of the log (as described in [RFC 6962](https://tools.ietf.org/html/rfc6962#section-2.1)), and a
+//! signed entry timestamp, which is the signature of the log over the position of the entry and the
+//! root of the tree. Clients check both against the public key of a log they trust with
+//! [`Invoice::verify_log_entries`](crate::Invoice::verify_log_entries), without having to talk to
+//! the log.
+//!
+//! Log entries are stored in the `logEntry` field of a signature. As signatures deny unknown fields,
+//! tools that predate transparency logs can't read invoices with recorded signatures
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use ed25519_dalek::{Keypair, PublicKey, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::signature::Signature;
+use crate::Invoice;
+
+// The prefixes that keep leaf hashes and node hashes apart, as described in RFC 6962
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// A custom result type representing a possible transparency log error
+pub type Result<T> = std::result::Result<T, TransparencyError>;
+
+/// Describes the errors that can occur when recording signatures in a transparency log or
+/// verifying their log entries
+#[derive(Error, Debug)]
+pub enum TransparencyError {
+    /// None of the signatures of the invoice were recorded in a transparency log
+    #[error("No signature of the invoice has been recorded in a transparency log")]
+    NotLogged,
+    /// The entry was recorded in a different log than the one that is trusted
+    #[error("The signature by {0} was recorded in an untrusted transparency log")]
+    UntrustedLog(String),
+    /// The signed entry timestamp isn't a valid signature of the trusted log
+    #[error("The log entry of the signature by {0} has an invalid signed entry timestamp")]
+    InvalidTimestamp(String),
+    /// The inclusion proof doesn't show that the signature is part of the log
+    #[error("The log entry of the signature by {0} has an invalid inclusion proof")]
+    InvalidProof(String),
+    /// A log entry or key couldn't be decoded
+    #[error("Corrupt log entry: {0}")]
+    Corrupt(String),
+    /// The transparency log couldn't record a signature
+    #[error("Unable to record signature in transparency log: {0}")]
+    Log(String),
+}
+
+/// The entry of a signature in a transparency log, proving that the signature was recorded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct LogEntry {
+    /// The hex encoded SHA-256 of the public key of the log the entry was recorded in
+    pub log_id: String,
+    /// The position of the entry in the log
+    pub log_index: u64,
+    /// The UNIX timestamp (in seconds) at which the log recorded the entry
+    pub integrated_time: u64,
+    /// The base64 encoded signature of the log over the entry (see
+    /// [`LogEntry::signed_data`](LogEntry::signed_data))
+    pub signed_entry_timestamp: String,
+    /// The proof that the entry is included in the log. It comes last, as TOML tables must follow
+    /// the plain values
+    pub inclusion_proof: InclusionProof,
+}
+
+/// A proof that an entry is included in the Merkle tree of a log, as described in RFC 6962
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct InclusionProof {
+    /// The number of entries in the tree the proof was made for
+    pub tree_size: u64,
+    /// The hex encoded root hash of the tree the proof was made for
+    pub root_hash: String,
+    /// The hex encoded hashes of the audit path from the entry to the root
+    pub hashes: Vec<String>,
+}
+
+impl LogEntry {
+    /// Returns the data the log signs for the entry of the given signature digest: the log ID, the
+    /// log index, the time the entry was recorded, the tree size, the root hash and the digest,
+    /// separated by newlines
+    pub fn signed_data(&self, digest: &str) -> String {
+        [
+            self.log_id.clone(),
+            self.log_index.to_string(),
+            self.integrated_time.to_string(),
+            self.inclusion_proof.tree_size.to_string(),
+            self.inclusion_proof.root_hash.clone(),
+            digest.to_owned(),
+        ]
+        .join("\n")
+    }
+
+    /// Checks that the entry was recorded for the given signature digest in the log with the given
+    /// public key. `by` is only used for error messages
+    pub fn verify(&self, digest: &str, log_key: &PublicKey, by: &str) -> Result<()> {
+        if self.log_id != log_id(log_key) {
+            return Err(TransparencyError::UntrustedLog(by.to_owned()));
+        }
+        let timestamp = base64::decode(&self.signed_entry_timestamp)
+            .map_err(|e| TransparencyError::Corrupt(e.to_string()))?;
+        let timestamp = ed25519_dalek::Signature::try_from(timestamp.as_slice())
+            .map_err(|e| TransparencyError::Corrupt(e.to_string()))?;
+        if log_key
+            .verify(self.signed_data(digest).as_bytes(), &timestamp)
+            .is_err()
+        {
+            return Err(TransparencyError::InvalidTimestamp(by.to_owned()));
+        }
+        let leaf = leaf_hash(&decode_hex(digest)?);
+        if !self.inclusion_proof.verify(self.log_index, leaf)? {
+            return Err(TransparencyError::InvalidProof(by.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+impl InclusionProof {
+    /// Returns whether the audit path leads from the leaf hash at the given index to the root hash,
+    /// using the verification algorithm from RFC 9162
+    fn verify(&self, index: u64, leaf: [u8; 32]) -> Result<bool> {
+        if index >= self.tree_size {
+            return Ok(false);
+        }
+        let mut f = index;
+        let mut s = self.tree_size - 1;
+        let mut hash = leaf;
+        for sibling in &self.hashes {
+            if s == 0 {
+                return Ok(false);
+            }
+            let sibling = decode_hash(sibling)?;
+            if f & 1 == 1 || f == s {
+                hash = node_hash(&sibling, &hash);
+                while f & 1 == 0 && f != 0 {
+                    f >>= 1;
+                    s >>= 1;
+                }
+            } else {
+                hash = node_hash(&hash, &sibling);
+            }
+            f >>= 1;
+            s >>= 1;
+        }
+        Ok(s == 0 && hash == decode_hash(&self.root_hash)?)
+    }
+}
+
+/// A transparency log that signature digests can be recorded in
+#[async_trait::async_trait]
+pub trait TransparencyLog: fmt::Debug {
+    /// Records the hex encoded signature digest (see [`signature_digest`](signature_digest)) in the
+    /// log, returning its entry
+    async fn record(&self, digest: &str) -> Result<LogEntry>;
+}
+
+/// Returns the hex encoded digest of a signature that is recorded in a transparency log: the
+/// SHA-256 of the data that was signed (see the [Signing
+/// Spec](https://github.com/deislabs/bindle/blob/master/docs/signing-spec.md)), the public key and
+/// the signature, separated by newlines
+pub fn signature_digest(invoice: &Invoice, sig: &Signature) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(invoice.cleartext(&sig.by, sig.role, sig.at).as_bytes());
+    hasher.update(b"\n");
+    hasher.update(sig.key.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(sig.signature.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Returns the ID of the log with the given public key, which is the hex encoded SHA-256 of the
+/// key
+pub fn log_id(log_key: &PublicKey) -> String {
+    format!("{:x}", Sha256::digest(log_key.as_bytes()))
+}
+
+impl Invoice {
+    /// Records every signature of the invoice that doesn't have a log entry yet in the given log,
+    /// attaching the entries to the signatures. Returns the number of signatures that were recorded
+    pub async fn record_signatures(&mut self, log: &(dyn TransparencyLog + Sync)) -> Result<usize> {
+        let digests: Vec<(usize, String)> = self
+            .signature
+            .iter()
+            .flatten()
+            .enumerate()
+            .filter(|(_, sig)| sig.log_entry.is_none())
+            .map(|(i, sig)| (i, signature_digest(self, sig)))
+            .collect();
+        for (i, digest) in digests.iter() {
+            let entry = log.record(digest).await?;
+            if let Some(sig) = self.signature.as_mut().and_then(|s| s.get_mut(*i)) {
+                sig.log_entry = Some(entry);
+            }
+        }
+        Ok(digests.len())
+    }
+
+    /// Checks the log entries of the signatures of the invoice against the public key of a trusted
+    /// transparency log. Every log entry must be valid and at least one signature must have one.
+    /// This doesn't check the signatures themselves, which is done with
+    /// [`verify`](Invoice::verify)
+    pub fn verify_log_entries(&self, log_key: &PublicKey) -> Result<()> {
+        let mut logged = false;
+        for sig in self.signature.iter().flatten() {
+            if let Some(entry) = &sig.log_entry {
+                entry.verify(&signature_digest(self, sig), log_key, &sig.by)?;
+                logged = true;
+            }
+        }
+        if !logged {
+            return Err(TransparencyError::NotLogged);
+        }
+        Ok(())
+    }
+}
+
+/// A transparency log kept in memory and signed with the given key. Entries are lost when it is
+/// dropped, so it is mostly useful for testing
+pub struct MemoryLog {
+    keypair: Keypair,
+    leaves: Mutex<Vec<[u8; 32]>>,
+}
+
+impl MemoryLog {
+    /// Creates an empty log that signs its entries with the given key
+    pub fn new(keypair: Keypair) -> Self {
+        MemoryLog {
+            keypair,
+            leaves: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the public key entries of the log are verified with
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.public
+    }
+}
+
+impl fmt::Debug for MemoryLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryLog")
+            .field("log_id", &log_id(&self.keypair.public))
+            .field("entries", &self.leaves.lock().unwrap().len())
+            .finish()
+    }
+}
+
+#[async_trait::async_trait]
+impl TransparencyLog for MemoryLog {
+    async fn record(&self, digest: &str) -> Result<LogEntry> {
+        let leaf = leaf_hash(&decode_hex(digest)?);
+        let mut leaves = self.leaves.lock().unwrap();
+        leaves.push(leaf);
+        let index = leaves.len() - 1;
+        let mut entry = LogEntry {
+            log_id: log_id(&self.keypair.public),
+            log_index: index as u64,
+            integrated_time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            inclusion_proof: InclusionProof {
+                tree_size: leaves.len() as u64,
+                root_hash: encode_hex(&tree_hash(&leaves)),
+                hashes: audit_path(index, &leaves).iter().map(encode_hex).collect(),
+            },
+            signed_entry_timestamp: String::new(),
+        };
+        let timestamp = self.keypair.sign(entry.signed_data(digest).as_bytes());
+        entry.signed_entry_timestamp = base64::encode(timestamp.to_bytes());
+        Ok(entry)
+    }
+}
+
+/// A transparency log that is reached over HTTP. Digests are recorded by posting
+/// `{"digest": "<hex>"}` as JSON to `{url}/api/v1/log/entries`, which must reply with the
+/// [`LogEntry`](LogEntry) as JSON. Every entry the log returns is verified against its public key,
+/// so a misbehaving log is noticed when a signature is recorded rather than when it is verified
+#[cfg(feature = "client")]
+#[derive(Clone)]
+pub struct HttpLog {
+    client: reqwest::Client,
+    url: url::Url,
+    key: PublicKey,
+}
+
+#[cfg(feature = "client")]
+impl HttpLog {
+    /// How long recording a single digest may take
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+    /// Creates a client for the log at the given base URL, which signs its entries with the given
+    /// public key
+    pub fn new(url: &str, key: PublicKey) -> std::result::Result<Self, url::ParseError> {
+        // The trailing slash keeps the last path segment of the base URL when joining
+        let mut url: url::Url = url.parse()?;
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(HttpLog {
+            client: reqwest::Client::builder()
+                .timeout(Self::TIMEOUT)
+                .build()
+                .expect("Unable to build HTTP client"),
+            url: url.join("api/v1/log/entries")?,
+            key,
+        })
+    }
+}
+
+#[cfg(feature = "client")]
+impl fmt::Debug for HttpLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpLog")
+            .field("url", &self.url.as_str())
+            .field("log_id", &log_id(&self.key))
+            .finish()
+    }
+}
+
+#[cfg(feature = "client")]
+#[async_trait::async_trait]
+impl TransparencyLog for HttpLog {
+    async fn record(&self, digest: &str) -> Result<LogEntry> {
+        let resp = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::json!({ "digest": digest }).to_string())
+            .send()
+            .await
+            .map_err(|e| TransparencyError::Log(e.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(TransparencyError::Log(format!(
+                "Received status {}",
+                resp.status()
+            )));
+        }
+        let body = resp
+            .bytes()
+            .await
+            .map_err(|e| TransparencyError::Log(e.to_string()))?;
+        let entry: LogEntry =
+            serde_json::from_slice(&body).map_err(|e| TransparencyError::Log(e.to_string()))?;
+        entry
+            .verify(digest, &self.key, digest)
+            .map_err(|e| TransparencyError::Log(format!("Log returned an invalid entry: {}", e)))?;
+        Ok(entry)
+    }
+}
+
+fn leaf_hash(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Returns the largest power of two smaller than `n`, which is where RFC 6962 splits a tree of `n`
+/// leaves. `n` must be at least 2
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// Returns the root hash of the tree with the given leaf hashes
+fn tree_hash(leaves: &[[u8; 32]]) -> [u8; 32] {
+    match leaves.len() {
+        0 => Sha256::digest(&[]).into(),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&tree_hash(&leaves[..k]), &tree_hash(&leaves[k..]))
+        }
+    }
+}
+
+/// Returns the audit path of the leaf at the given index, from the leaf up to the root
+fn audit_path(index: usize, leaves: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    if leaves.len() <= 1 {
+        return Vec::new();
+    }
+    let k = split(leaves.len());
+    let (mut path, sibling) = if index < k {
+        (audit_path(index, &leaves[..k]), tree_hash(&leaves[k..]))
+    } else {
+        (audit_path(index - k, &leaves[k..]), tree_hash(&leaves[..k]))
+    };
+    path.push(sibling);
+    path
+}
+
+fn encode_hex(bytes: &[u8; 32]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() & 1 == 1 || !hex.is_ascii() {
+        return Err(TransparencyError::Corrupt(format!("Invalid hex: {}", hex)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| TransparencyError::Corrupt(format!("Invalid hex: {}", hex)))
+        })
+        .collect()
+}
+
+fn decode_hash(hex: &str) -> Result<[u8; 32]> {
+    let bytes = decode_hex(hex)?;
+    <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| TransparencyError::Corrupt(format!("Invalid SHA-256 hash: {}", hex)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signature::SignatureRole;
+
+    fn signed_invoice() -> Invoice {
+        let mut inv: Invoice = toml::from_str(
+            r#"
+            bindleVersion = "1.0.0"
+
+            [bindle]
+            name = "example.com/transparent"
+            version = "1.0.0"
+
+            [[parcel]]
+            [parcel.label]
+            sha256 = "e1706ab0a39ac88094b6d54a3f5cdba41fe5a901"
+            mediaType = "text/html"
+            name = "myparcel.html"
+            size = 248098
+            "#,
+        )
+        .expect("invoice should parse");
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        inv.sign(SignatureRole::Creator, "Test", &keypair)
+            .expect("invoice should sign");
+        inv.sign(SignatureRole::Host, "Host", &keypair)
+            .expect("invoice should sign");
+        inv
+    }
+
+    #[test]
+    fn test_inclusion_proofs() {
+        // Every entry of trees of different shapes must have a valid proof, which must fail for any
+        // other entry
+        for size in 1..=9usize {
+            let leaves: Vec<[u8; 32]> = (0..size).map(|i| leaf_hash(&[i as u8])).collect();
+            let root = encode_hex(&tree_hash(&leaves));
+            for index in 0..size {
+                let proof = InclusionProof {
+                    tree_size: size as u64,
+                    root_hash: root.clone(),
+                    hashes: audit_path(index, &leaves).iter().map(encode_hex).collect(),
+                };
+                assert!(
+                    proof.verify(index as u64, leaves[index]).unwrap(),
+                    "Proof for entry {} of {} should be valid",
+                    index,
+                    size
+                );
+                let other = leaf_hash(&[size as u8]);
+                assert!(!proof.verify(index as u64, other).unwrap());
+                if size > 1 {
+                    let moved = (index as u64 + 1) % size as u64;
+                    assert!(!proof.verify(moved, leaves[index]).unwrap());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_verify() {
+        let log = MemoryLog::new(Keypair::generate(&mut rand::rngs::OsRng {}));
+        let mut inv = signed_invoice();
+        assert!(matches!(
+            inv.verify_log_entries(&log.public_key()),
+            Err(TransparencyError::NotLogged)
+        ));
+
+        assert_eq!(2, inv.record_signatures(&log).await.unwrap());
+        inv.verify_log_entries(&log.public_key())
+            .expect("recorded signatures should verify");
+        // Signatures that are already recorded are skipped
+        assert_eq!(0, inv.record_signatures(&log).await.unwrap());
+
+        // The entries survive a round trip through TOML
+        let roundtrip: Invoice = toml::from_str(&toml::to_string(&inv).unwrap()).unwrap();
+        roundtrip
+            .verify_log_entries(&log.public_key())
+            .expect("recorded signatures should verify");
+
+        // Entries can only be verified with the key of the log that recorded them
+        let other = MemoryLog::new(Keypair::generate(&mut rand::rngs::OsRng {}));
+        assert!(matches!(
+            inv.verify_log_entries(&other.public_key()),
+            Err(TransparencyError::UntrustedLog(_))
+        ));
+
+        // An entry doesn't vouch for a different signature
+        let mut swapped = inv.clone();
+        let signatures = swapped.signature.as_mut().unwrap();
+        let entry = signatures[0].log_entry.clone();
+        signatures[1].log_entry = entry;
+        assert!(matches!(
+            swapped.verify_log_entries(&log.public_key()),
+            Err(TransparencyError::InvalidTimestamp(_))
+        ));
+
+        // The audit path is not signed by the log, but must still lead to the signed root. The
+        // second entry is used, as the first one was recorded in a tree without other entries
+        let mut moved = inv.clone();
+        let entry = moved.signature.as_mut().unwrap()[1]
+            .log_entry
+            .as_mut()
+            .unwrap();
+        entry.inclusion_proof.hashes.clear();
+        assert!(matches!(
+            moved.verify_log_entries(&log.public_key()),
+            Err(TransparencyError::InvalidProof(_))
+        ));
+    }
+}
@@ -40,8 +40,7 @@ async fn test_push() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -64,8 +63,7 @@ async fn test_push_invoice_and_file() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -82,8 +80,7 @@ async fn test_push_invoice_and_file() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -104,8 +101,7 @@ async fn test_get() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -122,8 +118,7 @@ async fn test_get() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -141,8 +136,7 @@ async fn test_get() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -169,6 +163,126 @@ async fn test_get() {
     )
 }
 
+#[tokio::test]
+async fn test_info_resolves_version() {
+    let controller = TestController::new().await;
+    setup_data(&controller.client).await;
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    for version in &["1.1.0", "2.0.0"] {
+        let mut inv = scaffold.invoice.clone();
+        inv.bindle.id = format!("enterprise.com/warpcore/{}", version)
+            .parse()
+            .unwrap();
+        controller
+            .client
+            .create_invoice(inv)
+            .await
+            .expect("Unable to insert invoice");
+    }
+    controller
+        .client
+        .yank_invoice("enterprise.com/warpcore/2.0.0")
+        .await
+        .expect("Unable to yank invoice");
+
+    let info = |bindle: &str| {
+        std::process::Command::new("cargo")
+            .args([
+                "run",
+                "--all-features",
+                "--bin",
+                "bindle",
+                "--",
+                "info",
+                bindle,
+            ])
+            .env("BINDLE_SERVER_URL", &controller.base_url)
+            .output()
+            .expect("Should be able to run command")
+    };
+    for (bindle, expected) in &[
+        ("enterprise.com/warpcore", "1.1.0"),
+        ("enterprise.com/warpcore@~1.0", "1.0.0"),
+        ("enterprise.com/warpcore/1.0.0", "1.0.0"),
+    ] {
+        let output = info(bindle);
+        assert!(
+            output.status.success(),
+            "Should be able to get info for {}: {}",
+            bindle,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let inv: bindle::Invoice = toml::from_slice(&output.stdout)
+            .unwrap_or_else(|e| panic!("{}: {}", e, String::from_utf8_lossy(&output.stdout)));
+        assert_eq!(*expected, inv.bindle.id.version_string());
+    }
+
+    // Yanked versions are never picked
+    assert!(!info("enterprise.com/warpcore@^2").status.success());
+}
+
+#[tokio::test]
+async fn test_signatures() {
+    use bindle::signature::{KeyRing, SecretKeyEntry, SignatureRole};
+
+    let controller = TestController::new().await;
+    let tempdir = tempfile::tempdir().expect("Unable to set up tempdir");
+    let keyring_path = tempdir.path().join("keyring.toml");
+    let creator = SecretKeyEntry::generate(
+        "Creator <creator@example.com>",
+        vec![SignatureRole::Creator],
+    );
+    KeyRing::new(vec![creator.key_entry()])
+        .save(&keyring_path)
+        .await
+        .expect("Unable to save keyring");
+
+    let mut inv = testing::Scaffold::load("valid_v1").await.invoice;
+    inv.sign_with_key(SignatureRole::Creator, &creator)
+        .expect("Unable to sign invoice");
+    let stranger = SecretKeyEntry::generate("Stranger", vec![SignatureRole::Approver]);
+    inv.sign_with_key(SignatureRole::Approver, &stranger)
+        .expect("Unable to sign invoice");
+    controller
+        .client
+        .create_invoice(inv.clone())
+        .await
+        .expect("Unable to insert invoice");
+
+    let output = std::process::Command::new("cargo")
+        .args([
+            "run",
+            "--all-features",
+            "--bin",
+            "bindle",
+            "--",
+            "signatures",
+            &inv.bindle.id.to_string(),
+        ])
+        .env("BINDLE_SERVER_URL", &controller.base_url)
+        .env("BINDLE_KEYRING", &keyring_path)
+        .output()
+        .expect("Should be able to run command");
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert_status(output, "Should be able to show signatures");
+    assert!(
+        stdout.contains("has 2 signatures, 1 of them trusted"),
+        "Unexpected output: {}",
+        stdout
+    );
+    assert!(
+        stdout.contains("creator: Creator <creator@example.com>")
+            && stdout.contains("trusted, key \"Creator <creator@example.com>\""),
+        "Creator signature should be trusted: {}",
+        stdout
+    );
+    assert!(
+        stdout.contains("approver: Stranger") && stdout.contains("not in the keyring"),
+        "Approver key shouldn't be in the keyring: {}",
+        stdout
+    );
+}
+
 #[tokio::test]
 async fn test_get_invoice() {
     let controller = TestController::new().await;
@@ -178,8 +292,7 @@ async fn test_get_invoice() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -211,8 +324,7 @@ async fn test_get_parcel() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -244,8 +356,7 @@ async fn test_yank() {
     let output = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle",
             "--",
@@ -259,6 +370,155 @@ async fn test_yank() {
     assert_status(output, "Should be able to yank a bindle");
 }
 
+#[tokio::test]
+async fn test_compose() {
+    let controller = TestController::new().await;
+    setup_data(&controller.client).await;
+
+    let tempdir = tempfile::tempdir().expect("Unable to set up tempdir");
+    let output_path = tempdir.path().join("composed.toml");
+    let output = std::process::Command::new("cargo")
+        .args([
+            "run",
+            "--all-features",
+            "--bin",
+            "bindle",
+            "--",
+            "compose",
+            output_path.to_str().unwrap(),
+            "--bindle-id",
+            "enterprise.com/fleet/1.0.0",
+            "--from",
+            "enterprise.com/warpcore/1.0.0",
+            "--from",
+            "enterprise.com/cargobay/1.0.0",
+        ])
+        .env("BINDLE_SERVER_URL", &controller.base_url)
+        .output()
+        .expect("Should be able to run command");
+    assert_status(output, "Should be able to compose a bindle");
+
+    // All of the parcels already exist, so nothing should need to be uploaded
+    let resp = controller
+        .client
+        .create_invoice_from_file(&output_path)
+        .await
+        .expect("Should be able to create the composed invoice");
+    assert!(
+        resp.missing.unwrap_or_default().is_empty(),
+        "Composed bindle shouldn't be missing any parcels"
+    );
+}
+
+#[tokio::test]
+async fn test_keys() {
+    let tempdir = tempfile::tempdir().expect("Unable to set up tempdir");
+    let keyring_path = tempdir.path().join("keyring.toml");
+    // Managing keys shouldn't need a server
+    let run = |args: &[&str]| {
+        let mut full_args = vec!["run", "--all-features", "--bin", "bindle", "--", "keys"];
+        full_args.extend_from_slice(args);
+        std::process::Command::new("cargo")
+            .args(full_args)
+            .env("BINDLE_KEYRING", &keyring_path)
+            .env_remove("BINDLE_SERVER_URL")
+            .output()
+            .expect("Should be able to run command")
+    };
+    let key = "6kpsY+KcUgq+9VB7Ey7F+ZVHdq6+vnuSQh7qaRRG0iw=";
+
+    assert_status(
+        run(&[
+            "add",
+            "Matt <matt@example.com>",
+            key,
+            "--role",
+            "creator",
+            "--role",
+            "approver",
+        ]),
+        "Should be able to add a key",
+    );
+    let keyring = bindle::signature::KeyRing::load(&keyring_path)
+        .await
+        .expect("Unable to load keyring");
+    assert_eq!(1, keyring.key.len());
+    assert_eq!(2, keyring.key[0].roles.len(), "Key should have both roles");
+
+    let output = run(&["list", "--role", "host"]);
+    assert!(
+        !String::from_utf8_lossy(&output.stdout).contains(key),
+        "Key shouldn't be listed for a role it doesn't have"
+    );
+    assert_status(output, "Should be able to list keys");
+    let output = run(&["list", "--role", "approver"]);
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains(key),
+        "Key should be listed"
+    );
+
+    assert!(
+        !run(&["add", "Bad", "not-a-key", "--role", "creator"])
+            .status
+            .success(),
+        "Adding an invalid key should fail"
+    );
+
+    assert_status(
+        run(&["remove", "Matt <matt@example.com>"]),
+        "Should be able to remove a key",
+    );
+    let keyring = bindle::signature::KeyRing::load(&keyring_path)
+        .await
+        .expect("Unable to load keyring");
+    assert!(keyring.key.is_empty(), "Key should have been removed");
+    assert!(
+        !run(&["remove", "Matt <matt@example.com>"]).status.success(),
+        "Removing a missing key should fail"
+    );
+}
+
+#[tokio::test]
+async fn test_create_key() {
+    let tempdir = tempfile::tempdir().expect("Unable to set up tempdir");
+    let keyring_path = tempdir.path().join("keyring.toml");
+    let secret_keys_path = tempdir.path().join("secret_keys.toml");
+    let output = std::process::Command::new("cargo")
+        .args(vec![
+            "run",
+            "--all-features",
+            "--bin",
+            "bindle",
+            "--",
+            "keys",
+            "create-key",
+            "Matt <matt@example.com>",
+            "--role",
+            "creator",
+        ])
+        .env("BINDLE_KEYRING", &keyring_path)
+        .env("BINDLE_SECRET_KEYS", &secret_keys_path)
+        .env("BINDLE_KEY_PASSPHRASE", "hunter2")
+        .env_remove("BINDLE_SERVER_URL")
+        .output()
+        .expect("Should be able to run command");
+    assert_status(output, "Should be able to create a key");
+
+    let secret_keys = bindle::signature::SecretKeyFile::load(&secret_keys_path)
+        .await
+        .expect("Unable to load secret keys");
+    let key = secret_keys
+        .decrypt("Matt <matt@example.com>", "hunter2")
+        .expect("Key should decrypt with the passphrase");
+    let keyring = bindle::signature::KeyRing::load(&keyring_path)
+        .await
+        .expect("Unable to load keyring");
+    assert!(
+        keyring.is_trusted(&key.public_key(), bindle::signature::SignatureRole::Creator),
+        "Public key should have been added to the keyring"
+    );
+}
+
 fn assert_status(output: std::process::Output, message: &str) {
     assert!(
         output.status.success(),
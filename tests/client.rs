@@ -1,11 +1,18 @@
 //! Tests for the client. These tests are not intended to walk through all the API possibilites (as
 //! that is taken care of in the API tests), but instead focus on entire user workflows
 
+#![recursion_limit = "256"]
+
 mod test_util;
 use test_util::TestController;
 
 use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
 
+use bindle::standalone::{
+    ExportOptions, ParcelPushStatus, Progress, ProgressReporter, PushOptions, StandaloneRead,
+    StandaloneWrite,
+};
 use bindle::testing;
 
 use tokio::stream::StreamExt;
@@ -132,82 +139,1601 @@ async fn test_streaming_successful() {
 }
 
 #[tokio::test]
-async fn test_already_created() {
+async fn test_progress_reporting() {
     let controller = TestController::new().await;
 
-    let scaffold = testing::Scaffold::load("valid_v2").await;
+    let root = std::env::var("CARGO_MANIFEST_DIR").expect("Unable to get project directory");
+    let base = std::path::PathBuf::from(root).join("tests/scaffolds/valid_v1");
+
+    let inv = controller
+        .client
+        .create_invoice_from_file(base.join("invoice.toml"))
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+
+    let parcel_path = base.join("parcels/parcel.dat");
+    let parcel_sha = inv.parcel.expect("Should have parcels in invoice")[0]
+        .label
+        .sha256
+        .to_owned();
+    // Both directions report how many bytes of the parcel were transferred
+    let transferred = Arc::new(BytesRecorder::default());
+    let client = controller
+        .client
+        .clone()
+        .with_progress_reporter(transferred.clone());
+    client
+        .create_parcel_from_file(&inv.bindle.id, &parcel_sha, &parcel_path)
+        .await
+        .expect("Unable to create parcel");
+
+    let mut stream = client
+        .get_parcel_stream(&inv.bindle.id, &parcel_sha)
+        .await
+        .expect("unable to get parcel");
+    while let Some(res) = stream.next().await {
+        res.expect("Shouldn't get an error in stream");
+    }
+
+    let on_disk_len = tokio::fs::metadata(parcel_path)
+        .await
+        .expect("Unable to get file info")
+        .len();
+    assert_eq!(
+        2 * on_disk_len,
+        *transferred.0.lock().unwrap(),
+        "Expected the upload and download to be reported"
+    );
+}
+
+#[derive(Default)]
+struct BytesRecorder(Mutex<u64>);
+
+impl ProgressReporter for BytesRecorder {
+    fn bytes_transferred(&self, _sha: &str, bytes: u64) {
+        *self.0.lock().unwrap() += bytes;
+    }
+}
+
+#[tokio::test]
+async fn test_stream_upload_verification() {
+    let controller = TestController::new().await;
+
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+
+    let mut parcels = scaffold.parcel_files.values();
+    let (first, second) = (parcels.next().unwrap(), parcels.next().unwrap());
+    let chunks = |data: &[u8]| {
+        tokio::stream::iter(
+            data.chunks(2)
+                .map(|c| Ok::<_, std::io::Error>(bytes::Bytes::copy_from_slice(c)))
+                .collect::<Vec<_>>(),
+        )
+    };
 
     controller
         .client
-        .create_invoice(scaffold.invoice.clone())
+        .create_parcel_from_stream(
+            &inv.bindle.id,
+            &first.sha,
+            Some(first.data.len() as u64),
+            chunks(&first.data),
+        )
         .await
-        .expect("Invoice creation should not error");
+        .expect("Unable to create parcel from stream");
 
-    // Upload parcels for this bindle
-    for parcel in scaffold.parcel_files.values() {
+    // Data that doesn't match the SHA should be rejected before the upload completes
+    match controller
+        .client
+        .create_parcel_from_stream(&inv.bindle.id, &second.sha, None, chunks(&first.data))
+        .await
+    {
+        Err(bindle::client::ClientError::DigestMismatch { expected, .. }) => {
+            assert_eq!(second.sha, expected)
+        }
+        res => panic!("Expected a digest mismatch error, got: {:?}", res),
+    }
+    match controller
+        .client
+        .create_parcel_from_stream(
+            &inv.bindle.id,
+            &second.sha,
+            Some(second.data.len() as u64 + 1),
+            chunks(&second.data),
+        )
+        .await
+    {
+        Err(bindle::client::ClientError::SizeMismatch { .. }) => (),
+        res => panic!("Expected a size mismatch error, got: {:?}", res),
+    }
+    assert!(
         controller
             .client
-            .create_parcel(
-                &scaffold.invoice.bindle.id,
-                &parcel.sha,
-                parcel.data.clone(),
-            )
+            .get_parcel(&inv.bindle.id, &second.sha)
             .await
-            .expect("Unable to create parcel");
+            .is_err(),
+        "Parcel with invalid data should not have been created"
+    );
+}
+
+#[tokio::test]
+async fn test_chunked_parcels() {
+    use bindle::chunking::{ChunkedParcel, Chunker};
+
+    let controller = TestController::new().await;
+    let chunker = Chunker::content_defined(4096);
+    let invoice = |version: &str| -> bindle::Invoice {
+        toml::from_str(&format!(
+            "bindleVersion = \"1.0.0\"\n[bindle]\nname = \"chunky\"\nversion = \"{}\"",
+            version
+        ))
+        .unwrap()
+    };
+    let mut state = 42u64;
+    let original: Vec<u8> = (0..128 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+
+    let upload = |version: &'static str, data: Vec<u8>| {
+        let client = controller.client.clone();
+        let mut inv = invoice(version);
+        async move {
+            let chunked =
+                ChunkedParcel::new("big.bin", "application/octet-stream", &data, &chunker);
+            chunked.add_to(&mut inv);
+            let id = client
+                .create_invoice(inv)
+                .await
+                .expect("unable to create invoice")
+                .invoice
+                .bindle
+                .id;
+            let uploaded = client
+                .create_chunked_parcel(&id, &chunked)
+                .await
+                .expect("unable to upload chunks");
+            (
+                id,
+                chunked.manifest_label().sha256,
+                uploaded,
+                chunked.chunks().count(),
+            )
+        }
+    };
+
+    let (id, manifest_sha, uploaded, chunks) = upload("1.0.0", original.clone()).await;
+    assert_eq!(chunks + 1, uploaded, "All chunks should be uploaded");
+
+    let mut stream = controller
+        .client
+        .get_chunked_parcel_stream(&id, &manifest_sha)
+        .await
+        .expect("unable to get chunked parcel");
+    let mut data = Vec::new();
+    while let Some(res) = stream.next().await {
+        data.extend(res.expect("Shouldn't get an error in stream"));
     }
+    assert!(
+        data == original,
+        "Reassembled data should match the original"
+    );
 
-    // Make sure we can create an invoice where all parcels already exist
-    let mut other_inv = scaffold.invoice.clone();
-    other_inv.bindle.id = "another.com/bindle/1.0.0".try_into().unwrap();
+    // Changing a small part of the data should only upload the chunks around the change
+    let mut modified = original.clone();
+    modified[64 * 1024] ^= 0xff;
+    let (_, _, uploaded, chunks) = upload("1.1.0", modified).await;
+    assert!(
+        uploaded <= 4 && uploaded < chunks,
+        "Expected only a few of {} chunks to be uploaded, got {}",
+        chunks,
+        uploaded
+    );
+}
+
+#[tokio::test]
+async fn test_parcel_range() {
+    let controller = TestController::new().await;
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    let parcel = scaffold.parcel_files.get("parcel").expect("Missing parcel");
     controller
         .client
-        .create_invoice(other_inv)
+        .create_parcel(&inv.bindle.id, &parcel.sha, parcel.data.clone())
         .await
-        .expect("invoice creation should not error");
+        .expect("Unable to create parcel");
+
+    let data = controller
+        .client
+        .get_parcel_range(&inv.bindle.id, &parcel.sha, 1..4)
+        .await
+        .expect("Unable to get parcel range");
+    assert_eq!(&parcel.data[1..4], data.as_slice());
+
+    // Resume a download part way through
+    let data = controller
+        .client
+        .get_parcel_range(&inv.bindle.id, &parcel.sha, 2..)
+        .await
+        .expect("Unable to get parcel range");
+    assert_eq!(&parcel.data[2..], data.as_slice());
+
+    let past_end = parcel.data.len() as u64 + 10;
+    match controller
+        .client
+        .get_parcel_range(&inv.bindle.id, &parcel.sha, past_end..)
+        .await
+    {
+        Err(bindle::client::ClientError::InvalidRequest { status_code, .. }) => {
+            assert_eq!(reqwest::StatusCode::RANGE_NOT_SATISFIABLE, status_code)
+        }
+        res => panic!("Expected an unsatisfiable range error, got: {:?}", res),
+    }
 }
 
 #[tokio::test]
-async fn test_missing() {
+async fn test_update() {
     let controller = TestController::new().await;
 
-    // Create a bindle with missing invoices
-    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+    let mut parcels = std::collections::HashMap::new();
+    for name in &["valid_v1", "valid_v2"] {
+        let scaffold = testing::Scaffold::load(name).await;
+        let inv = controller
+            .client
+            .create_invoice(scaffold.invoice)
+            .await
+            .expect("unable to create invoice")
+            .invoice;
+        for parcel in scaffold.parcel_files.values() {
+            parcels.insert(parcel.sha.clone(), parcel.data.clone());
+            match controller
+                .client
+                .create_parcel(&inv.bindle.id, &parcel.sha, parcel.data.clone())
+                .await
+            {
+                Ok(_) | Err(bindle::client::ClientError::ParcelAlreadyExists) => (),
+                Err(e) => panic!("Unable to create parcel: {:?}", e),
+            }
+        }
+    }
+
+    let delta = controller
+        .client
+        .get_parcel_delta(
+            "enterprise.com/warpcore/1.0.0",
+            "enterprise.com/warpcore/2.0.0",
+        )
+        .await
+        .expect("Unable to get delta");
+    assert_eq!(1, delta.added.len());
+    assert_eq!(1, delta.unchanged.len());
+    assert!(delta.removed.is_empty());
+
+    // Start from a fresh download of the first version
+    let dest = tempfile::tempdir().expect("unable to create tempdir");
+    let report = controller
+        .client
+        .update(
+            "enterprise.com/warpcore/1.0.0",
+            "enterprise.com/warpcore/1.0.0",
+            dest.path(),
+        )
+        .await
+        .expect("Unable to download bindle");
+    assert_eq!(1, report.downloaded.len());
+
+    let report = controller
+        .client
+        .update(
+            "enterprise.com/warpcore/1.0.0",
+            "enterprise.com/warpcore/2.0.0",
+            dest.path(),
+        )
+        .await
+        .expect("Unable to update bindle");
+    assert_eq!(
+        1,
+        report.downloaded.len(),
+        "Only the new parcel should be fetched"
+    );
+    assert_eq!(1, report.reused.len());
+    let inv: bindle::Invoice = toml::from_slice(
+        &std::fs::read(dest.path().join("invoice.toml")).expect("invoice should be written"),
+    )
+    .unwrap();
+    assert_eq!("2.0.0", inv.bindle.id.version_string());
+    for label in report.downloaded.iter().chain(report.reused.iter()) {
+        let data = std::fs::read(dest.path().join(format!("parcels/{}.dat", label.sha256)))
+            .expect("parcel should be downloaded");
+        assert!(data == parcels[&label.sha256]);
+    }
+
+    // A corrupted local parcel should be fetched again, and going back should remove the parcel
+    // only in the newer version
+    let shared = &report.reused[0].sha256;
+    std::fs::write(dest.path().join(format!("parcels/{}.dat", shared)), b"oops").unwrap();
+    let report = controller
+        .client
+        .update(
+            "enterprise.com/warpcore/2.0.0",
+            "enterprise.com/warpcore/1.0.0",
+            dest.path(),
+        )
+        .await
+        .expect("Unable to update bindle");
+    assert_eq!(shared, &report.downloaded[0].sha256);
+    assert_eq!(1, report.removed.len());
+    assert!(!dest
+        .path()
+        .join(format!("parcels/{}.dat", report.removed[0].sha256))
+        .exists());
+}
+#[tokio::test]
+async fn test_resumable_upload() {
+    let controller = TestController::new().await;
 
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
     let inv = controller
         .client
         .create_invoice(scaffold.invoice)
         .await
         .expect("unable to create invoice")
         .invoice;
+    let parcel = scaffold
+        .parcel_files
+        .get("parcel")
+        .expect("parcel doesn't exist");
+    let size = parcel.data.len() as u64;
+    assert!(size > 2, "Test parcel is too small");
 
-    // Check we get the right amount of missing parcels
-    let missing = controller
+    let status = controller
         .client
-        .get_missing_parcels(&inv.bindle.id)
+        .start_parcel_upload(&inv.bindle.id, &parcel.sha)
         .await
-        .expect("Should be able to fetch list of missing parcels");
+        .expect("Unable to start upload");
+    assert_eq!(0, status.offset);
+    assert_eq!(size, status.size);
+
+    // Only send the first part, as if the connection dropped
+    let partial = controller
+        .client
+        .upload_parcel_chunk(&status.id, 0, parcel.data[..2].to_vec())
+        .await
+        .expect("Unable to upload chunk");
+    assert_eq!(2, partial.offset);
+
+    match controller
+        .client
+        .upload_parcel_chunk(&status.id, 0, parcel.data.clone())
+        .await
+    {
+        Err(bindle::client::ClientError::InvalidRequest { status_code, .. }) => {
+            assert_eq!(reqwest::StatusCode::CONFLICT, status_code)
+        }
+        res => panic!("Expected a conflict, got {:?}", res),
+    }
+
+    // Now resume the upload from a file, one byte at a time to exercise the chunking
+    let dir = tempfile::tempdir().expect("unable to create tempdir");
+    let path = dir.path().join("parcel.dat");
+    tokio::fs::write(&path, &parcel.data)
+        .await
+        .expect("Unable to write parcel file");
+    let done = controller
+        .client
+        .upload_parcel_file(&status.id, &path, 1)
+        .await
+        .expect("Unable to resume upload");
+    assert!(done.is_complete());
+
+    let data = controller
+        .client
+        .get_parcel(&inv.bindle.id, &parcel.sha)
+        .await
+        .expect("Unable to get uploaded parcel");
+    assert_eq!(parcel.data, data);
+
+    assert!(matches!(
+        controller.client.get_upload_status(&status.id).await,
+        Err(bindle::client::ClientError::InvalidRequest { .. })
+    ));
+    assert!(matches!(
+        controller
+            .client
+            .start_parcel_upload(&inv.bindle.id, &parcel.sha)
+            .await,
+        Err(bindle::client::ClientError::ParcelAlreadyExists)
+    ));
+
+    // Cancelled uploads are gone
+    let other = scaffold
+        .parcel_files
+        .get("crate")
+        .expect("crate doesn't exist");
+    let status = controller
+        .client
+        .start_parcel_upload(&inv.bindle.id, &other.sha)
+        .await
+        .expect("Unable to start upload");
+    controller
+        .client
+        .cancel_parcel_upload(&status.id)
+        .await
+        .expect("Unable to cancel upload");
+    assert!(controller
+        .client
+        .upload_parcel_chunk(&status.id, 0, other.data.clone())
+        .await
+        .is_err());
+}
+#[tokio::test]
+async fn test_capabilities() {
+    let controller = TestController::new().await;
+
+    let capabilities = controller
+        .client
+        .capabilities()
+        .await
+        .expect("Unable to get capabilities");
+    assert!(capabilities.resumable_uploads);
+    assert!(capabilities.range_requests);
     assert_eq!(
-        missing.len(),
-        scaffold.parcel_files.len(),
-        "Expected {} missing parcels, found {}",
-        scaffold.parcel_files.len(),
-        missing.len()
+        Some(env!("CARGO_PKG_VERSION")),
+        capabilities.version.as_deref()
     );
+}
 
-    // Yank the invoice
-    controller
+#[tokio::test]
+async fn test_ping() {
+    let controller = TestController::new().await;
+
+    let capabilities = controller
         .client
-        .yank_invoice(&inv.bindle.id)
+        .ping()
         .await
-        .expect("unable to yank invoice");
+        .expect("Unable to ping server");
+    assert_eq!(
+        Some(bindle::BINDLE_VERSION_1),
+        capabilities.spec_version.as_deref()
+    );
+}
 
-    // Make sure we can't get missing
-    match controller.client.get_missing_parcels(&inv.bindle.id).await {
-        Ok(_) => panic!("getting a yanked invoice should have errored"),
-        Err(e) => {
-            if !matches!(e, bindle::client::ClientError::InvoiceNotFound) {
-                panic!("Expected an invoice not found error, got: {:?}", e)
-            }
+#[tokio::test]
+async fn test_keyring_sync() {
+    use bindle::client::tokens::{Token, TokenCache};
+    use bindle::server::{auth::BearerAuthenticator, ApiOptions, InProcessOptions};
+    use bindle::signature::{KeyEntry, KeyRing, SecretKeyEntry, SignatureRole};
+    use sha2::Digest;
+
+    let (store, index) = testing::setup().await;
+    let keyring_dir = tempfile::tempdir().expect("Unable to create tempdir");
+    let mut tokens = std::collections::HashMap::new();
+    tokens.insert(
+        format!("{:x}", sha2::Sha256::digest(b"my-token")),
+        "alice".to_owned(),
+    );
+    let handle = bindle::server::start_in_process(
+        store,
+        index,
+        InProcessOptions {
+            address: ([127, 0, 0, 1], 0).into(),
+            authenticator: BearerAuthenticator::new(tokens),
+            authorizer: bindle::server::authz::AllowAll,
+            tls: None,
+            monitor: Default::default(),
+            api: ApiOptions {
+                keyring_dir: Some(keyring_dir.path().to_owned()),
+                ..Default::default()
+            },
+            drain_timeout: bindle::server::DEFAULT_DRAIN_TIMEOUT,
+        },
+    )
+    .expect("Unable to start server");
+    let client = bindle::client::Client::builder()
+        .token_cache(TokenCache::in_memory(Token::from_static("my-token")))
+        .build(&handle.base_url())
+        .expect("Unable to build client");
+
+    assert!(client
+        .pull_keyring("hunter2")
+        .await
+        .expect("Pulling a missing keyring should not fail")
+        .is_none());
+
+    let key = SecretKeyEntry::generate("Alice", vec![SignatureRole::Creator]);
+    let keyring = KeyRing::new(vec![KeyEntry::new(
+        "Alice",
+        vec![SignatureRole::Creator],
+        &key.public_key(),
+    )]);
+    client
+        .push_keyring(&keyring, "hunter2")
+        .await
+        .expect("Unable to push keyring");
+    let pulled = client
+        .pull_keyring("hunter2")
+        .await
+        .expect("Unable to pull keyring")
+        .expect("Keyring should exist");
+    assert_eq!(keyring, pulled);
+
+    assert!(
+        client.pull_keyring("wrong").await.is_err(),
+        "A wrong passphrase should fail"
+    );
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_verification_failure() {
+    use bindle::client::ClientError;
+    use bindle::signature::{KeyRing, VerificationStrategy};
+
+    let (store, index) = testing::setup().await;
+    let handle = bindle::server::start_in_process(
+        store,
+        index,
+        bindle::server::InProcessOptions {
+            api: bindle::server::ApiOptions {
+                verification_strategy: VerificationStrategy::AuthoritativeIntegrity,
+                keyring: std::sync::Arc::new(KeyRing::default()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .expect("Unable to start server");
+    let client = bindle::client::Client::new(&handle.base_url()).expect("Invalid URL");
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    match client.create_invoice(scaffold.invoice).await {
+        Err(ClientError::VerificationFailed(failure)) => {
+            assert_eq!(
+                VerificationStrategy::AuthoritativeIntegrity,
+                failure.strategy
+            )
+        }
+        res => panic!("Expected a verification failure, got {:?}", res),
+    }
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_transparency_log() {
+    use bindle::client::ClientError;
+    use bindle::signature::{SecretKeyEntry, SignatureRole};
+    use bindle::transparency::{MemoryLog, TransparencyError};
+
+    let (store, index) = testing::setup().await;
+    let log = std::sync::Arc::new(MemoryLog::new(ed25519_dalek::Keypair::generate(
+        &mut rand::rngs::OsRng {},
+    )));
+    let handle = bindle::server::start_in_process(
+        store,
+        index,
+        bindle::server::InProcessOptions {
+            api: bindle::server::ApiOptions {
+                transparency_log: Some(log.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .expect("Unable to start server");
+    let client = bindle::client::Client::new(&handle.base_url()).expect("Invalid URL");
+
+    let creator = SecretKeyEntry::generate("creator", vec![SignatureRole::Creator]);
+    let mut inv = testing::Scaffold::load("valid_v1").await.invoice;
+    inv.sign_with_key(SignatureRole::Creator, &creator)
+        .expect("Unable to sign invoice");
+    client
+        .create_invoice(inv.clone())
+        .await
+        .expect("Invoice creation should not error");
+
+    let fetched = client
+        .clone()
+        .with_transparency_log(log.public_key())
+        .get_invoice(&inv.bindle.id)
+        .await
+        .expect("A recorded invoice should be returned");
+    assert!(fetched.signature.unwrap()[0].log_entry.is_some());
+
+    let other = MemoryLog::new(ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng {}));
+    match client
+        .with_transparency_log(other.public_key())
+        .get_invoice(&inv.bindle.id)
+        .await
+    {
+        Err(ClientError::TransparencyError(TransparencyError::UntrustedLog(_))) => (),
+        res => panic!("Expected an untrusted log error, got {:?}", res),
+    }
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_client_verification() {
+    use bindle::client::ClientError;
+    use bindle::signature::{KeyRing, SecretKeyEntry, SignatureRole, VerificationStrategy};
+
+    let controller = TestController::new().await;
+    let creator = SecretKeyEntry::generate("creator", vec![SignatureRole::Creator]);
+
+    let unsigned = testing::Scaffold::load("valid_v1").await.invoice;
+    let mut signed = testing::Scaffold::load("valid_v2").await.invoice;
+    signed
+        .sign_with_key(SignatureRole::Creator, &creator)
+        .expect("Unable to sign invoice");
+    for inv in [unsigned.clone(), signed.clone()].iter().cloned() {
+        controller
+            .client
+            .create_invoice(inv)
+            .await
+            .expect("Invoice creation should not error");
+    }
+
+    let client = controller.client.clone().with_verification(
+        VerificationStrategy::CreativeIntegrity,
+        KeyRing::new(vec![creator.key_entry()]),
+    );
+    let fetched = client
+        .get_invoice(&signed.bindle.id)
+        .await
+        .expect("A trusted invoice should be returned");
+    assert_eq!(signed.signature, fetched.signature);
+
+    match client.get_invoice(&unsigned.bindle.id).await {
+        Err(ClientError::VerificationFailed(failure)) => {
+            assert_eq!(VerificationStrategy::CreativeIntegrity, failure.strategy)
         }
+        res => panic!("Expected a verification failure, got {:?}", res),
     }
+
+    // The same invoice is returned as usual by a client without a verification strategy
+    controller
+        .client
+        .get_invoice(&unsigned.bindle.id)
+        .await
+        .expect("Invoices should not be verified by default");
+}
+
+#[tokio::test]
+async fn test_fetch_and_attest() {
+    use bindle::client::attest::{Attestation, ATTESTATION_FILE};
+    use bindle::client::ClientError;
+    use bindle::signature::{KeyRing, SecretKeyEntry, SignatureRole, VerificationStrategy};
+
+    let controller = TestController::new().await;
+    let creator = SecretKeyEntry::generate("creator", vec![SignatureRole::Creator]);
+    let keyring = KeyRing::new(vec![creator.key_entry()]);
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    let mut inv = scaffold.invoice.clone();
+    inv.sign_with_key(SignatureRole::Creator, &creator)
+        .expect("Unable to sign invoice");
+    controller
+        .client
+        .create_invoice(inv.clone())
+        .await
+        .expect("Invoice creation should not error");
+    for parcel in scaffold.parcel_files.values() {
+        controller
+            .client
+            .create_parcel(&inv.bindle.id, &parcel.sha, parcel.data.clone())
+            .await
+            .expect("Unable to create parcel");
+    }
+
+    let dest = tempfile::tempdir().expect("unable to create tempdir");
+    let attestation = controller
+        .client
+        .fetch_and_attest(&inv.bindle.id, &keyring, dest.path())
+        .await
+        .expect("A trusted bindle should be attested");
+    assert_eq!(
+        VerificationStrategy::GreedyVerification,
+        attestation.verification_strategy
+    );
+    assert_eq!(inv.bindle.id.to_string(), attestation.bindle_id);
+    assert_eq!(1, attestation.signatures.len());
+    assert!(attestation.signatures[0].trusted);
+    assert_eq!(
+        Some("creator"),
+        attestation.signatures[0].key_label.as_deref()
+    );
+    assert_eq!(scaffold.parcel_files.len(), attestation.parcels.len());
+    for parcel in attestation.parcels.iter() {
+        let data = std::fs::read(dest.path().join(&parcel.path)).expect("parcel should exist");
+        assert_eq!(parcel.size, data.len() as u64);
+    }
+
+    // The attestation on disk matches the returned one, and the invoice digest matches the file
+    let written: Attestation = serde_json::from_slice(
+        &std::fs::read(dest.path().join(ATTESTATION_FILE)).expect("attestation should exist"),
+    )
+    .unwrap();
+    assert_eq!(attestation, written);
+    let raw_invoice = std::fs::read(dest.path().join("invoice.toml")).unwrap();
+    assert_eq!(
+        format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(&raw_invoice)),
+        attestation.invoice_sha256
+    );
+
+    // Nothing is attested for an untrusted bindle, and the previous attestation is removed
+    match controller
+        .client
+        .fetch_and_attest(&inv.bindle.id, &KeyRing::default(), dest.path())
+        .await
+    {
+        Err(ClientError::VerificationFailed(failure)) => {
+            assert_eq!(VerificationStrategy::GreedyVerification, failure.strategy)
+        }
+        res => panic!("Expected a verification failure, got {:?}", res),
+    }
+    assert!(!dest.path().join(ATTESTATION_FILE).exists());
+}
+
+#[tokio::test]
+async fn test_proxy_signing() {
+    use bindle::provider::Provider;
+    use bindle::signature::{KeyRing, RevocationList, SecretKeyEntry, SignatureRole};
+
+    let controller = TestController::new().await;
+    let key = SecretKeyEntry::generate("proxy", vec![SignatureRole::Proxy]);
+    let keyring = KeyRing::new(vec![key.key_entry()]);
+
+    assert!(
+        bindle::proxy::Proxy::with_signing_key(
+            controller.client.clone(),
+            SecretKeyEntry::generate("creator", vec![SignatureRole::Creator]),
+        )
+        .is_err(),
+        "A key without the proxy role should be rejected"
+    );
+    let proxy = bindle::proxy::Proxy::with_signing_key(controller.client.clone(), key)
+        .expect("Unable to create proxy");
+
+    // Invoices are signed on the way up...
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    proxy
+        .create_invoice(&scaffold.invoice)
+        .await
+        .expect("Invoice creation should not error");
+    let stored = controller
+        .client
+        .get_invoice(&scaffold.invoice.bindle.id)
+        .await
+        .expect("Invoice should exist");
+    let signatures = stored.signature.clone().unwrap_or_default();
+    assert_eq!(1, signatures.len());
+    assert_eq!(SignatureRole::Proxy, signatures[0].role);
+    stored
+        .verify(&keyring, &RevocationList::default())
+        .expect("Proxy signature should be valid");
+
+    // ...and on the way down
+    let fetched = proxy
+        .get_invoice(&scaffold.invoice.bindle.id)
+        .await
+        .expect("Invoice should be fetched through the proxy");
+    assert_eq!(
+        2,
+        fetched.signature.as_ref().map(Vec::len).unwrap_or_default()
+    );
+    fetched
+        .verify(&keyring, &RevocationList::default())
+        .expect("Proxy signatures should be valid");
+}
+
+#[tokio::test]
+async fn test_conditional_upload() {
+    let controller = TestController::new().await;
+
+    let scaffold = testing::Scaffold::load("valid_v2").await;
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    let (_, etag) = controller
+        .client
+        .get_invoice_with_etag(&inv.bindle.id)
+        .await
+        .expect("Should be able to fetch invoice");
+    let etag = etag.expect("Server should return an ETag");
+
+    let parcel = scaffold.parcel_files.get("parcel").unwrap();
+    controller
+        .client
+        .create_parcel_if_match(&inv.bindle.id, &parcel.sha, parcel.data.clone(), &etag)
+        .await
+        .expect("Parcel should be created while the invoice is unchanged");
+
+    controller
+        .client
+        .yank_invoice(&inv.bindle.id)
+        .await
+        .expect("unable to yank invoice");
+
+    let other = scaffold.parcel_files.get("other").unwrap();
+    match controller
+        .client
+        .create_parcel_if_match(&inv.bindle.id, &other.sha, other.data.clone(), &etag)
+        .await
+    {
+        Err(bindle::client::ClientError::PreconditionFailed) => (),
+        res => panic!("Expected a precondition failure, got {:?}", res),
+    }
+    match controller
+        .client
+        .start_parcel_upload_if_match(&inv.bindle.id, &other.sha, &etag)
+        .await
+    {
+        Err(bindle::client::ClientError::PreconditionFailed) => (),
+        res => panic!("Expected a precondition failure, got {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn test_json() {
+    let controller = TestController::new().await;
+    let client = bindle::client::Client::builder()
+        .json(true)
+        .build(&controller.base_url)
+        .expect("unable to build client");
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    let created = client
+        .create_invoice(scaffold.invoice.clone())
+        .await
+        .expect("unable to create invoice");
+    assert_eq!(
+        scaffold.parcel_files.len(),
+        created.missing.unwrap_or_default().len()
+    );
+    let inv = client
+        .get_invoice(&scaffold.invoice.bindle.id)
+        .await
+        .expect("unable to fetch invoice");
+    assert_eq!(scaffold.invoice.parcel, inv.parcel);
+
+    // Errors should come back typed, just like with TOML
+    match client.create_invoice(scaffold.invoice).await {
+        Err(bindle::client::ClientError::InvoiceAlreadyExists) => (),
+        res => panic!("Expected the invoice to already exist, got {:?}", res),
+    }
+    match client.get_invoice("enterprise.com/warpcore/9.9.9").await {
+        Err(bindle::client::ClientError::InvoiceNotFound) => (),
+        res => panic!("Expected the invoice to not be found, got {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn test_collect_garbage() {
+    let controller = TestController::new().await;
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    controller
+        .client
+        .create_invoice(scaffold.invoice.clone())
+        .await
+        .expect("unable to create invoice");
+    let parcel = scaffold.parcel_files.get("parcel").unwrap();
+    controller
+        .client
+        .create_parcel(
+            &scaffold.invoice.bindle.id,
+            &parcel.sha,
+            parcel.data.clone(),
+        )
+        .await
+        .expect("unable to create parcel");
+
+    // Everything that was pushed is referenced, so there is nothing to collect
+    for dry_run in &[true, false] {
+        let report = controller
+            .client
+            .collect_garbage(*dry_run)
+            .await
+            .expect("unable to collect garbage");
+        assert_eq!(*dry_run, report.dry_run);
+        assert_eq!(1, report.invoices);
+        assert_eq!(1, report.retained);
+        assert!(report.removed.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_delete_invoice() {
+    let controller = TestController::new().await;
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    controller
+        .client
+        .create_invoice(scaffold.invoice.clone())
+        .await
+        .expect("unable to create invoice");
+    let parcel = scaffold.parcel_files.get("parcel").unwrap();
+    controller
+        .client
+        .create_parcel(
+            &scaffold.invoice.bindle.id,
+            &parcel.sha,
+            parcel.data.clone(),
+        )
+        .await
+        .expect("unable to create parcel");
+
+    let resp = controller
+        .client
+        .delete_invoice(&scaffold.invoice.bindle.id)
+        .await
+        .expect("unable to delete invoice");
+    assert_eq!(vec![parcel.sha.clone()], resp.removed_parcels);
+
+    match controller
+        .client
+        .get_yanked_invoice(&scaffold.invoice.bindle.id)
+        .await
+    {
+        Err(bindle::client::ClientError::InvoiceNotFound) => (),
+        r => panic!("Expected the invoice to be gone, got {:?}", r.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_resolve_dependencies() {
+    let controller = TestController::new().await;
+
+    for raw in &[
+        "[bindle]\nname = \"base\"\nversion = \"1.0.0\"",
+        "[bindle]\nname = \"base\"\nversion = \"1.1.0\"",
+        "[bindle]\nname = \"base\"\nversion = \"2.0.0\"",
+        "[bindle]\nname = \"lib\"\nversion = \"1.0.0\"\n[[requires]]\nname = \"base\"\nversion = \"^1.0.0\"",
+        "[bindle]\nname = \"app\"\nversion = \"1.0.0\"\n[[requires]]\nname = \"lib\"",
+        "[bindle]\nname = \"broken\"\nversion = \"1.0.0\"\n[[requires]]\nname = \"lib\"\nversion = \"^2.0.0\"",
+    ] {
+        let inv: bindle::Invoice = toml::from_str(&format!("bindleVersion = \"1.0.0\"\n{}", raw))
+            .expect("invoice should parse");
+        controller
+            .client
+            .create_invoice(inv)
+            .await
+            .expect("unable to create invoice");
+    }
+
+    let resolved = controller
+        .client
+        .resolve_dependencies("app/1.0.0")
+        .await
+        .expect("unable to resolve dependencies");
+    assert_eq!(
+        vec!["lib/1.0.0", "base/1.1.0"],
+        resolved.iter().map(|id| id.to_string()).collect::<Vec<_>>()
+    );
+
+    match controller.client.resolve_dependencies("broken/1.0.0").await {
+        Err(bindle::client::ClientError::InvalidRequest {
+            status_code,
+            message,
+        }) => {
+            assert_eq!(reqwest::StatusCode::UNPROCESSABLE_ENTITY, status_code);
+            assert!(
+                message.unwrap_or_default().contains("lib ^2.0.0"),
+                "The error should name the dependency"
+            );
+        }
+        r => panic!("Expected an unsatisfied dependency, got {:?}", r),
+    }
+}
+
+#[tokio::test]
+async fn test_list_versions() {
+    let controller = TestController::new().await;
+
+    for version in &["1.10.0", "1.2.0", "2.0.0-rc.1"] {
+        let raw = format!(
+            "bindleVersion = \"1.0.0\"\n[bindle]\nname = \"example.com/app\"\nversion = \"{}\"",
+            version
+        );
+        let inv: bindle::Invoice = toml::from_str(&raw).expect("invoice should parse");
+        controller
+            .client
+            .create_invoice(inv)
+            .await
+            .expect("unable to create invoice");
+    }
+    controller
+        .client
+        .yank_invoice("example.com/app/1.2.0")
+        .await
+        .expect("unable to yank invoice");
+
+    let versions = controller
+        .client
+        .list_versions("example.com/app")
+        .await
+        .expect("unable to list versions");
+    assert_eq!(
+        vec![("1.2.0", true), ("1.10.0", false), ("2.0.0-rc.1", false)],
+        versions
+            .iter()
+            .map(|v| (v.version.as_str(), v.yanked))
+            .collect::<Vec<_>>()
+    );
+
+    match controller.client.list_versions("example.com/nope").await {
+        Err(bindle::client::ClientError::InvoiceNotFound) => (),
+        r => panic!("Expected no versions to be found, got {:?}", r),
+    }
+}
+
+#[tokio::test]
+async fn test_snapshot_from_server() {
+    let controller = TestController::new().await;
+
+    let scaffold = testing::Scaffold::load("valid_v2").await;
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice.clone())
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    for parcel in scaffold.parcel_files.values() {
+        controller
+            .client
+            .create_parcel(&inv.bindle.id, &parcel.sha, parcel.data.clone())
+            .await
+            .expect("Unable to create parcel");
+    }
+
+    let dir = tempfile::tempdir().expect("unable to create tempdir");
+    testing::snapshot_from_server_to(&controller.client, &inv.bindle.id, dir.path()).await;
+
+    let snapshot: bindle::Invoice = toml::from_slice(
+        &std::fs::read(dir.path().join("invoice.toml")).expect("invoice should be written"),
+    )
+    .expect("snapshot invoice should parse");
+    assert_eq!(inv.bindle.id.to_string(), snapshot.bindle.id.to_string());
+    assert_eq!(inv.annotations, snapshot.annotations);
+    for parcel in scaffold.parcel_files.values() {
+        let data = std::fs::read(
+            dir.path()
+                .join("parcels")
+                .join(format!("{}.dat", parcel.sha)),
+        )
+        .expect("parcel should be written");
+        assert_eq!(parcel.data, data);
+    }
+    assert_eq!(
+        scaffold.parcel_files.len(),
+        std::fs::read_dir(dir.path().join("parcels"))
+            .expect("parcel directory should exist")
+            .count()
+    );
+}
+
+#[tokio::test]
+async fn test_parcel_selection() {
+    let controller = TestController::new().await;
+
+    let raw = r#"
+    bindleVersion = "1.0.0"
+
+    [bindle]
+    name = "selection"
+    version = "1.0.0"
+
+    [[group]]
+    name = "frontend"
+
+    [[group]]
+    name = "lang"
+    satisfiedBy = "oneOf"
+    required = true
+
+    [[parcel]]
+    label = { name = "server", sha256 = "aaaa", mediaType = "text/plain", size = 1 }
+
+    [[parcel]]
+    label = { name = "ui", sha256 = "bbbb", mediaType = "text/plain", size = 1 }
+    conditions = { memberOf = ["frontend"] }
+
+    [[parcel]]
+    label = { name = "en", sha256 = "cccc", mediaType = "text/plain", size = 1, feature = { i18n = { lang = "en" } } }
+    conditions = { memberOf = ["lang"] }
+
+    [[parcel]]
+    label = { name = "de", sha256 = "dddd", mediaType = "text/plain", size = 2, feature = { i18n = { lang = "de" } } }
+    conditions = { memberOf = ["lang"] }
+    "#;
+    let inv: bindle::Invoice = toml::from_str(raw).expect("invoice should parse");
+    controller
+        .client
+        .create_invoice(inv)
+        .await
+        .expect("unable to create invoice");
+
+    let features = vec!["lang=de".parse().expect("valid feature")];
+    let labels = controller
+        .client
+        .get_parcel_selection("selection/1.0.0", &["frontend"], &features)
+        .await
+        .expect("unable to get parcel selection");
+    assert_eq!(
+        vec!["server", "ui", "de"],
+        labels.into_iter().map(|l| l.name).collect::<Vec<_>>()
+    );
+
+    let labels = controller
+        .client
+        .get_parcel_selection_with_preference(
+            "selection/1.0.0",
+            &[],
+            &[],
+            bindle::filters::resolution::Preference::Largest,
+        )
+        .await
+        .expect("unable to get parcel selection");
+    assert_eq!(
+        vec!["server", "de"],
+        labels.into_iter().map(|l| l.name).collect::<Vec<_>>()
+    );
+
+    match controller
+        .client
+        .get_parcel_selection("selection/1.0.0", &["backend"], &[])
+        .await
+    {
+        Err(bindle::client::ClientError::InvalidRequest { status_code, .. }) => {
+            assert_eq!(reqwest::StatusCode::BAD_REQUEST, status_code);
+        }
+        r => panic!("Expected an unknown group error, got {:?}", r),
+    }
+}
+
+#[tokio::test]
+async fn test_replication() {
+    use bindle::replication::Replicator;
+    use bindle::server::{ApiOptions, InProcessOptions};
+
+    let (primary_store, primary_index) = testing::setup().await;
+    let primary =
+        bindle::server::start_in_process(primary_store, primary_index, InProcessOptions::default())
+            .expect("Unable to start primary");
+    let primary_client = bindle::client::Client::new(&primary.base_url()).expect("Invalid URL");
+
+    let scaffold = testing::Scaffold::load("valid_v1").await;
+    let inv = primary_client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    for parcel in scaffold.parcel_files.values() {
+        primary_client
+            .create_parcel(&inv.bindle.id, &parcel.sha, parcel.data.clone())
+            .await
+            .expect("Unable to create parcel");
+    }
+
+    let (replica_store, replica_index) = testing::setup().await;
+    let replicator = Replicator::new(primary_client.clone(), replica_store.clone())
+        .with_query(bindle::QueryOptions {
+            query: Some(inv.bindle.id.name().to_owned()),
+            ..Default::default()
+        })
+        .with_event_secret("sw0rdf1sh");
+    let replica = bindle::server::start_in_process(
+        replica_store,
+        replica_index,
+        InProcessOptions {
+            api: ApiOptions {
+                replication: Some(replicator.handle()),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .expect("Unable to start replica");
+    let replica_client = bindle::client::Client::new(&replica.base_url()).expect("Invalid URL");
+
+    // Polling copies the invoice and all of its parcels
+    replicator.poll().await.expect("Poll should succeed");
+    replica_client
+        .get_invoice(&inv.bindle.id)
+        .await
+        .expect("Invoice should be replicated");
+    for parcel in scaffold.parcel_files.values() {
+        let data = replica_client
+            .get_parcel(&inv.bindle.id, &parcel.sha)
+            .await
+            .expect("Parcel should be replicated");
+        assert_eq!(parcel.data, data);
+    }
+
+    // Yanks are copied on the next poll
+    primary_client
+        .yank_invoice(&inv.bindle.id)
+        .await
+        .expect("Unable to yank invoice");
+    replicator.poll().await.expect("Poll should succeed");
+    assert!(replica_client.get_invoice(&inv.bindle.id).await.is_err());
+    assert!(replica_client
+        .get_yanked_invoice(&inv.bindle.id)
+        .await
+        .expect("Yanked invoice should still be on the replica")
+        .yanked
+        .unwrap_or(false));
+
+    // Deletions are only found when the replicator is told about the bindle
+    primary_client
+        .delete_invoice(&inv.bindle.id)
+        .await
+        .expect("Unable to delete invoice");
+    replicator
+        .replicate(&inv.bindle.id)
+        .await
+        .expect("Replication should succeed");
+    assert!(replica_client
+        .get_yanked_invoice(&inv.bindle.id)
+        .await
+        .is_err());
+
+    // Signed events are queued for the replicator, anything else is rejected
+    let event = r#"{"event": "invoice_created", "bindleId": "enterprise.com/warpcore/2.0.0"}"#;
+    let send_event = |signature: Option<String>| {
+        let mut req = reqwest::Client::new()
+            .post(&format!("{}_replication/events", replica.base_url()))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(event);
+        if let Some(signature) = signature {
+            req = req.header(bindle::provider::hooks::SIGNATURE_HEADER, signature);
+        }
+        req.send()
+    };
+    let resp = send_event(None).await.expect("Unable to send event");
+    assert_eq!(reqwest::StatusCode::UNAUTHORIZED, resp.status());
+    let resp = send_event(Some(bindle::provider::hooks::sign_payload(
+        b"wrong",
+        event.as_bytes(),
+    )))
+    .await
+    .expect("Unable to send event");
+    assert_eq!(reqwest::StatusCode::UNAUTHORIZED, resp.status());
+    let resp = send_event(Some(bindle::provider::hooks::sign_payload(
+        b"sw0rdf1sh",
+        event.as_bytes(),
+    )))
+    .await
+    .expect("Unable to send event");
+    assert_eq!(reqwest::StatusCode::ACCEPTED, resp.status());
+
+    let status = replica_client
+        .get_replication_status()
+        .await
+        .expect("Unable to get replication status");
+    assert_eq!(primary_client.base_url(), status.primary);
+    assert_eq!(1, status.invoices_copied);
+    assert_eq!(scaffold.parcel_files.len() as u64, status.parcels_copied);
+    assert_eq!(1, status.yanks_copied);
+    assert_eq!(1, status.deletes_copied);
+    assert_eq!(1, status.pending);
+    assert!(status.last_poll.is_some());
+
+    primary.shutdown().await;
+    replica.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_already_created() {
+    let controller = TestController::new().await;
+
+    let scaffold = testing::Scaffold::load("valid_v2").await;
+
+    controller
+        .client
+        .create_invoice(scaffold.invoice.clone())
+        .await
+        .expect("Invoice creation should not error");
+
+    // Upload parcels for this bindle
+    for parcel in scaffold.parcel_files.values() {
+        controller
+            .client
+            .create_parcel(
+                &scaffold.invoice.bindle.id,
+                &parcel.sha,
+                parcel.data.clone(),
+            )
+            .await
+            .expect("Unable to create parcel");
+    }
+
+    // Make sure we can create an invoice where all parcels already exist
+    let mut other_inv = scaffold.invoice.clone();
+    other_inv.bindle.id = "another.com/bindle/1.0.0".try_into().unwrap();
+    controller
+        .client
+        .create_invoice(other_inv)
+        .await
+        .expect("invoice creation should not error");
+}
+
+#[tokio::test]
+async fn test_missing() {
+    let controller = TestController::new().await;
+
+    // Create a bindle with missing invoices
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+
+    // Check we get the right amount of missing parcels
+    let missing = controller
+        .client
+        .get_missing_parcels(&inv.bindle.id)
+        .await
+        .expect("Should be able to fetch list of missing parcels");
+    assert_eq!(
+        missing.len(),
+        scaffold.parcel_files.len(),
+        "Expected {} missing parcels, found {}",
+        scaffold.parcel_files.len(),
+        missing.len()
+    );
+
+    // Yank the invoice
+    controller
+        .client
+        .yank_invoice(&inv.bindle.id)
+        .await
+        .expect("unable to yank invoice");
+
+    // Make sure we can't get missing
+    match controller.client.get_missing_parcels(&inv.bindle.id).await {
+        Ok(_) => panic!("getting a yanked invoice should have errored"),
+        Err(e) => {
+            if !matches!(e, bindle::client::ClientError::InvoiceNotFound) {
+                panic!("Expected an invoice not found error, got: {:?}", e)
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_missing_shas() {
+    let controller = TestController::new().await;
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    let parcel = scaffold.parcel_files.get("parcel").unwrap();
+    controller
+        .client
+        .create_parcel(&inv.bindle.id, &parcel.sha, parcel.data.clone())
+        .await
+        .expect("Unable to create parcel");
+    let shas: Vec<String> = scaffold
+        .parcel_files
+        .values()
+        .map(|p| p.sha.clone())
+        .collect();
+    let expected: Vec<String> = shas.iter().filter(|s| **s != parcel.sha).cloned().collect();
+
+    // Parcels are checked by SHA alone, so this still works once the invoice is yanked
+    controller
+        .client
+        .yank_invoice(&inv.bindle.id)
+        .await
+        .expect("unable to yank invoice");
+    let missing_shas = controller
+        .client
+        .get_missing_shas(&shas)
+        .await
+        .expect("Should be able to check parcels by SHA");
+    assert_eq!(expected, missing_shas);
+}
+
+#[tokio::test]
+async fn test_find_invoices_by_parcel() {
+    let controller = TestController::new().await;
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+
+    let inv = controller
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice")
+        .invoice;
+    let parcel = scaffold.parcel_files.get("parcel").unwrap();
+    controller
+        .client
+        .create_parcel(&inv.bindle.id, &parcel.sha, parcel.data.clone())
+        .await
+        .expect("Unable to create parcel");
+    controller
+        .client
+        .yank_invoice(&inv.bindle.id)
+        .await
+        .expect("unable to yank invoice");
+
+    // The parcel can be traced back to the (now yanked) invoice containing it
+    let found = controller
+        .client
+        .find_invoices_by_parcel(&parcel.sha)
+        .await
+        .expect("Should be able to find invoices by parcel");
+    assert_eq!(1, found.len());
+    assert_eq!(inv.bindle.id.to_string(), found[0].id.to_string());
+    assert!(found[0].yanked);
+}
+
+#[tokio::test]
+async fn test_standalone_push() {
+    let controller = TestController::new().await;
+    let tempdir = tempfile::tempdir().expect("unable to create tempdir");
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+    let id = scaffold.invoice.bindle.id.clone();
+    let expected_len = scaffold.parcel_files.len();
+
+    StandaloneWrite::new(&tempdir, &id)
+        .expect("Unable to create new standalone write")
+        .write(
+            scaffold.invoice,
+            scaffold
+                .parcel_files
+                .values()
+                .map(|parcel| {
+                    (
+                        parcel.sha.clone(),
+                        std::io::Cursor::new(parcel.data.clone()),
+                    )
+                })
+                .collect(),
+        )
+        .await
+        .expect("Unable to write standalone bindle");
+
+    let standalone = StandaloneRead::new(&tempdir, &id)
+        .await
+        .expect("Unable to read standalone bindle");
+    let opts = PushOptions {
+        concurrency: 2,
+        ..Default::default()
+    };
+    let report = standalone
+        .push_with_options(&controller.client, opts.clone())
+        .await
+        .expect("Unable to push bindle");
+    assert!(report.invoice_created);
+    assert!(report.is_complete());
+    assert_eq!(expected_len, report.parcels.len());
+    assert!(report
+        .parcels
+        .iter()
+        .all(|p| p.status == ParcelPushStatus::Uploaded && p.attempts == 1));
+    assert!(report.bytes_uploaded() > 0);
+
+    // Pushing again shouldn't upload anything
+    let report = standalone
+        .push_with_options(&controller.client, opts)
+        .await
+        .expect("Unable to push bindle a second time");
+    assert!(!report.invoice_created);
+    assert!(report.is_complete());
+    assert!(report
+        .parcels
+        .iter()
+        .all(|p| p.status == ParcelPushStatus::AlreadyExists));
+    assert_eq!(0, report.bytes_uploaded());
+}
+
+#[tokio::test]
+async fn test_standalone_round_trip() {
+    let source = TestController::new().await;
+    let target = TestController::new().await;
+    let tempdir = tempfile::tempdir().expect("unable to create tempdir");
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+    let id = scaffold.invoice.bindle.id.clone();
+    let expected_len = scaffold.parcel_files.len();
+
+    source
+        .client
+        .create_invoice(scaffold.invoice)
+        .await
+        .expect("unable to create invoice");
+    for parcel in scaffold.parcel_files.values() {
+        source
+            .client
+            .create_parcel(&id, &parcel.sha, parcel.data.clone())
+            .await
+            .expect("Unable to create parcel");
+    }
+
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let recorded = progress.clone();
+    let path = source
+        .client
+        .export_standalone_with_options(
+            &id,
+            tempdir.path(),
+            ExportOptions {
+                concurrency: 2,
+                progress: Some(Arc::new(move |p: &Progress| {
+                    recorded.lock().unwrap().push(p.clone())
+                })),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Unable to export bindle");
+    assert_eq!(tempdir.path().join(id.sha()), path);
+    {
+        let progress = progress.lock().unwrap();
+        assert_eq!(expected_len, progress.len());
+        assert!(progress.iter().all(|p| p.total == expected_len));
+        let mut done: Vec<usize> = progress.iter().map(|p| p.done).collect();
+        done.sort_unstable();
+        assert_eq!((1..=expected_len).collect::<Vec<_>>(), done);
+        for parcel in scaffold.parcel_files.values() {
+            assert!(
+                progress
+                    .iter()
+                    .any(|p| p.sha == parcel.sha && p.bytes == parcel.data.len() as u64),
+                "Missing progress for parcel {}",
+                parcel.sha
+            );
+        }
+    }
+
+    let report = target
+        .client
+        .push_standalone(&path)
+        .await
+        .expect("Unable to push exported bindle");
+    assert!(report.invoice_created);
+    assert!(report.is_complete());
+    assert_eq!(expected_len, report.parcels.len());
+    for parcel in scaffold.parcel_files.values() {
+        let data = target
+            .client
+            .get_parcel(&id, &parcel.sha)
+            .await
+            .expect("unable to get parcel");
+        assert_eq!(parcel.data, data, "Parcel {} doesn't match", parcel.sha);
+    }
+
+    // Exporting over an existing bindle shouldn't overwrite it
+    source
+        .client
+        .export_standalone(&id, tempdir.path())
+        .await
+        .expect_err("Exporting over an existing bindle should fail");
 }
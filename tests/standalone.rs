@@ -3,7 +3,7 @@ use bindle::testing;
 use std::collections::HashMap;
 use std::io::Cursor;
 
-use bindle::standalone::{StandaloneRead, StandaloneWrite, INVOICE_FILE, PARCEL_DIR};
+use bindle::standalone::{pack, StandaloneRead, StandaloneWrite, INVOICE_FILE, PARCEL_DIR};
 
 use tokio::stream::StreamExt;
 use tokio_util::codec::{BytesCodec, FramedRead};
@@ -139,6 +139,132 @@ async fn test_invalid_standalone_write() {
         .expect_err("write shouldn't succeed");
 }
 
+#[tokio::test]
+async fn test_archive_round_trip() {
+    let tempdir = tempfile::tempdir().expect("unable to create tempdir");
+
+    let scaffold = testing::Scaffold::load("lotsa_parcels").await;
+
+    let standalone = StandaloneWrite::new(tempdir.path().join("out"), &scaffold.invoice.bindle.id)
+        .expect("Unable to create new standalone write");
+
+    let expected_len = scaffold.parcel_files.len();
+    let id = scaffold.invoice.bindle.id.clone();
+    let expected_data: HashMap<String, Vec<u8>> = scaffold
+        .parcel_files
+        .values()
+        .map(|parcel| (parcel.sha.clone(), parcel.data.clone()))
+        .collect();
+
+    let archive = standalone
+        .write_archive(
+            scaffold.invoice,
+            scaffold
+                .parcel_files
+                .into_values()
+                .map(|parcel| (parcel.sha, Cursor::new(parcel.data)))
+                .collect(),
+        )
+        .await
+        .expect("write shouldn't error");
+    assert_eq!(standalone.archive_path(), archive);
+    assert!(
+        !standalone.path().exists(),
+        "Writing an archive shouldn't create the bindle directory"
+    );
+
+    let extracted = tempdir.path().join("extracted");
+    let read = StandaloneRead::from_archive(&archive, &extracted)
+        .await
+        .expect("Should be able to read the archive");
+    assert_eq!(expected_len, read.parcels.len());
+    for path in read.parcels.iter() {
+        let sha = path.file_stem().unwrap().to_string_lossy().to_string();
+        let data = tokio::fs::read(path).await.expect("Unable to read parcel");
+        assert_eq!(
+            expected_data.get(&sha),
+            Some(&data),
+            "Parcel {} doesn't match",
+            sha
+        );
+    }
+    validate_write(id, extracted.clone(), expected_len).await;
+
+    // Extracting again shouldn't overwrite anything
+    assert!(
+        StandaloneRead::from_archive(&archive, &extracted)
+            .await
+            .is_err(),
+        "Extracting over existing files shouldn't succeed"
+    );
+}
+
+#[tokio::test]
+async fn test_pack() {
+    let src = tempfile::tempdir().expect("unable to create tempdir");
+    let out = tempfile::tempdir().expect("unable to create tempdir");
+    tokio::fs::create_dir_all(src.path().join("static/css"))
+        .await
+        .expect("unable to create directories");
+    for (name, data) in &[
+        ("app.wasm", "module"),
+        ("static/index.html", "<html></html>"),
+        ("static/css/site.css", "body {}"),
+        ("static/css/site.css.map", "{}"),
+        ("README.md", "# App"),
+    ] {
+        tokio::fs::write(src.path().join(name), data)
+            .await
+            .expect("unable to write file");
+    }
+    tokio::fs::write(
+        src.path().join(pack::MANIFEST_FILE),
+        r#"
+        name = "example.com/app"
+        version = "1.0.0"
+        exclude = ["**/*.map"]
+
+        [[group]]
+        name = "server"
+        required = true
+        files = ["*.wasm"]
+
+        [[group]]
+        name = "static"
+        files = ["static/**"]
+        "#,
+    )
+    .await
+    .expect("unable to write manifest");
+
+    let dir = pack::pack(src.path(), out.path())
+        .await
+        .expect("directory should be packed");
+    let id: bindle::Id = "example.com/app/1.0.0".parse().unwrap();
+    assert_eq!(out.path().join(id.sha()), dir);
+    validate_write(id, out.path().to_owned(), 4).await;
+
+    let inv: bindle::Invoice = bindle::client::load::toml(dir.join(INVOICE_FILE))
+        .await
+        .expect("invoice should load");
+    let parcels = inv.parcel.expect("parcels should be set");
+    let names: Vec<&str> = parcels.iter().map(|p| p.label.name.as_str()).collect();
+    assert_eq!(
+        vec![
+            "README.md",
+            "app.wasm",
+            "static/css/site.css",
+            "static/index.html"
+        ],
+        names
+    );
+    assert!(parcels[0].is_global_group());
+    assert!(parcels[1].member_of("server"));
+    assert!(parcels[2].member_of("static"));
+    assert_eq!("text/css", parcels[2].label.media_type);
+    assert_eq!(2, inv.group.map(|g| g.len()).unwrap_or_default());
+}
+
 #[tokio::test]
 async fn test_push() {
     let tempdir = tempfile::tempdir().expect("unable to create tempdir");
@@ -146,7 +272,7 @@ async fn test_push() {
     // Build all the binaries and wait for it to complete
     let build_result = tokio::task::spawn_blocking(|| {
         std::process::Command::new("cargo")
-            .args(&["build", "--features", "cli"])
+            .args(&["build", "--all-features"])
             .output()
     })
     .await
@@ -162,8 +288,7 @@ async fn test_push() {
     let mut handle = std::process::Command::new("cargo")
         .args(&[
             "run",
-            "--features",
-            "cli",
+            "--all-features",
             "--bin",
             "bindle-server",
             "--",
@@ -36,8 +36,7 @@ impl TestController {
         let server_handle = std::process::Command::new("cargo")
             .args(&[
                 "run",
-                "--features",
-                "cli",
+                "--all-features",
                 "--bin",
                 "bindle-server",
                 "--",